@@ -0,0 +1,111 @@
+//! Compares the latency of reducer-like work on the node's runtime
+//! with 20k idle connections served on the same runtime, as by default,
+//! against serving them on a dedicated [`ClientRuntime`].
+//!
+//! Idle connections still wake up, to ping their client and check their liveness,
+//! which is what competes with module execution.
+//! Reports the p50 and p99 latencies, from spawning the work to its completion.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use spacetimedb::client::ClientRuntime;
+use tokio::sync::Notify;
+
+#[cfg(not(target_env = "msvc"))]
+use tikv_jemallocator::Jemalloc;
+
+#[cfg(not(target_env = "msvc"))]
+#[global_allocator]
+static GLOBAL: Jemalloc = Jemalloc;
+
+const IDLE_CONNECTIONS: usize = 20_000;
+/// How often each idle connection wakes up, give or take half of it.
+const IDLE_TICK: Duration = Duration::from_millis(100);
+const CALLS: usize = 5_000;
+/// How long each call spins for, as a reducer would.
+const CALL_WORK: Duration = Duration::from_micros(20);
+const NODE_THREADS: usize = 4;
+const CLIENT_THREADS: usize = 2;
+
+/// Stand in for an idle connection, which pings its client every so often, until `stop`.
+async fn idle_connection(stop: Arc<Notify>) {
+    let jitter = IDLE_TICK.mul_f64(rand::random::<f64>() - 0.5);
+    let mut interval = tokio::time::interval(IDLE_TICK + jitter);
+    let mut ping = [0u8; 64];
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                // Encode a ping, and check the connection's health.
+                for (i, byte) in ping.iter_mut().enumerate() {
+                    *byte = byte.wrapping_add(i as u8);
+                }
+                black_box(&ping);
+            }
+            _ = stop.notified() => break,
+        }
+    }
+}
+
+fn spin(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        std::hint::spin_loop();
+    }
+}
+
+/// The latencies of [`CALLS`] calls on `node`, with the idle connections spawned on `clients`.
+fn call_latencies(node: &tokio::runtime::Runtime, clients: &ClientRuntime) -> Vec<Duration> {
+    node.block_on(async {
+        let stop = Arc::new(Notify::new());
+        let connections = (0..IDLE_CONNECTIONS)
+            .map(|_| clients.spawn(idle_connection(stop.clone())))
+            .collect::<Vec<_>>();
+        // Let the connections settle into their ticks.
+        tokio::time::sleep(IDLE_TICK * 2).await;
+
+        let mut latencies = Vec::with_capacity(CALLS);
+        for _ in 0..CALLS {
+            let start = Instant::now();
+            tokio::spawn(async move { spin(CALL_WORK) }).await.unwrap();
+            latencies.push(start.elapsed());
+            tokio::time::sleep(Duration::from_micros(200)).await;
+        }
+
+        stop.notify_waiters();
+        for connection in connections {
+            connection.abort();
+        }
+        latencies
+    })
+}
+
+fn report(name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "client_runtime/{name}: p50 {:?}, p99 {:?}, max {:?}",
+        percentile(0.50),
+        percentile(0.99),
+        latencies[latencies.len() - 1],
+    );
+}
+
+fn main() {
+    let node = || {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(NODE_THREADS)
+            .enable_all()
+            .build()
+            .unwrap()
+    };
+
+    let shared = node();
+    report("shared", call_latencies(&shared, &ClientRuntime::current()));
+    drop(shared);
+
+    let dedicated = node();
+    let clients = ClientRuntime::with_worker_threads(CLIENT_THREADS).unwrap();
+    report("dedicated", call_latencies(&dedicated, &clients));
+}
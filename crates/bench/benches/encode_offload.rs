@@ -0,0 +1,94 @@
+//! Compares how responsive a connection's actor stays while encoding a huge message for its client,
+//! encoding it inline, as for small messages, against encoding it on the blocking pool.
+//!
+//! The actor runs on a single thread, as a busy node's worker may well be,
+//! and must keep answering its client's pings while the message is encoded and compressed.
+//! Reports the number of pings answered and the longest gap between any two of them.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use spacetimedb::client::messages::{IdentityTokenMessage, SerializeBuffer};
+use spacetimedb::client::{ClientConfig, EncodeResult, ProtocolCodec};
+use spacetimedb::messages::websocket::Compression;
+use spacetimedb_lib::{ConnectionId, Identity};
+
+#[cfg(not(target_env = "msvc"))]
+use tikv_jemallocator::Jemalloc;
+
+#[cfg(not(target_env = "msvc"))]
+#[global_allocator]
+static GLOBAL: Jemalloc = Jemalloc;
+
+/// The size of the message, e.g. a large initial subscription.
+const MESSAGE_LEN: usize = 32 * 1024 * 1024;
+/// How often the client pings.
+const PING_INTERVAL: Duration = Duration::from_millis(5);
+
+/// A message of [`MESSAGE_LEN`] bytes which doesn't compress to nothing.
+fn message() -> IdentityTokenMessage {
+    let token = (0..MESSAGE_LEN / 16)
+        .map(|_| format!("{:016x}", rand::random::<u64>()))
+        .collect::<String>();
+    IdentityTokenMessage {
+        identity: Identity::ZERO,
+        token: token.into(),
+        connection_id: ConnectionId::ZERO,
+    }
+}
+
+/// How [`answered_pings`] encodes the message.
+#[derive(Clone, Copy)]
+enum Encode {
+    Inline,
+    Offloaded,
+}
+
+/// Encode a message while answering pings, as the actor does, returning the pings answered
+/// and the longest gap between any two of them.
+fn answered_pings(encode: Encode) -> (usize, Duration) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let config = ClientConfig {
+            compression: Compression::Brotli,
+            ..ClientConfig::for_test()
+        };
+        let codec: Arc<dyn ProtocolCodec> = config.codec();
+        let (buffer, msg) = (SerializeBuffer::new(config), message());
+        let encoded = async move {
+            match encode {
+                Encode::Inline => codec.serialize(buffer, msg, config.compression),
+                Encode::Offloaded => codec.serialize_blocking(buffer, msg, config.compression).await,
+            }
+        };
+        tokio::pin!(encoded);
+
+        let mut interval = tokio::time::interval(PING_INTERVAL);
+        let (mut pings, mut longest_gap, mut last_ping) = (0, Duration::ZERO, Instant::now());
+        let encoded: EncodeResult = loop {
+            tokio::select! {
+                encoded = &mut encoded => break encoded,
+                _ = interval.tick() => {
+                    pings += 1;
+                    longest_gap = longest_gap.max(last_ping.elapsed());
+                    last_ping = Instant::now();
+                }
+            }
+        };
+        longest_gap = longest_gap.max(last_ping.elapsed());
+        assert!(encoded.is_ok(), "failed to encode the message");
+        (pings, longest_gap)
+    })
+}
+
+fn report(name: &str, (pings, longest_gap): (usize, Duration)) {
+    println!("encode_offload/{name}: {pings} pings answered, longest gap {longest_gap:?}");
+}
+
+fn main() {
+    report("inline", answered_pings(Encode::Inline));
+    report("offloaded", answered_pings(Encode::Offloaded));
+}
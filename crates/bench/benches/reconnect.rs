@@ -0,0 +1,67 @@
+//! Compares authenticating a websocket handshake with full credentials
+//! against redeeming a reconnect token.
+
+use std::time::Duration;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use spacetimedb::auth::token_validation::{new_validator, TokenValidator};
+use spacetimedb::auth::JwtKeys;
+use spacetimedb::client::{ReconnectGrant, ReconnectTokens};
+use spacetimedb::Identity;
+use spacetimedb_client_api::auth::TokenClaims;
+
+#[cfg(not(target_env = "msvc"))]
+use tikv_jemallocator::Jemalloc;
+
+#[cfg(not(target_env = "msvc"))]
+#[global_allocator]
+static GLOBAL: Jemalloc = Jemalloc;
+
+const LOCAL_ISSUER: &str = "localhost";
+
+fn handshake_auth(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let keys = JwtKeys::generate().unwrap();
+    let validator = new_validator(keys.public.clone(), LOCAL_ISSUER.to_owned());
+    let claims = TokenClaims::new(LOCAL_ISSUER.to_owned(), "bench-subject".to_owned());
+    let token = claims.encode_and_sign(&keys.private).unwrap();
+
+    let mut group = c.benchmark_group("handshake_auth");
+
+    group.bench_function("jwt", |b| {
+        b.iter(|| {
+            let claims = runtime.block_on(validator.validate_token(black_box(&token))).unwrap();
+            black_box((claims.identity, claims.subject, claims.issuer, token.clone()))
+        })
+    });
+
+    let tokens = ReconnectTokens::default();
+    let grant = ReconnectGrant {
+        identity: claims.id(),
+        subject: claims.subject.clone(),
+        issuer: claims.issuer.clone(),
+        token: token.clone(),
+        expires_at: None,
+        database_identity: Identity::ZERO,
+    };
+    // Tokens are single-use, so issue a fresh one outside of the measurement for each redemption.
+    // A reconnect authenticates with the token's grant, then redeems it once the database is known.
+    group.bench_function("reconnect_token", |b| {
+        b.iter_batched(
+            || tokens.issue(grant.clone(), Duration::from_secs(60)),
+            |reconnect_token| {
+                black_box(tokens.grant(&reconnect_token).unwrap());
+                black_box(tokens.redeem(&reconnect_token, &Identity::ZERO).unwrap())
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, handshake_auth);
+criterion_main!(benches);
@@ -0,0 +1,110 @@
+//! Compares the CPU spent encoding a transaction update broadcast to many clients,
+//! with the same subscriptions and the same config, each encoding its own copy,
+//! against them sharing the encoding of the first of them to encode it.
+//!
+//! Each simulated client does what its websocket actor does with the update:
+//! encode it, or reuse the shared encoding, then take back its buffer, or allocate a fresh one.
+//! Reports the time taken to encode the update for every client, on one thread.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use spacetimedb::client::messages::{
+    InUseSerializeBuffer, SerializableMessage, SerializeBuffer, SharedEncoding, SubscriptionUpdateMessage,
+    TransactionUpdateMessage, UpdateDetail,
+};
+use spacetimedb::client::{ClientConfig, ProtocolCodec};
+use spacetimedb::messages::websocket::{
+    self as ws, BsatnFormat, Compression, FormatSwitch, QueryUpdate, SingleQueryUpdate, WebsocketFormat,
+};
+use spacetimedb_lib::Timestamp;
+use spacetimedb_primitives::TableId;
+use spacetimedb_sats::{product, ProductValue};
+
+#[cfg(not(target_env = "msvc"))]
+use tikv_jemallocator::Jemalloc;
+
+#[cfg(not(target_env = "msvc"))]
+#[global_allocator]
+static GLOBAL: Jemalloc = Jemalloc;
+
+const CLIENTS: usize = 5_000;
+/// The rows inserted by the transaction, into the one table every client is subscribed to.
+const ROWS: u64 = 500;
+
+/// A light update of [`ROWS`] inserts, as sent to a client which doesn't ask for full updates.
+fn update() -> TransactionUpdateMessage {
+    let (inserts, num_rows) = BsatnFormat::encode_list((0..ROWS).map(|i| product![i, i * 31]));
+    let (deletes, _) = BsatnFormat::encode_list(std::iter::empty::<ProductValue>());
+    let query_update = QueryUpdate {
+        deletes,
+        inserts,
+        columns: Box::default(),
+    };
+    let update = SingleQueryUpdate {
+        update: BsatnFormat::into_query_update(query_update, Compression::None),
+        num_rows,
+    };
+    TransactionUpdateMessage {
+        event: None,
+        database_update: SubscriptionUpdateMessage {
+            database_update: FormatSwitch::Bsatn(ws::DatabaseUpdate {
+                tables: vec![ws::TableUpdate::new(TableId(1), "t".into(), update)],
+            }),
+            request_id: None,
+            timer: None,
+        },
+        committed_at: Timestamp::now(),
+        detail: UpdateDetail::FULL,
+        shared: None,
+    }
+}
+
+/// The time taken to encode `update` for [`CLIENTS`] clients with `config`,
+/// sharing its encoding among them should `share`.
+fn encode_for_clients(config: ClientConfig, update: &TransactionUpdateMessage, share: bool) -> Duration {
+    let codec: Arc<dyn ProtocolCodec> = config.codec();
+    let shared = share.then(SharedEncoding::default);
+    let mut buffer = SerializeBuffer::new(config);
+
+    let start = Instant::now();
+    for _ in 0..CLIENTS {
+        let msg = SerializableMessage::TxUpdate(TransactionUpdateMessage {
+            shared: shared.clone(),
+            ..update.clone()
+        });
+        let (msg_alloc, msg_data) = match msg.shared_encoding().and_then(SharedEncoding::get) {
+            Some(msg_data) => (InUseSerializeBuffer::Unused(buffer), msg_data),
+            None => {
+                let (msg_alloc, msg_data) = codec
+                    .serialize(buffer, msg, config.compression)
+                    .unwrap_or_else(|failure| panic!("{}", failure.error));
+                if let Some(shared) = &shared {
+                    shared.set(&msg_data);
+                }
+                (msg_alloc, msg_data)
+            }
+        };
+        drop(black_box(msg_data));
+        buffer = msg_alloc.try_reclaim().unwrap_or_else(|| SerializeBuffer::new(config));
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let update = update();
+    for compression in [Compression::None, Compression::Brotli, Compression::Zstd] {
+        let config = ClientConfig {
+            compression,
+            tx_update_full: false,
+            ..ClientConfig::for_test()
+        };
+        let private = encode_for_clients(config, &update, false);
+        let shared = encode_for_clients(config, &update, true);
+        println!(
+            "shared_encoding/{compression:?}: {CLIENTS} clients, private {private:?}, shared {shared:?} ({:.1}x)",
+            private.as_secs_f64() / shared.as_secs_f64(),
+        );
+    }
+}
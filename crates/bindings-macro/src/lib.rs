@@ -37,9 +37,11 @@ mod sym {
         };
     }
 
+    symbol!(acknowledged);
     symbol!(at);
     symbol!(auto_inc);
     symbol!(btree);
+    symbol!(client_callable);
     symbol!(client_connected);
     symbol!(client_disconnected);
     symbol!(column);
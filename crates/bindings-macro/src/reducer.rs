@@ -4,12 +4,14 @@ use proc_macro2::{Span, TokenStream};
 use quote::{quote, quote_spanned};
 use syn::parse::Parser as _;
 use syn::spanned::Spanned;
-use syn::{FnArg, Ident, ItemFn, LitStr};
+use syn::{FnArg, Ident, ItemFn, LitBool, LitStr};
 
 #[derive(Default)]
 pub(crate) struct ReducerArgs {
     name: Option<LitStr>,
     lifecycle: Option<LifecycleReducer>,
+    client_callable: Option<LitBool>,
+    acknowledged: Option<Span>,
 }
 
 enum LifecycleReducer {
@@ -51,6 +53,14 @@ impl ReducerArgs {
                     check_duplicate(&args.name, &meta)?;
                     args.name = Some(meta.value()?.parse()?);
                 }
+                sym::client_callable => {
+                    check_duplicate(&args.client_callable, &meta)?;
+                    args.client_callable = Some(meta.value()?.parse()?);
+                }
+                sym::acknowledged => {
+                    check_duplicate(&args.acknowledged, &meta)?;
+                    args.acknowledged = Some(meta.path.span());
+                }
             });
             Ok(())
         })
@@ -75,6 +85,8 @@ pub(crate) fn reducer_impl(args: ReducerArgs, original_function: &ItemFn) -> syn
     }
 
     let lifecycle = args.lifecycle.iter().filter_map(|lc| lc.to_lifecycle_value());
+    let client_callable = args.client_callable.iter();
+    let acknowledged = args.acknowledged.iter().map(|span| quote_spanned!(*span => true));
 
     // Extract all function parameters, except for `self` ones that aren't allowed.
     let typed_args = original_function
@@ -142,6 +154,8 @@ pub(crate) fn reducer_impl(args: ReducerArgs, original_function: &ItemFn) -> syn
         impl spacetimedb::rt::ReducerInfo for #func_name {
             const NAME: &'static str = #reducer_name;
             #(const LIFECYCLE: Option<spacetimedb::rt::LifecycleReducer> = Some(#lifecycle);)*
+            #(const CLIENT_CALLABLE: bool = #client_callable;)*
+            #(const ACKNOWLEDGED: bool = #acknowledged;)*
             const ARG_NAMES: &'static [Option<&'static str>] = &[#(#opt_arg_names),*];
             const INVOKE: spacetimedb::rt::ReducerFn = #func_name::invoke;
         }
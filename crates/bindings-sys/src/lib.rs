@@ -590,6 +590,87 @@ pub mod raw {
         pub fn identity(out_ptr: *mut u8);
     }
 
+    #[link(wasm_import_module = "spacetime_10.1")]
+    extern "C" {
+        /// Disconnects the clients matching the BSATN-encoded `ClientTarget`
+        /// in `target = target_ptr[..target_len]` in WASM memory
+        /// once the current transaction commits,
+        /// telling them they were kicked by the module
+        /// for the UTF-8 `reason = reason_ptr[..reason_len]`.
+        ///
+        /// Nothing is disconnected if the transaction is rolled back.
+        ///
+        /// The number of connections which matched is written to the WASM pointer `out`.
+        ///
+        /// # Traps
+        ///
+        /// Traps if:
+        /// - `target_ptr` is NULL or `target` is not in bounds of WASM memory.
+        /// - `reason_ptr` is NULL or `reason` is not in bounds of WASM memory.
+        /// - `reason` is not valid UTF-8.
+        /// - `out` is NULL or `out[..size_of::<u32>()]` is not in bounds of WASM memory.
+        ///
+        /// # Errors
+        ///
+        /// Returns an error:
+        ///
+        /// - `NOT_IN_TRANSACTION`, when called outside of a transaction.
+        /// - `BSATN_DECODE_ERROR`, when `target` cannot be decoded to a `ClientTarget`.
+        pub fn disconnect_client(
+            target_ptr: *const u8,
+            target_len: usize,
+            reason_ptr: *const u8,
+            reason_len: usize,
+            out: *mut u32,
+        ) -> u16;
+    }
+
+    #[link(wasm_import_module = "spacetime_10.2")]
+    extern "C" {
+        /// Returns the tag of the `ReducerTransport` the current reducer call arrived on:
+        /// `0` when the host called the reducer itself,
+        /// `1` for a websocket connection,
+        /// `2` for the HTTP call route,
+        /// and `3` for the scheduler.
+        pub fn reducer_transport() -> u32;
+    }
+
+    #[link(wasm_import_module = "spacetime_10.3")]
+    extern "C" {
+        /// Sends the bytes `payload = payload_ptr[..payload_len]` in WASM memory
+        /// to the caller of the current reducer as its progress, while the reducer is still running.
+        ///
+        /// Progress is sent even if the transaction is rolled back.
+        /// It may be dropped, e.g. when reported too often,
+        /// or when the reducer has no caller to send it to.
+        ///
+        /// Whether the progress was sent, `1`, or dropped, `0`, is written to the WASM pointer `out`.
+        ///
+        /// # Traps
+        ///
+        /// Traps if:
+        /// - `payload_ptr` is NULL or `payload` is not in bounds of WASM memory.
+        /// - `out` is NULL or `out[..size_of::<u32>()]` is not in bounds of WASM memory.
+        ///
+        /// # Errors
+        ///
+        /// Returns an error:
+        ///
+        /// - `NOT_IN_TRANSACTION`, when called outside of a transaction.
+        pub fn report_progress(payload_ptr: *const u8, payload_len: usize, out: *mut u32) -> u16;
+    }
+
+    #[link(wasm_import_module = "spacetime_10.4")]
+    extern "C" {
+        /// Returns the mask of the `DatabaseFeatures` the owner of the database turned on for it,
+        /// with a bit for each feature:
+        /// `1 << 0` for delta updates,
+        /// and `1 << 1` for acknowledged delivery.
+        ///
+        /// Hosts may set bits for features newer than the module, which it should ignore.
+        pub fn database_features() -> u64;
+    }
+
     /// What strategy does the database index use?
     ///
     /// See also: <https://www.postgresql.org/docs/current/sql-createindex.html>
@@ -1089,6 +1170,55 @@ pub fn identity() -> [u8; 32] {
     buf
 }
 
+/// Disconnects the clients matching the BSATN-encoded `ClientTarget` `target`
+/// once the current transaction commits,
+/// telling them they were kicked by the module for `reason`.
+///
+/// Returns the number of connections which matched.
+///
+/// # Errors
+///
+/// Returns an error:
+///
+/// - `NOT_IN_TRANSACTION`, when called outside of a transaction.
+/// - `BSATN_DECODE_ERROR`, when `target` cannot be decoded to a `ClientTarget`.
+#[inline]
+pub fn disconnect_client(target: &[u8], reason: &str) -> Result<u32, Errno> {
+    unsafe { call(|out| raw::disconnect_client(target.as_ptr(), target.len(), reason.as_ptr(), reason.len(), out)) }
+}
+
+/// Read the tag of the `ReducerTransport` the current reducer call arrived on.
+///
+/// Doesn't return a proper typed `ReducerTransport` because this crate doesn't depend on `spacetimedb_lib`.
+#[inline]
+pub fn reducer_transport() -> u32 {
+    unsafe { raw::reducer_transport() }
+}
+
+/// Read the mask of the `DatabaseFeatures` turned on for the database.
+///
+/// Doesn't return a proper typed `DatabaseFeatures` because this crate doesn't depend on `spacetimedb_lib`.
+#[inline]
+pub fn database_features() -> u64 {
+    unsafe { raw::database_features() }
+}
+
+/// Sends `payload` to the caller of the current reducer as its progress,
+/// while the reducer is still running, even if its transaction is then rolled back.
+///
+/// Returns whether the progress was sent rather than dropped.
+///
+/// # Errors
+///
+/// Returns an error:
+///
+/// - `NOT_IN_TRANSACTION`, when called outside of a transaction.
+#[inline]
+pub fn report_progress(payload: &[u8]) -> Result<bool, Errno> {
+    let sent = unsafe { call(|out| raw::report_progress(payload.as_ptr(), payload.len(), out))? };
+    Ok(sent != 0)
+}
+
 pub struct RowIter {
     raw: raw::RowIter,
 }
@@ -32,13 +32,16 @@ pub use spacetimedb_lib::de::{Deserialize, DeserializeOwned};
 pub use spacetimedb_lib::sats;
 pub use spacetimedb_lib::ser::Serialize;
 pub use spacetimedb_lib::AlgebraicValue;
+pub use spacetimedb_lib::ClientTarget;
 pub use spacetimedb_lib::ConnectionId;
 // `FilterableValue` re-exported purely for rustdoc.
 pub use spacetimedb_lib::FilterableValue;
 pub use spacetimedb_lib::Identity;
+pub use spacetimedb_lib::ReducerTransport;
 pub use spacetimedb_lib::ScheduleAt;
 pub use spacetimedb_lib::TimeDuration;
 pub use spacetimedb_lib::Timestamp;
+pub use spacetimedb_lib::{DatabaseFeature, DatabaseFeatures};
 pub use spacetimedb_primitives::TableId;
 pub use sys::Errno;
 pub use table::{AutoIncOverflow, RangedIndex, Table, TryInsertError, UniqueColumn, UniqueConstraintViolation};
@@ -538,6 +541,20 @@ pub use spacetimedb_bindings_macro::table;
 /// If an error occurs in the disconnect reducer,
 /// the client is still recorded as disconnected.
 ///
+/// # Reducers not callable by clients
+///
+/// A reducer marked with `#[spacetimedb::reducer(client_callable = false)]` can't be called by clients,
+/// and is omitted from generated client bindings.
+/// It can still be [scheduled](#scheduled-reducers), and can be called by the database owner.
+///
+/// # Acknowledged reducers
+///
+/// The updates of a reducer marked with `#[spacetimedb::reducer(acknowledged)]`,
+/// e.g. one recording an order fill, must be acknowledged by clients which opted into acknowledged delivery.
+/// Such clients are sent each update with a delivery id, which they acknowledge once they've applied it.
+/// Updates a client hasn't acknowledged are delivered again when it reconnects.
+/// Other clients receive the updates as usual.
+///
 /// # Scheduled reducers
 ///
 /// In addition to life cycle annotations, reducers can be made **scheduled**.
@@ -760,6 +777,49 @@ impl ReducerContext {
         // which reads the module identity out of the `InstanceEnv`.
         Identity::from_byte_array(spacetimedb_bindings_sys::identity())
     }
+
+    /// Disconnect the clients matching `target`, an [`Identity`] or a [`ConnectionId`],
+    /// once this reducer's transaction commits,
+    /// telling them they were kicked by the module for `reason`.
+    ///
+    /// Nothing is disconnected if the reducer fails.
+    ///
+    /// Returns the number of connections which matched.
+    pub fn disconnect_client(&self, target: impl Into<ClientTarget>, reason: &str) -> u32 {
+        let target = bsatn::to_vec(&target.into()).expect("failed to serialize client target");
+        sys::disconnect_client(&target, reason).expect("disconnect_client() call failed")
+    }
+
+    /// How this reducer call arrived: over a client's websocket connection,
+    /// through the HTTP call route, from the scheduler, or from the host itself.
+    ///
+    /// Modules that never call this method run on hosts which don't know about transports.
+    pub fn transport(&self) -> ReducerTransport {
+        ReducerTransport::from_u32(sys::reducer_transport()).unwrap_or_default()
+    }
+
+    /// Send `value` to the caller of this reducer as its progress, BSATN-encoded,
+    /// while the reducer is still running.
+    ///
+    /// Unlike the reducer's changes, progress is sent even if the reducer then fails.
+    /// Progress is best-effort: it's dropped when reported too often,
+    /// when the caller's connection is backed up,
+    /// or when there's no caller to send it to, e.g. for scheduled reducers.
+    ///
+    /// Returns whether the progress was sent.
+    pub fn report_progress(&self, value: impl Serialize) -> bool {
+        let payload = bsatn::to_vec(&value).expect("failed to serialize progress");
+        sys::report_progress(&payload).expect("report_progress() call failed")
+    }
+
+    /// The features the database's owner turned on for it, e.g. to branch on whether its clients
+    /// are sent the updates of acknowledged reducers as `AcknowledgedUpdate`s.
+    ///
+    /// Clients which connected before a feature was turned on or off keep what they negotiated until they reconnect,
+    /// so a reducer may see a feature which its caller's connection hasn't, or the other way around.
+    pub fn features(&self) -> DatabaseFeatures {
+        DatabaseFeatures::from_bits(sys::database_features())
+    }
 }
 
 /// A handle on a database with a particular table schema.
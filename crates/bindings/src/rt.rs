@@ -51,6 +51,12 @@ pub trait ReducerInfo {
     /// The lifecycle of the reducer, if there is one.
     const LIFECYCLE: Option<LifecycleReducer> = None;
 
+    /// Whether clients may call the reducer.
+    const CLIENT_CALLABLE: bool = true;
+
+    /// Whether clients must acknowledge the updates of the reducer.
+    const ACKNOWLEDGED: bool = false;
+
     /// A description of the parameter names of the reducer.
     const ARG_NAMES: &'static [Option<&'static str>];
 
@@ -362,6 +368,12 @@ pub fn register_reducer<'a, A: Args<'a>, I: ReducerInfo>(_: impl Reducer<'a, A>)
     register_describer(|module| {
         let params = A::schema::<I>(&mut module.inner);
         module.inner.add_reducer(I::NAME, params, I::LIFECYCLE);
+        if !I::CLIENT_CALLABLE {
+            module.inner.set_reducer_client_callable(I::NAME, false);
+        }
+        if I::ACKNOWLEDGED {
+            module.inner.set_reducer_acknowledged(I::NAME, true);
+        }
         module.reducers.push(I::INVOKE);
     })
 }
@@ -0,0 +1,78 @@
+//! Benchmarks of the compressions clients may negotiate for the messages they're sent,
+//! compressing and decompressing payloads like large initial subscription updates.
+//!
+//! The size of each compressed payload is printed once, to compare ratios alongside times.
+
+#![allow(clippy::disallowed_macros)]
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use spacetimedb_client_api_messages::websocket::{
+    brotli_compress, brotli_decompress, gzip_compress, gzip_decompress, zstd_compress_with_level, zstd_decompress,
+    DEFAULT_ZSTD_LEVEL,
+};
+use std::io;
+
+/// `n` BSATN-like rows of a table of players, which compress about as well as real updates.
+fn rows(n: u32) -> Vec<u8> {
+    let mut rows = Vec::new();
+    for i in 0..n {
+        rows.extend_from_slice(&u64::from(i).to_le_bytes());
+        rows.extend_from_slice(&12u32.to_le_bytes());
+        rows.extend_from_slice(format!("player-{:05}", i % 50_000).as_bytes());
+        rows.extend_from_slice(&(i % 100).to_le_bytes());
+        rows.extend_from_slice(&[(i % 3) as u8; 16]);
+    }
+    rows
+}
+
+type Compress = Box<dyn Fn(&[u8], &mut Vec<u8>)>;
+type Decompress = fn(&[u8]) -> io::Result<Vec<u8>>;
+
+fn codecs() -> Vec<(String, Compress, Decompress)> {
+    let mut codecs: Vec<(String, Compress, Decompress)> = vec![
+        ("brotli".into(), Box::new(brotli_compress), brotli_decompress),
+        ("gzip".into(), Box::new(gzip_compress), gzip_decompress),
+    ];
+    for level in [-3, DEFAULT_ZSTD_LEVEL, 3, 9] {
+        codecs.push((
+            format!("zstd-{level}"),
+            Box::new(move |b, out| zstd_compress_with_level(b, out, level)),
+            zstd_decompress,
+        ));
+    }
+    codecs
+}
+
+fn bench_compression(c: &mut Criterion) {
+    for n in [1_000, 100_000] {
+        let payload = rows(n);
+        let mut compress_group = c.benchmark_group(format!("compress/{n}_rows"));
+        compress_group.throughput(Throughput::Bytes(payload.len() as u64));
+        for (name, compress, _) in codecs() {
+            let mut out = Vec::with_capacity(payload.len());
+            compress(&payload, &mut out);
+            println!("{name}: {} -> {} bytes", payload.len(), out.len());
+            compress_group.bench_function(BenchmarkId::from_parameter(&name), |b| {
+                b.iter(|| {
+                    out.clear();
+                    compress(black_box(&payload), &mut out);
+                })
+            });
+        }
+        compress_group.finish();
+
+        let mut decompress_group = c.benchmark_group(format!("decompress/{n}_rows"));
+        decompress_group.throughput(Throughput::Bytes(payload.len() as u64));
+        for (name, compress, decompress) in codecs() {
+            let mut compressed = Vec::new();
+            compress(&payload, &mut compressed);
+            decompress_group.bench_function(BenchmarkId::from_parameter(&name), |b| {
+                b.iter(|| decompress(black_box(&compressed)).unwrap())
+            });
+        }
+        decompress_group.finish();
+    }
+}
+
+criterion_group!(benches, bench_compression);
+criterion_main!(benches);
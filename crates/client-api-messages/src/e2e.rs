@@ -0,0 +1,415 @@
+//! End-to-end encryption of websocket messages,
+//! for the confidentiality of payloads relayed by middleboxes which terminate TLS.
+//!
+//! ## Negotiation
+//!
+//! A client opts in by passing an ephemeral X25519 public key,
+//! base64url-encoded without padding, as the [`PUBLIC_KEY_PARAM`] query parameter of the subscribe request.
+//! The server answers with its own ephemeral public key, encoded likewise,
+//! in the [`PUBLIC_KEY_HEADER`] header of the upgrade response,
+//! and both sides derive a [`Session`] from the shared secret with [`KeyPair::agree`].
+//! Each direction has its own key.
+//!
+//! ## Authentication
+//!
+//! The key exchange by itself only keeps payloads from passive observers:
+//! a middlebox which terminates TLS could instead answer each side with a key of its own.
+//! So the server binds its key to the client's, by signing a [`KeyBinding`] of the two
+//! as an ES256 JWT with the node's token signing key,
+//! passed in the [`KEY_BINDING_HEADER`] header of the upgrade response.
+//! A client which checks the binding against the node's public key,
+//! as served by `/v1/identity/public-key`, and finds that it names both its own key and the server's,
+//! knows it shares its session with the node rather than with whatever relays the connection.
+//! This only holds if the client obtained the node's public key in a way the middlebox can't tamper with,
+//! e.g. pinned in its configuration, rather than fetched through the middlebox.
+//! A node without a signing key sends no binding, in which case the connection only stops passive observers.
+//!
+//! ## Framing
+//!
+//! Every message, in either direction, is then sealed into an envelope:
+//!
+//! ```text
+//! [version: u8][nonce: 12 bytes][ciphertext][tag: 16 bytes]
+//! ```
+//!
+//! sealed with ChaCha20-Poly1305, with the version and nonce as associated data.
+//! The nonce is the sender's key epoch, as a little-endian `u32`,
+//! followed by its count of messages sealed in that epoch, as a little-endian `u64`.
+//! Binary frames carry the envelope as is, text frames carry it base64-encoded.
+//!
+//! Messages are compressed, if at all, before they are encrypted,
+//! as ciphertext doesn't compress.
+//! The compression tag of a binary server message,
+//! and the `TextEnvelope` of a text one, are thus sealed along with the message.
+//!
+//! ## Key rotation
+//!
+//! Each side may [rotate](Sealer::rotate) its sending key at any time,
+//! ratcheting it forward and bumping its epoch, e.g. the server does so on its liveness check.
+//! A receiver seeing a later epoch ratchets its copy of the key forward to match,
+//! so keys of past epochs can't be recovered from current ones.
+//! Within an epoch, message counters must strictly increase, which rejects replayed messages.
+
+use base64::Engine;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// The query parameter of the subscribe request in which a client passes its public key.
+pub const PUBLIC_KEY_PARAM: &str = "e2e_public_key";
+
+/// The header of the upgrade response in which the server passes its public key.
+pub const PUBLIC_KEY_HEADER: &str = "spacetime-e2e-public-key";
+
+/// The header of the upgrade response in which the server passes its signed [`KeyBinding`].
+pub const KEY_BINDING_HEADER: &str = "spacetime-e2e-key-binding";
+
+/// The version of the envelope format, its first byte.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+/// The length of the part of an envelope before the ciphertext.
+pub const HEADER_LEN: usize = 1 + NONCE_LEN;
+
+/// How many epochs a receiver ratchets its key forward by to open a message.
+///
+/// Bounds the work a single message can cause.
+pub const MAX_EPOCH_SKIP: u32 = 64;
+
+const CLIENT_TO_SERVER_INFO: &[u8] = b"spacetimedb e2e v1 client to server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"spacetimedb e2e v1 server to client";
+const RATCHET_INFO: &[u8] = b"spacetimedb e2e v1 ratchet";
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum E2eError {
+    #[error("invalid e2e public key")]
+    InvalidPublicKey,
+    #[error("encrypted message is too short")]
+    Truncated,
+    #[error("unsupported encryption envelope version {0}")]
+    UnsupportedVersion(u8),
+    #[error("message was encrypted in epoch {epoch}, which has expired, the current epoch is {current}")]
+    StaleEpoch { epoch: u32, current: u32 },
+    #[error("message was encrypted in epoch {epoch}, too far ahead of the current epoch {current}")]
+    EpochTooFarAhead { epoch: u32, current: u32 },
+    #[error("encrypted message was replayed or reordered")]
+    Replayed,
+    #[error("failed to decrypt message")]
+    Decrypt,
+}
+
+/// The claims of the JWT with which the server binds its public key to the client's,
+/// see the [module docs](self#authentication).
+///
+/// Both keys are encoded as they are passed during negotiation.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeyBinding {
+    pub client_public_key: String,
+    pub server_public_key: String,
+}
+
+/// Which end of a connection a [`Session`] is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// An ephemeral X25519 key pair, used for a single connection.
+pub struct KeyPair {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let secret = EphemeralSecret::random();
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// The public key, encoded as it is passed during negotiation.
+    pub fn public_key(&self) -> String {
+        encode_public_key(self.public.as_bytes())
+    }
+
+    /// Derive the [`Session`] of `role` from this key pair and the peer's public key, as encoded.
+    pub fn agree(self, role: Role, peer_public: &str) -> Result<Session, E2eError> {
+        let peer_public = PublicKey::from(decode_public_key(peer_public)?);
+        let shared = self.secret.diffie_hellman(&peer_public);
+        // Refuse low-order points, which would make the shared secret predictable.
+        if !shared.was_contributory() {
+            return Err(E2eError::InvalidPublicKey);
+        }
+        let (client_public, server_public) = match role {
+            Role::Client => (self.public, peer_public),
+            Role::Server => (peer_public, self.public),
+        };
+        let salt = [client_public.as_bytes().as_slice(), server_public.as_bytes()].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        let expand = |info| {
+            let mut key = [0; 32];
+            hkdf.expand(info, &mut key)
+                .expect("32 bytes is a valid HKDF-SHA256 output length");
+            key
+        };
+        let (client_to_server, server_to_client) = (expand(CLIENT_TO_SERVER_INFO), expand(SERVER_TO_CLIENT_INFO));
+        let (send, recv) = match role {
+            Role::Client => (client_to_server, server_to_client),
+            Role::Server => (server_to_client, client_to_server),
+        };
+        Ok(Session {
+            sealer: Sealer::new(send),
+            opener: Opener::new(recv),
+        })
+    }
+}
+
+fn encode_public_key(key: &[u8; 32]) -> String {
+    base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(key)
+}
+
+fn decode_public_key(key: &str) -> Result<[u8; 32], E2eError> {
+    let bytes = base64::prelude::BASE64_URL_SAFE_NO_PAD
+        .decode(key)
+        .map_err(|_| E2eError::InvalidPublicKey)?;
+    bytes.try_into().map_err(|_| E2eError::InvalidPublicKey)
+}
+
+/// The keys of one end of an encrypted connection.
+pub struct Session {
+    pub sealer: Sealer,
+    pub opener: Opener,
+}
+
+/// Ratchet `key` forward by one epoch.
+fn ratchet(key: &[u8; 32]) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::from_prk(key).expect("32 bytes is a valid HKDF-SHA256 PRK length");
+    let mut next = [0; 32];
+    hkdf.expand(RATCHET_INFO, &mut next)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    next
+}
+
+fn nonce(epoch: u32, counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..4].copy_from_slice(&epoch.to_le_bytes());
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Seals the messages sent by one end of a connection.
+pub struct Sealer {
+    key: [u8; 32],
+    epoch: u32,
+    /// The counter of the next message to seal.
+    counter: u64,
+}
+
+impl Sealer {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            epoch: 0,
+            counter: 0,
+        }
+    }
+
+    /// The number of times the key has been rotated.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Ratchet the key forward, starting a new epoch.
+    pub fn rotate(&mut self) {
+        self.key = ratchet(&self.key);
+        self.epoch += 1;
+        self.counter = 0;
+    }
+
+    /// Seal `plaintext`, appending the envelope to `out`.
+    pub fn seal(&mut self, plaintext: &[u8], out: &mut Vec<u8>) {
+        let nonce = nonce(self.epoch, self.counter);
+        self.counter += 1;
+
+        out.reserve(HEADER_LEN + plaintext.len() + TAG_LEN);
+        let header_start = out.len();
+        out.push(ENVELOPE_VERSION);
+        out.extend_from_slice(&nonce);
+        let body_start = out.len();
+        out.extend_from_slice(plaintext);
+
+        let (header, body) = out[header_start..].split_at_mut(HEADER_LEN);
+        let tag = ChaCha20Poly1305::new(&self.key.into())
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), header, body)
+            .expect("ChaCha20-Poly1305 can encrypt any message which fits in memory");
+        debug_assert_eq!(out.len() - body_start, plaintext.len());
+        out.extend_from_slice(&tag);
+    }
+
+    /// Seal `plaintext` into a new envelope.
+    pub fn seal_to_vec(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.seal(plaintext, &mut out);
+        out
+    }
+}
+
+/// Opens the messages received by one end of a connection.
+pub struct Opener {
+    key: [u8; 32],
+    epoch: u32,
+    /// The lowest counter a message of `epoch` may still have.
+    next_counter: u64,
+}
+
+impl Opener {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            epoch: 0,
+            next_counter: 0,
+        }
+    }
+
+    /// The latest epoch of the peer's key a message was received in.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Open `envelope`, returning the plaintext.
+    ///
+    /// On error, the state of the opener is unchanged.
+    pub fn open(&mut self, envelope: &[u8]) -> Result<Vec<u8>, E2eError> {
+        if envelope.len() < HEADER_LEN + TAG_LEN {
+            return Err(E2eError::Truncated);
+        }
+        let (header, rest) = envelope.split_at(HEADER_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        if header[0] != ENVELOPE_VERSION {
+            return Err(E2eError::UnsupportedVersion(header[0]));
+        }
+        let nonce = &header[1..];
+        let epoch = u32::from_le_bytes(nonce[..4].try_into().unwrap());
+        let counter = u64::from_le_bytes(nonce[4..].try_into().unwrap());
+
+        let current = self.epoch;
+        let key = match epoch.checked_sub(current) {
+            None => return Err(E2eError::StaleEpoch { epoch, current }),
+            Some(0) if counter < self.next_counter => return Err(E2eError::Replayed),
+            Some(skip) if skip > MAX_EPOCH_SKIP => return Err(E2eError::EpochTooFarAhead { epoch, current }),
+            Some(skip) => (0..skip).fold(self.key, |key, _| ratchet(&key)),
+        };
+
+        let mut plaintext = ciphertext.to_vec();
+        ChaCha20Poly1305::new(&key.into())
+            .decrypt_in_place_detached(Nonce::from_slice(nonce), header, &mut plaintext, Tag::from_slice(tag))
+            .map_err(|_| E2eError::Decrypt)?;
+
+        self.key = key;
+        self.epoch = epoch;
+        self.next_counter = counter + 1;
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions() -> (Session, Session) {
+        let (client, server) = (KeyPair::generate(), KeyPair::generate());
+        let client_public = client.public_key();
+        let client = client.agree(Role::Client, &server.public_key()).unwrap();
+        let server = server.agree(Role::Server, &client_public).unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn messages_round_trip_in_both_directions() {
+        let (mut client, mut server) = sessions();
+        for msg in [&b""[..], b"hello", &[7; 10_000]] {
+            let envelope = client.sealer.seal_to_vec(msg);
+            assert_eq!(envelope.len(), HEADER_LEN + msg.len() + TAG_LEN);
+            assert_eq!(server.opener.open(&envelope).unwrap(), msg);
+
+            let envelope = server.sealer.seal_to_vec(msg);
+            assert_eq!(client.opener.open(&envelope).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn each_direction_has_its_own_key() {
+        let (mut client, _) = sessions();
+        let envelope = client.sealer.seal_to_vec(b"hello");
+        assert_eq!(client.opener.open(&envelope), Err(E2eError::Decrypt));
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let (mut client, mut server) = sessions();
+        let envelope = client.sealer.seal_to_vec(b"hello");
+        for i in 0..envelope.len() {
+            let mut tampered = envelope.clone();
+            tampered[i] ^= 1;
+            assert!(server.opener.open(&tampered).is_err(), "flipped byte {i}");
+        }
+        assert_eq!(server.opener.open(&envelope[..HEADER_LEN]), Err(E2eError::Truncated));
+        assert_eq!(server.opener.open(&envelope).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn replayed_messages_are_rejected() {
+        let (mut client, mut server) = sessions();
+        let first = client.sealer.seal_to_vec(b"first");
+        let second = client.sealer.seal_to_vec(b"second");
+        server.opener.open(&first).unwrap();
+        server.opener.open(&second).unwrap();
+        assert_eq!(server.opener.open(&first), Err(E2eError::Replayed));
+        assert_eq!(server.opener.open(&second), Err(E2eError::Replayed));
+    }
+
+    #[test]
+    fn receivers_follow_key_rotations() {
+        let (mut client, mut server) = sessions();
+        let old = server.sealer.seal_to_vec(b"old");
+        server.sealer.rotate();
+        server.sealer.rotate();
+        assert_eq!(server.sealer.epoch(), 2);
+        let new = server.sealer.seal_to_vec(b"new");
+
+        assert_eq!(client.opener.open(&new).unwrap(), b"new");
+        assert_eq!(client.opener.epoch(), 2);
+        assert_eq!(
+            client.opener.open(&old),
+            Err(E2eError::StaleEpoch { epoch: 0, current: 2 })
+        );
+
+        for _ in 0..=MAX_EPOCH_SKIP {
+            server.sealer.rotate();
+        }
+        let far = server.sealer.seal_to_vec(b"far");
+        assert!(matches!(
+            client.opener.open(&far),
+            Err(E2eError::EpochTooFarAhead { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_public_keys_are_rejected() {
+        for key in [
+            "",
+            "not base64!",
+            &encode_public_key(&[0; 32])[..10],
+            &encode_public_key(&[0; 32]),
+        ] {
+            assert!(
+                KeyPair::generate().agree(Role::Server, key).is_err(),
+                "{key:?} should be rejected"
+            );
+        }
+    }
+}
@@ -1,5 +1,6 @@
 //! Schemas of various messages sent over SpacetimeDB's HTTP and WebSocket APIs.
 
+pub mod e2e;
 pub mod energy;
 pub mod http;
 pub mod name;
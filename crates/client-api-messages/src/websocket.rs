@@ -13,17 +13,33 @@
 //!
 //! Changes to the Rust SDK are not necessarily required, as it depends on this crate
 //! rather than using an external mirror of this schema.
+//!
+//! ## Compatibility of text messages
+//!
+//! So that the protocol can evolve, servers are lenient about [`ClientMessage`]s
+//! they receive over the text protocol, [`TEXT_PROTOCOL`]:
+//!
+//! - Fields a server doesn't know about, at any level of nesting, are ignored.
+//!   SDKs may therefore send new, optional fields to older servers,
+//!   but must not rely on older servers acting on them.
+//! - A message type a server doesn't know about is rejected
+//!   with an error naming the message type and the protocol the server supports,
+//!   after which the server closes the connection.
+//!
+//! Messages sent over the binary protocol, [`BIN_PROTOCOL`], are not self-describing,
+//! and so any change to them requires a new protocol version.
 
 use crate::energy::EnergyQuanta;
 use bytes::Bytes;
 use bytestring::ByteString;
 use core::{
-    fmt::Debug,
+    fmt::{self, Debug},
     ops::{Deref, Range},
+    str::FromStr,
 };
 use enum_as_inner::EnumAsInner;
 use smallvec::SmallVec;
-use spacetimedb_lib::{ConnectionId, Identity, TimeDuration, Timestamp};
+use spacetimedb_lib::{ConnectionId, DatabaseFeature, DatabaseFeatures, Identity, TimeDuration, Timestamp};
 use spacetimedb_primitives::TableId;
 use spacetimedb_sats::{
     bsatn::{self, ToBsatn},
@@ -39,6 +55,165 @@ use std::{
 
 pub const TEXT_PROTOCOL: &str = "v1.json.spacetimedb";
 pub const BIN_PROTOCOL: &str = "v1.bsatn.spacetimedb";
+pub const TEXT_PROTOCOL_V2: &str = "v2.json.spacetimedb";
+pub const BIN_PROTOCOL_V2: &str = "v2.bsatn.spacetimedb";
+
+/// The header of the response to a websocket upgrade which tells the client
+/// how the reasons of the close frames it's sent, and the errors reported to it, are formatted:
+/// `legacy`, as the plain text of older versions, or `structured`, e.g. as JSON close payloads.
+pub const ERROR_FORMAT_HEADER: &str = "spacetime-error-format";
+
+/// The header of the response to a websocket upgrade which carries the token
+/// with which the client can resume its session should its connection drop,
+/// if it asked for a resumable session and the server keeps sessions for clients to resume.
+pub const RESUME_TOKEN_HEADER: &str = "spacetime-resume-token";
+
+/// The header of the response to a websocket upgrade of a client which presented a resume token:
+/// `true` if the client resumed its session, and is sent what was queued for it meanwhile,
+/// or `false` if the session could no longer be resumed, and the client has to subscribe anew.
+pub const SESSION_RESUMED_HEADER: &str = "spacetime-session-resumed";
+
+/// The header of the response to a websocket upgrade which tells the client
+/// which [`DatabaseFeature`]s of its database the connection negotiated,
+/// as a structured field dictionary (RFC 8941) of the names of features to booleans,
+/// e.g. `delta-updates=?1, acknowledged-delivery=?0`, see [`encode_features`].
+///
+/// Clients should ignore the features they don't know of, which newer servers may send.
+pub const FEATURES_HEADER: &str = "spacetime-features";
+
+/// Encode `features` for the [`FEATURES_HEADER`], listing every feature known to the server.
+pub fn encode_features(features: DatabaseFeatures) -> String {
+    DatabaseFeature::ALL
+        .iter()
+        .map(|&feature| {
+            let on = if features.contains(feature) { "?1" } else { "?0" };
+            format!("{}={on}", feature.name())
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Decode the [`FEATURES_HEADER`], ignoring the features unknown to this version, and anything malformed.
+///
+/// A feature without a value is on, as in RFC 8941.
+pub fn parse_features(header: &str) -> DatabaseFeatures {
+    header
+        .split(',')
+        .filter_map(|member| {
+            let (name, value) = member.split_once('=').unwrap_or((member, "?1"));
+            (value.trim() == "?1").then(|| DatabaseFeature::from_name(name.trim()))?
+        })
+        .collect()
+}
+
+/// The code of the close frame sent to a client which sent more messages than the server would queue for handling,
+/// if the server closes such connections rather than rejecting the excess messages.
+pub const QUEUE_OVERFLOW_CLOSE_CODE: u16 = 4429;
+
+/// Why the server closed a client's connection, for the causes a client is likely to act on,
+/// e.g. in deciding whether to reconnect, each sent as the code of its own close frame.
+///
+/// The codes are in the range reserved for private use by RFC 6455.
+/// They're sent to clients of the `structured` [error format](ERROR_FORMAT_HEADER),
+/// while clients of the `legacy` format are sent the generic codes of older versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum DisconnectReason {
+    /// The database's module exited, e.g. because it was updated, crashed or was deleted.
+    ModuleExited = 4000,
+    /// The client didn't answer a ping before the next one was due.
+    LivenessTimeout = 4001,
+    /// Sending a message or a ping to the client took too long, e.g. because the client stopped reading.
+    SendTimeout = 4002,
+    /// The client sent a message larger than the server accepts.
+    MessageTooLarge = 4003,
+    /// The database ran out of energy.
+    OutOfEnergy = 4004,
+    /// The credentials the client authenticated with expired.
+    AuthExpired = 4005,
+    /// The client sent more messages than the server would queue for handling.
+    QueueOverflow = QUEUE_OVERFLOW_CLOSE_CODE,
+    /// The client's messages stopped arriving for too long, though it still answered pings
+    /// and was sent messages, e.g. because a NAT on its path half-broke the connection.
+    ConnectionDegraded = 4006,
+    /// The client read the messages sent to it too slowly, letting them back up beyond what its database allows.
+    TooSlow = 4007,
+    /// The owner of the client's database kicked it.
+    KickedByAdmin = 4008,
+}
+
+impl DisconnectReason {
+    const ALL: [Self; 10] = [
+        Self::ModuleExited,
+        Self::LivenessTimeout,
+        Self::SendTimeout,
+        Self::MessageTooLarge,
+        Self::OutOfEnergy,
+        Self::AuthExpired,
+        Self::QueueOverflow,
+        Self::ConnectionDegraded,
+        Self::TooSlow,
+        Self::KickedByAdmin,
+    ];
+
+    /// The code of the close frames sent for this reason.
+    pub const fn code(self) -> u16 {
+        self as u16
+    }
+
+    /// The reason the code of a close frame stands for, if it's one of ours.
+    pub fn from_code(code: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|reason| reason.code() == code)
+    }
+}
+
+/// How a client should go about retrying after the server closed its connection or refused its request,
+/// as sent in structured close payloads and errors.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryHint {
+    /// Retry right away, e.g. because a new module is being published.
+    Immediately,
+    /// Retry with backoff, e.g. because the module crashed or the server is under load.
+    Backoff,
+    /// Don't retry, e.g. because the database was deleted.
+    Never,
+}
+
+/// The version of the websocket protocol, negotiated along with its format,
+/// e.g. [`BIN_PROTOCOL`] is version 1 and [`BIN_PROTOCOL_V2`] is version 2.
+///
+/// The versions differ in how each [`ServerMessage`] is framed:
+///
+/// - In version 1, binary messages are prefixed with a one-byte compression tag,
+///   one of the `SERVER_MSG_COMPRESSION_TAG_*` constants other than [`SERVER_MSG_COMPRESSION_TAG_ZSTD`],
+///   while text messages are bare JSON.
+/// - In version 2, binary messages are prefixed with the tag of the [`Compression`] actually applied,
+///   see [`Compression::tag`], which may also be [`SERVER_MSG_COMPRESSION_TAG_ZSTD`],
+///   while text messages are wrapped in a [`TextEnvelope`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ProtocolVersion {
+    #[default]
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Returns whether messages can be compressed with `compression` in this version.
+    pub fn supports(self, compression: Compression) -> bool {
+        self == Self::V2 || compression != Compression::Zstd
+    }
+}
+
+/// The framing of a text [`ServerMessage`] in [`ProtocolVersion::V2`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct TextEnvelope<M> {
+    /// The compression actually applied to `message`.
+    ///
+    /// Text messages are currently never compressed, so this is always [`Compression::None`].
+    pub compression: Compression,
+    pub message: M,
+}
 
 pub trait RowListLen {
     /// Returns the length of the list.
@@ -91,7 +266,7 @@ pub trait WebsocketFormat: Sized {
 
     /// The type used to encode query updates.
     /// This type exists so that some formats, e.g., BSATN, can compress an update.
-    type QueryUpdate: SpacetimeType + for<'de> Deserialize<'de> + Serialize + Debug + Clone + Send;
+    type QueryUpdate: SpacetimeType + for<'de> Deserialize<'de> + Serialize + ByteListLen + Debug + Clone + Send;
 
     /// Convert a `QueryUpdate` into `Self::QueryUpdate`.
     /// This allows some formats to e.g., compress the update.
@@ -116,6 +291,28 @@ pub enum ClientMessage<Args> {
     /// Remove a subscription to a SQL query that was added with SubscribeSingle.
     Unsubscribe(Unsubscribe),
     UnsubscribeMulti(UnsubscribeMulti),
+    /// Subscribe as with `SubscribeMulti`,
+    /// but receive a `SubscribeMultiExplained` in place of the `SubscribeMultiApplied`,
+    /// also describing how each query is evaluated.
+    ExplainSubscribeMulti(SubscribeMulti),
+    /// Switch the connection to the identity of another token.
+    Authenticate(Authenticate),
+    /// Acknowledge having applied an `AcknowledgedUpdate`.
+    Ack(Ack),
+    /// Ask for a `ConnectionStatus` describing the connection.
+    GetConnectionStatus(GetConnectionStatus),
+    /// Register a SQL query as with `SubscribeSingle`,
+    /// but initially receive only its newest rows.
+    SubscribeWindow(SubscribeWindow),
+    /// Change the settings of the connection which may change mid-session, e.g. its compression.
+    UpdateSettings(UpdateSettings),
+    /// Replace the token of the connection's identity with a newer one, e.g. before it expires.
+    RefreshToken(RefreshToken),
+    /// Send a one-off SQL query as with `OneOffQuery`,
+    /// but receive its rows in chunks tagged with its `request_id`.
+    Query(Query),
+    /// Grant the server credits to spend on sending updates, if the client negotiated flow control when connecting.
+    GrantCredits(GrantCredits),
 }
 
 impl<Args> ClientMessage<Args> {
@@ -126,11 +323,13 @@ impl<Args> ClientMessage<Args> {
                 args,
                 request_id,
                 flags,
+                traceparent,
             }) => ClientMessage::CallReducer(CallReducer {
                 reducer,
                 args: f(args),
                 request_id,
                 flags,
+                traceparent,
             }),
             ClientMessage::OneOffQuery(x) => ClientMessage::OneOffQuery(x),
             ClientMessage::SubscribeSingle(x) => ClientMessage::SubscribeSingle(x),
@@ -138,6 +337,15 @@ impl<Args> ClientMessage<Args> {
             ClientMessage::Subscribe(x) => ClientMessage::Subscribe(x),
             ClientMessage::SubscribeMulti(x) => ClientMessage::SubscribeMulti(x),
             ClientMessage::UnsubscribeMulti(x) => ClientMessage::UnsubscribeMulti(x),
+            ClientMessage::ExplainSubscribeMulti(x) => ClientMessage::ExplainSubscribeMulti(x),
+            ClientMessage::Authenticate(x) => ClientMessage::Authenticate(x),
+            ClientMessage::Ack(x) => ClientMessage::Ack(x),
+            ClientMessage::GetConnectionStatus(x) => ClientMessage::GetConnectionStatus(x),
+            ClientMessage::SubscribeWindow(x) => ClientMessage::SubscribeWindow(x),
+            ClientMessage::UpdateSettings(x) => ClientMessage::UpdateSettings(x),
+            ClientMessage::RefreshToken(x) => ClientMessage::RefreshToken(x),
+            ClientMessage::Query(x) => ClientMessage::Query(x),
+            ClientMessage::GrantCredits(x) => ClientMessage::GrantCredits(x),
         }
     }
 }
@@ -165,6 +373,12 @@ pub struct CallReducer<Args> {
     /// that the caller does not want to be notified about the reducer
     /// without being subscribed to any relevant queries.
     pub flags: CallReducerFlags,
+    /// A [W3C `traceparent`](https://www.w3.org/TR/trace-context/#traceparent-header)
+    /// naming the caller's span, under which the server records the spans of the call.
+    ///
+    /// If absent or malformed, the call starts a new trace.
+    /// Either way, the trace id is echoed in [`ReducerCallInfo::trace_id`].
+    pub traceparent: Option<Box<str>>,
 }
 
 #[derive(Clone, Copy, Default, PartialEq, Eq)]
@@ -192,6 +406,114 @@ impl_deserialize!([] CallReducerFlags, de => match de.deserialize_u8()? {
     x => Err(D::Error::custom(format_args!("invalid call reducer flag {x}"))),
 });
 
+/// Sent by client to re-authenticate the connection as the identity of `token`,
+/// without reconnecting.
+///
+/// If the token is valid and for a different identity,
+/// the connection is disconnected from the database as its current identity,
+/// losing all of its subscriptions, and connected as the new identity
+/// under a new connection id.
+/// The client then receives an `IdentityToken` message for the new identity,
+/// after which it receives no messages on behalf of the old identity.
+///
+/// If the token is valid and for the current identity, the client receives nothing,
+/// and the connection is kept open until the new token expires,
+/// which is how a client refreshes its token before the connection is closed as `AuthExpired`.
+///
+/// If the token is invalid, the client receives a failed `TransactionUpdate`,
+/// and the connection keeps its current identity.
+#[derive(SpacetimeType)]
+#[sats(crate = spacetimedb_lib)]
+pub struct Authenticate {
+    /// The token to authenticate with, as would be passed in the `Authorization` header.
+    pub token: Box<str>,
+}
+
+/// Acknowledges that the client applied the `AcknowledgedUpdate` with `delivery_id`,
+/// so that it's not delivered again.
+///
+/// Acknowledgments of unknown deliveries, or of deliveries to another connection, are ignored.
+#[derive(SpacetimeType, Debug, Clone, Copy)]
+#[sats(crate = spacetimedb_lib)]
+pub struct Ack {
+    pub delivery_id: u64,
+}
+
+/// Sent by client to change the settings of the connection which may change mid-session,
+/// e.g. to drop to light, compressed updates when its network degrades, without reconnecting.
+///
+/// Settings left `None` are kept as they are.
+/// The server answers with a `SettingsUpdated` carrying the settings in effect,
+/// or refuses the change as a whole, e.g. a compression the connection's protocol version doesn't support,
+/// with an error the client can recover from.
+///
+/// The new settings apply to the messages the server prepares after the change.
+/// Those it had already queued for the client, which may include messages sent after the `SettingsUpdated`,
+/// keep the detail they were prepared with, e.g. a full `TransactionUpdate`,
+/// and may keep the compression of the old settings, which their tag says, as for any binary message.
+#[derive(SpacetimeType, Debug, Clone, Copy)]
+#[sats(crate = spacetimedb_lib)]
+pub struct UpdateSettings {
+    /// An identifier for a client request.
+    ///
+    /// The server will include the same ID in the response `SettingsUpdated`.
+    pub request_id: u32,
+    /// The compression of messages to the client, if it's to change.
+    pub compression: Option<Compression>,
+    /// Whether the client is sent full `TransactionUpdate`s rather than light ones, if it's to change.
+    pub tx_update_full: Option<bool>,
+    /// The length, in bytes, of a serialized message beyond which it's compressed, if it's to change.
+    pub compression_threshold: Option<u64>,
+}
+
+/// Sent by client to replace the token the connection was authenticated with by `token`, for the same identity,
+/// so that a long-lived connection rides across token rotations without reconnecting.
+///
+/// The server answers with a `TokenRefreshed` carrying when `token` expires,
+/// after which the connection is kept open until then rather than until its previous token expires.
+/// A token which is invalid, e.g. has expired, or is for another identity than the connection's,
+/// is refused with an error the client can recover from, and the connection keeps its previous token.
+/// Unlike `Authenticate`, the connection never switches to another identity.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct RefreshToken {
+    /// An identifier for a client request.
+    ///
+    /// The server will include the same ID in the response `TokenRefreshed`.
+    pub request_id: u32,
+    /// The new token, as would be passed in the `Authorization` header.
+    pub token: Box<str>,
+}
+
+/// Sent by client to grant the server `credits` more to spend on sending it updates,
+/// if it negotiated flow control when connecting.
+///
+/// Each update sent spends credits by its size in bytes.
+/// Once the credits are spent, the server holds back the client's updates, coalescing them,
+/// until it's granted more, after which the held updates are sent.
+/// Messages other than updates, e.g. replies to the client's requests, don't spend credits,
+/// though they're held back behind any update held back before them.
+/// A client which didn't negotiate flow control has its grants ignored.
+#[derive(SpacetimeType, Debug, Clone, Copy)]
+#[sats(crate = spacetimedb_lib)]
+pub struct GrantCredits {
+    /// How many credits to add to those the server has left.
+    pub credits: u64,
+}
+
+/// Sent by client to ask for a `ConnectionStatus` describing the connection.
+///
+/// The status is answered by the connection itself, without involving the database's module,
+/// so it's cheap enough to poll, e.g. to drive a client's health indicator.
+#[derive(SpacetimeType, Debug, Clone, Copy)]
+#[sats(crate = spacetimedb_lib)]
+pub struct GetConnectionStatus {
+    /// An identifier for a client request.
+    ///
+    /// The server will include the same ID in the response `ConnectionStatus`.
+    pub request_id: u32,
+}
+
 /// An opaque id generated by the client to refer to a subscription.
 /// This is used in Unsubscribe messages and errors.
 #[derive(SpacetimeType, Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -266,6 +588,104 @@ pub struct SubscribeMulti {
     pub query_id: QueryId,
 }
 
+/// Sent by client to subscribe to a single query like `SubscribeSingle`,
+/// but to initially receive only the newest of its current rows,
+/// e.g. the last 50 messages of a chat.
+///
+/// The client receives a `SubscribeWindowApplied` containing those rows and the effective window.
+/// Afterwards, it receives `TransactionUpdate`s as for `SubscribeSingle`,
+/// including updates to rows older than the window, unless `older_rows` is [`OlderRows::Suppress`].
+///
+/// The subscription is removed with an `Unsubscribe` message.
+/// Like for `SubscribeSingle`, the `UnsubscribeApplied` lists every row currently matching the query,
+/// so it may delete rows the client was never sent.
+#[derive(SpacetimeType)]
+#[sats(crate = spacetimedb_lib)]
+pub struct SubscribeWindow {
+    /// A single SQL `SELECT` query to subscribe to.
+    pub query: Box<str>,
+    /// An identifier for a client request.
+    pub request_id: u32,
+    /// An identifier for this subscription, as for `SubscribeSingle`.
+    pub query_id: QueryId,
+    /// Which of the query's rows to send initially.
+    pub initial: InitialWindow,
+    /// Whether to send updates to rows older than the window.
+    ///
+    /// Encoded as 0 to deliver them or 1 to suppress them.
+    pub older_rows: OlderRows,
+}
+
+/// The newest `limit` rows of a query, ordered by the column `order_by`,
+/// written `last(limit, order_by)`.
+///
+/// The column must belong to the table the query returns, and must be indexed.
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct InitialWindow {
+    pub limit: u32,
+    pub order_by: Box<str>,
+}
+
+impl fmt::Display for InitialWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "last({}, {})", self.limit, self.order_by)
+    }
+}
+
+/// An [`InitialWindow`] couldn't be parsed.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("invalid window `{0}`, expected `last(N, column)`")]
+pub struct ParseWindowError(Box<str>);
+
+impl FromStr for InitialWindow {
+    type Err = ParseWindowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseWindowError(s.into());
+        let args = s
+            .trim()
+            .strip_prefix("last")
+            .map(str::trim_start)
+            .and_then(|s| s.strip_prefix('('))
+            .and_then(|s| s.strip_suffix(')'))
+            .ok_or_else(err)?;
+        let (limit, order_by) = args.split_once(',').ok_or_else(err)?;
+        let limit = limit.trim().parse().map_err(|_| err())?;
+        let order_by = order_by.trim();
+        if order_by.is_empty() || order_by.contains(|c: char| c.is_whitespace() || c == ',') {
+            return Err(err());
+        }
+        Ok(Self {
+            limit,
+            order_by: order_by.into(),
+        })
+    }
+}
+
+/// Whether a [`SubscribeWindow`] subscription is sent updates to rows older than its window.
+///
+/// A row is older than the window if its `order_by` column is less than that of every row in the window.
+/// Rows inserted later, and updates to rows in the window, are always sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OlderRows {
+    /// Send updates to older rows, as for any other subscription.
+    ///
+    /// This is the default.
+    #[default]
+    Deliver,
+    /// Leave out updates to older rows, which the client doesn't have.
+    Suppress,
+}
+
+impl_st!([] OlderRows, AlgebraicType::U8);
+impl_serialize!([] OlderRows, (self, ser) => ser.serialize_u8(*self as u8));
+impl_deserialize!([] OlderRows, de => match de.deserialize_u8()? {
+    0 => Ok(Self::Deliver),
+    1 => Ok(Self::Suppress),
+    x => Err(D::Error::custom(format_args!("invalid older rows setting {x}"))),
+});
+
 /// Client request for removing a query from a subscription.
 #[derive(SpacetimeType)]
 #[sats(crate = spacetimedb_lib)]
@@ -303,6 +723,25 @@ pub struct OneOffQuery {
     pub query_string: Box<str>,
 }
 
+/// Sent by client to run a one-off SQL query, read-only and with the client's permissions,
+/// without establishing a subscription.
+///
+/// The server answers with one or more `QueryRows` messages carrying the matching rows,
+/// the last of which has `last` set,
+/// or, if the query fails, e.g. as it reads a private table or takes too long, with a `RequestError`.
+/// Unlike `OneOffQuery`, a large result is split over several messages,
+/// and the query may be cut short by the server's limits on how long it may run and how many rows it may match.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct Query {
+    /// An identifier for a client request.
+    ///
+    /// The server will include the same ID in each of the responses.
+    pub request_id: u32,
+    /// The query, a single `SELECT` statement.
+    pub query_string: Box<str>,
+}
+
 /// The tag recognized by the host and SDKs to mean no compression of a [`ServerMessage`].
 pub const SERVER_MSG_COMPRESSION_TAG_NONE: u8 = 0;
 
@@ -312,6 +751,11 @@ pub const SERVER_MSG_COMPRESSION_TAG_BROTLI: u8 = 1;
 /// The tag recognized by the host and SDKs to mean brotli compression  of a [`ServerMessage`].
 pub const SERVER_MSG_COMPRESSION_TAG_GZIP: u8 = 2;
 
+/// The tag recognized by the host and SDKs to mean zstd compression of a [`ServerMessage`].
+///
+/// Only sent to clients which negotiated [`ProtocolVersion::V2`].
+pub const SERVER_MSG_COMPRESSION_TAG_ZSTD: u8 = 3;
+
 /// Messages sent from the server to the client.
 #[derive(SpacetimeType, derive_more::From)]
 #[sats(crate = spacetimedb_lib)]
@@ -337,6 +781,47 @@ pub enum ServerMessage<F: WebsocketFormat> {
     SubscribeMultiApplied(SubscribeMultiApplied<F>),
     /// Sent in response to an `UnsubscribeMulti` message. This contains the matching rows.
     UnsubscribeMultiApplied(UnsubscribeMultiApplied<F>),
+    /// Sent in response to an `ExplainSubscribeMulti` message. This contains the initial matching rows,
+    /// and the plans chosen for the queries.
+    SubscribeMultiExplained(SubscribeMultiExplained<F>),
+    /// Sent periodically to owners of the database who asked for storage statistics when connecting.
+    DatabaseStats(DatabaseStats),
+    /// Sent to the caller of a reducer after the `TransactionUpdate` for the call,
+    /// if it's an owner of the database who asked for reducer timings when connecting.
+    ReducerTimings(ReducerTimings),
+    /// Sent when the connection nears the longest the server lets connections live,
+    /// asking the client to reconnect before the connection is closed.
+    ReconnectRequested(ReconnectRequested),
+    /// Upon run of a reducer the module declared acknowledged,
+    /// if the client opted into acknowledged delivery when connecting.
+    AcknowledgedUpdate(AcknowledgedUpdate<F>),
+    /// Sent in response to a `GetConnectionStatus` message.
+    ConnectionStatus(ConnectionStatus),
+    /// Sent in place of a message the server couldn't send.
+    MessageDropped(MessageDropped),
+    /// Sent when the client uses a deprecated feature, once per feature per connection.
+    DeprecationNotice(DeprecationNotice),
+    /// Sent in response to a `SubscribeWindow` message. This contains the newest matching rows.
+    SubscribeWindowApplied(SubscribeWindowApplied<F>),
+    /// Sent to the caller of a reducer while it's still running, each time it reports its progress.
+    CallProgress(CallProgress),
+    /// Sent when the client's messages have stopped arriving for a while, though it's still sent messages,
+    /// suggesting it reconnect.
+    ConnectionDegraded(ConnectionDegraded),
+    /// Sent once the client has caught up after updates to some of its queries were skipped
+    /// while it was too far behind to accept them, telling it which to resubscribe to.
+    ResyncRequired(ResyncRequired),
+    /// Sent when the server couldn't handle one of the client's messages, for a reason it can recover from,
+    /// if the client asked for request errors when connecting.
+    RequestError(RequestError),
+    /// Sent in response to an `UpdateSettings` message, carrying the settings now in effect.
+    SettingsUpdated(SettingsUpdated),
+    /// Sent in response to a `RefreshToken` message, once the connection's token has been replaced.
+    TokenRefreshed(TokenRefreshed),
+    /// Sent in response to a `Query` message, carrying a chunk of its rows.
+    QueryRows(QueryRows<F>),
+    /// After the `IdentityToken`, to inform the client of what the server supports.
+    ServerCapabilities(ServerCapabilities),
 }
 
 /// The matching rows of a subscription query.
@@ -365,6 +850,37 @@ pub struct SubscribeApplied<F: WebsocketFormat> {
     pub rows: SubscribeRows<F>,
 }
 
+/// Response to [`SubscribeWindow`] containing the newest matching rows.
+#[derive(SpacetimeType)]
+#[sats(crate = spacetimedb_lib)]
+pub struct SubscribeWindowApplied<F: WebsocketFormat> {
+    /// The request_id of the corresponding `SubscribeWindow` message.
+    pub request_id: u32,
+    /// The overall time between the server receiving a request and sending the response.
+    pub total_host_execution_duration_micros: u64,
+    /// An identifier for the subscribed query sent by the client.
+    pub query_id: QueryId,
+    /// The rows in the window, newest first.
+    pub rows: SubscribeRows<F>,
+    /// The window the rows were chosen by.
+    pub window: AppliedWindow,
+}
+
+/// The window of rows sent in a [`SubscribeWindowApplied`].
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct AppliedWindow {
+    /// The window requested.
+    pub initial: InitialWindow,
+    /// Whether the query matched rows older than those sent,
+    /// which the client can fetch with a `OneOffQuery`, e.g. to load more of them.
+    pub has_older_rows: bool,
+    /// Whether updates to rows older than the window will be sent.
+    ///
+    /// If the query has no older rows, there's nothing to suppress, and this is always [`OlderRows::Deliver`].
+    pub older_rows: OlderRows,
+}
+
 /// Server response to a client [`Unsubscribe`] request.
 #[derive(SpacetimeType)]
 #[sats(crate = spacetimedb_lib)]
@@ -428,6 +944,351 @@ pub struct SubscribeMultiApplied<F: WebsocketFormat> {
     pub update: DatabaseUpdate<F>,
 }
 
+/// Response to [`ClientMessage::ExplainSubscribeMulti`] containing the initial matching rows,
+/// as a [`SubscribeMultiApplied`] would, and the plans chosen for the queries.
+#[derive(SpacetimeType)]
+#[sats(crate = spacetimedb_lib)]
+pub struct SubscribeMultiExplained<F: WebsocketFormat> {
+    /// The request_id of the corresponding `ExplainSubscribeMulti` message.
+    pub request_id: u32,
+    /// The overall time between the server receiving a request and sending the response.
+    pub total_host_execution_duration_micros: u64,
+    /// An identifier for the subscribed query sent by the client.
+    pub query_id: QueryId,
+    /// The matching rows for this query.
+    pub update: DatabaseUpdate<F>,
+    /// A JSON description of the plan for each query fragment.
+    ///
+    /// A query may be evaluated as several fragments,
+    /// e.g. if its table has more than one row level security rule.
+    ///
+    /// Plans are only described to the owner of the database.
+    /// For anyone else, this is empty.
+    pub plans: Box<[Box<str>]>,
+}
+
+/// Asks the client to reconnect at a convenient moment before `deadline`,
+/// at which the server closes the connection because it reached its maximum lifetime.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct ReconnectRequested {
+    /// When the connection will be closed.
+    pub deadline: Timestamp,
+    /// A reconnect token, standing in for the client's credentials when reconnecting
+    /// to the same database, if the server issues them.
+    ///
+    /// It remains valid for a short while after `deadline`.
+    pub reconnect_token: Option<Box<str>>,
+}
+
+/// A `TransactionUpdate` which the client must acknowledge with an `Ack` once it has applied it.
+///
+/// Until then, the update is delivered again, with the same `delivery_id`,
+/// whenever the client reconnects with the same identity and protocol,
+/// so a client which remembers the ids it has applied applies each update exactly once.
+#[derive(SpacetimeType, Debug)]
+#[sats(crate = spacetimedb_lib)]
+pub struct AcknowledgedUpdate<F: WebsocketFormat> {
+    /// Identifies the delivery of `update` to the client's identity.
+    pub delivery_id: u64,
+    pub update: TransactionUpdate<F>,
+}
+
+/// What the server knows about a connection, as of answering a `GetConnectionStatus`.
+///
+/// Queue depths and byte counts are snapshots, and may be stale by the time the client reads them.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct ConnectionStatus {
+    /// The `request_id` of the `GetConnectionStatus` message.
+    pub request_id: u32,
+    /// The negotiated websocket subprotocol, e.g. [`BIN_PROTOCOL_V2`].
+    pub protocol: Box<str>,
+    /// The negotiated [`Compression`], e.g. `"Brotli"`.
+    pub compression: Box<str>,
+    /// Whether the client asked for light transaction updates.
+    pub light: bool,
+    /// Whether the client asked for reducer timings.
+    pub reducer_timings: bool,
+    /// Whether the client opted into acknowledged delivery.
+    pub acknowledged_delivery: bool,
+    /// The round trip time of the most recent ping the server sent and the client answered.
+    ///
+    /// `None` if the client hasn't yet answered a ping.
+    pub round_trip_time: Option<TimeDuration>,
+    /// The number of messages waiting to be sent to the client.
+    pub outgoing_queue_len: u64,
+    /// The total size of the messages waiting to be sent to the client.
+    pub outgoing_queue_bytes: u64,
+    /// How long the outgoing queue has been non-empty, or `None` if it's empty.
+    pub outgoing_queue_age: Option<TimeDuration>,
+    /// The number of messages received from the client and waiting to be handled.
+    pub incoming_queue_len: u64,
+    /// The number of the client's active subscriptions, single, multi and legacy.
+    pub subscriptions: u64,
+    /// The number of bytes sent to the client in websocket messages.
+    pub bytes_sent: u64,
+    /// The number of bytes received from the client in websocket messages.
+    pub bytes_received: u64,
+    /// How long the connection has been open.
+    pub uptime: TimeDuration,
+    /// How long until the server closes the connection for having reached its maximum lifetime,
+    /// or `None` if the server doesn't limit the lifetime of connections.
+    pub lifetime_remaining: Option<TimeDuration>,
+}
+
+/// Tells the client that the server dropped a message instead of sending it,
+/// so the client's view of its subscriptions may be out of date.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct MessageDropped {
+    /// Why the message was dropped.
+    pub reason: MessageDropReason,
+    /// The kind of the dropped message, e.g. `"TransactionUpdate"`.
+    pub message_kind: Box<str>,
+}
+
+/// Warns the client that it used a feature which is deprecated,
+/// so that SDKs can surface it to developers before the feature is removed.
+///
+/// Sent at most once per feature per connection,
+/// either right after the `IdentityToken`, for features used when connecting,
+/// or after the client message which used the feature.
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct DeprecationNotice {
+    /// Identifies the deprecated feature, e.g. `"client_connection_id"`.
+    pub feature: Box<str>,
+    /// What's deprecated and what to do instead, for developers to read.
+    pub message: Box<str>,
+    /// The version of SpacetimeDB which is to remove the feature,
+    /// or `None` if its removal isn't scheduled for a particular version yet.
+    pub removal_version: Option<Box<str>>,
+}
+
+/// Warns the client that none of its messages have arrived for `last_inbound_ms`,
+/// though it still answers pings and the server still sends it messages,
+/// as when a NAT on its path lets traffic through in only one direction.
+///
+/// The connection is effectively read-only, so SDKs may recommend reconnecting.
+/// Sent once each time the client's messages stop arriving for long enough,
+/// after which the server may close the connection should they still not arrive.
+#[derive(SpacetimeType, Debug, Clone, Copy, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct ConnectionDegraded {
+    /// How long ago the server last received a message from the client, in milliseconds.
+    pub last_inbound_ms: u64,
+}
+
+/// Tells the client that the server skipped the updates to some of its queries
+/// while too many messages were queued for it, under its database's `resync` send backlog policy,
+/// so its view of them is out of date.
+///
+/// Sent once the client has caught up, at most once each time it falls behind.
+/// To resync, the client should unsubscribe from the queries and subscribe to them again.
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct ResyncRequired {
+    /// The subscriptions which missed updates.
+    pub query_ids: Box<[QueryId]>,
+    /// Whether the client's legacy subscription, made with `Subscribe`, missed updates.
+    pub legacy: bool,
+}
+
+/// Tells the client that the server couldn't handle one of its messages,
+/// for a reason the client can recover from, so the connection stays open.
+///
+/// Sent to clients which asked for request errors when connecting,
+/// in place of closing the connection, e.g. for a message which couldn't be decoded,
+/// or of replying with a failed `TransactionUpdate`, e.g. for a call to a reducer which doesn't exist.
+/// Failures the client can't recover from, e.g. speaking another protocol than the one negotiated,
+/// still close the connection.
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct RequestError {
+    /// What went wrong.
+    pub code: RequestErrorCode,
+    /// The ID of the request which failed, if it's known, e.g. of a reducer call.
+    ///
+    /// Unknown for a message which couldn't be decoded, or which was refused without being decoded.
+    pub request_id: Option<u32>,
+    /// A description of the error, for developers rather than for SDKs to match on.
+    pub message: Box<str>,
+}
+
+/// What went wrong handling a client's message, as told by a [`RequestError`].
+#[derive(SpacetimeType, Debug, Clone, Copy, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub enum RequestErrorCode {
+    /// The message couldn't be decoded, e.g. it was malformed.
+    DecodeFailure,
+    /// The message called a reducer which the module doesn't define.
+    NoSuchReducer,
+    /// The message called a reducer with arguments which don't match its parameters.
+    ArgsMismatch,
+    /// The message was refused, as the client sent messages faster than it may.
+    RateLimited,
+    /// The message was refused, as too many of the client's messages were waiting to be handled.
+    QueueFull,
+    /// The message asked for settings the connection can't have, e.g. a compression its protocol doesn't support.
+    InvalidSettings,
+    /// The message carried a token which couldn't be validated, e.g. as it has expired.
+    InvalidToken,
+    /// The message carried a token for another identity than the connection's.
+    IdentityMismatch,
+    /// The message's query couldn't be evaluated, e.g. as it's malformed or names a table which doesn't exist.
+    QueryFailed,
+    /// The message's query reads a table which the client may not, e.g. a private table.
+    PermissionDenied,
+    /// The message's query ran for longer than the server lets queries run.
+    QueryTimeout,
+    /// The message's query matched more rows than the server lets queries return.
+    TooManyRows,
+}
+
+/// The settings of a connection now in effect, in response to an `UpdateSettings` message.
+#[derive(SpacetimeType, Debug, Clone, Copy, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct SettingsUpdated {
+    /// The `request_id` of the `UpdateSettings` message.
+    pub request_id: u32,
+    /// The compression of messages to the client.
+    pub compression: Compression,
+    /// Whether the client is sent full `TransactionUpdate`s rather than light ones.
+    pub tx_update_full: bool,
+    /// The length, in bytes, of a serialized message beyond which it's compressed.
+    pub compression_threshold: u64,
+}
+
+/// The connection's token has been replaced, in response to a `RefreshToken` message.
+#[derive(SpacetimeType, Debug, Clone, Copy, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct TokenRefreshed {
+    /// The `request_id` of the `RefreshToken` message.
+    pub request_id: u32,
+    /// When the new token expires, if it does.
+    pub expires_at: Option<Timestamp>,
+}
+
+/// Why the server dropped a message.
+#[derive(SpacetimeType, Debug, Clone, Copy, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub enum MessageDropReason {
+    /// The message couldn't be encoded in the connection's protocol.
+    EncodeError,
+}
+
+/// How long each phase of a reducer call took on the server.
+///
+/// Each phase begins where the previous one ended,
+/// so the phases add up to `total`.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct ReducerTimings {
+    /// The `request_id` of the `CallReducer` message.
+    pub request_id: u32,
+    /// From starting to decode the arguments until subscriptions were evaluated.
+    pub total: TimeDuration,
+    /// Decoding the reducer's arguments.
+    pub decode: TimeDuration,
+    /// Waiting for the module to be free to run the reducer.
+    pub wait: TimeDuration,
+    /// Running the reducer, including beginning its transaction.
+    pub execute: TimeDuration,
+    /// Committing, or rolling back, the reducer's transaction.
+    pub commit: TimeDuration,
+    /// Evaluating subscriptions against the reducer's changes.
+    pub subscription_eval: TimeDuration,
+}
+
+/// The progress a reducer reported while running, sent to its caller before the call's reply.
+///
+/// Progress is reported outside of the reducer's transaction,
+/// so it's sent even if the reducer then fails,
+/// and it isn't ordered with respect to transaction updates.
+/// The server may drop progress reported too often, or while the caller's queue is backed up,
+/// so clients should treat each one as superseding the last.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct CallProgress {
+    /// The `request_id` of the `CallReducer` message.
+    pub request_id: u32,
+    /// The BSATN-encoded value the reducer reported, of a type agreed between the module and its clients.
+    pub payload: Bytes,
+}
+
+/// Storage statistics of a database, computed from bookkeeping maintained by the datastore
+/// rather than by scanning its tables.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct DatabaseStats {
+    /// Statistics for each table of the database, including system tables.
+    pub tables: Box<[TableStats]>,
+    /// The sum of `bytes` and `index_bytes` over all tables.
+    pub total_bytes: u64,
+    /// The health of the database's firehose, if a sink is attached to it.
+    pub firehose: Option<FirehoseStats>,
+    /// The messages held on the node for the database's clients, to be replayed to them later.
+    pub replay_buffers: ReplayBufferStats,
+}
+
+/// The messages held on a node for the clients of a database, to be replayed to them later.
+///
+/// Byte counts are estimates, from the size of the updates the messages carry.
+#[derive(SpacetimeType, Debug, Clone, Default)]
+#[sats(crate = spacetimedb_lib)]
+pub struct ReplayBufferStats {
+    /// The number of sessions kept for clients which lost their connection to resume.
+    pub parked_sessions: u32,
+    /// The bytes of the messages queued for those sessions meanwhile.
+    pub parked_session_bytes: u64,
+    /// The number of acknowledged updates which their clients have yet to acknowledge.
+    pub pending_deliveries: u64,
+    /// The bytes of those updates.
+    pub pending_delivery_bytes: u64,
+    /// The number of identities with any pending deliveries.
+    pub pending_delivery_identities: u32,
+}
+
+/// The health of the sink attached to a database's firehose,
+/// which is sent a copy of every committed transaction update.
+///
+/// Counts are since the sink was attached.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct FirehoseStats {
+    /// The number of updates waiting to be sent to the sink.
+    pub queue_depth: u32,
+    /// The number of updates sent to the sink.
+    pub sent: u64,
+    /// The number of updates which were never sent,
+    /// as the queue was full when they committed, or the sink failed to send them.
+    pub dropped: u64,
+    /// The last error with which the sink failed to send an update, if it has.
+    pub last_error: Option<Box<str>>,
+}
+
+/// Storage statistics of a single table.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct TableStats {
+    pub table_name: Box<str>,
+    /// The exact number of rows in the table.
+    pub rows: u64,
+    /// The number of bytes occupied by the table's pages and large blobs.
+    ///
+    /// This is an overestimate, as it counts whole pages, including their unused space,
+    /// and counts a blob once for every row which refers to it.
+    pub bytes: u64,
+    /// The number of indexes on the table.
+    pub indexes: u32,
+    /// The number of bytes of keys stored in the table's indexes.
+    ///
+    /// This is an underestimate, as it counts only the data in the keys,
+    /// and not the overhead of the indexes themselves.
+    pub index_bytes: u64,
+}
+
 /// Server response to a client [`Unsubscribe`] request.
 #[derive(SpacetimeType)]
 #[sats(crate = spacetimedb_lib)]
@@ -488,6 +1349,96 @@ pub struct IdentityToken {
     pub connection_id: ConnectionId,
 }
 
+/// Received by client from database right after the `IdentityToken`,
+/// describing what the server supports, as configured, and the connection as negotiated,
+/// so that the client needn't guess from version strings.
+///
+/// Clients which don't know this message may ignore it.
+#[derive(SpacetimeType, Debug, Clone)]
+#[sats(crate = spacetimedb_lib)]
+pub struct ServerCapabilities {
+    /// The negotiated websocket subprotocol, e.g. [`BIN_PROTOCOL_V2`].
+    pub protocol: Box<str>,
+    /// The compressions the client may ask for over this connection, e.g. in an `UpdateSettings`.
+    pub compression: Box<[Compression]>,
+    /// The largest message, in bytes, the client may send.
+    pub max_message_size: u64,
+    /// How often the client is pinged.
+    /// A client which hasn't answered a ping by the time the next is due is disconnected.
+    pub liveness_timeout: TimeDuration,
+    /// How long a send to the client may take before it's disconnected.
+    pub send_timeout: TimeDuration,
+    /// The longest the server lets connections live, or `None` if it doesn't limit their lifetime.
+    pub max_connection_lifetime: Option<TimeDuration>,
+    /// The names of the [`ServerFeature`]s the client may use, including any unknown to the client.
+    pub features: Box<[Box<str>]>,
+    /// The names of the [`DatabaseFeature`]s the connection negotiated, as in the [`FEATURES_HEADER`].
+    pub database_features: Box<[Box<str>]>,
+}
+
+/// A feature of the server which a client may use, listed in [`ServerCapabilities::features`] if it's available.
+///
+/// Unlike a [`DatabaseFeature`], it's up to the server's version and configuration, not the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServerFeature {
+    /// The client may ask for light transaction updates.
+    LightUpdates,
+    /// The client may run one-off queries with `OneOffQuery`.
+    OneOffQueries,
+    /// The client may ask for `RequestError`s when connecting.
+    RequestErrors,
+    /// The client may change its settings mid-session with `UpdateSettings`.
+    UpdateSettings,
+    /// The client may ask for a `ConnectionStatus` with `GetConnectionStatus`.
+    ConnectionStatus,
+    /// The client may resume its session after its connection drops, with the token of the [`RESUME_TOKEN_HEADER`].
+    SessionResume,
+    /// The client is issued a token with which to reconnect without authenticating anew.
+    ReconnectTokens,
+    /// The client may replace its token mid-session with `RefreshToken`.
+    TokenRefresh,
+    /// The client may run one-off queries with `Query`, receiving their rows in chunks.
+    ChunkedQueries,
+    /// The client may negotiate flow control of its updates when connecting, granting credits with `GrantCredits`.
+    FlowControl,
+}
+
+impl ServerFeature {
+    pub const ALL: [Self; 10] = [
+        Self::LightUpdates,
+        Self::OneOffQueries,
+        Self::RequestErrors,
+        Self::UpdateSettings,
+        Self::ConnectionStatus,
+        Self::SessionResume,
+        Self::ReconnectTokens,
+        Self::TokenRefresh,
+        Self::ChunkedQueries,
+        Self::FlowControl,
+    ];
+
+    /// The name of the feature, as sent to clients.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::LightUpdates => "light-updates",
+            Self::OneOffQueries => "one-off-queries",
+            Self::RequestErrors => "request-errors",
+            Self::UpdateSettings => "update-settings",
+            Self::ConnectionStatus => "connection-status",
+            Self::SessionResume => "session-resume",
+            Self::ReconnectTokens => "reconnect-tokens",
+            Self::TokenRefresh => "token-refresh",
+            Self::ChunkedQueries => "chunked-queries",
+            Self::FlowControl => "flow-control",
+        }
+    }
+
+    /// The feature named `name`, if any.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|feature| feature.name() == name)
+    }
+}
+
 /// Received by client from database upon a reducer run.
 ///
 /// Clients receive `TransactionUpdate`s only for reducers
@@ -558,6 +1509,11 @@ pub struct ReducerCallInfo<F: WebsocketFormat> {
     pub args: F::Single,
     /// An identifier for a client request
     pub request_id: u32,
+    /// The id of the trace of the reducer call, as 32 lowercase hex digits,
+    /// taken from [`CallReducer::traceparent`] or generated by the server.
+    ///
+    /// `None` if the transaction wasn't a reducer call, e.g. a SQL `INSERT`.
+    pub trace_id: Option<Box<str>>,
 }
 
 /// The status of a [`TransactionUpdate`].
@@ -590,6 +1546,11 @@ impl<F: WebsocketFormat> DatabaseUpdate<F> {
     pub fn num_rows(&self) -> usize {
         self.tables.iter().map(|t| t.num_rows()).sum()
     }
+
+    /// Returns the size of the rows in this update in bytes, as they would be sent.
+    pub fn num_bytes(&self) -> usize {
+        self.tables.iter().map(|t| t.num_bytes()).sum()
+    }
 }
 
 impl<F: WebsocketFormat> FromIterator<TableUpdate<F>> for DatabaseUpdate<F> {
@@ -656,6 +1617,11 @@ impl<F: WebsocketFormat> TableUpdate<F> {
     pub fn num_rows(&self) -> usize {
         self.num_rows as usize
     }
+
+    /// Returns the size of the rows in this update in bytes, as they would be sent.
+    pub fn num_bytes(&self) -> usize {
+        self.updates.iter().map(|u| u.num_bytes()).sum()
+    }
 }
 
 #[derive(SpacetimeType, Debug, Clone, EnumAsInner)]
@@ -682,6 +1648,16 @@ impl CompressableQueryUpdate<BsatnFormat> {
     }
 }
 
+impl ByteListLen for CompressableQueryUpdate<BsatnFormat> {
+    /// Returns the size of the update in bytes, compressed if it is.
+    fn num_bytes(&self) -> usize {
+        match self {
+            Self::Uncompressed(qu) => qu.num_bytes(),
+            Self::Brotli(bytes) | Self::Gzip(bytes) => bytes.len(),
+        }
+    }
+}
+
 #[derive(SpacetimeType, Debug, Clone)]
 #[sats(crate = spacetimedb_lib)]
 pub struct QueryUpdate<F: WebsocketFormat> {
@@ -698,6 +1674,23 @@ pub struct QueryUpdate<F: WebsocketFormat> {
     /// Rows are encoded as BSATN or JSON according to the table's schema
     /// and the client's requested protocol.
     pub inserts: F::List,
+    /// The column set of the rows, i.e. the positions of the columns they carry, in ascending order,
+    /// if the query selects only some columns of the table, e.g. `SELECT id, name FROM player`,
+    /// or empty if it selects whole rows.
+    ///
+    /// Such a query always selects the table's primary key.
+    /// Its rows are still encoded in the shape of the table, so that they decode into the same types,
+    /// but with each column not in the set holding a placeholder:
+    /// zero, `false`, an empty string or array, or the first unit variant of a sum type, if any.
+    /// A client subscribed to both such a query and one selecting whole rows of the same table
+    /// receives the rows matching both in either shape.
+    pub columns: Box<[u16]>,
+}
+
+impl<F: WebsocketFormat> ByteListLen for QueryUpdate<F> {
+    fn num_bytes(&self) -> usize {
+        self.deletes.num_bytes() + self.inserts.num_bytes()
+    }
 }
 
 /// A response to a [`OneOffQuery`].
@@ -732,6 +1725,26 @@ pub struct OneOffTable<F: WebsocketFormat> {
     pub rows: F::List,
 }
 
+/// A chunk of the rows matched by a [`Query`].
+///
+/// A query's rows are sent over one or more of these, in order, the last of which has `last` set.
+/// A query which matches no rows is answered with a single empty chunk.
+#[derive(SpacetimeType, Debug)]
+#[sats(crate = spacetimedb_lib)]
+pub struct QueryRows<F: WebsocketFormat> {
+    /// The `request_id` of the `Query` message.
+    pub request_id: u32,
+    /// The name of the table the query returns rows of.
+    pub table_name: Box<str>,
+    /// The rows of this chunk, encoded as BSATN or JSON according to the table's schema
+    /// and the client's requested protocol.
+    pub rows: F::List,
+    /// Whether this is the last chunk of the query's rows.
+    pub last: bool,
+    /// The total duration of query compilation and evaluation on the server.
+    pub total_host_execution_duration: TimeDuration,
+}
+
 /// Used whenever different formats need to coexist.
 #[derive(Debug, Clone)]
 pub enum FormatSwitch<B, J> {
@@ -817,7 +1830,9 @@ impl WebsocketFormat for BsatnFormat {
         let qu_len_would_have_been = bsatn::to_len(&qu).unwrap();
 
         match decide_compression(qu_len_would_have_been, compression) {
-            Compression::None => CompressableQueryUpdate::Uncompressed(qu),
+            // `CompressableQueryUpdate` has no zstd variant, so as not to change the schema of version 1,
+            // but the entire message will be compressed with zstd instead.
+            Compression::None | Compression::Zstd => CompressableQueryUpdate::Uncompressed(qu),
             Compression::Brotli => {
                 let bytes = bsatn::to_vec(&qu).unwrap();
                 let mut out = Vec::new();
@@ -835,7 +1850,8 @@ impl WebsocketFormat for BsatnFormat {
 }
 
 /// A specification of either a desired or decided compression algorithm.
-#[derive(serde::Deserialize, Default, PartialEq, Eq, Clone, Copy, Hash, Debug)]
+#[derive(SpacetimeType, serde::Serialize, serde::Deserialize, Default, PartialEq, Eq, Clone, Copy, Hash, Debug)]
+#[sats(crate = spacetimedb_lib)]
 pub enum Compression {
     /// No compression ever.
     None,
@@ -844,15 +1860,52 @@ pub enum Compression {
     Brotli,
     /// Compress using gzip if a certain size threshold was met.
     Gzip,
+    /// Compress using zstd if a certain size threshold was met.
+    ///
+    /// Only available in [`ProtocolVersion::V2`].
+    Zstd,
 }
 
+impl Compression {
+    pub const ALL: [Self; 4] = [Self::None, Self::Brotli, Self::Gzip, Self::Zstd];
+
+    /// The tag which prefixes a binary [`ServerMessage`] compressed with `self`.
+    pub fn tag(self) -> u8 {
+        match self {
+            Self::None => SERVER_MSG_COMPRESSION_TAG_NONE,
+            Self::Brotli => SERVER_MSG_COMPRESSION_TAG_BROTLI,
+            Self::Gzip => SERVER_MSG_COMPRESSION_TAG_GZIP,
+            Self::Zstd => SERVER_MSG_COMPRESSION_TAG_ZSTD,
+        }
+    }
+
+    /// The compression a binary [`ServerMessage`] prefixed with `tag` was compressed with, if known.
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        Some(match tag {
+            SERVER_MSG_COMPRESSION_TAG_NONE => Self::None,
+            SERVER_MSG_COMPRESSION_TAG_BROTLI => Self::Brotli,
+            SERVER_MSG_COMPRESSION_TAG_GZIP => Self::Gzip,
+            SERVER_MSG_COMPRESSION_TAG_ZSTD => Self::Zstd,
+            _ => return None,
+        })
+    }
+}
+
+/// The length, in bytes, beyond which messages are compressed, unless the client chose another threshold.
+/// 1KiB was chosen without measurement.
+/// TODO(perf): measure!
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Decide how to compress `len` serialized bytes for a client which asked for `compression`,
+/// with the [`DEFAULT_COMPRESSION_THRESHOLD`].
 pub fn decide_compression(len: usize, compression: Compression) -> Compression {
-    /// The threshold beyond which we start to compress messages.
-    /// 1KiB was chosen without measurement.
-    /// TODO(perf): measure!
-    const COMPRESS_THRESHOLD: usize = 1024;
+    decide_compression_with_threshold(len, compression, DEFAULT_COMPRESSION_THRESHOLD)
+}
 
-    if len > COMPRESS_THRESHOLD {
+/// Decide how to compress `len` serialized bytes for a client which asked for `compression`:
+/// not at all if they're no more than `threshold`, as compressing small messages costs more than it saves.
+pub fn decide_compression_with_threshold(len: usize, compression: Compression, threshold: usize) -> Compression {
+    if len > threshold {
         compression
     } else {
         Compression::None
@@ -892,6 +1945,30 @@ pub fn gzip_decompress(bytes: &[u8]) -> Result<Vec<u8>, io::Error> {
     Ok(decompressed)
 }
 
+/// The level at which [`zstd_compress`] compresses.
+///
+/// As for brotli and gzip, we optimize for compression speed.
+/// Servers may choose another level, see [`zstd_compress_with_level`].
+pub const DEFAULT_ZSTD_LEVEL: i32 = 1;
+
+pub fn zstd_compress(bytes: &[u8], out: &mut impl io::Write) {
+    zstd_compress_with_level(bytes, out, DEFAULT_ZSTD_LEVEL)
+}
+
+/// Compress `bytes` with zstd at `level`, writing them to `out`.
+///
+/// Higher levels, up to 22, compress better but slower,
+/// while negative levels compress faster still than level 1.
+/// Levels beyond those supported are clamped by zstd, and 0 means zstd's own default, currently 3.
+/// The level doesn't affect decompression.
+pub fn zstd_compress_with_level(bytes: &[u8], out: &mut impl io::Write, level: i32) {
+    zstd::stream::copy_encode(bytes, out, level).expect("should be able to zstd compress `bytes`");
+}
+
+pub fn zstd_decompress(bytes: &[u8]) -> Result<Vec<u8>, io::Error> {
+    zstd::stream::decode_all(bytes)
+}
+
 type RowSize = u16;
 type RowOffset = u64;
 
@@ -1045,3 +2122,260 @@ impl BsatnRowListBuilder {
         BsatnRowList { size_hint, rows_data }
     }
 }
+
+/// The path, under `/v1`, of the endpoint of multiplexed connections,
+/// over which a client can connect to several databases at once.
+pub const MULTIPLEX_PATH: &str = "/subscribe";
+
+/// The channel of a multiplexed connection which carries [`MultiplexClientMessage`]s and [`MultiplexServerMessage`]s,
+/// rather than the messages of an attached database.
+pub const MULTIPLEX_CONTROL_CHANNEL: u32 = 0;
+
+/// Messages sent by the client of a multiplexed connection on the [`MULTIPLEX_CONTROL_CHANNEL`].
+///
+/// A multiplexed connection isn't bound to any database.
+/// Rather, the client attaches databases to channels of its choosing,
+/// after which each channel carries the messages of a connection to its database,
+/// as if sent over a websocket of its own, in the protocol the multiplexed connection was opened with:
+///
+/// - A binary frame is prefixed with its channel as a little-endian `u32`,
+///   see [`encode_channel_frame`] and [`decode_channel_frame`].
+/// - A text frame is wrapped in a [`ChannelFrame`].
+///
+/// Control messages are framed the same way, on channel [`MULTIPLEX_CONTROL_CHANNEL`].
+/// Liveness is checked once for the multiplexed connection, rather than per channel.
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub enum MultiplexClientMessage {
+    Attach(Attach),
+    Detach(Detach),
+}
+
+/// Attach `database` to `channel`, which is answered by [`MultiplexServerMessage::Attached`]
+/// before the first message of the database's connection, e.g. its `IdentityToken`,
+/// or by [`MultiplexServerMessage::AttachFailed`].
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct Attach {
+    /// The channel to attach the database to, which mustn't be [`MULTIPLEX_CONTROL_CHANNEL`] nor already attached.
+    pub channel: u32,
+    /// The name or hex identity of the database.
+    pub database: Box<str>,
+    /// The compression the client wants for the channel, if it has a preference,
+    /// as the `compression` query parameter of a connection to the database.
+    pub compression: Option<Compression>,
+    /// Whether the client wants "light" responses on the channel, if it has a preference,
+    /// as the `light` query parameter of a connection to the database.
+    pub light: Option<bool>,
+}
+
+/// Detach the database attached to `channel`, as if the client closed its connection to it,
+/// which is answered by [`MultiplexServerMessage::Detached`].
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct Detach {
+    pub channel: u32,
+}
+
+/// Messages sent by the server of a multiplexed connection on the [`MULTIPLEX_CONTROL_CHANNEL`],
+/// see [`MultiplexClientMessage`].
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub enum MultiplexServerMessage {
+    Attached(Attached),
+    AttachFailed(AttachFailed),
+    Detached(Detached),
+}
+
+/// The database of `database_identity` was attached to `channel`.
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct Attached {
+    pub channel: u32,
+    pub database_identity: Identity,
+}
+
+/// The database couldn't be attached to `channel`, e.g. as it doesn't exist or the client may not connect to it.
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct AttachFailed {
+    pub channel: u32,
+    /// The HTTP status a connection to the database would have been refused with.
+    pub status: u16,
+    pub reason: Box<str>,
+}
+
+/// The database attached to `channel` was detached,
+/// as the client asked for it, or as the server closed its connection, e.g. as its module exited.
+///
+/// The channel can then be attached again.
+#[derive(SpacetimeType, Debug, Clone, PartialEq, Eq)]
+#[sats(crate = spacetimedb_lib)]
+pub struct Detached {
+    pub channel: u32,
+    /// The code of the close frame a connection to the database would have been closed with.
+    pub code: u16,
+    /// The reason of that close frame.
+    pub reason: Box<str>,
+}
+
+/// A text frame of a multiplexed connection, carrying the frame `message` of `channel`,
+/// see [`MultiplexClientMessage`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct ChannelFrame<M> {
+    pub channel: u32,
+    pub message: M,
+}
+
+/// Prefix the binary frame `frame` of `channel` with the channel, see [`MultiplexClientMessage`].
+pub fn encode_channel_frame(channel: u32, frame: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + frame.len());
+    out.extend_from_slice(&channel.to_le_bytes());
+    out.extend_from_slice(frame);
+    out
+}
+
+/// Split a binary frame of a multiplexed connection into its channel and the frame of that channel,
+/// or return `None` if it's too short to have a channel.
+pub fn decode_channel_frame(frame: &[u8]) -> Option<(u32, &[u8])> {
+    let (channel, frame) = frame.split_first_chunk::<4>()?;
+    Some((u32::from_le_bytes(*channel), frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_parse_from_their_display() {
+        let window = InitialWindow {
+            limit: 50,
+            order_by: "sent".into(),
+        };
+        assert_eq!(window.to_string(), "last(50, sent)");
+        assert_eq!("last(50, sent)".parse(), Ok(window.clone()));
+        assert_eq!(" last (50,sent) ".parse(), Ok(window));
+
+        for invalid in [
+            "first(50, sent)",
+            "last(50)",
+            "last(-1, sent)",
+            "last(50, )",
+            "last(50, a, b)",
+        ] {
+            assert_eq!(
+                invalid.parse::<InitialWindow>(),
+                Err(ParseWindowError(invalid.into())),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn disconnect_reasons_round_trip_through_their_codes() {
+        for reason in DisconnectReason::ALL {
+            assert!((4000..5000).contains(&reason.code()), "{reason:?}");
+            assert_eq!(DisconnectReason::from_code(reason.code()), Some(reason));
+        }
+        assert_eq!(DisconnectReason::QueueOverflow.code(), QUEUE_OVERFLOW_CLOSE_CODE);
+        assert_eq!(DisconnectReason::from_code(1001), None);
+    }
+
+    /// A payload like a large initial subscription update: many similar rows.
+    fn rows(n: u32) -> Vec<u8> {
+        let mut rows = Vec::new();
+        for i in 0..n {
+            rows.extend_from_slice(&i.to_le_bytes());
+            rows.extend_from_slice(b"player-name-");
+            rows.extend_from_slice(&(i % 7).to_le_bytes());
+        }
+        rows
+    }
+
+    #[test]
+    fn compressions_round_trip() {
+        type Codec = (fn(&[u8], &mut Vec<u8>), fn(&[u8]) -> io::Result<Vec<u8>>);
+        let codecs: [(Compression, Codec); 3] = [
+            (
+                Compression::Brotli,
+                (|b, out| brotli_compress(b, out), brotli_decompress),
+            ),
+            (Compression::Gzip, (|b, out| gzip_compress(b, out), gzip_decompress)),
+            (Compression::Zstd, (|b, out| zstd_compress(b, out), zstd_decompress)),
+        ];
+        for payload in [vec![], b"x".to_vec(), rows(10_000)] {
+            for (compression, (compress, decompress)) in codecs {
+                let mut compressed = Vec::new();
+                compress(&payload, &mut compressed);
+                assert_eq!(decompress(&compressed).unwrap(), payload, "{compression:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn zstd_round_trips_at_every_level() {
+        let payload = rows(10_000);
+        let compressed_at = |level| {
+            let mut compressed = Vec::new();
+            zstd_compress_with_level(&payload, &mut compressed, level);
+            assert_eq!(zstd_decompress(&compressed).unwrap(), payload, "level {level}");
+            compressed.len()
+        };
+        for level in [-5, 0, DEFAULT_ZSTD_LEVEL, 9, 19, 22, 100] {
+            compressed_at(level);
+        }
+        // Higher levels trade speed for size.
+        assert!(compressed_at(19) <= compressed_at(DEFAULT_ZSTD_LEVEL));
+        assert!(compressed_at(DEFAULT_ZSTD_LEVEL) < payload.len());
+    }
+
+    #[test]
+    fn compressions_are_tagged() {
+        for compression in [
+            Compression::None,
+            Compression::Brotli,
+            Compression::Gzip,
+            Compression::Zstd,
+        ] {
+            assert_eq!(Compression::from_tag(compression.tag()), Some(compression));
+        }
+        assert_eq!(Compression::from_tag(SERVER_MSG_COMPRESSION_TAG_ZSTD + 1), None);
+        // Zstd can't be negotiated in the first version of the protocol, whose clients can't decode it.
+        assert!(!ProtocolVersion::V1.supports(Compression::Zstd));
+        assert!(ProtocolVersion::V1.supports(Compression::Brotli));
+        assert!(ProtocolVersion::V2.supports(Compression::Zstd));
+    }
+
+    #[test]
+    fn channel_frames_round_trip() {
+        let frame = encode_channel_frame(7, b"hello");
+        assert_eq!(decode_channel_frame(&frame), Some((7, &b"hello"[..])));
+        assert_eq!(decode_channel_frame(&encode_channel_frame(1, &[])), Some((1, &[][..])));
+        assert_eq!(decode_channel_frame(&[1, 0, 0]), None);
+
+        let attach = MultiplexClientMessage::Attach(Attach {
+            channel: 1,
+            database: "quickstart".into(),
+            compression: None,
+            light: Some(true),
+        });
+        let bytes = bsatn::to_vec(&attach).unwrap();
+        assert_eq!(bsatn::from_slice::<MultiplexClientMessage>(&bytes).unwrap(), attach);
+    }
+
+    #[test]
+    fn features_header_ignores_unknown_features() {
+        let features = DatabaseFeatures::from_iter([DatabaseFeature::AcknowledgedDelivery]);
+        let header = encode_features(features);
+        assert_eq!(header, "delta-updates=?0, acknowledged-delivery=?1");
+        assert_eq!(parse_features(&header), features);
+
+        // As sent by a newer server, with a feature this version doesn't know of.
+        let newer = "encryption=?1, delta-updates, acknowledged-delivery=?0, future=(1 2)";
+        assert_eq!(
+            parse_features(newer),
+            DatabaseFeatures::from_iter([DatabaseFeature::DeltaUpdates])
+        );
+        assert!(parse_features("").is_empty());
+    }
+}
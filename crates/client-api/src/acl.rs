@@ -0,0 +1,208 @@
+//! Enforcement of per-database network ACLs, i.e. [`NetworkAcl`]s.
+
+use std::net::IpAddr;
+
+use http::StatusCode;
+use ipnet::IpNet;
+use spacetimedb::messages::control_db::NetworkAcl;
+use spacetimedb::Identity;
+
+use crate::routes::database::NO_SUCH_DATABASE;
+use crate::util::ClientAddr;
+use crate::{log_and_500, DatabaseResolution};
+
+/// Parse a range of a [`NetworkAcl`], in CIDR notation or as a single address.
+pub fn parse_range(range: &str) -> Result<IpNet, String> {
+    spacetimedb::client::parse_ip_range(range)
+}
+
+/// Returns whether `acl` admits a client at `addr`.
+///
+/// A client whose address is unknown is only admitted if the ACL has no rules.
+/// Ranges which fail to parse are ignored,
+/// as ACLs are validated with [`parse_range`] before being stored.
+pub fn permits(acl: &NetworkAcl, addr: Option<IpAddr>) -> bool {
+    if acl.is_empty() {
+        return true;
+    }
+    let Some(addr) = addr.map(|addr| addr.to_canonical()) else {
+        return false;
+    };
+    let contains = |ranges: &[String]| {
+        ranges
+            .iter()
+            .filter_map(|range| parse_range(range).ok())
+            .any(|net| net.contains(&addr))
+    };
+    !contains(&acl.deny) && (acl.allow.is_empty() || contains(&acl.allow))
+}
+
+/// Reject the request with an error if the network ACL of `database_identity` doesn't admit `addr`.
+///
+/// This must be checked before interacting with the database's module.
+/// Rejections don't mention the database, and, if the ACL asks for it,
+/// are indistinguishable from the database not existing.
+pub async fn check(
+    ctx: &(impl DatabaseResolution + ?Sized),
+    database_identity: &Identity,
+    addr: ClientAddr,
+) -> axum::response::Result<()> {
+    let addr = addr.ip();
+    let Some(acl) = ctx.find_network_acl(database_identity).map_err(log_and_500)? else {
+        return Ok(());
+    };
+    if permits(&acl, addr) {
+        return Ok(());
+    }
+    log::debug!("network ACL of database {database_identity} denied client at {addr:?}");
+    if acl.deny_as_not_found {
+        return Err(NO_SUCH_DATABASE.into());
+    }
+    Err((StatusCode::FORBIDDEN, "Access denied.").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+    use spacetimedb::client::NetworkOptions;
+
+    fn acl(allow: &[&str], deny: &[&str]) -> NetworkAcl {
+        NetworkAcl {
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            deny: deny.iter().map(|s| s.to_string()).collect(),
+            deny_as_not_found: false,
+        }
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_cidr_and_single_addresses() {
+        assert_eq!(parse_range("10.1.2.3/8").unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(parse_range(" 10.1.2.3 ").unwrap().to_string(), "10.1.2.3/32");
+        assert_eq!(parse_range("2001:db8::1/32").unwrap().to_string(), "2001:db8::/32");
+        assert_eq!(parse_range("::1").unwrap().to_string(), "::1/128");
+        assert!(parse_range("10.0.0.0/33").is_err());
+        assert!(parse_range("example.com").is_err());
+    }
+
+    #[test]
+    fn empty_acl_admits_everyone() {
+        assert!(permits(&NetworkAcl::default(), None));
+        assert!(permits(&NetworkAcl::default(), ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let acl = acl(&["10.0.0.0/8", "2001:db8::/32"], &["10.6.6.0/24", "2001:db8:6::/48"]);
+        assert!(permits(&acl, ip("10.1.2.3")));
+        assert!(permits(&acl, ip("2001:db8:1::1")));
+        assert!(!permits(&acl, ip("10.6.6.6")));
+        assert!(!permits(&acl, ip("2001:db8:6::6")));
+        assert!(!permits(&acl, ip("192.0.2.1")));
+        assert!(!permits(&acl, ip("2001:db9::1")));
+        assert!(!permits(&acl, None));
+    }
+
+    #[test]
+    fn deny_only_admits_the_rest() {
+        let acl = acl(&[], &["192.0.2.0/24"]);
+        assert!(!permits(&acl, ip("192.0.2.1")));
+        assert!(permits(&acl, ip("198.51.100.1")));
+        // Unknown addresses could be anything, including a denied one.
+        assert!(!permits(&acl, None));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_ranges() {
+        let acl = acl(&["10.0.0.0/8"], &[]);
+        assert!(permits(&acl, ip("::ffff:10.0.0.1")));
+        assert!(!permits(&acl, ip("::ffff:192.0.2.1")));
+    }
+
+    #[test]
+    fn client_addr_respects_trusted_proxy_depth() {
+        let peer = ip("203.0.113.1");
+        let xff = [
+            HeaderValue::from_static("6.6.6.6, 192.0.2.1"),
+            HeaderValue::from_static("10.0.0.2"),
+        ];
+
+        // Without trusted proxies, `X-Forwarded-For` is ignored entirely.
+        assert_eq!(resolve(&xff, peer, depth(0)), peer);
+        // The client can prepend to `X-Forwarded-For`, but not append.
+        assert_eq!(resolve(&xff, peer, depth(1)), ip("10.0.0.2"));
+        assert_eq!(resolve(&xff, peer, depth(2)), ip("192.0.2.1"));
+        assert_eq!(resolve(&xff, peer, depth(5)), ip("6.6.6.6"));
+        // Without the header, fall back to the peer.
+        assert_eq!(resolve([], peer, depth(1)), peer);
+
+        let garbage = [HeaderValue::from_static("192.0.2.1, not-an-ip")];
+        assert_eq!(resolve(&garbage, peer, depth(1)), None);
+    }
+
+    fn depth(trusted_proxy_depth: usize) -> NetworkOptions {
+        NetworkOptions {
+            trusted_proxy_depth,
+            ..<_>::default()
+        }
+    }
+
+    fn proxies(trusted_proxies: &[&str]) -> NetworkOptions {
+        NetworkOptions {
+            trusted_proxies: trusted_proxies.iter().map(|s| parse_range(s).unwrap()).collect(),
+            // Ignored in favor of the proxies.
+            trusted_proxy_depth: 1,
+        }
+    }
+
+    fn resolve<'a>(
+        xff: impl IntoIterator<Item = &'a HeaderValue>,
+        peer: Option<IpAddr>,
+        options: NetworkOptions,
+    ) -> Option<IpAddr> {
+        let addr = ClientAddr::from_headers(xff, peer, &options);
+        // Peers are kept in canonical form, with IPv4-mapped addresses as IPv4.
+        assert_eq!(addr.0.peer, peer.map(|ip| ip.to_canonical()));
+        addr.ip()
+    }
+
+    #[test]
+    fn client_addr_walks_back_through_trusted_proxies() {
+        let options = || proxies(&["10.0.0.0/8", "192.168.1.1"]);
+        let xff = [
+            HeaderValue::from_static("6.6.6.6, 198.51.100.7, 192.168.1.1"),
+            HeaderValue::from_static("10.1.2.3"),
+        ];
+
+        // Past every trusted hop, the first untrusted entry is the client;
+        // whatever it put before itself is ignored.
+        assert_eq!(resolve(&xff, ip("10.0.0.1"), options()), ip("198.51.100.7"));
+        // A peer which isn't a trusted proxy can't vouch for anyone.
+        assert_eq!(resolve(&xff, ip("203.0.113.1"), options()), ip("203.0.113.1"));
+        // A trusted peer which didn't forward for anyone is the client itself.
+        assert_eq!(resolve([], ip("10.0.0.1"), options()), ip("10.0.0.1"));
+        // If every hop is trusted, the first is as far back as we can go.
+        let internal = [HeaderValue::from_static("10.9.9.9, 192.168.1.1")];
+        assert_eq!(resolve(&internal, ip("10.0.0.1"), options()), ip("10.9.9.9"));
+        // IPv4-mapped peers are trusted by their IPv4 range.
+        assert_eq!(resolve(&xff, ip("::ffff:10.0.0.1"), options()), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn client_addr_is_unknown_behind_malformed_trusted_hops() {
+        let options = || proxies(&["10.0.0.0/8"]);
+        let peer = ip("10.0.0.1");
+
+        let garbage = [HeaderValue::from_static("198.51.100.7, not-an-ip")];
+        assert_eq!(resolve(&garbage, peer, options()), None);
+        // Garbage the client put before its own address is never looked at.
+        let prepended = [HeaderValue::from_static("not-an-ip, 198.51.100.7, 10.2.2.2")];
+        assert_eq!(resolve(&prepended, peer, options()), ip("198.51.100.7"));
+        // Nor is a header from an untrusted peer.
+        assert_eq!(resolve(&garbage, ip("203.0.113.1"), options()), ip("203.0.113.1"));
+    }
+}
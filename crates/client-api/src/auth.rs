@@ -13,7 +13,9 @@ use spacetimedb::auth::token_validation::{
     new_validator, DefaultValidator, TokenSigner, TokenValidationError, TokenValidator,
 };
 use spacetimedb::auth::JwtKeys;
+use spacetimedb::client::ReconnectGrant;
 use spacetimedb::energy::EnergyQuanta;
+use spacetimedb::host::trace_context::TraceParent;
 use spacetimedb::identity::Identity;
 use uuid::Uuid;
 
@@ -73,6 +75,8 @@ pub struct SpacetimeAuth {
     pub identity: Identity,
     pub subject: String,
     pub issuer: String,
+    /// When the token of `creds` expires, if it does.
+    pub expires_at: Option<SystemTime>,
 }
 
 use jsonwebtoken;
@@ -154,6 +158,7 @@ impl SpacetimeAuth {
             identity,
             subject,
             issuer: ctx.jwt_auth_provider().local_issuer().to_string(),
+            expires_at: None,
         })
     }
 
@@ -284,6 +289,7 @@ impl<S: NodeDelegate + Send + Sync> axum::extract::FromRequestParts<S> for Space
             identity: claims.identity,
             subject: claims.subject,
             issuer: claims.issuer,
+            expires_at: claims.exp,
         };
         Ok(Self { auth: Some(auth) })
     }
@@ -417,13 +423,128 @@ impl headers::Header for SpacetimeExecutionDurationMicros {
     }
 }
 
+/// The `traceparent` of an HTTP reducer call, naming the caller's span.
+pub struct TraceParentHeader(pub TraceParent);
+impl headers::Header for TraceParentHeader {
+    fn name() -> &'static http::HeaderName {
+        static NAME: http::HeaderName = http::HeaderName::from_static("traceparent");
+        &NAME
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(values: &mut I) -> Result<Self, headers::Error> {
+        let value = values.next().ok_or_else(headers::Error::invalid)?;
+        let traceparent = value.to_str().ok().and_then(|value| value.parse().ok());
+        traceparent.map(Self).ok_or_else(headers::Error::invalid)
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend([self.0.to_string().try_into().unwrap()])
+    }
+}
+
+/// The `traceresponse` to an HTTP reducer call, naming the span of the call,
+/// and so also the id of its trace.
+pub struct TraceResponseHeader(pub TraceParent);
+impl headers::Header for TraceResponseHeader {
+    fn name() -> &'static http::HeaderName {
+        static NAME: http::HeaderName = http::HeaderName::from_static("traceresponse");
+        &NAME
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(_values: &mut I) -> Result<Self, headers::Error> {
+        unimplemented!()
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend([self.0.to_string().try_into().unwrap()])
+    }
+}
+
+/// A reconnect token, issued when a websocket connection is accepted.
+///
+/// Presenting it as a query param `?reconnect_token=$token` when reconnecting
+/// to the same database stands in for the client's credentials.
+/// See [`ReconnectTokens`](spacetimedb::client::ReconnectTokens).
+#[derive(Deserialize)]
+struct ReconnectTokenParam {
+    reconnect_token: String,
+}
+
+/// A reconnect token a request was authenticated with, and the grant it's for.
+///
+/// The token isn't spent until the route [redeems](spacetimedb::client::ReconnectTokens::redeem) it,
+/// having checked that it's for the database being connected to.
+#[derive(Clone)]
+pub struct PresentedReconnectToken {
+    pub token: String,
+    pub grant: ReconnectGrant,
+}
+
+impl ReconnectTokenParam {
+    /// Extract a reconnect token from the query string of a websocket upgrade request.
+    fn from_request(req: &Request) -> Option<Self> {
+        let is_upgrade = req
+            .headers()
+            .get(http::header::UPGRADE)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"));
+        if !is_upgrade {
+            return None;
+        }
+        Query::<Self>::try_from_uri(req.uri()).ok().map(|Query(param)| param)
+    }
+}
+
+pub struct SpacetimeReconnectToken(pub String);
+impl headers::Header for SpacetimeReconnectToken {
+    fn name() -> &'static http::HeaderName {
+        static NAME: http::HeaderName = http::HeaderName::from_static("spacetime-reconnect-token");
+        &NAME
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(_values: &mut I) -> Result<Self, headers::Error> {
+        unimplemented!()
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        let mut value = HeaderValue::try_from(&self.0).unwrap();
+        value.set_sensitive(true);
+        values.extend([value])
+    }
+}
+
 pub async fn anon_auth_middleware<S: ControlStateDelegate + NodeDelegate>(
     State(worker_ctx): State<S>,
-    auth: SpacetimeAuthHeader,
     mut req: Request,
     next: Next,
 ) -> axum::response::Result<impl IntoResponse> {
-    let auth = auth.get_or_create(&worker_ctx).await?;
+    let auth = if let Some(param) = ReconnectTokenParam::from_request(&req) {
+        // A valid reconnect token skips validating credentials and resolving the identity.
+        // The route must redeem it, which checks that it's for the database being connected to.
+        let grant = worker_ctx
+            .client_actor_index()
+            .reconnect_tokens()
+            .grant(&param.reconnect_token)
+            .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Authorization failed: {e}")))?;
+        let auth = SpacetimeAuth {
+            creds: SpacetimeCreds::from_signed_token(grant.token.clone()),
+            identity: grant.identity,
+            subject: grant.subject.clone(),
+            issuer: grant.issuer.clone(),
+            expires_at: grant.expires_at,
+        };
+        req.extensions_mut().insert(PresentedReconnectToken {
+            token: param.reconnect_token,
+            grant,
+        });
+        auth
+    } else {
+        let (mut parts, body) = req.into_parts();
+        let auth =
+            <SpacetimeAuthHeader as axum::extract::FromRequestParts<S>>::from_request_parts(&mut parts, &worker_ctx)
+                .await?;
+        req = Request::from_parts(parts, body);
+        auth.get_or_create(&worker_ctx).await?
+    };
     req.extensions_mut().insert(auth.clone());
     let resp = next.run(req).await;
     Ok((auth.into_headers(), resp))
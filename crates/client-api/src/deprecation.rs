@@ -0,0 +1,208 @@
+//! The registry of deprecated features of the websocket API,
+//! of whose use clients are told in-band with a [`DeprecationNotice`](ws_api::DeprecationNotice).
+//!
+//! Deprecating another feature is a matter of adding an entry to [`DEPRECATIONS`],
+//! along with, for a client message, reporting its use as a
+//! [`HandleOutcome::HandledLegacy`](spacetimedb::client::HandleOutcome::HandledLegacy).
+
+use spacetimedb::client::messages::DeprecationNoticeMessage;
+use spacetimedb::worker_metrics::WORKER_METRICS;
+use spacetimedb::Identity;
+use spacetimedb_client_api_messages::websocket::{self as ws_api, Compression, ProtocolVersion};
+
+/// A deprecated feature, see [`ws_api::DeprecationNotice`].
+#[derive(Debug)]
+pub struct Deprecation {
+    /// Identifies the feature to clients, in metrics and to [`ConnectionLifecycleHooks`](crate::hooks::ConnectionLifecycleHooks).
+    pub feature: &'static str,
+    pub message: &'static str,
+    pub removal_version: Option<&'static str>,
+    /// Returns whether `usage` uses the feature.
+    pub used_by: fn(&Usage) -> bool,
+}
+
+/// Something a client did, checked against the [`DEPRECATIONS`].
+#[derive(Clone, Copy, Debug)]
+pub enum Usage {
+    /// The client connected with these parameters.
+    Handshake(Handshake),
+    /// The client sent a message of the legacy kind named here.
+    LegacyMessage(&'static str),
+}
+
+/// The parameters with which a client connected.
+#[derive(Clone, Copy, Debug)]
+pub struct Handshake {
+    /// Whether the client chose its own connection id.
+    pub client_connection_id: bool,
+    pub version: ProtocolVersion,
+    pub compression: Compression,
+}
+
+impl Usage {
+    fn handshake(&self) -> Option<&Handshake> {
+        match self {
+            Self::Handshake(handshake) => Some(handshake),
+            Self::LegacyMessage(_) => None,
+        }
+    }
+}
+
+/// The features clients are told are deprecated.
+pub static DEPRECATIONS: &[Deprecation] = &[
+    Deprecation {
+        feature: "client_connection_id",
+        message: "The `connection_id` query parameter of the subscribe endpoint is internal and will be removed. \
+                  Let the server choose the connection id, which it sends in the `IdentityToken`.",
+        removal_version: None,
+        used_by: |usage| usage.handshake().is_some_and(|h| h.client_connection_id),
+    },
+    Deprecation {
+        feature: "protocol_v1",
+        message: "The `v1.bsatn.spacetimedb` and `v1.json.spacetimedb` protocols will be removed. \
+                  Connect with `v2.bsatn.spacetimedb` or `v2.json.spacetimedb` instead.",
+        removal_version: None,
+        used_by: |usage| usage.handshake().is_some_and(|h| h.version == ProtocolVersion::V1),
+    },
+    Deprecation {
+        feature: "legacy_compression_framing",
+        message: "Compressed messages framed as in version 1 of the protocol will be removed. \
+                  Connect with version 2 of the protocol, whose messages are tagged with the compression actually applied.",
+        removal_version: None,
+        used_by: |usage| {
+            usage
+                .handshake()
+                .is_some_and(|h| h.version == ProtocolVersion::V1 && h.compression != Compression::None)
+        },
+    },
+    Deprecation {
+        feature: "legacy_subscribe",
+        message: "The `Subscribe` message will be removed. \
+                  Subscribe with `SubscribeMulti` instead, which can be unsubscribed from.",
+        removal_version: None,
+        used_by: |usage| matches!(usage, Usage::LegacyMessage("Subscribe")),
+    },
+];
+
+/// The deprecated features a connection has used.
+///
+/// The client is told of each feature once per connection,
+/// however often it uses it.
+#[derive(Debug, Default)]
+pub(crate) struct DeprecationNotices {
+    used: Vec<&'static str>,
+}
+
+impl DeprecationNotices {
+    /// Check `usage` of the connection to `database_identity` against the [`DEPRECATIONS`],
+    /// returning the notices to send the client for features it hasn't used before.
+    pub(crate) fn check(&mut self, database_identity: &Identity, usage: Usage) -> Vec<DeprecationNoticeMessage> {
+        self.check_against(DEPRECATIONS, database_identity, usage)
+    }
+
+    fn check_against(
+        &mut self,
+        deprecations: &[Deprecation],
+        database_identity: &Identity,
+        usage: Usage,
+    ) -> Vec<DeprecationNoticeMessage> {
+        let mut notices = Vec::new();
+        for deprecation in deprecations {
+            if !(deprecation.used_by)(&usage) || self.used.contains(&deprecation.feature) {
+                continue;
+            }
+            self.used.push(deprecation.feature);
+            WORKER_METRICS
+                .ws_deprecated_feature_connections
+                .with_label_values(database_identity, deprecation.feature)
+                .inc();
+            notices.push(ws_api::DeprecationNotice {
+                feature: deprecation.feature.into(),
+                message: deprecation.message.into(),
+                removal_version: deprecation.removal_version.map(Into::into),
+            });
+        }
+        notices
+    }
+
+    /// The deprecated features used so far, in the order they were first used.
+    pub(crate) fn used(&self) -> &[&'static str] {
+        &self.used
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(client_connection_id: bool, version: ProtocolVersion, compression: Compression) -> Usage {
+        Usage::Handshake(Handshake {
+            client_connection_id,
+            version,
+            compression,
+        })
+    }
+
+    fn features(notices: &[DeprecationNoticeMessage]) -> Vec<&str> {
+        notices.iter().map(|notice| &*notice.feature).collect()
+    }
+
+    #[test]
+    fn current_clients_get_no_notices() {
+        let mut notices = DeprecationNotices::default();
+        let usage = handshake(false, ProtocolVersion::V2, Compression::Brotli);
+        assert!(notices.check(&Identity::ZERO, usage).is_empty());
+        assert!(notices.used().is_empty());
+    }
+
+    #[test]
+    fn each_feature_is_noticed_once_per_connection() {
+        let mut notices = DeprecationNotices::default();
+        let usage = handshake(true, ProtocolVersion::V1, Compression::Gzip);
+        assert_eq!(
+            features(&notices.check(&Identity::ZERO, usage)),
+            ["client_connection_id", "protocol_v1", "legacy_compression_framing"]
+        );
+
+        let subscribe = Usage::LegacyMessage("Subscribe");
+        assert_eq!(
+            features(&notices.check(&Identity::ZERO, subscribe)),
+            ["legacy_subscribe"]
+        );
+        assert!(notices.check(&Identity::ZERO, subscribe).is_empty());
+        assert!(notices.check(&Identity::ZERO, usage).is_empty());
+
+        assert_eq!(
+            notices.used(),
+            [
+                "client_connection_id",
+                "protocol_v1",
+                "legacy_compression_framing",
+                "legacy_subscribe"
+            ]
+        );
+        // Another connection is told again.
+        let mut other = DeprecationNotices::default();
+        assert_eq!(features(&other.check(&Identity::ZERO, subscribe)), ["legacy_subscribe"]);
+    }
+
+    #[test]
+    fn notices_come_from_the_registry() {
+        let deprecations = [Deprecation {
+            feature: "text",
+            message: "Use the binary protocol.",
+            removal_version: Some("9.0.0"),
+            used_by: |usage| matches!(usage, Usage::LegacyMessage("Text")),
+        }];
+        let mut notices = DeprecationNotices::default();
+        let sent = notices.check_against(&deprecations, &Identity::ZERO, Usage::LegacyMessage("Text"));
+        assert_eq!(
+            sent,
+            [ws_api::DeprecationNotice {
+                feature: "text".into(),
+                message: "Use the binary protocol.".into(),
+                removal_version: Some("9.0.0".into()),
+            }]
+        );
+    }
+}
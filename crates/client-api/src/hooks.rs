@@ -0,0 +1,392 @@
+//! Hooks for embedders to run custom logic over the lifecycle of websocket connections,
+//! and to provide the sinks of database firehoses.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::response::ErrorResponse;
+use http::StatusCode;
+use spacetimedb::client::{ClientConfig, CloseReason};
+use spacetimedb::subscription::firehose::{FirehoseError, FirehoseSink, FirehoseTarget};
+use spacetimedb::Identity;
+use spacetimedb_lib::ConnectionId;
+use spacetimedb_paths::server::FirehoseDir;
+
+/// The longest a single hook may run before it is abandoned,
+/// so that a slow hook can't wedge a connection.
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A websocket connection, as seen by [`ConnectionLifecycleHooks`].
+#[derive(Clone, Debug)]
+pub struct ConnectionContext {
+    pub identity: Identity,
+    pub connection_id: ConnectionId,
+    pub database_identity: Identity,
+    pub config: ClientConfig,
+    /// The deprecated features the client has used, see [`crate::deprecation`].
+    ///
+    /// Those used when connecting are known to every hook,
+    /// while those used by the client's messages are only known to `on_close`.
+    pub deprecated_features: Vec<&'static str>,
+}
+
+/// Why a websocket connection was closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CloseCause {
+    /// The client closed the connection.
+    ClientClosed,
+    /// The database's module exited.
+    ModuleExited,
+    /// The node closed the connection, e.g. to shed load,
+    /// or because the client's identity token couldn't be sent to it.
+    Requested(CloseReason),
+    /// The client stopped responding, e.g. a send or ping timed out.
+    Unresponsive,
+    /// The connection dropped without being closed, e.g. as the client's network went away,
+    /// and the client didn't resume its session, if it could.
+    Lost,
+    /// The websocket failed, or the client sent a message it shouldn't have.
+    Error,
+    /// The connection's actor was cancelled before it could close the connection.
+    Cancelled,
+}
+
+/// Custom logic to run as websocket connections open and close,
+/// e.g. for telemetry, access control or accounting.
+///
+/// Each hook is bounded by [`HOOK_TIMEOUT`].
+/// All methods default to doing nothing.
+#[async_trait]
+pub trait ConnectionLifecycleHooks: Send + Sync {
+    /// Called once a client has authenticated and requested a connection,
+    /// before the connection is accepted.
+    ///
+    /// Returning an error rejects the connection with that response.
+    /// A hook which times out rejects the connection with `503 Service Unavailable`.
+    async fn on_connect(&self, _conn: &ConnectionContext) -> Result<(), ErrorResponse> {
+        Ok(())
+    }
+
+    /// Called once the connection is established,
+    /// i.e. the module has accepted the client and its actor has started.
+    async fn on_ready(&self, _conn: &ConnectionContext) {}
+
+    /// Called once the connection has been closed and the client disconnected from the module.
+    ///
+    /// Called exactly once for every connection for which [`Self::on_ready`] was called.
+    async fn on_close(&self, _conn: &ConnectionContext, _cause: CloseCause) {}
+
+    /// Called to open the sink of an [`FirehoseTarget::External`] target, e.g. a message broker,
+    /// as the owner of `database_identity` attaches it to the database's firehose,
+    /// or as the node starts with it attached.
+    ///
+    /// Returning `None` rejects the target as unsupported.
+    /// A hook which times out fails to open the sink.
+    async fn open_firehose_sink(
+        &self,
+        _database_identity: Identity,
+        _target: &FirehoseTarget,
+    ) -> anyhow::Result<Option<Arc<dyn FirehoseSink>>> {
+        Ok(None)
+    }
+}
+
+/// The default [`ConnectionLifecycleHooks`], which do nothing.
+pub struct NoopConnectionHooks;
+
+impl ConnectionLifecycleHooks for NoopConnectionHooks {}
+
+/// Run [`ConnectionLifecycleHooks::on_connect`], bounded by [`HOOK_TIMEOUT`].
+pub(crate) async fn on_connect(
+    hooks: &dyn ConnectionLifecycleHooks,
+    conn: &ConnectionContext,
+) -> axum::response::Result<()> {
+    match tokio::time::timeout(HOOK_TIMEOUT, hooks.on_connect(conn)).await {
+        Ok(res) => res,
+        Err(_) => {
+            log::warn!("on_connect hook for client {} timed out", conn.identity);
+            Err((StatusCode::SERVICE_UNAVAILABLE, "Timed out accepting the connection.").into())
+        }
+    }
+}
+
+/// Run [`ConnectionLifecycleHooks::on_ready`], bounded by [`HOOK_TIMEOUT`],
+/// and return a [`CloseHook`] which will run [`ConnectionLifecycleHooks::on_close`].
+pub(crate) async fn on_ready(hooks: Arc<dyn ConnectionLifecycleHooks>, conn: ConnectionContext) -> CloseHook {
+    if tokio::time::timeout(HOOK_TIMEOUT, hooks.on_ready(&conn)).await.is_err() {
+        log::warn!("on_ready hook for client {} timed out", conn.identity);
+    }
+    CloseHook(Some((hooks, conn)))
+}
+
+/// Runs [`ConnectionLifecycleHooks::on_close`] exactly once,
+/// either through [`CloseHook::close`] or, if the actor is cancelled, on drop.
+pub(crate) struct CloseHook(Option<(Arc<dyn ConnectionLifecycleHooks>, ConnectionContext)>);
+
+impl CloseHook {
+    /// Record the deprecated features the client has used, to be passed to `on_close`.
+    pub(crate) fn deprecated_features(&mut self, features: &[&'static str]) {
+        if let Some((_, conn)) = &mut self.0 {
+            conn.deprecated_features = features.to_vec();
+        }
+    }
+
+    pub(crate) async fn close(mut self, cause: CloseCause) {
+        if let Some((hooks, conn)) = self.0.take() {
+            run_on_close(hooks, conn, cause).await
+        }
+    }
+}
+
+impl Drop for CloseHook {
+    fn drop(&mut self) {
+        if let Some((hooks, conn)) = self.0.take() {
+            tokio::spawn(run_on_close(hooks, conn, CloseCause::Cancelled));
+        }
+    }
+}
+
+/// Open the sink of `target` for the firehose of `database_identity`:
+/// the in-tree sink of a `file:` or `unix:` target, in `dir`,
+/// or that of [`ConnectionLifecycleHooks::open_firehose_sink`], bounded by [`HOOK_TIMEOUT`].
+pub async fn open_firehose_sink(
+    hooks: &dyn ConnectionLifecycleHooks,
+    dir: &FirehoseDir,
+    database_identity: Identity,
+    target: &FirehoseTarget,
+) -> Result<Arc<dyn FirehoseSink>, FirehoseError> {
+    if let Some(sink) = target.open_in_tree(dir) {
+        return Ok(sink);
+    }
+    let opened = tokio::time::timeout(HOOK_TIMEOUT, hooks.open_firehose_sink(database_identity, target))
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+    match opened {
+        Ok(Some(sink)) => Ok(sink),
+        Ok(None) => Err(FirehoseError::Unsupported(target.to_string())),
+        Err(source) => Err(FirehoseError::Open {
+            target: target.to_string(),
+            source,
+        }),
+    }
+}
+
+async fn run_on_close(hooks: Arc<dyn ConnectionLifecycleHooks>, conn: ConnectionContext, cause: CloseCause) {
+    if tokio::time::timeout(HOOK_TIMEOUT, hooks.on_close(&conn, cause))
+        .await
+        .is_err()
+    {
+        log::warn!("on_close hook for client {} timed out", conn.identity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spacetimedb::client::Protocol;
+    use spacetimedb_client_api_messages::websocket::{Compression, ProtocolVersion};
+    use spacetimedb_paths::FromPathUnchecked;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct RecordingHooks {
+        events: Mutex<Vec<String>>,
+        reject: bool,
+        stall: bool,
+    }
+
+    impl RecordingHooks {
+        fn events(&self) -> Vec<String> {
+            self.events.lock().unwrap().clone()
+        }
+
+        async fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+            if self.stall {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ConnectionLifecycleHooks for RecordingHooks {
+        async fn on_connect(&self, _conn: &ConnectionContext) -> Result<(), ErrorResponse> {
+            self.record("connect".into()).await;
+            if self.reject {
+                return Err(StatusCode::FORBIDDEN.into());
+            }
+            Ok(())
+        }
+
+        async fn on_ready(&self, _conn: &ConnectionContext) {
+            self.record("ready".into()).await
+        }
+
+        async fn on_close(&self, conn: &ConnectionContext, cause: CloseCause) {
+            let deprecated = &conn.deprecated_features;
+            if deprecated.is_empty() {
+                self.record(format!("close {cause:?}")).await
+            } else {
+                self.record(format!("close {cause:?} deprecated {deprecated:?}")).await
+            }
+        }
+
+        async fn open_firehose_sink(
+            &self,
+            _database_identity: Identity,
+            target: &FirehoseTarget,
+        ) -> anyhow::Result<Option<Arc<dyn FirehoseSink>>> {
+            self.record(format!("open {target}")).await;
+            if !target.to_string().starts_with("kafka:") {
+                return Ok(None);
+            }
+            if self.reject {
+                anyhow::bail!("broker unreachable");
+            }
+            Ok(Some(Arc::new(BrokerSink)))
+        }
+    }
+
+    struct BrokerSink;
+
+    #[async_trait]
+    impl FirehoseSink for BrokerSink {
+        async fn send(&self, _update: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn conn() -> ConnectionContext {
+        ConnectionContext {
+            identity: Identity::ZERO,
+            connection_id: ConnectionId::ZERO,
+            database_identity: Identity::ZERO,
+            config: ClientConfig {
+                protocol: Protocol::Binary,
+                version: ProtocolVersion::V1,
+                compression: Compression::None,
+                tx_update_full: true,
+                reducer_timings: false,
+                acknowledged_delivery: false,
+                request_errors: false,
+                ..ClientConfig::for_test()
+            },
+            deprecated_features: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_run_at_each_lifecycle_point() {
+        let hooks = Arc::new(RecordingHooks::default());
+        on_connect(&*hooks, &conn()).await.unwrap();
+        let close = on_ready(hooks.clone(), conn()).await;
+        close.close(CloseCause::ModuleExited).await;
+        assert_eq!(hooks.events(), ["connect", "ready", "close ModuleExited"]);
+    }
+
+    #[tokio::test]
+    async fn on_close_sees_every_deprecated_feature() {
+        let hooks = Arc::new(RecordingHooks::default());
+        let conn = ConnectionContext {
+            deprecated_features: vec!["protocol_v1"],
+            ..conn()
+        };
+        let mut close = on_ready(hooks.clone(), conn).await;
+        close.deprecated_features(&["protocol_v1", "legacy_subscribe"]);
+        close.close(CloseCause::ClientClosed).await;
+        assert_eq!(
+            hooks.events(),
+            [
+                "ready",
+                r#"close ClientClosed deprecated ["protocol_v1", "legacy_subscribe"]"#
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn on_connect_can_reject() {
+        let hooks = RecordingHooks {
+            reject: true,
+            ..<_>::default()
+        };
+        assert!(on_connect(&hooks, &conn()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cancelled_actor_still_runs_on_close() {
+        let hooks = Arc::new(RecordingHooks::default());
+        let actor = tokio::spawn({
+            let hooks = hooks.clone();
+            async move {
+                let _close = on_ready(hooks, conn()).await;
+                std::future::pending::<()>().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        actor.abort();
+        assert!(actor.await.unwrap_err().is_cancelled());
+        // Let the spawned `on_close` run.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(hooks.events(), ["ready", "close Cancelled"]);
+    }
+
+    #[tokio::test]
+    async fn slow_hooks_are_bounded() {
+        let hooks = Arc::new(RecordingHooks {
+            stall: true,
+            ..<_>::default()
+        });
+        let err = on_connect(&*hooks, &conn()).await.unwrap_err();
+        assert_eq!(
+            axum::response::IntoResponse::into_response(Err::<(), _>(err)).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        on_ready(hooks.clone(), conn())
+            .await
+            .close(CloseCause::ClientClosed)
+            .await;
+        assert_eq!(hooks.events(), ["connect", "ready", "close ClientClosed"]);
+    }
+
+    async fn open(hooks: &RecordingHooks, target: &str) -> Result<Arc<dyn FirehoseSink>, FirehoseError> {
+        let dir = FirehoseDir::from_path_unchecked("/data/firehose");
+        let target = target.parse().unwrap();
+        open_firehose_sink(hooks, &dir, Identity::ZERO, &target).await
+    }
+
+    #[tokio::test]
+    async fn firehose_sinks_are_in_tree_or_provided_by_hooks() {
+        // In-tree targets don't reach the hooks.
+        let hooks = RecordingHooks::default();
+        assert!(open(&hooks, "file:events").await.is_ok());
+        assert!(open(&hooks, "kafka://broker/events").await.is_ok());
+        assert!(matches!(
+            open(&hooks, "nats://broker/events").await,
+            Err(FirehoseError::Unsupported(_))
+        ));
+        assert_eq!(
+            hooks.events(),
+            ["open kafka://broker/events", "open nats://broker/events"]
+        );
+
+        let failing = RecordingHooks {
+            reject: true,
+            ..<_>::default()
+        };
+        assert!(matches!(
+            open(&failing, "kafka://broker/events").await,
+            Err(FirehoseError::Open { .. })
+        ));
+
+        let stalled = RecordingHooks {
+            stall: true,
+            ..<_>::default()
+        };
+        assert!(matches!(
+            open(&stalled, "kafka://broker/events").await,
+            Err(FirehoseError::Open { .. })
+        ));
+    }
+}
@@ -1,24 +1,38 @@
 use std::num::NonZeroU8;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use axum::response::ErrorResponse;
 use http::StatusCode;
 
-use spacetimedb::client::ClientActorIndex;
+use spacetimedb::auth::identity::{JwtError, SpacetimeIdentityClaims};
+use spacetimedb::auth::token_validation::{TokenSigner, TokenValidationError, TokenValidator};
+use spacetimedb::client::uniques::{Day, IdentitySketch};
+use spacetimedb::client::{AdmissionPermit, ClientActorIndex, NodeOverloaded};
 use spacetimedb::energy::{EnergyBalance, EnergyQuanta};
 use spacetimedb::host::{HostController, ModuleHost, NoSuchModule, UpdateDatabaseResult};
 use spacetimedb::identity::{AuthCtx, Identity};
-use spacetimedb::messages::control_db::{Database, HostType, Node, Replica};
+use spacetimedb::messages::control_db::{
+    AuthExpiryPolicy, ClientConfigDefaults, ConnectionTimeouts, Database, FirehoseConfig, HostType, MessageSizeLimits,
+    NetworkAcl, Node, Replica, SendBacklogPolicy,
+};
 use spacetimedb::sql;
+use spacetimedb_client_api_messages::e2e;
 use spacetimedb_client_api_messages::http::{SqlStmtResult, SqlStmtStats};
 use spacetimedb_client_api_messages::name::{DomainName, InsertDomainResult, RegisterTldResult, SetDomainsResult, Tld};
-use spacetimedb_lib::{ProductTypeElement, ProductValue};
+use spacetimedb_lib::hash::Hash;
+use spacetimedb_lib::{DatabaseFeatures, ProductTypeElement, ProductValue};
 use spacetimedb_paths::server::ModuleLogsDir;
+use spacetimedb_schema::schema_diff::SchemaDiff;
 use tokio::sync::watch;
 
+pub mod acl;
 pub mod auth;
+pub mod deprecation;
+pub mod hooks;
 pub mod routes;
+pub mod timeouts;
 pub mod util;
 
 /// Defines the state / environment of a SpacetimeDB node from the PoV of the
@@ -26,6 +40,10 @@ pub mod util;
 ///
 /// Types returned here should be considered internal state and **never** be
 /// surfaced to the API.
+///
+/// Routes which only need part of this depend on the capabilities it provides instead,
+/// i.e. [`ClientActors`], [`LeaderLookup`] and [`MetricsSink`],
+/// so that embedders can serve them without implementing all of it.
 #[async_trait]
 pub trait NodeDelegate: Send + Sync {
     fn gather_metrics(&self) -> Vec<prometheus::proto::MetricFamily>;
@@ -39,6 +57,25 @@ pub trait NodeDelegate: Send + Sync {
     /// The [`Host`] is spawned implicitly if not already running.
     async fn leader(&self, database_id: u64) -> anyhow::Result<Option<Host>>;
     fn module_logs_dir(&self, replica_id: u64) -> ModuleLogsDir;
+
+    /// Whether a failed [`Self::leader`] lookup should be retried once
+    /// before reporting an error, if the database still exists.
+    ///
+    /// Useful when the control state is remote and may fail transiently.
+    fn retry_leader_lookup(&self) -> bool {
+        false
+    }
+
+    /// The [`ConnectionLifecycleHooks`](hooks::ConnectionLifecycleHooks)
+    /// to run for websocket connections to this node.
+    fn connection_hooks(&self) -> Arc<dyn hooks::ConnectionLifecycleHooks> {
+        Arc::new(hooks::NoopConnectionHooks)
+    }
+
+    /// The current [`TimeoutsConfig`](timeouts::TimeoutsConfig) of websocket connections to this node.
+    fn timeouts_config(&self) -> Arc<timeouts::TimeoutsConfig> {
+        Arc::default()
+    }
 }
 
 /// Client view of a running module.
@@ -202,6 +239,41 @@ pub trait ControlStateReadAccess {
     // DNS
     fn lookup_identity(&self, domain: &str) -> anyhow::Result<Option<Identity>>;
     fn reverse_lookup(&self, database_identity: &Identity) -> anyhow::Result<Vec<DomainName>>;
+
+    // Network access
+    fn get_network_acl(&self, database_identity: &Identity) -> anyhow::Result<Option<NetworkAcl>>;
+
+    // Client config
+    fn get_client_config_defaults(&self, database_identity: &Identity) -> anyhow::Result<Option<ClientConfigDefaults>>;
+    fn get_connection_timeouts(&self, database_identity: &Identity) -> anyhow::Result<Option<ConnectionTimeouts>>;
+    fn get_message_size_limits(&self, database_identity: &Identity) -> anyhow::Result<Option<MessageSizeLimits>>;
+    fn get_send_backlog_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<SendBacklogPolicy>>;
+    fn get_auth_expiry_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<AuthExpiryPolicy>>;
+
+    // Features
+    fn get_database_features(&self, database_identity: &Identity) -> anyhow::Result<Option<DatabaseFeatures>>;
+
+    // Firehose
+    fn get_firehose(&self, database_identity: &Identity) -> anyhow::Result<Option<FirehoseConfig>>;
+
+    // Schema diffs
+    /// Returns the diff of the schema of `database_identity` from the schema with digest `from` to that with `to`,
+    /// if a publish made that change.
+    fn get_schema_diff(
+        &self,
+        database_identity: &Identity,
+        from: &Hash,
+        to: &Hash,
+    ) -> anyhow::Result<Option<SchemaDiff>>;
+
+    // Unique identities
+    /// Returns the persisted sketches of the identities which connected to `database_identity` on each of `days`,
+    /// for those days on which any did.
+    fn get_unique_identities(
+        &self,
+        database_identity: &Identity,
+        days: RangeInclusive<Day>,
+    ) -> anyhow::Result<Vec<(Day, IdentitySketch)>>;
 }
 
 /// Write operations on the SpacetimeDB control plane.
@@ -251,6 +323,61 @@ pub trait ControlStateWriteAccess: Send + Sync {
         owner_identity: &Identity,
         domain_names: &[DomainName],
     ) -> anyhow::Result<SetDomainsResult>;
+
+    // Network access
+    /// Replace the network ACL of `database_identity`, removing it if `acl` is empty.
+    ///
+    /// The caller is responsible for checking that `acl` is well-formed.
+    async fn set_network_acl(&self, database_identity: &Identity, acl: NetworkAcl) -> anyhow::Result<()>;
+
+    // Client config
+    /// Replace the client config defaults of `database_identity`, removing them if `defaults` is empty.
+    async fn set_client_config_defaults(
+        &self,
+        database_identity: &Identity,
+        defaults: ClientConfigDefaults,
+    ) -> anyhow::Result<()>;
+    /// Replace the connection timeouts of `database_identity`, removing them if `timeouts` is empty.
+    async fn set_connection_timeouts(
+        &self,
+        database_identity: &Identity,
+        timeouts: ConnectionTimeouts,
+    ) -> anyhow::Result<()>;
+    /// Replace the message size limits of `database_identity`, removing them if `limits` is empty.
+    async fn set_message_size_limits(
+        &self,
+        database_identity: &Identity,
+        limits: MessageSizeLimits,
+    ) -> anyhow::Result<()>;
+    /// Replace the send backlog policy of `database_identity`, removing it if `policy` is empty.
+    async fn set_send_backlog_policy(
+        &self,
+        database_identity: &Identity,
+        policy: SendBacklogPolicy,
+    ) -> anyhow::Result<()>;
+    /// Replace the auth expiry policy of `database_identity`, removing it if `policy` is empty.
+    async fn set_auth_expiry_policy(
+        &self,
+        database_identity: &Identity,
+        policy: AuthExpiryPolicy,
+    ) -> anyhow::Result<()>;
+
+    // Features
+    /// Replace the features turned on for `database_identity`, removing them if `features` is empty,
+    /// which apply to the database's module from its next reducer call, and to its clients as they next connect.
+    async fn set_database_features(
+        &self,
+        database_identity: &Identity,
+        features: DatabaseFeatures,
+    ) -> anyhow::Result<()>;
+
+    // Firehose
+    /// Replace the firehose of `database_identity`, removing it if `firehose` is `None`,
+    /// and attach its sink to the database, or detach the database's sink, without restarting its module.
+    ///
+    /// Fails with a [`FirehoseError`](spacetimedb::subscription::firehose::FirehoseError)
+    /// if the target is invalid, or its sink can't be opened, in which case the firehose is left as it was.
+    async fn set_firehose(&self, database_identity: &Identity, firehose: Option<FirehoseConfig>) -> anyhow::Result<()>;
 }
 
 impl<T: ControlStateReadAccess + ?Sized> ControlStateReadAccess for Arc<T> {
@@ -301,6 +428,56 @@ impl<T: ControlStateReadAccess + ?Sized> ControlStateReadAccess for Arc<T> {
     fn get_leader_replica_by_database(&self, database_id: u64) -> Option<Replica> {
         (**self).get_leader_replica_by_database(database_id)
     }
+
+    // Network access
+    fn get_network_acl(&self, database_identity: &Identity) -> anyhow::Result<Option<NetworkAcl>> {
+        (**self).get_network_acl(database_identity)
+    }
+
+    // Client config
+    fn get_client_config_defaults(&self, database_identity: &Identity) -> anyhow::Result<Option<ClientConfigDefaults>> {
+        (**self).get_client_config_defaults(database_identity)
+    }
+    fn get_connection_timeouts(&self, database_identity: &Identity) -> anyhow::Result<Option<ConnectionTimeouts>> {
+        (**self).get_connection_timeouts(database_identity)
+    }
+    fn get_message_size_limits(&self, database_identity: &Identity) -> anyhow::Result<Option<MessageSizeLimits>> {
+        (**self).get_message_size_limits(database_identity)
+    }
+    fn get_send_backlog_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<SendBacklogPolicy>> {
+        (**self).get_send_backlog_policy(database_identity)
+    }
+    fn get_auth_expiry_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<AuthExpiryPolicy>> {
+        (**self).get_auth_expiry_policy(database_identity)
+    }
+
+    fn get_database_features(&self, database_identity: &Identity) -> anyhow::Result<Option<DatabaseFeatures>> {
+        (**self).get_database_features(database_identity)
+    }
+
+    // Firehose
+    fn get_firehose(&self, database_identity: &Identity) -> anyhow::Result<Option<FirehoseConfig>> {
+        (**self).get_firehose(database_identity)
+    }
+
+    // Schema diffs
+    fn get_schema_diff(
+        &self,
+        database_identity: &Identity,
+        from: &Hash,
+        to: &Hash,
+    ) -> anyhow::Result<Option<SchemaDiff>> {
+        (**self).get_schema_diff(database_identity, from, to)
+    }
+
+    // Unique identities
+    fn get_unique_identities(
+        &self,
+        database_identity: &Identity,
+        days: RangeInclusive<Day>,
+    ) -> anyhow::Result<Vec<(Day, IdentitySketch)>> {
+        (**self).get_unique_identities(database_identity, days)
+    }
 }
 
 #[async_trait]
@@ -347,6 +524,62 @@ impl<T: ControlStateWriteAccess + ?Sized> ControlStateWriteAccess for Arc<T> {
             .replace_dns_records(database_identity, owner_identity, domain_names)
             .await
     }
+
+    async fn set_network_acl(&self, database_identity: &Identity, acl: NetworkAcl) -> anyhow::Result<()> {
+        (**self).set_network_acl(database_identity, acl).await
+    }
+
+    async fn set_client_config_defaults(
+        &self,
+        database_identity: &Identity,
+        defaults: ClientConfigDefaults,
+    ) -> anyhow::Result<()> {
+        (**self).set_client_config_defaults(database_identity, defaults).await
+    }
+
+    async fn set_connection_timeouts(
+        &self,
+        database_identity: &Identity,
+        timeouts: ConnectionTimeouts,
+    ) -> anyhow::Result<()> {
+        (**self).set_connection_timeouts(database_identity, timeouts).await
+    }
+
+    async fn set_message_size_limits(
+        &self,
+        database_identity: &Identity,
+        limits: MessageSizeLimits,
+    ) -> anyhow::Result<()> {
+        (**self).set_message_size_limits(database_identity, limits).await
+    }
+
+    async fn set_send_backlog_policy(
+        &self,
+        database_identity: &Identity,
+        policy: SendBacklogPolicy,
+    ) -> anyhow::Result<()> {
+        (**self).set_send_backlog_policy(database_identity, policy).await
+    }
+
+    async fn set_auth_expiry_policy(
+        &self,
+        database_identity: &Identity,
+        policy: AuthExpiryPolicy,
+    ) -> anyhow::Result<()> {
+        (**self).set_auth_expiry_policy(database_identity, policy).await
+    }
+
+    async fn set_database_features(
+        &self,
+        database_identity: &Identity,
+        features: DatabaseFeatures,
+    ) -> anyhow::Result<()> {
+        (**self).set_database_features(database_identity, features).await
+    }
+
+    async fn set_firehose(&self, database_identity: &Identity, firehose: Option<FirehoseConfig>) -> anyhow::Result<()> {
+        (**self).set_firehose(database_identity, firehose).await
+    }
 }
 
 #[async_trait]
@@ -371,6 +604,193 @@ impl<T: NodeDelegate + ?Sized> NodeDelegate for Arc<T> {
     fn module_logs_dir(&self, replica_id: u64) -> ModuleLogsDir {
         (**self).module_logs_dir(replica_id)
     }
+
+    fn retry_leader_lookup(&self) -> bool {
+        (**self).retry_leader_lookup()
+    }
+
+    fn connection_hooks(&self) -> Arc<dyn hooks::ConnectionLifecycleHooks> {
+        (**self).connection_hooks()
+    }
+
+    fn timeouts_config(&self) -> Arc<timeouts::TimeoutsConfig> {
+        (**self).timeouts_config()
+    }
+}
+
+/// The capability of resolving the databases clients ask for,
+/// as needed by the subscribe route.
+///
+/// Every [`ControlStateReadAccess`] has this capability,
+/// but an embedder can provide it without implementing the whole control state.
+pub trait DatabaseResolution {
+    /// Returns the identity of the database named `name`, if any.
+    fn resolve_database_name(&self, name: &str) -> anyhow::Result<Option<Identity>>;
+    /// Returns the database with `database_identity`, if any.
+    fn find_database(&self, database_identity: &Identity) -> anyhow::Result<Option<Database>>;
+    /// Returns the network ACL of the database with `database_identity`, if it has one.
+    fn find_network_acl(&self, database_identity: &Identity) -> anyhow::Result<Option<NetworkAcl>>;
+    /// Returns the client config defaults of the database with `database_identity`, if it has any.
+    fn find_client_config_defaults(&self, database_identity: &Identity)
+        -> anyhow::Result<Option<ClientConfigDefaults>>;
+    /// Returns the connection timeouts of the database with `database_identity`, if it overrides any.
+    fn find_connection_timeouts(&self, database_identity: &Identity) -> anyhow::Result<Option<ConnectionTimeouts>>;
+    /// Returns the message size limits of the database with `database_identity`, if it sets any.
+    fn find_message_size_limits(&self, database_identity: &Identity) -> anyhow::Result<Option<MessageSizeLimits>>;
+    /// Returns the send backlog policy of the database with `database_identity`, if it sets one.
+    fn find_send_backlog_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<SendBacklogPolicy>>;
+    /// Returns the auth expiry policy of the database with `database_identity`, if it sets one.
+    fn find_auth_expiry_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<AuthExpiryPolicy>>;
+    /// Returns the features turned on for the database with `database_identity`, if any are.
+    fn find_database_features(&self, database_identity: &Identity) -> anyhow::Result<Option<DatabaseFeatures>>;
+}
+
+impl<T: ControlStateReadAccess + ?Sized> DatabaseResolution for T {
+    fn resolve_database_name(&self, name: &str) -> anyhow::Result<Option<Identity>> {
+        self.lookup_identity(name)
+    }
+
+    fn find_database(&self, database_identity: &Identity) -> anyhow::Result<Option<Database>> {
+        self.get_database_by_identity(database_identity)
+    }
+
+    fn find_network_acl(&self, database_identity: &Identity) -> anyhow::Result<Option<NetworkAcl>> {
+        self.get_network_acl(database_identity)
+    }
+
+    fn find_client_config_defaults(
+        &self,
+        database_identity: &Identity,
+    ) -> anyhow::Result<Option<ClientConfigDefaults>> {
+        self.get_client_config_defaults(database_identity)
+    }
+
+    fn find_connection_timeouts(&self, database_identity: &Identity) -> anyhow::Result<Option<ConnectionTimeouts>> {
+        self.get_connection_timeouts(database_identity)
+    }
+
+    fn find_message_size_limits(&self, database_identity: &Identity) -> anyhow::Result<Option<MessageSizeLimits>> {
+        self.get_message_size_limits(database_identity)
+    }
+
+    fn find_send_backlog_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<SendBacklogPolicy>> {
+        self.get_send_backlog_policy(database_identity)
+    }
+
+    fn find_auth_expiry_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<AuthExpiryPolicy>> {
+        self.get_auth_expiry_policy(database_identity)
+    }
+
+    fn find_database_features(&self, database_identity: &Identity) -> anyhow::Result<Option<DatabaseFeatures>> {
+        self.get_database_features(database_identity)
+    }
+}
+
+/// The capability of looking up the leader [`Host`] of a database,
+/// as needed by the subscribe route.
+///
+/// Every [`NodeDelegate`] has this capability.
+#[async_trait]
+pub trait LeaderLookup: Send + Sync {
+    /// See [`NodeDelegate::leader`].
+    async fn find_leader_host(&self, database_id: u64) -> anyhow::Result<Option<Host>>;
+
+    /// See [`NodeDelegate::retry_leader_lookup`].
+    fn retry_failed_leader_lookup(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl<T: NodeDelegate + ?Sized> LeaderLookup for T {
+    async fn find_leader_host(&self, database_id: u64) -> anyhow::Result<Option<Host>> {
+        self.leader(database_id).await
+    }
+
+    fn retry_failed_leader_lookup(&self) -> bool {
+        self.retry_leader_lookup()
+    }
+}
+
+/// The capability of hosting the actors of client connections:
+/// admitting clients, registering their connections and validating the tokens they re-authenticate with.
+///
+/// Every [`NodeDelegate`] has this capability.
+/// An embedder which wants to decide for itself which clients to admit
+/// can provide it without implementing the whole [`NodeDelegate`].
+#[async_trait]
+pub trait ClientActors: Send + Sync {
+    /// The index of the client connections to this node.
+    fn actor_index(&self) -> &ClientActorIndex;
+
+    /// Admit a new client, or refuse it if the node can't take any more clients.
+    ///
+    /// Called before doing any work on behalf of the client.
+    /// The client holds a slot until the returned permit is dropped, see [`ClientActorIndex::try_admit`].
+    fn admit_client(&self) -> Result<AdmissionPermit, NodeOverloaded> {
+        self.actor_index().try_admit()
+    }
+
+    /// The [`ConnectionLifecycleHooks`](hooks::ConnectionLifecycleHooks)
+    /// to run for websocket connections to this node.
+    fn lifecycle_hooks(&self) -> Arc<dyn hooks::ConnectionLifecycleHooks> {
+        Arc::new(hooks::NoopConnectionHooks)
+    }
+
+    /// The [`TimeoutsConfig`](timeouts::TimeoutsConfig) of connections made now,
+    /// which they keep for their lifetime.
+    fn timeouts(&self) -> Arc<timeouts::TimeoutsConfig> {
+        Arc::default()
+    }
+
+    /// Validate the token a connected client re-authenticates with.
+    async fn validate_client_token(&self, token: &str) -> Result<SpacetimeIdentityClaims, TokenValidationError>;
+
+    /// Sign `binding` with the node's token signing key, see [`e2e::KeyBinding`].
+    ///
+    /// Returns `None` if there's no key to sign it with,
+    /// in which case end-to-end encrypted connections only stop passive observers.
+    fn sign_e2e_key_binding(&self, _binding: &e2e::KeyBinding) -> Option<Result<String, JwtError>> {
+        None
+    }
+}
+
+#[async_trait]
+impl<T: NodeDelegate + ?Sized> ClientActors for T {
+    fn actor_index(&self) -> &ClientActorIndex {
+        self.client_actor_index()
+    }
+
+    fn lifecycle_hooks(&self) -> Arc<dyn hooks::ConnectionLifecycleHooks> {
+        self.connection_hooks()
+    }
+
+    fn timeouts(&self) -> Arc<timeouts::TimeoutsConfig> {
+        self.timeouts_config()
+    }
+
+    async fn validate_client_token(&self, token: &str) -> Result<SpacetimeIdentityClaims, TokenValidationError> {
+        auth::JwtAuthProvider::validator(self.jwt_auth_provider())
+            .validate_token(token)
+            .await
+    }
+
+    fn sign_e2e_key_binding(&self, binding: &e2e::KeyBinding) -> Option<Result<String, JwtError>> {
+        Some(self.jwt_auth_provider().sign(binding))
+    }
+}
+
+/// The capability of providing the metrics of a node, besides those in the default registry.
+///
+/// Every [`NodeDelegate`] has this capability.
+pub trait MetricsSink: Send + Sync {
+    fn collect_metrics(&self) -> Vec<prometheus::proto::MetricFamily>;
+}
+
+impl<T: NodeDelegate + ?Sized> MetricsSink for T {
+    fn collect_metrics(&self) -> Vec<prometheus::proto::MetricFamily> {
+        self.gather_metrics()
+    }
 }
 
 pub fn log_and_500(e: impl std::fmt::Display) -> ErrorResponse {
@@ -1,35 +1,57 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
 use std::num::NonZeroU8;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
+use crate::acl;
 use crate::auth::{
     anon_auth_middleware, SpacetimeAuth, SpacetimeEnergyUsed, SpacetimeExecutionDurationMicros, SpacetimeIdentity,
-    SpacetimeIdentityToken,
+    SpacetimeIdentityToken, TraceParentHeader, TraceResponseHeader,
 };
-use crate::routes::subscribe::generate_random_connection_id;
-use crate::util::{ByteStringBody, NameOrIdentity};
-use crate::{log_and_500, ControlStateDelegate, DatabaseDef, NodeDelegate};
+use crate::routes::identity::IdentityForUrl;
+use crate::routes::subscribe::{
+    database_stats, generate_random_connection_id, protocol_name, purge_replay_buffers, PurgedReplayBuffers,
+};
+use crate::util::{ByteStringBody, ClientAddr, NameOrIdentity};
+use crate::{log_and_500, ControlStateDelegate, DatabaseDef, DatabaseResolution, Host, LeaderLookup, NodeDelegate};
 use axum::body::{Body, Bytes};
 use axum::extract::{Path, Query, State};
 use axum::response::{ErrorResponse, IntoResponse};
 use axum::routing::MethodRouter;
 use axum::Extension;
 use axum_extra::TypedHeader;
+use chrono::NaiveDate;
 use futures::StreamExt;
-use http::StatusCode;
-use serde::Deserialize;
+use http::{header, HeaderName, HeaderValue, StatusCode};
+use serde::{Deserialize, Serialize};
+use spacetimedb::client::uniques::{self, Day, IdentitySketch};
+use spacetimedb::client::ListedClient;
 use spacetimedb::database_logger::DatabaseLogger;
-use spacetimedb::host::module_host::ClientConnectedError;
+use spacetimedb::host::metering::{ExecutionMetering, EPOCH_TICK_LENGTH};
+use spacetimedb::host::module_host::{ClientConnectedError, QueryKind};
+use spacetimedb::host::module_schema::ModuleSchema;
+use spacetimedb::host::ModuleExitCause;
 use spacetimedb::host::ReducerArgs;
 use spacetimedb::host::ReducerCallError;
 use spacetimedb::host::ReducerOutcome;
 use spacetimedb::host::UpdateDatabaseResult;
 use spacetimedb::identity::Identity;
-use spacetimedb::messages::control_db::{Database, HostType};
+use spacetimedb::messages::control_db::{
+    AuthExpiryAction, AuthExpiryPolicy, ClientConfigDefaults, ClientDefault, ConnectionTimeouts, Database,
+    FirehoseConfig, HostType, MessageSizeLimits, NetworkAcl, SendBacklogAction, SendBacklogPolicy,
+};
+use spacetimedb::subscription::dump::ClientSubscriptions;
+use spacetimedb::subscription::firehose::{FirehoseError, FirehoseTarget};
+use spacetimedb::worker_metrics::WORKER_METRICS;
 use spacetimedb_client_api_messages::name::{self, DatabaseName, DomainName, PublishOp, PublishResult};
-use spacetimedb_lib::db::raw_def::v9::RawModuleDefV9;
+use spacetimedb_client_api_messages::websocket::Compression;
+use spacetimedb_lib::connection_id::ConnectionIdForUrl;
+use spacetimedb_lib::hash::Hash;
 use spacetimedb_lib::identity::AuthCtx;
-use spacetimedb_lib::{sats, Timestamp};
+use spacetimedb_lib::{sats, DatabaseFeature, DatabaseFeatures, ReducerTransport, Timestamp};
+use spacetimedb_schema::schema_diff::SchemaDiff;
 
 use super::subscribe::handle_websocket;
 
@@ -49,6 +71,9 @@ pub async fn call<S: ControlStateDelegate + NodeDelegate>(
         reducer,
     }): Path<CallParams>,
     TypedHeader(content_type): TypedHeader<headers::ContentType>,
+    // A missing or malformed `traceparent` starts a new trace.
+    traceparent: Option<TypedHeader<TraceParentHeader>>,
+    client_addr: ClientAddr,
     ByteStringBody(body): ByteStringBody,
 ) -> axum::response::Result<impl IntoResponse> {
     if content_type != headers::ContentType::json() {
@@ -59,6 +84,7 @@ pub async fn call<S: ControlStateDelegate + NodeDelegate>(
     let args = ReducerArgs::Json(body);
 
     let db_identity = name_or_identity.resolve(&worker_ctx).await?;
+    acl::check(&worker_ctx, &db_identity, client_addr).await?;
     let database = worker_ctx_find_database(&worker_ctx, &db_identity)
         .await?
         .ok_or_else(|| {
@@ -67,11 +93,7 @@ pub async fn call<S: ControlStateDelegate + NodeDelegate>(
         })?;
     let identity = database.owner_identity;
 
-    let leader = worker_ctx
-        .leader(database.id)
-        .await
-        .map_err(log_and_500)?
-        .ok_or(StatusCode::NOT_FOUND)?;
+    let leader = find_leader(&worker_ctx, &database).await?;
     let module = leader.module().await.map_err(log_and_500)?;
 
     // HTTP callers always need a connection ID to provide to connect/disconnect,
@@ -108,7 +130,17 @@ pub async fn call<S: ControlStateDelegate + NodeDelegate>(
         Ok(()) => (),
     }
     let result = match module
-        .call_reducer(caller_identity, Some(connection_id), None, None, None, &reducer, args)
+        .call_reducer(
+            caller_identity,
+            Some(connection_id),
+            None,
+            ReducerTransport::Http,
+            None,
+            None,
+            traceparent.map(|TypedHeader(TraceParentHeader(traceparent))| traceparent),
+            &reducer,
+            args,
+        )
         .await
     {
         Ok(rcr) => Ok(rcr),
@@ -127,6 +159,11 @@ pub async fn call<S: ControlStateDelegate + NodeDelegate>(
                     log::debug!("Attempt to call {lifecycle:?} lifecycle reducer {}", reducer);
                     StatusCode::BAD_REQUEST
                 }
+                ReducerCallError::NotClientCallable => {
+                    log::debug!("Attempt to call reducer {} which is not callable by clients", reducer);
+                    StatusCode::FORBIDDEN
+                }
+                ReducerCallError::Publishing(_) => StatusCode::SERVICE_UNAVAILABLE,
             };
 
             log::debug!("Error while invoking reducer {:#}", e);
@@ -149,6 +186,7 @@ pub async fn call<S: ControlStateDelegate + NodeDelegate>(
                 status,
                 TypedHeader(SpacetimeEnergyUsed(result.energy_used)),
                 TypedHeader(SpacetimeExecutionDurationMicros(result.execution_duration)),
+                TypedHeader(TraceResponseHeader(result.traceparent)),
                 body,
             ))
         }
@@ -213,28 +251,92 @@ where
         .await?
         .ok_or(NO_SUCH_DATABASE)?;
 
-    let leader = worker_ctx
-        .leader(database.id)
-        .await
-        .map_err(log_and_500)?
-        .ok_or(StatusCode::NOT_FOUND)?;
+    let leader = find_leader(&worker_ctx, &database).await?;
     let module = leader.module().await.map_err(log_and_500)?;
 
-    let module_def = &module.info.module_def;
+    // Serve the schema serialized when the module was created,
+    // leaving out the private tables for anyone but the owner.
+    let schema = &module.info.schema;
+    let is_owner = auth.identity == database.owner_identity;
     let response_json = match version {
-        SchemaVersion::V9 => {
-            let raw = RawModuleDefV9::from(module_def.clone());
-            axum::Json(sats::serde::SerdeWrapper(raw)).into_response()
-        }
+        SchemaVersion::V9 if is_owner => schema.v9_json.clone(),
+        SchemaVersion::V9 => schema.public_v9_json.clone(),
     };
 
     Ok((
         TypedHeader(SpacetimeIdentity(auth.identity)),
         TypedHeader(SpacetimeIdentityToken(auth.creds)),
+        module_schema_headers(schema),
+        [(header::CONTENT_TYPE, HeaderValue::from_static("application/json"))],
         response_json,
     ))
 }
 
+/// Headers describing the module whose schema is served, or to which a client is connecting.
+///
+/// A client can tell from these whether the module was updated between two requests.
+pub(crate) fn module_schema_headers(schema: &ModuleSchema) -> [(HeaderName, HeaderValue); 3] {
+    [
+        (
+            HeaderName::from_static("spacetime-module-hash"),
+            schema.module_hash.to_hex().as_str().try_into().unwrap(),
+        ),
+        (
+            HeaderName::from_static("spacetime-schema-digest"),
+            schema.digest.to_hex().as_str().try_into().unwrap(),
+        ),
+        (
+            HeaderName::from_static("spacetime-module-generation"),
+            schema.generation.into(),
+        ),
+    ]
+}
+
+#[derive(Deserialize)]
+pub struct SchemaDiffQueryParams {
+    from: String,
+    to: String,
+}
+
+/// Returns how a publish changed the schema of this database,
+/// from the schema with digest `from` to that with digest `to`,
+/// as served in the `spacetime-schema-digest` header.
+///
+/// Each change is tagged as breaking or not,
+/// so that a client built against the old schema can tell whether it can keep running.
+/// Diffs are recorded by the publishes which make them,
+/// so schemas not separated by exactly one publish are `404 Not Found`.
+pub async fn schema_diff<S>(
+    State(worker_ctx): State<S>,
+    Path(SchemaParams { name_or_identity }): Path<SchemaParams>,
+    Query(SchemaDiffQueryParams { from, to }): Query<SchemaDiffQueryParams>,
+) -> axum::response::Result<impl IntoResponse>
+where
+    S: ControlStateDelegate + NodeDelegate,
+{
+    let parse = |digest: &str| {
+        Hash::from_hex(digest).map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid schema digest: {digest}")))
+    };
+    let (from, to) = (parse(&from)?, parse(&to)?);
+    let db_identity = name_or_identity.resolve(&worker_ctx).await?;
+    let database = worker_ctx_find_database(&worker_ctx, &db_identity)
+        .await?
+        .ok_or(NO_SUCH_DATABASE)?;
+
+    let diff = if from == to {
+        SchemaDiff::default()
+    } else {
+        worker_ctx
+            .get_schema_diff(&database.database_identity, &from, &to)
+            .map_err(log_and_500)?
+            .ok_or((
+                StatusCode::NOT_FOUND,
+                "No publish changed the schema between these digests",
+            ))?
+    };
+    Ok(axum::Json(sats::serde::SerdeWrapper(diff)))
+}
+
 #[derive(Deserialize)]
 pub struct DatabaseParam {
     name_or_identity: NameOrIdentity,
@@ -246,6 +348,31 @@ struct DatabaseResponse {
     owner_identity: Identity,
     host_type: HostType,
     initial_program: spacetimedb_lib::Hash,
+    /// How the reducers of the database's module are metered,
+    /// or `None` if its module isn't running.
+    execution_metering: Option<ExecutionMeteringResponse>,
+}
+
+#[derive(sats::Serialize)]
+struct ExecutionMeteringResponse {
+    mode: String,
+    fuel: bool,
+    epoch_tick_ms: u64,
+    epoch_interval_ms: Option<u64>,
+    max_reducer_duration_ms: Option<u64>,
+}
+
+impl From<ExecutionMetering> for ExecutionMeteringResponse {
+    fn from(metering: ExecutionMetering) -> Self {
+        let as_ms = |d: Duration| d.as_millis().try_into().unwrap_or(u64::MAX);
+        ExecutionMeteringResponse {
+            mode: metering.mode().to_owned(),
+            fuel: metering.fuel,
+            epoch_tick_ms: as_ms(EPOCH_TICK_LENGTH),
+            epoch_interval_ms: metering.epoch.map(|epoch| as_ms(epoch.interval())),
+            max_reducer_duration_ms: metering.epoch.and_then(|epoch| epoch.max_reducer_duration).map(as_ms),
+        }
+    }
 }
 
 impl From<Database> for DatabaseResponse {
@@ -255,11 +382,12 @@ impl From<Database> for DatabaseResponse {
             owner_identity: db.owner_identity,
             host_type: db.host_type,
             initial_program: db.initial_program,
+            execution_metering: None,
         }
     }
 }
 
-pub async fn db_info<S: ControlStateDelegate>(
+pub async fn db_info<S: ControlStateDelegate + NodeDelegate>(
     State(worker_ctx): State<S>,
     Path(DatabaseParam { name_or_identity }): Path<DatabaseParam>,
 ) -> axum::response::Result<impl IntoResponse> {
@@ -271,7 +399,14 @@ pub async fn db_info<S: ControlStateDelegate>(
         .ok_or(NO_SUCH_DATABASE)?;
     log::trace!("Fetched database from the worker db for database identity: {database_identity:?}");
 
-    let response = DatabaseResponse::from(database);
+    let database_id = database.id;
+    let mut response = DatabaseResponse::from(database);
+    // Only the leader knows how its module is metered, and it may not be running.
+    if let Some(host) = worker_ctx.find_leader_host(database_id).await.ok().flatten() {
+        if let Ok(module) = host.module().await {
+            response.execution_metering = Some(module.execution_metering().await.into());
+        }
+    }
     Ok(axum::Json(sats::serde::SerdeWrapper(response)))
 }
 
@@ -325,11 +460,7 @@ where
     let lines = DatabaseLogger::read_latest(logs_dir, num_lines).await;
 
     let body = if follow {
-        let leader = worker_ctx
-            .leader(database.id)
-            .await
-            .map_err(log_and_500)?
-            .ok_or(StatusCode::NOT_FOUND)?;
+        let leader = find_leader(&worker_ctx, &database).await?;
         let log_rx = leader
             .module()
             .await
@@ -372,12 +503,119 @@ fn mime_ndjson() -> mime::Mime {
 }
 
 pub(crate) async fn worker_ctx_find_database(
-    worker_ctx: &(impl ControlStateDelegate + ?Sized),
+    worker_ctx: &(impl DatabaseResolution + ?Sized),
     database_identity: &Identity,
 ) -> axum::response::Result<Option<Database>> {
-    worker_ctx
-        .get_database_by_identity(database_identity)
-        .map_err(log_and_500)
+    worker_ctx.find_database(database_identity).map_err(log_and_500)
+}
+
+/// How long clients are told to wait before retrying a request to a database without a leader.
+pub const LEADER_UNAVAILABLE_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Look up the leader [`Host`] of a `database` just resolved from the control state.
+///
+/// The database may be deleted between being resolved and its leader being looked up,
+/// in which case the lookup fails or comes back empty.
+/// That's a benign race, so we report it as `410 Gone` rather than an internal error.
+///
+/// If the database is still there but has no leader, e.g. as it's failing over,
+/// we report `503 Service Unavailable`, so that clients retry rather than take it for missing.
+pub(crate) async fn find_leader<S>(worker_ctx: &S, database: &Database) -> axum::response::Result<Host>
+where
+    S: DatabaseResolution + LeaderLookup,
+{
+    let retry = worker_ctx.retry_failed_leader_lookup();
+    resolve_leader(worker_ctx, database, retry, || worker_ctx.find_leader_host(database.id)).await
+}
+
+/// The logic of [`find_leader`], generic over the leader lookup so that it can be tested
+/// without spinning up a host.
+async fn resolve_leader<T, F, Fut>(
+    ctl: &(impl DatabaseResolution + ?Sized),
+    database: &Database,
+    retry: bool,
+    mut leader: F,
+) -> axum::response::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<Option<T>>>,
+{
+    let mut retried = false;
+    loop {
+        let err = match leader().await {
+            Ok(Some(leader)) => return Ok(leader),
+            Ok(None) => None,
+            Err(e) => Some(e),
+        };
+
+        // Check whether the database is still there before blaming the control state.
+        match ctl.find_database(&database.database_identity) {
+            Ok(Some(current)) if current.id == database.id => {}
+            Ok(_) => {
+                WORKER_METRICS
+                    .leader_lookup_failures
+                    .with_label_values("database_deleted")
+                    .inc();
+                return Err(database_gone(database));
+            }
+            Err(e) => {
+                WORKER_METRICS
+                    .leader_lookup_failures
+                    .with_label_values("control_state_error")
+                    .inc();
+                return Err(log_and_500(e));
+            }
+        }
+
+        match err {
+            None => {
+                WORKER_METRICS
+                    .leader_lookup_failures
+                    .with_label_values("no_leader")
+                    .inc();
+                return Err(leader_unavailable(database));
+            }
+            Some(e) if retry && !retried => {
+                log::warn!("retrying leader lookup for database {}: {e:#}", database.id);
+                retried = true;
+            }
+            Some(e) => {
+                WORKER_METRICS
+                    .leader_lookup_failures
+                    .with_label_values("control_state_error")
+                    .inc();
+                return Err(log_and_500(e));
+            }
+        }
+    }
+}
+
+fn database_gone(database: &Database) -> ErrorResponse {
+    (
+        StatusCode::GONE,
+        axum::Json(serde_json::json!({
+            "error": "database_deleted",
+            "message": "The database was deleted while handling this request.",
+            "database_identity": database.database_identity.to_hex().as_str(),
+        })),
+    )
+        .into()
+}
+
+fn leader_unavailable(database: &Database) -> ErrorResponse {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(
+            header::RETRY_AFTER,
+            LEADER_UNAVAILABLE_RETRY_AFTER.as_secs().to_string(),
+        )],
+        axum::Json(serde_json::json!({
+            "error": "leader_unavailable",
+            "message": "The database has no leader right now, e.g. as it's failing over. Try again later.",
+            "database_identity": database.database_identity.to_hex().as_str(),
+        })),
+    )
+        .into()
 }
 
 #[derive(Deserialize)]
@@ -393,6 +631,7 @@ pub async fn sql<S>(
     Path(SqlParams { name_or_identity }): Path<SqlParams>,
     Query(SqlQueryParams {}): Query<SqlQueryParams>,
     Extension(auth): Extension<SpacetimeAuth>,
+    client_addr: ClientAddr,
     body: String,
 ) -> axum::response::Result<impl IntoResponse>
 where
@@ -402,6 +641,7 @@ where
     // which queries this identity is allowed to execute against the database.
 
     let db_identity = name_or_identity.resolve(&worker_ctx).await?;
+    acl::check(&worker_ctx, &db_identity, client_addr).await?;
     let database = worker_ctx_find_database(&worker_ctx, &db_identity)
         .await?
         .ok_or(NO_SUCH_DATABASE)?;
@@ -409,11 +649,7 @@ where
     let auth = AuthCtx::new(database.owner_identity, auth.identity);
     log::debug!("auth: {auth:?}");
 
-    let host = worker_ctx
-        .leader(database.id)
-        .await
-        .map_err(log_and_500)?
-        .ok_or(StatusCode::NOT_FOUND)?;
+    let host = find_leader(&worker_ctx, &database).await?;
     let json = host.exec_sql(auth, database, body).await?;
 
     let total_duration = json.iter().fold(0, |acc, x| acc + x.total_duration_micros);
@@ -424,6 +660,192 @@ where
     ))
 }
 
+#[derive(Deserialize)]
+pub struct ExplainParams {
+    name_or_identity: NameOrIdentity,
+}
+
+/// The body of a request to `POST /database/:name_or_identity/explain`.
+#[derive(Deserialize)]
+pub struct ExplainRequest {
+    #[serde(flatten)]
+    query: ExplainQuery,
+    #[serde(default)]
+    kind: ExplainKind,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ExplainQuery {
+    /// A raw SQL query.
+    Query(String),
+    /// Shorthand for `SELECT * FROM <table>`.
+    Table(String),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum ExplainKind {
+    #[default]
+    Subscription,
+    OneOff,
+}
+
+pub async fn explain<S>(
+    State(worker_ctx): State<S>,
+    Path(ExplainParams { name_or_identity }): Path<ExplainParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(ExplainRequest { query, kind }): axum::Json<ExplainRequest>,
+) -> axum::response::Result<impl IntoResponse>
+where
+    S: NodeDelegate + ControlStateDelegate,
+{
+    // Plans reveal which indexes and row level security rules a database has,
+    // so only the owner may see them.
+
+    let db_identity = name_or_identity.resolve(&worker_ctx).await?;
+    let database = worker_ctx_find_database(&worker_ctx, &db_identity)
+        .await?
+        .ok_or(NO_SUCH_DATABASE)?;
+
+    if database.owner_identity != auth.identity {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!(
+                "Identity does not own database, expected: {} got: {}",
+                database.owner_identity.to_hex(),
+                auth.identity.to_hex()
+            ),
+        )
+            .into());
+    }
+
+    let sql = match query {
+        ExplainQuery::Query(sql) => sql,
+        ExplainQuery::Table(table) => format!("SELECT * FROM {table}"),
+    };
+    let kind = match kind {
+        ExplainKind::Subscription => QueryKind::Subscription,
+        ExplainKind::OneOff => QueryKind::OneOff,
+    };
+
+    let module = find_leader(&worker_ctx, &database)
+        .await?
+        .module()
+        .await
+        .map_err(log_and_500)?;
+    let plans = module
+        .explain_query(auth.identity, sql, kind)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+
+    Ok(axum::Json(plans))
+}
+
+#[derive(Deserialize)]
+pub struct StatsParams {
+    name_or_identity: NameOrIdentity,
+}
+
+/// Storage statistics of a database, for capacity planning by its owner.
+///
+/// Unlike `count(*)` queries, this doesn't scan any tables, and so costs no energy.
+/// See [`DatabaseStats`](spacetimedb_client_api_messages::websocket::DatabaseStats)
+/// for the accuracy of each statistic.
+pub async fn stats<S>(
+    State(worker_ctx): State<S>,
+    Path(StatsParams { name_or_identity }): Path<StatsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse>
+where
+    S: ControlStateDelegate + NodeDelegate,
+{
+    let database = owned_database(&worker_ctx, name_or_identity, &auth).await?;
+    let module = find_leader(&worker_ctx, &database)
+        .await?
+        .module()
+        .await
+        .map_err(log_and_500)?;
+
+    Ok(axum::Json(sats::serde::SerdeWrapper(database_stats(&module).await)))
+}
+
+#[derive(Deserialize)]
+pub struct ClientSubscriptionsParams {
+    name_or_identity: NameOrIdentity,
+    connection_id: ConnectionIdForUrl,
+}
+
+/// The queries a client connection of this database is subscribed to,
+/// for debugging reports of missing updates.
+///
+/// See [`ClientSubscriptions`].
+/// The response can be posted to `/clients/subscriptions` of another instance
+/// to register the same queries there.
+pub async fn get_client_subscriptions<S>(
+    State(worker_ctx): State<S>,
+    Path(ClientSubscriptionsParams {
+        name_or_identity,
+        connection_id,
+    }): Path<ClientSubscriptionsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse>
+where
+    S: ControlStateDelegate + NodeDelegate,
+{
+    let database = owned_database(&worker_ctx, name_or_identity, &auth).await?;
+    let module = find_leader(&worker_ctx, &database)
+        .await?
+        .module()
+        .await
+        .map_err(log_and_500)?;
+
+    let subscriptions = module
+        .client_subscriptions(connection_id.into())
+        .await
+        .map_err(log_and_500)?
+        .ok_or((StatusCode::NOT_FOUND, "No such client connection."))?;
+    Ok(axum::Json(sats::serde::SerdeWrapper(subscriptions)))
+}
+
+#[derive(Deserialize)]
+pub struct RestoreClientSubscriptionsParams {
+    name_or_identity: NameOrIdentity,
+}
+
+/// Register the queries of a [`ClientSubscriptions`],
+/// as returned by `/clients/:connection_id/subscriptions`,
+/// under a new test connection with the same identity.
+///
+/// Responds with the subscriptions of the test connection,
+/// which lacks any of the queries that failed to register.
+pub async fn restore_client_subscriptions<S>(
+    State(worker_ctx): State<S>,
+    Path(RestoreClientSubscriptionsParams { name_or_identity }): Path<RestoreClientSubscriptionsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(sats::serde::SerdeWrapper(dump)): axum::Json<sats::serde::SerdeWrapper<ClientSubscriptions>>,
+) -> axum::response::Result<impl IntoResponse>
+where
+    S: ControlStateDelegate + NodeDelegate,
+{
+    let database = owned_database(&worker_ctx, name_or_identity, &auth).await?;
+    let module = find_leader(&worker_ctx, &database)
+        .await?
+        .module()
+        .await
+        .map_err(log_and_500)?;
+
+    if dump.redacted {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The parameters of these subscriptions were redacted, so they can't be registered.",
+        )
+            .into());
+    }
+    let restored = module.restore_client_subscriptions(dump).await.map_err(log_and_500)?;
+    Ok(axum::Json(sats::serde::SerdeWrapper(restored)))
+}
+
 #[derive(Deserialize)]
 pub struct DNSParams {
     name_or_identity: NameOrIdentity,
@@ -553,15 +975,25 @@ pub async fn publish<S: NodeDelegate + ControlStateDelegate>(
     log::trace!("Publishing to the identity: {}", database_identity.to_hex());
 
     let op = {
-        let exists = ctx
-            .get_database_by_identity(&database_identity)
-            .map_err(log_and_500)?
-            .is_some();
+        let existing = ctx.get_database_by_identity(&database_identity).map_err(log_and_500)?;
+        let exists = existing.is_some();
         if !exists {
             allow_creation(&auth)?;
         }
 
-        if clear && exists {
+        if let Some(database) = existing.filter(|_| clear) {
+            // Let connected clients know that the database is about to be replaced,
+            // rather than just deleted, so they can reconnect right away.
+            // Only the owner may delete it, so don't mark it for anyone else.
+            let leader = match database.owner_identity == auth.identity {
+                true => ctx.leader(database.id).await,
+                false => Ok(None),
+            };
+            if let Ok(Some(leader)) = leader {
+                if let Ok(module) = leader.module().await {
+                    let _ = module.info().exit_cause.set(ModuleExitCause::Published);
+                }
+            }
             ctx.delete_database(&auth.identity, &database_identity)
                 .await
                 .map_err(log_and_500)?;
@@ -759,82 +1191,1059 @@ async fn get_timestamp<S: ControlStateDelegate>(
     Ok(axum::Json(sats::serde::SerdeWrapper(Timestamp::now())).into_response())
 }
 
-/// This struct allows the edition to customize `/database` routes more meticulously.
-pub struct DatabaseRoutes<S> {
-    /// POST /database
-    pub root_post: MethodRouter<S>,
-    /// PUT: /database/:name_or_identity
-    pub db_put: MethodRouter<S>,
-    /// GET: /database/:name_or_identity
-    pub db_get: MethodRouter<S>,
-    /// DELETE: /database/:name_or_identity
-    pub db_delete: MethodRouter<S>,
-    /// GET: /database/:name_or_identity/names
-    pub names_get: MethodRouter<S>,
-    /// POST: /database/:name_or_identity/names
-    pub names_post: MethodRouter<S>,
-    /// PUT: /database/:name_or_identity/names
-    pub names_put: MethodRouter<S>,
-    /// GET: /database/:name_or_identity/identity
-    pub identity_get: MethodRouter<S>,
-    /// GET: /database/:name_or_identity/subscribe
-    pub subscribe_get: MethodRouter<S>,
-    /// POST: /database/:name_or_identity/call/:reducer
-    pub call_reducer_post: MethodRouter<S>,
-    /// GET: /database/:name_or_identity/schema
-    pub schema_get: MethodRouter<S>,
-    /// GET: /database/:name_or_identity/logs
-    pub logs_get: MethodRouter<S>,
-    /// POST: /database/:name_or_identity/sql
-    pub sql_post: MethodRouter<S>,
+#[derive(Deserialize)]
+pub struct PresenceParams {
+    name_or_identity: NameOrIdentity,
+}
 
-    /// GET: /database/: name_or_identity/unstable/timestamp
-    pub timestamp_get: MethodRouter<S>,
+/// Start publishing the presence of this database's clients.
+///
+/// Presence is only visible to the database's owner, via `/presence`.
+pub async fn publish_presence<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(PresenceParams { name_or_identity }): Path<PresenceParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    ctx.client_actor_index()
+        .presence()
+        .publish(database.database_identity, database.owner_identity);
+    Ok(())
 }
 
-impl<S> Default for DatabaseRoutes<S>
-where
-    S: NodeDelegate + ControlStateDelegate + Clone + 'static,
-{
-    fn default() -> Self {
-        use axum::routing::{delete, get, post, put};
-        Self {
-            root_post: post(publish::<S>),
-            db_put: put(publish::<S>),
-            db_get: get(db_info::<S>),
-            db_delete: delete(delete_database::<S>),
-            names_get: get(get_names::<S>),
-            names_post: post(add_name::<S>),
-            names_put: put(set_names::<S>),
-            identity_get: get(get_identity::<S>),
-            subscribe_get: get(handle_websocket::<S>),
-            call_reducer_post: post(call::<S>),
-            schema_get: get(schema::<S>),
-            logs_get: get(logs::<S>),
-            sql_post: post(sql::<S>),
-            timestamp_get: get(get_timestamp::<S>),
-        }
-    }
+/// Stop publishing the presence of this database's clients.
+pub async fn unpublish_presence<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(PresenceParams { name_or_identity }): Path<PresenceParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    ctx.client_actor_index()
+        .presence()
+        .unpublish(&database.database_identity);
+    Ok(())
 }
 
-impl<S> DatabaseRoutes<S>
-where
-    S: NodeDelegate + ControlStateDelegate + Clone + 'static,
-{
-    pub fn into_router(self, ctx: S) -> axum::Router<S> {
-        let db_router = axum::Router::<S>::new()
-            .route("/", self.db_put)
-            .route("/", self.db_get)
-            .route("/", self.db_delete)
-            .route("/names", self.names_get)
-            .route("/names", self.names_post)
-            .route("/names", self.names_put)
+#[derive(Deserialize)]
+pub struct NetworkAclParams {
+    name_or_identity: NameOrIdentity,
+}
+
+/// The network ACL of a database, as exchanged over `/database/:name_or_identity/acl`.
+///
+/// See [`NetworkAcl`].
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct NetworkAclBody {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub deny_as_not_found: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SetNetworkAclQueryParams {
+    /// Also close existing connections from addresses denied by the new ACL.
+    #[serde(default)]
+    kick_existing: bool,
+}
+
+#[derive(Serialize)]
+pub struct SetNetworkAclResponse {
+    /// The number of existing connections which were closed.
+    closed_connections: usize,
+}
+
+/// Returns the network ACL of this database, which is empty if none is set.
+pub async fn get_network_acl<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(NetworkAclParams { name_or_identity }): Path<NetworkAclParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let NetworkAcl {
+        allow,
+        deny,
+        deny_as_not_found,
+    } = ctx
+        .get_network_acl(&database.database_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    Ok(axum::Json(NetworkAclBody {
+        allow,
+        deny,
+        deny_as_not_found,
+    }))
+}
+
+/// Replace the network ACL of this database.
+///
+/// The new ACL applies to new requests immediately.
+/// Existing connections are only affected if `kick_existing` is set.
+pub async fn set_network_acl<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(NetworkAclParams { name_or_identity }): Path<NetworkAclParams>,
+    Query(SetNetworkAclQueryParams { kick_existing }): Query<SetNetworkAclQueryParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(body): axum::Json<NetworkAclBody>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let normalize = |ranges: Vec<String>| {
+        ranges
+            .iter()
+            .map(|range| acl::parse_range(range).map(|net| net.to_string()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))
+    };
+    let acl = NetworkAcl {
+        allow: normalize(body.allow)?,
+        deny: normalize(body.deny)?,
+        deny_as_not_found: body.deny_as_not_found,
+    };
+    ctx.set_network_acl(&database.database_identity, acl.clone())
+        .await
+        .map_err(log_and_500)?;
+
+    let closed_connections = if kick_existing {
+        ctx.client_actor_index()
+            .close_denied(&database.database_identity, |addr| !acl::permits(&acl, addr))
+    } else {
+        0
+    };
+    Ok(axum::Json(SetNetworkAclResponse { closed_connections }))
+}
+
+#[derive(Deserialize)]
+pub struct ClientConfigDefaultsParams {
+    name_or_identity: NameOrIdentity,
+}
+
+/// The client config defaults of a database, as exchanged over `/database/:name_or_identity/client-defaults`.
+///
+/// See [`ClientConfigDefaults`].
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ClientConfigDefaultsBody {
+    pub compression: Option<ClientDefaultBody<Compression>>,
+    pub light: Option<ClientDefaultBody<bool>>,
+}
+
+/// See [`ClientDefault`].
+#[derive(Serialize, Deserialize)]
+pub struct ClientDefaultBody<T> {
+    pub value: T,
+    #[serde(default)]
+    pub forced: bool,
+}
+
+impl<T> From<ClientDefault<T>> for ClientDefaultBody<T> {
+    fn from(ClientDefault { value, forced }: ClientDefault<T>) -> Self {
+        Self { value, forced }
+    }
+}
+
+impl<T> From<ClientDefaultBody<T>> for ClientDefault<T> {
+    fn from(ClientDefaultBody { value, forced }: ClientDefaultBody<T>) -> Self {
+        Self { value, forced }
+    }
+}
+
+/// Returns the client config defaults of this database, which are empty if none are set.
+pub async fn get_client_config_defaults<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let ClientConfigDefaults { compression, light } = ctx
+        .get_client_config_defaults(&database.database_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    Ok(axum::Json(ClientConfigDefaultsBody {
+        compression: compression.map(Into::into),
+        light: light.map(Into::into),
+    }))
+}
+
+/// Replace the client config defaults of this database.
+///
+/// The new defaults apply to clients connecting from now on.
+pub async fn set_client_config_defaults<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(body): axum::Json<ClientConfigDefaultsBody>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let defaults = ClientConfigDefaults {
+        compression: body.compression.map(Into::into),
+        light: body.light.map(Into::into),
+    };
+    ctx.set_client_config_defaults(&database.database_identity, defaults)
+        .await
+        .map_err(log_and_500)?;
+    Ok(())
+}
+
+/// The connection timeouts of a database, as exchanged over `/database/:name_or_identity/connection-timeouts`.
+///
+/// See [`ConnectionTimeouts`].
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ConnectionTimeoutsBody {
+    pub liveness_timeout_secs: Option<u64>,
+    pub send_timeout_secs: Option<u64>,
+}
+
+/// Returns the connection timeouts this database overrides, which are empty if none.
+pub async fn get_connection_timeouts<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let ConnectionTimeouts {
+        liveness_timeout_secs,
+        send_timeout_secs,
+    } = ctx
+        .get_connection_timeouts(&database.database_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    Ok(axum::Json(ConnectionTimeoutsBody {
+        liveness_timeout_secs,
+        send_timeout_secs,
+    }))
+}
+
+/// Replace the connection timeouts of this database.
+///
+/// The new timeouts apply to clients connecting from now on,
+/// clamped to the bounds set by the node they connect to.
+pub async fn set_connection_timeouts<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(body): axum::Json<ConnectionTimeoutsBody>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let timeouts = ConnectionTimeouts {
+        liveness_timeout_secs: body.liveness_timeout_secs,
+        send_timeout_secs: body.send_timeout_secs,
+    };
+    ctx.set_connection_timeouts(&database.database_identity, timeouts)
+        .await
+        .map_err(log_and_500)?;
+    Ok(())
+}
+
+/// The message size limits of a database, as exchanged over `/database/:name_or_identity/message-size-limits`.
+///
+/// See [`MessageSizeLimits`].
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct MessageSizeLimitsBody {
+    pub max_message_size: Option<u64>,
+    pub max_frame_size: Option<u64>,
+}
+
+/// Returns the message size limits this database sets, which are empty if none.
+pub async fn get_message_size_limits<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let MessageSizeLimits {
+        max_message_size,
+        max_frame_size,
+    } = ctx
+        .get_message_size_limits(&database.database_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    Ok(axum::Json(MessageSizeLimitsBody {
+        max_message_size,
+        max_frame_size,
+    }))
+}
+
+/// Replace the message size limits of this database.
+///
+/// The new limits apply to clients connecting from now on,
+/// and can only lower the limits set by the node they connect to.
+pub async fn set_message_size_limits<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(body): axum::Json<MessageSizeLimitsBody>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    if body.max_message_size == Some(0) || body.max_frame_size == Some(0) {
+        return Err((StatusCode::BAD_REQUEST, "Message size limits must not be zero").into());
+    }
+    let limits = MessageSizeLimits {
+        max_message_size: body.max_message_size,
+        max_frame_size: body.max_frame_size,
+    };
+    ctx.set_message_size_limits(&database.database_identity, limits)
+        .await
+        .map_err(log_and_500)?;
+    Ok(())
+}
+
+/// The send backlog policy of a database, as exchanged over `/database/:name_or_identity/send-backlog`.
+///
+/// See [`SendBacklogPolicy`].
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct SendBacklogPolicyBody {
+    pub action: SendBacklogAction,
+    pub max_messages: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// Returns the send backlog policy of this database, which has no thresholds if none is set.
+pub async fn get_send_backlog_policy<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let SendBacklogPolicy {
+        action,
+        max_messages,
+        max_bytes,
+    } = ctx
+        .get_send_backlog_policy(&database.database_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    Ok(axum::Json(SendBacklogPolicyBody {
+        action,
+        max_messages,
+        max_bytes,
+    }))
+}
+
+/// Replace the send backlog policy of this database.
+///
+/// The new policy applies to clients connecting from now on.
+pub async fn set_send_backlog_policy<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(body): axum::Json<SendBacklogPolicyBody>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    if body.max_messages == Some(0) || body.max_bytes == Some(0) {
+        return Err((StatusCode::BAD_REQUEST, "Send backlog thresholds must not be zero").into());
+    }
+    let policy = SendBacklogPolicy {
+        action: body.action,
+        max_messages: body.max_messages,
+        max_bytes: body.max_bytes,
+    };
+    ctx.set_send_backlog_policy(&database.database_identity, policy)
+        .await
+        .map_err(log_and_500)?;
+    Ok(())
+}
+
+/// The auth expiry policy of a database, as exchanged over `/database/:name_or_identity/auth-expiry`.
+///
+/// See [`AuthExpiryPolicy`].
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct AuthExpiryPolicyBody {
+    pub action: AuthExpiryAction,
+    pub grace_secs: u64,
+}
+
+/// Returns the auth expiry policy of this database,
+/// which closes clients as their token expires if none is set.
+pub async fn get_auth_expiry_policy<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let AuthExpiryPolicy { action, grace_secs } = ctx
+        .get_auth_expiry_policy(&database.database_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    Ok(axum::Json(AuthExpiryPolicyBody { action, grace_secs }))
+}
+
+/// Replace the auth expiry policy of this database.
+///
+/// The new policy applies to clients connecting from now on.
+pub async fn set_auth_expiry_policy<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(body): axum::Json<AuthExpiryPolicyBody>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let policy = AuthExpiryPolicy {
+        action: body.action,
+        grace_secs: body.grace_secs,
+    };
+    ctx.set_auth_expiry_policy(&database.database_identity, policy)
+        .await
+        .map_err(log_and_500)?;
+    Ok(())
+}
+
+/// The features turned on for a database, by name, as exchanged over `/database/:name_or_identity/features`.
+///
+/// See [`DatabaseFeature`] for the features.
+#[derive(Serialize, Deserialize)]
+pub struct DatabaseFeaturesBody {
+    pub features: Vec<String>,
+}
+
+/// Returns the features turned on for this database.
+pub async fn get_database_features<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let features = ctx
+        .get_database_features(&database.database_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    Ok(axum::Json(DatabaseFeaturesBody {
+        features: features.iter().map(|feature| feature.name().to_owned()).collect(),
+    }))
+}
+
+/// Replace the features turned on for this database, refusing any feature unknown to this node.
+///
+/// The module sees the new features from its next reducer call.
+/// Clients negotiate them as they connect, so connected clients keep theirs until they reconnect.
+pub async fn set_database_features<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(body): axum::Json<DatabaseFeaturesBody>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let features = body
+        .features
+        .iter()
+        .map(|name| {
+            DatabaseFeature::from_name(name).ok_or_else(|| {
+                let known = DatabaseFeature::ALL.map(DatabaseFeature::name).join(", ");
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Unknown feature `{name}`, expected one of: {known}"),
+                )
+            })
+        })
+        .collect::<Result<DatabaseFeatures, _>>()?;
+    ctx.set_database_features(&database.database_identity, features)
+        .await
+        .map_err(log_and_500)?;
+    Ok(())
+}
+
+/// The firehose of a database, as exchanged over `/database/:name_or_identity/firehose`.
+///
+/// See [`FirehoseTarget`] for the targets.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct FirehoseBody {
+    pub target: Option<String>,
+}
+
+/// Returns the target of this database's firehose, which is `null` if it has none.
+///
+/// The health of the firehose is part of the database's [`stats`].
+pub async fn get_firehose<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let firehose = ctx.get_firehose(&database.database_identity).map_err(log_and_500)?;
+    Ok(axum::Json(FirehoseBody {
+        target: firehose.map(|firehose| firehose.target),
+    }))
+}
+
+/// Replace the firehose of this database, or remove it if `target` is `null`.
+///
+/// Takes effect from the next committed transaction, without restarting the database's module.
+pub async fn set_firehose<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientConfigDefaultsParams { name_or_identity }): Path<ClientConfigDefaultsParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+    axum::Json(body): axum::Json<FirehoseBody>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    if let Some(target) = &body.target {
+        FirehoseTarget::from_str(target).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    }
+    let firehose = body.target.map(|target| FirehoseConfig { target });
+    ctx.set_firehose(&database.database_identity, firehose)
+        .await
+        .map_err(|e| match e.downcast_ref::<FirehoseError>() {
+            Some(err @ FirehoseError::Open { .. }) => (StatusCode::BAD_GATEWAY, err.to_string()).into(),
+            Some(err) => (StatusCode::BAD_REQUEST, err.to_string()).into(),
+            None => log_and_500(e),
+        })?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct UniqueIdentitiesParams {
+    name_or_identity: NameOrIdentity,
+}
+
+/// The buckets by which unique identities are counted.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UniquesGranularity {
+    #[default]
+    Day,
+    /// Weeks starting on Monday.
+    Week,
+}
+
+impl UniquesGranularity {
+    /// The first day of the bucket containing `day`.
+    fn bucket_of(self, day: Day) -> Day {
+        match self {
+            Self::Day => day,
+            // The Unix epoch was a Thursday.
+            Self::Week => day - (day + 3) % 7,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UniqueIdentitiesQueryParams {
+    #[serde(default)]
+    granularity: UniquesGranularity,
+    /// The first day to count, which defaults to `to`.
+    from: Option<NaiveDate>,
+    /// The last day to count, which defaults to today, in UTC.
+    to: Option<NaiveDate>,
+}
+
+#[derive(Serialize)]
+pub struct UniqueIdentitiesResponse {
+    granularity: UniquesGranularity,
+    /// The relative standard error of each count.
+    ///
+    /// Counts are within this fraction of the true count about 68% of the time,
+    /// and within twice it about 95% of the time.
+    relative_standard_error: f64,
+    buckets: Vec<UniqueIdentitiesBucket>,
+}
+
+#[derive(Serialize)]
+pub struct UniqueIdentitiesBucket {
+    /// The first day of the bucket,
+    /// which may be before the first day counted for the first bucket of a week.
+    start: NaiveDate,
+    /// The approximate number of unique identities which connected on the days of the bucket being counted.
+    uniques: u64,
+}
+
+const UNIX_EPOCH_DATE: NaiveDate = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+
+fn day_of_date(date: NaiveDate) -> axum::response::Result<Day> {
+    let day = (date - UNIX_EPOCH_DATE).num_days();
+    Day::try_from(day).map_err(|_| (StatusCode::BAD_REQUEST, "dates must be after the Unix epoch").into())
+}
+
+fn date_of_day(day: Day) -> NaiveDate {
+    UNIX_EPOCH_DATE + chrono::Days::new(day.into())
+}
+
+/// Count the unique identities which connected to this database on the days `from` through `to`,
+/// by day or by week.
+///
+/// Counts are estimated from HyperLogLog sketches, see [`uniques`],
+/// and so are approximate, with a relative standard error of [`uniques::RELATIVE_STANDARD_ERROR`].
+/// Connections to other nodes are counted once persisted, about every [`uniques::FLUSH_INTERVAL`].
+/// At most [`uniques::RETENTION_DAYS`] days may be counted at once, nor are any older days kept.
+pub async fn get_unique_identities<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(UniqueIdentitiesParams { name_or_identity }): Path<UniqueIdentitiesParams>,
+    Query(UniqueIdentitiesQueryParams { granularity, from, to }): Query<UniqueIdentitiesQueryParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let to = to.map_or_else(|| Ok(uniques::day_of(SystemTime::now())), day_of_date)?;
+    let from = from.map_or(Ok(to), day_of_date)?;
+    if from > to {
+        Err((StatusCode::BAD_REQUEST, "`from` must not be after `to`"))?;
+    }
+    if to - from >= uniques::RETENTION_DAYS {
+        Err((
+            StatusCode::BAD_REQUEST,
+            format!("At most {} days may be counted at once.", uniques::RETENTION_DAYS),
+        ))?;
+    }
+
+    let days = from..=to;
+    let persisted = ctx
+        .get_unique_identities(&database.database_identity, days.clone())
+        .map_err(log_and_500)?;
+    let pending = ctx
+        .client_actor_index()
+        .unique_identities()
+        .pending(&database.database_identity, days.clone());
+    Ok(axum::Json(UniqueIdentitiesResponse {
+        granularity,
+        relative_standard_error: uniques::RELATIVE_STANDARD_ERROR,
+        buckets: count_uniques(granularity, days, persisted.into_iter().chain(pending)),
+    }))
+}
+
+/// Count the unique identities in the `sketches` of `days`, in buckets of `granularity`,
+/// with a bucket for every bucket overlapping `days`, even if no identities connected during it.
+fn count_uniques(
+    granularity: UniquesGranularity,
+    days: RangeInclusive<Day>,
+    sketches: impl IntoIterator<Item = (Day, IdentitySketch)>,
+) -> Vec<UniqueIdentitiesBucket> {
+    let mut buckets = BTreeMap::<Day, IdentitySketch>::new();
+    for (day, sketch) in sketches {
+        buckets.entry(granularity.bucket_of(day)).or_default().merge(&sketch);
+    }
+    let starts: BTreeSet<Day> = days.map(|day| granularity.bucket_of(day)).collect();
+    starts
+        .into_iter()
+        .map(|start| UniqueIdentitiesBucket {
+            start: date_of_day(start),
+            uniques: buckets.get(&start).map_or(0, IdentitySketch::estimate),
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct ClientsParams {
+    name_or_identity: NameOrIdentity,
+}
+
+#[derive(Deserialize)]
+pub struct ClientsQueryParams {
+    /// List the clients after the one with this connection id,
+    /// i.e. the `next_after` of the previous page.
+    after: Option<ConnectionIdForUrl>,
+    /// How many clients to list at most, [`DEFAULT_CLIENTS_PAGE_LEN`] by default.
+    limit: Option<usize>,
+}
+
+/// How many clients `/database/:name_or_identity/clients` lists at once, unless asked for fewer or more.
+const DEFAULT_CLIENTS_PAGE_LEN: usize = 1000;
+/// The most clients `/database/:name_or_identity/clients` lists at once.
+const MAX_CLIENTS_PAGE_LEN: usize = 10_000;
+
+/// A page of the clients connected to a database, ordered by connection id.
+#[derive(Serialize)]
+pub struct ClientsPage {
+    pub clients: Vec<ConnectedClient>,
+    /// The connection id to pass as `after` for the next page, if the page is full.
+    pub next_after: Option<String>,
+}
+
+/// A client connected to a database, as listed by `/database/:name_or_identity/clients`.
+///
+/// The config is the one in effect for the connection,
+/// i.e. what the client asked for merged with the database's client config defaults.
+#[derive(Serialize)]
+pub struct ConnectedClient {
+    pub identity: String,
+    pub connection_id: String,
+    /// The negotiated websocket subprotocol, e.g. `v2.bsatn.spacetimedb`.
+    pub protocol: &'static str,
+    pub compression: Compression,
+    pub light: bool,
+    pub include_caller_info: bool,
+    pub include_timings: bool,
+    pub reducer_timings: bool,
+    pub acknowledged_delivery: bool,
+    /// How long the client has been connected, in seconds.
+    pub uptime_secs: u64,
+    /// The round trip time of the most recent ping the client answered, in microseconds,
+    /// if it has answered one.
+    pub round_trip_time_micros: Option<u64>,
+    /// When the client connected, in microseconds since the Unix epoch.
+    pub connected_at_micros: i64,
+    /// The address the client connected from, as determined by the node's `[network]` options, if known.
+    pub addr: Option<IpAddr>,
+    pub subscriptions: usize,
+    /// How many messages are queued to be sent to the client.
+    pub outgoing_queue_len: usize,
+    /// How many bytes of messages are queued to be sent to the client.
+    pub outgoing_queue_bytes: usize,
+}
+
+/// Returns a page of the clients connected to this database on this node.
+pub async fn get_clients<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(ClientsParams { name_or_identity }): Path<ClientsParams>,
+    Query(ClientsQueryParams { after, limit }): Query<ClientsQueryParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let limit = limit.unwrap_or(DEFAULT_CLIENTS_PAGE_LEN);
+    if !(1..=MAX_CLIENTS_PAGE_LEN).contains(&limit) {
+        Err((
+            StatusCode::BAD_REQUEST,
+            format!("`limit` must be between 1 and {MAX_CLIENTS_PAGE_LEN}"),
+        ))?;
+    }
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let page = ctx
+        .client_actor_index()
+        .clients_page(&database.database_identity, after.map(Into::into), limit);
+    let now = Timestamp::now();
+    let next_after = page
+        .last()
+        .filter(|_| page.len() == limit)
+        .map(|last| last.sender.id.connection_id.to_hex().to_string());
+    let clients = page
+        .into_iter()
+        .map(|ListedClient { sender: client, addr }| {
+            let (outgoing_queue_len, outgoing_queue_bytes) = client.queued();
+            ConnectedClient {
+                identity: client.id.identity.to_hex().to_string(),
+                connection_id: client.id.connection_id.to_hex().to_string(),
+                protocol: protocol_name(&client.config()),
+                compression: client.config().compression,
+                light: !client.config().tx_update_full,
+                include_caller_info: client.config().include_caller_info,
+                include_timings: client.config().include_timings,
+                reducer_timings: client.config().reducer_timings,
+                acknowledged_delivery: client.config().acknowledged_delivery,
+                uptime_secs: client.uptime().as_secs(),
+                round_trip_time_micros: client.round_trip_time().map(|rtt| rtt.as_micros() as u64),
+                connected_at_micros: (now - client.uptime()).to_micros_since_unix_epoch(),
+                addr,
+                subscriptions: client.subscriptions(),
+                outgoing_queue_len,
+                outgoing_queue_bytes,
+            }
+        })
+        .collect();
+    Ok(axum::Json(ClientsPage { clients, next_after }))
+}
+
+#[derive(Deserialize)]
+pub struct KickClientParams {
+    name_or_identity: NameOrIdentity,
+    connection_id: ConnectionIdForUrl,
+}
+
+#[derive(Deserialize)]
+pub struct KickClientQueryParams {
+    /// The identity the client connected as.
+    identity: IdentityForUrl,
+    /// Why the client is kicked, sent to it in the close frame.
+    reason: Option<String>,
+}
+
+/// Disconnects the client connected to this database on this node as `identity` with `connection_id`,
+/// telling it that it was kicked by an admin, and why, if given.
+///
+/// The connection is closed the way the client itself would close it,
+/// so the module's `client_disconnected` reducer is called exactly once.
+pub async fn kick_client<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(KickClientParams {
+        name_or_identity,
+        connection_id,
+    }): Path<KickClientParams>,
+    Query(KickClientQueryParams { identity, reason }): Query<KickClientQueryParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database_identity = name_or_identity.resolve(&ctx).await?;
+    let database = worker_ctx_find_database(&ctx, &database_identity)
+        .await?
+        .ok_or(NO_SUCH_DATABASE)?;
+    check_kicker(&database, &auth.identity)?;
+
+    let kicked = ctx.client_actor_index().kick(
+        &database.database_identity,
+        identity.into(),
+        connection_id.into(),
+        reason.map(Into::into),
+    );
+    if !kicked {
+        return Err((StatusCode::NOT_FOUND, "No such client connection.").into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct PendingDeliveriesParams {
+    name_or_identity: NameOrIdentity,
+}
+
+/// The identities of a database with acknowledged updates pending for them on this node,
+/// as listed by `/database/:name_or_identity/deliveries`, those with the most bytes pending first.
+#[derive(Serialize)]
+pub struct PendingDeliveries {
+    pub identities: Vec<IdentityDeliveries>,
+}
+
+#[derive(Serialize)]
+pub struct IdentityDeliveries {
+    pub identity: String,
+    /// The number of updates the identity's clients have yet to acknowledge.
+    pub deliveries: usize,
+    /// The estimated bytes of those updates.
+    pub bytes: usize,
+    /// How long ago the oldest of them was delivered, in seconds.
+    pub oldest_secs: u64,
+}
+
+/// Lists the identities with acknowledged updates pending for them,
+/// which are delivered again when they reconnect, until they expire.
+pub async fn get_pending_deliveries<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(PendingDeliveriesParams { name_or_identity }): Path<PendingDeliveriesParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<impl IntoResponse> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let module = find_leader(&ctx, &database)
+        .await?
+        .module()
+        .await
+        .map_err(log_and_500)?;
+    let identities = module
+        .pending_deliveries()
+        .by_identity()
+        .into_iter()
+        .map(|pending| IdentityDeliveries {
+            identity: pending.identity.to_hex().to_string(),
+            deliveries: pending.deliveries,
+            bytes: pending.bytes,
+            oldest_secs: pending.oldest.as_secs(),
+        })
+        .collect();
+    Ok(axum::Json(PendingDeliveries { identities }))
+}
+
+#[derive(Deserialize)]
+pub struct PurgeDeliveriesParams {
+    name_or_identity: NameOrIdentity,
+    identity: IdentityForUrl,
+}
+
+/// Forgets everything held on this node to replay to the clients of a database connected as `identity`,
+/// i.e. their pending acknowledged updates and the sessions parked for them to resume,
+/// returning how much of each there was.
+///
+/// The identity's next connection starts afresh: resuming a purged session fails,
+/// as though it had expired, rather than replaying part of it.
+pub async fn purge_pending_deliveries<S: ControlStateDelegate + NodeDelegate>(
+    State(ctx): State<S>,
+    Path(PurgeDeliveriesParams {
+        name_or_identity,
+        identity,
+    }): Path<PurgeDeliveriesParams>,
+    Extension(auth): Extension<SpacetimeAuth>,
+) -> axum::response::Result<axum::Json<PurgedReplayBuffers>> {
+    let database = owned_database(&ctx, name_or_identity, &auth).await?;
+    let module = find_leader(&ctx, &database)
+        .await?
+        .module()
+        .await
+        .map_err(log_and_500)?;
+    Ok(axum::Json(purge_replay_buffers(&module, &identity.into_inner())))
+}
+
+/// Only the owner of a database may kick its clients.
+fn check_kicker(database: &Database, identity: &Identity) -> axum::response::Result<()> {
+    if database.owner_identity != *identity {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only the owner of the database may kick its clients.",
+        )
+            .into());
+    }
+    Ok(())
+}
+
+async fn owned_database<S: ControlStateDelegate>(
+    ctx: &S,
+    name_or_identity: NameOrIdentity,
+    auth: &SpacetimeAuth,
+) -> axum::response::Result<Database> {
+    let database_identity = name_or_identity.resolve(ctx).await?;
+    let database = worker_ctx_find_database(ctx, &database_identity)
+        .await?
+        .ok_or(NO_SUCH_DATABASE)?;
+
+    if database.owner_identity != auth.identity {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Identity does not own database, expected: {} got: {}",
+                database.owner_identity.to_hex(),
+                auth.identity.to_hex()
+            ),
+        )
+            .into());
+    }
+    Ok(database)
+}
+
+/// This struct allows the edition to customize `/database` routes more meticulously.
+pub struct DatabaseRoutes<S> {
+    /// POST /database
+    pub root_post: MethodRouter<S>,
+    /// PUT: /database/:name_or_identity
+    pub db_put: MethodRouter<S>,
+    /// GET: /database/:name_or_identity
+    pub db_get: MethodRouter<S>,
+    /// DELETE: /database/:name_or_identity
+    pub db_delete: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/names
+    pub names_get: MethodRouter<S>,
+    /// POST: /database/:name_or_identity/names
+    pub names_post: MethodRouter<S>,
+    /// PUT: /database/:name_or_identity/names
+    pub names_put: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/identity
+    pub identity_get: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/subscribe
+    pub subscribe_get: MethodRouter<S>,
+    /// POST: /database/:name_or_identity/call/:reducer
+    pub call_reducer_post: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/schema
+    pub schema_get: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/schema/diff
+    pub schema_diff_get: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/logs
+    pub logs_get: MethodRouter<S>,
+    /// POST: /database/:name_or_identity/sql
+    pub sql_post: MethodRouter<S>,
+    /// POST: /database/:name_or_identity/explain
+    pub explain_post: MethodRouter<S>,
+    /// PUT, DELETE: /database/:name_or_identity/presence
+    pub presence: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/stats
+    pub stats_get: MethodRouter<S>,
+    /// GET, PUT: /database/:name_or_identity/acl
+    pub acl: MethodRouter<S>,
+    /// GET, PUT: /database/:name_or_identity/client-defaults
+    pub client_defaults: MethodRouter<S>,
+    /// GET, PUT: /database/:name_or_identity/connection-timeouts
+    pub connection_timeouts: MethodRouter<S>,
+    /// GET, PUT: /database/:name_or_identity/message-size-limits
+    pub message_size_limits: MethodRouter<S>,
+    /// GET, PUT: /database/:name_or_identity/send-backlog
+    pub send_backlog: MethodRouter<S>,
+    /// GET, PUT: /database/:name_or_identity/auth-expiry
+    pub auth_expiry: MethodRouter<S>,
+    /// GET, PUT: /database/:name_or_identity/features
+    pub features: MethodRouter<S>,
+    /// GET, PUT: /database/:name_or_identity/firehose
+    pub firehose: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/uniques
+    pub uniques_get: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/clients
+    pub clients_get: MethodRouter<S>,
+    /// DELETE: /database/:name_or_identity/clients/:connection_id?identity=...
+    pub client_delete: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/clients/:connection_id/subscriptions
+    pub client_subscriptions_get: MethodRouter<S>,
+    /// GET: /database/:name_or_identity/deliveries
+    pub deliveries_get: MethodRouter<S>,
+    /// DELETE: /database/:name_or_identity/deliveries/:identity
+    pub deliveries_delete: MethodRouter<S>,
+    /// POST: /database/:name_or_identity/clients/subscriptions
+    pub client_subscriptions_post: MethodRouter<S>,
+
+    /// GET: /database/: name_or_identity/unstable/timestamp
+    pub timestamp_get: MethodRouter<S>,
+}
+
+impl<S> Default for DatabaseRoutes<S>
+where
+    S: NodeDelegate + ControlStateDelegate + Clone + 'static,
+{
+    fn default() -> Self {
+        use axum::routing::{delete, get, post, put};
+        Self {
+            root_post: post(publish::<S>),
+            db_put: put(publish::<S>),
+            db_get: get(db_info::<S>),
+            db_delete: delete(delete_database::<S>),
+            names_get: get(get_names::<S>),
+            names_post: post(add_name::<S>),
+            names_put: put(set_names::<S>),
+            identity_get: get(get_identity::<S>),
+            subscribe_get: get(handle_websocket::<S>),
+            call_reducer_post: post(call::<S>),
+            schema_get: get(schema::<S>),
+            schema_diff_get: get(schema_diff::<S>),
+            logs_get: get(logs::<S>),
+            sql_post: post(sql::<S>),
+            explain_post: post(explain::<S>),
+            presence: put(publish_presence::<S>).delete(unpublish_presence::<S>),
+            stats_get: get(stats::<S>),
+            acl: get(get_network_acl::<S>).put(set_network_acl::<S>),
+            client_defaults: get(get_client_config_defaults::<S>).put(set_client_config_defaults::<S>),
+            connection_timeouts: get(get_connection_timeouts::<S>).put(set_connection_timeouts::<S>),
+            message_size_limits: get(get_message_size_limits::<S>).put(set_message_size_limits::<S>),
+            send_backlog: get(get_send_backlog_policy::<S>).put(set_send_backlog_policy::<S>),
+            auth_expiry: get(get_auth_expiry_policy::<S>).put(set_auth_expiry_policy::<S>),
+            features: get(get_database_features::<S>).put(set_database_features::<S>),
+            firehose: get(get_firehose::<S>).put(set_firehose::<S>),
+            uniques_get: get(get_unique_identities::<S>),
+            clients_get: get(get_clients::<S>),
+            client_delete: delete(kick_client::<S>),
+            client_subscriptions_get: get(get_client_subscriptions::<S>),
+            client_subscriptions_post: post(restore_client_subscriptions::<S>),
+            deliveries_get: get(get_pending_deliveries::<S>),
+            deliveries_delete: delete(purge_pending_deliveries::<S>),
+            timestamp_get: get(get_timestamp::<S>),
+        }
+    }
+}
+
+impl<S> DatabaseRoutes<S>
+where
+    S: NodeDelegate + ControlStateDelegate + Clone + 'static,
+{
+    pub fn into_router(self, ctx: S) -> axum::Router<S> {
+        let db_router = axum::Router::<S>::new()
+            .route("/", self.db_put)
+            .route("/", self.db_get)
+            .route("/", self.db_delete)
+            .route("/names", self.names_get)
+            .route("/names", self.names_post)
+            .route("/names", self.names_put)
             .route("/identity", self.identity_get)
             .route("/subscribe", self.subscribe_get)
             .route("/call/:reducer", self.call_reducer_post)
             .route("/schema", self.schema_get)
+            .route("/schema/diff", self.schema_diff_get)
             .route("/logs", self.logs_get)
             .route("/sql", self.sql_post)
+            .route("/explain", self.explain_post)
+            .route("/presence", self.presence)
+            .route("/stats", self.stats_get)
+            .route("/acl", self.acl)
+            .route("/client-defaults", self.client_defaults)
+            .route("/connection-timeouts", self.connection_timeouts)
+            .route("/message-size-limits", self.message_size_limits)
+            .route("/send-backlog", self.send_backlog)
+            .route("/auth-expiry", self.auth_expiry)
+            .route("/features", self.features)
+            .route("/firehose", self.firehose)
+            .route("/uniques", self.uniques_get)
+            .route("/clients", self.clients_get)
+            .route("/clients/:connection_id", self.client_delete)
+            .route("/clients/:connection_id/subscriptions", self.client_subscriptions_get)
+            .route("/clients/subscriptions", self.client_subscriptions_post)
+            .route("/deliveries", self.deliveries_get)
+            .route("/deliveries/:identity", self.deliveries_delete)
             .route("/unstable/timestamp", self.timestamp_get);
 
         axum::Router::new()
@@ -843,3 +2252,289 @@ where
             .route_layer(axum::middleware::from_fn_with_state(ctx, anon_auth_middleware::<S>))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ControlStateReadAccess;
+    use spacetimedb::energy::EnergyBalance;
+    use spacetimedb::messages::control_db::{Node, Replica};
+    use spacetimedb_lib::Hash;
+    use std::sync::Mutex;
+
+    /// A control state holding at most one database,
+    /// which tests can delete or break out from under a request.
+    struct MockControlState {
+        database: Mutex<Option<Database>>,
+        broken: Mutex<bool>,
+    }
+
+    impl MockControlState {
+        fn new(database: Database) -> Self {
+            Self {
+                database: Mutex::new(Some(database)),
+                broken: Mutex::new(false),
+            }
+        }
+
+        fn delete(&self) {
+            *self.database.lock().unwrap() = None;
+        }
+
+        fn set_broken(&self, broken: bool) {
+            *self.broken.lock().unwrap() = broken;
+        }
+    }
+
+    impl ControlStateReadAccess for MockControlState {
+        fn get_node_id(&self) -> Option<u64> {
+            None
+        }
+        fn get_node_by_id(&self, _node_id: u64) -> anyhow::Result<Option<Node>> {
+            Ok(None)
+        }
+        fn get_nodes(&self) -> anyhow::Result<Vec<Node>> {
+            Ok(vec![])
+        }
+
+        fn get_database_by_id(&self, id: u64) -> anyhow::Result<Option<Database>> {
+            Ok(self.get_databases()?.into_iter().find(|db| db.id == id))
+        }
+        fn get_database_by_identity(&self, database_identity: &Identity) -> anyhow::Result<Option<Database>> {
+            Ok(self
+                .get_databases()?
+                .into_iter()
+                .find(|db| db.database_identity == *database_identity))
+        }
+        fn get_databases(&self) -> anyhow::Result<Vec<Database>> {
+            if *self.broken.lock().unwrap() {
+                anyhow::bail!("control state unavailable");
+            }
+            Ok(self.database.lock().unwrap().iter().cloned().collect())
+        }
+
+        fn get_replica_by_id(&self, _id: u64) -> anyhow::Result<Option<Replica>> {
+            Ok(None)
+        }
+        fn get_replicas(&self) -> anyhow::Result<Vec<Replica>> {
+            Ok(vec![])
+        }
+        fn get_leader_replica_by_database(&self, _database_id: u64) -> Option<Replica> {
+            None
+        }
+
+        fn get_energy_balance(&self, _identity: &Identity) -> anyhow::Result<Option<EnergyBalance>> {
+            Ok(None)
+        }
+
+        fn lookup_identity(&self, _domain: &str) -> anyhow::Result<Option<Identity>> {
+            Ok(None)
+        }
+        fn reverse_lookup(&self, _database_identity: &Identity) -> anyhow::Result<Vec<DomainName>> {
+            Ok(vec![])
+        }
+
+        fn get_network_acl(&self, _database_identity: &Identity) -> anyhow::Result<Option<NetworkAcl>> {
+            Ok(None)
+        }
+
+        fn get_client_config_defaults(
+            &self,
+            _database_identity: &Identity,
+        ) -> anyhow::Result<Option<ClientConfigDefaults>> {
+            Ok(None)
+        }
+
+        fn get_connection_timeouts(&self, _database_identity: &Identity) -> anyhow::Result<Option<ConnectionTimeouts>> {
+            Ok(None)
+        }
+
+        fn get_message_size_limits(&self, _database_identity: &Identity) -> anyhow::Result<Option<MessageSizeLimits>> {
+            Ok(None)
+        }
+
+        fn get_send_backlog_policy(&self, _database_identity: &Identity) -> anyhow::Result<Option<SendBacklogPolicy>> {
+            Ok(None)
+        }
+
+        fn get_auth_expiry_policy(&self, _database_identity: &Identity) -> anyhow::Result<Option<AuthExpiryPolicy>> {
+            Ok(None)
+        }
+
+        fn get_database_features(&self, _database_identity: &Identity) -> anyhow::Result<Option<DatabaseFeatures>> {
+            Ok(None)
+        }
+
+        fn get_firehose(&self, _database_identity: &Identity) -> anyhow::Result<Option<FirehoseConfig>> {
+            Ok(None)
+        }
+
+        fn get_schema_diff(
+            &self,
+            _database_identity: &Identity,
+            _from: &Hash,
+            _to: &Hash,
+        ) -> anyhow::Result<Option<SchemaDiff>> {
+            Ok(None)
+        }
+
+        fn get_unique_identities(
+            &self,
+            _database_identity: &Identity,
+            _days: RangeInclusive<Day>,
+        ) -> anyhow::Result<Vec<(Day, IdentitySketch)>> {
+            Ok(vec![])
+        }
+    }
+
+    fn database() -> Database {
+        Database {
+            id: 1,
+            database_identity: Identity::ONE,
+            owner_identity: Identity::ZERO,
+            host_type: HostType::Wasm,
+            initial_program: Hash::ZERO,
+        }
+    }
+
+    fn status(res: axum::response::Result<()>) -> StatusCode {
+        Err::<(), _>(res.unwrap_err()).into_response().status()
+    }
+
+    #[test]
+    fn only_the_owner_may_kick_clients() {
+        let database = database();
+        assert!(check_kicker(&database, &database.owner_identity).is_ok());
+        assert_eq!(status(check_kicker(&database, &Identity::ONE)), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn database_deleted_before_leader_lookup_is_gone() {
+        let database = database();
+        let ctl = MockControlState::new(database.clone());
+
+        // The leader lookup fails because the database was deleted concurrently.
+        let res = resolve_leader(&ctl, &database, false, || {
+            ctl.delete();
+            async { anyhow::Result::<Option<()>>::Err(anyhow::anyhow!("no such database")) }
+        })
+        .await;
+        assert_eq!(status(res), StatusCode::GONE);
+
+        // Same if the lookup merely comes back empty.
+        let ctl = MockControlState::new(database.clone());
+        let res = resolve_leader(&ctl, &database, false, || {
+            ctl.delete();
+            async { Ok(None::<()>) }
+        })
+        .await;
+        assert_eq!(status(res), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn database_without_a_leader_is_unavailable() {
+        let database = database();
+        let ctl = MockControlState::new(database.clone());
+
+        // The database is registered, but no replica of it leads.
+        let res = resolve_leader(&ctl, &database, false, || async { Ok(None::<()>) }).await;
+        let response = Err::<(), _>(res.unwrap_err()).into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[header::RETRY_AFTER],
+            LEADER_UNAVAILABLE_RETRY_AFTER.as_secs().to_string()
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "leader_unavailable");
+        assert_eq!(body["database_identity"], database.database_identity.to_hex().as_str());
+    }
+
+    #[tokio::test]
+    async fn control_state_error_is_internal_error() {
+        let database = database();
+        let ctl = MockControlState::new(database.clone());
+
+        let res = resolve_leader(&ctl, &database, false, || {
+            ctl.set_broken(true);
+            async { anyhow::Result::<Option<()>>::Err(anyhow::anyhow!("control state unavailable")) }
+        })
+        .await;
+        assert_eq!(status(res), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn transient_leader_error_is_retried_once() {
+        let database = database();
+        let ctl = MockControlState::new(database.clone());
+
+        let mut attempts = 0;
+        let res = resolve_leader(&ctl, &database, true, || {
+            attempts += 1;
+            let res = if attempts == 1 {
+                Err(anyhow::anyhow!("transient failure"))
+            } else {
+                Ok(Some(()))
+            };
+            std::future::ready(res)
+        })
+        .await;
+        assert!(res.is_ok());
+        assert_eq!(attempts, 2);
+
+        // Without retries, the first failure is reported.
+        let res = resolve_leader(&ctl, &database, false, || async {
+            anyhow::Result::<Option<()>>::Err(anyhow::anyhow!("transient failure"))
+        })
+        .await;
+        assert_eq!(status(res), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn uniques_are_counted_by_day_or_by_week() {
+        let date = |s: &str| s.parse::<NaiveDate>().unwrap();
+        let day = |s| day_of_date(date(s)).unwrap();
+        let sketch = |identities: std::ops::Range<u64>| {
+            let mut sketch = IdentitySketch::default();
+            identities.for_each(|i| sketch.insert(&Identity::from_u256(i.into())));
+            sketch
+        };
+        // Wednesday, and the following Sunday and Monday.
+        let sketches = || {
+            [
+                (day("2026-10-07"), sketch(0..10)),
+                (day("2026-10-11"), sketch(5..20)),
+                (day("2026-10-12"), sketch(0..3)),
+            ]
+        };
+        let days = day("2026-10-06")..=day("2026-10-12");
+        // Counts this small are estimated all but exactly.
+        let assert_counts = |buckets: Vec<UniqueIdentitiesBucket>, expected: &[(&str, u64)]| {
+            assert_eq!(buckets.len(), expected.len());
+            for (bucket, &(start, uniques)) in buckets.iter().zip(expected) {
+                assert_eq!(bucket.start, date(start));
+                assert!(bucket.uniques.abs_diff(uniques) <= 1, "{start}: {}", bucket.uniques);
+            }
+        };
+
+        let by_day = count_uniques(UniquesGranularity::Day, days.clone(), sketches());
+        assert_counts(
+            by_day,
+            &[
+                ("2026-10-06", 0),
+                ("2026-10-07", 10),
+                ("2026-10-08", 0),
+                ("2026-10-09", 0),
+                ("2026-10-10", 0),
+                ("2026-10-11", 15),
+                ("2026-10-12", 3),
+            ],
+        );
+        // Identities connecting on several days of a week count once.
+        let by_week = count_uniques(UniquesGranularity::Week, days, sketches());
+        assert_counts(by_week, &[("2026-10-05", 20), ("2026-10-12", 3)]);
+
+        assert_eq!(date_of_day(day("2026-10-15")), date("2026-10-15"));
+        assert!(day_of_date(date("1969-12-31")).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+//! A self-test of the node, which takes a client through every stage of its life
+//! against a throwaway database running the built-in diagnostic module, `modules/diagnostics`.
+//!
+//! The compiled diagnostic module is embedded from the path in `SPACETIMEDB_DIAGNOSTICS_MODULE`
+//! when this crate is built with the `diagnostics` feature.
+
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use http::{header, HeaderValue, StatusCode};
+use serde::Serialize;
+use spacetimedb::messages::control_db::HostType;
+use spacetimedb_client_api_messages::websocket::{
+    self as ws, BsatnFormat, CallReducer, CallReducerFlags, ClientMessage, DatabaseUpdate, ServerMessage, Subscribe,
+    UpdateStatus, SERVER_MSG_COMPRESSION_TAG_NONE,
+};
+use spacetimedb_lib::{bsatn, Identity};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::auth::{anon_auth_middleware, SpacetimeAuth};
+use crate::routes::subscribe::handle_websocket;
+use crate::{ControlStateDelegate, DatabaseDef, NodeDelegate};
+
+static DIAGNOSTIC_MODULE: &[u8] = include_bytes!(env!("SPACETIMEDB_DIAGNOSTICS_MODULE"));
+
+/// The longest a single stage of the self-test may take before it fails.
+const STAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A stage of the self-test, in the order they run.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Publish the diagnostic module to a fresh database.
+    Publish,
+    /// Open a websocket to the database, as a client would,
+    /// and receive its `IdentityToken`.
+    Connect,
+    /// Subscribe to the `probes` table and receive the initial subscription.
+    Subscribe,
+    /// Call the `probe` reducer and receive the reply.
+    Call,
+    /// Check that the reply delivered the row the reducer inserted.
+    Update,
+    /// Close the websocket.
+    Close,
+    /// Delete the database.
+    Teardown,
+}
+
+#[derive(Serialize)]
+pub struct StageReport {
+    stage: Stage,
+    passed: bool,
+    duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The outcome of a self-test.
+///
+/// Stages after the first to fail are skipped, and not reported,
+/// except for [`Stage::Teardown`] which runs whenever the database was created.
+#[derive(Serialize)]
+pub struct SelfTestReport {
+    passed: bool,
+    database_identity: String,
+    duration_ms: f64,
+    stages: Vec<StageReport>,
+}
+
+#[derive(Default)]
+struct SelfTest {
+    stages: Vec<StageReport>,
+}
+
+impl SelfTest {
+    /// Run `stage`, bounded by [`STAGE_TIMEOUT`], and record its outcome.
+    async fn run<T>(&mut self, stage: Stage, fut: impl Future<Output = anyhow::Result<T>>) -> Option<T> {
+        let start = Instant::now();
+        let res = tokio::time::timeout(STAGE_TIMEOUT, fut)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {STAGE_TIMEOUT:?}")));
+        let (value, error) = match res {
+            Ok(value) => (Some(value), None),
+            Err(e) => {
+                log::warn!("self-test failed at stage {stage:?}: {e:#}");
+                (None, Some(format!("{e:#}")))
+            }
+        };
+        self.stages.push(StageReport {
+            stage,
+            passed: value.is_some(),
+            duration_ms: millis(start.elapsed()),
+            error,
+        });
+        value
+    }
+
+    fn passed(&self) -> bool {
+        self.stages.iter().all(|stage| stage.passed)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Run the self-test and return a [`SelfTestReport`],
+/// with status `200 OK` if every stage passed and `500 Internal Server Error` otherwise.
+pub async fn selftest<S>(State(ctx): State<S>) -> axum::response::Result<impl IntoResponse>
+where
+    S: NodeDelegate + ControlStateDelegate + Clone + 'static,
+{
+    let start = Instant::now();
+    let owner = SpacetimeAuth::alloc(&ctx).await?;
+    let database_identity = SpacetimeAuth::alloc(&ctx).await?.identity;
+
+    let mut test = SelfTest::default();
+    test.run(Stage::Publish, publish(&ctx, &owner, database_identity)).await;
+    if test.passed() {
+        exercise_client(&mut test, &ctx, &owner, database_identity).await;
+    }
+    // Publishing may have failed after creating the database, e.g. in the module's `init`.
+    if let Ok(Some(_)) = ctx.get_database_by_identity(&database_identity) {
+        test.run(
+            Stage::Teardown,
+            ctx.delete_database(&owner.identity, &database_identity),
+        )
+        .await;
+    }
+
+    let passed = test.passed();
+    let report = SelfTestReport {
+        passed,
+        database_identity: database_identity.to_hex().to_string(),
+        duration_ms: millis(start.elapsed()),
+        stages: test.stages,
+    };
+    let status = match passed {
+        true => StatusCode::OK,
+        false => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    Ok((status, axum::Json(report)))
+}
+
+async fn publish(
+    ctx: &impl ControlStateDelegate,
+    owner: &SpacetimeAuth,
+    database_identity: Identity,
+) -> anyhow::Result<()> {
+    ctx.publish_database(
+        &owner.identity,
+        DatabaseDef {
+            database_identity,
+            program_bytes: DIAGNOSTIC_MODULE.to_vec(),
+            num_replicas: None,
+            host_type: HostType::Wasm,
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+/// Run the client's stages, stopping at the first to fail.
+async fn exercise_client<S>(
+    test: &mut SelfTest,
+    ctx: &S,
+    owner: &SpacetimeAuth,
+    database_identity: Identity,
+) -> Option<()>
+where
+    S: NodeDelegate + ControlStateDelegate + Clone + 'static,
+{
+    let mut client = test
+        .run(Stage::Connect, Client::connect(ctx.clone(), owner, database_identity))
+        .await?;
+    test.run(Stage::Subscribe, client.subscribe()).await?;
+    let nonce = rand::random::<u64>();
+    let update = test.run(Stage::Call, client.call_probe(nonce)).await?;
+    test.run(Stage::Update, async { expect_probe(update, nonce) }).await?;
+    test.run(Stage::Close, client.close()).await
+}
+
+/// A websocket client of the database being tested,
+/// connected through an in-process listener serving the real subscribe route.
+struct Client {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    server: JoinHandle<std::io::Result<()>>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl Client {
+    async fn connect<S>(ctx: S, owner: &SpacetimeAuth, database_identity: Identity) -> anyhow::Result<Self>
+    where
+        S: NodeDelegate + ControlStateDelegate + Clone + 'static,
+    {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let router = axum::Router::new()
+            .route(
+                "/database/:name_or_identity/subscribe",
+                axum::routing::get(handle_websocket::<S>),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                ctx.clone(),
+                anon_auth_middleware::<S>,
+            ))
+            .with_state(ctx);
+        let server = tokio::spawn(async move {
+            axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+
+        let mut request = format!(
+            "ws://{addr}/database/{}/subscribe?compression=None",
+            database_identity.to_hex()
+        )
+        .into_client_request()?;
+        let headers = request.headers_mut();
+        headers.insert(header::AUTHORIZATION, owner.creds.to_header_value());
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(ws::BIN_PROTOCOL),
+        );
+        let (socket, _) = match tokio_tungstenite::connect_async(request).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                server.abort();
+                return Err(e.into());
+            }
+        };
+
+        let mut client = Self { socket, server };
+        match client.recv().await? {
+            ServerMessage::IdentityToken(token) => ensure!(
+                token.identity == owner.identity,
+                "connected as {}, not {}",
+                token.identity,
+                owner.identity
+            ),
+            msg => bail!("expected an IdentityToken, got {}", message_kind(&msg)),
+        }
+        Ok(client)
+    }
+
+    async fn subscribe(&mut self) -> anyhow::Result<()> {
+        self.send(ClientMessage::Subscribe(Subscribe {
+            query_strings: ["SELECT * FROM probes".into()].into(),
+            request_id: 1,
+        }))
+        .await?;
+        match self.recv().await? {
+            ServerMessage::InitialSubscription(_) => Ok(()),
+            ServerMessage::SubscriptionError(e) => bail!("subscription failed: {}", e.error),
+            msg => bail!("expected an InitialSubscription, got {}", message_kind(&msg)),
+        }
+    }
+
+    /// Call the `probe` reducer and return the rows its reply delivered.
+    async fn call_probe(&mut self, nonce: u64) -> anyhow::Result<DatabaseUpdate<BsatnFormat>> {
+        self.send(ClientMessage::CallReducer(CallReducer {
+            reducer: "probe".into(),
+            args: bsatn::to_vec(&nonce)?.into(),
+            request_id: 2,
+            flags: CallReducerFlags::FullUpdate,
+            traceparent: None,
+        }))
+        .await?;
+        let update = match self.recv().await? {
+            ServerMessage::TransactionUpdate(update) => update,
+            msg => bail!("expected a TransactionUpdate, got {}", message_kind(&msg)),
+        };
+        match update.status {
+            UpdateStatus::Committed(update) => Ok(update),
+            UpdateStatus::Failed(e) => bail!("the reducer failed: {e}"),
+            UpdateStatus::OutOfEnergy => bail!("the reducer ran out of energy"),
+        }
+    }
+
+    async fn close(mut self) -> anyhow::Result<()> {
+        self.socket.close(None).await?;
+        // Wait for the server to acknowledge the close.
+        while let Some(msg) = self.socket.next().await {
+            msg?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: ClientMessage<Bytes>) -> anyhow::Result<()> {
+        self.socket.send(WsMessage::Binary(bsatn::to_vec(&msg)?.into())).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> anyhow::Result<ServerMessage<BsatnFormat>> {
+        loop {
+            match self.socket.next().await.context("the connection was dropped")?? {
+                WsMessage::Binary(bytes) => {
+                    let (&tag, msg) = bytes.split_first().context("received an empty message")?;
+                    ensure!(tag == SERVER_MSG_COMPRESSION_TAG_NONE, "received a compressed message");
+                    return Ok(bsatn::from_slice(msg)?);
+                }
+                WsMessage::Close(frame) => bail!("the server closed the connection: {frame:?}"),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Check that `update` inserted the `probes` row for `nonce`.
+fn expect_probe(update: DatabaseUpdate<BsatnFormat>, nonce: u64) -> anyhow::Result<()> {
+    let table = update
+        .tables
+        .into_iter()
+        .find(|table| &*table.table_name == "probes")
+        .context("the update didn't include the probes table")?;
+    for update in table.updates {
+        for row in &update.maybe_decompress().inserts {
+            if bsatn::from_slice::<u64>(&row)? == nonce {
+                return Ok(());
+            }
+        }
+    }
+    bail!("the update didn't include the inserted row")
+}
+
+fn message_kind(msg: &ServerMessage<BsatnFormat>) -> &'static str {
+    match msg {
+        ServerMessage::InitialSubscription(_) => "InitialSubscription",
+        ServerMessage::TransactionUpdate(_) => "TransactionUpdate",
+        ServerMessage::TransactionUpdateLight(_) => "TransactionUpdateLight",
+        ServerMessage::IdentityToken(_) => "IdentityToken",
+        ServerMessage::OneOffQueryResponse(_) => "OneOffQueryResponse",
+        ServerMessage::SubscribeApplied(_) => "SubscribeApplied",
+        ServerMessage::UnsubscribeApplied(_) => "UnsubscribeApplied",
+        ServerMessage::SubscriptionError(_) => "SubscriptionError",
+        ServerMessage::SubscribeMultiApplied(_) => "SubscribeMultiApplied",
+        ServerMessage::UnsubscribeMultiApplied(_) => "UnsubscribeMultiApplied",
+        ServerMessage::SubscribeMultiExplained(_) => "SubscribeMultiExplained",
+        ServerMessage::DatabaseStats(_) => "DatabaseStats",
+        ServerMessage::ReducerTimings(_) => "ReducerTimings",
+        ServerMessage::ReconnectRequested(_) => "ReconnectRequested",
+        ServerMessage::AcknowledgedUpdate(_) => "AcknowledgedUpdate",
+        ServerMessage::ConnectionStatus(_) => "ConnectionStatus",
+        ServerMessage::MessageDropped(_) => "MessageDropped",
+        ServerMessage::DeprecationNotice(_) => "DeprecationNotice",
+        ServerMessage::SubscribeWindowApplied(_) => "SubscribeWindowApplied",
+        ServerMessage::CallProgress(_) => "CallProgress",
+        ServerMessage::ConnectionDegraded(_) => "ConnectionDegraded",
+        ServerMessage::ResyncRequired(_) => "ResyncRequired",
+        ServerMessage::RequestError(_) => "RequestError",
+        ServerMessage::SettingsUpdated(_) => "SettingsUpdated",
+        ServerMessage::TokenRefreshed(_) => "TokenRefreshed",
+        ServerMessage::QueryRows(_) => "QueryRows",
+        ServerMessage::ServerCapabilities(_) => "ServerCapabilities",
+    }
+}
+
+pub fn router<S>() -> axum::Router<S>
+where
+    S: NodeDelegate + ControlStateDelegate + Clone + 'static,
+{
+    use axum::routing::post;
+    axum::Router::new().route("/selftest", post(selftest::<S>))
+}
@@ -1,4 +1,4 @@
-use crate::NodeDelegate;
+use crate::{ControlStateDelegate, NodeDelegate};
 
 #[cfg(not(target_env = "msvc"))]
 mod jemalloc_profiling {
@@ -153,10 +153,75 @@ mod jemalloc_profiling {
     }
 }
 
+mod drain {
+    use std::time::Duration;
+
+    use axum::extract::{Query, State};
+    use axum::response::IntoResponse;
+    use axum::Json;
+    use serde::{Deserialize, Serialize};
+
+    use crate::NodeDelegate;
+
+    /// Query parameters for draining the node
+    #[derive(Deserialize)]
+    struct DrainQuery {
+        /// How many seconds clients should wait before reconnecting, if known.
+        retry_after_secs: Option<u64>,
+    }
+
+    /// JSON response for the current state of draining
+    #[derive(Serialize)]
+    struct DrainState {
+        draining: bool,
+        retry_after_secs: Option<u64>,
+        /// The number of clients asked to close, when draining begins.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        closing: Option<usize>,
+    }
+
+    impl DrainState {
+        fn of<S: NodeDelegate>(ctx: &S, closing: Option<usize>) -> Self {
+            let draining = ctx.client_actor_index().draining();
+            Self {
+                draining: draining.is_some(),
+                retry_after_secs: draining.and_then(|d| d.retry_after).map(|after| after.as_secs()),
+                closing,
+            }
+        }
+    }
+
+    /// Handles draining the node via a `POST` request,
+    /// closing every websocket connection and refusing new ones, e.g. before the node is restarted.
+    async fn handle_post_drain<S: NodeDelegate>(
+        State(ctx): State<S>,
+        Query(params): Query<DrainQuery>,
+    ) -> impl IntoResponse {
+        let retry_after = params.retry_after_secs.map(Duration::from_secs);
+        let closing = ctx.client_actor_index().drain(retry_after);
+        Json(DrainState::of(&ctx, Some(closing)))
+    }
+
+    /// Handles retrieving whether the node is draining via a `GET` request
+    async fn handle_get_drain<S: NodeDelegate>(State(ctx): State<S>) -> impl IntoResponse {
+        Json(DrainState::of(&ctx, None))
+    }
+
+    pub fn drain_router<S: NodeDelegate + Clone + 'static>() -> axum::Router<S> {
+        use axum::routing::get;
+        axum::Router::new().route("/", get(handle_get_drain::<S>).post(handle_post_drain::<S>))
+    }
+}
+
 // The internal router is for things that are not meant to be exposed to the public API.
 pub fn router<S>() -> axum::Router<S>
 where
-    S: NodeDelegate + Clone + 'static,
+    S: NodeDelegate + ControlStateDelegate + Clone + 'static,
 {
-    axum::Router::new().nest("/heap", jemalloc_profiling::jemalloc_router())
+    let router = axum::Router::new()
+        .nest("/heap", jemalloc_profiling::jemalloc_router())
+        .nest("/drain", drain::drain_router());
+    #[cfg(feature = "diagnostics")]
+    let router = router.nest("/diagnostics", super::diagnostics::router());
+    router
 }
@@ -1,7 +1,7 @@
 use axum::extract::State;
 use axum::response::IntoResponse;
 
-use crate::NodeDelegate;
+use crate::MetricsSink;
 
 // #[derive(Clone, NewMiddleware)]
 // pub struct MetricsAuthMiddleware;
@@ -15,7 +15,7 @@ use crate::NodeDelegate;
 //     }
 // }
 
-pub async fn metrics<S: NodeDelegate>(State(ctx): State<S>) -> axum::response::Result<impl IntoResponse> {
+pub async fn metrics<S: MetricsSink>(State(ctx): State<S>) -> axum::response::Result<impl IntoResponse> {
     let mut buf = String::new();
 
     let mut encode_to_buffer = |mfs: &[_]| {
@@ -24,7 +24,7 @@ pub async fn metrics<S: NodeDelegate>(State(ctx): State<S>) -> axum::response::R
         }
     };
 
-    encode_to_buffer(&ctx.gather_metrics());
+    encode_to_buffer(&ctx.collect_metrics());
     encode_to_buffer(&prometheus::gather());
 
     Ok(buf)
@@ -32,7 +32,7 @@ pub async fn metrics<S: NodeDelegate>(State(ctx): State<S>) -> axum::response::R
 
 pub fn router<S>() -> axum::Router<S>
 where
-    S: NodeDelegate + Clone + 'static,
+    S: MetricsSink + Clone + 'static,
 {
     use axum::routing::get;
     axum::Router::new().route("/", get(metrics::<S>))
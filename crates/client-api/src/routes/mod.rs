@@ -5,11 +5,14 @@ use tower_http::cors;
 use crate::{ControlStateDelegate, NodeDelegate};
 
 pub mod database;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
 pub mod energy;
 pub mod health;
 pub mod identity;
 mod internal;
 pub mod metrics;
+pub mod presence;
 pub mod prometheus;
 pub mod subscribe;
 
@@ -29,7 +32,15 @@ where
         .nest("/energy", energy::router())
         .nest("/prometheus", prometheus::router())
         .nest("/metrics", metrics::router())
+        .nest("/presence", presence::router())
         .route("/ping", get(ping))
+        .route(
+            "/subscribe",
+            get(subscribe::handle_multiplexed_websocket::<S>).route_layer(axum::middleware::from_fn_with_state(
+                ctx.clone(),
+                crate::auth::anon_auth_middleware::<S>,
+            )),
+        )
         .merge(extra);
 
     let cors = cors::CorsLayer::new()
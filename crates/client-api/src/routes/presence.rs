@@ -0,0 +1,159 @@
+use axum::extract::{Query, State};
+use axum::response::IntoResponse;
+use futures::{SinkExt, StreamExt};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use spacetimedb::client::PresenceEvent;
+use spacetimedb_lib::Identity;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::auth::SpacetimeAuthRequired;
+use crate::util::websocket::{Message as WsMessage, WebSocketConfig, WebSocketUpgrade};
+use crate::NodeDelegate;
+
+#[derive(Deserialize)]
+pub struct PresenceQueryParams {
+    /// A comma-separated list of hex-encoded identities.
+    identities: String,
+}
+
+impl PresenceQueryParams {
+    fn identities(&self) -> axum::response::Result<Vec<Identity>> {
+        self.identities
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                Identity::from_hex(s).map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid identity: {s}")).into())
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct IdentityPresence {
+    identity: String,
+    databases: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct PresenceResponse {
+    presence: Vec<IdentityPresence>,
+}
+
+#[derive(Serialize)]
+pub struct PresenceUpdate {
+    identity: String,
+    database: String,
+    online: bool,
+}
+
+impl From<PresenceEvent> for PresenceUpdate {
+    fn from(event: PresenceEvent) -> Self {
+        Self {
+            identity: event.identity.to_hex().to_string(),
+            database: event.database_identity.to_hex().to_string(),
+            online: event.online,
+        }
+    }
+}
+
+/// Returns the publishing databases owned by the caller
+/// which each of the requested identities is connected to.
+pub async fn get_presence<S: NodeDelegate>(
+    State(ctx): State<S>,
+    Query(params): Query<PresenceQueryParams>,
+    SpacetimeAuthRequired(auth): SpacetimeAuthRequired,
+) -> axum::response::Result<impl IntoResponse> {
+    let identities = params.identities()?;
+    let presence = ctx
+        .client_actor_index()
+        .presence()
+        .query(&auth.identity, &identities)
+        .into_iter()
+        .map(|(identity, databases)| IdentityPresence {
+            identity: identity.to_hex().to_string(),
+            databases: databases.iter().map(|db| db.to_hex().to_string()).collect(),
+        })
+        .collect();
+    Ok(axum::Json(PresenceResponse { presence }))
+}
+
+/// Streams [`PresenceUpdate`]s for the requested identities as JSON text messages,
+/// restricted to publishing databases owned by the caller.
+pub async fn subscribe_presence<S: NodeDelegate + Clone + 'static>(
+    State(ctx): State<S>,
+    Query(params): Query<PresenceQueryParams>,
+    SpacetimeAuthRequired(auth): SpacetimeAuthRequired,
+    ws_upgrade: WebSocketUpgrade,
+) -> axum::response::Result<impl IntoResponse> {
+    let identities = params.identities()?;
+    let mut events = ctx.client_actor_index().presence().subscribe();
+    let (res, ws_upgrade) = ws_upgrade.ignore_protocol();
+
+    tokio::spawn(async move {
+        let mut ws = match ws_upgrade.upgrade(WebSocketConfig::default()).await {
+            Ok(ws) => ws,
+            Err(err) => {
+                log::error!("WebSocket init error: {}", err);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(n)) => {
+                            log::warn!("presence subscriber for {} lagged by {n} events", auth.identity);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    if event.owner_identity != auth.identity || !identities.contains(&event.identity) {
+                        continue;
+                    }
+                    let json = serde_json::to_string(&PresenceUpdate::from(event)).unwrap();
+                    if ws.send(WsMessage::Text(json.into())).await.is_err() {
+                        break;
+                    }
+                }
+                message = ws.next() => match message {
+                    Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    });
+
+    Ok(res)
+}
+
+/// Opts the caller out of having their presence published by any database.
+pub async fn opt_out<S: NodeDelegate>(
+    State(ctx): State<S>,
+    SpacetimeAuthRequired(auth): SpacetimeAuthRequired,
+) -> axum::response::Result<impl IntoResponse> {
+    ctx.client_actor_index().presence().set_opted_out(auth.identity, true);
+    Ok(())
+}
+
+/// Reverts [`opt_out`].
+pub async fn opt_in<S: NodeDelegate>(
+    State(ctx): State<S>,
+    SpacetimeAuthRequired(auth): SpacetimeAuthRequired,
+) -> axum::response::Result<impl IntoResponse> {
+    ctx.client_actor_index().presence().set_opted_out(auth.identity, false);
+    Ok(())
+}
+
+pub fn router<S>() -> axum::Router<S>
+where
+    S: NodeDelegate + Clone + 'static,
+{
+    use axum::routing::{get, put};
+    axum::Router::new()
+        .route("/", get(get_presence::<S>))
+        .route("/subscribe", get(subscribe_presence::<S>))
+        .route("/opt-out", put(opt_out::<S>).delete(opt_in::<S>))
+}
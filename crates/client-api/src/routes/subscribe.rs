@@ -1,45 +1,87 @@
 use std::mem;
 use std::pin::{pin, Pin};
-use std::time::Duration;
+use std::str::Utf8Error;
+use std::time::{Duration, SystemTime};
 
+use async_trait::async_trait;
 use axum::extract::{Path, Query, State};
 use axum::response::IntoResponse;
 use axum::Extension;
 use axum_extra::TypedHeader;
 use bytes::Bytes;
 use bytestring::ByteString;
-use futures::future::MaybeDone;
+use futures::future::{BoxFuture, MaybeDone};
 use futures::{Future, FutureExt, SinkExt, StreamExt};
-use http::{HeaderValue, StatusCode};
-use scopeguard::ScopeGuard;
-use serde::Deserialize;
-use spacetimedb::client::messages::{serialize, IdentityTokenMessage, SerializableMessage, SerializeBuffer};
+use http::{header, HeaderValue, StatusCode};
+use prometheus::Histogram;
+use serde::{Deserialize, Serialize};
+use spacetimedb::auth::identity::SpacetimeIdentityClaims;
+use spacetimedb::auth::token_validation::{TokenValidationError, TokenValidator};
+use spacetimedb::client::messages::{
+    ConnectionDegradedMessage, ConnectionStatusMessage, IdentityTokenMessage, InUseSerializeBuffer,
+    MessageDroppedMessage, MessageKind, ReconnectRequestedMessage, RequestErrorMessage, SerializableMessage,
+    SerializeBuffer, ServerCapabilitiesMessage, SwitchedServerMessage, ToProtocol, TokenRefreshedMessage,
+};
 use spacetimedb::client::{
-    ClientActorId, ClientConfig, ClientConnection, DataMessage, MessageHandleError, MeteredDeque, MeteredReceiver,
-    Protocol,
+    new_resume_token, AddressPermit, AdmissionPermit, ClientActorId, ClientActorIndex, ClientConfig, ClientConnection,
+    ClientConnectionSender, ClientRegistration, ClientSendError, CloseReason, ConnectionIdReservation,
+    ConnectionIdReuse, ConnectionPolicy, DataMessage, Draining, EncodeErrorPolicy, EncodeFailure, EncodeResult,
+    ErrorFormat, HandleOutcome, IncomingQueueOverflow, MessageExecutionError, MessageHandleError, MessageRateLimit,
+    MessageThrottle, MeteredDeque, MeteredReceiver, NodeOverloaded, NodeUnderLoad, Protocol, ProtocolViolation,
+    ReconnectGrant, ReconnectTokens, RequestRejected, ResumableSessions, ResumeError, Resumption, SizeHint,
+    TokenBucket, WebSocketOptions,
 };
 use spacetimedb::execution_context::WorkloadType;
 use spacetimedb::host::module_host::ClientConnectedError;
-use spacetimedb::host::NoSuchModule;
+use spacetimedb::host::{ModuleExitCause, ModuleHost};
+use spacetimedb::messages::control_db::{
+    AuthExpiryAction, AuthExpiryPolicy, ClientConfigDefaults, ClientDefault, ConnectionTimeouts, SendBacklogAction,
+    SendBacklogPolicy,
+};
 use spacetimedb::util::also_poll;
 use spacetimedb::worker_metrics::WORKER_METRICS;
 use spacetimedb::Identity;
-use spacetimedb_client_api_messages::websocket::{self as ws_api, Compression};
+use spacetimedb_client_api_messages::e2e;
+pub use spacetimedb_client_api_messages::websocket::RetryHint;
+use spacetimedb_client_api_messages::websocket::{
+    self as ws_api, Compression, DisconnectReason, ProtocolVersion, RequestErrorCode, ServerFeature,
+};
 use spacetimedb_lib::connection_id::{ConnectionId, ConnectionIdForUrl};
+use spacetimedb_lib::{DatabaseFeature, DatabaseFeatures, TimeDuration, Timestamp};
+use std::sync::{Arc, LazyLock};
 use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Semaphore;
+use tokio::time::error::Elapsed;
+use tokio::time::MissedTickBehavior;
 use tokio_tungstenite::tungstenite::Utf8Bytes;
 
-use crate::auth::SpacetimeAuth;
+use crate::acl;
+use crate::auth::{PresentedReconnectToken, SpacetimeAuth, SpacetimeReconnectToken};
+use crate::deprecation::{DeprecationNotices, Handshake, Usage};
+use crate::hooks::{self, CloseCause, ConnectionContext, ConnectionLifecycleHooks};
+use crate::routes::database::{find_leader, module_schema_headers, worker_ctx_find_database};
+use crate::timeouts::{ClientTimeouts, TimeoutsConfig};
 use crate::util::websocket::{
-    CloseCode, CloseFrame, Message as WsMessage, WebSocketConfig, WebSocketStream, WebSocketUpgrade,
+    tungstenite::error::ProtocolError, tungstenite::Error as WsError, CloseCode, CloseFrame, Message as WsMessage,
+    PendingWebSocket, WebSocketConfig, WebSocketStream, WebSocketUpgrade,
 };
-use crate::util::{NameOrIdentity, XForwardedFor};
-use crate::{log_and_500, ControlStateDelegate, NodeDelegate};
+use crate::util::{ClientAddr, NameOrIdentity};
+use crate::{log_and_500, ClientActors, DatabaseResolution, LeaderLookup};
+
+mod multiplex;
+mod poll_watchdog;
+pub use multiplex::handle_multiplexed_websocket;
+use poll_watchdog::PollWatchdog;
 
 #[allow(clippy::declare_interior_mutable_const)]
 pub const TEXT_PROTOCOL: HeaderValue = HeaderValue::from_static(ws_api::TEXT_PROTOCOL);
 #[allow(clippy::declare_interior_mutable_const)]
 pub const BIN_PROTOCOL: HeaderValue = HeaderValue::from_static(ws_api::BIN_PROTOCOL);
+#[allow(clippy::declare_interior_mutable_const)]
+pub const TEXT_PROTOCOL_V2: HeaderValue = HeaderValue::from_static(ws_api::TEXT_PROTOCOL_V2);
+#[allow(clippy::declare_interior_mutable_const)]
+pub const BIN_PROTOCOL_V2: HeaderValue = HeaderValue::from_static(ws_api::BIN_PROTOCOL_V2);
 
 #[derive(Deserialize)]
 pub struct SubscribeParams {
@@ -49,14 +91,202 @@ pub struct SubscribeParams {
 #[derive(Deserialize)]
 pub struct SubscribeQueryParams {
     pub connection_id: Option<ConnectionIdForUrl>,
-    #[serde(default)]
-    pub compression: Compression,
+    /// The compression the client wants, if it has a preference.
+    ///
+    /// If not, the database's default applies, see [`ClientConfigDefaults`].
+    pub compression: Option<Compression>,
+    /// The length, in bytes, of a serialized message beyond which it's compressed,
+    /// if the client wants another than [`DEFAULT_COMPRESSION_THRESHOLD`](ws_api::DEFAULT_COMPRESSION_THRESHOLD).
+    ///
+    /// Shorter messages are sent uncompressed, tagged as such, whatever the compression.
+    pub compression_threshold: Option<usize>,
     /// Whether we want "light" responses, tailored to network bandwidth constrained clients.
-    /// This knob works by setting other, more specific, knobs to the value.
+    /// This knob works by setting other, more specific, knobs to the value,
+    /// i.e. turning off `tx_update_full`, `include_caller_info` and `include_timings`.
+    ///
+    /// Those knobs which are set as well take precedence over it,
+    /// e.g. `light=true&tx_update_full=true` gets full transaction updates without caller info or timings.
+    ///
+    /// If unset, the database's default applies, see [`ClientConfigDefaults`].
+    /// Should the database's owner have forced a default, it takes precedence over all of the knobs.
+    pub light: Option<bool>,
+    /// Whether the client is sent full transaction updates, rather than light ones,
+    /// see [`ClientConfig::tx_update_full`].
+    pub tx_update_full: Option<bool>,
+    /// Whether full transaction updates carry the arguments and caller of other clients' reducer calls,
+    /// see [`ClientConfig::include_caller_info`].
+    pub include_caller_info: Option<bool>,
+    /// Whether full transaction updates carry when their reducer ran, and for how long,
+    /// see [`ClientConfig::include_timings`].
+    pub include_timings: Option<bool>,
+    /// If set, the owner of the database is sent a `DatabaseStats` message this often,
+    /// but no more than once per [`MIN_STATS_INTERVAL`].
+    pub stats_interval_secs: Option<u64>,
+    /// If set, the owner of the database is sent a `ReducerTimings` message
+    /// after the reply to each of its reducer calls.
+    #[serde(default)]
+    pub reducer_timings: bool,
+    /// If set, the updates of reducers the module declared acknowledged are sent as `AcknowledgedUpdate`s,
+    /// which the client must acknowledge, and those it didn't are delivered again when it reconnects.
     #[serde(default)]
-    pub light: bool,
+    pub acknowledged_delivery: bool,
+    /// If set, the client is sent a `RequestError` when one of its messages fails for a reason it can recover from,
+    /// e.g. it couldn't be decoded or called a reducer which doesn't exist, rather than having its connection closed.
+    #[serde(default)]
+    pub request_errors: bool,
+    /// If set, the client's ephemeral X25519 public key, base64url-encoded,
+    /// with which every message of the connection is end-to-end encrypted, see [`e2e`].
+    pub e2e_public_key: Option<String>,
+    /// How often the client wants to be pinged, in seconds, if it has a preference.
+    ///
+    /// If not, the database's timeout applies, see [`ConnectionTimeouts`].
+    /// Either way, it's clamped to the bounds set by the node.
+    pub liveness_timeout_secs: Option<u64>,
+    /// How long the client wants sends to it to be allowed to take, in seconds, if it has a preference.
+    ///
+    /// As with [`Self::liveness_timeout_secs`], the database's timeout applies if not,
+    /// and either is clamped to the node's bounds.
+    pub send_timeout_secs: Option<u64>,
+    /// If set, and the node keeps the sessions of clients which lost their connection,
+    /// the client is sent a token with which it can resume its session, see [`ws_api::RESUME_TOKEN_HEADER`].
+    #[serde(default)]
+    pub resumable: bool,
+    /// The token of the session the client wants to resume, rather than connecting anew.
+    ///
+    /// Whether it did is told by [`ws_api::SESSION_RESUMED_HEADER`].
+    /// A resumed session keeps its token and connection id, so any `connection_id` asked for is ignored.
+    pub resume_token: Option<String>,
+    /// If set, the client's updates are subject to flow control, with this many credits to begin with,
+    /// which it replenishes with `GrantCredits` messages, see [`ws_api::GrantCredits`].
+    ///
+    /// If unset, updates are sent as fast as the connection allows.
+    pub flow_control_credits: Option<u64>,
+}
+
+/// The server's ephemeral public key for an end-to-end encrypted connection,
+/// in the [`e2e::PUBLIC_KEY_HEADER`] header of the upgrade response.
+pub struct SpacetimeE2ePublicKey(pub String);
+impl headers::Header for SpacetimeE2ePublicKey {
+    fn name() -> &'static http::HeaderName {
+        static NAME: http::HeaderName = http::HeaderName::from_static(e2e::PUBLIC_KEY_HEADER);
+        &NAME
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(_values: &mut I) -> Result<Self, headers::Error> {
+        unimplemented!()
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend([HeaderValue::try_from(&self.0).unwrap()])
+    }
+}
+
+/// The server's [`e2e::KeyBinding`], signed by the node,
+/// in the [`e2e::KEY_BINDING_HEADER`] header of the upgrade response.
+pub struct SpacetimeE2eKeyBinding(pub String);
+impl headers::Header for SpacetimeE2eKeyBinding {
+    fn name() -> &'static http::HeaderName {
+        static NAME: http::HeaderName = http::HeaderName::from_static(e2e::KEY_BINDING_HEADER);
+        &NAME
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(_values: &mut I) -> Result<Self, headers::Error> {
+        unimplemented!()
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend([HeaderValue::try_from(&self.0).unwrap()])
+    }
+}
+
+/// The token with which a client can resume its session, in the [`ws_api::RESUME_TOKEN_HEADER`] header
+/// of the upgrade response.
+pub struct SpacetimeResumeToken(pub String);
+impl headers::Header for SpacetimeResumeToken {
+    fn name() -> &'static http::HeaderName {
+        static NAME: http::HeaderName = http::HeaderName::from_static(ws_api::RESUME_TOKEN_HEADER);
+        &NAME
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(_values: &mut I) -> Result<Self, headers::Error> {
+        unimplemented!()
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        let mut value = HeaderValue::try_from(&self.0).unwrap();
+        value.set_sensitive(true);
+        values.extend([value])
+    }
+}
+
+/// The sessions of the clients of this node which lost their connection, kept for them to resume.
+static RESUMABLE_SESSIONS: LazyLock<ResumableSessions<WebSocketStream>> = LazyLock::new(Default::default);
+
+/// How often the messages queued for a parked session are counted,
+/// to give up on it once there are more than [`SessionResume::buffer_len`].
+const RESUME_BUFFER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How the session of a client which asked for it to be resumable is kept should its connection drop,
+/// see [`WebSocketOptions::session_resume_grace`].
+struct SessionResume<S: 'static> {
+    /// Where the session is parked, to be resumed over a new websocket of type `S`.
+    sessions: &'static ResumableSessions<S>,
+    /// The token the client resumes the session with.
+    token: String,
+    /// How long the session is kept for the client to resume.
+    grace: Duration,
+    /// How many messages may be queued for the client meanwhile.
+    buffer_len: usize,
+}
+
+/// Count an attempt to resume a session of a client of `database_identity`, by its `result`.
+fn count_resume_attempt<T>(database_identity: &Identity, result: Result<T, ResumeError>) -> Result<T, ResumeError> {
+    let outcome = match &result {
+        Ok(_) => "hit",
+        Err(ResumeError::Unknown) => "miss",
+        Err(ResumeError::Mismatch) => "mismatch",
+    };
+    WORKER_METRICS
+        .ws_session_resume_attempts
+        .with_label_values(database_identity, outcome)
+        .inc();
+    result
+}
+
+/// The [`DatabaseStats`](ws_api::DatabaseStats) of the database of `module`,
+/// including the sessions parked on this node for its clients to resume.
+pub async fn database_stats(module: &ModuleHost) -> ws_api::DatabaseStats {
+    let mut stats = module.database_stats().await;
+    let (sessions, bytes) = RESUMABLE_SESSIONS.buffered(&module.info().database_identity);
+    stats.replay_buffers.parked_sessions = sessions as u32;
+    stats.replay_buffers.parked_session_bytes = bytes as u64;
+    stats
 }
 
+/// What was held for a client to replay to it later, and was purged, see [`purge_replay_buffers`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct PurgedReplayBuffers {
+    /// The number of sessions parked for the client to resume.
+    pub parked_sessions: usize,
+    /// The number of acknowledged updates pending for the client.
+    pub pending_deliveries: usize,
+}
+
+/// Forget what's held on this node for the clients of the database of `module` connected as `identity`,
+/// so that their next connection starts afresh.
+///
+/// A client resuming a purged session is told it can't be resumed, rather than being replayed part of it,
+/// and a client reconnecting is redelivered none of its pending acknowledged updates.
+pub fn purge_replay_buffers(module: &ModuleHost, identity: &Identity) -> PurgedReplayBuffers {
+    PurgedReplayBuffers {
+        parked_sessions: RESUMABLE_SESSIONS.purge(&module.info().database_identity, identity),
+        pending_deliveries: module.pending_deliveries().purge(identity),
+    }
+}
+
+/// The shortest interval at which database stats are pushed to a connection.
+pub const MIN_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
 pub fn generate_random_connection_id() -> ConnectionId {
     ConnectionId::from_le_byte_array(rand::random())
 }
@@ -67,74 +297,296 @@ pub async fn handle_websocket<S>(
     Query(SubscribeQueryParams {
         connection_id,
         compression,
+        compression_threshold,
         light,
+        tx_update_full,
+        include_caller_info,
+        include_timings,
+        stats_interval_secs,
+        reducer_timings,
+        acknowledged_delivery,
+        request_errors,
+        e2e_public_key,
+        liveness_timeout_secs,
+        send_timeout_secs,
+        resumable,
+        resume_token,
+        flow_control_credits,
     }): Query<SubscribeQueryParams>,
-    forwarded_for: Option<TypedHeader<XForwardedFor>>,
+    client_addr: ClientAddr,
     Extension(auth): Extension<SpacetimeAuth>,
+    reconnect: Option<Extension<PresentedReconnectToken>>,
     ws: WebSocketUpgrade,
 ) -> axum::response::Result<impl IntoResponse>
 where
-    S: NodeDelegate + ControlStateDelegate,
+    S: ClientActors + DatabaseResolution + LeaderLookup + Clone + 'static,
 {
-    if connection_id.is_some() {
+    // Shed new connections before doing any work on their behalf.
+    let admission = admit(&ctx)?;
+    // Counts the connection against the limit of its address for as long as it's served.
+    let address_permit = admit_address(ctx.actor_index(), client_addr)?;
+
+    let client_connection_id = connection_id.is_some();
+    if client_connection_id {
         // TODO: Bump this up to `log::warn!` after removing the client SDKs' uses of that parameter.
         log::debug!("The connection_id query parameter to the subscribe HTTP endpoint is internal and will be removed in a future version of SpacetimeDB.");
     }
 
-    let connection_id = connection_id
-        .map(ConnectionId::from)
-        .unwrap_or_else(generate_random_connection_id);
+    let db_identity = name_or_identity.resolve(&ctx).await?;
+
+    // A resuming client keeps the connection id of its session, so any it asked for is ignored.
+    let requested_connection_id = connection_id
+        .filter(|_| resume_token.is_none())
+        .map(|connection_id| check_client_connection_id(connection_id.into()))
+        .transpose()?;
+    // A client taking over the connection id of another of its connections to the database replaces it,
+    // which is put off until nothing else could refuse the client.
+    let replaces = requested_connection_id.is_some()
+        && ctx.actor_index().websocket_options().connection_id_reuse == ConnectionIdReuse::Replace;
+    let connection_id = requested_connection_id
+        .unwrap_or_else(|| ctx.actor_index().generate_connection_id(generate_random_connection_id));
+    // Held until the client is registered, so that no other client can take its connection id in the meantime.
+    let connection_id_reservation = (!replaces)
+        .then(|| reserve_client_connection_id(ctx.actor_index(), db_identity, auth.identity, connection_id))
+        .transpose()?;
 
-    if connection_id == ConnectionId::ZERO {
+    acl::check(&ctx, &db_identity, client_addr).await?;
+    let config_defaults = ctx
+        .find_client_config_defaults(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    // The connection negotiates the features of the database as of now, keeping them until it reconnects.
+    let features = ctx
+        .find_database_features(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    let features_header = [(
+        http::HeaderName::from_static(ws_api::FEATURES_HEADER),
+        HeaderValue::from_str(&ws_api::encode_features(features)).unwrap(),
+    )];
+    let database_timeouts = ctx
+        .find_connection_timeouts(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    // The database may lower the node's limits on the size of the messages its clients send.
+    let size_limits = ctx
+        .find_message_size_limits(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    let websocket_options = ctx
+        .actor_index()
+        .websocket_options()
+        .with_message_size_limits(&size_limits);
+    let requested_timeouts = ConnectionTimeouts {
+        liveness_timeout_secs,
+        send_timeout_secs,
+    };
+    // The connection keeps the timeouts current as it connects, should they be reloaded.
+    let timeouts_config = ctx.timeouts();
+    let timeouts = timeouts_config.client_timeouts(&database_timeouts, &requested_timeouts);
+
+    let (res, ws_upgrade, protocol) = ws.select_protocol([
+        (BIN_PROTOCOL_V2, (Protocol::Binary, ProtocolVersion::V2)),
+        (TEXT_PROTOCOL_V2, (Protocol::Text, ProtocolVersion::V2)),
+        (BIN_PROTOCOL, (Protocol::Binary, ProtocolVersion::V1)),
+        (TEXT_PROTOCOL, (Protocol::Text, ProtocolVersion::V1)),
+    ]);
+
+    let (protocol, version) = protocol.ok_or((StatusCode::BAD_REQUEST, "no valid protocol selected"))?;
+    if let Some(compression) = compression.filter(|&compression| !version.supports(compression)) {
+        // Refuse the connection outright, rather than sending frames the client can't decode.
         Err((
             StatusCode::BAD_REQUEST,
-            "Invalid connection ID: the all-zeros ConnectionId is reserved.",
+            format!(
+                "The requested compression, {compression:?}, requires a newer version of the protocol: \
+                 connect with `{}` or `{}`.",
+                ws_api::BIN_PROTOCOL_V2,
+                ws_api::TEXT_PROTOCOL_V2,
+            ),
         ))?;
     }
-
-    let db_identity = name_or_identity.resolve(&ctx).await?;
-
-    let (res, ws_upgrade, protocol) =
-        ws.select_protocol([(BIN_PROTOCOL, Protocol::Binary), (TEXT_PROTOCOL, Protocol::Text)]);
-
-    let protocol = protocol.ok_or((StatusCode::BAD_REQUEST, "no valid protocol selected"))?;
+    // A client resuming its session hands its websocket over to the actor which kept the session,
+    // rather than connecting anew, unless the session can no longer be resumed.
+    let session_resumed = match resume_token {
+        Some(token) => match count_resume_attempt(
+            &db_identity,
+            RESUMABLE_SESSIONS.resume(&token, auth.identity, db_identity, protocol, version),
+        ) {
+            Ok(resumption) => {
+                let ws_config = websocket_config(&websocket_options, protocol);
+                let runtime = ctx.actor_index().client_runtime();
+                runtime.spawn(resume_session(resumption, ws_upgrade, ws_config));
+                return Ok((session_resumed_header(true), res).into_response());
+            }
+            Err(e @ ResumeError::Mismatch) => Err((StatusCode::BAD_REQUEST, format!("Invalid resume token: {e}")))?,
+            Err(ResumeError::Unknown) => Some(session_resumed_header(false)),
+        },
+        None => None,
+    };
+    // Messages are encrypted after being compressed, so encryption is independent of the compression in effect.
+    let encryption = e2e_public_key
+        .map(|client_public_key| {
+            let key_pair = e2e::KeyPair::generate();
+            let server_public_key = key_pair.public_key();
+            let session = key_pair
+                .agree(e2e::Role::Server, &client_public_key)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            let binding = e2e::KeyBinding {
+                client_public_key,
+                server_public_key,
+            };
+            let signed_binding = ctx.sign_e2e_key_binding(&binding).transpose().map_err(log_and_500)?;
+            let headers = (
+                TypedHeader(SpacetimeE2ePublicKey(binding.server_public_key)),
+                signed_binding.map(|binding| TypedHeader(SpacetimeE2eKeyBinding(binding))),
+            );
+            Ok::<_, axum::response::ErrorResponse>((session, headers))
+        })
+        .transpose()?;
+    let (encryption, e2e_headers) = encryption.unzip();
+    let (compression, light) = merge_config_defaults(version, compression, light, &config_defaults, features);
+    let requested_knobs = UpdateKnobs {
+        tx_update_full,
+        include_caller_info,
+        include_timings,
+    };
+    let knobs = requested_knobs.resolve(light, config_defaults.light);
     let client_config = ClientConfig {
         protocol,
+        version,
         compression,
-        tx_update_full: !light,
+        tx_update_full: knobs.tx_update_full,
+        include_caller_info: knobs.include_caller_info,
+        include_timings: knobs.include_timings,
+        reducer_timings,
+        acknowledged_delivery: acknowledged_delivery || features.contains(DatabaseFeature::AcknowledgedDelivery),
+        request_errors,
+        zstd_level: ctx.actor_index().websocket_options().zstd_level,
+        compression_threshold: compression_threshold.unwrap_or(ws_api::DEFAULT_COMPRESSION_THRESHOLD),
     };
 
-    // TODO: Should also maybe refactor the code and the protocol to allow a single websocket
-    // to connect to multiple modules
+    let mut deprecations = DeprecationNotices::default();
+    let handshake = Handshake {
+        client_connection_id,
+        version,
+        compression,
+    };
+    let deprecation_notices = deprecations.check(&db_identity, Usage::Handshake(handshake));
 
-    let database = ctx
-        .get_database_by_identity(&db_identity)
-        .unwrap()
-        .ok_or(StatusCode::NOT_FOUND)?;
+    let hooks = ctx.lifecycle_hooks();
+    let conn = ConnectionContext {
+        identity: auth.identity,
+        connection_id,
+        database_identity: db_identity,
+        config: client_config,
+        deprecated_features: deprecations.used().to_vec(),
+    };
+    hooks::on_connect(&*hooks, &conn).await?;
 
-    let leader = ctx
-        .leader(database.id)
-        .await
-        .map_err(log_and_500)?
+    // A client connecting to several databases may do so over a single websocket,
+    // see `handle_multiplexed_websocket`.
+
+    let database = worker_ctx_find_database(&ctx, &db_identity)
+        .await?
         .ok_or(StatusCode::NOT_FOUND)?;
 
+    if stats_interval_secs.is_some() && database.owner_identity != auth.identity {
+        Err((
+            StatusCode::FORBIDDEN,
+            "Only the owner of a database can receive its stats.",
+        ))?;
+    }
+    if reducer_timings && database.owner_identity != auth.identity {
+        Err((
+            StatusCode::FORBIDDEN,
+            "Only the owner of a database can receive reducer timings.",
+        ))?;
+    }
+    let stats_interval = stats_interval_secs.map(|secs| Duration::from_secs(secs).max(MIN_STATS_INTERVAL));
+
+    let leader = find_leader(&ctx, &database).await?;
+
     let identity_token = auth.creds.token().into();
 
+    let reconnect_token_ttl = timeouts_config.reconnect_token_ttl;
+    let reconnect_grant = (!reconnect_token_ttl.is_zero()).then(|| ReconnectGrant {
+        identity: auth.identity,
+        subject: auth.subject.clone(),
+        issuer: auth.issuer.clone(),
+        token: auth.creds.token().to_owned(),
+        expires_at: auth.expires_at,
+        database_identity: db_identity,
+    });
+    let resume = websocket_options
+        .session_resume_grace
+        .filter(|_| resumable)
+        .map(|grace| SessionResume {
+            sessions: &RESUMABLE_SESSIONS,
+            token: new_resume_token(),
+            grace,
+            buffer_len: websocket_options.session_resume_buffer_len,
+        });
+    let resume_token = resume
+        .as_ref()
+        .map(|resume| TypedHeader(SpacetimeResumeToken(resume.token.clone())));
+
     let module_rx = leader.module_watcher().await.map_err(log_and_500)?;
+    let schema_headers = module_schema_headers(&module_rx.borrow().info().schema);
+    // Tell the client how its errors will be formatted, before it's sent any.
+    let module = module_rx.borrow().clone();
+    let error_format = module.error_format().await;
+    let client_metrics_allowlisted = module.is_client_metrics_allowlisted(auth.identity).await;
+    let rate_limit = module
+        .client_message_rate_limit(ctx.actor_index().websocket_options().message_rate_limit())
+        .await;
+    let send_backlog = ctx
+        .find_send_backlog_policy(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    let auth_expiry = ctx
+        .find_auth_expiry_policy(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+
+    // There's nothing left to refuse the client for, so it may replace the connection it's taking over.
+    let connection_id_reservation = match connection_id_reservation {
+        Some(reservation) => reservation,
+        None => {
+            replace_client_connection(&ctx, db_identity, auth.identity, connection_id).await?;
+            reserve_client_connection_id(ctx.actor_index(), db_identity, auth.identity, connection_id)?
+        }
+    };
+    // Spent only once the client is sure to be let in, and it's known to be for this database.
+    // Having authenticated the request, it could only fail to redeem if spent concurrently since.
+    if let Some(Extension(reconnect)) = &reconnect {
+        ctx.actor_index()
+            .reconnect_tokens()
+            .redeem(&reconnect.token, &db_identity)
+            .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Authorization failed: {e}")))?;
+    }
+    let reconnect_token = reconnect_grant.clone().map(|grant| {
+        let token = ctx.actor_index().reconnect_tokens().issue(grant, reconnect_token_ttl);
+        TypedHeader(SpacetimeReconnectToken(token))
+    });
+
+    let error_format_header = [(
+        http::HeaderName::from_static(ws_api::ERROR_FORMAT_HEADER),
+        HeaderValue::from_static(error_format.as_str()),
+    )];
 
     let client_id = ClientActorId {
         identity: auth.identity,
         connection_id,
-        name: ctx.client_actor_index().next_client_name(),
+        name: ctx.actor_index().next_client_name(),
     };
 
-    let ws_config = WebSocketConfig::default()
-        .max_message_size(Some(0x2000000))
-        .max_frame_size(None)
-        .accept_unmasked_frames(false);
+    let ws_config = websocket_config(&websocket_options, protocol);
+    let capabilities = server_capabilities(&client_config, &websocket_options, &timeouts_config, timeouts, features);
 
-    tokio::spawn(async move {
-        let ws = match ws_upgrade.upgrade(ws_config).await {
+    // The connection is served on the node's client runtime, as is its actor, see `ConnectionPolicy::runtime`.
+    let runtime = ctx.actor_index().client_runtime().clone();
+    runtime.spawn(async move {
+        let mut ws = match ws_upgrade.upgrade(ws_config).await {
             Ok(ws) => ws,
             Err(err) => {
                 log::error!("WebSocket init error: {}", err);
@@ -142,23 +594,101 @@ where
             }
         };
 
-        match forwarded_for {
-            Some(TypedHeader(XForwardedFor(ip))) => {
-                log::debug!("New client connected from ip {}", ip)
-            }
-            None => log::debug!("New client connected from unknown ip"),
-        }
+        log::debug!("New client connected from {}", client_addr.0);
 
-        let actor = |client, sendrx| ws_client_actor(client, ws, sendrx);
-        let client = match ClientConnection::spawn(client_id, client_config, leader.replica_id, module_rx, actor).await
+        // The websocket is handed to the actor once the client has connected to the module,
+        // so that it can still be closed should the module refuse the client.
+        let (ws_tx, ws_rx) = tokio::sync::oneshot::channel();
+        let actor = |client: ClientConnection, sendrx| {
+            // The registration also disconnects the client from its module when dropped,
+            // so that it's cleaned up even if the actor is aborted before it first runs.
+            let registration = ctx.actor_index().register_client(&client, client_addr.ip());
+            drop(connection_id_reservation);
+            // Now counted as registered, the client gives up the slot it was admitted with.
+            drop(admission);
+            client.set_auth_expiry(auth.expires_at);
+            let options = websocket_options.clone();
+            let validator = Arc::new(ClientTokenValidator(ctx.clone()));
+            let connection_ids: ConnectionIds = {
+                let ctx = ctx.clone();
+                Arc::new(move || ctx.actor_index().generate_connection_id(generate_random_connection_id))
+            };
+            let lifetime = timeouts_config.max_connection_lifetime.map(|max_lifetime| {
+                let reconnect = reconnect_grant.map(|grant| {
+                    let tokens = ctx.actor_index().reconnect_tokens().clone();
+                    (tokens, grant, timeouts_config.reconnect_token_ttl)
+                });
+                ConnectionLifetime::new(max_lifetime, timeouts_config.reconnect_notice, reconnect)
+            });
+            async move {
+                // Held until the actor exits, or the client is cleaned up without it ever starting.
+                let _address_permit = address_permit;
+                let Ok(ws) = ws_rx.await else {
+                    // Upgrading the connection went no further, so dropping the registration disconnects the client.
+                    WORKER_METRICS
+                        .ws_clients_cleaned_up_before_start
+                        .with_label_values(&db_identity)
+                        .inc();
+                    return;
+                };
+                ws_client_actor(
+                    registration,
+                    options,
+                    error_format,
+                    rate_limit,
+                    send_backlog,
+                    auth_expiry,
+                    timeouts_config,
+                    timeouts,
+                    validator,
+                    connection_ids,
+                    lifetime,
+                    (hooks, conn),
+                    deprecations,
+                    resume,
+                    flow_control_credits,
+                    client,
+                    ws,
+                    sendrx,
+                )
+                .await
+            }
+        };
+        let client_series = ctx
+            .actor_index()
+            .client_series()
+            .admit(db_identity, client_id.identity, client_metrics_allowlisted)
+            .map(Arc::new);
+        let policy = ConnectionPolicy {
+            one_off_query_limits: ctx.actor_index().websocket_options().one_off_query_limits(),
+            load_admission: ctx.actor_index().load_admission().clone(),
+            error_format,
+            connect_retry: ctx.actor_index().websocket_options().connect_retry(),
+            client_series,
+            runtime: ctx.actor_index().client_runtime().clone(),
+            send_backlog,
+            addr: client_addr.0,
+            config_defaults,
+        };
+        let client = match ClientConnection::spawn(
+            client_id,
+            client_config,
+            leader.replica_id,
+            module_rx,
+            policy,
+            encryption,
+            actor,
+        )
+        .await
         {
-            Ok(s) => s,
-            Err(e @ (ClientConnectedError::Rejected(_) | ClientConnectedError::OutOfEnergy)) => {
-                log::info!("{e}");
-                return;
+            Ok(s) => {
+                // Should the actor have been cancelled already, the websocket is dropped along with it.
+                let _ = ws_tx.send(ws);
+                s
             }
-            Err(e @ (ClientConnectedError::DBError(_) | ClientConnectedError::ReducerCall(_))) => {
-                log::warn!("ModuleHost died while we were connecting: {e:#}");
+            Err(e) => {
+                let deadline = tokio::time::Instant::now() + LAST_CLOSE_TIMEOUT;
+                close_before_dropping(&mut ws, connect_failed_close_frame(&e, error_format), deadline).await;
                 return;
             }
         };
@@ -173,411 +703,4530 @@ where
             token: identity_token,
             connection_id,
         };
-        if let Err(e) = client.send_message(message) {
-            log::warn!("{e}, before identity token was sent")
+        if send_identity_token(&client, db_identity, message).is_err() {
+            // The connection is being closed, so there's nothing more to send it.
+            return;
+        }
+        // Followed by what the server supports, which clients that don't know the message ignore.
+        if let Err(e) = client.send_message(capabilities) {
+            log_send_error(e, "server capabilities")
+        }
+        for notice in deprecation_notices {
+            if let Err(e) = client.send_message(notice) {
+                log_send_error(e, "deprecation notice")
+            }
+        }
+        redeliver(&client);
+
+        if let Some(interval) = stats_interval {
+            ctx.actor_index()
+                .client_runtime()
+                .spawn(push_database_stats(client, interval));
         }
     });
 
-    Ok(res)
+    Ok((
+        reconnect_token,
+        resume_token,
+        session_resumed,
+        e2e_headers,
+        schema_headers,
+        error_format_header,
+        features_header,
+        res,
+    )
+        .into_response())
+}
+
+/// The close frame of a client which failed to connect to its module with `e`, logging the failure.
+fn connect_failed_close_frame(e: &ClientConnectedError, error_format: ErrorFormat) -> CloseFrame {
+    match e {
+        ClientConnectedError::Rejected(_) => {
+            log::info!("{e}");
+            close_frame(
+                error_format,
+                CloseCode::Policy,
+                &ReasonClose::new("connection rejected"),
+            )
+        }
+        ClientConnectedError::OutOfEnergy => {
+            log::info!("{e}");
+            disconnect_close_frame(DisconnectReason::OutOfEnergy, error_format)
+        }
+        ClientConnectedError::DBError(_) | ClientConnectedError::ReducerCall(_) => {
+            log::warn!("ModuleHost died while we were connecting: {e:#}");
+            close_frame(error_format, CloseCode::Again, &ConnectFailedClose::default())
+        }
+    }
 }
 
-const LIVELINESS_TIMEOUT: Duration = Duration::from_secs(60);
-const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+/// The configuration of the websockets of clients of `protocol`.
+fn websocket_config(options: &WebSocketOptions, protocol: Protocol) -> WebSocketConfig {
+    let size_limit = MessageSizeLimit::new(options, protocol);
+    WebSocketConfig::default()
+        .max_message_size(Some(size_limit.read_limit()))
+        .max_frame_size(options.max_frame_size)
+        .accept_unmasked_frames(false)
+}
 
-async fn ws_client_actor(client: ClientConnection, ws: WebSocketStream, sendrx: MeteredReceiver<SerializableMessage>) {
-    // ensure that even if this task gets cancelled, we always cleanup the connection
-    let mut client = scopeguard::guard(client, |client| {
-        tokio::spawn(client.disconnect());
-    });
+fn session_resumed_header(resumed: bool) -> [(http::HeaderName, HeaderValue); 1] {
+    [(
+        http::HeaderName::from_static(ws_api::SESSION_RESUMED_HEADER),
+        HeaderValue::from_static(if resumed { "true" } else { "false" }),
+    )]
+}
 
-    ws_client_actor_inner(&mut client, ws, sendrx).await;
+/// Merge the `compression` and `light` a client asked for, if anything, with the `defaults` of its database,
+/// returning the settings in effect for the client.
+///
+/// What the client asked for wins, unless the database's owner forced a default.
+/// A default compression which the client's protocol `version` doesn't support is ignored.
+/// Should neither the client nor the database choose, clients are light if the database's `features`
+/// include [`DatabaseFeature::DeltaUpdates`].
+fn merge_config_defaults(
+    version: ProtocolVersion,
+    compression: Option<Compression>,
+    light: Option<bool>,
+    defaults: &ClientConfigDefaults,
+    features: DatabaseFeatures,
+) -> (Compression, bool) {
+    let compression_default = defaults.compression.filter(|default| version.supports(default.value));
+    let compression = ClientDefault::resolve(compression_default, compression, Compression::default());
+    let light = ClientDefault::resolve(defaults.light, light, features.contains(DatabaseFeature::DeltaUpdates));
+    (compression, light)
+}
 
-    ScopeGuard::into_inner(client).disconnect().await;
+/// The knobs of [`ClientConfig`] which trim the transaction updates sent to a client,
+/// which `light` is a preset of, see [`SubscribeQueryParams::light`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct UpdateKnobs<T = bool> {
+    tx_update_full: T,
+    include_caller_info: T,
+    include_timings: T,
 }
 
-async fn make_progress<Fut: Future>(fut: &mut Pin<&mut MaybeDone<Fut>>) {
-    if let MaybeDone::Gone = **fut {
-        // nothing to do
-    } else {
-        fut.await
+impl UpdateKnobs {
+    /// The knobs set by the `light` preset, all of which a light client goes without.
+    fn preset(light: bool) -> Self {
+        Self {
+            tx_update_full: !light,
+            include_caller_info: !light,
+            include_timings: !light,
+        }
     }
 }
 
-async fn ws_client_actor_inner(
-    client: &mut ClientConnection,
-    mut ws: WebSocketStream,
-    mut sendrx: MeteredReceiver<SerializableMessage>,
+impl UpdateKnobs<Option<bool>> {
+    /// Resolve the knobs a client asked for, if any, on top of the `light` preset in effect for it,
+    /// as merged with the database's `light_default` by [`merge_config_defaults`].
+    ///
+    /// The knobs asked for win over the preset, unless the database's owner forced its default.
+    fn resolve(self, light: bool, light_default: Option<ClientDefault<bool>>) -> UpdateKnobs {
+        let preset = UpdateKnobs::preset(light);
+        if light_default.is_some_and(|default| default.forced) {
+            return preset;
+        }
+        UpdateKnobs {
+            tx_update_full: self.tx_update_full.unwrap_or(preset.tx_update_full),
+            include_caller_info: self.include_caller_info.unwrap_or(preset.include_caller_info),
+            include_timings: self.include_timings.unwrap_or(preset.include_timings),
+        }
+    }
+}
+
+/// Send [`DatabaseStats`](ws_api::DatabaseStats) to `client` every `period`, until it disconnects.
+async fn push_database_stats(client: ClientConnection, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let stats = database_stats(&client.module).await;
+        if client.send_message(stats).is_err() {
+            break;
+        }
+    }
+}
+
+/// Hand the websocket of a client resuming its session over to the actor which kept the session,
+/// once the websocket is upgraded.
+async fn resume_session(
+    resumption: Resumption<WebSocketStream>,
+    ws_upgrade: PendingWebSocket,
+    ws_config: WebSocketConfig,
 ) {
-    let mut liveness_check_interval = tokio::time::interval(LIVELINESS_TIMEOUT);
-    let mut got_pong = true;
+    let ws = match ws_upgrade.upgrade(ws_config).await {
+        Ok(ws) => ws,
+        Err(err) => {
+            log::error!("WebSocket init error: {}", err);
+            return;
+        }
+    };
+    let id = resumption.id;
+    log::debug!("client {id} is resuming its session");
+    if resumption.hand_over(ws).is_err() {
+        // The session's actor was cancelled, e.g. as too much was queued for the client,
+        // so the websocket is dropped, and the client has to reconnect anew.
+        log::info!("the session of client {id} ended before it could be resumed");
+    }
+}
 
-    let addr = client.module.info().database_identity;
+/// Check that the `connection_id` a client asked to connect with is valid.
+fn check_client_connection_id(connection_id: ConnectionId) -> axum::response::Result<ConnectionId> {
+    if connection_id == ConnectionId::ZERO {
+        Err((
+            StatusCode::BAD_REQUEST,
+            "Invalid connection ID: the all-zeros ConnectionId is reserved.",
+        ))?;
+    }
+    Ok(connection_id)
+}
 
-    // Build a queue of incoming messages to handle, to be processed one at a time,
-    // in the order they're received.
-    //
-    // N.B. if you're refactoring this code: you must ensure the handle_queue is dropped before
-    // client.disconnect() is called. Otherwise, we can be left with a stale future that's never
-    // awaited, which can lead to bugs like:
-    // https://rust-lang.github.io/wg-async/vision/submitted_stories/status_quo/aws_engineer/solving_a_deadlock.html
-    //
-    // NOTE: never let this go unpolled while you're awaiting something; otherwise, it's possible
-    //       to deadlock or delay for a long time. see usage of `also_poll()` in the branches of the
-    //       `select!` for examples of how to do this.
-    //
-    // TODO: do we want this to have a fixed capacity? or should it be unbounded
-    let mut message_queue = MeteredDeque::<(DataMessage, Instant)>::new(
-        WORKER_METRICS.total_incoming_queue_length.with_label_values(&addr),
-    );
-    let mut current_message = pin!(MaybeDone::Gone);
+/// Reserve the `connection_id` a client of `identity` is to connect to `database_identity` with,
+/// unless it's that of another client of `identity` connected to `database_identity`.
+fn reserve_client_connection_id(
+    index: &ClientActorIndex,
+    database_identity: Identity,
+    identity: Identity,
+    connection_id: ConnectionId,
+) -> axum::response::Result<ConnectionIdReservation> {
+    index
+        .reserve_connection_id(database_identity, identity, connection_id)
+        .ok_or_else(|| {
+            (
+                StatusCode::CONFLICT,
+                "Invalid connection ID: the ConnectionId is in use.",
+            )
+                .into()
+        })
+}
 
-    let mut closed = false;
-    let mut rx_buf = Vec::new();
+/// Close the connections of `identity` to `database_identity` which use `connection_id`,
+/// so that a new one can take it over, under [`ConnectionIdReuse::Replace`].
+///
+/// Refuses the new connection with `409 Conflict` if the old connections don't go away in time.
+async fn replace_client_connection<S: ClientActors>(
+    ctx: &S,
+    database_identity: Identity,
+    identity: Identity,
+    connection_id: ConnectionId,
+) -> axum::response::Result<()> {
+    // The old connections get a teardown to send their close frame,
+    // and as long again to disconnect from their module.
+    let timeout = ctx.timeouts().teardown_timeout * 2;
+    match ctx
+        .actor_index()
+        .replace_connection(database_identity, identity, connection_id, timeout)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::warn!("refusing to replace the connection {connection_id}: {e}");
+            Err((
+                StatusCode::CONFLICT,
+                "Invalid connection ID: the ConnectionId is in use.",
+            )
+                .into())
+        }
+    }
+}
 
-    let mut msg_buffer = SerializeBuffer::new(client.config);
-    loop {
-        rx_buf.clear();
-        enum Item {
-            Message(ClientMessage),
-            HandleResult(Result<(), MessageHandleError>),
+/// Admit a new client through `ctx`, or refuse it with `503 Service Unavailable`.
+///
+/// The client holds a slot until the returned permit is dropped, which should be once it's registered.
+fn admit(ctx: &impl ClientActors) -> axum::response::Result<AdmissionPermit> {
+    if let Some(Draining { retry_after }) = ctx.actor_index().draining() {
+        log::info!("rejecting new client, node is draining");
+        let retry_after = retry_after.map(|after| [(header::RETRY_AFTER, after.as_secs().to_string())]);
+        Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            retry_after,
+            "The node is draining, try again later.",
+        ))?;
+    }
+    let permit = ctx.admit_client().map_err(|NodeOverloaded { limit, retry_after }| {
+        log::warn!("rejecting new client, node is at its connection limit ({limit} clients)");
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after.as_secs().to_string())],
+            "The node is at its connection limit, try again later.",
+        )
+    })?;
+    if let Err(NodeUnderLoad { retry_after }) = ctx.actor_index().load_admission().admit_upgrade() {
+        log::warn!("rejecting new client, node is under load");
+        Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after.as_secs().to_string())],
+            "The node is under load, try again later.",
+        ))?;
+    }
+    Ok(permit)
+}
+
+/// Admit a new client from `client_addr`, or refuse it with `429 Too Many Requests`
+/// should its address be at its connection limit.
+///
+/// Should the websocket upgrade or connecting the client fail,
+/// dropping the permit along with the rest of the connection releases it.
+fn admit_address(index: &ClientActorIndex, client_addr: ClientAddr) -> axum::response::Result<AddressPermit> {
+    index.admit_address(client_addr.ip()).map_err(|e| {
+        log::warn!("rejecting new client, {e}");
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, e.retry_after.as_secs().to_string())],
+            "Too many connections from this address, try again later.",
+        )
+            .into()
+    })
+}
+
+/// The error sent to `client` when one of its messages is refused as the node is draining.
+fn draining_rejection(client: &ClientConnection) -> MessageExecutionError {
+    MessageExecutionError {
+        reducer: None,
+        reducer_id: None,
+        caller_identity: client.id.identity,
+        caller_connection_id: Some(client.id.connection_id),
+        request_id: None,
+        err: anyhow::anyhow!("the node is draining, reconnect to send this message"),
+    }
+}
+
+/// The reply to a client whose message couldn't be handled, short of closing its connection.
+enum ErrorReply {
+    /// A [`ws_api::RequestError`], for a client which asked for request errors.
+    Request(RequestErrorMessage),
+    /// A failed `TransactionUpdate`, as if the message were a reducer call which failed.
+    Failed(MessageExecutionError),
+}
+
+impl ToProtocol for ErrorReply {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match self {
+            Self::Request(error) => error.to_protocol(protocol),
+            Self::Failed(err) => err.to_protocol(protocol),
         }
-        if let MaybeDone::Gone = *current_message {
-            if let Some((message, timer)) = message_queue.pop_front() {
-                let client = client.clone();
-                let fut = async move { client.handle_message(message, timer).await };
-                current_message.set(MaybeDone::Future(fut));
-            }
+    }
+}
+
+/// Validates the tokens of clients re-authenticating over their websocket,
+/// see [`ws_api::Authenticate`].
+struct ClientTokenValidator<S>(S);
+
+/// Draws the connection id of a client which re-authenticates,
+/// through [`ClientActorIndex::generate_connection_id`] so that it doesn't collide with another.
+type ConnectionIds = Arc<dyn Fn() -> ConnectionId + Send + Sync>;
+
+#[async_trait]
+impl<S: ClientActors> TokenValidator for ClientTokenValidator<S> {
+    async fn validate_token(&self, token: &str) -> Result<SpacetimeIdentityClaims, TokenValidationError> {
+        self.0.validate_client_token(token).await
+    }
+}
+
+/// Bounds how many connections may concurrently close their websocket
+/// in response to their module exiting.
+///
+/// Every connection to a module observes its exit at the same time,
+/// so without a bound, a restart of a busy module results in a burst
+/// of close handshakes proportional to the number of connections.
+static MODULE_EXIT_CLOSE_PERMITS: Semaphore = Semaphore::const_new(MAX_CONCURRENT_MODULE_EXIT_CLOSES);
+const MAX_CONCURRENT_MODULE_EXIT_CLOSES: usize = 256;
+
+#[allow(clippy::too_many_arguments)]
+async fn ws_client_actor<T: AsyncRead + AsyncWrite + Unpin>(
+    mut registration: ClientRegistration,
+    options: WebSocketOptions,
+    error_format: ErrorFormat,
+    rate_limit: Option<MessageRateLimit>,
+    send_backlog: SendBacklogPolicy,
+    auth_expiry: AuthExpiryPolicy,
+    timeouts_config: Arc<TimeoutsConfig>,
+    timeouts: ClientTimeouts,
+    validator: Arc<dyn TokenValidator + Send + Sync>,
+    connection_ids: ConnectionIds,
+    mut lifetime: Option<ConnectionLifetime>,
+    (hooks, conn): (Arc<dyn ConnectionLifecycleHooks>, ConnectionContext),
+    mut deprecations: DeprecationNotices,
+    resume: Option<SessionResume<tokio_tungstenite::WebSocketStream<T>>>,
+    flow_control_credits: Option<u64>,
+    mut client: ClientConnection,
+    mut ws: tokio_tungstenite::WebSocketStream<T>,
+    mut sendrx: MeteredReceiver<SerializableMessage>,
+) {
+    // If this task gets cancelled, dropping this runs the `on_close` hook.
+    let mut close_hook = hooks::on_ready(hooks, conn).await;
+
+    let mut teardown = Teardown::new(timeouts_config.teardown_timeout, timeouts.send);
+    // Kept across resumed sessions, along with the messages held back for the client.
+    let mut flow_control = flow_control_credits.map(FlowControl::new);
+    // `registration` keeps the client registered with the `ClientActorIndex` for as long as this task lives,
+    // following it should it re-authenticate,
+    // and disconnects it from the module should this task be cancelled.
+    let cause = loop {
+        let cause = ws_client_actor_inner(
+            &mut client,
+            &mut registration,
+            &options,
+            error_format,
+            rate_limit,
+            send_backlog,
+            auth_expiry,
+            &timeouts_config,
+            timeouts.liveness,
+            &validator,
+            &connection_ids,
+            &mut lifetime,
+            &mut teardown,
+            &mut deprecations,
+            &mut flow_control,
+            ws,
+            &mut sendrx,
+        )
+        .await;
+        // A client which lost its connection may resume its session over a new one,
+        // which the session is then served over, with what was queued for the client meanwhile.
+        let Some(resume) = resume.as_ref().filter(|_| cause == CloseCause::Lost) else {
+            break cause;
+        };
+        match park_session(&mut client, &sendrx, resume).await {
+            Some(resumed) => ws = resumed,
+            None => break cause,
         }
-        let message = tokio::select! {
-            // NOTE: all of the futures for these branches **must** be cancel safe. do not
-            //       change this if you don't know what that means.
+    };
+    sendrx.close();
 
-            // If we have a result from handling a past message to report,
-            // grab it to handle in the next `match`.
-            Some(res) = async {
-                make_progress(&mut current_message).await;
-                current_message.as_mut().take_output()
-            } => {
-                Item::HandleResult(res)
-            }
+    let addr = client.module.info().database_identity;
+    let deadline = teardown.begin();
+    // Disconnect on a separate task, so that abandoning it doesn't cancel it halfway through.
+    let disconnect = tokio::spawn(client.disconnect());
+    if tokio::time::timeout_at(deadline, disconnect).await.is_err() {
+        log::warn!("client disconnect did not complete before the teardown deadline, abandoning it");
+    }
+    WORKER_METRICS
+        .ws_teardown_seconds
+        .with_label_values(&addr)
+        .observe(teardown.elapsed().as_secs_f64());
 
-            // If we've received an incoming message,
-            // grab it to handle in the next `match`.
-            message = ws.next() => match message {
-                Some(Ok(m)) => Item::Message(ClientMessage::from_message(m)),
-                Some(Err(error)) => {
-                    log::warn!("Websocket receive error: {}", error);
-                    break;
-                }
-                // the client sent us a close frame
-                None => {
+    close_hook.deprecated_features(deprecations.used());
+    close_hook.close(cause).await;
+}
+
+/// Keep the session of `client`, which lost its connection, for it to resume, see [`ResumableSessions`],
+/// returning the websocket it resumed the session over,
+/// or `None` should it not do so in time, too many messages be queued for it, or the session have to end.
+async fn park_session<S>(
+    client: &mut ClientConnection,
+    sendrx: &MeteredReceiver<SerializableMessage>,
+    resume: &SessionResume<S>,
+) -> Option<S> {
+    let database_identity = client.module.info().database_identity;
+    let sender = client.sender();
+    let mut parked = resume
+        .sessions
+        .park(resume.token.clone(), sender.clone(), database_identity);
+    log::debug!(
+        "client {} lost its connection, keeping its session for {:?}",
+        client.id,
+        resume.grace
+    );
+    // Should the session neither be resumed nor given up on, it was purged,
+    // or the client resuming it failed to connect.
+    let mut outcome = "withdrawn";
+    let buffer_bytes = WORKER_METRICS
+        .replay_buffer_bytes
+        .with_label_values(&database_identity, "parked_sessions");
+    let mut counted_bytes = 0;
+    let give_up = async {
+        let mut buffer_check = tokio::time::interval(RESUME_BUFFER_CHECK_INTERVAL);
+        let overflowed = async {
+            loop {
+                let bytes = sendrx.queued_bytes() as i64;
+                buffer_bytes.add(bytes - counted_bytes);
+                counted_bytes = bytes;
+                if sendrx.len() > resume.buffer_len {
                     break;
                 }
-            },
+                buffer_check.tick().await;
+            }
+        };
+        let module_exited = async { while client.watch_module_host().await.is_ok() {} };
+        tokio::select! {
+            () = tokio::time::sleep(resume.grace) => outcome = "expired",
+            () = overflowed => outcome = "overflowed",
+            _ = sender.close_requested() => outcome = "closed",
+            () = module_exited => outcome = "closed",
+        }
+    };
+    let resumed = parked.wait(give_up).await;
+    buffer_bytes.sub(counted_bytes);
+    let outcome = if resumed.is_some() { "resumed" } else { outcome };
+    WORKER_METRICS
+        .ws_session_resumes
+        .with_label_values(&database_identity, outcome)
+        .inc();
+    resumed
+}
 
-            // If we have an outgoing message to send, send it off.
-            // No incoming `message` to handle, so `continue`.
-            Some(n) = sendrx.recv_many(&mut rx_buf, 32).map(|n| (n != 0).then_some(n)) => {
-                if closed {
-                    // TODO: this isn't great. when we receive a close request from the peer,
-                    //       tungstenite doesn't let us send any new messages on the socket,
-                    //       even though the websocket RFC allows it. should we fork tungstenite?
-                    log::info!("dropping {n} messages due to ws already being closed");
-                    log::debug!("dropped messages: {:?}", &rx_buf[..n]);
-                } else {
-                    let send_all = async {
-                        for msg in rx_buf.drain(..n) {
-                            let workload = msg.workload();
-                            let num_rows = msg.num_rows();
+/// The single deadline by which a connection must be torn down.
+///
+/// Every step of closing a connection, i.e. sending the close frame,
+/// waiting for the client to acknowledge it, and running `disconnect()`,
+/// is bounded by the same deadline, so that an unresponsive client
+/// can't hold on to its actor for a fresh timeout per step.
+struct Teardown {
+    timeout: Duration,
+    /// How long a send may take, see [`ClientTimeouts::send`].
+    send_timeout: Duration,
+    started: Option<Instant>,
+}
 
-                            // Serialize the message, report metrics,
-                            // and keep a handle to the buffer.
-                            let (msg_alloc, msg_data) = serialize(msg_buffer, msg, client.config);
-                            report_ws_sent_metrics(&addr, workload, num_rows, &msg_data);
+impl Teardown {
+    fn new(timeout: Duration, send_timeout: Duration) -> Self {
+        Self {
+            timeout,
+            send_timeout,
+            started: None,
+        }
+    }
 
-                            // Buffer the message without necessarily sending it.
-                            let res = ws.feed(datamsg_to_wsmsg(msg_data)).await;
+    /// Begin tearing down the connection, if not already begun, and return the deadline.
+    fn begin(&mut self) -> tokio::time::Instant {
+        self.begin_at(Instant::now())
+    }
 
-                            // At this point,
-                            // the underlying allocation of `msg_data` should have a single referent
-                            // and this should be `msg_alloc`.
-                            // We can put this back into our pool.
-                            msg_buffer = msg_alloc.try_reclaim()
-                                .expect("should have a unique referent to `msg_alloc`");
+    /// Like [`Self::begin`], but treat the teardown as having begun at `start`.
+    ///
+    /// Used when an operation that was already in flight, e.g. a send, timed out,
+    /// so that time spent waiting on it counts towards the deadline.
+    fn begin_at(&mut self, start: Instant) -> tokio::time::Instant {
+        let started = *self.started.get_or_insert(start);
+        (started + self.timeout).into()
+    }
 
-                            if res.is_err() {
-                                return (res, msg_buffer);
-                            }
-                        }
-                        // now we flush all the messages to the socket
-                        (ws.flush().await, msg_buffer)
-                    };
-                    // Build a future that both times out and drives the send.
-                    //
-                    // Note that if flushing cannot immediately complete for whatever reason,
-                    // it will wait without polling the other futures in the `select!` arms.
-                    // Among other things, this means our liveness tick will not be polled.
-                    //
-                    // To avoid waiting indefinitely, we wrap the send in a timeout.
-                    // A timeout is treated as an unresponsive client and we drop the connection.
-                    let send_all = tokio::time::timeout(SEND_TIMEOUT, send_all);
-                    // Flush the websocket while continuing to poll the `handle_queue`,
-                    // to avoid deadlocks or delays due to enqueued futures holding resources.
-                    let send_all = also_poll(send_all, make_progress(&mut current_message));
-                    let t1 = Instant::now();
-                    let (send_all_result, buf) = match send_all.await {
-                        Ok((send_all_result, buf)) => {
-                            (send_all_result, buf)
-                        }
-                        Err(e) => {
-                            // Our send timed out; drop client without trying to send them a Close
-                            log::warn!("send_all timed out: {e}");
-                            break;
-                        }
-                    };
-                    msg_buffer = buf;
-                    if let Err(error) = send_all_result {
-                        log::warn!("Websocket send error: {error}")
-                    }
-                    let time = t1.elapsed();
-                    if time > Duration::from_millis(50) {
-                        tracing::warn!(?time, "send_all took a very long time");
-                    }
-                }
-                continue;
-            }
+    /// Begin tearing down the connection, if not already begun,
+    /// and return the deadline for the last close frame sent before dropping it,
+    /// which never extends past the teardown deadline.
+    fn last_close_deadline(&mut self) -> tokio::time::Instant {
+        let teardown = self.begin();
+        teardown.min(tokio::time::Instant::now() + LAST_CLOSE_TIMEOUT)
+    }
 
-            res = client.watch_module_host(), if !closed => {
-                match res {
-                    Ok(()) => {}
-                    // If the module has exited, close the websocket.
-                    Err(NoSuchModule) => {
-                        // Send a close frame while continuing to poll the `handle_queue`,
-                        // to avoid deadlocks or delays due to enqueued futures holding resources.
-                        let close = ws.close(Some(CloseFrame { code: CloseCode::Away, reason: "module exited".into() }));
-                        // Wrap the close in a timeout
-                        let close = tokio::time::timeout(SEND_TIMEOUT, close);
-                        match also_poll(close, make_progress(&mut current_message)).await {
-                            Ok(Err(e)) => {
-                                log::warn!("error closing websocket: {e:#}")
-                            }
-                            Err(e) => {
-                                // Our send timed out; drop client without trying to send them a Close.
-                                //
-                                // Is it correct to break if a reducer is still in progress?
-                                // Answer: Yes it is.
-                                //
-                                // If a reducer is currently being executed,
-                                // we are waiting for the `current_message` future to complete.
-                                // When we break, the task completes and this future is dropped.
-                                //
-                                // Notably though the reducer itself will run to completion,
-                                // however when it tries to notify this task that it is done,
-                                // it will encounter a closed sender in `JobThread::run`,
-                                // dropping the value that it's trying to send.
-                                // In particular it will not throw an error or panic.
-                                log::warn!("websocket close timed out: {e}");
-                                break;
-                            }
-                            _ => {}
-                        };
-                        closed = true;
-                    }
-                }
-                continue;
-            }
+    fn deadline(&self) -> Option<tokio::time::Instant> {
+        self.started.map(|started| (started + self.timeout).into())
+    }
 
-            // If it's time to send a ping...
-            _ = liveness_check_interval.tick() => {
-                // If we received a pong at some point, send a fresh ping.
-                if mem::take(&mut got_pong) {
-                    // Build a future that both times out and drives the send.
-                    //
-                    // Note that if the send cannot immediately complete for whatever reason,
-                    // it will wait without polling the other futures in the `select!` arms.
-                    // Among other things, this means we won't poll the websocket for a Close frame.
-                    //
-                    // To avoid waiting indefinitely, we wrap the ping in a timeout.
-                    // A timeout is treated as an unresponsive client and we drop the connection.
-                    let ping = ws.send(WsMessage::Ping(Bytes::new()));
-                    let ping_with_timeout = tokio::time::timeout(SEND_TIMEOUT, ping);
+    /// The deadline for a send started now,
+    /// which never extends past the teardown deadline.
+    fn send_deadline(&self) -> tokio::time::Instant {
+        let deadline = tokio::time::Instant::now() + self.send_timeout;
+        self.deadline().map_or(deadline, |teardown| deadline.min(teardown))
+    }
 
-                    // Send a ping message while continuing to poll the `handle_queue`,
-                    // to avoid deadlocks or delays due to enqueued futures holding resources.
-                    match also_poll(ping_with_timeout, make_progress(&mut current_message)).await {
-                        Ok(Err(e)) => {
-                            log::warn!("error sending ping: {e:#}");
-                        }
-                        Err(e) => {
-                            // Our ping timed out; drop them without trying to send them a Close
-                            log::warn!("ping timed out after: {e}");
-                            break;
-                        }
-                        _ => {}
-                    }
-                    continue;
-                } else {
-                    // the client never responded to our ping; drop them without trying to send them a Close
-                    log::warn!("client {} timed out", client.id);
-                    break;
-                }
+    /// Resolves once the teardown deadline has passed,
+    /// or never if the teardown hasn't begun.
+    fn expired(&self) -> impl Future<Output = ()> {
+        let deadline = self.deadline();
+        async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
             }
-        };
+        }
+    }
 
-        // Handle the incoming message we grabbed in the previous `select!`.
+    /// How long the teardown has taken so far.
+    fn elapsed(&self) -> Duration {
+        self.started.map_or(Duration::ZERO, |started| started.elapsed())
+    }
+}
 
-        // TODO: Data flow appears to not require `enum Item` or this distinct `match`,
-        //       since `Item::HandleResult` comes from exactly one `select!` branch,
-        //       and `Item::Message` comes from exactly one distinct `select!` branch.
-        //       Consider merging this `match` with the previous `select!`.
-        match message {
-            Item::Message(ClientMessage::Message(message)) => {
-                let timer = Instant::now();
-                message_queue.push_back((message, timer))
-            }
-            Item::HandleResult(res) => {
-                if let Err(e) = res {
-                    if let MessageHandleError::Execution(err) = e {
-                        log::error!("reducer execution error: {err:#}");
-                        // Serialize the message and keep a handle to the buffer.
-                        let (msg_alloc, msg_data) = serialize(msg_buffer, err, client.config);
+/// Send a close frame on `ws`, giving up at `deadline`.
+async fn close_ws<T: AsyncRead + AsyncWrite + Unpin>(
+    ws: &mut tokio_tungstenite::WebSocketStream<T>,
+    frame: CloseFrame,
+    deadline: tokio::time::Instant,
+) -> Result<Result<(), WsError>, Elapsed> {
+    tokio::time::timeout_at(deadline, ws.close(Some(frame))).await
+}
 
-                        let send = async { ws.send(datamsg_to_wsmsg(msg_data)).await };
-                        let send = tokio::time::timeout(SEND_TIMEOUT, send);
+/// How long the close frame sent as a connection is dropped may take to send,
+/// as the client, having timed out or failed, may never read it.
+const LAST_CLOSE_TIMEOUT: Duration = Duration::from_millis(500);
 
-                        match send.await {
-                            Ok(Err(error)) => {
-                                log::warn!("Websocket send error: {error}")
-                            }
-                            Err(error) => {
-                                log::warn!("send timed out after: {error}");
-                                break;
-                            }
-                            _ => {}
-                        }
+/// Make a last try at telling the client why its connection is being dropped, until `deadline`,
+/// rather than leaving it to guess from the socket ending.
+///
+/// Outside of a [`Teardown`], `deadline` is [`LAST_CLOSE_TIMEOUT`] from now.
+async fn close_before_dropping<T: AsyncRead + AsyncWrite + Unpin>(
+    ws: &mut tokio_tungstenite::WebSocketStream<T>,
+    frame: CloseFrame,
+    deadline: tokio::time::Instant,
+) {
+    match close_ws(ws, frame, deadline).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::debug!("error closing websocket before dropping it: {e:#}"),
+        Err(_) => log::debug!("websocket close timed out before dropping it"),
+    }
+}
 
-                        // At this point,
-                        // the underlying allocation of `msg_data` should have a single referent
-                        // and this should be `msg_alloc`.
-                        // We can put this back into our pool.
-                        msg_buffer = msg_alloc
-                            .try_reclaim()
-                            .expect("should have a unique referent to `msg_alloc`");
+/// The fraction of [`TimeoutsConfig::max_connection_lifetime`]
+/// over which the deadlines of connections are spread.
+const MAX_LIFETIME_JITTER: f64 = 0.1;
 
-                        continue;
-                    }
-                    log::warn!("Client caused error on text message: {}", e);
-                    let close = ws.close(Some(CloseFrame {
-                        code: CloseCode::Error,
-                        reason: format!("{e:#}").into(),
-                    }));
-
-                    // Wrap the close in a timeout
-                    match tokio::time::timeout(SEND_TIMEOUT, close).await {
-                        Ok(Err(e)) => {
-                            log::warn!("error closing websocket: {e:#}")
-                        }
-                        Err(e) => {
-                            log::warn!("send timed out after: {e}");
-                            break;
-                        }
-                        _ => {}
-                    }
-                }
-            }
-            Item::Message(ClientMessage::Ping(_message)) => {
-                log::trace!("Received ping from client {}", client.id);
-                // No need to explicitly respond with a `Pong`, as tungstenite handles this automatically.
-                // See [https://github.com/snapview/tokio-tungstenite/issues/88].
-            }
-            Item::Message(ClientMessage::Pong(_message)) => {
-                log::trace!("Received heartbeat from client {}", client.id);
-                got_pong = true;
-            }
-            Item::Message(ClientMessage::Close(close_frame)) => {
-                // This happens in 2 cases:
-                // a) We sent a Close frame and this is the ack.
-                // b) This is the client telling us they want to close.
-                // in either case, after the remaining messages in the queue flush,
-                // ws.next() will return None and we'll exit the loop.
-                // NOTE: No need to send a close frame, it's is queued
-                //       automatically by tungstenite.
+/// Enforces [`TimeoutsConfig::max_connection_lifetime`] on a connection.
+struct ConnectionLifetime {
+    /// When the client is to be asked to reconnect, or `None` once it has been.
+    notice_at: Option<Instant>,
+    /// When the connection is closed.
+    deadline: Instant,
+    /// What to issue the client a reconnect token for,
+    /// and for how long past the deadline the token remains valid.
+    reconnect: Option<(Arc<ReconnectTokens>, ReconnectGrant, Duration)>,
+}
 
-                // if this is the closed-by-them case, let the ClientConnectionSenders know now.
-                sendrx.close();
-                log::trace!("Close frame {:?}", close_frame);
-                if !closed {
-                    // This is the client telling us they want to close.
-                    WORKER_METRICS
-                        .ws_clients_closed_connection
-                        .with_label_values(&addr)
-                        .inc();
-                }
+/// What a connection is due for, according to its [`ConnectionLifetime`].
+#[derive(Debug, PartialEq, Eq)]
+enum LifetimeCheck {
+    Live,
+    /// Ask the client to reconnect, as the connection will be closed in this long.
+    RequestReconnect(Duration),
+    /// Close the connection.
+    Expired,
+}
 
-                // Can't we just break out of the loop here?
-                // Not, if we want tungstenite to send a close frame back to the client.
-                // That will only happen once `ws.next()` returns `None`.
-                closed = true;
-            }
+impl ConnectionLifetime {
+    fn new(
+        max_lifetime: Duration,
+        notice: Duration,
+        reconnect: Option<(Arc<ReconnectTokens>, ReconnectGrant, Duration)>,
+    ) -> Self {
+        let jitter = max_lifetime.mul_f64(MAX_LIFETIME_JITTER * rand::random::<f64>());
+        Self::starting_at(Instant::now(), max_lifetime - jitter, notice, reconnect)
+    }
+
+    fn starting_at(
+        start: Instant,
+        lifetime: Duration,
+        notice: Duration,
+        reconnect: Option<(Arc<ReconnectTokens>, ReconnectGrant, Duration)>,
+    ) -> Self {
+        Self {
+            notice_at: Some(start + lifetime.saturating_sub(notice)),
+            deadline: start + lifetime,
+            reconnect,
+        }
+    }
+
+    fn check(&mut self, now: Instant) -> LifetimeCheck {
+        if now >= self.deadline {
+            LifetimeCheck::Expired
+        } else if self.notice_at.is_some_and(|notice_at| now >= notice_at) {
+            self.notice_at = None;
+            LifetimeCheck::RequestReconnect(self.deadline - now)
+        } else {
+            LifetimeCheck::Live
+        }
+    }
+
+    /// The [`ReconnectRequested`](ws_api::ReconnectRequested) message for a connection
+    /// which will be closed in `remaining`.
+    fn reconnect_requested(&self, remaining: Duration) -> ReconnectRequestedMessage {
+        let reconnect_token = self
+            .reconnect
+            .as_ref()
+            .map(|(tokens, grant, ttl)| tokens.issue(grant.clone(), remaining + *ttl).into());
+        ReconnectRequestedMessage {
+            deadline: Timestamp::now() + remaining,
+            reconnect_token,
         }
     }
-    log::debug!("Client connection ended");
-    sendrx.close();
 }
 
-enum ClientMessage {
-    Message(DataMessage),
-    Ping(Bytes),
-    Pong(Bytes),
-    Close(Option<CloseFrame>),
+/// Enforces [`WebSocketOptions::max_message_size`] on the messages received from a client,
+/// allowing for [`WebSocketOptions::oversized_message_strikes`] if it uses the text protocol.
+struct MessageSizeLimit {
+    limit: usize,
+    /// How many more oversized messages the client may send.
+    strikes_left: u32,
+    /// Whether oversized messages are read, to be rejected, rather than fail the websocket.
+    has_strikes: bool,
 }
-impl ClientMessage {
-    fn from_message(msg: WsMessage) -> Self {
-        match msg {
-            WsMessage::Text(s) => Self::Message(DataMessage::Text(utf8bytes_to_bytestring(s))),
-            WsMessage::Binary(b) => Self::Message(DataMessage::Binary(b)),
-            WsMessage::Ping(b) => Self::Ping(b),
-            WsMessage::Pong(b) => Self::Pong(b),
-            WsMessage::Close(frame) => Self::Close(frame),
-            // WebSocket::read_message() never returns a raw Message::Frame
-            WsMessage::Frame(_) => unreachable!(),
+
+/// Whether a received message is within its [`MessageSizeLimit`].
+#[derive(Debug, PartialEq, Eq)]
+enum SizeCheck {
+    Within,
+    /// The message is too large, and is to be rejected.
+    Strike,
+    /// The message is too large, and the connection is to be closed.
+    TooLarge,
+}
+
+impl MessageSizeLimit {
+    fn new(options: &WebSocketOptions, protocol: Protocol) -> Self {
+        // A client of the binary protocol can't be expected to recover from an oversized message.
+        let strikes = match protocol {
+            Protocol::Text => options.oversized_message_strikes,
+            Protocol::Binary => 0,
+        };
+        Self {
+            limit: options.max_message_size,
+            strikes_left: strikes,
+            has_strikes: strikes > 0,
+        }
+    }
+
+    /// The largest message the websocket reads,
+    /// beyond which it fails with a capacity error.
+    fn read_limit(&self) -> usize {
+        if self.has_strikes {
+            self.limit.saturating_mul(2)
+        } else {
+            self.limit
         }
     }
+
+    fn check(&mut self, len: usize) -> SizeCheck {
+        if len <= self.limit {
+            SizeCheck::Within
+        } else if self.strikes_left > 0 {
+            self.strikes_left -= 1;
+            SizeCheck::Strike
+        } else {
+            SizeCheck::TooLarge
+        }
+    }
+
+    /// The error sent to `client` when one of its messages is rejected.
+    fn rejection(&self, client: &ClientConnection) -> MessageExecutionError {
+        MessageExecutionError {
+            reducer: None,
+            reducer_id: None,
+            caller_identity: client.id.identity,
+            caller_connection_id: Some(client.id.connection_id),
+            request_id: None,
+            err: anyhow::anyhow!("message too large, limit {} bytes", self.limit),
+        }
+    }
+
+    fn close_frame(&self, format: ErrorFormat) -> CloseFrame {
+        let payload = MessageTooLargeClose {
+            reason: "message too large",
+            limit: self.limit,
+        };
+        let code = disconnect_code(format, DisconnectReason::MessageTooLarge, CloseCode::Size);
+        close_frame(format, code, &payload)
+    }
 }
 
-/// Report metrics on sent rows and message sizes to a websocket client.
-fn report_ws_sent_metrics(
-    addr: &Identity,
-    workload: Option<WorkloadType>,
-    num_rows: Option<usize>,
-    msg_ws: &DataMessage,
-) {
-    // These metrics should be updated together,
-    // or not at all.
-    if let (Some(workload), Some(num_rows)) = (workload, num_rows) {
-        WORKER_METRICS
-            .websocket_sent_num_rows
-            .with_label_values(addr, &workload)
-            .observe(num_rows as f64);
+/// The reason of the close frame sent when a client sends a message larger than it may, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct MessageTooLargeClose {
+    pub reason: &'static str,
+    /// The largest message the client may send, in bytes.
+    pub limit: usize,
+}
+
+/// Counts the protocol violations of a client against [`WebSocketOptions::protocol_violation_budget`].
+struct ViolationBudget {
+    budget: Option<u32>,
+}
+
+/// What becomes of a protocol violation, according to the client's [`ViolationBudget`].
+#[derive(Debug, PartialEq, Eq)]
+enum ViolationCheck {
+    /// There's no budget, so the violation is handled as it always was.
+    Unbudgeted,
+    /// The violation is to be rejected with an error the client can recover from.
+    Tolerated,
+    /// The client has committed this many violations, exceeding its budget,
+    /// so its connection is to be closed.
+    Exceeded(u32),
+}
+
+impl ViolationBudget {
+    fn new(options: &WebSocketOptions) -> Self {
+        Self {
+            budget: options.protocol_violation_budget,
+        }
+    }
+
+    /// Count `violation` against the connection of `client`.
+    fn record(&self, client: &ClientConnection, violation: ProtocolViolation) -> ViolationCheck {
+        let violations = client.record_protocol_violation();
         WORKER_METRICS
-            .websocket_sent_msg_size
-            .with_label_values(addr, &workload)
-            .observe(msg_ws.len() as f64);
+            .ws_protocol_violations
+            .with_label_values(&client.module.info().database_identity, violation.as_ref())
+            .inc();
+        log::info!(
+            "client {} committed protocol violation `{}`, {violations} in total",
+            client.id,
+            violation.as_ref()
+        );
+        self.check(violations)
     }
-}
 
-fn datamsg_to_wsmsg(msg: DataMessage) -> WsMessage {
-    match msg {
-        DataMessage::Text(text) => WsMessage::Text(bytestring_to_utf8bytes(text)),
-        DataMessage::Binary(bin) => WsMessage::Binary(bin),
+    fn check(&self, violations: u32) -> ViolationCheck {
+        match self.budget {
+            None => ViolationCheck::Unbudgeted,
+            Some(budget) if violations > budget => ViolationCheck::Exceeded(violations),
+            Some(_) => ViolationCheck::Tolerated,
+        }
+    }
+
+    /// The error handled in place of `client`'s violation `e` when it's tolerated.
+    ///
+    /// A client which asked for request errors is told of `e` itself, if it's one it can recover from.
+    fn tolerated(client: &ClientConnection, e: MessageHandleError) -> MessageHandleError {
+        if client.config().request_errors && e.request_error().is_some() {
+            return e;
+        }
+        Self::rejection(client, &e).into()
+    }
+
+    /// The error sent to `client` when its violation `e` is tolerated.
+    fn rejection(client: &ClientConnection, e: &MessageHandleError) -> MessageExecutionError {
+        MessageExecutionError {
+            reducer: None,
+            reducer_id: None,
+            caller_identity: client.id.identity,
+            caller_connection_id: Some(client.id.connection_id),
+            request_id: None,
+            err: anyhow::anyhow!("{e:#}"),
+        }
+    }
+
+    fn close_frame(violations: u32, format: ErrorFormat) -> CloseFrame {
+        let payload = TooManyViolationsClose {
+            reason: "too many protocol errors",
+            violations,
+        };
+        close_frame(format, CloseCode::Policy, &payload)
     }
 }
 
-fn utf8bytes_to_bytestring(s: Utf8Bytes) -> ByteString {
-    // SAFETY: `Utf8Bytes` and `ByteString` have the same invariant of UTF-8 validity
-    unsafe { ByteString::from_bytes_unchecked(Bytes::from(s)) }
+/// The reason of the close frame sent when a client exceeds its protocol violation budget, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct TooManyViolationsClose {
+    pub reason: &'static str,
+    /// How many protocol violations the client committed over the connection.
+    pub violations: u32,
 }
-fn bytestring_to_utf8bytes(s: ByteString) -> Utf8Bytes {
-    // SAFETY: `Utf8Bytes` and `ByteString` have the same invariant of UTF-8 validity
-    unsafe { Utf8Bytes::from_bytes_unchecked(s.into_bytes()) }
+
+/// Bounds the queue of messages received from a client which have yet to be handled,
+/// according to [`WebSocketOptions::max_incoming_queue_len`] and [`WebSocketOptions::max_incoming_queue_bytes`].
+struct IncomingQueueLimit {
+    max_len: usize,
+    max_bytes: usize,
+    overflow: IncomingQueueOverflow,
+    /// The bytes of the messages in the queue.
+    bytes: usize,
+}
+
+/// What becomes of a received message, according to the [`IncomingQueueLimit`].
+#[derive(Debug, PartialEq, Eq)]
+enum QueueCheck {
+    Queued,
+    /// The queue is full, and the message is to be rejected.
+    Rejected,
+    /// The queue is full, and the connection is to be closed.
+    Overflowed,
+}
+
+impl IncomingQueueLimit {
+    fn new(options: &WebSocketOptions) -> Self {
+        Self {
+            max_len: options.max_incoming_queue_len,
+            max_bytes: options.max_incoming_queue_bytes,
+            overflow: options.incoming_queue_overflow,
+            bytes: 0,
+        }
+    }
+
+    /// Check whether a message of `len` bytes may join the `queued` messages in the queue,
+    /// counting its bytes if so.
+    fn admit(&mut self, queued: usize, len: usize) -> QueueCheck {
+        let fits = queued < self.max_len && self.bytes.saturating_add(len) <= self.max_bytes;
+        if queued == 0 || fits {
+            self.bytes += len;
+            return QueueCheck::Queued;
+        }
+        match self.overflow {
+            IncomingQueueOverflow::Reject => QueueCheck::Rejected,
+            IncomingQueueOverflow::Close => QueueCheck::Overflowed,
+        }
+    }
+
+    /// Stop counting a message of `len` bytes which has left the queue to be handled.
+    fn release(&mut self, len: usize) {
+        self.bytes = self.bytes.saturating_sub(len);
+    }
+
+    /// The error sent to `client` when one of its messages is rejected, with `queued` messages waiting ahead of it.
+    ///
+    /// Under [`ErrorFormat::Structured`], the error is a [`ServerBusyError`] encoded as JSON.
+    fn rejection(&self, client: &ClientConnection, queued: usize, format: ErrorFormat) -> MessageExecutionError {
+        let message = match format {
+            ErrorFormat::Legacy => format!(
+                "too many messages waiting to be handled, limit {} messages or {} bytes",
+                self.max_len, self.max_bytes
+            ),
+            ErrorFormat::Structured => {
+                let error = ServerBusyError {
+                    reason: "server busy",
+                    queued,
+                    max_len: self.max_len,
+                    max_bytes: self.max_bytes,
+                    retry: RetryHint::Backoff,
+                };
+                serde_json::to_string(&error).unwrap()
+            }
+        };
+        MessageExecutionError {
+            reducer: None,
+            reducer_id: None,
+            caller_identity: client.id.identity,
+            caller_connection_id: Some(client.id.connection_id),
+            request_id: None,
+            err: RequestRejected {
+                code: RequestErrorCode::QueueFull,
+                message,
+            }
+            .into(),
+        }
+    }
+
+    fn close_frame(&self, format: ErrorFormat) -> CloseFrame {
+        let payload = QueueOverflowClose {
+            reason: "too many queued messages",
+            max_len: self.max_len,
+            max_bytes: self.max_bytes,
+        };
+        close_frame(
+            format,
+            CloseCode::Library(DisconnectReason::QueueOverflow.code()),
+            &payload,
+        )
+    }
+}
+
+/// The error a client is sent, encoded as JSON, when one of its messages is rejected
+/// as it has as many messages waiting to be handled as it may.
+#[derive(Serialize, Debug)]
+pub struct ServerBusyError {
+    pub reason: &'static str,
+    /// How many of the client's messages were waiting to be handled, ahead of the rejected one.
+    pub queued: usize,
+    /// How many messages the client may have waiting.
+    pub max_len: usize,
+    /// How many bytes of messages the client may have waiting.
+    pub max_bytes: usize,
+    pub retry: RetryHint,
+}
+
+/// Handles on the `ws_incoming_queue_wait_seconds` and `ws_incoming_handle_seconds` metrics of a database,
+/// for text and binary messages.
+struct IncomingMessageTimes {
+    text: (Histogram, Histogram),
+    binary: (Histogram, Histogram),
+}
+
+impl IncomingMessageTimes {
+    fn new(database_identity: &Identity) -> Self {
+        let histograms = |kind| {
+            (
+                WORKER_METRICS
+                    .ws_incoming_queue_wait_seconds
+                    .with_label_values(database_identity, kind),
+                WORKER_METRICS
+                    .ws_incoming_handle_seconds
+                    .with_label_values(database_identity, kind),
+            )
+        };
+        Self {
+            text: histograms("text"),
+            binary: histograms("binary"),
+        }
+    }
+
+    /// Record how long `message`, queued at `queued_at`, waited to be handled,
+    /// returning the histogram of how long it then takes to handle.
+    fn dequeued(&self, message: &DataMessage, queued_at: Instant) -> Histogram {
+        let (queue_wait, handle) = match message {
+            DataMessage::Text(_) => &self.text,
+            DataMessage::Binary(_) => &self.binary,
+        };
+        queue_wait.observe(queued_at.elapsed().as_secs_f64());
+        handle.clone()
+    }
+}
+
+/// Counts the messages received from a client against its [`MessageRateLimit`],
+/// see [`WebSocketOptions::client_message_rate`].
+struct MessageRate {
+    bucket: TokenBucket,
+    throttle: MessageThrottle,
+}
+
+impl MessageRate {
+    fn new(limit: MessageRateLimit, throttle: MessageThrottle) -> Self {
+        Self {
+            bucket: TokenBucket::new(limit, Instant::now()),
+            throttle,
+        }
+    }
+
+    /// Count a message received from a client of `database_identity`.
+    ///
+    /// Under [`MessageThrottle::Delay`], the message is always let through,
+    /// but `throttled_until` is set to when the client is back within its limit.
+    /// Under [`MessageThrottle::Reject`], the message is refused if beyond the limit,
+    /// with how long until the client may send another.
+    fn check(
+        &mut self,
+        database_identity: &Identity,
+        throttled_until: &mut Option<tokio::time::Instant>,
+    ) -> Result<(), Duration> {
+        let now = Instant::now();
+        let (action, result) = match self.throttle {
+            MessageThrottle::Delay => match self.bucket.take(now) {
+                Some(wait) => {
+                    *throttled_until = Some(tokio::time::Instant::from_std(now + wait));
+                    ("delayed", Ok(()))
+                }
+                None => return Ok(()),
+            },
+            MessageThrottle::Reject => match self.bucket.try_take(now) {
+                Ok(()) => return Ok(()),
+                Err(retry_after) => ("rejected", Err(retry_after)),
+            },
+        };
+        WORKER_METRICS
+            .ws_throttled_messages
+            .with_label_values(database_identity, action)
+            .inc();
+        result
+    }
+
+    /// The error sent to `client` when one of its messages is rejected, as it may send another after `retry_after`.
+    ///
+    /// Under [`ErrorFormat::Structured`], the error is a [`RateLimitedError`] encoded as JSON.
+    fn rejection(
+        &self,
+        client: &ClientConnection,
+        retry_after: Duration,
+        format: ErrorFormat,
+    ) -> MessageExecutionError {
+        // Round up, so that a client retrying after exactly this long is within its limit.
+        let retry_after_ms = retry_after.as_micros().div_ceil(1000) as u64;
+        let limit = self.bucket.limit();
+        let message = match format {
+            ErrorFormat::Legacy => format!("too many messages, retry in {retry_after_ms}ms"),
+            ErrorFormat::Structured => {
+                let error = RateLimitedError {
+                    reason: "rate limited",
+                    retry_after_ms,
+                    rate: limit.rate,
+                    burst: limit.burst,
+                    retry: RetryHint::Backoff,
+                };
+                serde_json::to_string(&error).unwrap()
+            }
+        };
+        MessageExecutionError {
+            reducer: None,
+            reducer_id: None,
+            caller_identity: client.id.identity,
+            caller_connection_id: Some(client.id.connection_id),
+            request_id: None,
+            err: RequestRejected {
+                code: RequestErrorCode::RateLimited,
+                message,
+            }
+            .into(),
+        }
+    }
+}
+
+/// Checks the messages queued for a client against its database's [`SendBacklogPolicy`].
+struct SendBacklog {
+    policy: SendBacklogPolicy,
+    /// Whether the client fell behind under [`SendBacklogAction::Coalesce`], and hasn't caught up since.
+    coalescing: bool,
+}
+
+/// What to do with a batch of messages about to be sent, see [`SendBacklog::check`].
+#[derive(Debug, PartialEq, Eq)]
+enum BacklogCheck {
+    Send,
+    /// Coalesce the transaction updates of the batch, having `started` to just now.
+    Coalesce {
+        started: bool,
+    },
+    Close,
+}
+
+impl SendBacklog {
+    fn new(policy: SendBacklogPolicy) -> Self {
+        Self {
+            policy,
+            coalescing: false,
+        }
+    }
+
+    /// Check the `len` messages, of `bytes` bytes, still queued behind a batch about to be sent.
+    ///
+    /// Once coalescing, a client keeps coalescing until nothing is left queued behind its batch.
+    fn check(&mut self, len: usize, bytes: usize) -> BacklogCheck {
+        let behind = self.policy.is_behind(len, bytes);
+        match self.policy.action {
+            SendBacklogAction::Close if behind => BacklogCheck::Close,
+            SendBacklogAction::Close => BacklogCheck::Send,
+            SendBacklogAction::Coalesce => {
+                let started = behind && !self.coalescing;
+                self.coalescing = behind || (self.coalescing && len != 0);
+                if self.coalescing {
+                    BacklogCheck::Coalesce { started }
+                } else {
+                    BacklogCheck::Send
+                }
+            }
+            // Rather than the actor, the subscription manager holds back the client's updates,
+            // see `ClientConnectionSender::accepts_updates`.
+            SendBacklogAction::Resync => BacklogCheck::Send,
+        }
+    }
+}
+
+/// Fold each run of light transaction updates in `batch` into one, see [`TransactionUpdateMessage::coalesce`].
+///
+/// Updates carrying their event, such as the replies to the client's own calls, are never folded,
+/// as that would drop the event, and with it the outcome of the call.
+fn coalesce_updates(batch: &mut Vec<SerializableMessage>) {
+    for msg in mem::take(batch) {
+        match msg {
+            SerializableMessage::TxUpdate(next) if next.event.is_none() => match batch.last_mut() {
+                Some(SerializableMessage::TxUpdate(update)) if update.event.is_none() => update.coalesce(next),
+                _ => batch.push(SerializableMessage::TxUpdate(next)),
+            },
+            msg => batch.push(msg),
+        }
+    }
+}
+
+/// The credits of a client which negotiated flow control when connecting,
+/// see [`SubscribeQueryParams::flow_control_credits`].
+///
+/// Each update sent to the client spends credits by its estimated size in bytes,
+/// and once they're spent, its updates are held back until it grants more with `GrantCredits`,
+/// with its light updates coalesced, see [`coalesce_updates`].
+/// Other messages spend no credits, but are held back behind any update held back before them,
+/// so that the client receives its messages in order.
+#[derive(Debug)]
+struct FlowControl {
+    credits: u64,
+    /// The messages held back for the client, oldest first.
+    held: Vec<SerializableMessage>,
+}
+
+impl FlowControl {
+    fn new(credits: u64) -> Self {
+        Self { credits, held: vec![] }
+    }
+
+    /// Grant the client `credits` more.
+    fn grant(&mut self, credits: u64) {
+        self.credits = self.credits.saturating_add(credits);
+    }
+
+    /// Hold back the messages of `batch` until they can be released,
+    /// coalescing the light updates held back while the client has no credits left.
+    fn hold(&mut self, batch: &mut Vec<SerializableMessage>) {
+        self.held.append(batch);
+        if self.credits == 0 {
+            coalesce_updates(&mut self.held);
+        }
+    }
+
+    /// Move up to `limit` of the messages held back into `batch`, oldest first, as far as the client's credits allow,
+    /// returning how many were moved.
+    ///
+    /// An update is released as long as the client has any credits left, even if it costs more than that.
+    fn release(&mut self, batch: &mut Vec<SerializableMessage>, limit: usize) -> usize {
+        let mut n = 0;
+        for msg in self.held.iter().take(limit) {
+            if matches!(
+                msg,
+                SerializableMessage::TxUpdate(_) | SerializableMessage::AcknowledgedUpdate(_)
+            ) {
+                if self.credits == 0 {
+                    break;
+                }
+                self.credits = self.credits.saturating_sub(msg.size_hint() as u64);
+            }
+            n += 1;
+        }
+        batch.extend(self.held.drain(..n));
+        n
+    }
+}
+
+/// Returns when a client whose token expires at `expires_at`, if it does,
+/// is to be closed under its database's `policy`, as of `now`, if ever.
+///
+/// A client whose token expired longer ago than the grace period is closed right away.
+fn auth_expiry_deadline(
+    policy: AuthExpiryPolicy,
+    expires_at: Option<SystemTime>,
+    (system_now, now): (SystemTime, tokio::time::Instant),
+) -> Option<tokio::time::Instant> {
+    match policy.action {
+        AuthExpiryAction::Close => {
+            let closes_at = expires_at? + policy.grace();
+            Some(now + closes_at.duration_since(system_now).unwrap_or_default())
+        }
+        AuthExpiryAction::Ignore => None,
+    }
+}
+
+/// The error a client is sent, encoded as JSON, when one of its messages is rejected
+/// as it has sent messages faster than it may.
+#[derive(Serialize, Debug)]
+pub struct RateLimitedError {
+    pub reason: &'static str,
+    /// How long until the client may send another message, in milliseconds.
+    pub retry_after_ms: u64,
+    /// How many messages the client may send per second, on average.
+    pub rate: f64,
+    /// How many messages the client may send at once.
+    pub burst: u32,
+    pub retry: RetryHint,
+}
+
+/// The reason of the close frame sent when a client has more messages waiting to be handled than it may,
+/// encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct QueueOverflowClose {
+    pub reason: &'static str,
+    /// How many messages the client may have waiting.
+    pub max_len: usize,
+    /// How many bytes of messages the client may have waiting.
+    pub max_bytes: usize,
+}
+
+async fn make_progress<Fut: Future>(fut: &mut Pin<&mut MaybeDone<Fut>>) {
+    if let MaybeDone::Gone = **fut {
+        // nothing to do
+    } else {
+        fut.await
+    }
+}
+
+/// The outcome of an item of the ordered lane of incoming messages.
+enum LaneOutcome {
+    Handled(HandleOutcome),
+    /// The client re-authenticated as another identity, see [`ws_api::Authenticate`].
+    Reauthenticated(ClientConnection, MeteredReceiver<SerializableMessage>),
+}
+
+/// Validate `token` and, if it's for another identity, switch `client` over to it.
+///
+/// Either way, the client is kept connected until `token` expires, see [`auth_expiry_deadline`],
+/// which is how a client refreshes its token.
+///
+/// The new identity's [`IdentityTokenMessage`] is the first message in the returned queue.
+async fn reauthenticate(
+    client: ClientConnection,
+    validator: Arc<dyn TokenValidator + Send + Sync>,
+    connection_ids: ConnectionIds,
+    token: Box<str>,
+) -> Result<LaneOutcome, MessageHandleError> {
+    let claims = validator
+        .validate_token(&token)
+        .await
+        .map_err(|e| MessageExecutionError {
+            reducer: None,
+            reducer_id: None,
+            caller_identity: client.id.identity,
+            caller_connection_id: Some(client.id.connection_id),
+            request_id: None,
+            err: anyhow::anyhow!("authentication failed: {e}"),
+        })?;
+    client.set_auth_expiry(claims.exp);
+    if claims.identity == client.id.identity {
+        return Ok(LaneOutcome::Handled(HandleOutcome::Handled));
+    }
+
+    let (client, sendrx) = client.reauthenticate(claims.identity, connection_ids()).await?;
+    let message = IdentityTokenMessage {
+        identity: claims.identity,
+        token,
+        connection_id: client.id.connection_id,
+    };
+    let database_identity = client.module.info().database_identity;
+    if send_identity_token(&client, database_identity, message).is_ok() {
+        redeliver(&client);
+    }
+    Ok(LaneOutcome::Reauthenticated(client, sendrx))
+}
+
+/// Validate `token` and, if it's for the identity of `client`, keep the client connected until it expires,
+/// telling the client so in reply to `request_id`.
+///
+/// A token for another identity is refused, rather than switching the client over to it as [`reauthenticate`] does.
+async fn refresh_token(
+    client: ClientConnection,
+    validator: Arc<dyn TokenValidator + Send + Sync>,
+    request_id: u32,
+    token: Box<str>,
+) -> Result<LaneOutcome, MessageHandleError> {
+    let rejected = |code, message| MessageExecutionError {
+        reducer: None,
+        reducer_id: None,
+        caller_identity: client.id.identity,
+        caller_connection_id: Some(client.id.connection_id),
+        request_id: Some(request_id),
+        err: RequestRejected { code, message }.into(),
+    };
+    let claims = validator
+        .validate_token(&token)
+        .await
+        .map_err(|e| rejected(RequestErrorCode::InvalidToken, format!("token refresh failed: {e}")))?;
+    if claims.identity != client.id.identity {
+        let message = format!(
+            "token refresh failed: the token is for {}, not the connection's identity",
+            claims.identity
+        );
+        return Err(rejected(RequestErrorCode::IdentityMismatch, message).into());
+    }
+
+    client.set_auth_expiry(claims.exp);
+    let ack = TokenRefreshedMessage {
+        request_id,
+        expires_at: claims.exp.map(Timestamp::from),
+    };
+    if let Err(e) = client.send_message(ack) {
+        log_send_error(e, "token refresh")
+    }
+    Ok(LaneOutcome::Handled(HandleOutcome::Handled))
+}
+
+/// Send `client` of `database_identity` its identity token, the first message of its connection.
+///
+/// SDKs which never receive their token never consider the connection established,
+/// so if it can't be sent, the connection is closed with a "handshake failed" close frame,
+/// rather than left open but half-initialized.
+fn send_identity_token(
+    client: &ClientConnectionSender,
+    database_identity: Identity,
+    message: IdentityTokenMessage,
+) -> Result<(), ClientSendError> {
+    client.send_message(message).inspect_err(|&e| {
+        log::warn!(
+            "{e}, before identity token was sent to client {}, closing the connection",
+            client.id
+        );
+        WORKER_METRICS
+            .ws_handshake_failures
+            .with_label_values(&database_identity, e.as_ref())
+            .inc();
+        client.request_close(CloseReason::HandshakeFailed(e));
+    })
+}
+
+/// Log that `what` couldn't be sent to a client, because of `e`.
+///
+/// A client which has disconnected is unremarkable,
+/// but one which isn't keeping up with its queue is worth a warning.
+fn log_send_error(e: ClientSendError, what: &str) {
+    match e {
+        ClientSendError::Disconnected => log::debug!("{e}, before {what} was sent"),
+        ClientSendError::QueueFullByCount | ClientSendError::QueueFullByBytes | ClientSendError::ConnectionClosing => {
+            log::warn!("{e}, before {what} was sent")
+        }
+    }
+}
+
+/// Deliver again the acknowledged updates pending for the identity of `client`, if it asked for them,
+/// following its identity token.
+fn redeliver(client: &ClientConnection) {
+    match client.redeliver() {
+        Ok(0) => {}
+        Ok(n) => log::debug!("delivered {n} unacknowledged updates again to client {}", client.id),
+        Err(e) => log::warn!("{e}, while delivering unacknowledged updates again"),
+    }
+}
+
+/// What the websocket actor observes of the health of its connection,
+/// answered to a [`ws_api::GetConnectionStatus`] along with the rest of the connection's state.
+struct ConnectionHealth {
+    /// When the connection started, from which the timestamps in ping payloads are measured.
+    started_at: Instant,
+    /// The timestamp in the payload of the ping the client has yet to answer, and when it was sent.
+    ping_sent: Option<(u64, Instant)>,
+    /// The round trip time of the most recent ping the client answered.
+    round_trip_time: Option<Duration>,
+    /// When messages were last taken off the outgoing queue to be sent.
+    last_drained_at: Instant,
+    /// When the client last sent a message, other than a ping, pong, or close frame, if it has at all.
+    last_inbound_at: Option<Instant>,
+    /// Whether the client was sent a [`ConnectionDegraded`](ws_api::ConnectionDegraded) notice
+    /// since its last message arrived.
+    degraded_noticed: bool,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+/// Whether a client's messages still arrive, see [`ConnectionHealth::inbound_check`].
+#[derive(Debug, PartialEq, Eq)]
+enum InboundCheck {
+    Healthy,
+    /// The client's messages last arrived this long ago, past [`TimeoutsConfig::inbound_silence_notice`].
+    Degraded(Duration),
+    /// The client's messages last arrived this long ago, past [`TimeoutsConfig::inbound_silence_close`].
+    Broken(Duration),
+}
+
+impl ConnectionHealth {
+    fn new(now: Instant) -> Self {
+        Self {
+            started_at: now,
+            ping_sent: None,
+            round_trip_time: None,
+            last_drained_at: now,
+            last_inbound_at: None,
+            degraded_noticed: false,
+            bytes_sent: 0,
+            bytes_received: 0,
+        }
+    }
+
+    /// Record that a ping is being sent, returning its payload:
+    /// the nanoseconds since the connection started, which the client echoes back in its pong.
+    fn ping_sent(&mut self, now: Instant) -> Bytes {
+        let timestamp = now.saturating_duration_since(self.started_at).as_nanos() as u64;
+        self.ping_sent = Some((timestamp, now));
+        Bytes::copy_from_slice(&timestamp.to_le_bytes())
+    }
+
+    /// Record that a pong with `payload` was received,
+    /// returning the round trip time of the ping it answers.
+    ///
+    /// A payload which isn't the timestamp of the outstanding ping, e.g. as the client mangled it
+    /// or sent an unsolicited pong, is skipped, leaving the ping outstanding.
+    fn pong_received(&mut self, now: Instant, payload: &[u8]) -> Option<Duration> {
+        let timestamp = u64::from_le_bytes(payload.try_into().ok()?);
+        let (_, sent_at) = self.ping_sent.take_if(|(sent, _)| *sent == timestamp)?;
+        let round_trip_time = now.saturating_duration_since(sent_at);
+        self.round_trip_time = Some(round_trip_time);
+        Some(round_trip_time)
+    }
+
+    /// Record that `bytes` were taken off the outgoing queue and sent.
+    fn sent(&mut self, now: Instant, bytes: usize) {
+        self.last_drained_at = now;
+        self.bytes_sent += bytes as u64;
+    }
+
+    fn received(&mut self, bytes: usize) {
+        self.bytes_received += bytes as u64;
+    }
+
+    /// Record that a message other than a ping, pong, or close frame arrived from the client.
+    fn inbound(&mut self, now: Instant) {
+        self.last_inbound_at = Some(now);
+        self.degraded_noticed = false;
+    }
+
+    /// Check whether the client's messages have stopped arriving for longer than `notice` or `close`,
+    /// while it's still sent messages, i.e. while messages were sent to it within as long.
+    ///
+    /// A client is noticed as [`InboundCheck::Degraded`] only once each time its messages stop arriving.
+    fn inbound_check(&mut self, now: Instant, notice: Option<Duration>, close: Option<Duration>) -> InboundCheck {
+        let Some(last_inbound_at) = self.last_inbound_at else {
+            return InboundCheck::Healthy;
+        };
+        let silence = now.saturating_duration_since(last_inbound_at);
+        let sending = |threshold: Duration| {
+            silence >= threshold
+                && self.last_drained_at > last_inbound_at
+                && now.saturating_duration_since(self.last_drained_at) < threshold
+        };
+        if close.is_some_and(sending) {
+            return InboundCheck::Broken(silence);
+        }
+        if !self.degraded_noticed && notice.is_some_and(sending) {
+            self.degraded_noticed = true;
+            return InboundCheck::Degraded(silence);
+        }
+        InboundCheck::Healthy
+    }
+
+    /// The status of the connection of `client`, in reply to `request_id`.
+    ///
+    /// The outgoing queue is `outgoing` along with the queue of the client's previous identity, if any,
+    /// and its age is the time since it was last drained,
+    /// which bounds how long its oldest message has waited.
+    fn status(
+        &self,
+        request_id: u32,
+        now: Instant,
+        client: &ClientConnectionSender,
+        lifetime: Option<&ConnectionLifetime>,
+        incoming_queue_len: usize,
+        outgoing: [Option<&MeteredReceiver<SerializableMessage>>; 2],
+    ) -> ConnectionStatusMessage {
+        let outgoing = outgoing.into_iter().flatten();
+        let outgoing_queue_len = outgoing.clone().map(|rx| rx.len() as u64).sum();
+        let outgoing_queue_bytes = outgoing.map(|rx| rx.queued_bytes() as u64).sum();
+        let config = client.config();
+        ConnectionStatusMessage {
+            request_id,
+            protocol: protocol_name(&config).into(),
+            compression: format!("{:?}", config.compression).into(),
+            light: !config.tx_update_full,
+            reducer_timings: config.reducer_timings,
+            acknowledged_delivery: config.acknowledged_delivery,
+            round_trip_time: self.round_trip_time.map(TimeDuration::from_duration),
+            outgoing_queue_len,
+            outgoing_queue_bytes,
+            outgoing_queue_age: (outgoing_queue_len != 0)
+                .then(|| TimeDuration::from_duration(now.saturating_duration_since(self.last_drained_at))),
+            incoming_queue_len: incoming_queue_len as u64,
+            subscriptions: client.subscriptions() as u64,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            uptime: TimeDuration::from_duration(client.uptime()),
+            lifetime_remaining: lifetime
+                .map(|lifetime| TimeDuration::from_duration(lifetime.deadline.saturating_duration_since(now))),
+        }
+    }
+}
+
+/// What the node supports, as configured by its websocket `options` and `timeouts_config`,
+/// sent to a client of `config` right after its identity token,
+/// along with the `timeouts` and database `features` its connection negotiated.
+fn server_capabilities(
+    config: &ClientConfig,
+    options: &WebSocketOptions,
+    timeouts_config: &TimeoutsConfig,
+    timeouts: ClientTimeouts,
+    features: DatabaseFeatures,
+) -> ServerCapabilitiesMessage {
+    let available = |feature: &ServerFeature| match feature {
+        ServerFeature::SessionResume => options.session_resume_grace.is_some(),
+        ServerFeature::ReconnectTokens => !timeouts_config.reconnect_token_ttl.is_zero(),
+        ServerFeature::LightUpdates
+        | ServerFeature::OneOffQueries
+        | ServerFeature::RequestErrors
+        | ServerFeature::UpdateSettings
+        | ServerFeature::ConnectionStatus
+        | ServerFeature::TokenRefresh
+        | ServerFeature::ChunkedQueries
+        | ServerFeature::FlowControl => true,
+    };
+    ServerCapabilitiesMessage {
+        protocol: protocol_name(config).into(),
+        compression: Compression::ALL
+            .into_iter()
+            .filter(|&compression| config.version.supports(compression))
+            .collect(),
+        max_message_size: options.max_message_size as u64,
+        liveness_timeout: TimeDuration::from_duration(timeouts.liveness),
+        send_timeout: TimeDuration::from_duration(timeouts.send),
+        max_connection_lifetime: timeouts_config.max_connection_lifetime.map(TimeDuration::from_duration),
+        features: ServerFeature::ALL
+            .iter()
+            .filter(|feature| available(feature))
+            .map(|feature| feature.name().into())
+            .collect(),
+        database_features: features.iter().map(|feature| feature.name().into()).collect(),
+    }
+}
+
+/// The websocket subprotocol negotiated for `config`.
+pub(crate) fn protocol_name(config: &ClientConfig) -> &'static str {
+    match (config.protocol, config.version) {
+        (Protocol::Binary, ProtocolVersion::V1) => ws_api::BIN_PROTOCOL,
+        (Protocol::Text, ProtocolVersion::V1) => ws_api::TEXT_PROTOCOL,
+        (Protocol::Binary, ProtocolVersion::V2) => ws_api::BIN_PROTOCOL_V2,
+        (Protocol::Text, ProtocolVersion::V2) => ws_api::TEXT_PROTOCOL_V2,
+    }
+}
+
+/// Receive up to `limit` messages into `buf`,
+/// first draining the queue of the client's previous identity, if any, and then from `sendrx`.
+///
+/// Returns 0 once `sendrx` is closed and drained.
+async fn recv_many_after_retired(
+    retired: &mut Option<MeteredReceiver<SerializableMessage>>,
+    sendrx: &mut MeteredReceiver<SerializableMessage>,
+    buf: &mut Vec<SerializableMessage>,
+    limit: usize,
+) -> usize {
+    if let Some(retired_rx) = retired {
+        // A retired queue is closed, and tokio only reports a closed queue drained into an empty buffer.
+        let mut received = Vec::new();
+        let n = retired_rx.recv_many(&mut received, limit).await;
+        if n != 0 {
+            buf.append(&mut received);
+            return n;
+        }
+        *retired = None;
+    }
+    sendrx.recv_many(buf, limit).await
+}
+
+/// Receive up to `limit` messages into `buf`, as [`recv_many_after_retired`] does,
+/// but for a client under `flow_control`, only as many as its credits allow, holding back the rest.
+///
+/// Returns 0 once `sendrx` is closed and drained, and no more of what's held back can be released.
+async fn recv_many_within_credits(
+    flow_control: &mut Option<FlowControl>,
+    retired: &mut Option<MeteredReceiver<SerializableMessage>>,
+    sendrx: &mut MeteredReceiver<SerializableMessage>,
+    buf: &mut Vec<SerializableMessage>,
+    limit: usize,
+) -> usize {
+    let Some(flow_control) = flow_control else {
+        return recv_many_after_retired(retired, sendrx, buf, limit).await;
+    };
+    loop {
+        let n = flow_control.release(buf, limit);
+        if n != 0 {
+            return n;
+        }
+        // Holding back what's received happens right away, so this is as cancel safe as receiving.
+        if recv_many_after_retired(retired, sendrx, buf, limit).await == 0 {
+            return 0;
+        }
+        flow_control.hold(buf);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn ws_client_actor_inner<T: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut ClientConnection,
+    registration: &mut ClientRegistration,
+    options: &WebSocketOptions,
+    error_format: ErrorFormat,
+    rate_limit: Option<MessageRateLimit>,
+    send_backlog: SendBacklogPolicy,
+    auth_expiry: AuthExpiryPolicy,
+    timeouts_config: &TimeoutsConfig,
+    liveness_timeout: Duration,
+    validator: &Arc<dyn TokenValidator + Send + Sync>,
+    connection_ids: &ConnectionIds,
+    lifetime: &mut Option<ConnectionLifetime>,
+    teardown: &mut Teardown,
+    deprecations: &mut DeprecationNotices,
+    flow_control: &mut Option<FlowControl>,
+    mut ws: tokio_tungstenite::WebSocketStream<T>,
+    sendrx: &mut MeteredReceiver<SerializableMessage>,
+) -> CloseCause {
+    let mut liveness_check_interval = tokio::time::interval(liveness_timeout);
+    let mut got_pong = true;
+
+    let addr = client.module.info().database_identity;
+    let mut sender = client.sender();
+    let mut send_backlog = SendBacklog::new(send_backlog);
+    // The closed queue of the client's previous identity, if it re-authenticated,
+    // which is drained before receiving from `sendrx`.
+    let mut retired_sendrx: Option<MeteredReceiver<SerializableMessage>> = None;
+
+    // Build a queue of incoming messages to handle, to be processed one at a time,
+    // in the order they're received.
+    //
+    // N.B. if you're refactoring this code: you must ensure the handle_queue is dropped before
+    // client.disconnect() is called. Otherwise, we can be left with a stale future that's never
+    // awaited, which can lead to bugs like:
+    // https://rust-lang.github.io/wg-async/vision/submitted_stories/status_quo/aws_engineer/solving_a_deadlock.html
+    //
+    // NOTE: never let this go unpolled while you're awaiting something; otherwise, it's possible
+    //       to deadlock or delay for a long time. see usage of `also_poll()` in the branches of the
+    //       `select!` for examples of how to do this.
+    //       The `watchdog` warns of the message in flight going unpolled for longer than its budget,
+    //       blaming the branch entered last, so each branch tells it when it's entered.
+    //
+    // The queue is bounded by `queue_limit`, so that a client can't grow it faster than its messages are handled.
+    let mut message_queue = MeteredDeque::<(DataMessage, Instant)>::new(
+        WORKER_METRICS.total_incoming_queue_length.with_label_values(&addr),
+    );
+    let incoming_times = IncomingMessageTimes::new(&addr);
+    let watchdog = PollWatchdog::new(
+        timeouts_config.poll_starvation_budget,
+        timeouts_config.slow_send_warn_interval,
+        WORKER_METRICS.ws_message_poll_max_gap_seconds.with_label_values(&addr),
+    );
+    let mut current_message = pin!(MaybeDone::Gone);
+
+    let mut closed = false;
+    let mut rx_buf = Vec::new();
+    let mut health = ConnectionHealth::new(Instant::now());
+
+    let mut size_limit = MessageSizeLimit::new(options, client.config().protocol);
+    let violation_budget = ViolationBudget::new(options);
+    let mut queue_limit = IncomingQueueLimit::new(options);
+    let mut message_rate = rate_limit.map(|limit| MessageRate::new(limit, options.client_message_throttle));
+    // While the client is beyond its rate limit under `MessageThrottle::Delay`, nothing more is read from it.
+    let mut throttled_until: Option<tokio::time::Instant> = None;
+
+    let mut msg_buffer = SerializeBuffer::new(client.config());
+    let encode_error_policy = client.module.encode_error_policy().await;
+    let mut last_slow_send_warning: Option<Instant> = None;
+    let mut close_cause = None;
+    // Once the node begins draining, the connection stops taking messages
+    // and delivers what's left for the client until then, before closing.
+    let mut flushing_until: Option<tokio::time::Instant> = None;
+    let mut flush_expired = false;
+    // Whether the connection was asked to close as the client's token expired.
+    let mut auth_expired = false;
+    let cause = loop {
+        rx_buf.clear();
+        enum Item {
+            Message(ClientMessage),
+            HandleResult(Result<LaneOutcome, MessageHandleError>),
+            /// The client sent a message larger than it may, and has no strikes left.
+            TooLarge,
+            /// The client committed this many protocol violations, exceeding its budget.
+            TooManyViolations(u32),
+            /// The client sent a message while its queue of messages to handle was full.
+            QueueOverflow,
+        }
+        if let MaybeDone::Gone = *current_message {
+            if let Some((message, timer)) = message_queue.pop_front() {
+                queue_limit.release(message.len());
+                let handle_seconds = incoming_times.dequeued(&message, timer);
+                let client = client.clone();
+                let fut: BoxFuture<'static, _> = Box::pin(async move {
+                    let start = Instant::now();
+                    let res = client.handle_message(message, timer).await;
+                    handle_seconds.observe(start.elapsed().as_secs_f64());
+                    res.map(LaneOutcome::Handled)
+                });
+                current_message.set(MaybeDone::Future(watchdog.watch(fut)));
+            }
+        }
+        let flushed = flush_expired
+            || (message_queue.is_empty()
+                && matches!(*current_message, MaybeDone::Gone)
+                && sendrx.is_empty()
+                && retired_sendrx.as_ref().is_none_or(|rx| rx.is_empty())
+                && flow_control.as_ref().is_none_or(|flow| flow.held.is_empty()));
+        // Looked up anew each time around, as the client may have refreshed its token meanwhile.
+        let auth_deadline = auth_expiry_deadline(
+            auth_expiry,
+            client.auth_expires_at(),
+            (SystemTime::now(), tokio::time::Instant::now()),
+        );
+        let message = tokio::select! {
+            // NOTE: all of the futures for these branches **must** be cancel safe. do not
+            //       change this if you don't know what that means.
+
+            // If we have a result from handling a past message to report,
+            // grab it to handle in the next `match`.
+            Some(res) = async {
+                make_progress(&mut current_message).await;
+                watchdog.enter("handled");
+                current_message.as_mut().take_output()
+            } => match res {
+                Err(e) => match e.violation().map(|violation| violation_budget.record(client, violation)) {
+                    Some(ViolationCheck::Exceeded(violations)) => Item::TooManyViolations(violations),
+                    Some(ViolationCheck::Tolerated) => Item::HandleResult(Err(ViolationBudget::tolerated(client, e))),
+                    Some(ViolationCheck::Unbudgeted) | None => Item::HandleResult(Err(e)),
+                },
+                res => Item::HandleResult(res),
+            },
+
+            // If we've received an incoming message,
+            // grab it to handle in the next `match`.
+            message = ws.next().inspect(|_| watchdog.enter("receive")), if throttled_until.is_none() => match message {
+                Some(Ok(m)) => {
+                    sender.record_activity();
+                    sender.record_received(m.len());
+                    health.received(m.len());
+                    if m.is_text() || m.is_binary() {
+                        health.inbound(Instant::now());
+                    }
+                    match size_limit.check(m.len()) {
+                        SizeCheck::Within => match ClientMessage::from_message(m) {
+                            // The connection is about to close, so the message is refused rather than handled.
+                            Ok(ClientMessage::Message(_)) if flushing_until.is_some() => {
+                                Item::HandleResult(Err(draining_rejection(client).into()))
+                            }
+                            Ok(ClientMessage::Message(message)) => {
+                                // Only data messages count against the rate limit, not pings, pongs, or close frames.
+                                let rate_check = match &mut message_rate {
+                                    Some(rate) => rate.check(&addr, &mut throttled_until),
+                                    None => Ok(()),
+                                };
+                                match rate_check.map(|()| queue_limit.admit(message_queue.len(), message.len())) {
+                                    Err(retry_after) => {
+                                        log::debug!("client {} sent messages too fast, rejecting one", client.id);
+                                        // Reply as if handling the message failed, which the client can recover from.
+                                        let rate = message_rate.as_ref().unwrap();
+                                        Item::HandleResult(Err(rate.rejection(client, retry_after, error_format).into()))
+                                    }
+                                    Ok(QueueCheck::Queued) => Item::Message(ClientMessage::Message(message)),
+                                    Ok(QueueCheck::Rejected) => {
+                                        log::info!("client {} has too many queued messages, rejecting one", client.id);
+                                        WORKER_METRICS
+                                            .ws_incoming_queue_overflows
+                                            .with_label_values(&addr, "rejected")
+                                            .inc();
+                                        // Reply as if handling the message failed, which the client can recover from.
+                                        let rejection = queue_limit.rejection(client, message_queue.len(), error_format);
+                                        Item::HandleResult(Err(rejection.into()))
+                                    }
+                                    Ok(QueueCheck::Overflowed) => Item::QueueOverflow,
+                                }
+                            }
+                            Ok(message) => Item::Message(message),
+                            // Counted as a message which couldn't be decoded, as if it had been handled.
+                            Err(e) => {
+                                let e = MessageHandleError::from(e);
+                                match violation_budget.record(client, ProtocolViolation::Decode) {
+                                    ViolationCheck::Exceeded(violations) => Item::TooManyViolations(violations),
+                                    ViolationCheck::Tolerated => {
+                                        Item::HandleResult(Err(ViolationBudget::tolerated(client, e)))
+                                    }
+                                    ViolationCheck::Unbudgeted => Item::HandleResult(Err(e)),
+                                }
+                            }
+                        },
+                        SizeCheck::Strike => {
+                            log::info!("client {} sent a message of {} bytes, rejecting it", client.id, m.len());
+                            WORKER_METRICS.ws_oversized_messages.with_label_values(&addr).inc();
+                            match violation_budget.record(client, ProtocolViolation::Oversized) {
+                                ViolationCheck::Exceeded(violations) => Item::TooManyViolations(violations),
+                                // Reply as if handling the message failed, which the client can recover from.
+                                _ => Item::HandleResult(Err(size_limit.rejection(client).into())),
+                            }
+                        }
+                        SizeCheck::TooLarge => Item::TooLarge,
+                    }
+                }
+                // The message was too large for the websocket to read at all,
+                // after which it won't read any more messages.
+                // The same goes for a frame larger than `WebSocketOptions::max_frame_size`.
+                Some(Err(WsError::Capacity(error))) => {
+                    log::debug!("client {} exceeded the websocket's capacity: {error}", client.id);
+                    Item::TooLarge
+                }
+                // The connection dropped, so there's no one to send a close frame to.
+                Some(Err(error)) if is_connection_lost(&error) => {
+                    log::debug!("client {} lost its connection: {error}", client.id);
+                    break close_cause.unwrap_or(CloseCause::Lost);
+                }
+                Some(Err(error)) => {
+                    log::warn!("Websocket receive error: {}", error);
+                    let frame = close_frame(error_format, CloseCode::Protocol, &ReasonClose::new("protocol error"));
+                    let deadline = teardown.last_close_deadline();
+                    also_poll(close_before_dropping(&mut ws, frame, deadline), make_progress(&mut current_message)).await;
+                    break CloseCause::Error;
+                }
+                // the client sent us a close frame
+                None => {
+                    break close_cause.unwrap_or(CloseCause::ClientClosed);
+                }
+            },
+
+            // Once the client is back within its rate limit, read from it again.
+            _ = tokio::time::sleep_until(throttled_until.unwrap_or_else(tokio::time::Instant::now)),
+                if throttled_until.is_some() => {
+                watchdog.enter("throttled");
+                throttled_until = None;
+                continue;
+            }
+
+            // If what's left for the client of a draining node takes too long to send, close without it.
+            _ = tokio::time::sleep_until(flushing_until.unwrap_or_else(tokio::time::Instant::now)),
+                if flushing_until.is_some() && !flushed && !closed => {
+                watchdog.enter("flush_expired");
+                log::info!("client {} was not sent everything queued for it before the node drained it", client.id);
+                flush_expired = true;
+                continue;
+            }
+
+            // If the client hasn't completed the close handshake in time, give up on it.
+            _ = teardown.expired() => {
+                watchdog.enter("teardown_expired");
+                log::warn!("client {} did not close the websocket before the teardown deadline", client.id);
+                break close_cause.unwrap_or(CloseCause::Unresponsive);
+            }
+
+            // If we have an outgoing message to send, send it off.
+            // No incoming `message` to handle, so `continue`.
+            Some(n) = recv_many_within_credits(flow_control, &mut retired_sendrx, sendrx, &mut rx_buf, 32)
+                .map(|n| (n != 0).then_some(n)) => {
+                watchdog.enter("send");
+                if closed {
+                    // TODO: this isn't great. when we receive a close request from the peer,
+                    //       tungstenite doesn't let us send any new messages on the socket,
+                    //       even though the websocket RFC allows it. should we fork tungstenite?
+                    log::info!("dropping {n} messages due to ws already being closed");
+                    log::debug!("dropped messages: {:?}", &rx_buf[..n]);
+                } else {
+                    // Check what's still queued behind the batch, which the client has fallen behind by.
+                    let n = match send_backlog.check(sendrx.len(), sendrx.queued_bytes()) {
+                        BacklogCheck::Send => n,
+                        BacklogCheck::Coalesce { started } => {
+                            if started {
+                                log::info!("client {} fell behind, coalescing its updates until it catches up", client.id);
+                                WORKER_METRICS
+                                    .ws_send_backlog_actions
+                                    .with_label_values(&addr, "coalesced")
+                                    .inc();
+                            }
+                            coalesce_updates(&mut rx_buf);
+                            rx_buf.len()
+                        }
+                        BacklogCheck::Close => {
+                            log::info!("client {} fell too far behind the messages queued for it", client.id);
+                            WORKER_METRICS
+                                .ws_send_backlog_actions
+                                .with_label_values(&addr, "closed")
+                                .inc();
+                            let frame = disconnect_close_frame(DisconnectReason::TooSlow, error_format);
+                            let deadline = teardown.last_close_deadline();
+                    also_poll(close_before_dropping(&mut ws, frame, deadline), make_progress(&mut current_message)).await;
+                            break CloseCause::Unresponsive;
+                        }
+                    };
+                    let send_all = async {
+                        let mut stats = SendStats::default();
+                        for msg in rx_buf.drain(..n) {
+                            let workload = msg.workload();
+                            let kind = msg.kind();
+                            let num_rows = msg.num_rows();
+                            let committed_at = msg.committed_at();
+
+                            // Serialize the message, report metrics,
+                            // and keep a handle to the buffer.
+                            let serialize_start = Instant::now();
+                            let encoded = encode_for_client_offloading(
+                                client,
+                                encode_error_policy,
+                                msg_buffer,
+                                msg,
+                                kind,
+                                options.encode_offload_threshold,
+                            )
+                            .await;
+                            stats.serialize_time += serialize_start.elapsed();
+                            let (msg_alloc, msg_data, committed_at) = match encoded {
+                                EncodeOutcome::Encoded(msg_alloc, msg_data) => {
+                                    report_ws_sent_metrics(&addr, workload, kind, num_rows, &msg_data);
+                                    (msg_alloc, msg_data, committed_at)
+                                }
+                                // The update isn't delivered, so there's no latency to speak of.
+                                EncodeOutcome::Dropped(msg_alloc, msg_data) => (msg_alloc, msg_data, None),
+                                // The rest of the batch is dropped along with the connection.
+                                EncodeOutcome::Close(buf) => {
+                                    msg_buffer = buf;
+                                    stats.encode_failed = Some(kind);
+                                    break;
+                                }
+                            };
+                            stats.bytes += msg_data.len();
+                            if let Some(committed_at) = committed_at {
+                                report_update_delivery_latency(&addr, committed_at);
+                            }
+
+                            // Buffer the message without necessarily sending it.
+                            let (res, buf) = feed_reclaiming(&mut ws, msg_alloc, msg_data, client.config()).await;
+                            msg_buffer = buf;
+
+                            if res.is_err() {
+                                return (res, msg_buffer, stats);
+                            }
+                        }
+                        // now we flush all the messages to the socket
+                        (ws.flush().await, msg_buffer, stats)
+                    };
+                    // Build a future that both times out and drives the send.
+                    //
+                    // Note that if flushing cannot immediately complete for whatever reason,
+                    // it will wait without polling the other futures in the `select!` arms.
+                    // Among other things, this means our liveness tick will not be polled.
+                    //
+                    // To avoid waiting indefinitely, we wrap the send in a timeout.
+                    // A timeout is treated as an unresponsive client and we drop the connection.
+                    let send_all = tokio::time::timeout_at(teardown.send_deadline(), send_all);
+                    // Flush the websocket while continuing to poll the `handle_queue`,
+                    // to avoid deadlocks or delays due to enqueued futures holding resources.
+                    let send_all = also_poll(send_all, make_progress(&mut current_message));
+                    let t1 = Instant::now();
+                    let (send_all_result, buf, stats) = match send_all.await {
+                        Ok((send_all_result, buf, stats)) => {
+                            (send_all_result, buf, stats)
+                        }
+                        Err(e) => {
+                            // Our send timed out; drop client, with a last try at sending them a Close.
+                            // The time spent waiting on the send counts towards the teardown.
+                            log::warn!("send_all timed out: {e}");
+                            teardown.begin_at(t1);
+                            let frame = disconnect_close_frame(DisconnectReason::SendTimeout, error_format);
+                            let deadline = teardown.last_close_deadline();
+                    also_poll(close_before_dropping(&mut ws, frame, deadline), make_progress(&mut current_message)).await;
+                            break CloseCause::Unresponsive;
+                        }
+                    };
+                    msg_buffer = buf;
+                    health.sent(Instant::now(), stats.bytes);
+                    sender.record_sent(stats.bytes, sendrx.len());
+                    if let Err(error) = send_all_result {
+                        log::warn!("Websocket send error: {error}")
+                    }
+                    let time = t1.elapsed();
+                    let write_time = time.saturating_sub(stats.serialize_time);
+                    report_ws_send_timings(&addr, n, time, stats.serialize_time, write_time);
+                    if time > timeouts_config.slow_send_warn_threshold
+                        && last_slow_send_warning
+                            .is_none_or(|at| at.elapsed() >= timeouts_config.slow_send_warn_interval)
+                    {
+                        last_slow_send_warning = Some(Instant::now());
+                        tracing::warn!(
+                            ?time,
+                            serialize_time = ?stats.serialize_time,
+                            ?write_time,
+                            messages = n,
+                            bytes = stats.bytes,
+                            "send_all took a very long time"
+                        );
+                    }
+                    if let Some(kind) = stats.encode_failed {
+                        let close = close_ws(&mut ws, encode_error_close_frame(kind, error_format), teardown.begin());
+                        match also_poll(close, make_progress(&mut current_message)).await {
+                            Ok(Err(e)) => {
+                                log::warn!("error closing websocket: {e:#}")
+                            }
+                            Err(e) => {
+                                log::warn!("websocket close timed out: {e}");
+                                break CloseCause::Error;
+                            }
+                            _ => {}
+                        };
+                        closed = true;
+                        close_cause.get_or_insert(CloseCause::Error);
+                    }
+                }
+                continue;
+            }
+
+            res = client.watch_module_host(), if !closed => {
+                watchdog.enter("module_update");
+                match res {
+                    Ok(()) => {}
+                    // If the module has exited, close the websocket.
+                    Err(exit_cause) => {
+                        WORKER_METRICS
+                            .ws_module_exit_closes
+                            .with_label_values(&client.module.info().database_identity, exit_cause.as_str())
+                            .inc();
+                        let _permit = also_poll(MODULE_EXIT_CLOSE_PERMITS.acquire(), make_progress(&mut current_message)).await;
+                        // Send a close frame while continuing to poll the `handle_queue`,
+                        // to avoid deadlocks or delays due to enqueued futures holding resources.
+                        let frame = module_exit_close_frame(exit_cause, error_format);
+                        let close = close_ws(&mut ws, frame, teardown.begin());
+                        match also_poll(close, make_progress(&mut current_message)).await {
+                            Ok(Err(e)) => {
+                                log::warn!("error closing websocket: {e:#}")
+                            }
+                            Err(e) => {
+                                // Our send timed out; drop client without trying to send them a Close.
+                                //
+                                // Is it correct to break if a reducer is still in progress?
+                                // Answer: Yes it is.
+                                //
+                                // If a reducer is currently being executed,
+                                // we are waiting for the `current_message` future to complete.
+                                // When we break, the task completes and this future is dropped.
+                                //
+                                // Notably though the reducer itself will run to completion,
+                                // however when it tries to notify this task that it is done,
+                                // it will encounter a closed sender in `JobThread::run`,
+                                // dropping the value that it's trying to send.
+                                // In particular it will not throw an error or panic.
+                                log::warn!("websocket close timed out: {e}");
+                                break CloseCause::ModuleExited;
+                            }
+                            _ => {}
+                        };
+                        closed = true;
+                        close_cause.get_or_insert(CloseCause::ModuleExited);
+                    }
+                }
+                continue;
+            }
+
+            // If we've been asked to close the connection, e.g. to shed load, do so,
+            // though if the node is draining, only once the client has been sent what's left for it.
+            reason = sender.close_requested(), if !closed && (flushing_until.is_none() || flushed) => {
+                watchdog.enter("close_requested");
+                if let (CloseReason::Draining { .. }, None, false) = (&reason, flushing_until, flushed) {
+                    flushing_until = Some(teardown.send_deadline());
+                    continue;
+                }
+                let close = close_ws(&mut ws, close_frame_for(reason.clone(), error_format), teardown.begin());
+                match also_poll(close, make_progress(&mut current_message)).await {
+                    Ok(Err(e)) => {
+                        log::warn!("error closing websocket: {e:#}")
+                    }
+                    Err(e) => {
+                        log::warn!("websocket close timed out: {e}");
+                        break CloseCause::Requested(reason);
+                    }
+                    _ => {}
+                };
+                closed = true;
+                close_cause.get_or_insert(CloseCause::Requested(reason));
+                continue;
+            }
+
+            // If the client's token expired, and it didn't refresh it before its database's grace period was up, close.
+            _ = tokio::time::sleep_until(auth_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                if auth_deadline.is_some() && !auth_expired && !closed => {
+                watchdog.enter("auth_expired");
+                log::info!("client {} did not refresh its expired token, closing", client.id);
+                WORKER_METRICS.ws_auth_expired_closes.with_label_values(&addr).inc();
+                auth_expired = true;
+                sender.request_close(CloseReason::AuthExpired);
+                continue;
+            }
+
+            // If it's time to send a ping...
+            _ = liveness_check_interval.tick() => {
+                watchdog.enter("liveness");
+                // ...also check whether the connection is due to be rolled over.
+                if let Some(lifetime) = lifetime.as_mut() {
+                    match lifetime.check(Instant::now()) {
+                        LifetimeCheck::Live => {}
+                        LifetimeCheck::RequestReconnect(remaining) => {
+                            let message = lifetime.reconnect_requested(remaining);
+                            if let Err(e) = client.send_message(message) {
+                                log_send_error(e, "reconnect request")
+                            }
+                        }
+                        LifetimeCheck::Expired => sender.request_close(CloseReason::MaxLifetimeReached),
+                    }
+                }
+                // ...and whether the client's messages still arrive, as it's sent messages and answers pings.
+                let inbound = health.inbound_check(
+                    Instant::now(),
+                    timeouts_config.inbound_silence_notice,
+                    timeouts_config.inbound_silence_close,
+                );
+                match inbound {
+                    InboundCheck::Healthy => {}
+                    InboundCheck::Degraded(silence) => {
+                        log::info!("client {} last sent a message {silence:?} ago, warning it", client.id);
+                        WORKER_METRICS
+                            .ws_degraded_connections
+                            .with_label_values(&addr, "noticed")
+                            .inc();
+                        let notice = ConnectionDegradedMessage {
+                            last_inbound_ms: silence.as_millis() as u64,
+                        };
+                        if let Err(e) = client.send_message(notice) {
+                            log_send_error(e, "connection degraded notice")
+                        }
+                    }
+                    InboundCheck::Broken(silence) => {
+                        // The session is effectively broken; drop them, with a last try at sending them a Close.
+                        log::warn!("client {} last sent a message {silence:?} ago, closing", client.id);
+                        WORKER_METRICS
+                            .ws_degraded_connections
+                            .with_label_values(&addr, "closed")
+                            .inc();
+                        let frame = disconnect_close_frame(DisconnectReason::ConnectionDegraded, error_format);
+                        let deadline = teardown.last_close_deadline();
+                    also_poll(close_before_dropping(&mut ws, frame, deadline), make_progress(&mut current_message)).await;
+                        break CloseCause::Unresponsive;
+                    }
+                }
+                // ...and ratchet the keys of an end-to-end encrypted connection forward.
+                client.codec().rotate_keys();
+                // If we received a pong at some point, send a fresh ping.
+                if mem::take(&mut got_pong) {
+                    // Build a future that both times out and drives the send.
+                    //
+                    // Note that if the send cannot immediately complete for whatever reason,
+                    // it will wait without polling the other futures in the `select!` arms.
+                    // Among other things, this means we won't poll the websocket for a Close frame.
+                    //
+                    // To avoid waiting indefinitely, we wrap the ping in a timeout.
+                    // A timeout is treated as an unresponsive client and we drop the connection.
+                    let payload = health.ping_sent(Instant::now());
+                    let ping = ws.send(WsMessage::Ping(payload));
+                    let ping_with_timeout = tokio::time::timeout_at(teardown.send_deadline(), ping);
+
+                    // Send a ping message while continuing to poll the `handle_queue`,
+                    // to avoid deadlocks or delays due to enqueued futures holding resources.
+                    match also_poll(ping_with_timeout, make_progress(&mut current_message)).await {
+                        Ok(Err(e)) => {
+                            log::warn!("error sending ping: {e:#}");
+                        }
+                        Err(e) => {
+                            // Our ping timed out; drop them, with a last try at sending them a Close.
+                            log::warn!("ping timed out after: {e}");
+                            let frame = disconnect_close_frame(DisconnectReason::SendTimeout, error_format);
+                            let deadline = teardown.last_close_deadline();
+                    also_poll(close_before_dropping(&mut ws, frame, deadline), make_progress(&mut current_message)).await;
+                            break CloseCause::Unresponsive;
+                        }
+                        _ => {}
+                    }
+                    continue;
+                } else {
+                    // The client never responded to our ping; drop them, with a last try at sending them a Close.
+                    log::warn!("client {} timed out", client.id);
+                    let frame = disconnect_close_frame(DisconnectReason::LivenessTimeout, error_format);
+                    let deadline = teardown.last_close_deadline();
+                    also_poll(close_before_dropping(&mut ws, frame, deadline), make_progress(&mut current_message)).await;
+                    break CloseCause::Unresponsive;
+                }
+            }
+        };
+
+        // Handle the incoming message we grabbed in the previous `select!`.
+
+        // TODO: Data flow appears to not require `enum Item` or this distinct `match`,
+        //       since `Item::HandleResult` and `Item::TooManyViolations` come from the lane or a rejected message,
+        //       and `Item::Message`, `Item::TooLarge` and `Item::QueueOverflow`
+        //       come from exactly one distinct `select!` branch.
+        //       Consider merging this `match` with the previous `select!`.
+        match message {
+            Item::Message(ClientMessage::Message(message)) => {
+                let timer = Instant::now();
+                message_queue.push_back((message, timer))
+            }
+            Item::HandleResult(Ok(LaneOutcome::Handled(HandleOutcome::Handled))) => {}
+            Item::HandleResult(Ok(LaneOutcome::Handled(HandleOutcome::HandledLegacy { kind }))) => {
+                for notice in deprecations.check(&addr, Usage::LegacyMessage(kind)) {
+                    if let Err(e) = client.send_message(notice) {
+                        log_send_error(e, "deprecation notice")
+                    }
+                }
+            }
+            Item::HandleResult(Ok(LaneOutcome::Handled(HandleOutcome::GetConnectionStatus { request_id }))) => {
+                let status = health.status(
+                    request_id,
+                    Instant::now(),
+                    &sender,
+                    lifetime.as_ref(),
+                    message_queue.len(),
+                    [retired_sendrx.as_ref(), Some(&*sendrx)],
+                );
+                if let Err(e) = client.send_message(status) {
+                    log_send_error(e, "connection status")
+                }
+            }
+            Item::HandleResult(Ok(LaneOutcome::Handled(HandleOutcome::GrantCredits { credits }))) => {
+                match flow_control.as_mut() {
+                    Some(flow_control) => flow_control.grant(credits),
+                    None => log::debug!("ignoring credits granted by client {} without flow control", client.id),
+                }
+            }
+            Item::HandleResult(Ok(LaneOutcome::Handled(HandleOutcome::Authenticate { token }))) => {
+                // Re-authenticate as the next item of the lane,
+                // so that the client's later messages are handled as the new identity.
+                let fut: BoxFuture<'static, _> = Box::pin(reauthenticate(
+                    client.clone(),
+                    validator.clone(),
+                    connection_ids.clone(),
+                    token,
+                ));
+                current_message.set(MaybeDone::Future(watchdog.watch(fut)));
+            }
+            Item::HandleResult(Ok(LaneOutcome::Handled(HandleOutcome::RefreshToken { request_id, token }))) => {
+                // Refresh as the next item of the lane, as for `Authenticate`,
+                // so that the client's later messages are handled once its token has been replaced.
+                let fut: BoxFuture<'static, _> =
+                    Box::pin(refresh_token(client.clone(), validator.clone(), request_id, token));
+                current_message.set(MaybeDone::Future(watchdog.watch(fut)));
+            }
+            Item::HandleResult(Ok(LaneOutcome::Reauthenticated(new_client, new_sendrx))) => {
+                log::debug!("client {} re-authenticated as {}", client.id, new_client.id);
+                registration.reauthenticated(new_client.sender());
+                // The reconnect grant is for the previous identity.
+                // The client can reconnect with the credentials it re-authenticated with instead.
+                if let Some(lifetime) = lifetime.as_mut() {
+                    lifetime.reconnect = None;
+                }
+                // Nothing sent on behalf of the previous identity after this point reaches the client,
+                // and what it has already sent is delivered before the new identity's token.
+                sendrx.close();
+                retired_sendrx = Some(mem::replace(sendrx, new_sendrx));
+                *client = new_client;
+                sender = client.sender();
+            }
+            Item::HandleResult(Err(e)) => {
+                let request_error = client.config().request_errors.then(|| e.request_error()).flatten();
+                let reply = match (request_error, e) {
+                    (Some(error), e) => {
+                        log::debug!("client {} caused request error: {e:#}", client.id);
+                        Ok((ErrorReply::Request(error), MessageKind::RequestError))
+                    }
+                    (None, MessageHandleError::Execution(err)) => {
+                        log::error!("reducer execution error: {err:#}");
+                        Ok((ErrorReply::Failed(err), MessageKind::TransactionUpdate))
+                    }
+                    (None, e) => Err(e),
+                };
+                let e = match reply {
+                    Ok((reply, kind)) => {
+                        // Serialize the message and keep a handle to the buffer.
+                        let encoded = encode_for_client(client, encode_error_policy, msg_buffer, reply, kind);
+                        let (msg_alloc, msg_data) = match encoded {
+                            EncodeOutcome::Encoded(msg_alloc, msg_data)
+                            | EncodeOutcome::Dropped(msg_alloc, msg_data) => (msg_alloc, msg_data),
+                            EncodeOutcome::Close(buf) => {
+                                msg_buffer = buf;
+                                let frame = encode_error_close_frame(kind, error_format);
+                                match close_ws(&mut ws, frame, teardown.begin()).await {
+                                    Ok(Err(e)) => {
+                                        log::warn!("error closing websocket: {e:#}")
+                                    }
+                                    Err(e) => {
+                                        log::warn!("send timed out after: {e}");
+                                        break CloseCause::Error;
+                                    }
+                                    _ => {}
+                                }
+                                closed = true;
+                                close_cause.get_or_insert(CloseCause::Error);
+                                continue;
+                            }
+                        };
+
+                        let send = async { ws.send(datamsg_to_wsmsg(msg_data)).await };
+                        let send = tokio::time::timeout_at(teardown.send_deadline(), send);
+
+                        match send.await {
+                            Ok(Err(error)) => {
+                                log::warn!("Websocket send error: {error}")
+                            }
+                            Err(error) => {
+                                log::warn!("send timed out after: {error}");
+                                let frame = disconnect_close_frame(DisconnectReason::SendTimeout, error_format);
+                                close_before_dropping(&mut ws, frame, teardown.last_close_deadline()).await;
+                                break CloseCause::Unresponsive;
+                            }
+                            _ => {}
+                        }
+
+                        // At this point,
+                        // the underlying allocation of `msg_data` should have a single referent
+                        // and this should be `msg_alloc`.
+                        // We can put this back into our pool.
+                        msg_buffer = msg_alloc
+                            .try_reclaim()
+                            .expect("should have a unique referent to `msg_alloc`");
+
+                        continue;
+                    }
+                    Err(e) => e,
+                };
+                log::warn!("Client caused error on text message: {}", e);
+                let error = format!("{e:#}");
+                let frame = close_frame(error_format, CloseCode::Error, &InvalidMessageClose::new(&error));
+                match close_ws(&mut ws, frame, teardown.begin()).await {
+                    Ok(Err(e)) => {
+                        log::warn!("error closing websocket: {e:#}")
+                    }
+                    Err(e) => {
+                        log::warn!("send timed out after: {e}");
+                        break CloseCause::Error;
+                    }
+                    _ => {}
+                }
+                close_cause.get_or_insert(CloseCause::Error);
+            }
+            Item::TooLarge => {
+                log::warn!(
+                    "client {} sent a message over the limit of {} bytes",
+                    client.id,
+                    size_limit.limit
+                );
+                WORKER_METRICS.ws_oversized_messages.with_label_values(&addr).inc();
+                // The connection is closed regardless, but the violation still counts.
+                violation_budget.record(client, ProtocolViolation::Oversized);
+                let frame = size_limit.close_frame(error_format);
+                match close_ws(&mut ws, frame, teardown.begin()).await {
+                    Ok(Err(e)) => {
+                        log::warn!("error closing websocket: {e:#}")
+                    }
+                    Err(e) => {
+                        log::warn!("send timed out after: {e}");
+                        break CloseCause::Error;
+                    }
+                    _ => {}
+                }
+                closed = true;
+                close_cause.get_or_insert(CloseCause::Error);
+            }
+            Item::QueueOverflow => {
+                log::warn!(
+                    "client {} has too many messages waiting to be handled, closing",
+                    client.id
+                );
+                WORKER_METRICS
+                    .ws_incoming_queue_overflows
+                    .with_label_values(&addr, "closed")
+                    .inc();
+                let frame = queue_limit.close_frame(error_format);
+                match close_ws(&mut ws, frame, teardown.begin()).await {
+                    Ok(Err(e)) => {
+                        log::warn!("error closing websocket: {e:#}")
+                    }
+                    Err(e) => {
+                        log::warn!("send timed out after: {e}");
+                        break CloseCause::Error;
+                    }
+                    _ => {}
+                }
+                closed = true;
+                close_cause.get_or_insert(CloseCause::Error);
+            }
+            Item::TooManyViolations(violations) => {
+                log::warn!(
+                    "client {} committed {violations} protocol violations, closing",
+                    client.id
+                );
+                let frame = ViolationBudget::close_frame(violations, error_format);
+                match close_ws(&mut ws, frame, teardown.begin()).await {
+                    Ok(Err(e)) => {
+                        log::warn!("error closing websocket: {e:#}")
+                    }
+                    Err(e) => {
+                        log::warn!("send timed out after: {e}");
+                        break CloseCause::Error;
+                    }
+                    _ => {}
+                }
+                closed = true;
+                close_cause.get_or_insert(CloseCause::Error);
+            }
+            Item::Message(ClientMessage::Ping(_message)) => {
+                log::trace!("Received ping from client {}", client.id);
+                // No need to explicitly respond with a `Pong`, as tungstenite handles this automatically.
+                // See [https://github.com/snapview/tokio-tungstenite/issues/88].
+            }
+            Item::Message(ClientMessage::Pong(message)) => {
+                log::trace!("Received heartbeat from client {}", client.id);
+                got_pong = true;
+                match health.pong_received(Instant::now(), &message) {
+                    Some(round_trip_time) => client.record_round_trip_time(round_trip_time),
+                    None => log::trace!("Skipping the round trip time of a pong from client {}", client.id),
+                }
+            }
+            Item::Message(ClientMessage::Close(close_frame)) => {
+                // This happens in 2 cases:
+                // a) We sent a Close frame and this is the ack.
+                // b) This is the client telling us they want to close.
+                // in either case, after the remaining messages in the queue flush,
+                // ws.next() will return None and we'll exit the loop.
+                // NOTE: No need to send a close frame, it's is queued
+                //       automatically by tungstenite.
+
+                // if this is the closed-by-them case, let the ClientConnectionSenders know now.
+                sendrx.close();
+                teardown.begin();
+                log::trace!("Close frame {:?}", close_frame);
+                if !closed {
+                    // This is the client telling us they want to close.
+                    WORKER_METRICS
+                        .ws_clients_closed_connection
+                        .with_label_values(&addr)
+                        .inc();
+                }
+
+                // Can't we just break out of the loop here?
+                // Not, if we want tungstenite to send a close frame back to the client.
+                // That will only happen once `ws.next()` returns `None`.
+                closed = true;
+                close_cause.get_or_insert(CloseCause::ClientClosed);
+            }
+        }
+    };
+    log::debug!("Client connection ended");
+    cause
+}
+
+/// Whether `error` means the connection dropped without being closed, e.g. as the client's network went away.
+fn is_connection_lost(error: &WsError) -> bool {
+    matches!(
+        error,
+        WsError::Io(_) | WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake)
+    )
+}
+
+/// The reason of the close frame sent when a client's module exits, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct ModuleExitClose {
+    pub reason: &'static str,
+    /// One of `crash`, `publish` or `delete`.
+    pub cause: &'static str,
+    pub retry: RetryHint,
+}
+
+impl From<ModuleExitCause> for ModuleExitClose {
+    fn from(cause: ModuleExitCause) -> Self {
+        let retry = match cause {
+            ModuleExitCause::Crashed => RetryHint::Backoff,
+            ModuleExitCause::Published => RetryHint::Immediately,
+            ModuleExitCause::Deleted => RetryHint::Never,
+        };
+        Self {
+            reason: "module exited",
+            cause: cause.as_str(),
+            retry,
+        }
+    }
+}
+
+fn module_exit_close_frame(cause: ModuleExitCause, format: ErrorFormat) -> CloseFrame {
+    let code = disconnect_code(format, DisconnectReason::ModuleExited, CloseCode::Away);
+    close_frame(format, code, &ModuleExitClose::from(cause))
+}
+
+fn close_frame_for(reason: CloseReason, format: ErrorFormat) -> CloseFrame {
+    match reason {
+        CloseReason::Overloaded => close_frame(format, CloseCode::Again, &ReasonClose::new("node overloaded")),
+        CloseReason::Denied => close_frame(format, CloseCode::Policy, &ReasonClose::new("access denied")),
+        CloseReason::KickedByModule(message) => close_frame(format, CloseCode::Policy, &KickedClose::new(&message)),
+        CloseReason::KickedByAdmin(message) => {
+            let code = disconnect_code(format, DisconnectReason::KickedByAdmin, CloseCode::Policy);
+            close_frame(format, code, &AdminKickedClose::new(message.as_deref()))
+        }
+        CloseReason::MaxLifetimeReached => close_frame(format, CloseCode::Away, &MaxLifetimeClose::default()),
+        CloseReason::HandshakeFailed(error) => {
+            close_frame(format, CloseCode::Error, &HandshakeFailedClose::new(&error))
+        }
+        CloseReason::Draining { retry_after } => close_frame(format, CloseCode::Away, &DrainingClose::new(retry_after)),
+        CloseReason::Replaced => close_frame(format, CloseCode::Policy, &ReplacedClose::default()),
+        CloseReason::AuthExpired => disconnect_close_frame(DisconnectReason::AuthExpired, format),
+    }
+}
+
+/// The most bytes the reason of a close frame may have.
+const MAX_CLOSE_REASON_LEN: usize = 123;
+
+/// The payload of a close frame, sent to the client encoded as JSON,
+/// or, under [`ErrorFormat::Legacy`], as the plain text older versions sent.
+trait ClosePayload: Serialize {
+    /// The reason sent under [`ErrorFormat::Legacy`].
+    fn legacy_reason(&self) -> &str;
+
+    /// The reason sent under [`ErrorFormat::Structured`].
+    fn to_payload(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Implement [`ClosePayload`] for payloads which older versions sent as their `reason` alone.
+macro_rules! impl_close_payload {
+    ($($ty:ty),* $(,)?) => {
+        $(impl ClosePayload for $ty {
+            fn legacy_reason(&self) -> &str {
+                self.reason
+            }
+        })*
+    };
+}
+
+impl_close_payload!(
+    ReasonClose,
+    ModuleExitClose,
+    MaxLifetimeClose,
+    DrainingClose,
+    ReplacedClose,
+    ConnectFailedClose,
+    HandshakeFailedClose<'_>,
+    MessageTooLargeClose,
+    TooManyViolationsClose,
+    QueueOverflowClose,
+    EncodeErrorClose,
+);
+
+/// The close frame with `code` and `payload`, formatted as `format`.
+///
+/// Every close frame sent to a client is built here, so that all of them honor its [`ErrorFormat`].
+fn close_frame(format: ErrorFormat, code: CloseCode, payload: &impl ClosePayload) -> CloseFrame {
+    let reason = match format {
+        ErrorFormat::Legacy => payload.legacy_reason().to_owned(),
+        ErrorFormat::Structured => payload.to_payload(),
+    };
+    CloseFrame {
+        code,
+        reason: reason.into(),
+    }
+}
+
+/// The code of the close frames sent for `reason`,
+/// or, under [`ErrorFormat::Legacy`], the generic `legacy` code older versions sent.
+fn disconnect_code(format: ErrorFormat, reason: DisconnectReason, legacy: CloseCode) -> CloseCode {
+    match format {
+        ErrorFormat::Legacy => legacy,
+        ErrorFormat::Structured => CloseCode::Library(reason.code()),
+    }
+}
+
+/// The close frame sent when a connection is dropped for `reason`, which carries nothing else.
+fn disconnect_close_frame(reason: DisconnectReason, format: ErrorFormat) -> CloseFrame {
+    let (text, legacy) = match reason {
+        DisconnectReason::LivenessTimeout => ("liveness timeout", CloseCode::Away),
+        DisconnectReason::SendTimeout => ("send timeout", CloseCode::Away),
+        DisconnectReason::OutOfEnergy => ("out of energy", CloseCode::Policy),
+        DisconnectReason::AuthExpired => ("auth expired", CloseCode::Policy),
+        // Usually sent with more detail by close frames of their own.
+        DisconnectReason::ModuleExited => ("module exited", CloseCode::Away),
+        DisconnectReason::KickedByAdmin => ("kicked by admin", CloseCode::Policy),
+        DisconnectReason::MessageTooLarge => ("message too large", CloseCode::Size),
+        DisconnectReason::QueueOverflow => (
+            "too many queued messages",
+            CloseCode::Library(DisconnectReason::QueueOverflow.code()),
+        ),
+        DisconnectReason::ConnectionDegraded => ("connection degraded", CloseCode::Away),
+        DisconnectReason::TooSlow => ("too slow", CloseCode::Away),
+    };
+    close_frame(format, disconnect_code(format, reason, legacy), &ReasonClose::new(text))
+}
+
+/// Encode the payload built from `text` as JSON,
+/// truncating `text` until the payload fits into a close frame.
+fn truncated_payload<'a, P: Serialize>(mut text: &'a str, payload: impl Fn(&'a str) -> P) -> String {
+    loop {
+        let encoded = serde_json::to_string(&payload(text)).unwrap();
+        if encoded.len() <= MAX_CLOSE_REASON_LEN {
+            return encoded;
+        }
+        let mut end = text.len() - 1;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text = &text[..end];
+    }
+}
+
+/// The reason of a close frame which carries nothing else, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct ReasonClose {
+    pub reason: &'static str,
+}
+
+impl ReasonClose {
+    fn new(reason: &'static str) -> Self {
+        Self { reason }
+    }
+}
+
+/// The reason of the close frame sent when a client sends a message which can't be handled, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct InvalidMessageClose<'a> {
+    pub reason: &'static str,
+    /// Why the message couldn't be handled,
+    /// truncated to fit into the close frame.
+    pub error: &'a str,
+}
+
+impl<'a> InvalidMessageClose<'a> {
+    fn new(error: &'a str) -> Self {
+        Self {
+            reason: "invalid message",
+            error,
+        }
+    }
+}
+
+impl ClosePayload for InvalidMessageClose<'_> {
+    /// The error alone, in full.
+    fn legacy_reason(&self) -> &str {
+        self.error
+    }
+
+    fn to_payload(&self) -> String {
+        truncated_payload(self.error, |error| InvalidMessageClose { error, ..*self })
+    }
+}
+
+/// The reason of the close frame sent when a client's identity token couldn't be sent, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct HandshakeFailedClose<'a> {
+    pub reason: &'static str,
+    /// Why the identity token couldn't be sent, e.g. `disconnected`.
+    pub error: &'a str,
+    pub retry: RetryHint,
+}
+
+impl<'a> HandshakeFailedClose<'a> {
+    fn new(error: &'a ClientSendError) -> Self {
+        Self {
+            reason: "handshake failed",
+            error: error.as_ref(),
+            retry: RetryHint::Backoff,
+        }
+    }
+}
+
+/// The reason of the close frame sent when a connection reaches its maximum lifetime, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct MaxLifetimeClose {
+    pub reason: &'static str,
+    pub retry: RetryHint,
+}
+
+impl Default for MaxLifetimeClose {
+    fn default() -> Self {
+        Self {
+            reason: "max lifetime reached",
+            retry: RetryHint::Immediately,
+        }
+    }
+}
+
+/// The reason of the close frame sent when the node drains its connections, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct DrainingClose {
+    pub reason: &'static str,
+    pub retry: RetryHint,
+    /// How many seconds the client should wait before reconnecting, if the node knows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+impl DrainingClose {
+    fn new(retry_after: Option<Duration>) -> Self {
+        Self {
+            reason: "server draining",
+            retry: RetryHint::Backoff,
+            retry_after_secs: retry_after.map(|after| after.as_secs()),
+        }
+    }
+}
+
+/// The reason of the close frame sent when a newer connection takes over a connection's id, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct ReplacedClose {
+    pub reason: &'static str,
+    pub retry: RetryHint,
+}
+
+impl Default for ReplacedClose {
+    fn default() -> Self {
+        Self {
+            reason: "connection replaced",
+            retry: RetryHint::Never,
+        }
+    }
+}
+
+/// The reason of the close frame sent when a client couldn't be connected to its module,
+/// even after retrying, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct ConnectFailedClose {
+    pub reason: &'static str,
+    pub retry: RetryHint,
+}
+
+impl Default for ConnectFailedClose {
+    fn default() -> Self {
+        Self {
+            reason: "module unavailable",
+            retry: RetryHint::Backoff,
+        }
+    }
+}
+
+/// The reason of the close frame sent when a module kicks a client, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct KickedClose<'a> {
+    pub reason: &'static str,
+    /// The reason the module gave for kicking the client,
+    /// truncated to fit into the close frame.
+    pub message: &'a str,
+}
+
+impl<'a> KickedClose<'a> {
+    fn new(message: &'a str) -> Self {
+        Self {
+            reason: "kicked by module",
+            message,
+        }
+    }
+}
+
+impl ClosePayload for KickedClose<'_> {
+    fn legacy_reason(&self) -> &str {
+        self.reason
+    }
+
+    /// Encode as JSON, truncating the message until it fits into a close frame.
+    fn to_payload(&self) -> String {
+        truncated_payload(self.message, |message| KickedClose { message, ..*self })
+    }
+}
+
+/// The reason of the close frame sent when the owner of a client's database kicks it, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct AdminKickedClose<'a> {
+    pub reason: &'static str,
+    /// The reason the owner gave for kicking the client, if any,
+    /// truncated to fit into the close frame.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<&'a str>,
+}
+
+impl<'a> AdminKickedClose<'a> {
+    fn new(message: Option<&'a str>) -> Self {
+        Self {
+            reason: "kicked by admin",
+            message,
+        }
+    }
+}
+
+impl ClosePayload for AdminKickedClose<'_> {
+    fn legacy_reason(&self) -> &str {
+        self.reason
+    }
+
+    /// Encode as JSON, truncating the message, if any, until it fits into a close frame.
+    fn to_payload(&self) -> String {
+        match self.message {
+            Some(message) => truncated_payload(message, |message| AdminKickedClose {
+                message: Some(message),
+                ..*self
+            }),
+            None => serde_json::to_string(self).unwrap(),
+        }
+    }
+}
+
+enum ClientMessage {
+    Message(DataMessage),
+    Ping(Bytes),
+    Pong(Bytes),
+    Close(Option<CloseFrame>),
+}
+impl ClientMessage {
+    /// Convert a message read from a client's websocket,
+    /// failing if it's a text frame which isn't valid UTF-8, see [`utf8bytes_to_bytestring`].
+    fn from_message(msg: WsMessage) -> Result<Self, Utf8Error> {
+        Ok(match msg {
+            WsMessage::Text(s) => Self::Message(DataMessage::Text(utf8bytes_to_bytestring(s)?)),
+            WsMessage::Binary(b) => Self::Message(DataMessage::Binary(b)),
+            WsMessage::Ping(b) => Self::Ping(b),
+            WsMessage::Pong(b) => Self::Pong(b),
+            WsMessage::Close(frame) => Self::Close(frame),
+            // WebSocket::read_message() never returns a raw Message::Frame
+            WsMessage::Frame(_) => unreachable!(),
+        })
+    }
+}
+
+/// Report metrics on sent rows and message sizes to a websocket client.
+fn report_ws_sent_metrics(
+    addr: &Identity,
+    workload: Option<WorkloadType>,
+    kind: MessageKind,
+    num_rows: Option<usize>,
+    msg_ws: &DataMessage,
+) {
+    // These metrics should be updated together,
+    // or not at all.
+    if let (Some(workload), Some(num_rows)) = (workload, num_rows) {
+        WORKER_METRICS
+            .websocket_sent_num_rows
+            .with_label_values(addr, &workload, &kind)
+            .observe(num_rows as f64);
+        WORKER_METRICS
+            .websocket_sent_msg_size
+            .with_label_values(addr, &workload, &kind)
+            .observe(msg_ws.len() as f64);
+    }
+}
+
+/// Record how long it took the update of a transaction which committed at `committed_at`
+/// to get from the commit to the client's socket.
+fn report_update_delivery_latency(addr: &Identity, committed_at: Timestamp) {
+    // The wall clock may have gone backwards since the commit.
+    if let Some(latency) = Timestamp::now().duration_since(committed_at) {
+        WORKER_METRICS
+            .update_delivery_latency_seconds
+            .with_label_values(addr)
+            .observe(latency.as_secs_f64());
+    }
+}
+
+/// Accumulated while sending a batch of messages to a client.
+#[derive(Default)]
+struct SendStats {
+    /// Time spent serializing the messages in the batch.
+    serialize_time: Duration,
+    /// Total size of the serialized messages.
+    bytes: usize,
+    /// The kind of message which failed to encode, cutting the batch short,
+    /// if the connection is to be closed for it.
+    encode_failed: Option<MessageKind>,
+}
+
+/// A message encoded for a client by [`encode_for_client`].
+enum EncodeOutcome {
+    /// The message itself.
+    Encoded(InUseSerializeBuffer, DataMessage),
+    /// A `MessageDropped` in place of the message, which failed to encode.
+    Dropped(InUseSerializeBuffer, DataMessage),
+    /// The message failed to encode, and the connection is to be closed with [`encode_error_close_frame`].
+    /// The buffer is handed back for reuse.
+    Close(SerializeBuffer),
+}
+
+/// Encode `msg`, of `kind`, into `buffer` for `client`,
+/// handling a failure to encode it according to `policy`.
+fn encode_for_client(
+    client: &ClientConnection,
+    policy: EncodeErrorPolicy,
+    buffer: SerializeBuffer,
+    msg: impl ToProtocol<Encoded = SwitchedServerMessage>,
+    kind: MessageKind,
+) -> EncodeOutcome {
+    let encoded = client.codec().serialize(buffer, msg, client.config().compression);
+    encoded_for_client(client, policy, encoded, kind)
+}
+
+/// Encode `msg` as [`encode_for_client`] does,
+/// but on the blocking pool should it be estimated to be at least `offload_threshold` bytes,
+/// see [`WebSocketOptions::encode_offload_threshold`].
+///
+/// Should another client have already encoded the same copy of a broadcast `msg`, its encoding is reused,
+/// see [`SharedEncoding`](spacetimedb::client::messages::SharedEncoding).
+async fn encode_for_client_offloading(
+    client: &ClientConnection,
+    policy: EncodeErrorPolicy,
+    buffer: SerializeBuffer,
+    msg: SerializableMessage,
+    kind: MessageKind,
+    offload_threshold: usize,
+) -> EncodeOutcome {
+    let Some(shared) = msg.shared_encoding().cloned() else {
+        return encode_unshared(client, policy, buffer, msg, kind, offload_threshold).await;
+    };
+    if let Some(msg_data) = shared.get() {
+        WORKER_METRICS
+            .ws_encodes_shared
+            .with_label_values(&client.module.info().database_identity)
+            .inc();
+        return EncodeOutcome::Encoded(InUseSerializeBuffer::Unused(buffer), msg_data);
+    }
+    let encoded = encode_unshared(client, policy, buffer, msg, kind, offload_threshold).await;
+    if let EncodeOutcome::Encoded(_, msg_data) = &encoded {
+        shared.set(msg_data);
+    }
+    encoded
+}
+
+/// Encode `msg` for `client` alone, see [`encode_for_client_offloading`].
+async fn encode_unshared(
+    client: &ClientConnection,
+    policy: EncodeErrorPolicy,
+    buffer: SerializeBuffer,
+    msg: SerializableMessage,
+    kind: MessageKind,
+    offload_threshold: usize,
+) -> EncodeOutcome {
+    if msg.size_hint() < offload_threshold {
+        return encode_for_client(client, policy, buffer, msg, kind);
+    }
+    WORKER_METRICS
+        .ws_encodes_offloaded
+        .with_label_values(&client.module.info().database_identity)
+        .inc();
+    let encoded = client
+        .codec()
+        .serialize_blocking(buffer, msg, client.config().compression)
+        .await;
+    encoded_for_client(client, policy, encoded, kind)
+}
+
+/// Hand on the outcome of encoding a message of `kind` for `client`,
+/// handling a failure to encode it according to `policy`.
+fn encoded_for_client(
+    client: &ClientConnection,
+    policy: EncodeErrorPolicy,
+    encoded: EncodeResult,
+    kind: MessageKind,
+) -> EncodeOutcome {
+    let compression = client.config().compression;
+    let EncodeFailure { buffer, error } = match encoded {
+        Ok((msg_alloc, msg_data)) => return EncodeOutcome::Encoded(msg_alloc, msg_data),
+        Err(failure) => failure,
+    };
+    log::error!("failed to encode `{}` for client {}: {error}", kind.as_ref(), client.id);
+    WORKER_METRICS
+        .ws_encode_errors
+        .with_label_values(&client.module.info().database_identity, &kind)
+        .inc();
+    match policy {
+        EncodeErrorPolicy::Close => EncodeOutcome::Close(buffer),
+        EncodeErrorPolicy::Skip => {
+            let dropped = MessageDroppedMessage {
+                reason: ws_api::MessageDropReason::EncodeError,
+                message_kind: kind.as_ref().into(),
+            };
+            match client.codec().serialize(buffer, dropped, compression) {
+                Ok((msg_alloc, msg_data)) => EncodeOutcome::Dropped(msg_alloc, msg_data),
+                // Without a way to tell the client what it's missing, give up on the connection.
+                Err(EncodeFailure { buffer, error }) => {
+                    log::error!("failed to encode `MessageDropped` for client {}: {error}", client.id);
+                    EncodeOutcome::Close(buffer)
+                }
+            }
+        }
+    }
+}
+
+/// The close frame sent when a message of `kind` fails to encode,
+/// under [`EncodeErrorPolicy::Close`].
+fn encode_error_close_frame(kind: MessageKind, format: ErrorFormat) -> CloseFrame {
+    let payload = EncodeErrorClose {
+        reason: "failed to encode message",
+        message_kind: kind.into(),
+    };
+    close_frame(format, CloseCode::Error, &payload)
+}
+
+/// The reason of the close frame sent when a message to the client fails to encode, encoded as JSON.
+#[derive(Serialize, Debug)]
+pub struct EncodeErrorClose {
+    pub reason: &'static str,
+    /// The kind of the message which failed to encode, e.g. `"TransactionUpdate"`.
+    pub message_kind: &'static str,
+}
+
+fn report_ws_send_timings(addr: &Identity, batch_size: usize, total: Duration, serialize: Duration, write: Duration) {
+    // Batches hold at most 32 messages, see the `recv_many` in `ws_client_actor_inner`.
+    let batch_size_bucket = match batch_size {
+        0..=1 => "1",
+        2..=4 => "2-4",
+        5..=8 => "5-8",
+        9..=16 => "9-16",
+        _ => "17+",
+    };
+    WORKER_METRICS
+        .ws_send_flush_seconds
+        .with_label_values(addr, batch_size_bucket)
+        .observe(total.as_secs_f64());
+    WORKER_METRICS
+        .ws_send_serialize_seconds
+        .with_label_values(addr)
+        .observe(serialize.as_secs_f64());
+    WORKER_METRICS
+        .ws_send_write_seconds
+        .with_label_values(addr)
+        .observe(write.as_secs_f64());
+}
+
+/// Buffer `msg_data` to be sent over `ws`, without necessarily sending it,
+/// and put the buffer `msg_alloc` it was encoded into back into our pool,
+/// whether or not it was buffered, e.g. as the connection broke in the middle of a frame.
+async fn feed_reclaiming<S: AsyncRead + AsyncWrite + Unpin>(
+    ws: &mut tokio_tungstenite::WebSocketStream<S>,
+    msg_alloc: InUseSerializeBuffer,
+    msg_data: DataMessage,
+    config: ClientConfig,
+) -> (Result<(), WsError>, SerializeBuffer) {
+    let res = ws.feed(datamsg_to_wsmsg(msg_data)).await;
+
+    // At this point,
+    // the underlying allocation of `msg_data` should have a single referent
+    // and this should be `msg_alloc`,
+    // unless the message's encoding is shared with other clients yet to send it,
+    // in which case they get to keep the allocation, and we start on a new one.
+    let msg_buffer = msg_alloc.try_reclaim().unwrap_or_else(|| SerializeBuffer::new(config));
+    (res, msg_buffer)
+}
+
+fn datamsg_to_wsmsg(msg: DataMessage) -> WsMessage {
+    match msg {
+        DataMessage::Text(text) => WsMessage::Text(bytestring_to_utf8bytes(text)),
+        DataMessage::Binary(bin) => WsMessage::Binary(bin),
+    }
+}
+
+/// Convert a text frame read from a client's websocket, validating that it's UTF-8.
+///
+/// Whether tungstenite validates the text frames it reads has varied across its versions and configurations,
+/// so that's not relied upon, unless the `unchecked-utf8` feature is enabled.
+#[cfg(not(feature = "unchecked-utf8"))]
+fn utf8bytes_to_bytestring(s: Utf8Bytes) -> Result<ByteString, Utf8Error> {
+    ByteString::try_from(Bytes::from(s))
+}
+#[cfg(feature = "unchecked-utf8")]
+fn utf8bytes_to_bytestring(s: Utf8Bytes) -> Result<ByteString, Utf8Error> {
+    // SAFETY: With `unchecked-utf8`, the deployment has verified that tungstenite validates text frames,
+    // so `Utf8Bytes` and `ByteString` have the same invariant of UTF-8 validity.
+    Ok(unsafe { ByteString::from_bytes_unchecked(Bytes::from(s)) })
+}
+
+fn bytestring_to_utf8bytes(s: ByteString) -> Utf8Bytes {
+    // SAFETY: `Utf8Bytes` and `ByteString` have the same invariant of UTF-8 validity
+    unsafe { Utf8Bytes::from_bytes_unchecked(s.into_bytes()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::shaped_network::{NetworkConditions, TestWsClient};
+    use spacetimedb::client::messages::{SubscriptionUpdateMessage, TransactionUpdateMessage, UpdateDetail};
+    use spacetimedb::client::{
+        ClientAddress, ConnectionLimits, LoadAdmission, LoadAdmissionConfig, LoadThresholds, SendQueueCapacity,
+    };
+    use spacetimedb::energy::EnergyQuanta;
+    use spacetimedb::host::module_host::{EventStatus, ModuleEvent, ModuleFunctionCall};
+    use spacetimedb::messages::control_db::{Database, MessageSizeLimits, NetworkAcl};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    /// An example embedder of the subscribe route,
+    /// which has neither a control state nor hosts to speak of,
+    /// and provides only the capabilities the route depends on.
+    ///
+    /// It swaps out just how clients are admitted:
+    /// while under maintenance, it refuses every new client,
+    /// and otherwise admits them as the node's connection limits allow.
+    #[derive(Clone)]
+    struct MaintenanceGate {
+        index: Arc<ClientActorIndex>,
+        under_maintenance: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl ClientActors for MaintenanceGate {
+        fn actor_index(&self) -> &ClientActorIndex {
+            &self.index
+        }
+
+        fn admit_client(&self) -> Result<AdmissionPermit, NodeOverloaded> {
+            if self.under_maintenance.load(Ordering::Relaxed) {
+                return Err(NodeOverloaded {
+                    limit: 0,
+                    retry_after: Duration::from_secs(60),
+                });
+            }
+            self.index.try_admit()
+        }
+
+        async fn validate_client_token(&self, _token: &str) -> Result<SpacetimeIdentityClaims, TokenValidationError> {
+            Err(anyhow::anyhow!("re-authenticating is not supported").into())
+        }
+    }
+
+    impl DatabaseResolution for MaintenanceGate {
+        fn resolve_database_name(&self, _name: &str) -> anyhow::Result<Option<Identity>> {
+            Ok(None)
+        }
+
+        fn find_database(&self, _database_identity: &Identity) -> anyhow::Result<Option<Database>> {
+            Ok(None)
+        }
+
+        fn find_network_acl(&self, _database_identity: &Identity) -> anyhow::Result<Option<NetworkAcl>> {
+            Ok(None)
+        }
+
+        fn find_client_config_defaults(
+            &self,
+            _database_identity: &Identity,
+        ) -> anyhow::Result<Option<ClientConfigDefaults>> {
+            Ok(None)
+        }
+
+        fn find_connection_timeouts(
+            &self,
+            _database_identity: &Identity,
+        ) -> anyhow::Result<Option<ConnectionTimeouts>> {
+            Ok(None)
+        }
+
+        fn find_message_size_limits(&self, _database_identity: &Identity) -> anyhow::Result<Option<MessageSizeLimits>> {
+            Ok(None)
+        }
+
+        fn find_send_backlog_policy(&self, _database_identity: &Identity) -> anyhow::Result<Option<SendBacklogPolicy>> {
+            Ok(None)
+        }
+
+        fn find_auth_expiry_policy(&self, _database_identity: &Identity) -> anyhow::Result<Option<AuthExpiryPolicy>> {
+            Ok(None)
+        }
+
+        fn find_database_features(&self, _database_identity: &Identity) -> anyhow::Result<Option<DatabaseFeatures>> {
+            Ok(None)
+        }
+    }
+
+    #[async_trait]
+    impl LeaderLookup for MaintenanceGate {
+        async fn find_leader_host(&self, _database_id: u64) -> anyhow::Result<Option<crate::Host>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn connection_ids_never_collide_with_connected_clients() {
+        let index = ClientActorIndex::new();
+        let connected = ClientActorId {
+            connection_id: ConnectionId::from_u128(42),
+            ..ClientActorId::for_test(Identity::ZERO)
+        };
+        let sender = ClientConnectionSender::dummy(connected, ClientConfig::for_test());
+        let _registration = index.register(Arc::new(sender), Identity::ZERO, None);
+
+        let (db, other_db) = (Identity::ZERO, Identity::ONE);
+        let reserve = |database_identity, identity, connection_id| {
+            reserve_client_connection_id(&index, database_identity, identity, connection_id)
+        };
+        let status = |res: axum::response::Result<ConnectionIdReservation>| {
+            axum::response::IntoResponse::into_response(res.map(|_| ())).status()
+        };
+
+        // A client can't take over the connection id of a client of its identity connected to the database...
+        assert_eq!(
+            status(reserve(db, Identity::ZERO, connected.connection_id)),
+            StatusCode::CONFLICT
+        );
+        assert!(check_client_connection_id(ConnectionId::ZERO).is_err());
+        // ...nor one reserved by a client connecting concurrently...
+        let fresh = generate_random_connection_id();
+        let reservation = reserve(db, Identity::ZERO, fresh).unwrap();
+        assert_eq!(reservation.connection_id(), fresh);
+        assert_eq!(status(reserve(db, Identity::ZERO, fresh)), StatusCode::CONFLICT);
+        drop(reservation);
+        // ...though one of another identity may share it,
+        // as may one of the same identity connecting to another database, as an SDK's clients in one process do.
+        assert!(reserve(db, Identity::ONE, connected.connection_id).is_ok());
+        assert!(reserve(other_db, Identity::ZERO, connected.connection_id).is_ok());
+
+        // Nor is a client assigned it, even by a generator which keeps colliding.
+        let mut collisions = 3;
+        let generated = index.generate_connection_id(|| {
+            if collisions == 0 {
+                return fresh;
+            }
+            collisions -= 1;
+            connected.connection_id
+        });
+        assert_eq!(generated, fresh);
+        assert_eq!(collisions, 0);
+    }
+
+    #[test]
+    fn embedders_can_swap_out_just_admission() {
+        let gate = MaintenanceGate {
+            index: Arc::new(ClientActorIndex::new()),
+            under_maintenance: Arc::new(true.into()),
+        };
+        // The route is served from the embedder's state...
+        let _router: axum::Router = axum::Router::new()
+            .route(
+                "/database/:name_or_identity/subscribe",
+                axum::routing::get(handle_websocket::<MaintenanceGate>),
+            )
+            .with_state(gate.clone());
+
+        // ...which decides which clients are admitted.
+        let err = admit(&gate).unwrap_err();
+        assert_eq!(
+            axum::response::IntoResponse::into_response(Err::<(), _>(err)).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        gate.under_maintenance.store(false, Ordering::Relaxed);
+        assert!(admit(&gate).is_ok());
+    }
+
+    #[test]
+    fn new_clients_are_refused_under_load() {
+        let config = LoadAdmissionConfig {
+            shed_upgrades: LoadThresholds {
+                queued_messages: Some(0),
+                ..<_>::default()
+            },
+            ..<_>::default()
+        };
+        let gate = MaintenanceGate {
+            index: Arc::new(ClientActorIndex::new().with_load_admission(LoadAdmission::new(config).unwrap())),
+            under_maintenance: Arc::new(false.into()),
+        };
+        let response = axum::response::IntoResponse::into_response(Err::<(), _>(admit(&gate).unwrap_err()));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+
+        // Once the thresholds are lifted, clients are admitted again.
+        gate.index
+            .load_admission()
+            .reload(LoadAdmissionConfig::default())
+            .unwrap();
+        assert!(admit(&gate).is_ok());
+    }
+
+    #[test]
+    fn clients_over_their_address_limit_are_refused() {
+        let index = ClientActorIndex::with_limits(ConnectionLimits {
+            per_ip_limit: Some(1),
+            retry_after: Duration::from_secs(7),
+            ..<_>::default()
+        });
+        let ip = Some(std::net::IpAddr::from([10, 0, 0, 1]));
+        let client_addr = ClientAddr(ClientAddress { ip, peer: ip });
+        let permit = admit_address(&index, client_addr).unwrap();
+        let response =
+            axum::response::IntoResponse::into_response(Err::<(), _>(admit_address(&index, client_addr).unwrap_err()));
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "7");
+
+        // Once the connection is gone, however far it got, its address may connect again.
+        drop(permit);
+        assert!(admit_address(&index, client_addr).is_ok());
+    }
+
+    #[tokio::test]
+    async fn last_close_is_bounded_by_the_teardown_deadline() {
+        let mut teardown = Teardown::new(LAST_CLOSE_TIMEOUT * 4, LAST_CLOSE_TIMEOUT);
+        // The last close frame gets its own timeout while there's time left...
+        let deadline = teardown.last_close_deadline();
+        assert!(deadline < teardown.deadline().unwrap());
+        assert!(deadline <= tokio::time::Instant::now() + LAST_CLOSE_TIMEOUT);
+
+        // ...but not once the teardown, e.g. begun as a send started, is nearly over.
+        let mut teardown = Teardown::new(LAST_CLOSE_TIMEOUT, LAST_CLOSE_TIMEOUT);
+        teardown.begin_at(Instant::now() - LAST_CLOSE_TIMEOUT / 2);
+        assert_eq!(teardown.last_close_deadline(), teardown.deadline().unwrap());
+    }
+
+    #[test]
+    fn sends_are_bounded_by_the_clients_send_timeout() {
+        const SEND_TIMEOUT: Duration = Duration::from_secs(20);
+        let mut teardown = Teardown::new(Duration::from_secs(5), SEND_TIMEOUT);
+
+        let before = tokio::time::Instant::now();
+        let deadline = teardown.send_deadline();
+        assert!(deadline >= before + SEND_TIMEOUT && deadline <= tokio::time::Instant::now() + SEND_TIMEOUT);
+        // Once the teardown has begun, its deadline is sooner.
+        let teardown_deadline = teardown.begin();
+        assert_eq!(teardown.send_deadline(), teardown_deadline);
+    }
+
+    #[tokio::test]
+    async fn nothing_from_a_retired_identity_is_received_after_the_switch() {
+        let token = |identity: Identity, token: &str| IdentityTokenMessage {
+            identity,
+            token: token.into(),
+            connection_id: ConnectionId::ZERO,
+        };
+        let config = ClientConfig::for_test();
+        let (old, mut sendrx) = ClientConnectionSender::dummy_with_capacity(
+            ClientActorId::for_test(Identity::ZERO),
+            config,
+            <_>::default(),
+        );
+        let (new, new_sendrx) =
+            ClientConnectionSender::dummy_with_capacity(ClientActorId::for_test(Identity::ONE), config, <_>::default());
+
+        // An update for the old identity was queued before the switch,
+        // which retires the old queue as in `ws_client_actor_inner`.
+        old.send_message(token(Identity::ZERO, "before")).unwrap();
+        new.send_message(token(Identity::ONE, "new")).unwrap();
+        sendrx.close();
+        let mut retired = Some(mem::replace(&mut sendrx, new_sendrx));
+        assert!(old.send_message(token(Identity::ZERO, "after")).is_err());
+
+        let mut buf = Vec::new();
+        while buf.len() < 2 {
+            recv_many_after_retired(&mut retired, &mut sendrx, &mut buf, 32).await;
+        }
+        assert!(retired.is_none());
+        let tokens: Vec<_> = buf
+            .iter()
+            .map(|msg| match msg {
+                SerializableMessage::Identity(msg) => &*msg.token,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(tokens, ["before", "new"]);
+        assert!(sendrx.is_empty());
+    }
+
+    #[tokio::test]
+    async fn module_exit_close_frame_carries_cause_and_retry_hint() {
+        for (cause, expected_cause, expected_retry) in [
+            (ModuleExitCause::Crashed, "crash", "backoff"),
+            (ModuleExitCause::Published, "publish", "immediately"),
+            (ModuleExitCause::Deleted, "delete", "never"),
+        ] {
+            let (server, client) = tokio::io::duplex(1024);
+            let mut server = tokio_tungstenite::WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+            let mut client = tokio_tungstenite::WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+            let (closed, received) = tokio::join!(
+                close_ws(
+                    &mut server,
+                    module_exit_close_frame(cause, ErrorFormat::Structured),
+                    deadline
+                ),
+                client.next()
+            );
+            assert!(closed.is_ok(), "sending the close frame timed out");
+
+            let Some(Ok(WsMessage::Close(Some(frame)))) = received else {
+                panic!("expected a close frame, got {received:?}");
+            };
+            assert_eq!(frame.code, CloseCode::Library(DisconnectReason::ModuleExited.code()));
+            let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+            assert_eq!(payload["reason"], "module exited");
+            assert_eq!(payload["cause"], expected_cause);
+            assert_eq!(payload["retry"], expected_retry);
+        }
+    }
+
+    #[test]
+    fn kicked_close_frame_carries_truncated_message() {
+        for (message, expected) in [
+            ("spam".to_owned(), "spam".to_owned()),
+            ("é".repeat(100), "é".repeat(40)),
+            ("\"".repeat(100), "\"".repeat(40)),
+        ] {
+            let frame = close_frame_for(CloseReason::KickedByModule(message.into()), ErrorFormat::Structured);
+            assert_eq!(frame.code, CloseCode::Policy);
+            assert!(frame.reason.len() <= 123);
+            let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+            assert_eq!(payload["reason"], "kicked by module");
+            assert_eq!(payload["message"], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn kicked_clients_are_sent_the_admins_reason() {
+        let index = ClientActorIndex::new();
+        let id = ClientActorId::for_test(Identity::ZERO);
+        let sender = Arc::new(ClientConnectionSender::dummy(id, ClientConfig::for_test()));
+        let _registration = index.register(sender.clone(), Identity::ONE, None);
+        assert!(index.kick(&Identity::ONE, id.identity, id.connection_id, Some("spam".into())));
+
+        let reason = tokio::time::timeout(Duration::from_secs(1), sender.close_requested())
+            .await
+            .expect("the connection should be asked to close");
+        let (server, client) = tokio::io::duplex(1024);
+        let mut server = tokio_tungstenite::WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut client = tokio_tungstenite::WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+        let (closed, received) = tokio::join!(
+            close_ws(&mut server, close_frame_for(reason, ErrorFormat::Structured), deadline),
+            client.next()
+        );
+        assert!(closed.is_ok(), "sending the close frame timed out");
+
+        let Some(Ok(WsMessage::Close(Some(frame)))) = received else {
+            panic!("expected a close frame, got {received:?}");
+        };
+        assert_eq!(frame.code, CloseCode::Library(DisconnectReason::KickedByAdmin.code()));
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "kicked by admin");
+        assert_eq!(payload["message"], "spam");
+
+        // Without a reason, there's no message.
+        let frame = close_frame_for(CloseReason::KickedByAdmin(None), ErrorFormat::Structured);
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload, serde_json::json!({ "reason": "kicked by admin" }));
+        let frame = close_frame_for(CloseReason::KickedByAdmin(Some("spam".into())), ErrorFormat::Legacy);
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(frame.reason.as_str(), "kicked by admin");
+    }
+
+    #[test]
+    fn connections_are_asked_to_reconnect_before_being_closed() {
+        let hour = Duration::from_secs(3600);
+        let start = Instant::now();
+        let mut lifetime = ConnectionLifetime::starting_at(start, 10 * hour, hour, None);
+
+        assert_eq!(lifetime.check(start), LifetimeCheck::Live);
+        assert_eq!(
+            lifetime.check(start + 9 * hour - Duration::from_secs(1)),
+            LifetimeCheck::Live
+        );
+        assert_eq!(
+            lifetime.check(start + 9 * hour + Duration::from_secs(60)),
+            LifetimeCheck::RequestReconnect(hour - Duration::from_secs(60))
+        );
+        // The client is only asked once.
+        assert_eq!(
+            lifetime.check(start + 9 * hour + Duration::from_secs(120)),
+            LifetimeCheck::Live
+        );
+        assert_eq!(lifetime.check(start + 10 * hour), LifetimeCheck::Expired);
+    }
+
+    #[test]
+    fn connection_deadlines_are_jittered() {
+        let max_lifetime = Duration::from_secs(3600);
+        let start = Instant::now();
+        let deadlines: Vec<_> = (0..32)
+            .map(|_| ConnectionLifetime::new(max_lifetime, Duration::from_secs(60), None).deadline)
+            .collect();
+        let earliest = start + max_lifetime.mul_f64(1.0 - MAX_LIFETIME_JITTER);
+        assert!(deadlines
+            .iter()
+            .all(|&d| d >= earliest && d <= Instant::now() + max_lifetime));
+        assert!(deadlines.iter().any(|&d| d != deadlines[0]));
+    }
+
+    #[test]
+    fn reconnect_requests_carry_a_redeemable_token() {
+        let tokens = Arc::new(ReconnectTokens::default());
+        let grant = ReconnectGrant {
+            identity: Identity::ONE,
+            subject: "subject".into(),
+            issuer: "localhost".into(),
+            token: "creds".into(),
+            expires_at: None,
+            database_identity: Identity::ZERO,
+        };
+        let reconnect = Some((tokens.clone(), grant.clone(), Duration::from_secs(60)));
+        let lifetime =
+            ConnectionLifetime::starting_at(Instant::now(), Duration::from_secs(60), Duration::ZERO, reconnect);
+
+        let message = lifetime.reconnect_requested(Duration::from_secs(30));
+        assert!(message.deadline > Timestamp::now());
+        assert_eq!(
+            tokens.redeem(&message.reconnect_token.unwrap(), &Identity::ZERO),
+            Ok(grant)
+        );
+
+        let lifetime = ConnectionLifetime::starting_at(Instant::now(), Duration::from_secs(60), Duration::ZERO, None);
+        assert!(lifetime
+            .reconnect_requested(Duration::from_secs(30))
+            .reconnect_token
+            .is_none());
+    }
+
+    #[test]
+    fn max_lifetime_close_frame_asks_to_reconnect() {
+        let frame = close_frame_for(CloseReason::MaxLifetimeReached, ErrorFormat::Structured);
+        assert_eq!(frame.code, CloseCode::Away);
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "max lifetime reached");
+        assert_eq!(payload["retry"], "immediately");
+    }
+
+    #[test]
+    fn auth_expired_close_frame_has_a_code_of_its_own() {
+        let frame = close_frame_for(CloseReason::AuthExpired, ErrorFormat::Structured);
+        assert_eq!(frame.code, CloseCode::Library(DisconnectReason::AuthExpired.code()));
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "auth expired");
+    }
+
+    #[test]
+    fn auth_expiry_deadlines_follow_the_database_policy() {
+        let now = (SystemTime::now(), tokio::time::Instant::now());
+        let expires_at = Some(now.0 + Duration::from_secs(60));
+        let grace = AuthExpiryPolicy {
+            action: AuthExpiryAction::Close,
+            grace_secs: 30,
+        };
+
+        let deadline = auth_expiry_deadline(AuthExpiryPolicy::default(), expires_at, now);
+        assert_eq!(deadline, Some(now.1 + Duration::from_secs(60)));
+        let deadline = auth_expiry_deadline(grace, expires_at, now);
+        assert_eq!(deadline, Some(now.1 + Duration::from_secs(90)));
+        // A token which doesn't expire keeps the client connected, as does a database ignoring expiry.
+        assert_eq!(auth_expiry_deadline(grace, None, now), None);
+        let ignore = AuthExpiryPolicy {
+            action: AuthExpiryAction::Ignore,
+            grace_secs: 0,
+        };
+        assert_eq!(auth_expiry_deadline(ignore, expires_at, now), None);
+
+        // A token which expired before the grace period closes the client right away,
+        // however often the deadline is looked up.
+        let expired = Some(now.0 - Duration::from_secs(60));
+        assert_eq!(auth_expiry_deadline(grace, expired, now), Some(now.1));
+        let expired = Some(now.0 - Duration::from_secs(10));
+        assert_eq!(
+            auth_expiry_deadline(grace, expired, now),
+            Some(now.1 + Duration::from_secs(20))
+        );
+    }
+
+    #[test]
+    fn draining_close_frame_hints_when_to_reconnect() {
+        let retry_after = Some(Duration::from_secs(30));
+        let frame = close_frame_for(CloseReason::Draining { retry_after }, ErrorFormat::Structured);
+        assert_eq!(frame.code, CloseCode::Away);
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "server draining");
+        assert_eq!(payload["retry_after_secs"], 30);
+
+        let frame = close_frame_for(CloseReason::Draining { retry_after: None }, ErrorFormat::Structured);
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert!(payload.get("retry_after_secs").is_none());
+    }
+
+    #[tokio::test]
+    async fn failing_to_send_the_identity_token_closes_the_connection() {
+        let (sender, mut sendrx) = ClientConnectionSender::dummy_with_capacity(
+            ClientActorId::for_test(Identity::ZERO),
+            ClientConfig::for_test(),
+            <_>::default(),
+        );
+        // The queue closes between the client being spawned and its token being sent.
+        sendrx.close();
+        let message = IdentityTokenMessage {
+            identity: Identity::ZERO,
+            token: "token".into(),
+            connection_id: ConnectionId::ZERO,
+        };
+        assert_eq!(
+            send_identity_token(&sender, Identity::ZERO, message),
+            Err(ClientSendError::Disconnected)
+        );
+
+        // The actor is asked to close the connection right away, and the client told why.
+        let reason = tokio::time::timeout(Duration::from_secs(1), sender.close_requested())
+            .await
+            .expect("the connection should be asked to close");
+        assert_eq!(reason, CloseReason::HandshakeFailed(ClientSendError::Disconnected));
+
+        let (server, client) = tokio::io::duplex(1024);
+        let mut server = tokio_tungstenite::WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut client = tokio_tungstenite::WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+        let (closed, received) = tokio::join!(
+            close_ws(&mut server, close_frame_for(reason, ErrorFormat::Structured), deadline),
+            client.next()
+        );
+        assert!(closed.is_ok(), "sending the close frame timed out");
+
+        let Some(Ok(WsMessage::Close(Some(frame)))) = received else {
+            panic!("expected a close frame, got {received:?}");
+        };
+        assert_eq!(frame.code, CloseCode::Error);
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "handshake failed");
+        assert_eq!(payload["error"], "disconnected");
+        assert_eq!(payload["retry"], "backoff");
+    }
+
+    #[test]
+    fn encode_error_close_frame_carries_the_message_kind() {
+        let frame = encode_error_close_frame(MessageKind::TransactionUpdate, ErrorFormat::Structured);
+        assert_eq!(frame.code, CloseCode::Error);
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "failed to encode message");
+        assert_eq!(payload["message_kind"], "TransactionUpdate");
+    }
+
+    #[test]
+    fn dropped_connections_are_told_why_by_distinct_codes() {
+        let reasons = [
+            (DisconnectReason::LivenessTimeout, "liveness timeout"),
+            (DisconnectReason::SendTimeout, "send timeout"),
+            (DisconnectReason::OutOfEnergy, "out of energy"),
+        ];
+        for (reason, text) in reasons {
+            let frame = disconnect_close_frame(reason, ErrorFormat::Structured);
+            assert_eq!(frame.code, CloseCode::Library(reason.code()));
+            assert_eq!(DisconnectReason::from_code(frame.code.into()), Some(reason));
+            let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+            assert_eq!(payload["reason"], text);
+        }
+    }
+
+    #[test]
+    fn legacy_close_frames_are_plain_text() {
+        let legacy = ErrorFormat::Legacy;
+        let error = "invalid type: string \"oops\", expected u32".repeat(10);
+        for (frame, code, reason) in [
+            (
+                module_exit_close_frame(ModuleExitCause::Crashed, legacy),
+                CloseCode::Away,
+                "module exited",
+            ),
+            (
+                close_frame_for(CloseReason::Overloaded, legacy),
+                CloseCode::Again,
+                "node overloaded",
+            ),
+            (
+                disconnect_close_frame(DisconnectReason::LivenessTimeout, legacy),
+                CloseCode::Away,
+                "liveness timeout",
+            ),
+            (
+                disconnect_close_frame(DisconnectReason::SendTimeout, legacy),
+                CloseCode::Away,
+                "send timeout",
+            ),
+            (
+                close_frame_for(CloseReason::Denied, legacy),
+                CloseCode::Policy,
+                "access denied",
+            ),
+            (
+                close_frame_for(CloseReason::KickedByModule("spam".into()), legacy),
+                CloseCode::Policy,
+                "kicked by module",
+            ),
+            (
+                close_frame_for(CloseReason::MaxLifetimeReached, legacy),
+                CloseCode::Away,
+                "max lifetime reached",
+            ),
+            (
+                close_frame_for(CloseReason::Replaced, legacy),
+                CloseCode::Policy,
+                "connection replaced",
+            ),
+            (
+                close_frame_for(CloseReason::AuthExpired, legacy),
+                CloseCode::Policy,
+                "auth expired",
+            ),
+            (
+                close_frame(legacy, CloseCode::Again, &ConnectFailedClose::default()),
+                CloseCode::Again,
+                "module unavailable",
+            ),
+            (
+                close_frame_for(CloseReason::HandshakeFailed(ClientSendError::Disconnected), legacy),
+                CloseCode::Error,
+                "handshake failed",
+            ),
+            (
+                close_frame_for(CloseReason::Draining { retry_after: None }, legacy),
+                CloseCode::Away,
+                "server draining",
+            ),
+            (
+                encode_error_close_frame(MessageKind::TransactionUpdate, legacy),
+                CloseCode::Error,
+                "failed to encode message",
+            ),
+            (
+                size_limit(Protocol::Text, 1024, 0).close_frame(legacy),
+                CloseCode::Size,
+                "message too large",
+            ),
+            (
+                ViolationBudget::close_frame(3, legacy),
+                CloseCode::Policy,
+                "too many protocol errors",
+            ),
+            // The error is sent in full, as it always was.
+            (
+                close_frame(legacy, CloseCode::Error, &InvalidMessageClose::new(&error)),
+                CloseCode::Error,
+                error.as_str(),
+            ),
+        ] {
+            assert_eq!(frame.code, code);
+            assert_eq!(frame.reason.as_bytes(), reason.as_bytes());
+        }
+    }
+
+    #[test]
+    fn structured_close_frames_are_json() {
+        let structured = ErrorFormat::Structured;
+        let frame = close_frame_for(CloseReason::Denied, structured);
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(&*frame.reason, r#"{"reason":"access denied"}"#);
+
+        let error = "é".repeat(100);
+        let frame = close_frame(structured, CloseCode::Error, &InvalidMessageClose::new(&error));
+        assert!(frame.reason.len() <= MAX_CLOSE_REASON_LEN);
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "invalid message");
+        assert!(error.starts_with(payload["error"].as_str().unwrap()));
+    }
+
+    #[test]
+    fn capabilities_follow_the_node_configuration() {
+        let timeouts_config = TimeoutsConfig::default();
+        let timeouts = ClientTimeouts {
+            liveness: Duration::from_secs(30),
+            send: Duration::from_secs(10),
+        };
+        let has = |capabilities: &ServerCapabilitiesMessage, feature: ServerFeature| {
+            capabilities.features.iter().any(|name| **name == *feature.name())
+        };
+
+        let config = ClientConfig {
+            version: ProtocolVersion::V1,
+            ..ClientConfig::for_test()
+        };
+        let options = WebSocketOptions {
+            session_resume_grace: None,
+            ..<_>::default()
+        };
+        let v1 = server_capabilities(
+            &config,
+            &options,
+            &timeouts_config,
+            timeouts,
+            DatabaseFeatures::default(),
+        );
+        assert_eq!(&*v1.protocol, ws_api::BIN_PROTOCOL);
+        assert_eq!(
+            &*v1.compression,
+            [Compression::None, Compression::Brotli, Compression::Gzip]
+        );
+        assert_eq!(v1.max_message_size, options.max_message_size as u64);
+        assert_eq!(v1.liveness_timeout, Duration::from_secs(30).into());
+        assert_eq!(v1.send_timeout, Duration::from_secs(10).into());
+        assert!(!has(&v1, ServerFeature::SessionResume));
+        assert!(has(&v1, ServerFeature::UpdateSettings));
+        assert!(has(&v1, ServerFeature::TokenRefresh));
+        assert!(has(&v1, ServerFeature::ChunkedQueries));
+        assert!(has(&v1, ServerFeature::FlowControl));
+        assert!(v1.database_features.is_empty());
+
+        let config = ClientConfig {
+            version: ProtocolVersion::V2,
+            ..ClientConfig::for_test()
+        };
+        let options = WebSocketOptions {
+            session_resume_grace: Some(Duration::from_secs(5)),
+            ..<_>::default()
+        };
+        let features = [DatabaseFeature::DeltaUpdates].into_iter().collect();
+        let v2 = server_capabilities(&config, &options, &timeouts_config, timeouts, features);
+        assert_eq!(&*v2.protocol, ws_api::BIN_PROTOCOL_V2);
+        assert!(v2.compression.contains(&Compression::Zstd));
+        assert!(has(&v2, ServerFeature::SessionResume));
+        let database_features = v2.database_features.iter().map(|name| &**name).collect::<Vec<_>>();
+        assert_eq!(database_features, [DatabaseFeature::DeltaUpdates.name()]);
+    }
+
+    #[tokio::test]
+    async fn connection_status_reports_the_connection_as_observed() {
+        let second = Duration::from_secs(1);
+        let start = Instant::now();
+        let config = ClientConfig {
+            version: ProtocolVersion::V2,
+            compression: Compression::Zstd,
+            ..ClientConfig::for_test()
+        };
+        let (client, sendrx) = ClientConnectionSender::dummy_with_capacity(
+            ClientActorId::for_test(Identity::ZERO),
+            config,
+            <_>::default(),
+        );
+        let lifetime = ConnectionLifetime::starting_at(start, 60 * second, Duration::ZERO, None);
+
+        let mut health = ConnectionHealth::new(start);
+        health.received(10);
+        health.sent(start, 100);
+        assert_eq!(health.pong_received(start, &[]), None);
+        let ping = health.ping_sent(start + second);
+        assert_eq!(health.pong_received(start + 3 * second, &ping), Some(2 * second));
+        client
+            .send_message(IdentityTokenMessage {
+                identity: Identity::ZERO,
+                token: "token".into(),
+                connection_id: ConnectionId::ZERO,
+            })
+            .unwrap();
+
+        let now = start + 5 * second;
+        let status = health.status(7, now, &client, Some(&lifetime), 3, [None, Some(&sendrx)]);
+        assert_eq!(status.request_id, 7);
+        assert_eq!(&*status.protocol, ws_api::BIN_PROTOCOL_V2);
+        assert_eq!(&*status.compression, "Zstd");
+        assert!(!status.light);
+        assert_eq!(status.round_trip_time, Some((2 * second).into()));
+        assert_eq!(status.outgoing_queue_len, 1);
+        assert_eq!(status.outgoing_queue_bytes, sendrx.queued_bytes() as u64);
+        assert_eq!(status.outgoing_queue_age, Some((5 * second).into()));
+        assert_eq!(status.incoming_queue_len, 3);
+        assert_eq!(status.subscriptions, 0);
+        assert_eq!((status.bytes_sent, status.bytes_received), (100, 10));
+        assert_eq!(status.lifetime_remaining, Some((55 * second).into()));
+
+        // Without a queue to wait in or a lifetime to run out, there's no age or time remaining.
+        let status = health.status(8, now, &client, None, 0, [None, None]);
+        assert_eq!(status.outgoing_queue_age, None);
+        assert_eq!(status.lifetime_remaining, None);
+    }
+
+    #[test]
+    fn pongs_with_mangled_payloads_are_skipped() {
+        let second = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut health = ConnectionHealth::new(start);
+        let ping = health.ping_sent(start + second);
+        assert_eq!(ping.len(), 8);
+
+        assert_eq!(health.pong_received(start + 2 * second, b"mangled"), None);
+        assert_eq!(health.pong_received(start + 2 * second, &u64::MAX.to_le_bytes()), None);
+        // The ping is still outstanding, and is answered once.
+        assert_eq!(health.pong_received(start + 3 * second, &ping), Some(2 * second));
+        assert_eq!(health.pong_received(start + 4 * second, &ping), None);
+        assert_eq!(health.round_trip_time, Some(2 * second));
+    }
+
+    #[test]
+    fn silent_clients_still_sent_messages_are_noticed_then_closed() {
+        let second = Duration::from_secs(1);
+        let start = Instant::now();
+        let (notice, close) = (Some(10 * second), Some(30 * second));
+        let mut health = ConnectionHealth::new(start);
+        // A client which never sent anything only receives, which is healthy.
+        health.sent(start + 20 * second, 100);
+        assert_eq!(
+            health.inbound_check(start + 20 * second, notice, close),
+            InboundCheck::Healthy
+        );
+
+        // Nothing was sent to the client since its message arrived, so it's not expected to answer.
+        let mut health = ConnectionHealth::new(start);
+        health.inbound(start);
+        assert_eq!(
+            health.inbound_check(start + 20 * second, notice, close),
+            InboundCheck::Healthy
+        );
+
+        health.sent(start + 15 * second, 100);
+        assert_eq!(
+            health.inbound_check(start + 20 * second, notice, close),
+            InboundCheck::Degraded(20 * second)
+        );
+        // The client is noticed only once...
+        health.sent(start + 25 * second, 100);
+        assert_eq!(
+            health.inbound_check(start + 25 * second, notice, close),
+            InboundCheck::Healthy
+        );
+        // ...until its messages arrive again.
+        health.inbound(start + 26 * second);
+        health.sent(start + 40 * second, 100);
+        assert_eq!(
+            health.inbound_check(start + 40 * second, notice, close),
+            InboundCheck::Degraded(14 * second)
+        );
+
+        health.sent(start + 60 * second, 100);
+        assert_eq!(
+            health.inbound_check(start + 60 * second, notice, close),
+            InboundCheck::Broken(34 * second)
+        );
+        // Without thresholds, nothing is checked.
+        assert_eq!(
+            health.inbound_check(start + 60 * second, None, None),
+            InboundCheck::Healthy
+        );
+    }
+
+    fn size_limit(protocol: Protocol, max_message_size: usize, oversized_message_strikes: u32) -> MessageSizeLimit {
+        let options = WebSocketOptions {
+            max_message_size,
+            oversized_message_strikes,
+            ..<_>::default()
+        };
+        MessageSizeLimit::new(&options, protocol)
+    }
+
+    #[test]
+    fn only_text_clients_get_strikes_for_oversized_messages() {
+        let mut text = size_limit(Protocol::Text, 100, 2);
+        assert_eq!(text.read_limit(), 200);
+        assert_eq!(text.check(100), SizeCheck::Within);
+        assert_eq!(text.check(101), SizeCheck::Strike);
+        assert_eq!(text.check(200), SizeCheck::Strike);
+        assert_eq!(text.check(100), SizeCheck::Within);
+        assert_eq!(text.check(101), SizeCheck::TooLarge);
+
+        let mut binary = size_limit(Protocol::Binary, 100, 2);
+        assert_eq!(binary.read_limit(), 100);
+        assert_eq!(binary.check(100), SizeCheck::Within);
+        assert_eq!(binary.check(101), SizeCheck::TooLarge);
+    }
+
+    /// Check the next message received on `ws` against `limit`,
+    /// treating a capacity error as too large, as `ws_client_actor_inner` does.
+    async fn receive<S: AsyncRead + AsyncWrite + Unpin>(
+        ws: &mut tokio_tungstenite::WebSocketStream<S>,
+        limit: &mut MessageSizeLimit,
+    ) -> SizeCheck {
+        match ws.next().await.unwrap() {
+            Ok(m) => limit.check(m.len()),
+            Err(WsError::Capacity(_)) => SizeCheck::TooLarge,
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_just_over_the_limit_are_caught() {
+        const LIMIT: usize = 1024;
+        for (protocol, strikes, expected) in [
+            (Protocol::Text, 0, SizeCheck::TooLarge),
+            (Protocol::Text, 1, SizeCheck::Strike),
+            (Protocol::Binary, 0, SizeCheck::TooLarge),
+            (Protocol::Binary, 1, SizeCheck::TooLarge),
+        ] {
+            let mut limit = size_limit(protocol, LIMIT, strikes);
+            let (server, client) = tokio::io::duplex(16 * LIMIT);
+            let config = WebSocketConfig::default().max_message_size(Some(limit.read_limit()));
+            let mut server =
+                tokio_tungstenite::WebSocketStream::from_raw_socket(server, Role::Server, Some(config)).await;
+            let mut client = tokio_tungstenite::WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+            let message = |len: usize| match protocol {
+                Protocol::Text => WsMessage::Text("x".repeat(len).into()),
+                Protocol::Binary => WsMessage::Binary(vec![0; len].into()),
+            };
+
+            client.send(message(LIMIT)).await.unwrap();
+            let received = receive(&mut server, &mut limit).await;
+            assert_eq!(received, SizeCheck::Within, "{protocol:?}");
+            client.send(message(LIMIT + 1)).await.unwrap();
+            let received = receive(&mut server, &mut limit).await;
+            assert_eq!(received, expected, "{protocol:?} with {strikes} strikes");
+        }
+    }
+
+    #[test]
+    fn too_large_close_frame_carries_the_limit() {
+        let frame = size_limit(Protocol::Text, 1024, 0).close_frame(ErrorFormat::Structured);
+        assert_eq!(frame.code, CloseCode::Library(DisconnectReason::MessageTooLarge.code()));
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "message too large");
+        assert_eq!(payload["limit"], 1024);
+    }
+
+    #[test]
+    fn violations_close_the_connection_once_over_budget() {
+        let unbudgeted = ViolationBudget::new(&WebSocketOptions::default());
+        assert_eq!(unbudgeted.check(1), ViolationCheck::Unbudgeted);
+
+        let budget = ViolationBudget::new(&WebSocketOptions {
+            protocol_violation_budget: Some(2),
+            ..<_>::default()
+        });
+        assert_eq!(budget.check(1), ViolationCheck::Tolerated);
+        assert_eq!(budget.check(2), ViolationCheck::Tolerated);
+        assert_eq!(budget.check(3), ViolationCheck::Exceeded(3));
+
+        let frame = ViolationBudget::close_frame(3, ErrorFormat::Structured);
+        assert_eq!(frame.code, CloseCode::Policy);
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "too many protocol errors");
+        assert_eq!(payload["violations"], 3);
+    }
+
+    #[test]
+    fn violations_are_classified_by_handle_error() {
+        let decode = MessageHandleError::from(serde_json::from_str::<u32>("{").unwrap_err());
+        assert_eq!(decode.violation(), Some(ProtocolViolation::Decode));
+        let invalid_utf8 = MessageHandleError::from(std::str::from_utf8(std::hint::black_box(&[0xff])).unwrap_err());
+        assert_eq!(invalid_utf8.violation(), Some(ProtocolViolation::Decode));
+        let mismatch = MessageHandleError::UnsupportedMessage {
+            message_type: "Bogus".into(),
+            protocol: "v1.json.spacetimedb",
+        };
+        assert_eq!(mismatch.violation(), Some(ProtocolViolation::ProtocolMismatch));
+        assert_eq!(ProtocolViolation::ProtocolMismatch.as_ref(), "protocol_mismatch");
+    }
+
+    #[test]
+    fn utf8_text_frames_are_handled_as_text() {
+        let text = WsMessage::Text("{}".into());
+        assert!(matches!(
+            ClientMessage::from_message(text),
+            Ok(ClientMessage::Message(DataMessage::Text(text))) if &*text == "{}"
+        ));
+    }
+
+    fn queue_limit(max_incoming_queue_len: usize, max_incoming_queue_bytes: usize) -> IncomingQueueLimit {
+        IncomingQueueLimit::new(&WebSocketOptions {
+            max_incoming_queue_len,
+            max_incoming_queue_bytes,
+            ..<_>::default()
+        })
+    }
+
+    #[test]
+    fn incoming_queue_is_bounded_by_messages_and_bytes() {
+        let mut limit = queue_limit(2, 100);
+        assert_eq!(limit.admit(0, 60), QueueCheck::Queued);
+        assert_eq!(limit.admit(1, 60), QueueCheck::Rejected);
+        assert_eq!(limit.admit(1, 40), QueueCheck::Queued);
+        assert_eq!(limit.admit(2, 0), QueueCheck::Rejected);
+        limit.release(60);
+        limit.release(40);
+        // A message is queued if none are waiting, however large.
+        assert_eq!(limit.admit(0, 1000), QueueCheck::Queued);
+        assert_eq!(limit.admit(1, 1), QueueCheck::Rejected);
+
+        let mut closing = IncomingQueueLimit::new(&WebSocketOptions {
+            max_incoming_queue_len: 1,
+            incoming_queue_overflow: IncomingQueueOverflow::Close,
+            ..<_>::default()
+        });
+        assert_eq!(closing.admit(0, 1), QueueCheck::Queued);
+        assert_eq!(closing.admit(1, 1), QueueCheck::Overflowed);
+
+        let frame = closing.close_frame(ErrorFormat::Structured);
+        assert_eq!(frame.code, CloseCode::Library(ws_api::QUEUE_OVERFLOW_CLOSE_CODE));
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "too many queued messages");
+        assert_eq!(payload["max_len"], 1);
+        let frame = closing.close_frame(ErrorFormat::Legacy);
+        assert_eq!(frame.reason.as_str(), "too many queued messages");
+    }
+
+    #[test]
+    fn clients_behind_their_send_backlog_are_closed_or_coalesced() {
+        let policy = |action| SendBacklogPolicy {
+            action,
+            max_messages: Some(10),
+            max_bytes: Some(1000),
+        };
+        let mut closing = SendBacklog::new(policy(SendBacklogAction::Close));
+        assert_eq!(closing.check(10, 1000), BacklogCheck::Send);
+        assert_eq!(closing.check(11, 0), BacklogCheck::Close);
+        assert_eq!(closing.check(0, 1001), BacklogCheck::Close);
+
+        // Once behind, a client keeps coalescing until nothing is queued behind its batch.
+        let mut coalescing = SendBacklog::new(policy(SendBacklogAction::Coalesce));
+        assert_eq!(coalescing.check(5, 0), BacklogCheck::Send);
+        assert_eq!(coalescing.check(11, 0), BacklogCheck::Coalesce { started: true });
+        assert_eq!(coalescing.check(5, 0), BacklogCheck::Coalesce { started: false });
+        assert_eq!(coalescing.check(0, 0), BacklogCheck::Send);
+        assert_eq!(coalescing.check(5, 0), BacklogCheck::Send);
+
+        // Resyncing clients are held back before their updates are queued, so whatever is queued is sent.
+        let mut resyncing = SendBacklog::new(policy(SendBacklogAction::Resync));
+        assert_eq!(resyncing.check(11, 1001), BacklogCheck::Send);
+
+        // Without thresholds, clients are never behind.
+        let mut unlimited = SendBacklog::new(SendBacklogPolicy::default());
+        assert_eq!(unlimited.check(usize::MAX, usize::MAX), BacklogCheck::Send);
+    }
+
+    /// A light update, as sent to a client for a transaction it didn't cause.
+    fn light_update() -> SerializableMessage {
+        SerializableMessage::TxUpdate(TransactionUpdateMessage {
+            event: None,
+            database_update: SubscriptionUpdateMessage {
+                database_update: ws_api::FormatSwitch::Bsatn(<_>::default()),
+                request_id: None,
+                timer: None,
+            },
+            committed_at: Timestamp::UNIX_EPOCH,
+            detail: UpdateDetail::FULL,
+            shared: None,
+        })
+    }
+
+    /// The reply to the client's call tagged with `request_id`.
+    fn reply(request_id: u32) -> SerializableMessage {
+        let event = Arc::new(ModuleEvent {
+            timestamp: Timestamp::UNIX_EPOCH,
+            caller_identity: Identity::ZERO,
+            caller_connection_id: Some(ConnectionId::from_u128(1)),
+            function_call: ModuleFunctionCall::default(),
+            status: EventStatus::Committed(<_>::default()),
+            energy_quanta_used: EnergyQuanta::ZERO,
+            host_execution_duration: Duration::ZERO,
+            request_id: Some(request_id),
+            timer: None,
+            acknowledged: false,
+            trace_id: None,
+        });
+        let reply =
+            TransactionUpdateMessage::reply(event, Timestamp::UNIX_EPOCH, Protocol::Binary, UpdateDetail::FULL, None);
+        SerializableMessage::TxUpdate(reply)
+    }
+
+    fn degraded_notice() -> SerializableMessage {
+        SerializableMessage::ConnectionDegraded(ConnectionDegradedMessage { last_inbound_ms: 0 })
+    }
+
+    /// The request ids of the replies in `batch`.
+    fn reply_request_ids(batch: &[SerializableMessage]) -> Vec<u32> {
+        batch
+            .iter()
+            .filter_map(|msg| match msg {
+                SerializableMessage::TxUpdate(update) => update.event.as_ref()?.request_id,
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn coalescing_folds_runs_of_transaction_updates() {
+        let mut batch = vec![
+            light_update(),
+            light_update(),
+            degraded_notice(),
+            light_update(),
+            light_update(),
+            light_update(),
+        ];
+        coalesce_updates(&mut batch);
+        let kinds = batch.iter().map(|msg| msg.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                MessageKind::TransactionUpdateLight,
+                MessageKind::ConnectionDegraded,
+                MessageKind::TransactionUpdateLight,
+            ]
+        );
+    }
+
+    #[test]
+    fn coalescing_keeps_replies_to_the_clients_calls() {
+        let mut batch = vec![
+            light_update(),
+            reply(1),
+            light_update(),
+            light_update(),
+            reply(2),
+            reply(3),
+        ];
+        coalesce_updates(&mut batch);
+        let kinds = batch.iter().map(|msg| msg.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                MessageKind::TransactionUpdateLight,
+                MessageKind::TransactionUpdate,
+                MessageKind::TransactionUpdateLight,
+                MessageKind::TransactionUpdate,
+                MessageKind::TransactionUpdate,
+            ]
+        );
+        assert_eq!(reply_request_ids(&batch), [1, 2, 3]);
+    }
+
+    #[test]
+    fn flow_control_holds_updates_until_credits_are_granted() {
+        let mut flow = FlowControl::new(0);
+        let mut batch = vec![];
+
+        // Without credits, only messages other than updates get through.
+        batch.push(degraded_notice());
+        flow.hold(&mut batch);
+        assert_eq!(flow.release(&mut batch, 32), 1);
+        assert_eq!(batch.pop().map(|msg| msg.kind()), Some(MessageKind::ConnectionDegraded));
+
+        // Updates stall, light ones coalesced, and hold back what comes after them.
+        batch.extend([light_update(), light_update(), degraded_notice(), light_update()]);
+        flow.hold(&mut batch);
+        assert_eq!(flow.release(&mut batch, 32), 0);
+        assert!(batch.is_empty());
+        let held = flow.held.iter().map(|msg| msg.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            held,
+            [
+                MessageKind::TransactionUpdateLight,
+                MessageKind::ConnectionDegraded,
+                MessageKind::TransactionUpdateLight,
+            ]
+        );
+
+        // Once granted credits, they resume in order.
+        flow.grant(u64::MAX);
+        assert_eq!(flow.release(&mut batch, 2), 2);
+        assert_eq!(flow.release(&mut batch, 32), 1);
+        assert!(flow.held.is_empty());
+        assert_eq!(batch.len(), 3);
+    }
+
+    #[test]
+    fn flow_control_keeps_replies_to_the_clients_calls() {
+        let mut flow = FlowControl::new(0);
+        let mut batch = vec![light_update(), reply(1), light_update(), light_update(), reply(2)];
+        flow.hold(&mut batch);
+        batch.push(light_update());
+        flow.hold(&mut batch);
+
+        flow.grant(u64::MAX);
+        assert_eq!(flow.release(&mut batch, 32), 5);
+        let kinds = batch.iter().map(|msg| msg.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                MessageKind::TransactionUpdateLight,
+                MessageKind::TransactionUpdate,
+                MessageKind::TransactionUpdateLight,
+                MessageKind::TransactionUpdate,
+                MessageKind::TransactionUpdateLight,
+            ]
+        );
+        assert_eq!(reply_request_ids(&batch), [1, 2]);
+    }
+
+    #[test]
+    fn queue_wait_is_recorded_by_message_kind() {
+        let database_identity = Identity::from_byte_array(rand::random());
+        let times = IncomingMessageTimes::new(&database_identity);
+        let queued_at = Instant::now() - Duration::from_millis(50);
+
+        let handle_seconds = times.dequeued(&DataMessage::from("{}".to_owned()), queued_at);
+        handle_seconds.observe(0.01);
+        let (text_wait, text_handle) = &times.text;
+        assert_eq!(text_wait.get_sample_count(), 1);
+        assert!(text_wait.get_sample_sum() >= 0.05);
+        assert_eq!(text_handle.get_sample_count(), 1);
+        let (binary_wait, binary_handle) = &times.binary;
+        assert_eq!(binary_wait.get_sample_count(), 0);
+        assert_eq!(binary_handle.get_sample_count(), 0);
+
+        times.dequeued(&DataMessage::from(vec![0u8]), queued_at);
+        assert_eq!(binary_wait.get_sample_count(), 1);
+        assert_eq!(text_wait.get_sample_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn flooding_a_connection_rejects_messages_beyond_the_queue() {
+        const FLOOD: usize = 10_000;
+        const MAX_LEN: usize = 64;
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        let mut server = tokio_tungstenite::WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut client = tokio_tungstenite::WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let flood = tokio::spawn(async move {
+            for i in 0..FLOOD {
+                client.send(WsMessage::Text(i.to_string().into())).await.unwrap();
+            }
+            client
+        });
+
+        // None of the queued messages are handled, as if the module were stuck on a long reducer.
+        let mut limit = queue_limit(MAX_LEN, usize::MAX);
+        let mut queue = std::collections::VecDeque::new();
+        let mut rejected = 0;
+        for _ in 0..FLOOD {
+            let Some(Ok(m)) = server.next().await else {
+                panic!("the flood was cut short");
+            };
+            match limit.admit(queue.len(), m.len()) {
+                QueueCheck::Queued => queue.push_back(m),
+                QueueCheck::Rejected => rejected += 1,
+                QueueCheck::Overflowed => panic!("rejecting clients aren't closed"),
+            }
+        }
+        let _client = flood.await.unwrap();
+        assert_eq!(queue.len(), MAX_LEN);
+        assert_eq!(rejected, FLOOD - MAX_LEN);
+        assert_eq!(limit.bytes, queue.iter().map(|m| m.len()).sum::<usize>());
+    }
+
+    #[test]
+    fn individual_update_knobs_win_over_the_light_preset() {
+        fn knobs<T>(tx_update_full: T, include_caller_info: T, include_timings: T) -> UpdateKnobs<T> {
+            UpdateKnobs {
+                tx_update_full,
+                include_caller_info,
+                include_timings,
+            }
+        }
+        let default = |forced| Some(ClientDefault { value: true, forced });
+        #[rustfmt::skip]
+        let cases = [
+            // (effective light, database's light default, requested knobs, effective knobs)
+            (false, None,           knobs(None, None, None),               knobs(true, true, true)),
+            (true,  None,           knobs(None, None, None),               knobs(false, false, false)),
+            (true,  None,           knobs(Some(true), None, None),         knobs(true, false, false)),
+            (true,  None,           knobs(None, Some(true), Some(true)),   knobs(false, true, true)),
+            (false, None,           knobs(None, Some(false), None),        knobs(true, false, true)),
+            (false, None,           knobs(Some(false), None, Some(false)), knobs(false, true, false)),
+            (true,  default(false), knobs(Some(true), None, None),         knobs(true, false, false)),
+            // A forced preset leaves the client no say.
+            (true,  default(true),  knobs(Some(true), Some(true), None),   knobs(false, false, false)),
+        ];
+        for (i, (light, light_default, requested, effective)) in cases.into_iter().enumerate() {
+            assert_eq!(requested.resolve(light, light_default), effective, "case {i}");
+        }
+    }
+
+    #[test]
+    fn explicit_settings_win_over_defaults_unless_forced() {
+        use Compression::{Brotli, Gzip, Zstd};
+        use ProtocolVersion::{V1, V2};
+        const NONE: Compression = Compression::None;
+
+        fn default<T>(value: T, forced: bool) -> Option<ClientDefault<T>> {
+            Some(ClientDefault { value, forced })
+        }
+        let defaults = |compression, light| ClientConfigDefaults { compression, light };
+        #[rustfmt::skip]
+        let cases = [
+            // (version, requested compression, requested light, defaults, effective)
+            (V2, None,       None,        defaults(None, None),                                (Brotli, false)),
+            (V2, Some(Gzip), Some(true),  defaults(None, None),                                (Gzip, true)),
+            (V2, None,       None,        defaults(default(NONE, false), default(true, false)), (NONE, true)),
+            (V2, Some(Gzip), Some(false), defaults(default(NONE, false), default(true, false)), (Gzip, false)),
+            (V2, Some(Gzip), Some(false), defaults(default(Zstd, true), default(true, true)),   (Zstd, true)),
+            (V2, None,       Some(true),  defaults(default(Zstd, false), None),                 (Zstd, true)),
+            // A default the client's protocol doesn't support is ignored, even if forced.
+            (V1, None,       None,        defaults(default(Zstd, false), None),                 (Brotli, false)),
+            (V1, Some(Gzip), None,        defaults(default(Zstd, true), None),                  (Gzip, false)),
+            (V1, Some(NONE), None,        defaults(default(Brotli, true), None),                (Brotli, false)),
+        ];
+        for (i, (version, compression, light, defaults, effective)) in cases.into_iter().enumerate() {
+            assert_eq!(
+                merge_config_defaults(version, compression, light, &defaults, DatabaseFeatures::default()),
+                effective,
+                "case {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn delta_updates_make_clients_light_unless_they_or_the_database_choose() {
+        let delta = DatabaseFeatures::from_iter([DatabaseFeature::DeltaUpdates]);
+        let light = |requested, defaults: &ClientConfigDefaults| {
+            merge_config_defaults(ProtocolVersion::V2, None, requested, defaults, delta).1
+        };
+        let no_defaults = ClientConfigDefaults::default();
+        assert!(light(None, &no_defaults));
+        assert!(!light(Some(false), &no_defaults));
+        let full_default = ClientConfigDefaults {
+            light: Some(ClientDefault {
+                value: false,
+                forced: false,
+            }),
+            ..<_>::default()
+        };
+        assert!(!light(None, &full_default));
+    }
+
+    fn long_token(len: usize) -> IdentityTokenMessage {
+        IdentityTokenMessage {
+            identity: Identity::ZERO,
+            token: "x".repeat(len).into(),
+            connection_id: ConnectionId::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_readers_are_backpressured_by_bytes_then_evicted() {
+        let downstream = NetworkConditions {
+            bandwidth: Some(1024),
+            ..<_>::default()
+        };
+        let (mut client, mut server) = TestWsClient::connect(downstream, <_>::default(), 4096, None).await;
+        let capacity = SendQueueCapacity {
+            messages: 1024,
+            bytes: 16 * 1024,
+        };
+        let config = ClientConfig::for_test();
+        let (sender, mut sendrx) =
+            ClientConnectionSender::dummy_with_capacity(ClientActorId::for_test(Identity::ZERO), config, capacity);
+
+        // Write out the client's queue as `ws_client_actor_inner` does, until its socket backs up.
+        let mut sent = 0;
+        let pump = async {
+            let mut buffer = SerializeBuffer::new(config);
+            while let Some(msg) = sendrx.recv().await {
+                let (msg_alloc, msg_data) = sender
+                    .codec()
+                    .serialize(buffer, msg, config.compression)
+                    .unwrap_or_else(|failure| panic!("{}", failure.error));
+                let (res, buf) = feed_reclaiming(&mut server, msg_alloc, msg_data, config).await;
+                buffer = buf;
+                res.and(server.flush().await).unwrap();
+                sent += 1;
+            }
+            std::future::pending().await
+        };
+        let produce = async {
+            let mut pressured = false;
+            loop {
+                pressured |= sender.is_under_pressure();
+                match sender.send_message(long_token(1024)) {
+                    Ok(()) => tokio::time::sleep(Duration::from_millis(1)).await,
+                    Err(e) => return (pressured, e),
+                }
+            }
+        };
+        let (pressured, error) = tokio::select! {
+            result = produce => result,
+            () = pump => unreachable!(),
+            _ = client.drain() => unreachable!("the server never hangs up"),
+        };
+
+        // The queue came under pressure before it filled up by bytes, at which point the client was evicted.
+        assert!(pressured);
+        assert_eq!(error, ClientSendError::QueueFullByBytes);
+        assert!(sender.is_cancelled());
+        assert!(sent > 0);
+    }
+
+    #[tokio::test]
+    async fn chunked_sends_survive_a_bandwidth_cap_within_the_send_timeout() {
+        const CHUNK: usize = 8 * 1024;
+        const CHUNKS: usize = 8;
+        const SEND_TIMEOUT: Duration = Duration::from_millis(500);
+        let downstream = NetworkConditions {
+            bandwidth: Some(64 * 1024),
+            ..<_>::default()
+        };
+        let (mut client, mut server) = TestWsClient::connect(downstream, <_>::default(), CHUNK, None).await;
+        let teardown = Teardown::new(Duration::from_secs(5), SEND_TIMEOUT);
+
+        let start = Instant::now();
+        // The server hangs up once it's sent every chunk, which ends the client's stream.
+        let send = async move {
+            for _ in 0..CHUNKS {
+                let chunk = server.send(WsMessage::binary(vec![0; CHUNK]));
+                tokio::time::timeout_at(teardown.send_deadline(), chunk)
+                    .await
+                    .expect("each chunk should be sent within the send timeout")
+                    .unwrap();
+            }
+            server.close(None).await.unwrap();
+        };
+        let ((), received) = tokio::join!(send, client.drain());
+        assert_eq!(received, CHUNKS * CHUNK);
+        // All of them together took longer than any one send may.
+        assert!(start.elapsed() > SEND_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn disconnecting_mid_frame_hands_back_the_buffer() {
+        let downstream = NetworkConditions {
+            disconnect_at: Some(1000),
+            ..<_>::default()
+        };
+        let (mut client, mut server) = TestWsClient::connect(downstream, <_>::default(), 256, None).await;
+        // Uncompressed, so that the message is long enough on the wire to be cut off.
+        let config = ClientConfig {
+            compression: Compression::None,
+            ..ClientConfig::for_test()
+        };
+        let codec = config.codec();
+        let encode = |buffer, token| {
+            codec
+                .serialize(buffer, token, config.compression)
+                .unwrap_or_else(|failure| panic!("{}", failure.error))
+        };
+
+        let (msg_alloc, msg_data) = encode(SerializeBuffer::new(config), long_token(10_000));
+        let send = async {
+            let (res, buffer) = feed_reclaiming(&mut server, msg_alloc, msg_data, config).await;
+            (res.and(server.flush().await), buffer)
+        };
+        let ((res, buffer), received) = tokio::join!(send, client.drain());
+        assert!(res.is_err());
+        assert_eq!(received, 0);
+
+        // The buffer is handed back, ready to encode the next message.
+        let (_, msg) = encode(buffer, long_token(10));
+        assert!(matches!(msg, DataMessage::Binary(_)));
+    }
 }
@@ -0,0 +1,696 @@
+//! Multiplexed connections, over which a client connects to several databases at once,
+//! see [`MultiplexClientMessage`].
+//!
+//! Each database attached to a multiplexed connection is connected to as over a websocket of its own,
+//! by a [`ws_client_actor`] of its own, whose websocket is one end of an in-memory pipe.
+//! The multiplexer holds the other end of the pipe of each channel,
+//! and forwards the frames of the client's websocket to and from the channels they're for.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Extension;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, SelectAll, SplitSink};
+use futures::{SinkExt, StreamExt};
+use http::StatusCode;
+use serde_json::value::RawValue;
+use spacetimedb::client::messages::IdentityTokenMessage;
+use spacetimedb::client::{ClientActorId, ClientConfig, ClientConnection, ConnectionPolicy, ErrorFormat, Protocol};
+use spacetimedb::messages::control_db::ConnectionTimeouts;
+use spacetimedb::Identity;
+use spacetimedb_client_api_messages::websocket::{
+    self as ws_api, decode_channel_frame, encode_channel_frame, Attach, AttachFailed, Attached, ChannelFrame, Detached,
+    MultiplexClientMessage, MultiplexServerMessage, ProtocolVersion, MULTIPLEX_CONTROL_CHANNEL,
+};
+use spacetimedb_lib::bsatn;
+use spacetimedb_lib::de::serde::DeserializeWrapper;
+use spacetimedb_lib::ser::serde::SerializeWrapper;
+use spacetimedb_lib::DatabaseFeatures;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+use super::{
+    admit, connect_failed_close_frame, disconnect_close_frame, generate_random_connection_id, merge_config_defaults,
+    reserve_client_connection_id, send_identity_token, websocket_config, ws_client_actor, ClientTokenValidator,
+    ConnectionIds, ConnectionLifetime, MessageSizeLimit, UpdateKnobs, BIN_PROTOCOL, BIN_PROTOCOL_V2,
+    LAST_CLOSE_TIMEOUT, TEXT_PROTOCOL, TEXT_PROTOCOL_V2,
+};
+use crate::acl;
+use crate::auth::{PresentedReconnectToken, SpacetimeAuth};
+use crate::deprecation::DeprecationNotices;
+use crate::hooks::{self, ConnectionContext};
+use crate::routes::database::{find_leader, worker_ctx_find_database};
+use crate::util::websocket::{
+    CloseCode, CloseFrame, Message as WsMessage, WebSocketConfig, WebSocketStream, WebSocketUpgrade,
+};
+use crate::util::{ClientAddr, NameOrIdentity};
+use crate::{log_and_500, ClientActors, DatabaseResolution, LeaderLookup};
+
+/// The capacity, in bytes, of the pipe between a multiplexed connection and each of its channels.
+const CHANNEL_PIPE_CAPACITY: usize = 64 * 1024;
+
+/// How many bytes framing a message with its channel adds to it, at most.
+const MAX_CHANNEL_FRAMING: usize = 64;
+
+/// How many databases may be attached to a multiplexed connection at once.
+const MAX_CHANNELS: usize = 32;
+
+/// The end of the pipe of a channel of a multiplexed connection held by its database's actor,
+/// or, as a client, by the multiplexer.
+type ChannelSocket = tokio_tungstenite::WebSocketStream<DuplexStream>;
+
+/// What became of an attempt to attach a database to a channel:
+/// the identity of the database, or why it couldn't be attached.
+type AttachResult = Result<Identity, (StatusCode, String)>;
+
+/// Accept a multiplexed connection, not bound to any database, see [`MultiplexClientMessage`].
+pub async fn handle_multiplexed_websocket<S>(
+    State(ctx): State<S>,
+    client_addr: ClientAddr,
+    Extension(auth): Extension<SpacetimeAuth>,
+    reconnect: Option<Extension<PresentedReconnectToken>>,
+    ws: WebSocketUpgrade,
+) -> axum::response::Result<impl IntoResponse>
+where
+    S: ClientActors + DatabaseResolution + LeaderLookup + Clone + 'static,
+{
+    // The multiplexed connection itself is never registered, but each database it attaches is admitted in turn.
+    drop(admit(&ctx)?);
+    if reconnect.is_some() {
+        Err((
+            StatusCode::BAD_REQUEST,
+            "Reconnect tokens are issued for a single database, and can't open a multiplexed connection",
+        ))?;
+    }
+
+    let (res, ws_upgrade, protocol) = ws.select_protocol([
+        (BIN_PROTOCOL_V2, (Protocol::Binary, ProtocolVersion::V2)),
+        (TEXT_PROTOCOL_V2, (Protocol::Text, ProtocolVersion::V2)),
+        (BIN_PROTOCOL, (Protocol::Binary, ProtocolVersion::V1)),
+        (TEXT_PROTOCOL, (Protocol::Text, ProtocolVersion::V1)),
+    ]);
+    let (protocol, version) = protocol.ok_or((StatusCode::BAD_REQUEST, "no valid protocol selected"))?;
+
+    // Any message of a channel is framed with its channel.
+    // The limits of the node apply to the connection, and those of each database to its channel's messages.
+    let size_limit = MessageSizeLimit::new(ctx.actor_index().websocket_options(), protocol);
+    let ws_config = WebSocketConfig::default()
+        .max_message_size(Some(size_limit.read_limit() + MAX_CHANNEL_FRAMING))
+        .max_frame_size(ctx.actor_index().websocket_options().max_frame_size)
+        .accept_unmasked_frames(false);
+    let timeouts = ctx
+        .timeouts()
+        .client_timeouts(&ConnectionTimeouts::default(), &ConnectionTimeouts::default());
+
+    // Being spawned from the multiplexer, the tasks attaching databases run on the client runtime too.
+    let runtime = ctx.actor_index().client_runtime().clone();
+    runtime.spawn(async move {
+        let ws = match ws_upgrade.upgrade(ws_config).await {
+            Ok(ws) => ws,
+            Err(err) => {
+                log::error!("WebSocket init error: {}", err);
+                return;
+            }
+        };
+        log::debug!("new multiplexed connection of {}", auth.identity);
+        let multiplexer = Multiplexer {
+            ctx,
+            auth,
+            client_addr,
+            protocol,
+            version,
+            send_timeout: timeouts.send,
+            attaching: HashMap::new(),
+            attached: HashMap::new(),
+            outgoing: SelectAll::new(),
+            next_generation: 0,
+        };
+        multiplexer.run(ws, timeouts.liveness).await;
+    });
+
+    Ok(res)
+}
+
+/// A channel whose database is attached.
+struct Channel {
+    /// Tells apart the attachments of the same channel, as it may be attached again once detached.
+    generation: u64,
+    /// Where the client's frames for the channel are forwarded.
+    sink: SplitSink<ChannelSocket, WsMessage>,
+}
+
+/// A frame sent on a channel by its database's actor, or `None` once the channel's pipe is closed.
+type ChannelEvent = (
+    u32,
+    u64,
+    Option<Result<WsMessage, tokio_tungstenite::tungstenite::Error>>,
+);
+
+/// The frame, or control message, the multiplexer couldn't send to the client in time, or at all,
+/// after which the connection is dropped.
+struct SendFailed;
+
+struct Multiplexer<S> {
+    ctx: S,
+    auth: SpacetimeAuth,
+    client_addr: ClientAddr,
+    protocol: Protocol,
+    version: ProtocolVersion,
+    /// How long a send to the client may take.
+    send_timeout: Duration,
+    /// The channels whose databases are being attached, with their generation and their end of the pipe.
+    attaching: HashMap<u32, (u64, ChannelSocket)>,
+    attached: HashMap<u32, Channel>,
+    /// The frames sent on the attached channels.
+    outgoing: SelectAll<BoxStream<'static, ChannelEvent>>,
+    next_generation: u64,
+}
+
+impl<S> Multiplexer<S>
+where
+    S: ClientActors + DatabaseResolution + LeaderLookup + Clone + 'static,
+{
+    async fn run(mut self, mut ws: WebSocketStream, liveness_timeout: Duration) {
+        let (results_tx, mut results) = mpsc::unbounded_channel();
+        let mut liveness_check_interval = tokio::time::interval(liveness_timeout);
+        let mut got_pong = true;
+        let close = loop {
+            let res = tokio::select! {
+                message = ws.next() => match message {
+                    Some(Ok(WsMessage::Binary(frame))) => match decode_channel_frame(&frame) {
+                        Some((MULTIPLEX_CONTROL_CHANNEL, message)) => match bsatn::from_slice(message) {
+                            Ok(message) => self.control(&mut ws, message, &results_tx).await,
+                            Err(e) => break Some(invalid_message(format!("invalid control message: {e}"))),
+                        },
+                        Some((channel, frame)) => {
+                            self.forward(channel, WsMessage::Binary(Bytes::copy_from_slice(frame))).await;
+                            Ok(())
+                        }
+                        None => break Some(invalid_message("binary frame without a channel".into())),
+                    },
+                    Some(Ok(WsMessage::Text(text))) => match serde_json::from_str::<ChannelFrame<Box<RawValue>>>(&text) {
+                        Ok(ChannelFrame { channel: MULTIPLEX_CONTROL_CHANNEL, message }) => {
+                            match serde_json::from_str::<DeserializeWrapper<_>>(message.get()) {
+                                Ok(DeserializeWrapper(message)) => self.control(&mut ws, message, &results_tx).await,
+                                Err(e) => break Some(invalid_message(format!("invalid control message: {e}"))),
+                            }
+                        }
+                        Ok(ChannelFrame { channel, message }) => {
+                            self.forward(channel, WsMessage::Text(message.get().to_owned().into())).await;
+                            Ok(())
+                        }
+                        Err(e) => break Some(invalid_message(format!("invalid channel frame: {e}"))),
+                    },
+                    Some(Ok(WsMessage::Pong(_))) => {
+                        got_pong = true;
+                        Ok(())
+                    }
+                    // Pings are answered, and close frames acknowledged, by tungstenite.
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(e)) => {
+                        log::debug!("multiplexed connection of {} ended: {e}", self.auth.identity);
+                        break None;
+                    }
+                    None => break None,
+                },
+
+                Some((channel, generation, result)) = results.recv() => {
+                    self.attach_result(&mut ws, channel, generation, result).await
+                }
+
+                Some((channel, generation, event)) = self.outgoing.next() => {
+                    self.channel_event(&mut ws, channel, generation, event).await
+                }
+
+                _ = liveness_check_interval.tick() => {
+                    if !mem::take(&mut got_pong) {
+                        log::warn!("multiplexed connection of {} timed out", self.auth.identity);
+                        break Some(disconnect_close_frame(ws_api::DisconnectReason::LivenessTimeout, ErrorFormat::default()));
+                    }
+                    self.send(&mut ws, WsMessage::Ping(Bytes::new())).await
+                }
+            };
+            if res.is_err() {
+                break None;
+            }
+        };
+        if let Some(frame) = close {
+            super::close_before_dropping(&mut ws, frame, tokio::time::Instant::now() + LAST_CLOSE_TIMEOUT).await;
+        }
+        // The channels are closed as if their client had closed them,
+        // rather than left to find their pipes gone.
+        for (_, mut channel) in self.attached.drain() {
+            let _ = tokio::time::timeout(LAST_CLOSE_TIMEOUT, channel.sink.send(WsMessage::Close(None))).await;
+        }
+    }
+
+    /// Act on the control `message` of the client.
+    async fn control(
+        &mut self,
+        ws: &mut WebSocketStream,
+        message: MultiplexClientMessage,
+        results: &mpsc::UnboundedSender<(u32, u64, AttachResult)>,
+    ) -> Result<(), SendFailed> {
+        match message {
+            MultiplexClientMessage::Attach(attach) => {
+                let channel = attach.channel;
+                let refusal = if channel == MULTIPLEX_CONTROL_CHANNEL
+                    || self.attaching.contains_key(&channel)
+                    || self.attached.contains_key(&channel)
+                {
+                    Some((StatusCode::CONFLICT, "the channel is in use"))
+                } else if self.attaching.len() + self.attached.len() >= MAX_CHANNELS {
+                    Some((StatusCode::TOO_MANY_REQUESTS, "too many channels are attached"))
+                } else {
+                    None
+                };
+                if let Some((status, reason)) = refusal {
+                    return self.attach_failed(ws, channel, status, reason.into()).await;
+                }
+
+                let generation = self.next_generation;
+                self.next_generation += 1;
+                let (local, remote) = tokio::io::duplex(CHANNEL_PIPE_CAPACITY);
+                let local_config = WebSocketConfig::default().max_message_size(None).max_frame_size(None);
+                let local = ChannelSocket::from_raw_socket(local, Role::Client, Some(local_config)).await;
+                let remote_config = websocket_config(self.ctx.actor_index().websocket_options(), self.protocol);
+                let remote = ChannelSocket::from_raw_socket(remote, Role::Server, Some(remote_config)).await;
+                self.attaching.insert(channel, (generation, local));
+
+                let ctx = self.ctx.clone();
+                let auth = self.auth.clone();
+                let (client_addr, protocol, version) = (self.client_addr, self.protocol, self.version);
+                let results = results.clone();
+                tokio::spawn(async move {
+                    let result = match attach_database(ctx, auth, client_addr, protocol, version, attach, remote).await
+                    {
+                        Ok(database_identity) => Ok(database_identity),
+                        Err(e) => Err(refusal_reason(e).await),
+                    };
+                    let _ = results.send((channel, generation, result));
+                });
+                Ok(())
+            }
+            MultiplexClientMessage::Detach(ws_api::Detach { channel }) => {
+                if let Some(attached) = self.attached.get_mut(&channel) {
+                    // The channel is detached once its actor acknowledges the close.
+                    let frame = CloseFrame {
+                        code: CloseCode::Normal,
+                        reason: "detached".into(),
+                    };
+                    let close =
+                        tokio::time::timeout(self.send_timeout, attached.sink.send(WsMessage::Close(Some(frame))));
+                    if !matches!(close.await, Ok(Ok(()))) {
+                        self.attached.remove(&channel);
+                    }
+                    Ok(())
+                } else if self.attaching.remove(&channel).is_some() {
+                    // Dropping its end of the pipe disconnects the database, should it have been attached meanwhile.
+                    self.detached(ws, channel, CloseCode::Normal.into(), "detached".into())
+                        .await
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Forward the `frame` of the client to `channel`.
+    async fn forward(&mut self, channel: u32, frame: WsMessage) {
+        let Some(attached) = self.attached.get_mut(&channel) else {
+            // The channel may have been detached while the client sent the frame.
+            log::debug!("dropping a frame for channel {channel}, which isn't attached");
+            return;
+        };
+        let send = tokio::time::timeout(self.send_timeout, attached.sink.send(frame));
+        if !matches!(send.await, Ok(Ok(()))) {
+            // The channel's actor isn't keeping up, or is gone, so it's dropped,
+            // and detached once its pipe is closed.
+            log::warn!(
+                "channel {channel} of {} isn't taking frames, dropping it",
+                self.auth.identity
+            );
+            self.attached.remove(&channel);
+        }
+    }
+
+    /// Act on what became of attaching a database to `channel`.
+    async fn attach_result(
+        &mut self,
+        ws: &mut WebSocketStream,
+        channel: u32,
+        generation: u64,
+        result: AttachResult,
+    ) -> Result<(), SendFailed> {
+        // The client may have detached the channel in the meantime.
+        if self
+            .attaching
+            .get(&channel)
+            .is_none_or(|(pending, _)| *pending != generation)
+        {
+            return Ok(());
+        }
+        let (_, socket) = self.attaching.remove(&channel).unwrap();
+        match result {
+            Ok(database_identity) => {
+                let attached = MultiplexServerMessage::Attached(Attached {
+                    channel,
+                    database_identity,
+                });
+                self.send_control(ws, attached).await?;
+                let (sink, frames) = socket.split();
+                self.attached.insert(channel, Channel { generation, sink });
+                let events = frames
+                    .map(move |frame| (channel, generation, Some(frame)))
+                    .chain(stream::once(async move { (channel, generation, None) }));
+                self.outgoing.push(events.boxed());
+                Ok(())
+            }
+            Err((status, reason)) => self.attach_failed(ws, channel, status, reason).await,
+        }
+    }
+
+    /// Act on the `event` of the attachment `generation` of `channel`.
+    async fn channel_event(
+        &mut self,
+        ws: &mut WebSocketStream,
+        channel: u32,
+        generation: u64,
+        event: Option<Result<WsMessage, tokio_tungstenite::tungstenite::Error>>,
+    ) -> Result<(), SendFailed> {
+        let current = self.attached.get(&channel).is_some_and(|c| c.generation == generation);
+        match event {
+            Some(Ok(WsMessage::Binary(frame))) if current => {
+                let frame = encode_channel_frame(channel, &frame);
+                self.send(ws, WsMessage::Binary(frame.into())).await
+            }
+            Some(Ok(WsMessage::Text(text))) if current => {
+                let Ok(message) = RawValue::from_string(text.to_string()) else {
+                    log::warn!("dropping a text frame of channel {channel} which isn't JSON");
+                    return Ok(());
+                };
+                let frame = serde_json::to_string(&ChannelFrame { channel, message }).unwrap();
+                self.send(ws, WsMessage::Text(frame.into())).await
+            }
+            // The database's connection was closed, e.g. as its module exited.
+            Some(Ok(WsMessage::Close(frame))) if current => {
+                self.attached.remove(&channel);
+                let (code, reason) = frame.map_or((CloseCode::Normal.into(), String::new()), |frame| {
+                    (frame.code.into(), frame.reason.to_string())
+                });
+                self.detached(ws, channel, code, reason).await
+            }
+            Some(Err(_)) | None if current => {
+                self.attached.remove(&channel);
+                self.detached(ws, channel, CloseCode::Abnormal.into(), "connection lost".into())
+                    .await
+            }
+            // Pings from the channel's actor are answered by tungstenite.
+            _ => Ok(()),
+        }
+    }
+
+    async fn attach_failed(
+        &mut self,
+        ws: &mut WebSocketStream,
+        channel: u32,
+        status: StatusCode,
+        reason: String,
+    ) -> Result<(), SendFailed> {
+        let failed = MultiplexServerMessage::AttachFailed(AttachFailed {
+            channel,
+            status: status.as_u16(),
+            reason: reason.into(),
+        });
+        self.send_control(ws, failed).await
+    }
+
+    async fn detached(
+        &mut self,
+        ws: &mut WebSocketStream,
+        channel: u32,
+        code: u16,
+        reason: String,
+    ) -> Result<(), SendFailed> {
+        let detached = MultiplexServerMessage::Detached(Detached {
+            channel,
+            code,
+            reason: reason.into(),
+        });
+        self.send_control(ws, detached).await
+    }
+
+    /// Send the control `message` to the client, in the protocol of the connection.
+    async fn send_control(
+        &mut self,
+        ws: &mut WebSocketStream,
+        message: MultiplexServerMessage,
+    ) -> Result<(), SendFailed> {
+        let frame = match self.protocol {
+            Protocol::Binary => {
+                let message = bsatn::to_vec(&message).unwrap();
+                WsMessage::Binary(encode_channel_frame(MULTIPLEX_CONTROL_CHANNEL, &message).into())
+            }
+            Protocol::Text => {
+                let message = serde_json::to_string(&SerializeWrapper::from_ref(&message)).unwrap();
+                let frame = ChannelFrame {
+                    channel: MULTIPLEX_CONTROL_CHANNEL,
+                    message: RawValue::from_string(message).unwrap(),
+                };
+                WsMessage::Text(serde_json::to_string(&frame).unwrap().into())
+            }
+        };
+        self.send(ws, frame).await
+    }
+
+    async fn send(&mut self, ws: &mut WebSocketStream, frame: WsMessage) -> Result<(), SendFailed> {
+        match tokio::time::timeout(self.send_timeout, ws.send(frame)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                log::warn!("Websocket send error: {e}");
+                Err(SendFailed)
+            }
+            Err(_) => {
+                log::warn!("send to multiplexed connection of {} timed out", self.auth.identity);
+                Err(SendFailed)
+            }
+        }
+    }
+}
+
+/// The close frame of a multiplexed connection whose client sent a frame which isn't valid.
+fn invalid_message(reason: String) -> CloseFrame {
+    log::info!("closing multiplexed connection: {reason}");
+    CloseFrame {
+        code: CloseCode::Protocol,
+        reason: reason.into(),
+    }
+}
+
+/// The status and body of the response with which a connection would have been refused, as `e`.
+async fn refusal_reason(e: axum::response::ErrorResponse) -> (StatusCode, String) {
+    // `ErrorResponse` only turns into a response as the error of an `axum::response::Result`.
+    let response = Err::<(), _>(e).into_response();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), 64 * 1024)
+        .await
+        .unwrap_or_default();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Connect the client of `auth` to the database of `attach`, as over a websocket of its own, `ws`,
+/// performing the checks of [`handle_websocket`](super::handle_websocket),
+/// and return the identity of the database.
+async fn attach_database<S>(
+    ctx: S,
+    auth: SpacetimeAuth,
+    client_addr: ClientAddr,
+    protocol: Protocol,
+    version: ProtocolVersion,
+    attach: Attach,
+    ws: ChannelSocket,
+) -> axum::response::Result<Identity>
+where
+    S: ClientActors + DatabaseResolution + LeaderLookup + Clone + 'static,
+{
+    let admission = admit(&ctx)?;
+    let name_or_identity: NameOrIdentity = attach
+        .database
+        .parse()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid database: {e}")))?;
+    let db_identity = name_or_identity.resolve(&ctx).await?;
+    acl::check(&ctx, &db_identity, client_addr).await?;
+    let config_defaults = ctx
+        .find_client_config_defaults(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    let database_timeouts = ctx
+        .find_connection_timeouts(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    let timeouts_config = ctx.timeouts();
+    let timeouts = timeouts_config.client_timeouts(&database_timeouts, &ConnectionTimeouts::default());
+
+    if let Some(compression) = attach.compression.filter(|&compression| !version.supports(compression)) {
+        Err((
+            StatusCode::BAD_REQUEST,
+            format!("The requested compression, {compression:?}, requires a newer version of the protocol."),
+        ))?;
+    }
+    // A channel has no way to tell the client what it negotiated, so it negotiates none of the database's features.
+    let (compression, light) = merge_config_defaults(
+        version,
+        attach.compression,
+        attach.light,
+        &config_defaults,
+        DatabaseFeatures::default(),
+    );
+    // Channels only get to choose the `light` preset, not the knobs it sets.
+    let knobs = UpdateKnobs::preset(light);
+    let size_limits = ctx
+        .find_message_size_limits(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    let websocket_options = ctx
+        .actor_index()
+        .websocket_options()
+        .with_message_size_limits(&size_limits);
+    let client_config = ClientConfig {
+        protocol,
+        version,
+        compression,
+        tx_update_full: knobs.tx_update_full,
+        include_caller_info: knobs.include_caller_info,
+        include_timings: knobs.include_timings,
+        reducer_timings: false,
+        acknowledged_delivery: false,
+        request_errors: false,
+        zstd_level: websocket_options.zstd_level,
+        compression_threshold: ws_api::DEFAULT_COMPRESSION_THRESHOLD,
+    };
+    let connection_id = ctx.actor_index().generate_connection_id(generate_random_connection_id);
+    let connection_id_reservation =
+        reserve_client_connection_id(ctx.actor_index(), db_identity, auth.identity, connection_id)?;
+
+    let hooks = ctx.lifecycle_hooks();
+    let conn = ConnectionContext {
+        identity: auth.identity,
+        connection_id,
+        database_identity: db_identity,
+        config: client_config,
+        deprecated_features: Vec::new(),
+    };
+    hooks::on_connect(&*hooks, &conn).await?;
+
+    let database = worker_ctx_find_database(&ctx, &db_identity)
+        .await?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let leader = find_leader(&ctx, &database).await?;
+    let module_rx = leader.module_watcher().await.map_err(log_and_500)?;
+    let module = module_rx.borrow().clone();
+    let error_format = module.error_format().await;
+    let client_metrics_allowlisted = module.is_client_metrics_allowlisted(auth.identity).await;
+    // Each channel is limited on its own, as a connection of its own would be.
+    let rate_limit = module
+        .client_message_rate_limit(ctx.actor_index().websocket_options().message_rate_limit())
+        .await;
+    let send_backlog = ctx
+        .find_send_backlog_policy(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+    let auth_expiry = ctx
+        .find_auth_expiry_policy(&db_identity)
+        .map_err(log_and_500)?
+        .unwrap_or_default();
+
+    let client_id = ClientActorId {
+        identity: auth.identity,
+        connection_id,
+        name: ctx.actor_index().next_client_name(),
+    };
+    let actor = |client: ClientConnection, sendrx| {
+        let registration = ctx.actor_index().register_client(&client, client_addr.ip());
+        drop(connection_id_reservation);
+        drop(admission);
+        client.set_auth_expiry(auth.expires_at);
+        let options = websocket_options.clone();
+        let validator = Arc::new(ClientTokenValidator(ctx.clone()));
+        let connection_ids: ConnectionIds = {
+            let ctx = ctx.clone();
+            Arc::new(move || ctx.actor_index().generate_connection_id(generate_random_connection_id))
+        };
+        // The client reconnects by attaching the database again, so it's issued no reconnect token.
+        let lifetime = timeouts_config
+            .max_connection_lifetime
+            .map(|max_lifetime| ConnectionLifetime::new(max_lifetime, timeouts_config.reconnect_notice, None));
+        ws_client_actor(
+            registration,
+            options,
+            error_format,
+            rate_limit,
+            send_backlog,
+            auth_expiry,
+            timeouts_config,
+            timeouts,
+            validator,
+            connection_ids,
+            lifetime,
+            (hooks, conn),
+            DeprecationNotices::default(),
+            None,
+            None,
+            client,
+            ws,
+            sendrx,
+        )
+    };
+    let client_series = ctx
+        .actor_index()
+        .client_series()
+        .admit(db_identity, client_id.identity, client_metrics_allowlisted)
+        .map(Arc::new);
+    let policy = ConnectionPolicy {
+        one_off_query_limits: websocket_options.one_off_query_limits(),
+        load_admission: ctx.actor_index().load_admission().clone(),
+        error_format,
+        connect_retry: websocket_options.connect_retry(),
+        client_series,
+        runtime: ctx.actor_index().client_runtime().clone(),
+        send_backlog,
+        addr: client_addr.0,
+        config_defaults,
+    };
+    let client = ClientConnection::spawn(
+        client_id,
+        client_config,
+        leader.replica_id,
+        module_rx,
+        policy,
+        None,
+        actor,
+    )
+    .await
+    .map_err(|e| {
+        let status = if e.is_transient() {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::FORBIDDEN
+        };
+        (status, connect_failed_close_frame(&e, error_format).reason.to_string())
+    })?;
+
+    let message = IdentityTokenMessage {
+        identity: auth.identity,
+        token: auth.creds.token().into(),
+        connection_id,
+    };
+    // Should it fail, the database's connection is closed, and the channel detached.
+    let _ = send_identity_token(&client, db_identity, message);
+    Ok(db_identity)
+}
@@ -0,0 +1,200 @@
+//! Watching that the message in flight of a [`ws_client_actor`](super::ws_client_actor) is polled promptly.
+//!
+//! The actor handles one message at a time, which it must keep polling from every branch of its `select!`
+//! which awaits anything, or else the message, e.g. a reducer call, completes but isn't noticed to.
+//! A [`PollWatchdog`] measures how long the message waits to be polled once it's woken,
+//! blaming gaps over its budget on the branch of the actor most recently [entered](PollWatchdog::enter).
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use prometheus::Histogram;
+
+/// Watches the messages in flight of a connection, see the [module docs](self).
+///
+/// The longest gap of the connection is recorded in the `ws_message_poll_max_gap_seconds` metric
+/// when the watchdog is dropped, if a message was ever in flight.
+pub(super) struct PollWatchdog {
+    /// Gaps longer than this are logged as a warning.
+    budget: Duration,
+    /// The minimum time between warnings.
+    warn_interval: Duration,
+    max_gap_seconds: Histogram,
+    state: Mutex<WatchdogState>,
+}
+
+#[derive(Default)]
+struct WatchdogState {
+    /// The branch of the actor's `select!` most recently entered.
+    branch: &'static str,
+    /// The longest gap so far, if a message was ever polled.
+    max_gap: Option<Duration>,
+    /// How many gaps exceeded the budget.
+    starved: u64,
+    last_warning: Option<Instant>,
+}
+
+impl PollWatchdog {
+    pub(super) fn new(budget: Duration, warn_interval: Duration, max_gap_seconds: Histogram) -> Arc<Self> {
+        Arc::new(Self {
+            budget,
+            warn_interval,
+            max_gap_seconds,
+            state: Mutex::new(WatchdogState {
+                branch: "select",
+                ..<_>::default()
+            }),
+        })
+    }
+
+    /// Note that the actor is now in `branch`, which is blamed for gaps until another is entered.
+    pub(super) fn enter(&self, branch: &'static str) {
+        self.state.lock().unwrap().branch = branch;
+    }
+
+    /// Watch `fut`, a message in flight.
+    pub(super) fn watch<T>(self: &Arc<Self>, fut: BoxFuture<'static, T>) -> Watched<T> {
+        let waker = Arc::new(GapWaker {
+            // The message is ready to be polled as soon as it's in flight.
+            woken_at: Mutex::new(Some(Instant::now())),
+            inner: Mutex::new(futures::task::noop_waker()),
+        });
+        Watched {
+            fut,
+            waker,
+            watchdog: self.clone(),
+        }
+    }
+
+    /// Record that a message was polled `gap` after it was woken.
+    fn polled(&self, gap: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.max_gap = state.max_gap.max(Some(gap));
+        if gap <= self.budget {
+            return;
+        }
+        state.starved += 1;
+        if state.last_warning.is_none_or(|at| at.elapsed() >= self.warn_interval) {
+            state.last_warning = Some(Instant::now());
+            tracing::warn!(
+                ?gap,
+                budget = ?self.budget,
+                branch = state.branch,
+                starved = state.starved,
+                "the message in flight was not polled for a long time"
+            );
+        }
+    }
+}
+
+impl Drop for PollWatchdog {
+    fn drop(&mut self) {
+        if let Some(max_gap) = self.state.get_mut().unwrap().max_gap {
+            self.max_gap_seconds.observe(max_gap.as_secs_f64());
+        }
+    }
+}
+
+/// Notes when the message it's the waker of is woken, see [`PollWatchdog::watch`].
+struct GapWaker {
+    woken_at: Mutex<Option<Instant>>,
+    /// The waker of the task polling the message.
+    inner: Mutex<Waker>,
+}
+
+impl Wake for GapWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken_at.lock().unwrap().get_or_insert_with(Instant::now);
+        self.inner.lock().unwrap().wake_by_ref();
+    }
+}
+
+/// A message in flight, watched by a [`PollWatchdog`].
+pub(super) struct Watched<T> {
+    fut: BoxFuture<'static, T>,
+    waker: Arc<GapWaker>,
+    watchdog: Arc<PollWatchdog>,
+}
+
+impl<T> Future for Watched<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let this = self.get_mut();
+        let woken_at = this.waker.woken_at.lock().unwrap().take();
+        if let Some(woken_at) = woken_at {
+            this.watchdog.polled(woken_at.elapsed());
+        }
+        {
+            let mut inner = this.waker.inner.lock().unwrap();
+            if !inner.will_wake(cx.waker()) {
+                inner.clone_from(cx.waker());
+            }
+        }
+        let waker = Waker::from(this.waker.clone());
+        this.fut.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    const BUDGET: Duration = Duration::from_millis(50);
+
+    fn watchdog() -> Arc<PollWatchdog> {
+        let histogram = Histogram::with_opts(prometheus::HistogramOpts::new("gap", "gap")).unwrap();
+        PollWatchdog::new(BUDGET, Duration::from_secs(60), histogram)
+    }
+
+    fn starved(watchdog: &PollWatchdog) -> u64 {
+        watchdog.state.lock().unwrap().starved
+    }
+
+    #[test]
+    fn a_blocked_branch_starving_the_message_is_caught() {
+        let watchdog = watchdog();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let mut message = watchdog.watch(rx.boxed());
+
+        // Polled as soon as it's in flight, then left waiting on the reducer.
+        assert!((&mut message).now_or_never().is_none());
+        assert_eq!(starved(&watchdog), 0);
+
+        // The reducer completes while the actor is stuck in a branch which doesn't poll the message.
+        tx.send(()).unwrap();
+        watchdog.enter("blocking");
+        std::thread::sleep(BUDGET * 2);
+        assert_eq!((&mut message).now_or_never(), Some(Ok(())));
+
+        let state = watchdog.state.lock().unwrap();
+        assert_eq!(state.starved, 1);
+        assert_eq!(state.branch, "blocking");
+        assert!(state.max_gap.unwrap() >= BUDGET * 2);
+        assert!(state.last_warning.is_some());
+    }
+
+    #[test]
+    fn waiting_without_being_woken_is_not_starvation() {
+        let watchdog = watchdog();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let mut message = watchdog.watch(rx.boxed());
+        assert!((&mut message).now_or_never().is_none());
+
+        // A long reducer isn't the actor's fault.
+        std::thread::sleep(BUDGET * 2);
+        tx.send(()).unwrap();
+        assert_eq!((&mut message).now_or_never(), Some(Ok(())));
+        assert_eq!(starved(&watchdog), 0);
+        assert!(watchdog.state.lock().unwrap().max_gap.unwrap() < BUDGET);
+    }
+}
@@ -0,0 +1,644 @@
+//! The timeouts of websocket connections, in one place.
+//!
+//! A [`TimeoutsConfig`] is read from the `[websocket]` section of `config.toml`,
+//! alongside the [`WebSocketOptions`](spacetimedb::client::WebSocketOptions) which aren't timeouts,
+//! and [validated](TimeoutsConfig::validate) as a whole before it's used,
+//! so that timeouts which can't work together are refused at startup rather than misbehaving later.
+//!
+//! A node holds its config in a [`ReloadableTimeouts`], which may be replaced while the node runs.
+//! Each connection takes the config current when it connects, and keeps it for its lifetime.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use spacetimedb::config::{parse_config, watch_config_toml};
+use spacetimedb::messages::control_db::ConnectionTimeouts;
+use spacetimedb_paths::server::ConfigToml;
+use tokio::sync::watch;
+
+/// How often `config.toml` is checked for changes, see [`ReloadableTimeouts::watch`].
+pub use spacetimedb::config::RELOAD_INTERVAL;
+
+/// The timeouts of the websocket connections to a node.
+///
+/// Read from the `[websocket]` section of `config.toml`.
+/// The defaults are those of [`Default`], which are always [valid](Self::validate).
+#[serde_with::serde_as]
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct TimeoutsConfig {
+    /// Sending a batch of messages to a client taking longer than this is logged as a warning,
+    /// at most once per connection per [`Self::slow_send_warn_interval`].
+    ///
+    /// Defaults to 50ms, and may be no longer than [`Self::send_timeout`].
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(rename = "slow-send-warn-threshold-ms")]
+    pub slow_send_warn_threshold: Duration,
+    /// The minimum time between slow send warnings for a single connection.
+    ///
+    /// Defaults to 60s.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "slow-send-warn-interval-secs")]
+    pub slow_send_warn_interval: Duration,
+    /// A connection's message in flight, e.g. a reducer call, waiting longer than this to be polled once woken
+    /// is logged as a warning, at most once per connection per [`Self::slow_send_warn_interval`].
+    ///
+    /// Defaults to 250ms.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(rename = "poll-starvation-budget-ms")]
+    pub poll_starvation_budget: Duration,
+    /// The total time allowed for closing a connection,
+    /// including sending the close frame and disconnecting the client from its module,
+    /// after which the connection is abandoned.
+    ///
+    /// Defaults to 5s, and may be no shorter than [`Self::send_timeout`],
+    /// so that the close frame has as long to be sent as any other message.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(rename = "teardown-timeout-ms")]
+    pub teardown_timeout: Duration,
+    /// How long a client may reconnect with the reconnect token it was issued at connect.
+    /// If zero, no reconnect tokens are issued.
+    ///
+    /// Defaults to 60s.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "reconnect-token-ttl-secs")]
+    pub reconnect_token_ttl: Duration,
+    /// The longest a connection may live.
+    ///
+    /// Each connection gets a deadline within the last tenth of this,
+    /// chosen at random so that connections opened together don't all reconnect together.
+    /// Once the deadline is within [`Self::reconnect_notice`], the client is asked to reconnect,
+    /// and once it has passed, the connection is closed.
+    /// Both are checked as often as the connection is pinged.
+    ///
+    /// Unset by default, letting connections live indefinitely.
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(rename = "max-connection-lifetime-secs")]
+    pub max_connection_lifetime: Option<Duration>,
+    /// How long before its deadline a client is asked to reconnect,
+    /// see [`Self::max_connection_lifetime`].
+    ///
+    /// Defaults to 300s, and must be shorter than [`Self::max_connection_lifetime`], if set.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "reconnect-notice-secs")]
+    pub reconnect_notice: Duration,
+    /// How often clients are pinged, and so how long they have to answer a ping,
+    /// unless their database overrides it, see [`ConnectionTimeouts`].
+    ///
+    /// Defaults to 60s, and must be longer than [`Self::send_timeout`],
+    /// so that a client is never disconnected before the ping it failed to answer could reach it.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "liveness-timeout-secs")]
+    pub liveness_timeout: Duration,
+    /// The shortest liveness timeout a database or client may choose.
+    ///
+    /// Defaults to 10s, and must be longer than [`Self::min_send_timeout`].
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "min-liveness-timeout-secs")]
+    pub min_liveness_timeout: Duration,
+    /// The longest liveness timeout a database or client may choose.
+    ///
+    /// Defaults to 600s.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "max-liveness-timeout-secs")]
+    pub max_liveness_timeout: Duration,
+    /// How long sending a batch of messages or a ping to a client may take,
+    /// unless its database overrides it, see [`ConnectionTimeouts`].
+    ///
+    /// Defaults to 5s.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "send-timeout-secs")]
+    pub send_timeout: Duration,
+    /// The shortest send timeout a database or client may choose.
+    ///
+    /// Defaults to 1s.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "min-send-timeout-secs")]
+    pub min_send_timeout: Duration,
+    /// The longest send timeout a database or client may choose.
+    ///
+    /// Defaults to 60s.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "max-send-timeout-secs")]
+    pub max_send_timeout: Duration,
+    /// How long a client's messages may stop arriving, while it's still sent messages,
+    /// before it's sent a [`ConnectionDegraded`](spacetimedb_client_api_messages::websocket::ConnectionDegraded) notice.
+    ///
+    /// A client which answers pings but whose own messages never arrive is typically behind a half-broken NAT.
+    /// Only clients which have sent a message since connecting are noticed, and only while messages are sent to them,
+    /// as clients which only receive are otherwise indistinguishable.
+    /// Checked as often as the connection is pinged.
+    ///
+    /// Unset by default.
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(rename = "inbound-silence-notice-secs")]
+    pub inbound_silence_notice: Option<Duration>,
+    /// How long a client's messages may stop arriving, as for [`Self::inbound_silence_notice`],
+    /// before its connection is closed as degraded.
+    ///
+    /// Unset by default, and must be longer than [`Self::inbound_silence_notice`], if both are set.
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(rename = "inbound-silence-close-secs")]
+    pub inbound_silence_close: Option<Duration>,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            slow_send_warn_threshold: Duration::from_millis(50),
+            slow_send_warn_interval: Duration::from_secs(60),
+            poll_starvation_budget: Duration::from_millis(250),
+            teardown_timeout: Duration::from_secs(5),
+            reconnect_token_ttl: Duration::from_secs(60),
+            max_connection_lifetime: None,
+            reconnect_notice: Duration::from_secs(300),
+            liveness_timeout: Duration::from_secs(60),
+            min_liveness_timeout: Duration::from_secs(10),
+            max_liveness_timeout: Duration::from_secs(600),
+            send_timeout: Duration::from_secs(5),
+            min_send_timeout: Duration::from_secs(1),
+            max_send_timeout: Duration::from_secs(60),
+            inbound_silence_notice: None,
+            inbound_silence_close: None,
+        }
+    }
+}
+
+/// Returned by [`TimeoutsConfig::validate`] for timeouts which can't work together.
+///
+/// Timeouts are named by their keys in `config.toml`.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum TimeoutsError {
+    #[error("`{name}` must not be zero")]
+    Zero { name: &'static str },
+    #[error("`{name}` of {value:?} is outside of its bounds, {min:?} to {max:?}")]
+    OutOfBounds {
+        name: &'static str,
+        value: Duration,
+        min: Duration,
+        max: Duration,
+    },
+    #[error("`teardown-timeout-ms` of {teardown:?} is shorter than `send-timeout-secs` of {send:?}, leaving too little time to send the close frame")]
+    TeardownShorterThanSend { teardown: Duration, send: Duration },
+    #[error("`{liveness_name}` of {liveness:?} is no longer than `{send_name}` of {send:?}, so clients could be disconnected before a ping reaches them")]
+    LivenessWithinSend {
+        liveness_name: &'static str,
+        liveness: Duration,
+        send_name: &'static str,
+        send: Duration,
+    },
+    #[error("`reconnect-notice-secs` of {notice:?} is no shorter than `max-connection-lifetime-secs` of {lifetime:?}, so clients would be asked to reconnect as soon as they connect")]
+    NoticeWithinLifetime { notice: Duration, lifetime: Duration },
+    #[error("`slow-send-warn-threshold-ms` of {threshold:?} is longer than `send-timeout-secs` of {send:?}, so slow sends would time out before being warned of")]
+    SlowSendBeyondSend { threshold: Duration, send: Duration },
+    #[error("`inbound-silence-close-secs` of {close:?} is no longer than `inbound-silence-notice-secs` of {notice:?}, so clients would be closed without notice")]
+    SilenceCloseWithinNotice { close: Duration, notice: Duration },
+}
+
+/// The `config.toml` sections a [`TimeoutsConfig`] is read from.
+#[derive(serde::Deserialize, Default)]
+struct TimeoutsConfigFile {
+    #[serde(default)]
+    websocket: TimeoutsConfig,
+}
+
+impl TimeoutsConfig {
+    /// Read the config from the `config.toml` at `path`, returning `None` if there's no such file.
+    ///
+    /// The config isn't validated, see [`Self::validate`].
+    pub fn read(path: &Path) -> anyhow::Result<Option<Self>> {
+        Ok(parse_config::<TimeoutsConfigFile>(path)?.map(|file| file.websocket))
+    }
+
+    /// Check that the timeouts can work together, returning the first rule they break, if any.
+    pub fn validate(&self) -> Result<(), TimeoutsError> {
+        let non_zero = |name, value: Duration| {
+            if value.is_zero() {
+                return Err(TimeoutsError::Zero { name });
+            }
+            Ok(())
+        };
+        non_zero("teardown-timeout-ms", self.teardown_timeout)?;
+        // Liveness checks run on an interval, which can't be zero.
+        non_zero("min-liveness-timeout-secs", self.min_liveness_timeout)?;
+        non_zero("min-send-timeout-secs", self.min_send_timeout)?;
+
+        let within = |name, value: Duration, min: Duration, max: Duration| {
+            if !(min..=max).contains(&value) {
+                return Err(TimeoutsError::OutOfBounds { name, value, min, max });
+            }
+            Ok(())
+        };
+        within(
+            "liveness-timeout-secs",
+            self.liveness_timeout,
+            self.min_liveness_timeout,
+            self.max_liveness_timeout,
+        )?;
+        within(
+            "send-timeout-secs",
+            self.send_timeout,
+            self.min_send_timeout,
+            self.max_send_timeout,
+        )?;
+
+        if self.teardown_timeout < self.send_timeout {
+            return Err(TimeoutsError::TeardownShorterThanSend {
+                teardown: self.teardown_timeout,
+                send: self.send_timeout,
+            });
+        }
+        for (liveness_name, liveness, send_name, send) in [
+            (
+                "liveness-timeout-secs",
+                self.liveness_timeout,
+                "send-timeout-secs",
+                self.send_timeout,
+            ),
+            (
+                "min-liveness-timeout-secs",
+                self.min_liveness_timeout,
+                "min-send-timeout-secs",
+                self.min_send_timeout,
+            ),
+        ] {
+            if liveness <= send {
+                return Err(TimeoutsError::LivenessWithinSend {
+                    liveness_name,
+                    liveness,
+                    send_name,
+                    send,
+                });
+            }
+        }
+        if let Some(lifetime) = self.max_connection_lifetime {
+            if self.reconnect_notice >= lifetime {
+                return Err(TimeoutsError::NoticeWithinLifetime {
+                    notice: self.reconnect_notice,
+                    lifetime,
+                });
+            }
+        }
+        if self.slow_send_warn_threshold > self.send_timeout {
+            return Err(TimeoutsError::SlowSendBeyondSend {
+                threshold: self.slow_send_warn_threshold,
+                send: self.send_timeout,
+            });
+        }
+        if let (Some(notice), Some(close)) = (self.inbound_silence_notice, self.inbound_silence_close) {
+            if close <= notice {
+                return Err(TimeoutsError::SilenceCloseWithinNotice { close, notice });
+            }
+        }
+        Ok(())
+    }
+
+    /// The timeouts of a client which asked for the `requested` timeouts, if any,
+    /// connecting to a database which overrides the node's with `database`.
+    ///
+    /// What the client asked for wins over its database's overrides,
+    /// and either is clamped to the node's bounds.
+    pub fn client_timeouts(&self, database: &ConnectionTimeouts, requested: &ConnectionTimeouts) -> ClientTimeouts {
+        let resolve =
+            |requested: Option<u64>, database: Option<u64>, default: Duration, min: Duration, max: Duration| {
+                requested
+                    .or(database)
+                    .map_or(default, |secs| Duration::from_secs(secs).min(max).max(min))
+            };
+        ClientTimeouts {
+            liveness: resolve(
+                requested.liveness_timeout_secs,
+                database.liveness_timeout_secs,
+                self.liveness_timeout,
+                self.min_liveness_timeout,
+                self.max_liveness_timeout,
+            ),
+            send: resolve(
+                requested.send_timeout_secs,
+                database.send_timeout_secs,
+                self.send_timeout,
+                self.min_send_timeout,
+                self.max_send_timeout,
+            ),
+        }
+    }
+}
+
+/// The timeouts after which an unresponsive client is disconnected,
+/// see [`TimeoutsConfig::client_timeouts`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientTimeouts {
+    /// How often the client is pinged.
+    /// If it hasn't answered a ping by the time the next is due, it's disconnected.
+    pub liveness: Duration,
+    /// How long a send to the client may take.
+    pub send: Duration,
+}
+
+/// The [`TimeoutsConfig`] of a node, which may be replaced while it runs.
+///
+/// Only valid configs are ever current.
+pub struct ReloadableTimeouts {
+    current: watch::Sender<Arc<TimeoutsConfig>>,
+}
+
+impl ReloadableTimeouts {
+    /// Hold `config`, if it's valid.
+    pub fn new(config: TimeoutsConfig) -> Result<Self, TimeoutsError> {
+        config.validate()?;
+        log::info!("websocket timeouts: {config:?}");
+        Ok(Self {
+            current: watch::Sender::new(Arc::new(config)),
+        })
+    }
+
+    /// The current config.
+    pub fn current(&self) -> Arc<TimeoutsConfig> {
+        self.current.borrow().clone()
+    }
+
+    /// Replace the current config with `config`, if it's valid.
+    ///
+    /// Otherwise, the current config is kept.
+    /// Connections already open keep the config they connected with.
+    pub fn reload(&self, config: TimeoutsConfig) -> Result<(), TimeoutsError> {
+        config.validate()?;
+        log::info!("reloaded websocket timeouts: {config:?}");
+        self.current.send_replace(Arc::new(config));
+        Ok(())
+    }
+
+    /// Reload the config from `config_toml` whenever it's modified, checking every [`RELOAD_INTERVAL`],
+    /// for as long as `self` lives.
+    ///
+    /// Configs which can't be read or are invalid are logged and ignored.
+    pub fn watch(self: &Arc<Self>, config_toml: ConfigToml) {
+        watch_config_toml(self, config_toml, |this, config_toml| {
+            match TimeoutsConfig::read(config_toml.as_ref()) {
+                Ok(Some(config)) => {
+                    if let Err(e) = this.reload(config) {
+                        log::error!("not reloading websocket timeouts, keeping the current ones: {e}");
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("failed to read websocket timeouts from config.toml: {e:#}"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(TimeoutsConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn minimums_must_not_be_zero() {
+        for (name, config) in [
+            (
+                "teardown-timeout-ms",
+                TimeoutsConfig {
+                    teardown_timeout: Duration::ZERO,
+                    ..<_>::default()
+                },
+            ),
+            (
+                "min-liveness-timeout-secs",
+                TimeoutsConfig {
+                    min_liveness_timeout: Duration::ZERO,
+                    ..<_>::default()
+                },
+            ),
+            (
+                "min-send-timeout-secs",
+                TimeoutsConfig {
+                    min_send_timeout: Duration::ZERO,
+                    ..<_>::default()
+                },
+            ),
+        ] {
+            assert_eq!(config.validate(), Err(TimeoutsError::Zero { name }));
+        }
+    }
+
+    #[test]
+    fn defaults_must_be_within_their_bounds() {
+        let config = TimeoutsConfig {
+            liveness_timeout: secs(601),
+            ..<_>::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(TimeoutsError::OutOfBounds {
+                name: "liveness-timeout-secs",
+                value: secs(601),
+                min: secs(10),
+                max: secs(600),
+            })
+        );
+        let config = TimeoutsConfig {
+            max_send_timeout: secs(4),
+            ..<_>::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(TimeoutsError::OutOfBounds {
+                name: "send-timeout-secs",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn teardown_must_allow_for_a_send() {
+        let config = TimeoutsConfig {
+            teardown_timeout: secs(4),
+            ..<_>::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(TimeoutsError::TeardownShorterThanSend {
+                teardown: secs(4),
+                send: secs(5),
+            })
+        );
+    }
+
+    #[test]
+    fn liveness_must_exceed_send() {
+        let config = TimeoutsConfig {
+            liveness_timeout: secs(10),
+            send_timeout: secs(10),
+            teardown_timeout: secs(10),
+            ..<_>::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(TimeoutsError::LivenessWithinSend {
+                liveness_name: "liveness-timeout-secs",
+                ..
+            })
+        ));
+        let config = TimeoutsConfig {
+            min_liveness_timeout: secs(1),
+            ..<_>::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(TimeoutsError::LivenessWithinSend {
+                liveness_name: "min-liveness-timeout-secs",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn reconnect_notice_must_be_within_the_lifetime() {
+        let config = TimeoutsConfig {
+            max_connection_lifetime: Some(secs(300)),
+            ..<_>::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(TimeoutsError::NoticeWithinLifetime {
+                notice: secs(300),
+                lifetime: secs(300),
+            })
+        );
+        let config = TimeoutsConfig {
+            max_connection_lifetime: Some(secs(3600)),
+            ..<_>::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn inbound_silence_must_be_noticed_before_closing() {
+        let config = TimeoutsConfig {
+            inbound_silence_notice: Some(secs(60)),
+            inbound_silence_close: Some(secs(60)),
+            ..<_>::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(TimeoutsError::SilenceCloseWithinNotice {
+                close: secs(60),
+                notice: secs(60),
+            })
+        );
+        for (notice, close) in [
+            (Some(secs(60)), Some(secs(300))),
+            (None, Some(secs(60))),
+            (Some(secs(60)), None),
+        ] {
+            let config = TimeoutsConfig {
+                inbound_silence_notice: notice,
+                inbound_silence_close: close,
+                ..<_>::default()
+            };
+            assert_eq!(config.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn slow_send_warnings_must_precede_the_send_timeout() {
+        let config = TimeoutsConfig {
+            slow_send_warn_threshold: Duration::from_millis(5001),
+            ..<_>::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(TimeoutsError::SlowSendBeyondSend {
+                threshold: Duration::from_millis(5001),
+                send: secs(5),
+            })
+        );
+    }
+
+    #[test]
+    fn config_is_read_from_the_websocket_section() {
+        let file: TimeoutsConfigFile = toml::from_str(
+            r#"
+            [websocket]
+            teardown-timeout-ms = 10000
+            liveness-timeout-secs = 30
+            max-message-size = 1024
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            file.websocket,
+            TimeoutsConfig {
+                teardown_timeout: secs(10),
+                liveness_timeout: secs(30),
+                ..<_>::default()
+            }
+        );
+        let file: TimeoutsConfigFile = toml::from_str("").unwrap();
+        assert_eq!(file.websocket, TimeoutsConfig::default());
+    }
+
+    #[test]
+    fn invalid_configs_are_not_reloaded() {
+        let timeouts = ReloadableTimeouts::new(TimeoutsConfig::default()).unwrap();
+        let longer = TimeoutsConfig {
+            teardown_timeout: secs(10),
+            ..<_>::default()
+        };
+        timeouts.reload(longer.clone()).unwrap();
+        assert_eq!(*timeouts.current(), longer);
+
+        let invalid = TimeoutsConfig {
+            teardown_timeout: secs(1),
+            ..<_>::default()
+        };
+        assert!(timeouts.reload(invalid.clone()).is_err());
+        assert_eq!(*timeouts.current(), longer);
+        assert!(ReloadableTimeouts::new(invalid).is_err());
+    }
+
+    #[test]
+    fn client_timeouts_are_clamped_to_the_nodes_bounds() {
+        let config = TimeoutsConfig::default();
+        let timeouts = |liveness_timeout_secs, send_timeout_secs| ConnectionTimeouts {
+            liveness_timeout_secs,
+            send_timeout_secs,
+        };
+
+        assert_eq!(
+            config.client_timeouts(&timeouts(None, None), &timeouts(None, None)),
+            ClientTimeouts {
+                liveness: config.liveness_timeout,
+                send: config.send_timeout,
+            }
+        );
+        // One database may have longer timeouts, and another shorter ones.
+        let long = config.client_timeouts(&timeouts(Some(120), Some(20)), &timeouts(None, None));
+        assert_eq!((long.liveness, long.send), (secs(120), secs(20)));
+        let short = config.client_timeouts(&timeouts(Some(20), None), &timeouts(None, None));
+        assert_eq!((short.liveness, short.send), (secs(20), config.send_timeout));
+        // What a client asks for wins over its database's overrides...
+        let requested = config.client_timeouts(&timeouts(Some(120), Some(20)), &timeouts(Some(30), None));
+        assert_eq!((requested.liveness, requested.send), (secs(30), secs(20)));
+        // ...but neither may leave the node's bounds.
+        let clamped = config.client_timeouts(&timeouts(Some(0), Some(u64::MAX)), &timeouts(None, None));
+        assert_eq!(
+            (clamped.liveness, clamped.send),
+            (config.min_liveness_timeout, config.max_send_timeout)
+        );
+        let clamped = config.client_timeouts(&timeouts(None, None), &timeouts(Some(u64::MAX), Some(0)));
+        assert_eq!(
+            (clamped.liveness, clamped.send),
+            (config.max_liveness_timeout, config.min_send_timeout)
+        );
+    }
+}
@@ -1,22 +1,27 @@
 mod flat_csv;
+#[cfg(test)]
+pub(crate) mod shaped_network;
 pub mod websocket;
 
 use core::fmt;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 
 use axum::body::Bytes;
-use axum::extract::{FromRequest, Request};
+use axum::extract::{ConnectInfo, FromRequest, FromRequestParts, Request};
 use axum::response::IntoResponse;
 use bytestring::ByteString;
 use futures::TryStreamExt;
-use http::{HeaderName, HeaderValue, StatusCode};
+use http::{request, HeaderName, HeaderValue, StatusCode};
 
 use hyper::body::Body;
+use ipnet::IpNet;
+use spacetimedb::client::{ClientAddress, NetworkOptions};
 use spacetimedb::Identity;
-use spacetimedb_client_api_messages::name::DatabaseName;
+use spacetimedb_client_api_messages::name::{DatabaseName, DatabaseNameError};
 
 use crate::routes::identity::IdentityForUrl;
-use crate::{log_and_500, ControlStateReadAccess};
+use crate::{log_and_500, ClientActors, DatabaseResolution};
 
 pub struct ByteStringBody(pub ByteString);
 
@@ -58,6 +63,100 @@ impl headers::Header for XForwardedFor {
     }
 }
 
+/// The address of the client making a request,
+/// as reported by the node's trusted reverse proxies.
+///
+/// See [`NetworkOptions`] for which proxies are trusted.
+/// The address is `None` if it couldn't be determined,
+/// e.g. because the header was malformed or the server wasn't set up to provide the peer address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddr(pub ClientAddress);
+
+impl ClientAddr {
+    /// Determine the client address from the `X-Forwarded-For` headers and the peer address,
+    /// trusting the entries of `X-Forwarded-For` appended by the proxies `options` trusts.
+    pub fn from_headers<'a>(
+        forwarded_for: impl IntoIterator<Item = &'a HeaderValue>,
+        peer: Option<IpAddr>,
+        options: &NetworkOptions,
+    ) -> Self {
+        let peer = peer.map(|ip| ip.to_canonical());
+        let ip = if !options.trusted_proxies.is_empty() {
+            from_trusted_proxies(forwarded_entries(forwarded_for), peer, &options.trusted_proxies)
+        } else if options.trusted_proxy_depth > 0 {
+            from_trusted_depth(forwarded_entries(forwarded_for), peer, options.trusted_proxy_depth)
+        } else {
+            peer
+        };
+        Self(ClientAddress { ip, peer })
+    }
+
+    /// The address of the client, if known.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.0.ip
+    }
+}
+
+/// The entries of the `X-Forwarded-For` headers, in order, each `None` if it isn't an address.
+fn forwarded_entries<'a>(forwarded_for: impl IntoIterator<Item = &'a HeaderValue>) -> Vec<Option<IpAddr>> {
+    let mut entries = Vec::new();
+    for val in forwarded_for {
+        match val.to_str() {
+            Ok(val) => entries.extend(val.split(',').map(|entry| entry.trim().parse::<IpAddr>().ok())),
+            Err(_) => entries.push(None),
+        }
+    }
+    entries.into_iter().map(|ip| ip.map(|ip| ip.to_canonical())).collect()
+}
+
+/// The client address, given that the last `depth` entries of `X-Forwarded-For` were appended by our proxies.
+fn from_trusted_depth(entries: Vec<Option<IpAddr>>, peer: Option<IpAddr>, depth: usize) -> Option<IpAddr> {
+    if entries.is_empty() {
+        return peer;
+    }
+    // Entries before those appended by our trusted proxies may have been forged by the client.
+    // If there are fewer entries than trusted proxies,
+    // the first was still appended by one of our proxies.
+    entries[entries.len().saturating_sub(depth)]
+}
+
+/// The client address, given that only the `proxies` are trusted to append to `X-Forwarded-For`.
+fn from_trusted_proxies(entries: Vec<Option<IpAddr>>, peer: Option<IpAddr>, proxies: &[IpNet]) -> Option<IpAddr> {
+    let trusted = |ip: &IpAddr| proxies.iter().any(|net| net.contains(ip));
+    if !peer.is_some_and(|peer| trusted(&peer)) {
+        return peer;
+    }
+    // Walk back through the proxies which forwarded the request, up to the first we don't trust,
+    // which is the client, as far as we can tell: anything before it may have been forged.
+    let mut client = peer;
+    for entry in entries.into_iter().rev() {
+        // Should one of our proxies have appended garbage, we can't tell who it forwarded for.
+        let ip = entry?;
+        client = Some(ip);
+        if !trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+#[async_trait::async_trait]
+impl<S: ClientActors> FromRequestParts<S> for ClientAddr {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut request::Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        Ok(Self::from_headers(
+            parts.headers.get_all(<XForwardedFor as headers::Header>::name()),
+            peer,
+            state.actor_index().network_options(),
+        ))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum NameOrIdentity {
     Identity(IdentityForUrl),
@@ -95,18 +194,21 @@ impl NameOrIdentity {
     /// i.e. no corresponding [`Identity`] exists.
     pub async fn try_resolve(
         &self,
-        ctx: &(impl ControlStateReadAccess + ?Sized),
+        ctx: &(impl DatabaseResolution + ?Sized),
     ) -> axum::response::Result<Result<Identity, &DatabaseName>> {
         Ok(match self {
             Self::Identity(identity) => Ok(Identity::from(*identity)),
-            Self::Name(name) => ctx.lookup_identity(name.as_ref()).map_err(log_and_500)?.ok_or(name),
+            Self::Name(name) => ctx
+                .resolve_database_name(name.as_ref())
+                .map_err(log_and_500)?
+                .ok_or(name),
         })
     }
 
     /// A variant of [`Self::try_resolve()`] which maps to a 404 (Not Found)
     /// response if `self` is a [`NameOrIdentity::Name`] for which no
     /// corresponding [`Identity`] is found in the SpacetimeDB DNS.
-    pub async fn resolve(&self, ctx: &(impl ControlStateReadAccess + ?Sized)) -> axum::response::Result<Identity> {
+    pub async fn resolve(&self, ctx: &(impl DatabaseResolution + ?Sized)) -> axum::response::Result<Identity> {
         self.try_resolve(ctx).await?.map_err(|_| StatusCode::NOT_FOUND.into())
     }
 }
@@ -116,11 +218,20 @@ impl<'de> serde::Deserialize<'de> for NameOrIdentity {
     where
         D: serde::Deserializer<'de>,
     {
-        let s = String::deserialize(deserializer)?;
-        if let Ok(addr) = Identity::from_hex(&s) {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for NameOrIdentity {
+    type Err = DatabaseNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = Identity::from_hex(s) {
             Ok(NameOrIdentity::Identity(IdentityForUrl::from(addr)))
         } else {
-            let name: DatabaseName = s.try_into().map_err(serde::de::Error::custom)?;
+            let name: DatabaseName = s.to_owned().try_into()?;
             Ok(NameOrIdentity::Name(name))
         }
     }
@@ -0,0 +1,252 @@
+//! An in-process network for testing websocket connections under degraded conditions,
+//! like latency, a bandwidth cap, lost packets and abrupt disconnects.
+//!
+//! Each direction of a connection is shaped by its own [`NetworkConditions`],
+//! applied where the bytes are read, so that a slow reader backs up its writer
+//! once the buffer between them is full, much as TCP flow control would.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::time::Sleep;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+use super::websocket::{Message, WebSocketConfig};
+
+/// The conditions of one direction of a [`ShapedStream`].
+///
+/// The default is a perfect network.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct NetworkConditions {
+    /// How long each read waits before seeing the bytes available to it.
+    pub latency: Duration,
+    /// How many bytes a second can be read, if limited.
+    pub bandwidth: Option<u64>,
+    /// Once this many bytes have been read, reads stall for the duration,
+    /// as if a burst of packets was lost and had to be retransmitted.
+    pub loss: Option<(u64, Duration)>,
+    /// Once this many bytes have been read, the connection is dropped,
+    /// failing both reads and writes, even in the middle of a frame.
+    pub disconnect_at: Option<u64>,
+}
+
+/// A stream whose reads are shaped by [`NetworkConditions`], while its writes pass through,
+/// until the conditions disconnect it.
+pub(crate) struct ShapedStream<S> {
+    /// The stream, until it's disconnected,
+    /// which is then dropped, so that its peer's writes fail too.
+    inner: Option<S>,
+    conditions: NetworkConditions,
+    /// How many bytes have been read.
+    read: u64,
+    /// Whether the burst of [`NetworkConditions::loss`] has happened.
+    lost: bool,
+    /// Whether the current read has waited out the latency.
+    waited: bool,
+    /// What reads are waiting for before they proceed, if anything.
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ShapedStream<S> {
+    pub fn new(inner: S, conditions: NetworkConditions) -> Self {
+        Self {
+            inner: Some(inner),
+            conditions,
+            read: 0,
+            lost: false,
+            waited: false,
+            delay: None,
+        }
+    }
+
+    fn delay(&mut self, duration: Duration) {
+        self.delay = Some(Box::pin(tokio::time::sleep(duration)));
+    }
+
+    /// How many more bytes may be read before the next scripted event.
+    fn allowance(&self) -> u64 {
+        let until = |at: u64| at.saturating_sub(self.read);
+        let disconnect = self.conditions.disconnect_at.map_or(u64::MAX, until);
+        let loss = match self.conditions.loss {
+            Some((at, _)) if !self.lost => until(at),
+            _ => u64::MAX,
+        };
+        disconnect.min(loss)
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::ErrorKind::ConnectionReset.into()
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ShapedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(delay) = &mut this.delay {
+                ready!(delay.as_mut().poll(cx));
+                this.delay = None;
+            }
+            if this.inner.is_none() {
+                return Poll::Ready(Err(disconnected()));
+            }
+            let conditions = this.conditions;
+            if conditions.disconnect_at.is_some_and(|at| this.read >= at) {
+                this.inner = None;
+                continue;
+            }
+            if let Some((_, stall)) = conditions.loss.filter(|&(at, _)| !this.lost && this.read >= at) {
+                this.lost = true;
+                this.delay(stall);
+                continue;
+            }
+            if !this.waited && !conditions.latency.is_zero() {
+                this.waited = true;
+                this.delay(conditions.latency);
+                continue;
+            }
+
+            // Read no further than the next scripted event, so that it happens at exactly its offset.
+            let allowance = buf.remaining().min(this.allowance().try_into().unwrap_or(usize::MAX));
+            let mut limited = vec![0; allowance];
+            let mut limited = ReadBuf::new(&mut limited);
+            let inner = this.inner.as_mut().unwrap();
+            ready!(Pin::new(inner).poll_read(cx, &mut limited))?;
+            let n = limited.filled().len();
+            buf.put_slice(limited.filled());
+            this.read += n as u64;
+            this.waited = false;
+            if let Some(bandwidth) = conditions.bandwidth {
+                this.delay(Duration::from_secs_f64(n as f64 / bandwidth as f64));
+            }
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ShapedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            Some(inner) => Pin::new(inner).poll_write(cx, buf),
+            None => Poll::Ready(Err(disconnected())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            Some(inner) => Pin::new(inner).poll_flush(cx),
+            None => Poll::Ready(Err(disconnected())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            Some(inner) => Pin::new(inner).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// The websocket of one end of an in-process connection.
+pub(crate) type ShapedWebSocket = tokio_tungstenite::WebSocketStream<ShapedStream<DuplexStream>>;
+
+/// A websocket client connected in-process to a server-side websocket,
+/// over a network shaped in each direction.
+pub(crate) struct TestWsClient {
+    pub ws: ShapedWebSocket,
+}
+
+impl TestWsClient {
+    /// Connect a client to a server configured with `config`,
+    /// with `buffer` bytes in flight in each direction,
+    /// shaping what the client receives by `downstream` and what the server receives by `upstream`.
+    ///
+    /// Returns the client and the server's end of the connection.
+    pub async fn connect(
+        downstream: NetworkConditions,
+        upstream: NetworkConditions,
+        buffer: usize,
+        config: Option<WebSocketConfig>,
+    ) -> (Self, ShapedWebSocket) {
+        let (server, client) = tokio::io::duplex(buffer);
+        let server = ShapedStream::new(server, upstream);
+        let client = ShapedStream::new(client, downstream);
+        let server = tokio_tungstenite::WebSocketStream::from_raw_socket(server, Role::Server, config).await;
+        let ws = tokio_tungstenite::WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        (Self { ws }, server)
+    }
+
+    /// Receive the next message, or `None` once the connection is closed or broken.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.ws.next().await?.ok()
+    }
+
+    /// Receive messages until the connection is closed or broken, returning how many bytes of data they carried.
+    pub async fn drain(&mut self) -> usize {
+        let mut bytes = 0;
+        while let Some(message) = self.recv().await {
+            bytes += message.len();
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn reads_are_paced_by_the_bandwidth() {
+        let downstream = NetworkConditions {
+            bandwidth: Some(64 * 1024),
+            ..<_>::default()
+        };
+        let (mut client, mut server) = TestWsClient::connect(downstream, <_>::default(), 4096, None).await;
+        let start = Instant::now();
+        // The server hangs up once it's sent its message, which ends the client's stream.
+        let send = async move {
+            server.send(Message::binary(vec![0; 16 * 1024])).await.unwrap();
+            server.close(None).await.unwrap();
+        };
+        let (_, received) = tokio::join!(send, client.drain());
+        assert_eq!(received, 16 * 1024);
+        // A quarter second's worth of bandwidth.
+        assert!(start.elapsed() >= Duration::from_millis(180), "{:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn reads_stall_after_a_loss_and_fail_after_a_disconnect() {
+        let downstream = NetworkConditions {
+            loss: Some((100, Duration::from_millis(200))),
+            disconnect_at: Some(1000),
+            ..<_>::default()
+        };
+        let (mut client, mut server) = TestWsClient::connect(downstream, <_>::default(), 64, None).await;
+        let start = Instant::now();
+        let send = async {
+            server.send(Message::binary(vec![0; 50])).await.unwrap();
+            let stalled = server.send(Message::binary(vec![0; 500])).await;
+            // The disconnect happens in the middle of this frame.
+            let broken = server.send(Message::binary(vec![0; 1000])).await;
+            (stalled, broken)
+        };
+        let recv = async {
+            let first = client.recv().await.map(|msg| msg.len());
+            let second = client.recv().await.map(|msg| msg.len());
+            (first, second, client.recv().await)
+        };
+        let ((stalled, broken), (first, second, third)) = tokio::join!(send, recv);
+        assert!(stalled.is_ok());
+        assert!(broken.is_err());
+        assert_eq!((first, second), (Some(50), Some(500)));
+        assert!(third.is_none());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -87,12 +87,13 @@ pub(super) fn is_reducer_invokable(reducer: &ReducerDef) -> bool {
 
 /// Iterate over all the [`ReducerDef`]s defined by the module, in alphabetical order by name.
 ///
-/// The init reducer is skipped because it should never be visible to the clients.
+/// The init reducer and reducers declared not client-callable are skipped
+/// because they should never be visible to the clients.
 /// Sorting is not necessary for reducers because they are already stored in an IndexMap.
 pub(super) fn iter_reducers(module: &ModuleDef) -> impl Iterator<Item = &ReducerDef> {
     module
         .reducers()
-        .filter(|reducer| reducer.lifecycle != Some(Lifecycle::Init))
+        .filter(|reducer| reducer.lifecycle != Some(Lifecycle::Init) && reducer.client_callable)
 }
 
 /// Iterate over all the [`TableDef`]s defined by the module, in alphabetical order by name.
@@ -3,15 +3,57 @@ use std::fmt;
 
 mod client_connection;
 mod client_connection_index;
+mod client_runtime;
+mod codec;
+mod connected_clients;
+mod connection_sender;
+#[cfg(feature = "unstable-codecs")]
+mod custom_codec;
+mod deliveries;
+mod load_admission;
 mod message_handlers;
 pub mod messages;
+mod presence;
+mod query_limits;
+mod rate_limit;
+mod reconnect;
+mod resume;
+pub mod uniques;
 
 pub use client_connection::{
-    ClientConfig, ClientConnection, ClientConnectionSender, ClientSendError, DataMessage, MeteredDeque,
-    MeteredReceiver, Protocol,
+    ClientConfig, ClientConnection, ClientConnectionSender, ClientSendError, CloseReason, ConnectRetry,
+    ConnectionPolicy, DataMessage, MeteredDeque, MeteredReceiver, Protocol, SendQueueCapacity, SettingsError, SizeHint,
 };
-pub use client_connection_index::ClientActorIndex;
-pub use message_handlers::MessageHandleError;
+pub use client_connection_index::{
+    parse_ip_range, AddressOverloaded, AddressPermit, AdmissionPermit, ClientActorIndex, ClientAddress,
+    ClientRegistration, ConnectionIdReservation, ConnectionIdReuse, ConnectionLimits, ConnectionReplaceError, Draining,
+    IncomingQueueOverflow, ListedClient, NetworkOptions, NodeOverloaded, WebSocketOptions,
+};
+pub use client_runtime::ClientRuntime;
+pub use codec::{
+    BinaryCodec, DecodedMessage, EncodeError, EncodeErrorPolicy, EncodeFailure, EncodeResult, EncryptedCodec,
+    ErrorFormat, ProtocolCodec, TextCodec,
+};
+pub use connected_clients::ConnectedClients;
+pub use connection_sender::{ConnectionSendError, ConnectionSender};
+#[cfg(feature = "unstable-codecs")]
+pub use custom_codec::{set_codec_factory, CodecFactory, CodecFactoryAlreadySet};
+pub use deliveries::{AckError, IdentityPending, PendingDeliveries, MAX_PENDING_DELIVERIES, PENDING_DELIVERY_TTL};
+pub use load_admission::{
+    LoadAdmission, LoadAdmissionConfig, LoadAdmissionError, LoadLevel, LoadThresholds, NodeLoad, NodeUnderLoad,
+    LOAD_SAMPLE_INTERVAL,
+};
+pub use message_handlers::{
+    HandleOutcome, MessageExecutionError, MessageHandleError, ProtocolViolation, RequestRejected,
+};
+pub use presence::{PresenceEvent, PresenceIndex};
+pub use query_limits::{
+    InFlightQueries, OneOffQueryLimits, OneOffQueryPermit, QueryBudget, QueryCutShort, QueryLimitScope,
+    TooManyConcurrentQueries,
+};
+pub use rate_limit::{MessageRateLimit, MessageThrottle, TokenBucket};
+pub use reconnect::{ReconnectGrant, ReconnectTokenError, ReconnectTokens};
+pub use resume::{new_resume_token, ParkedSession, ResumableSessions, ResumeError, Resumption};
 use spacetimedb_lib::ConnectionId;
 
 #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
@@ -22,7 +64,6 @@ pub struct ClientActorId {
 }
 
 impl ClientActorId {
-    #[cfg(test)]
     pub fn for_test(identity: Identity) -> Self {
         ClientActorId {
             identity,
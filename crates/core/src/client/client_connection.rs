@@ -1,32 +1,46 @@
 use std::collections::VecDeque;
 use std::ops::Deref;
 use std::sync::atomic::Ordering;
-use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering::Relaxed};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
-use super::messages::{OneOffQueryResponseMessage, SerializableMessage};
-use super::{message_handlers, ClientActorId, MessageHandleError};
+use super::message_handlers::{self, HandleOutcome, RequestRejected};
+use super::messages::{
+    OneOffQueryResponseMessage, QueryRowsMessage, SerializableMessage, SubscriptionError, SubscriptionMessage,
+    SubscriptionResult, TransactionUpdateMessage,
+};
+use super::query_limits::{start_one_off_query, InFlightQueries, OneOffQueryLimits, QueryCutShort};
+use super::{
+    AckError, BinaryCodec, ClientActorId, ClientAddress, ClientRuntime, EncryptedCodec, ErrorFormat, LoadAdmission,
+    MessageHandleError, PendingDeliveries, ProtocolCodec, TextCodec,
+};
 use crate::error::DBError;
-use crate::host::module_host::ClientConnectedError;
-use crate::host::{ModuleHost, NoSuchModule, ReducerArgs, ReducerCallError, ReducerCallResult};
-use crate::messages::websocket::Subscribe;
+use crate::host::module_host::{ClientConnectedError, QueryKind};
+use crate::host::trace_context::TraceParent;
+use crate::host::{ModuleExitCause, ModuleHost, ReducerArgs, ReducerCallError, ReducerCallResult};
+use crate::messages::control_db::{ClientConfigDefaults, SendBacklogAction, SendBacklogPolicy};
+use crate::messages::websocket::{Subscribe, UpdateSettings};
 use crate::util::asyncify;
 use crate::util::prometheus_handle::IntGaugeExt;
-use crate::worker_metrics::WORKER_METRICS;
+use crate::worker_metrics::{ClientSeries, WORKER_METRICS};
 use bytes::Bytes;
 use bytestring::ByteString;
 use derive_more::From;
 use futures::prelude::*;
+use parking_lot::{Mutex, RwLock};
 use prometheus::{Histogram, IntCounter, IntGauge};
+use spacetimedb_client_api_messages::e2e;
 use spacetimedb_client_api_messages::websocket::{
-    BsatnFormat, CallReducerFlags, Compression, FormatSwitch, JsonFormat, SubscribeMulti, SubscribeSingle, Unsubscribe,
-    UnsubscribeMulti,
+    BsatnFormat, CallReducerFlags, Compression, FormatSwitch, JsonFormat, ProtocolVersion, QueryId, RequestErrorCode,
+    SubscribeMulti, SubscribeSingle, SubscribeWindow, Unsubscribe, UnsubscribeMulti, WebsocketFormat,
+    DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_ZSTD_LEVEL,
 };
+use spacetimedb_expr::errors::TypingError;
 use spacetimedb_lib::identity::RequestId;
 use spacetimedb_lib::metrics::ExecutionMetrics;
-use spacetimedb_lib::Identity;
-use tokio::sync::{mpsc, oneshot, watch};
+use spacetimedb_lib::{ConnectionId, Identity, ReducerTransport};
+use tokio::sync::{mpsc, oneshot, watch, Semaphore, SemaphorePermit};
 use tokio::task::AbortHandle;
 
 #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
@@ -51,35 +65,166 @@ impl Protocol {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct ClientConfig {
     /// The client's desired protocol (format) when the host replies.
     pub protocol: Protocol,
+    /// The version of the protocol, which determines how messages are framed.
+    pub version: ProtocolVersion,
     /// The client's desired (conditional) compression algorithm, if any.
     pub compression: Compression,
     /// Whether the client prefers full [`TransactionUpdate`]s
     /// rather than  [`TransactionUpdateLight`]s on a successful update.
     // TODO(centril): As more knobs are added, make this into a bitfield (when there's time).
     pub tx_update_full: bool,
+    /// Whether the full [`TransactionUpdate`]s of other clients' reducer calls carry the call's arguments,
+    /// and the identity and connection id of its caller.
+    ///
+    /// The replies to the client's own calls always do, as its SDK matches them to the calls by them.
+    pub include_caller_info: bool,
+    /// Whether full [`TransactionUpdate`]s carry when their reducer ran, and for how long.
+    pub include_timings: bool,
+    /// Whether the client is sent [`ReducerTimings`](crate::messages::websocket::ReducerTimings)
+    /// after the reply to each of its reducer calls.
+    pub reducer_timings: bool,
+    /// Whether the client is sent the updates of acknowledged reducers as
+    /// [`AcknowledgedUpdate`](crate::messages::websocket::AcknowledgedUpdate)s,
+    /// which it must acknowledge, see [`PendingDeliveries`](super::PendingDeliveries).
+    pub acknowledged_delivery: bool,
+    /// Whether the client is sent a [`RequestError`](crate::messages::websocket::RequestError)
+    /// when one of its messages fails for a reason it can recover from,
+    /// rather than having its connection closed or being replied to with a failed update.
+    pub request_errors: bool,
+    /// The level at which messages are compressed, should the client ask for [`Compression::Zstd`],
+    /// set for the node by [`WebSocketOptions::zstd_level`](super::WebSocketOptions::zstd_level).
+    pub zstd_level: i32,
+    /// The length, in bytes, of a serialized message beyond which it's compressed,
+    /// should the client ask for compression.
+    pub compression_threshold: usize,
 }
 
 impl ClientConfig {
+    /// The [`ProtocolCodec`] to use for a connection with this config.
+    pub fn codec(&self) -> Arc<dyn ProtocolCodec> {
+        #[cfg(feature = "unstable-codecs")]
+        if let Some(codec) = super::custom_codec::select(self, || self.builtin_codec()) {
+            return codec;
+        }
+        self.builtin_codec()
+    }
+
+    /// The [`ProtocolCodec`] built into the host for a connection with this config.
+    pub fn builtin_codec(&self) -> Arc<dyn ProtocolCodec> {
+        match self.protocol {
+            Protocol::Text => Arc::new(TextCodec(self.version)),
+            Protocol::Binary => Arc::new(BinaryCodec {
+                zstd_level: self.zstd_level,
+                compression_threshold: self.compression_threshold,
+            }),
+        }
+    }
+
     pub fn for_test() -> ClientConfig {
         Self {
             protocol: Protocol::Binary,
+            version: <_>::default(),
             compression: <_>::default(),
             tx_update_full: true,
+            include_caller_info: true,
+            include_timings: true,
+            reducer_timings: false,
+            acknowledged_delivery: false,
+            request_errors: false,
+            zstd_level: DEFAULT_ZSTD_LEVEL,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
         }
     }
 }
 
+/// Why the server asked a client connection to close.
+///
+/// Close requests are made from outside of the websocket actor,
+/// e.g. by the [`ClientActorIndex`](super::ClientActorIndex),
+/// and are turned into a close frame by the actor.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum CloseReason {
+    /// The node is above its hard connection limit
+    /// and this connection was among the longest idle ones.
+    Overloaded,
+    /// The client's address was denied by the network ACL of its database.
+    Denied,
+    /// A reducer of the database's module disconnected the client,
+    /// giving this reason.
+    KickedByModule(Arc<str>),
+    /// The owner of the client's database kicked it through the HTTP API,
+    /// giving this reason, if any.
+    KickedByAdmin(Option<Arc<str>>),
+    /// The connection has been open for as long as the node lets connections live,
+    /// see `max-connection-lifetime-secs` in the `[websocket]` section of `config.toml`.
+    MaxLifetimeReached,
+    /// The client's identity token couldn't be sent to it, for this reason,
+    /// without which SDKs never consider the connection established.
+    HandshakeFailed(ClientSendError),
+    /// The node is [draining](super::ClientActorIndex::drain), e.g. to be restarted,
+    /// and clients should reconnect, after `retry_after` if given.
+    Draining { retry_after: Option<Duration> },
+    /// A newer connection of the same client, with the same connection id, has replaced this one,
+    /// see [`ConnectionIdReuse::Replace`](super::ConnectionIdReuse::Replace).
+    Replaced,
+    /// The token the client authenticated with expired, and it didn't refresh it in time,
+    /// see [`ClientConnection::auth_expires_at`].
+    AuthExpired,
+}
+
+impl CloseReason {
+    /// Whether the client is no longer let in, so that its reconnect tokens for the database are revoked,
+    /// see [`ReconnectTokens::revoke_identity`](super::ReconnectTokens::revoke_identity).
+    pub fn revokes_reconnect(&self) -> bool {
+        matches!(
+            self,
+            Self::Denied | Self::KickedByModule(_) | Self::KickedByAdmin(_) | Self::AuthExpired
+        )
+    }
+}
+
+/// The value of [`ClientConnectionSender::round_trip_time_us`] before the client has answered a ping.
+const NO_ROUND_TRIP_TIME: u64 = u64::MAX;
+
 #[derive(Debug)]
 pub struct ClientConnectionSender {
     pub id: ClientActorId,
-    pub config: ClientConfig,
+    /// The client's config, and the codec selected according to it,
+    /// which the client may change mid-session, see [`Self::update_settings`].
+    settings: RwLock<ConnectionSettings>,
+    /// Where the client connected from.
+    pub addr: ClientAddress,
     sendtx: mpsc::Sender<SerializableMessage>,
+    /// The bytes of the messages in `sendtx`, shared with its receiver.
+    queued_bytes: Arc<QueuedBytes>,
+    capacity: SendQueueCapacity,
     abort_handle: AbortHandle,
     cancelled: AtomicBool,
+    /// Whether `id` has been disconnected from the module,
+    /// so that it's disconnected at most once.
+    disconnected: AtomicBool,
+    close_tx: watch::Sender<Option<CloseReason>>,
+
+    /// When this sender was created.
+    connected_at: Instant,
+    /// Milliseconds after `connected_at` at which we last received a message from the client.
+    last_active_ms: AtomicU64,
+    /// The round trip time of the most recent ping the client answered, in microseconds,
+    /// or [`NO_ROUND_TRIP_TIME`] if it has yet to answer one.
+    round_trip_time_us: AtomicU64,
+    /// How many protocol violations the client has committed over the connection,
+    /// shared with the senders it re-authenticates as.
+    protocol_violations: Arc<AtomicU32>,
+    /// The acknowledged updates of the database which its clients have yet to acknowledge.
+    deliveries: PendingDeliveries,
+    /// How many subscriptions `id` has, as last reported by the subscription manager.
+    subscriptions: AtomicUsize,
+    /// The send backlog policy of the client's database, as of when it connected.
+    send_backlog: SendBacklogPolicy,
 
     /// Handles on Prometheus metrics related to connections to this database.
     ///
@@ -89,8 +234,38 @@ pub struct ClientConnectionSender {
     metrics: Option<ClientConnectionMetrics>,
 }
 
+/// The [`ClientConfig`] of a connection, and the [`ProtocolCodec`] selected according to it.
 #[derive(Debug)]
+struct ConnectionSettings {
+    config: ClientConfig,
+    /// Encodes messages to and decodes messages from the client.
+    codec: Arc<dyn ProtocolCodec>,
+}
+
+impl ConnectionSettings {
+    fn new(config: ClientConfig, codec: Arc<dyn ProtocolCodec>) -> Self {
+        Self { config, codec }
+    }
+}
+
+/// Why the settings a client asked for in an [`UpdateSettings`] can't be had,
+/// see [`ClientConnectionSender::update_settings`].
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum SettingsError {
+    #[error("compression {compression:?} isn't supported by protocol version {version:?}")]
+    UnsupportedCompression {
+        compression: Compression,
+        version: ProtocolVersion,
+    },
+    /// The database's owner forced the setting on every client, see [`ClientConfigDefaults`].
+    #[error("the database forces the {setting} of its clients")]
+    Forced { setting: &'static str },
+}
+
+#[derive(Clone, Debug)]
 pub struct ClientConnectionMetrics {
+    /// The database the client is connected to, labeling metrics recorded on demand.
+    pub database_identity: Identity,
     pub websocket_request_msg_size: Histogram,
     pub websocket_requests: IntCounter,
 
@@ -102,54 +277,151 @@ pub struct ClientConnectionMetrics {
     /// Care must be taken not to increment it after the client has disconnected
     /// and performed its clean-up.
     pub sendtx_queue_size: IntGauge,
+
+    /// The `total_outgoing_queue_bytes` metric labeled with this database's `Identity`,
+    /// tracking the estimated size of the messages counted by `sendtx_queue_size`.
+    pub sendtx_queue_bytes: IntGauge,
+
+    /// The `ws_round_trip_seconds` metric labeled with this database's `Identity`.
+    pub round_trip_seconds: Histogram,
+
+    /// The metric series labeled with the client's `Identity`, if it has series of its own,
+    /// see [`ClientSeriesSampler`](crate::worker_metrics::ClientSeriesSampler).
+    pub client_series: Option<Arc<ClientSeries>>,
 }
 
 impl ClientConnectionMetrics {
-    fn new(database_identity: Identity, protocol: Protocol) -> Self {
+    fn new(
+        database_identity: Identity,
+        protocol: Protocol,
+        encrypted: bool,
+        client_series: Option<Arc<ClientSeries>>,
+    ) -> Self {
         let message_kind = protocol.as_str();
-        let websocket_request_msg_size = WORKER_METRICS
-            .websocket_request_msg_size
-            .with_label_values(&database_identity, message_kind);
-        let websocket_requests = WORKER_METRICS
-            .websocket_requests
-            .with_label_values(&database_identity, message_kind);
+        let encryption = if encrypted { "e2e" } else { "none" };
+        let websocket_request_msg_size =
+            WORKER_METRICS
+                .websocket_request_msg_size
+                .with_label_values(&database_identity, message_kind, encryption);
+        let websocket_requests =
+            WORKER_METRICS
+                .websocket_requests
+                .with_label_values(&database_identity, message_kind, encryption);
         let sendtx_queue_size = WORKER_METRICS
             .total_outgoing_queue_length
             .with_label_values(&database_identity);
+        let sendtx_queue_bytes = WORKER_METRICS
+            .total_outgoing_queue_bytes
+            .with_label_values(&database_identity);
+        let round_trip_seconds = WORKER_METRICS
+            .ws_round_trip_seconds
+            .with_label_values(&database_identity);
 
         Self {
+            database_identity,
             websocket_request_msg_size,
             websocket_requests,
             sendtx_queue_size,
+            sendtx_queue_bytes,
+            round_trip_seconds,
+            client_series,
         }
     }
 }
 
-#[derive(Debug, thiserror::Error)]
+/// Why a message wasn't queued for a client.
+///
+/// Counted per database, by variant, in the `ws_client_send_errors` metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error, strum::AsRefStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum ClientSendError {
+    /// The client's queue holds as many messages as its [`SendQueueCapacity`] allows.
+    ///
+    /// [`ClientConnectionSender::send_message`] disconnects such a client,
+    /// while a [`ConnectionSender`](super::ConnectionSender) hands the message back.
+    #[error("client queue is full by message count")]
+    QueueFullByCount,
+    /// The client's queue holds as many bytes as its [`SendQueueCapacity`] allows.
+    ///
+    /// As for [`Self::QueueFullByCount`].
+    #[error("client queue is full by bytes")]
+    QueueFullByBytes,
+    /// The client is being disconnected, having not kept up with its queue.
+    #[error("client was not responding and is being disconnected")]
+    ConnectionClosing,
+    /// The client's connection has closed.
     #[error("client disconnected")]
     Disconnected,
-    #[error("client was not responding and has been disconnected")]
-    Cancelled,
+}
+
+impl ClientSendError {
+    /// Whether the message was refused for backpressure,
+    /// rather than because the connection is gone or going.
+    pub fn is_queue_full(self) -> bool {
+        matches!(self, Self::QueueFullByCount | Self::QueueFullByBytes)
+    }
+}
+
+/// Why [`ClientConnectionSender::try_enqueue`] didn't queue a message.
+#[derive(Debug)]
+pub(super) enum EnqueueError {
+    /// Queueing the message would exceed a bound of the client's [`SendQueueCapacity`],
+    /// either [`ClientSendError::QueueFullByCount`] or [`ClientSendError::QueueFullByBytes`].
+    Full {
+        message: SerializableMessage,
+        bound: ClientSendError,
+    },
+    Client(ClientSendError),
+}
+
+impl From<ClientSendError> for EnqueueError {
+    fn from(e: ClientSendError) -> Self {
+        Self::Client(e)
+    }
 }
 
 impl ClientConnectionSender {
     pub fn dummy_with_channel(id: ClientActorId, config: ClientConfig) -> (Self, MeteredReceiver<SerializableMessage>) {
-        let (sendtx, rx) = mpsc::channel(1);
+        let capacity = SendQueueCapacity {
+            messages: 1,
+            ..<_>::default()
+        };
+        Self::dummy_with_capacity(id, config, capacity)
+    }
+
+    pub fn dummy_with_capacity(
+        id: ClientActorId,
+        config: ClientConfig,
+        capacity: SendQueueCapacity,
+    ) -> (Self, MeteredReceiver<SerializableMessage>) {
+        let (sendtx, rx) = mpsc::channel(capacity.messages);
         // just make something up, it doesn't need to be attached to a real task
         let abort_handle = match tokio::runtime::Handle::try_current() {
             Ok(h) => h.spawn(async {}).abort_handle(),
             Err(_) => tokio::runtime::Runtime::new().unwrap().spawn(async {}).abort_handle(),
         };
 
-        let rx = MeteredReceiver::new(rx);
+        let queued_bytes = Arc::new(QueuedBytes::default());
+        let rx = MeteredReceiver::new(rx, queued_bytes.clone());
         let cancelled = AtomicBool::new(false);
         let sender = Self {
             id,
-            config,
+            settings: RwLock::new(ConnectionSettings::new(config, config.codec())),
             sendtx,
+            queued_bytes,
+            capacity,
             abort_handle,
             cancelled,
+            disconnected: AtomicBool::new(false),
+            close_tx: watch::Sender::new(None),
+            connected_at: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+            round_trip_time_us: AtomicU64::new(NO_ROUND_TRIP_TIME),
+            protocol_violations: Arc::default(),
+            deliveries: <_>::default(),
+            subscriptions: AtomicUsize::new(0),
+            send_backlog: SendBacklogPolicy::default(),
+            addr: ClientAddress::default(),
             metrics: None,
         };
         (sender, rx)
@@ -159,31 +431,374 @@ impl ClientConnectionSender {
         Self::dummy_with_channel(id, config).0
     }
 
+    /// Returns this sender under the send backlog policy `send_backlog`, e.g. to test a dummy under it.
+    pub fn with_send_backlog(self, send_backlog: SendBacklogPolicy) -> Self {
+        Self { send_backlog, ..self }
+    }
+
+    /// Returns this sender encoding with `codec`, e.g. to test a dummy which encrypts.
+    pub fn with_codec(self, codec: Arc<dyn ProtocolCodec>) -> Self {
+        let settings = RwLock::new(ConnectionSettings::new(self.config(), codec));
+        Self { settings, ..self }
+    }
+
+    /// Returns a sender for the same connection, now as `id`,
+    /// with a fresh queue, and the receiving end of that queue.
+    ///
+    /// Messages sent through `self` are never received from the new queue.
+    fn reauthenticated(&self, id: ClientActorId, config: ClientConfig) -> (Self, MeteredReceiver<SerializableMessage>) {
+        let (sendtx, sendrx) = mpsc::channel(self.capacity.messages);
+        let (queued_bytes, sendrx) = match &self.metrics {
+            Some(metrics) => {
+                let queued_bytes = Arc::new(QueuedBytes::with_gauge(metrics.sendtx_queue_bytes.clone()));
+                let sendrx =
+                    MeteredReceiver::with_gauge(sendrx, metrics.sendtx_queue_size.clone(), queued_bytes.clone());
+                (queued_bytes, sendrx)
+            }
+            None => {
+                let queued_bytes = Arc::new(QueuedBytes::default());
+                (queued_bytes.clone(), MeteredReceiver::new(sendrx, queued_bytes))
+            }
+        };
+        let sender = Self {
+            id,
+            settings: RwLock::new(ConnectionSettings::new(config, self.codec())),
+            sendtx,
+            queued_bytes,
+            capacity: self.capacity,
+            abort_handle: self.abort_handle.clone(),
+            cancelled: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
+            close_tx: watch::Sender::new(self.close_reason()),
+            connected_at: self.connected_at,
+            last_active_ms: AtomicU64::new(self.last_active_ms.load(Relaxed)),
+            round_trip_time_us: AtomicU64::new(self.round_trip_time_us.load(Relaxed)),
+            protocol_violations: self.protocol_violations.clone(),
+            deliveries: self.deliveries.clone(),
+            // Re-authenticating drops the subscriptions of the old identity.
+            subscriptions: AtomicUsize::new(0),
+            send_backlog: self.send_backlog,
+            addr: self.addr,
+            metrics: self.metrics.clone(),
+        };
+        (sender, sendrx)
+    }
+
+    /// The client's config, as of now.
+    pub fn config(&self) -> ClientConfig {
+        self.settings.read().config
+    }
+
+    /// Encodes messages to and decodes messages from the client, selected according to its [`Self::config`].
+    pub fn codec(&self) -> Arc<dyn ProtocolCodec> {
+        self.settings.read().codec.clone()
+    }
+
+    /// Whether the client may share the encodings of its updates with other clients of the same config,
+    /// see [`ProtocolCodec::shares_encodings`].
+    pub fn shares_encodings(&self) -> bool {
+        self.settings.read().codec.shares_encodings()
+    }
+
+    /// Change the settings of the connection which may change mid-session as the client asked in `update`,
+    /// unless they're settings the connection can't have, or which `defaults` forces,
+    /// returning the config now in effect.
+    ///
+    /// The new settings apply to messages prepared from now on.
+    /// Messages already queued for the client keep the detail they were prepared with,
+    /// e.g. a full [`TransactionUpdateMessage`],
+    /// and those sharing their encoding with other clients may keep the compression of the old settings.
+    pub fn update_settings(
+        &self,
+        update: &UpdateSettings,
+        defaults: &ClientConfigDefaults,
+    ) -> Result<ClientConfig, SettingsError> {
+        let mut settings = self.settings.write();
+        let mut config = settings.config;
+        if let Some(compression) = update.compression {
+            if !config.version.supports(compression) {
+                return Err(SettingsError::UnsupportedCompression {
+                    compression,
+                    version: config.version,
+                });
+            }
+            if compression != config.compression && defaults.compression.is_some_and(|default| default.forced) {
+                return Err(SettingsError::Forced { setting: "compression" });
+            }
+            config.compression = compression;
+        }
+        if let Some(tx_update_full) = update.tx_update_full {
+            if tx_update_full != config.tx_update_full && defaults.light.is_some_and(|default| default.forced) {
+                return Err(SettingsError::Forced { setting: "light mode" });
+            }
+            config.tx_update_full = tx_update_full;
+        }
+        if let Some(compression_threshold) = update.compression_threshold {
+            config.compression_threshold = usize::try_from(compression_threshold).unwrap_or(usize::MAX);
+        }
+        if config.compression_threshold != settings.config.compression_threshold {
+            settings.codec = settings.codec.with_compression_threshold(config.compression_threshold);
+        }
+        settings.config = config;
+        Ok(config)
+    }
+
     pub fn is_cancelled(&self) -> bool {
         self.cancelled.load(Ordering::Relaxed)
     }
 
+    /// Returns whether the task running the client's actor has finished,
+    /// whether it returned, panicked or was aborted.
+    pub fn actor_finished(&self) -> bool {
+        self.abort_handle.is_finished()
+    }
+
+    /// Returns whether the client has been disconnected from its module.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Relaxed)
+    }
+
+    /// Disconnect the client from `module`, unless it already has been.
+    pub async fn disconnect_from(&self, module: &ModuleHost) {
+        if self.disconnected.swap(true, Relaxed) {
+            return;
+        }
+        module.replica_ctx().clients.remove(&self.id);
+        module.disconnect_client(self.id).await
+    }
+
+    /// Ask the websocket actor to close the connection for `reason`.
+    ///
+    /// Only the first request is honored; subsequent requests are ignored.
+    pub fn request_close(&self, reason: CloseReason) {
+        self.close_tx.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(reason);
+            true
+        });
+    }
+
+    /// Returns the reason the connection was asked to close, if any.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_tx.borrow().clone()
+    }
+
+    /// Resolves once the connection has been asked to close via [`Self::request_close`].
+    pub async fn close_requested(&self) -> CloseReason {
+        let mut close_rx = self.close_tx.subscribe();
+        // `self` holds onto the sender, so the channel can't be closed.
+        let reason = close_rx
+            .wait_for(Option::is_some)
+            .await
+            .expect("close_tx should not be dropped")
+            .clone();
+        reason.expect("waited for `Some`")
+    }
+
+    /// Record that the client sent us something just now.
+    pub fn record_activity(&self) {
+        let elapsed = self.connected_at.elapsed().as_millis() as u64;
+        self.last_active_ms.fetch_max(elapsed, Relaxed);
+    }
+
+    /// Returns how long ago the client last sent us something,
+    /// or how long it's been connected if it hasn't sent anything yet.
+    pub fn idle_time(&self) -> Duration {
+        let last_active = self.connected_at + Duration::from_millis(self.last_active_ms.load(Relaxed));
+        last_active.elapsed()
+    }
+
+    /// Record that the client answered a ping after `round_trip_time`.
+    pub fn record_round_trip_time(&self, round_trip_time: Duration) {
+        let micros = u64::try_from(round_trip_time.as_micros()).unwrap_or(u64::MAX);
+        self.round_trip_time_us
+            .store(micros.min(NO_ROUND_TRIP_TIME - 1), Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.round_trip_seconds.observe(round_trip_time.as_secs_f64());
+        }
+        if let Some(series) = self.client_series() {
+            series.round_trip_seconds.set(round_trip_time.as_secs_f64());
+        }
+    }
+
+    /// Returns the round trip time of the most recent ping the client answered, if it has answered one.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        let micros = self.round_trip_time_us.load(Relaxed);
+        (micros != NO_ROUND_TRIP_TIME).then(|| Duration::from_micros(micros))
+    }
+
+    /// Record that the client violated the protocol,
+    /// returning how many times it has done so over the connection.
+    pub fn record_protocol_violation(&self) -> u32 {
+        if let Some(series) = self.client_series() {
+            series.protocol_violations.inc();
+        }
+        self.protocol_violations.fetch_add(1, Relaxed).saturating_add(1)
+    }
+
+    /// Record that a message of `bytes` was received from the client,
+    /// in its metric series if it has series of its own.
+    pub fn record_received(&self, bytes: usize) {
+        if let Some(series) = self.client_series() {
+            series.bytes_received.inc_by(bytes as u64);
+        }
+    }
+
+    /// Record that messages of `bytes` were sent to the client, leaving `queue_len` messages queued for it,
+    /// in its metric series if it has series of its own.
+    pub fn record_sent(&self, bytes: usize, queue_len: usize) {
+        if let Some(series) = self.client_series() {
+            series.bytes_sent.inc_by(bytes as u64);
+            series.outgoing_queue_length.set(queue_len as i64);
+        }
+    }
+
+    /// The metric series labeled with the client's identity, if it has series of its own.
+    fn client_series(&self) -> Option<&ClientSeries> {
+        self.metrics.as_ref()?.client_series.as_deref()
+    }
+
+    /// Returns how many times the client has violated the protocol over the connection.
+    pub fn protocol_violations(&self) -> u32 {
+        self.protocol_violations.load(Relaxed)
+    }
+
+    /// Returns how long the connection has been open.
+    pub fn uptime(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+
+    /// Record that `id` now has `count` subscriptions.
+    ///
+    /// Called by the subscription manager whenever it adds or removes the client's subscriptions,
+    /// so that the count can be read without taking its lock.
+    pub fn set_subscriptions(&self, count: usize) {
+        self.subscriptions.store(count, Relaxed);
+    }
+
+    /// Returns how many subscriptions `id` has.
+    pub fn subscriptions(&self) -> usize {
+        self.subscriptions.load(Relaxed)
+    }
+
+    /// Whether the client's queue is more than half full, by message count or by bytes,
+    /// in which case messages the client can do without, like reducer progress, should be dropped.
+    pub fn is_under_pressure(&self) -> bool {
+        let (messages, bytes) = self.queued();
+        messages * 2 > self.capacity.messages || bytes * 2 > self.capacity.bytes
+    }
+
+    /// Whether updates to the client's subscriptions should be computed and queued for it.
+    ///
+    /// Under [`SendBacklogAction::Resync`], they aren't while the client is behind,
+    /// and the subscriptions which miss updates are resynced once it catches up.
+    /// Clients which opted into acknowledged delivery are always sent their updates.
+    pub fn accepts_updates(&self) -> bool {
+        if self.send_backlog.action != SendBacklogAction::Resync || self.config().acknowledged_delivery {
+            return true;
+        }
+        let (messages, bytes) = self.queued();
+        !self.send_backlog.is_behind(messages, bytes)
+    }
+
+    /// Returns how many messages are queued for the client, and how many bytes they take up.
+    pub fn queued(&self) -> (usize, usize) {
+        let messages = self.sendtx.max_capacity() - self.sendtx.capacity();
+        (messages, self.queued_bytes.bytes.load(Relaxed))
+    }
+
+    /// Send `update` to the client as an [`AcknowledgedUpdate`](crate::messages::websocket::AcknowledgedUpdate),
+    /// which stays pending until the client acknowledges it.
+    pub fn send_acknowledged(&self, update: TransactionUpdateMessage) -> Result<(), ClientSendError> {
+        let message = self.deliveries.deliver(&self.id, self.config().protocol, update);
+        self.send_message(message)
+    }
+
+    /// Record that the client acknowledged the delivery `delivery_id`.
+    pub fn acknowledge(&self, delivery_id: u64) -> Result<(), AckError> {
+        self.deliveries.acknowledge(&self.id, delivery_id)
+    }
+
+    /// Deliver again the updates pending for the client's identity, if it opted into acknowledged delivery,
+    /// e.g. those its previous connection didn't acknowledge before it closed.
+    ///
+    /// Returns how many updates were delivered again.
+    pub fn redeliver(&self) -> Result<usize, ClientSendError> {
+        if !self.config().acknowledged_delivery {
+            return Ok(0);
+        }
+        let messages = self.deliveries.redeliver(&self.id, self.config().protocol);
+        let redelivered = messages.len();
+        for message in messages {
+            self.send_message(message)?;
+        }
+        Ok(redelivered)
+    }
+
     /// Send a message to the client. For data-related messages, you should probably use
     /// `BroadcastQueue::send` to ensure that the client sees data messages in a consistent order.
+    ///
+    /// If the client's queue is full, the client is disconnected,
+    /// and this returns which bound of its [`SendQueueCapacity`] was hit.
     pub fn send_message(&self, message: impl Into<SerializableMessage>) -> Result<(), ClientSendError> {
         self.send(message.into())
     }
 
     fn send(&self, message: SerializableMessage) -> Result<(), ClientSendError> {
+        let err = match self.try_enqueue(message) {
+            Ok(()) => return Ok(()),
+            Err(EnqueueError::Full { bound, .. }) => self.kick(bound),
+            Err(EnqueueError::Client(e)) => e,
+        };
+        self.record_send_error(err);
+        Err(err)
+    }
+
+    /// Count a message not queued for the client because of `err`.
+    pub(super) fn record_send_error(&self, err: ClientSendError) {
+        if let Some(metrics) = &self.metrics {
+            WORKER_METRICS
+                .ws_client_send_errors
+                .with_label_values(&metrics.database_identity, err.as_ref())
+                .inc();
+        }
+    }
+
+    /// Queue `message` for the client if there's room for it within [`Self::capacity`],
+    /// handing it back if there isn't.
+    pub(super) fn try_enqueue(&self, message: SerializableMessage) -> Result<(), EnqueueError> {
         if self.cancelled.load(Relaxed) {
-            return Err(ClientSendError::Cancelled);
+            return Err(ClientSendError::ConnectionClosing.into());
+        }
+
+        // Account for the message's bytes before sending it,
+        // so that the receiver never sees them before they're added.
+        let size = message.size_hint();
+        let queued = self.queued_bytes.add(size);
+        // A single message larger than the byte capacity is let through if nothing else is queued,
+        // as it could never be sent otherwise.
+        if queued > 0 && queued + size > self.capacity.bytes {
+            self.queued_bytes.sub(size);
+            return Err(EnqueueError::Full {
+                message,
+                bound: ClientSendError::QueueFullByBytes,
+            });
         }
 
         match self.sendtx.try_send(message) {
-            Err(mpsc::error::TrySendError::Full(_)) => {
-                // we've hit CLIENT_CHANNEL_CAPACITY messages backed up in
-                // the channel, so forcibly kick the client
-                tracing::warn!(identity = %self.id.identity, connection_id = %self.id.connection_id, "client channel capacity exceeded");
-                self.abort_handle.abort();
-                self.cancelled.store(true, Ordering::Relaxed);
-                return Err(ClientSendError::Cancelled);
+            Err(mpsc::error::TrySendError::Full(message)) => {
+                self.queued_bytes.sub(size);
+                return Err(EnqueueError::Full {
+                    message,
+                    bound: ClientSendError::QueueFullByCount,
+                });
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                self.queued_bytes.sub(size);
+                return Err(ClientSendError::Disconnected.into());
             }
-            Err(mpsc::error::TrySendError::Closed(_)) => return Err(ClientSendError::Disconnected),
             Ok(()) => {
                 // If we successfully pushed a message into the queue, increment the queue size metric.
                 // Don't do this before pushing because, if the client has disconnected,
@@ -198,6 +813,14 @@ impl ClientConnectionSender {
         Ok(())
     }
 
+    /// Forcibly disconnect the client, as it has backed up more than `bound` of [`Self::capacity`].
+    fn kick(&self, bound: ClientSendError) -> ClientSendError {
+        tracing::warn!(identity = %self.id.identity, connection_id = %self.id.connection_id, bound = bound.as_ref(), "client channel capacity exceeded");
+        self.abort_handle.abort();
+        self.cancelled.store(true, Ordering::Relaxed);
+        bound
+    }
+
     pub(crate) fn observe_websocket_request_message(&self, message: &DataMessage) {
         if let Some(metrics) = &self.metrics {
             metrics.websocket_request_msg_size.observe(message.len() as f64);
@@ -213,6 +836,95 @@ pub struct ClientConnection {
     pub replica_id: u64,
     pub module: ModuleHost,
     module_rx: watch::Receiver<ModuleHost>,
+    /// The one-off queries in flight over this connection,
+    /// shared with the connections it re-authenticates as.
+    one_off_queries: Arc<InFlightQueries>,
+    /// When the token the client last authenticated with expires, if it does,
+    /// shared with the connections it re-authenticates as.
+    auth_expires_at: Arc<Mutex<Option<SystemTime>>>,
+    policy: ConnectionPolicy,
+}
+
+/// How the node treats the requests of a connection,
+/// as decided by the node and the client's database rather than the client.
+#[derive(Clone, Default)]
+pub struct ConnectionPolicy {
+    /// The limits on the one-off queries in flight.
+    pub one_off_query_limits: OneOffQueryLimits,
+    /// Whether the node takes on new subscriptions, given its load.
+    pub load_admission: Arc<LoadAdmission>,
+    /// How the errors reported to the client are formatted.
+    pub error_format: ErrorFormat,
+    /// How connecting the client to its module is retried.
+    pub connect_retry: ConnectRetry,
+    /// The metric series of the client, if it has series of its own.
+    pub client_series: Option<Arc<ClientSeries>>,
+    /// Where the client's actor is spawned.
+    pub runtime: ClientRuntime,
+    /// What becomes of the client should it fall behind,
+    /// which may be that its updates aren't computed, see [`ClientConnectionSender::accepts_updates`].
+    pub send_backlog: SendBacklogPolicy,
+    /// Where the client connected from.
+    pub addr: ClientAddress,
+    /// The defaults of the client's database, which the client's settings are subject to,
+    /// see [`ClientConnectionSender::update_settings`].
+    pub config_defaults: ClientConfigDefaults,
+}
+
+/// How connecting a client to its module is retried after a transient failure,
+/// see [`ClientConnectedError::is_transient`].
+///
+/// Attempts are spaced by a backoff which doubles each time,
+/// and stop early rather than outlast the budget.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectRetry {
+    /// How many attempts are made, at least one.
+    pub attempts: u32,
+    /// How long the attempts may take together.
+    pub budget: Duration,
+    /// How long to wait before the first retry.
+    pub backoff: Duration,
+}
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            budget: Duration::from_secs(2),
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+impl ConnectRetry {
+    /// Run `attempt` until it succeeds, fails for good, or runs out of attempts or budget,
+    /// counting the outcome in `spacetime_worker_ws_connect_outcomes_total`.
+    pub async fn run<T, Fut>(self, mut attempt: impl FnMut() -> Fut) -> Result<T, ClientConnectedError>
+    where
+        Fut: Future<Output = Result<T, ClientConnectedError>>,
+    {
+        let start = Instant::now();
+        let mut backoff = self.backoff;
+        let mut attempts = 1;
+        let (outcome, res) = loop {
+            match attempt().await {
+                Ok(x) if attempts == 1 => break ("connected", Ok(x)),
+                Ok(x) => break ("connected_after_retry", Ok(x)),
+                Err(e) if !e.is_transient() => break ("failed", Err(e)),
+                Err(e) if attempts >= self.attempts || start.elapsed() + backoff > self.budget => {
+                    break ("retries_exhausted", Err(e))
+                }
+                Err(e) => {
+                    log::warn!("connecting client failed, retrying in {backoff:?}: {e}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                    attempts += 1;
+                }
+            }
+        };
+        WORKER_METRICS.ws_connect_outcomes.with_label_values(outcome).inc();
+        res
+    }
 }
 
 impl Deref for ClientConnection {
@@ -222,7 +934,7 @@ impl Deref for ClientConnection {
     }
 }
 
-#[derive(Debug, From)]
+#[derive(Debug, Clone, From)]
 pub enum DataMessage {
     Text(ByteString),
     Binary(Bytes),
@@ -317,41 +1029,122 @@ impl<T> Drop for MeteredDeque<T> {
     }
 }
 
-/// Wraps the receiving end of a channel with a gauge for tracking the size of the channel.
-/// We subtract the size of the channel from the gauge on drop to avoid leaking the metric.
-pub struct MeteredReceiver<T> {
+/// Values which can estimate how many bytes they'll take up once serialized,
+/// so that queues of them can be bounded by size rather than just by length.
+pub trait SizeHint {
+    fn size_hint(&self) -> usize;
+}
+
+/// The estimated bytes of the messages in a channel, shared by both of its ends.
+#[derive(Default)]
+pub(crate) struct QueuedBytes {
+    bytes: AtomicUsize,
+    gauge: Option<IntGauge>,
+}
+
+impl QueuedBytes {
+    fn with_gauge(gauge: IntGauge) -> Self {
+        Self {
+            bytes: AtomicUsize::new(0),
+            gauge: Some(gauge),
+        }
+    }
+
+    /// Add `n` bytes, returning the bytes queued before.
+    fn add(&self, n: usize) -> usize {
+        if let Some(gauge) = &self.gauge {
+            gauge.add(n as _);
+        }
+        self.bytes.fetch_add(n, Relaxed)
+    }
+
+    fn sub(&self, n: usize) {
+        if let Some(gauge) = &self.gauge {
+            gauge.sub(n as _);
+        }
+        self.bytes.fetch_sub(n, Relaxed);
+    }
+}
+
+impl std::fmt::Debug for QueuedBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueuedBytes")
+            .field("bytes", &self.bytes.load(Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Bounds on the messages queued for a client,
+/// past either of which the client is disconnected.
+#[derive(Clone, Copy, Debug)]
+pub struct SendQueueCapacity {
+    /// The number of messages.
+    pub messages: usize,
+    /// The sum of the messages' [`SizeHint`]s.
+    ///
+    /// A single message exceeding this is still queued if nothing else is.
+    pub bytes: usize,
+}
+
+impl Default for SendQueueCapacity {
+    fn default() -> Self {
+        Self {
+            messages: CLIENT_CHANNEL_CAPACITY,
+            bytes: CLIENT_CHANNEL_CAPACITY_BYTES,
+        }
+    }
+}
+
+/// Wraps the receiving end of a channel with gauges for tracking the size of the channel,
+/// both in messages and in bytes.
+/// We drain the channel on drop, subtracting its contents from the gauges, to avoid leaking the metrics.
+pub struct MeteredReceiver<T: SizeHint> {
     inner: mpsc::Receiver<T>,
     gauge: Option<IntGauge>,
+    queued_bytes: Arc<QueuedBytes>,
 }
 
-impl<T> MeteredReceiver<T> {
-    pub fn new(inner: mpsc::Receiver<T>) -> Self {
-        Self { inner, gauge: None }
+impl<T: SizeHint> MeteredReceiver<T> {
+    pub(crate) fn new(inner: mpsc::Receiver<T>, queued_bytes: Arc<QueuedBytes>) -> Self {
+        Self {
+            inner,
+            gauge: None,
+            queued_bytes,
+        }
     }
 
-    pub fn with_gauge(inner: mpsc::Receiver<T>, gauge: IntGauge) -> Self {
+    pub(crate) fn with_gauge(inner: mpsc::Receiver<T>, gauge: IntGauge, queued_bytes: Arc<QueuedBytes>) -> Self {
         Self {
             inner,
             gauge: Some(gauge),
+            queued_bytes,
         }
     }
 
     pub async fn recv(&mut self) -> Option<T> {
-        self.inner.recv().await.inspect(|_| {
+        self.inner.recv().await.inspect(|msg| {
             if let Some(gauge) = &self.gauge {
                 gauge.dec();
             }
+            self.queued_bytes.sub(msg.size_hint());
         })
     }
 
     pub async fn recv_many(&mut self, buf: &mut Vec<T>, max: usize) -> usize {
+        let start = buf.len();
         let n = self.inner.recv_many(buf, max).await;
         if let Some(gauge) = &self.gauge {
             gauge.sub(n as _);
         }
+        self.queued_bytes.sub(buf[start..].iter().map(T::size_hint).sum());
         n
     }
 
+    /// Returns the estimated bytes of the messages in the channel.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes.bytes.load(Relaxed)
+    }
+
     pub fn len(&self) -> usize {
         self.inner.len()
     }
@@ -365,11 +1158,16 @@ impl<T> MeteredReceiver<T> {
     }
 }
 
-impl<T> Drop for MeteredReceiver<T> {
+impl<T: SizeHint> Drop for MeteredReceiver<T> {
     fn drop(&mut self) {
-        // Record the number of elements still in the channel on drop
-        if let Some(gauge) = &self.gauge {
-            gauge.sub(self.inner.len() as _);
+        // Close the channel first, so that nothing is sent while we drain it,
+        // then record the elements still in the channel.
+        self.inner.close();
+        while let Ok(msg) = self.inner.try_recv() {
+            if let Some(gauge) = &self.gauge {
+                gauge.dec();
+            }
+            self.queued_bytes.sub(msg.size_hint());
         }
     }
 }
@@ -377,7 +1175,58 @@ impl<T> Drop for MeteredReceiver<T> {
 // if a client racks up this many messages in the queue without ACK'ing
 // anything, we boot 'em.
 const CLIENT_CHANNEL_CAPACITY: usize = 16 * KB;
+// Likewise for this many bytes of messages, as their sizes vary by orders of magnitude.
+const CLIENT_CHANNEL_CAPACITY_BYTES: usize = 128 * MB;
 const KB: usize = 1024;
+const MB: usize = 1024 * KB;
+
+/// A client connected to its module whose actor hasn't started yet,
+/// which disconnects the client should it be dropped before then,
+/// e.g. because `actor` panicked in [`ClientConnection::spawn`], or the actor's task was aborted before it ran.
+///
+/// Once the actor has started, it's up to the actor to disconnect the client.
+struct UnstartedActor {
+    client: Option<(Arc<ClientConnectionSender>, ModuleHost)>,
+}
+
+impl UnstartedActor {
+    fn started(mut self) {
+        self.client = None;
+    }
+}
+
+impl Drop for UnstartedActor {
+    fn drop(&mut self) {
+        let Some((sender, module)) = self.client.take() else {
+            return;
+        };
+        if sender.is_disconnected() {
+            return;
+        }
+        module.info.metrics.ws_clients_cleaned_up_before_start.inc();
+        tokio::spawn(async move { sender.disconnect_from(&module).await });
+    }
+}
+
+/// How many connections on the node may act on a change of their module at once,
+/// switching over to the updated module, or seeing that it exited.
+///
+/// Every connection to a module observes a change at the same time,
+/// so without a bound, a restart of a busy module makes a burst of work proportional to its connections,
+/// rather than spreading it over a few scheduler ticks.
+pub const MAX_CONCURRENT_MODULE_CHANGES: usize = 256;
+static MODULE_CHANGE_PERMITS: Semaphore = Semaphore::const_new(MAX_CONCURRENT_MODULE_CHANGES);
+
+/// Wait for `rx` to change, or for its sender to be dropped,
+/// and then for one of `permits`, to be held while acting on it.
+async fn changed_within<'p, T>(
+    rx: &mut watch::Receiver<T>,
+    permits: &'p Semaphore,
+) -> (Result<(), watch::error::RecvError>, Option<SemaphorePermit<'p>>) {
+    let res = rx.changed().await;
+    // The permits are never closed, but should they be, the change is acted on regardless.
+    (res, permits.acquire().await.ok())
+}
 
 impl ClientConnection {
     /// Returns an error if ModuleHost closed
@@ -386,6 +1235,8 @@ impl ClientConnection {
         config: ClientConfig,
         replica_id: u64,
         mut module_rx: watch::Receiver<ModuleHost>,
+        policy: ConnectionPolicy,
+        encryption: Option<e2e::Session>,
         actor: impl FnOnce(ClientConnection, MeteredReceiver<SerializableMessage>) -> Fut,
     ) -> Result<ClientConnection, ClientConnectedError>
     where
@@ -395,19 +1246,34 @@ impl ClientConnection {
         // TODO: Right now this is connecting clients directly to a replica, but their requests should be
         // logically subscribed to the database, not any particular replica. We should handle failover for
         // them and stuff. Not right now though.
-        let module = module_rx.borrow_and_update().clone();
-        module.call_identity_connected(id.identity, id.connection_id).await?;
+        //
+        // The module is looked up anew for each attempt, so that a module which restarted is picked up.
+        let module = policy
+            .connect_retry
+            .run(|| {
+                let module = module_rx.borrow_and_update().clone();
+                async move {
+                    module.call_identity_connected(id.identity, id.connection_id).await?;
+                    Ok(module)
+                }
+            })
+            .await?;
 
-        let (sendtx, sendrx) = mpsc::channel::<SerializableMessage>(CLIENT_CHANNEL_CAPACITY);
+        let capacity = SendQueueCapacity::default();
+        let (sendtx, sendrx) = mpsc::channel::<SerializableMessage>(capacity.messages);
 
-        let (fut_tx, fut_rx) = oneshot::channel::<Fut>();
+        let (fut_tx, fut_rx) = oneshot::channel::<(Fut, UnstartedActor)>();
         // weird dance so that we can get an abort_handle into ClientConnection
         let module_info = module.info.clone();
         let database_identity = module_info.database_identity;
-        let abort_handle = tokio::spawn(async move {
-            let Ok(fut) = fut_rx.await else { return };
+        let abort_handle = policy
+            .runtime
+            .spawn(async move {
+                let Ok((fut, unstarted)) = fut_rx.await else { return };
+                unstarted.started();
 
             let _gauge_guard = module_info.metrics.connected_clients.inc_scope();
+            let _watcher_guard = module_info.metrics.module_watchers.inc_scope();
             module_info.metrics.ws_clients_spawned.inc();
             scopeguard::defer! {
                 let database_identity = module_info.database_identity;
@@ -420,27 +1286,62 @@ impl ClientConnection {
         })
         .abort_handle();
 
-        let metrics = ClientConnectionMetrics::new(database_identity, config.protocol);
-        let sendrx = MeteredReceiver::with_gauge(sendrx, metrics.sendtx_queue_size.clone());
+        let metrics = ClientConnectionMetrics::new(
+            database_identity,
+            config.protocol,
+            encryption.is_some(),
+            policy.client_series.clone(),
+        );
+        // Only clients with series of their own get a series per connection.
+        let one_off_queries = match &policy.client_series {
+            Some(_) => InFlightQueries::for_connection(database_identity, id.connection_id),
+            None => InFlightQueries::default(),
+        };
+        let codec: Arc<dyn ProtocolCodec> = match encryption {
+            Some(session) => Arc::new(EncryptedCodec::new(config.codec(), session)),
+            None => config.codec(),
+        };
+        let queued_bytes = Arc::new(QueuedBytes::with_gauge(metrics.sendtx_queue_bytes.clone()));
+        let sendrx = MeteredReceiver::with_gauge(sendrx, metrics.sendtx_queue_size.clone(), queued_bytes.clone());
 
         let sender = Arc::new(ClientConnectionSender {
             id,
-            config,
+            settings: RwLock::new(ConnectionSettings::new(config, codec)),
             sendtx,
+            queued_bytes,
+            capacity,
             abort_handle,
             cancelled: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
+            close_tx: watch::Sender::new(None),
+            connected_at: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+            round_trip_time_us: AtomicU64::new(NO_ROUND_TRIP_TIME),
+            protocol_violations: Arc::default(),
+            deliveries: module.replica_ctx().deliveries.clone(),
+            subscriptions: AtomicUsize::new(0),
+            send_backlog: policy.send_backlog,
+            addr: policy.addr,
             metrics: Some(metrics),
         });
+        module.replica_ctx().clients.insert(&sender);
+        let unstarted = UnstartedActor {
+            client: Some((sender.clone(), module.clone())),
+        };
         let this = Self {
             sender,
             replica_id,
             module,
             module_rx,
+            one_off_queries: Arc::new(one_off_queries),
+            auth_expires_at: Arc::default(),
+            policy,
         };
 
         let actor_fut = actor(this.clone(), sendrx);
         // if this fails, the actor() function called .abort(), which like... okay, I guess?
-        let _ = fut_tx.send(actor_fut);
+        // Either way, the client is disconnected by `unstarted` being dropped.
+        let _ = fut_tx.send((actor_fut, unstarted));
 
         Ok(this)
     }
@@ -452,11 +1353,16 @@ impl ClientConnection {
         mut module_rx: watch::Receiver<ModuleHost>,
     ) -> Self {
         let module = module_rx.borrow_and_update().clone();
+        let sender = Arc::new(ClientConnectionSender::dummy(id, config));
+        module.replica_ctx().clients.insert(&sender);
         Self {
-            sender: Arc::new(ClientConnectionSender::dummy(id, config)),
+            sender,
             replica_id,
             module,
             module_rx,
+            one_off_queries: <_>::default(),
+            auth_expires_at: <_>::default(),
+            policy: <_>::default(),
         }
     }
 
@@ -464,22 +1370,63 @@ impl ClientConnection {
         self.sender.clone()
     }
 
+    /// Returns a receiver of the module the client is connected to, following its updates.
+    pub fn module_watcher(&self) -> watch::Receiver<ModuleHost> {
+        self.module_rx.clone()
+    }
+
+    /// Returns when the token the client last authenticated with expires, if it does.
+    pub fn auth_expires_at(&self) -> Option<SystemTime> {
+        *self.auth_expires_at.lock()
+    }
+
+    /// Record that the client authenticated with a token expiring at `expires_at`, if it does,
+    /// whether as it connected or as it refreshed its token, which then keeps it connected for longer.
+    pub fn set_auth_expiry(&self, expires_at: Option<SystemTime>) {
+        *self.auth_expires_at.lock() = expires_at;
+    }
+
     #[inline]
     pub fn handle_message(
         &self,
         message: impl Into<DataMessage>,
         timer: Instant,
-    ) -> impl Future<Output = Result<(), MessageHandleError>> + '_ {
+    ) -> impl Future<Output = Result<HandleOutcome, MessageHandleError>> + '_ {
         message_handlers::handle(self, message.into(), timer)
     }
 
-    pub async fn watch_module_host(&mut self) -> Result<(), NoSuchModule> {
-        match self.module_rx.changed().await {
+    /// Wait for the module to be updated, switching over to the new module,
+    /// or to exit, returning why.
+    ///
+    /// Only so many connections on the node act on a change of their module at once,
+    /// see [`MAX_CONCURRENT_MODULE_CHANGES`].
+    pub async fn watch_module_host(&mut self) -> Result<(), ModuleExitCause> {
+        let (res, _permit) = changed_within(&mut self.module_rx, &MODULE_CHANGE_PERMITS).await;
+        let metrics = &self.module.info.metrics;
+        let latency = self
+            .module
+            .info
+            .superseded_at
+            .get()
+            .map(|at| at.elapsed().as_secs_f64());
+        match res {
             Ok(()) => {
+                if let Some(latency) = latency {
+                    metrics.module_update_observe_latency.observe(latency);
+                }
                 self.module = self.module_rx.borrow_and_update().clone();
                 Ok(())
             }
-            Err(_) => Err(NoSuchModule),
+            Err(_) => {
+                if let Some(latency) = latency {
+                    metrics.module_exit_observe_latency.observe(latency);
+                    // Connections observe the exit in some order,
+                    // so the last one to do so leaves the total drain time here.
+                    metrics.module_exit_last_observed.set(latency);
+                }
+                let cause = self.module_rx.borrow().info.exit_cause.get().copied();
+                Err(cause.unwrap_or(ModuleExitCause::Crashed))
+            }
         }
     }
 
@@ -490,6 +1437,7 @@ impl ClientConnection {
         request_id: RequestId,
         timer: Instant,
         flags: CallReducerFlags,
+        traceparent: Option<TraceParent>,
     ) -> Result<ReducerCallResult, ReducerCallError> {
         let caller = match flags {
             CallReducerFlags::FullUpdate => Some(self.sender()),
@@ -503,8 +1451,10 @@ impl ClientConnection {
                 self.id.identity,
                 Some(self.id.connection_id),
                 caller,
+                ReducerTransport::Websocket,
                 Some(request_id),
                 Some(timer),
+                traceparent,
                 reducer,
                 args,
             )
@@ -525,6 +1475,20 @@ impl ClientConnection {
         .await
     }
 
+    pub async fn subscribe_window(
+        &self,
+        subscription: SubscribeWindow,
+        timer: Instant,
+    ) -> Result<Option<ExecutionMetrics>, DBError> {
+        let me = self.clone();
+        asyncify(move || {
+            me.module
+                .subscriptions()
+                .add_window_subscription(me.sender, subscription, timer, None)
+        })
+        .await
+    }
+
     pub async fn unsubscribe(&self, request: Unsubscribe, timer: Instant) -> Result<Option<ExecutionMetrics>, DBError> {
         let me = self.clone();
         asyncify(move || {
@@ -549,6 +1513,42 @@ impl ClientConnection {
         .await
     }
 
+    /// Like [`Self::subscribe_multi`],
+    /// but send the client the plans chosen for each of its queries along with the initial rows.
+    ///
+    /// This is purely informational, so queries which fail to compile are left for
+    /// the subscription to report, and non-owners receive no plans.
+    pub async fn explain_subscribe_multi(
+        &self,
+        request: SubscribeMulti,
+        timer: Instant,
+    ) -> Result<Option<ExecutionMetrics>, DBError> {
+        let mut plans = vec![];
+        if self.id.identity == self.module.replica_ctx().owner_identity {
+            for query in &request.query_strings {
+                let Ok(explained) = self
+                    .module
+                    .explain_query(self.id.identity, query.to_string(), QueryKind::Subscription)
+                    .await
+                else {
+                    continue;
+                };
+                plans.extend(explained.iter().map(|plan| {
+                    serde_json::to_string(plan)
+                        .expect("should be able to json encode a query plan")
+                        .into_boxed_str()
+                }));
+            }
+        }
+        let me = self.clone();
+        asyncify(move || {
+            me.module
+                .subscriptions()
+                .add_explained_multi_subscription(me.sender, request, plans.into(), timer)
+        })
+        .await
+    }
+
     pub async fn unsubscribe_multi(
         &self,
         request: UnsubscribeMulti,
@@ -563,6 +1563,32 @@ impl ClientConnection {
         .await
     }
 
+    /// Change the settings of the connection as the client asked in `update`,
+    /// subject to the defaults of its database, see [`ClientConnectionSender::update_settings`].
+    pub fn update_settings(&self, update: &UpdateSettings) -> Result<ClientConfig, SettingsError> {
+        self.sender.update_settings(update, &self.policy.config_defaults)
+    }
+
+    /// Refuse a new subscription, requested by `request_id` as `query_id`, should the node be under load,
+    /// answering it with an error, and returning whether it was refused.
+    pub fn shed_subscription(&self, request_id: RequestId, query_id: Option<QueryId>, timer: Instant) -> bool {
+        let Err(err) = self.policy.load_admission.admit_subscription() else {
+            return false;
+        };
+        log::debug!("refusing a subscription of client {}: {err}", self.id);
+        // Should the client be gone, there's no one to tell.
+        let _ = self.send_message(SubscriptionMessage {
+            timer: Some(timer),
+            request_id: Some(request_id),
+            query_id,
+            result: SubscriptionResult::Error(SubscriptionError {
+                table_id: None,
+                message: err.subscription_error(self.policy.error_format),
+            }),
+        });
+        true
+    }
+
     pub async fn subscribe(&self, subscription: Subscribe, timer: Instant) -> Result<ExecutionMetrics, DBError> {
         let me = self.clone();
         asyncify(move || {
@@ -579,37 +1605,524 @@ impl ClientConnection {
         message_id: &[u8],
         timer: Instant,
     ) -> Result<(), anyhow::Error> {
+        self.one_off_query(
+            query,
+            message_id,
+            timer,
+            |msg: OneOffQueryResponseMessage<JsonFormat>| msg.into(),
+        )
+        .await
+    }
+
+    pub async fn one_off_query_bsatn(
+        &self,
+        query: &str,
+        message_id: &[u8],
+        timer: Instant,
+    ) -> Result<(), anyhow::Error> {
+        self.one_off_query(
+            query,
+            message_id,
+            timer,
+            |msg: OneOffQueryResponseMessage<BsatnFormat>| msg.into(),
+        )
+        .await
+    }
+
+    /// Run a one-off query, unless too many are already in flight over this connection or to its database,
+    /// in which case the client is told so right away.
+    async fn one_off_query<F: WebsocketFormat>(
+        &self,
+        query: &str,
+        message_id: &[u8],
+        timer: Instant,
+        into_message: impl FnOnce(OneOffQueryResponseMessage<F>) -> SerializableMessage + Send + 'static,
+    ) -> Result<(), anyhow::Error> {
+        let database = &self.module.replica_ctx().one_off_queries;
+        let _permit = match start_one_off_query(&self.one_off_queries, database, self.policy.one_off_query_limits) {
+            Ok(permit) => permit,
+            Err(err) => {
+                WORKER_METRICS
+                    .one_off_queries_refused
+                    .with_label_values(&self.module.info().database_identity, err.scope.as_str())
+                    .inc();
+                self.send_message(into_message(OneOffQueryResponseMessage {
+                    message_id: message_id.to_owned(),
+                    error: Some(err.to_string()),
+                    results: vec![],
+                    total_host_execution_duration: timer.elapsed().into(),
+                }))?;
+                return Ok(());
+            }
+        };
         self.module
-            .one_off_query::<JsonFormat>(
+            .one_off_query::<F>(
                 self.id.identity,
                 query.to_owned(),
                 self.sender.clone(),
                 message_id.to_owned(),
                 timer,
-                |msg: OneOffQueryResponseMessage<JsonFormat>| msg.into(),
+                into_message,
             )
             .await
     }
 
-    pub async fn one_off_query_bsatn(
+    pub async fn query_json(&self, query: &str, request_id: u32, timer: Instant) -> Result<(), anyhow::Error> {
+        self.query(query, request_id, timer, |msg: QueryRowsMessage<JsonFormat>| msg.into())
+            .await
+    }
+
+    pub async fn query_bsatn(&self, query: &str, request_id: u32, timer: Instant) -> Result<(), anyhow::Error> {
+        self.query(query, request_id, timer, |msg: QueryRowsMessage<BsatnFormat>| {
+            msg.into()
+        })
+        .await
+    }
+
+    /// Run a one-off query sent with `Query`, within the [`OneOffQueryLimits`] of the connection,
+    /// sending its rows to the client in chunks.
+    ///
+    /// A query which fails, or which is refused as too many are already in flight,
+    /// fails with a [`RequestRejected`] telling the client why, e.g. [`RequestErrorCode::QueryTimeout`].
+    async fn query<F: WebsocketFormat>(
         &self,
         query: &str,
-        message_id: &[u8],
+        request_id: u32,
         timer: Instant,
+        into_message: impl Fn(QueryRowsMessage<F>) -> SerializableMessage + Send + 'static,
     ) -> Result<(), anyhow::Error> {
+        let database = &self.module.replica_ctx().one_off_queries;
+        let limits = self.policy.one_off_query_limits;
+        let _permit = start_one_off_query(&self.one_off_queries, database, limits).map_err(|err| {
+            WORKER_METRICS
+                .one_off_queries_refused
+                .with_label_values(&self.module.info().database_identity, err.scope.as_str())
+                .inc();
+            RequestRejected {
+                code: RequestErrorCode::QueueFull,
+                message: err.to_string(),
+            }
+        })?;
         self.module
-            .one_off_query::<BsatnFormat>(
+            .query::<F>(
                 self.id.identity,
                 query.to_owned(),
                 self.sender.clone(),
-                message_id.to_owned(),
+                request_id,
+                limits,
                 timer,
-                |msg: OneOffQueryResponseMessage<BsatnFormat>| msg.into(),
+                into_message,
             )
             .await
+            .map_err(|err| {
+                RequestRejected {
+                    code: query_error_code(&err),
+                    message: format!("{err:#}"),
+                }
+                .into()
+            })
     }
 
+    /// Switch the connection over to `identity`, under the new `connection_id`,
+    /// returning the connection as `identity` and the receiving end of its message queue.
+    ///
+    /// The current identity is disconnected from the module first,
+    /// dropping its subscriptions and running its `client_disconnected` reducer,
+    /// and then `identity` is connected as if by [`Self::spawn`].
+    /// If the module rejects `identity`, the connection is left without an identity,
+    /// and the caller should close it.
+    ///
+    /// Messages sent through `self` are never received from the returned queue,
+    /// so once the caller stops receiving from the current queue,
+    /// nothing on behalf of the current identity reaches the client.
+    pub async fn reauthenticate(
+        &self,
+        identity: Identity,
+        connection_id: ConnectionId,
+    ) -> Result<(ClientConnection, MeteredReceiver<SerializableMessage>), ClientConnectedError> {
+        self.clone().disconnect().await;
+
+        let id = ClientActorId {
+            identity,
+            connection_id,
+            name: self.id.name,
+        };
+        self.module
+            .call_identity_connected(id.identity, id.connection_id)
+            .await?;
+
+        let mut config = self.config();
+        // Reducer timings are only for the owner of the database.
+        config.reducer_timings &= identity == self.module.info().owner_identity;
+        let (sender, sendrx) = self.sender.reauthenticated(id, config);
+        let sender = Arc::new(sender);
+        self.module.replica_ctx().clients.insert(&sender);
+        let this = Self {
+            sender,
+            replica_id: self.replica_id,
+            module: self.module.clone(),
+            module_rx: self.module_rx.clone(),
+            one_off_queries: self.one_off_queries.clone(),
+            auth_expires_at: self.auth_expires_at.clone(),
+            policy: self.policy.clone(),
+        };
+        Ok((this, sendrx))
+    }
+
+    /// Disconnect the client from the module, unless it already has been.
     pub async fn disconnect(self) {
-        self.module.disconnect_client(self.id).await
+        self.sender.disconnect_from(&self.module).await
+    }
+}
+
+/// The [`RequestErrorCode`] the client is told a query sent with `Query` failed with.
+fn query_error_code(err: &anyhow::Error) -> RequestErrorCode {
+    match (err.downcast_ref(), err.downcast_ref()) {
+        (Some(QueryCutShort::Timeout(_)), _) => RequestErrorCode::QueryTimeout,
+        (Some(QueryCutShort::TooManyRows(_)), _) => RequestErrorCode::TooManyRows,
+        (_, Some(TypingError::NotAccessible(_))) => RequestErrorCode::PermissionDenied,
+        _ => RequestErrorCode::QueryFailed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::messages::IdentityTokenMessage;
+    use crate::messages::control_db::ClientDefault;
+
+    fn huge_message(size: usize) -> SerializableMessage {
+        SerializableMessage::Identity(IdentityTokenMessage {
+            identity: Identity::ZERO,
+            token: "x".repeat(size).into(),
+            connection_id: ConnectionId::ZERO,
+        })
+    }
+
+    #[test]
+    fn failed_queries_have_request_error_codes() {
+        let code = |err: anyhow::Error| query_error_code(&err);
+        assert_eq!(
+            code(QueryCutShort::Timeout(Duration::from_secs(1)).into()),
+            RequestErrorCode::QueryTimeout
+        );
+        assert_eq!(
+            code(QueryCutShort::TooManyRows(10).into()),
+            RequestErrorCode::TooManyRows
+        );
+        let private = TypingError::NotAccessible(spacetimedb_expr::errors::TableNotAccessible("secret".into()));
+        assert_eq!(code(private.into()), RequestErrorCode::PermissionDenied);
+        assert_eq!(code(anyhow::anyhow!("no such table")), RequestErrorCode::QueryFailed);
+    }
+
+    #[tokio::test]
+    async fn huge_messages_trip_the_byte_bound_before_the_count_bound() {
+        let capacity = SendQueueCapacity {
+            messages: 1000,
+            bytes: 5 * MB,
+        };
+        let id = ClientActorId::for_test(Identity::ZERO);
+        let (sender, mut rx) = ClientConnectionSender::dummy_with_capacity(id, ClientConfig::for_test(), capacity);
+
+        for _ in 0..4 {
+            sender.send_message(huge_message(MB)).unwrap();
+        }
+        assert!(rx.queued_bytes() > 4 * MB);
+        // Only a handful of messages in, the byte bound is hit, not the count bound.
+        assert_eq!(
+            sender.send_message(huge_message(MB)),
+            Err(ClientSendError::QueueFullByBytes)
+        );
+        assert!(sender.is_cancelled());
+        assert_eq!(rx.len(), 4);
+
+        // Receiving releases the bytes of the received messages.
+        let mut buf = Vec::new();
+        rx.recv_many(&mut buf, 2).await;
+        assert!(rx.queued_bytes() < 3 * MB);
+    }
+
+    #[tokio::test]
+    async fn a_single_oversized_message_is_queued_alone() {
+        let capacity = SendQueueCapacity {
+            messages: 1000,
+            bytes: MB,
+        };
+        let id = ClientActorId::for_test(Identity::ZERO);
+        let (sender, mut rx) = ClientConnectionSender::dummy_with_capacity(id, ClientConfig::for_test(), capacity);
+
+        sender.send_message(huge_message(2 * MB)).unwrap();
+        assert!(rx.recv().await.is_some());
+        assert_eq!(rx.queued_bytes(), 0);
+        sender.send_message(huge_message(2 * MB)).unwrap();
+        assert!(sender.send_message(huge_message(1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn send_errors_say_why_the_message_was_not_queued() {
+        let capacity = SendQueueCapacity {
+            messages: 2,
+            bytes: 5 * MB,
+        };
+        let id = ClientActorId::for_test(Identity::ZERO);
+
+        // The count bound.
+        let (sender, _rx) = ClientConnectionSender::dummy_with_capacity(id, ClientConfig::for_test(), capacity);
+        sender.send_message(huge_message(1)).unwrap();
+        sender.send_message(huge_message(1)).unwrap();
+        assert_eq!(
+            sender.send_message(huge_message(1)),
+            Err(ClientSendError::QueueFullByCount)
+        );
+        // Having tripped a bound, the client is being disconnected.
+        assert_eq!(
+            sender.send_message(huge_message(1)),
+            Err(ClientSendError::ConnectionClosing)
+        );
+
+        // The byte bound.
+        let (sender, _rx) = ClientConnectionSender::dummy_with_capacity(id, ClientConfig::for_test(), capacity);
+        sender.send_message(huge_message(5 * MB)).unwrap();
+        assert_eq!(
+            sender.send_message(huge_message(1)),
+            Err(ClientSendError::QueueFullByBytes)
+        );
+        assert_eq!(
+            sender.send_message(huge_message(1)),
+            Err(ClientSendError::ConnectionClosing)
+        );
+
+        // A closed queue.
+        let (sender, mut rx) = ClientConnectionSender::dummy_with_capacity(id, ClientConfig::for_test(), capacity);
+        rx.close();
+        assert_eq!(sender.send_message(huge_message(1)), Err(ClientSendError::Disconnected));
+        assert!(!sender.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn reauthenticated_sender_has_its_own_queue() {
+        let old_id = ClientActorId::for_test(Identity::ZERO);
+        let (old, mut old_rx) =
+            ClientConnectionSender::dummy_with_capacity(old_id, ClientConfig::for_test(), <_>::default());
+        let new_id = ClientActorId::for_test(Identity::ONE);
+        let (new, mut new_rx) = old.reauthenticated(new_id, old.config());
+        assert_eq!(new.id, new_id);
+
+        old.send_message(huge_message(1)).unwrap();
+        new.send_message(huge_message(2)).unwrap();
+
+        // Once the old queue is closed, what was already sent through it is still received,
+        // but nothing more can be sent.
+        old_rx.close();
+        assert_eq!(old.send_message(huge_message(3)), Err(ClientSendError::Disconnected));
+        assert!(!new.is_cancelled());
+
+        // A closed receiver is only drained into an empty buffer.
+        assert_eq!(old_rx.recv_many(&mut Vec::new(), 32).await, 1);
+        assert_eq!(old_rx.recv_many(&mut Vec::new(), 32).await, 0);
+        assert_eq!(new_rx.recv_many(&mut Vec::new(), 32).await, 1);
+        assert!(new_rx.is_empty());
+    }
+
+    #[tokio::test]
+    async fn protocol_violations_are_counted_per_connection() {
+        let (old, _old_rx) = ClientConnectionSender::dummy_with_channel(
+            ClientActorId::for_test(Identity::ZERO),
+            ClientConfig::for_test(),
+        );
+        assert_eq!(old.record_protocol_violation(), 1);
+        let (new, _new_rx) = old.reauthenticated(ClientActorId::for_test(Identity::ONE), old.config());
+        assert_eq!(new.record_protocol_violation(), 2);
+        assert_eq!(old.protocol_violations(), 2);
+
+        let (other, _other_rx) = ClientConnectionSender::dummy_with_channel(
+            ClientActorId::for_test(Identity::ZERO),
+            ClientConfig::for_test(),
+        );
+        assert_eq!(other.protocol_violations(), 0);
+    }
+
+    fn update(compression: Option<Compression>, tx_update_full: Option<bool>) -> UpdateSettings {
+        UpdateSettings {
+            request_id: 1,
+            compression,
+            tx_update_full,
+            compression_threshold: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn settings_change_mid_session() {
+        let sender = ClientConnectionSender::dummy(ClientActorId::for_test(Identity::ZERO), ClientConfig::for_test());
+        let defaults = ClientConfigDefaults::default();
+        let codec = sender.codec();
+
+        let config = sender
+            .update_settings(&update(Some(Compression::Gzip), Some(false)), &defaults)
+            .unwrap();
+        assert_eq!((config.compression, config.tx_update_full), (Compression::Gzip, false));
+        assert_eq!(sender.config(), config);
+        // The codec only changes along with the compression threshold.
+        assert!(Arc::ptr_eq(&codec, &sender.codec()));
+
+        let threshold = UpdateSettings {
+            compression_threshold: Some(16),
+            ..update(None, None)
+        };
+        let config = sender.update_settings(&threshold, &defaults).unwrap();
+        assert_eq!(config.compression_threshold, 16);
+        assert_eq!((config.compression, config.tx_update_full), (Compression::Gzip, false));
+        assert!(!Arc::ptr_eq(&codec, &sender.codec()));
+    }
+
+    #[tokio::test]
+    async fn invalid_settings_are_refused_as_a_whole() {
+        let config = ClientConfig {
+            version: ProtocolVersion::V1,
+            ..ClientConfig::for_test()
+        };
+        let sender = ClientConnectionSender::dummy(ClientActorId::for_test(Identity::ZERO), config);
+        let unsupported = sender.update_settings(&update(Some(Compression::Zstd), Some(false)), &<_>::default());
+        assert_eq!(
+            unsupported,
+            Err(SettingsError::UnsupportedCompression {
+                compression: Compression::Zstd,
+                version: ProtocolVersion::V1,
+            })
+        );
+        assert_eq!(sender.config(), config);
+
+        let defaults = ClientConfigDefaults {
+            light: Some(ClientDefault {
+                value: false,
+                forced: true,
+            }),
+            ..<_>::default()
+        };
+        let forced = sender.update_settings(&update(None, Some(false)), &defaults);
+        assert_eq!(forced, Err(SettingsError::Forced { setting: "light mode" }));
+        // Asking for what's forced anyway is no change.
+        assert!(sender.update_settings(&update(None, Some(true)), &defaults).is_ok());
+        assert_eq!(sender.config(), config);
+    }
+
+    /// Retries a connection attempt which fails transiently `failures` times before succeeding.
+    async fn connect_after(retry: ConnectRetry, failures: u32) -> (Result<u32, ClientConnectedError>, u32) {
+        let mut attempts = 0;
+        let res = retry
+            .run(|| {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    if attempt <= failures {
+                        Err(ReducerCallError::NoSuchModule(crate::host::NoSuchModule).into())
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+        (res, attempts)
+    }
+
+    #[tokio::test]
+    async fn transient_connect_failures_are_retried() {
+        let retry = ConnectRetry {
+            attempts: 3,
+            budget: Duration::from_secs(10),
+            backoff: Duration::from_millis(1),
+        };
+        let connected = WORKER_METRICS
+            .ws_connect_outcomes
+            .with_label_values("connected_after_retry");
+        let before = connected.get();
+        let start = Instant::now();
+        let (res, attempts) = connect_after(retry, 2).await;
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(attempts, 3);
+        assert!(connected.get() > before);
+        // The client only sees the backoff as added latency.
+        assert!(start.elapsed() >= Duration::from_millis(3));
+
+        let (res, attempts) = connect_after(retry, 3).await;
+        assert!(res.unwrap_err().is_transient());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn connect_retries_stop_at_the_budget_or_a_lasting_failure() {
+        let retry = ConnectRetry {
+            attempts: 10,
+            budget: Duration::from_millis(10),
+            backoff: Duration::from_millis(4),
+        };
+        // The second retry would wait until past the budget.
+        let (res, attempts) = connect_after(retry, 10).await;
+        assert!(res.is_err());
+        assert_eq!(attempts, 2);
+
+        let mut attempts = 0;
+        let res: Result<(), _> = retry
+            .run(|| {
+                attempts += 1;
+                async { Err(ClientConnectedError::Rejected("no".into())) }
+            })
+            .await;
+        assert!(!res.unwrap_err().is_transient());
+        assert_eq!(attempts, 1);
+    }
+
+    /// Test that 10k connections watching a module all observe it restart within a bounded window,
+    /// while no more than a bounded number of them act on each change at once.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn watchers_observe_a_restart_in_bounded_batches() {
+        const WATCHERS: usize = 10_000;
+        const BOUND: usize = 64;
+        static PERMITS: Semaphore = Semaphore::const_new(BOUND);
+
+        let (tx, rx) = watch::channel(0_u32);
+        let acting = Arc::new(AtomicUsize::new(0));
+        let most_acting = Arc::new(AtomicUsize::new(0));
+        let watchers = (0..WATCHERS)
+            .map(|_| {
+                let mut rx = rx.clone();
+                let (acting, most_acting) = (acting.clone(), most_acting.clone());
+                tokio::spawn(async move {
+                    let mut observed = vec![];
+                    loop {
+                        let (res, _permit) = changed_within(&mut rx, &PERMITS).await;
+                        most_acting.fetch_max(acting.fetch_add(1, Relaxed) + 1, Relaxed);
+                        // Act on the change across a tick, as switching modules or closing a socket would.
+                        tokio::task::yield_now().await;
+                        acting.fetch_sub(1, Relaxed);
+                        observed.push(Instant::now());
+                        if res.is_err() {
+                            return observed;
+                        }
+                        rx.borrow_and_update();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(rx);
+
+        // The module is updated, and then the updated module exits.
+        let restarted = Instant::now();
+        tx.send_replace(1);
+        drop(tx);
+
+        let mut last_observed = restarted;
+        for watcher in watchers {
+            let observed = watcher.await.unwrap();
+            assert_eq!(observed.len(), 2);
+            last_observed = last_observed.max(observed[1]);
+        }
+        let most_acting = most_acting.load(Relaxed);
+        assert!(most_acting <= BOUND, "{most_acting} watchers acted at once");
+        let window = last_observed - restarted;
+        assert!(
+            window < Duration::from_secs(5),
+            "the restart took {window:?} to observe"
+        );
     }
 }
@@ -1,17 +1,1715 @@
-use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::Duration;
 
-use super::ClientName;
+use ipnet::IpNet;
+use parking_lot::Mutex;
+
+use super::client_connection::{CloseReason, ConnectRetry};
+use super::uniques::{self, Day, IdentitySketch, UniqueIdentities};
+use super::{
+    ClientActorId, ClientConnection, ClientConnectionSender, ClientName, ClientRuntime, ConnectionSender,
+    LoadAdmission, MessageRateLimit, MessageThrottle, OneOffQueryLimits, PresenceIndex, ReconnectTokens,
+};
+use crate::host::ModuleHost;
+use crate::identity::Identity;
+use crate::messages::control_db::MessageSizeLimits;
+use crate::worker_metrics::{ClientSeriesConfig, ClientSeriesSampler, WORKER_METRICS};
+use spacetimedb_lib::ConnectionId;
+use tokio::sync::{watch, Notify};
+use tokio::task::AbortHandle;
+
+/// Node-wide limits on the number of concurrent websocket connections.
+///
+/// Read from the `[connection-limits]` section of `config.toml`.
+#[serde_with::serde_as]
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ConnectionLimits {
+    /// Once this many clients are connected, new connections are rejected
+    /// with `503 Service Unavailable` and a `Retry-After` header.
+    pub soft_limit: Option<usize>,
+    /// The absolute maximum number of clients this node will serve,
+    /// which should leave a safety margin below the file descriptor limit.
+    ///
+    /// New connections are rejected once this many clients are connected, whether or not a `soft_limit` is set.
+    pub hard_limit: Option<usize>,
+    /// When the number of connected clients exceeds `hard_limit`,
+    /// close the longest idle connections until we're back under it.
+    pub evict_idlest: bool,
+    /// How many connections from a single address the node serves at once,
+    /// beyond which new connections from it are rejected with `429 Too Many Requests` and a `Retry-After` header.
+    ///
+    /// Addresses are as determined by the node's [`NetworkOptions`].
+    /// Connections from an unknown address aren't limited.
+    /// If unset, there is no limit.
+    pub per_ip_limit: Option<usize>,
+    /// The value of the `Retry-After` header sent with a shed connection.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "retry-after-secs")]
+    pub retry_after: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            soft_limit: None,
+            hard_limit: None,
+            evict_idlest: false,
+            per_ip_limit: None,
+            retry_after: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ConnectionLimits {
+    /// The number of connections at which new connections are shed.
+    fn admission_limit(&self) -> Option<usize> {
+        match (self.soft_limit, self.hard_limit) {
+            (Some(soft_limit), Some(hard_limit)) => Some(soft_limit.min(hard_limit)),
+            (soft_limit, hard_limit) => soft_limit.or(hard_limit),
+        }
+    }
+}
+
+/// Node-wide tuning of websocket connections.
+///
+/// Read from the `[websocket]` section of `config.toml`.
+#[serde_with::serde_as]
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct WebSocketOptions {
+    /// The largest message a client may send, in bytes.
+    ///
+    /// A database may lower it for its own clients, see [`Self::with_message_size_limits`].
+    pub max_message_size: usize,
+    /// The largest websocket frame a client may send, in bytes,
+    /// of which a message may be split over several.
+    ///
+    /// A frame larger than this closes the connection, as does a message larger than twice [`Self::max_message_size`].
+    /// If unset, frames are limited only by [`Self::max_message_size`].
+    pub max_frame_size: Option<usize>,
+    /// How many messages larger than [`Self::max_message_size`]
+    /// a client using the text protocol may send before its connection is closed.
+    ///
+    /// Each such message is rejected with an error the client can recover from,
+    /// as long as it is no larger than twice the limit.
+    /// Any larger message closes the connection right away.
+    ///
+    /// If zero, the first oversized message closes the connection.
+    pub oversized_message_strikes: u32,
+    /// How many protocol violations, e.g. undecodable or oversized messages,
+    /// a client may commit before its connection is closed.
+    ///
+    /// Within the budget, each violation is rejected with an error the client can recover from.
+    /// If unset, a client's first undecodable message closes its connection,
+    /// and its oversized messages are subject only to [`Self::oversized_message_strikes`].
+    pub protocol_violation_budget: Option<u32>,
+    /// How many one-off queries a single connection may have in flight at once.
+    ///
+    /// Queries over the limit are refused right away with a `too many concurrent queries` error.
+    /// If unset, there is no limit.
+    pub max_concurrent_one_off_queries: Option<usize>,
+    /// How many one-off queries may be in flight at once to a single database,
+    /// over all of its connections on this node.
+    ///
+    /// As with [`Self::max_concurrent_one_off_queries`], queries over the limit are refused.
+    /// If unset, there is no limit.
+    pub max_concurrent_one_off_queries_per_database: Option<usize>,
+    /// How long a one-off query sent with `Query` may run before it's cut short with a `QueryTimeout` error.
+    ///
+    /// If unset, there is no limit.
+    #[serde_as(as = "Option<serde_with::DurationMilliSeconds<u64>>")]
+    #[serde(rename = "one-off-query-timeout-ms")]
+    pub one_off_query_timeout: Option<Duration>,
+    /// How many rows a one-off query sent with `Query` may match before it's cut short with a `TooManyRows` error.
+    ///
+    /// If unset, there is no limit.
+    pub max_one_off_query_rows: Option<usize>,
+    /// How many rows of a one-off query sent with `Query` are sent per `QueryRows` message,
+    /// so that a large result doesn't make for one huge message.
+    ///
+    /// If unset, all of a query's rows are sent in one message.
+    pub one_off_query_chunk_rows: Option<usize>,
+    /// How often the [`ClientActorIndex`] is swept for connections whose actor died
+    /// without deregistering them, see [`ClientActorIndex::evict_stale`].
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "stale-connection-sweep-interval-secs")]
+    pub stale_connection_sweep_interval: Duration,
+    /// How long a connection whose actor has finished may go without activity
+    /// before a sweep evicts it, so that connections being torn down aren't raced.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "stale-connection-grace-secs")]
+    pub stale_connection_grace: Duration,
+    /// How many messages received from a client may wait to be handled,
+    /// beyond which [`Self::incoming_queue_overflow`] applies.
+    pub max_incoming_queue_len: usize,
+    /// How many bytes of messages received from a client may wait to be handled,
+    /// beyond which [`Self::incoming_queue_overflow`] applies.
+    ///
+    /// A message is always queued if none are waiting, however large.
+    pub max_incoming_queue_bytes: usize,
+    /// What becomes of a message received from a client whose queue of messages to handle is full.
+    pub incoming_queue_overflow: IncomingQueueOverflow,
+    /// The level at which messages are compressed for clients which asked for zstd compression,
+    /// see [`zstd_compress_with_level`](spacetimedb_client_api_messages::websocket::zstd_compress_with_level).
+    pub zstd_level: i32,
+    /// The estimated size, in bytes, of a message to a client from which it's encoded and compressed
+    /// on the blocking pool, rather than by the client's actor,
+    /// so that encoding e.g. a large initial subscription doesn't stall the actor, nor the other actors of its worker.
+    ///
+    /// Smaller messages are encoded by the actor, sparing them the hop to the blocking pool.
+    pub encode_offload_threshold: usize,
+    /// What becomes of a new connection asking for the connection id of a connected client of its identity.
+    pub connection_id_reuse: ConnectionIdReuse,
+    /// How many times connecting a client to its module is attempted,
+    /// should it fail in a way which may pass, e.g. while the module restarts.
+    ///
+    /// Once the attempts are used up, the client is closed with a hint to retry later.
+    pub connect_retry_attempts: u32,
+    /// How long connecting a client to its module may take over all of its attempts,
+    /// see [`Self::connect_retry_attempts`].
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(rename = "connect-retry-budget-ms")]
+    pub connect_retry_budget: Duration,
+    /// How long the session of a client which lost its connection is kept for it to resume,
+    /// see [`ResumableSessions`](super::ResumableSessions).
+    ///
+    /// Only clients which connect with `resumable=true` can resume their sessions.
+    /// If unset, no session can be resumed.
+    #[serde_as(as = "Option<serde_with::DurationMilliSeconds<u64>>")]
+    #[serde(rename = "session-resume-grace-ms")]
+    pub session_resume_grace: Option<Duration>,
+    /// How many messages may be queued for a client whose session is kept for it to resume,
+    /// beyond which the session is given up on, and the client has to subscribe anew.
+    pub session_resume_buffer_len: usize,
+    /// The fraction of clients, from 0 to 1, which get metric series labeled with their identity,
+    /// beyond those on their database's `client_metrics_allowlist`.
+    ///
+    /// Every client is counted in the metric series of its database regardless,
+    /// see [`ClientSeriesSampler`].
+    pub client_metrics_sample_rate: f64,
+    /// How many sampled clients may have metric series of their own at once,
+    /// see [`Self::client_metrics_sample_rate`].
+    pub client_metrics_max_clients: usize,
+    /// How long the metric series of a client are kept after its last connection is gone.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "client-metrics-ttl-secs")]
+    pub client_metrics_ttl: Duration,
+    /// If set, websocket connections are served by a runtime of their own with this many worker threads,
+    /// rather than by the runtime which also executes modules, see [`ClientRuntime`].
+    pub client_runtime_threads: Option<usize>,
+    /// How many messages per second a client may send, on average,
+    /// beyond which [`Self::client_message_throttle`] applies.
+    ///
+    /// A database may override it with its own `client_message_rate`, or lift it by setting that to zero.
+    /// Pings, pongs, and close frames don't count against the limit.
+    /// If unset, there is no limit, unless the database sets one.
+    pub client_message_rate: Option<f64>,
+    /// How many messages a client may send at once, within [`Self::client_message_rate`].
+    ///
+    /// If unset, a client may send as many messages at once as it may send per second.
+    pub client_message_burst: Option<u32>,
+    /// What becomes of a message received from a client beyond its [`Self::client_message_rate`].
+    pub client_message_throttle: MessageThrottle,
+}
+
+/// What becomes of a new connection asking for the connection id of a connected client of its identity,
+/// see [`WebSocketOptions::connection_id_reuse`].
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionIdReuse {
+    /// The new connection is rejected with `409 Conflict`.
+    #[default]
+    Reject,
+    /// The connected client is closed, and the new connection takes its place once it has disconnected,
+    /// see [`ClientActorIndex::replace_connection`].
+    ///
+    /// Only clients of the same identity are replaced, as those of others may share the connection id.
+    Replace,
+}
+
+/// What becomes of a message received from a client whose queue of messages to handle is full,
+/// see [`WebSocketOptions::max_incoming_queue_len`].
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IncomingQueueOverflow {
+    /// The message is dropped, and the client sent an error it can recover from.
+    #[default]
+    Reject,
+    /// The connection is closed.
+    Close,
+}
+
+impl Default for WebSocketOptions {
+    fn default() -> Self {
+        Self {
+            max_message_size: 0x2000000,
+            max_frame_size: None,
+            oversized_message_strikes: 0,
+            protocol_violation_budget: None,
+            max_concurrent_one_off_queries: None,
+            max_concurrent_one_off_queries_per_database: None,
+            one_off_query_timeout: None,
+            max_one_off_query_rows: None,
+            one_off_query_chunk_rows: Some(1024),
+            stale_connection_sweep_interval: Duration::from_secs(60),
+            stale_connection_grace: Duration::from_secs(30),
+            max_incoming_queue_len: 1024,
+            max_incoming_queue_bytes: 16 * 1024 * 1024,
+            incoming_queue_overflow: IncomingQueueOverflow::Reject,
+            zstd_level: spacetimedb_client_api_messages::websocket::DEFAULT_ZSTD_LEVEL,
+            encode_offload_threshold: 1024 * 1024,
+            connection_id_reuse: ConnectionIdReuse::Reject,
+            connect_retry_attempts: ConnectRetry::default().attempts,
+            connect_retry_budget: ConnectRetry::default().budget,
+            session_resume_grace: None,
+            session_resume_buffer_len: 256,
+            client_metrics_sample_rate: ClientSeriesConfig::default().sample_rate,
+            client_metrics_max_clients: ClientSeriesConfig::default().max_sampled_clients,
+            client_metrics_ttl: ClientSeriesConfig::default().ttl,
+            client_runtime_threads: None,
+            client_message_rate: None,
+            client_message_burst: None,
+            client_message_throttle: MessageThrottle::Delay,
+        }
+    }
+}
+
+impl WebSocketOptions {
+    /// The limits on one-off queries in flight, see [`Self::max_concurrent_one_off_queries`],
+    /// and on those sent with `Query`, see [`Self::one_off_query_timeout`].
+    pub fn one_off_query_limits(&self) -> OneOffQueryLimits {
+        OneOffQueryLimits {
+            per_connection: self.max_concurrent_one_off_queries,
+            per_database: self.max_concurrent_one_off_queries_per_database,
+            timeout: self.one_off_query_timeout,
+            max_rows: self.max_one_off_query_rows,
+            chunk_rows: self.one_off_query_chunk_rows,
+        }
+    }
+
+    /// How connecting a client to its module is retried, see [`Self::connect_retry_attempts`].
+    pub fn connect_retry(&self) -> ConnectRetry {
+        ConnectRetry {
+            attempts: self.connect_retry_attempts.max(1),
+            budget: self.connect_retry_budget,
+            ..ConnectRetry::default()
+        }
+    }
+
+    /// Which clients get metric series of their own, see [`Self::client_metrics_sample_rate`].
+    pub fn client_series(&self) -> ClientSeriesConfig {
+        ClientSeriesConfig {
+            sample_rate: self.client_metrics_sample_rate.clamp(0.0, 1.0),
+            max_sampled_clients: self.client_metrics_max_clients,
+            ttl: self.client_metrics_ttl,
+        }
+    }
+
+    /// The options of a client of a database which sets message size `limits` of its own,
+    /// which can only lower those of the node.
+    pub fn with_message_size_limits(&self, limits: &MessageSizeLimits) -> Self {
+        let limit = |limit: Option<u64>| limit.map(|limit| usize::try_from(limit).unwrap_or(usize::MAX));
+        let mut options = self.clone();
+        if let Some(max_message_size) = limit(limits.max_message_size) {
+            options.max_message_size = options.max_message_size.min(max_message_size);
+        }
+        if let Some(max_frame_size) = limit(limits.max_frame_size) {
+            options.max_frame_size = Some(
+                options
+                    .max_frame_size
+                    .map_or(max_frame_size, |node| node.min(max_frame_size)),
+            );
+        }
+        options
+    }
+
+    /// The node's limit on the rate at which a client may send messages, see [`Self::client_message_rate`].
+    pub fn message_rate_limit(&self) -> Option<MessageRateLimit> {
+        let rate = self
+            .client_message_rate
+            .filter(|rate| rate.is_finite() && *rate > 0.0)?;
+        Some(MessageRateLimit {
+            rate,
+            burst: self.client_message_burst.unwrap_or(rate.ceil() as u32).max(1),
+        })
+    }
+}
+
+/// How the node determines the address of its clients.
+///
+/// Read from the `[network]` section of `config.toml`.
+#[serde_with::serde_as]
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct NetworkOptions {
+    /// The number of reverse proxies in front of the node which append to `X-Forwarded-For`.
+    ///
+    /// The client address is taken to be the entry this many places from the end of the header,
+    /// as any entries before it could have been forged by the client.
+    /// If zero, `X-Forwarded-For` is ignored and the address of the peer is used.
+    ///
+    /// Ignored if `trusted_proxies` is set.
+    pub trusted_proxy_depth: usize,
+    /// The address ranges of the reverse proxies trusted to append to `X-Forwarded-For`,
+    /// in CIDR notation or as single addresses.
+    ///
+    /// If the peer is one of them, the client address is taken to be
+    /// the last entry of the header which isn't, as any entries before it could have been forged.
+    /// Otherwise, the header is ignored and the address of the peer is used.
+    #[serde_as(as = "Vec<IpRange>")]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// Parse a range of addresses, in CIDR notation or as a single address.
+pub fn parse_ip_range(range: &str) -> Result<IpNet, String> {
+    let range = range.trim();
+    range
+        .parse::<IpNet>()
+        .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+        .map(|net| net.trunc())
+        .map_err(|_| format!("Invalid address range: {range}"))
+}
+
+/// Deserializes a range of addresses with [`parse_ip_range`].
+struct IpRange;
+impl<'de> serde_with::DeserializeAs<'de, IpNet> for IpRange {
+    fn deserialize_as<D>(deserializer: D) -> Result<IpNet, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let range = <std::borrow::Cow<'de, str> as serde::Deserialize>::deserialize(deserializer)?;
+        parse_ip_range(&range).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Where a client connected from, as determined by the node's [`NetworkOptions`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClientAddress {
+    /// The address of the client, if known,
+    /// e.g. not if `X-Forwarded-For` was malformed or the peer address isn't available.
+    pub ip: Option<IpAddr>,
+    /// The address of the peer of the client's socket,
+    /// which is one of the node's reverse proxies if it differs from `ip`.
+    pub peer: Option<IpAddr>,
+}
+
+impl fmt::Display for ClientAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{ip}")?,
+            None => f.write_str("unknown address")?,
+        }
+        match self.peer {
+            Some(peer) if Some(peer) != self.ip => write!(f, " via {peer}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A client connected to a node, as listed by [`ClientActorIndex::clients_page`].
+pub struct ListedClient {
+    pub sender: Arc<ClientConnectionSender>,
+    /// The address the client connected from, if known.
+    pub addr: Option<IpAddr>,
+}
+
+/// The state of a node which is [draining](ClientActorIndex::drain).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Draining {
+    /// How long clients should wait before reconnecting, if the node knows.
+    pub retry_after: Option<Duration>,
+}
+
+/// Returned by [`ClientActorIndex::replace_connection`] when the connected clients can't be replaced.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ConnectionReplaceError {
+    #[error("the connection with the connection id didn't close within {0:?}")]
+    TimedOut(Duration),
+}
+
+/// Returned by [`ClientActorIndex::try_admit`] when a new connection should be shed.
+#[derive(thiserror::Error, Debug)]
+#[error("node is at its connection limit ({limit} clients)")]
+pub struct NodeOverloaded {
+    pub limit: usize,
+    /// How long the client should wait before retrying.
+    pub retry_after: Duration,
+}
+
+/// Returned by [`ClientActorIndex::admit_address`] when a new connection should be shed,
+/// as its address has as many connections as it may.
+#[derive(thiserror::Error, Debug)]
+#[error("{addr} is at its connection limit ({limit} clients)")]
+pub struct AddressOverloaded {
+    pub addr: IpAddr,
+    pub limit: usize,
+    /// How long the client should wait before retrying.
+    pub retry_after: Duration,
+}
+
+/// Counts a connection against the [`ConnectionLimits::per_ip_limit`] of its address until dropped,
+/// see [`ClientActorIndex::admit_address`].
+#[must_use]
+#[derive(Debug)]
+pub struct AddressPermit {
+    /// The address and counts of connections per address, if the connection is counted.
+    counted: Option<(IpAddr, AddressCounts)>,
+}
+
+type AddressCounts = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+impl Drop for AddressPermit {
+    fn drop(&mut self) {
+        let Some((addr, counts)) = self.counted.take() else {
+            return;
+        };
+        if let Entry::Occupied(mut count) = counts.lock().entry(addr) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        };
+    }
+}
+
+/// Holds a slot for an admitted connection until dropped, see [`ClientActorIndex::try_admit`].
+#[must_use]
+#[derive(Debug)]
+pub struct AdmissionPermit {
+    /// The count of reserved slots, if the connection holds one.
+    reserved: Option<Arc<AtomicUsize>>,
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        if let Some(reserved) = self.reserved.take() {
+            reserved.fetch_sub(1, Relaxed);
+        }
+    }
+}
+
+type Connections = Arc<Mutex<ConnectionMap>>;
+
+/// The connections registered with a [`ClientActorIndex`].
+#[derive(Default)]
+struct ConnectionMap {
+    by_id: HashMap<ClientActorId, Connection>,
+    /// How many connections in `by_id` have each connection id,
+    /// so that a connection id can be checked for collisions without a scan.
+    connection_ids: HashMap<ConnectionId, usize>,
+    /// Notified whenever a connection is removed, e.g. for those waiting to take over its connection id.
+    removed: Arc<Notify>,
+    /// The connection ids held for clients yet to register, see [`ClientActorIndex::reserve_connection_id`].
+    reserved: HashSet<ConnectionIdKey>,
+}
+
+/// A connection id as a module knows it, i.e. of a client of a database connected as an identity.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct ConnectionIdKey {
+    database_identity: Identity,
+    identity: Identity,
+    connection_id: ConnectionId,
+}
+
+impl ConnectionMap {
+    fn insert(&mut self, id: ClientActorId, conn: Connection) {
+        if self.by_id.insert(id, conn).is_none() {
+            *self.connection_ids.entry(id.connection_id).or_default() += 1;
+        }
+        self.report();
+    }
+
+    fn remove(&mut self, id: &ClientActorId) -> Option<Connection> {
+        let conn = self.by_id.remove(id)?;
+        if let Entry::Occupied(mut count) = self.connection_ids.entry(id.connection_id) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+        self.report();
+        self.removed.notify_waiters();
+        Some(conn)
+    }
+
+    /// Returns whether a connection has, or is about to have, the connection id of `key`.
+    fn is_used_by(&self, key: ConnectionIdKey) -> bool {
+        self.reserved.contains(&key)
+            || (self.connection_ids.contains_key(&key.connection_id)
+                && self.by_id.iter().any(|(id, conn)| {
+                    conn.database_identity == key.database_identity
+                        && id.identity == key.identity
+                        && id.connection_id == key.connection_id
+                }))
+    }
+
+    fn report(&self) {
+        WORKER_METRICS.ws_connections.set(self.by_id.len() as i64);
+        WORKER_METRICS.ws_connection_ids.set(self.connection_ids.len() as i64);
+    }
+}
+
+struct Connection {
+    sender: Arc<ClientConnectionSender>,
+    database_identity: Identity,
+    addr: Option<IpAddr>,
+    /// The module the client is connected to, if registered with [`ClientActorIndex::register_client`],
+    /// which it's disconnected from when it's deregistered.
+    module: Option<watch::Receiver<ModuleHost>>,
+}
+
+impl Connection {
+    /// Whether the connection's actor has died, and the connection been idle for longer than `grace`.
+    fn is_stale(&self, grace: Duration) -> bool {
+        self.sender.actor_finished() && self.sender.idle_time() > grace
+    }
+
+    /// Disconnect the client from its module, unless it already has been,
+    /// e.g. by its actor tearing it down.
+    fn disconnect(self) {
+        let Some(module) = self.module else { return };
+        if self.sender.is_disconnected() {
+            return;
+        }
+        let module = module.borrow().clone();
+        tokio::spawn(async move { self.sender.disconnect_from(&module).await });
+    }
+}
 
 #[derive(Default)]
 pub struct ClientActorIndex {
     client_name_auto_increment_state: AtomicU64,
+    limits: ConnectionLimits,
+    websocket_options: WebSocketOptions,
+    network_options: NetworkOptions,
+    connections: Connections,
+    /// How many connections each address has, counted by their [`AddressPermit`]s.
+    address_counts: AddressCounts,
+    /// How many admitted connections are yet to be registered, counted by their [`AdmissionPermit`]s.
+    reserved_slots: Arc<AtomicUsize>,
+    presence: Arc<PresenceIndex>,
+    reconnect_tokens: Arc<ReconnectTokens>,
+    unique_identities: Arc<UniqueIdentities>,
+    draining: Mutex<Option<Draining>>,
+    load_admission: Arc<LoadAdmission>,
+    client_series: Arc<ClientSeriesSampler>,
+    client_runtime: ClientRuntime,
 }
 
 impl ClientActorIndex {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn with_limits(limits: ConnectionLimits) -> Self {
+        let to_gauge = |limit: Option<usize>| limit.map_or(0, |l| l as i64);
+        WORKER_METRICS
+            .ws_connections_soft_limit
+            .set(to_gauge(limits.soft_limit));
+        WORKER_METRICS
+            .ws_connections_hard_limit
+            .set(to_gauge(limits.hard_limit));
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_websocket_options(self, websocket_options: WebSocketOptions) -> Self {
+        Self {
+            client_series: Arc::new(ClientSeriesSampler::new(websocket_options.client_series())),
+            websocket_options,
+            ..self
+        }
+    }
+
+    pub fn with_network_options(self, network_options: NetworkOptions) -> Self {
+        Self {
+            network_options,
+            ..self
+        }
+    }
+
+    pub fn with_load_admission(self, load_admission: LoadAdmission) -> Self {
+        Self {
+            load_admission: Arc::new(load_admission),
+            ..self
+        }
+    }
+
+    pub fn with_client_runtime(self, client_runtime: ClientRuntime) -> Self {
+        Self { client_runtime, ..self }
+    }
+
     pub fn next_client_name(&self) -> ClientName {
         ClientName(self.client_name_auto_increment_state.fetch_add(1, Relaxed))
     }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    pub fn websocket_options(&self) -> &WebSocketOptions {
+        &self.websocket_options
+    }
+
+    pub fn network_options(&self) -> &NetworkOptions {
+        &self.network_options
+    }
+
+    /// Decides whether the node takes on new subscriptions and connections, given its load.
+    pub fn load_admission(&self) -> &Arc<LoadAdmission> {
+        &self.load_admission
+    }
+
+    /// Which clients connected to this node get metric series of their own.
+    pub fn client_series(&self) -> &Arc<ClientSeriesSampler> {
+        &self.client_series
+    }
+
+    /// Where the tasks serving the clients of this node are spawned.
+    pub fn client_runtime(&self) -> &ClientRuntime {
+        &self.client_runtime
+    }
+
+    /// The presence of clients connected to this node.
+    pub fn presence(&self) -> &PresenceIndex {
+        &self.presence
+    }
+
+    /// The reconnect tokens issued to clients of this node.
+    pub fn reconnect_tokens(&self) -> &Arc<ReconnectTokens> {
+        &self.reconnect_tokens
+    }
+
+    /// The identities which connected to each database on this node, yet to be persisted.
+    pub fn unique_identities(&self) -> &UniqueIdentities {
+        &self.unique_identities
+    }
+
+    /// Returns the number of websocket clients currently connected to this node.
+    pub fn num_connections(&self) -> usize {
+        self.connections.lock().by_id.len()
+    }
+
+    /// Returns the clients connected to `database_identity` on this node.
+    pub fn clients(&self, database_identity: &Identity) -> Vec<Arc<ClientConnectionSender>> {
+        let connections = self.connections.lock();
+        connections
+            .by_id
+            .values()
+            .filter(|conn| conn.database_identity == *database_identity)
+            .map(|conn| conn.sender.clone())
+            .collect()
+    }
+
+    /// Returns up to `limit` of the clients connected to `database_identity` on this node,
+    /// ordered by connection id, starting after the client with connection id `after`, if given.
+    ///
+    /// Connection ids are unique among the clients connected to the node,
+    /// so paging through the clients by the connection id of the last client of each page
+    /// lists every client connected throughout exactly once, whoever connects or disconnects in between.
+    pub fn clients_page(
+        &self,
+        database_identity: &Identity,
+        after: Option<ConnectionId>,
+        limit: usize,
+    ) -> Vec<ListedClient> {
+        let mut page: Vec<_> = {
+            let connections = self.connections.lock();
+            connections
+                .by_id
+                .values()
+                .filter(|conn| conn.database_identity == *database_identity)
+                .filter(|conn| after.is_none_or(|after| conn.sender.id.connection_id > after))
+                .map(|conn| ListedClient {
+                    sender: conn.sender.clone(),
+                    addr: conn.addr,
+                })
+                .collect()
+        };
+        page.sort_unstable_by_key(|client| client.sender.id.connection_id);
+        page.truncate(limit);
+        page
+    }
+
+    /// Returns whether a client connected to this node has, or has [reserved](Self::reserve_connection_id), `connection_id`.
+    pub fn connection_id_in_use(&self, connection_id: ConnectionId) -> bool {
+        let connections = self.connections.lock();
+        connections.connection_ids.contains_key(&connection_id)
+            || connections
+                .reserved
+                .iter()
+                .any(|key| key.connection_id == connection_id)
+    }
+
+    /// Returns whether a client connected to `database_identity` as `identity` has `connection_id`,
+    /// or has [reserved](Self::reserve_connection_id) it.
+    ///
+    /// A module tells its clients apart by both identity and connection id,
+    /// so clients of different identities, or of different databases, may share a connection id,
+    /// as those of an SDK connecting several times from one process do.
+    pub fn connection_id_in_use_by(
+        &self,
+        database_identity: Identity,
+        identity: Identity,
+        connection_id: ConnectionId,
+    ) -> bool {
+        self.connections.lock().is_used_by(ConnectionIdKey {
+            database_identity,
+            identity,
+            connection_id,
+        })
+    }
+
+    /// Hold `connection_id` for a client about to connect to `database_identity` as `identity`
+    /// until the returned reservation is dropped, which should be once the client is [registered](Self::register),
+    /// or return `None` if another client already has it.
+    ///
+    /// Checking and reserving at once means that clients connecting concurrently can't both take the id.
+    pub fn reserve_connection_id(
+        &self,
+        database_identity: Identity,
+        identity: Identity,
+        connection_id: ConnectionId,
+    ) -> Option<ConnectionIdReservation> {
+        let key = ConnectionIdKey {
+            database_identity,
+            identity,
+            connection_id,
+        };
+        let mut connections = self.connections.lock();
+        if connections.is_used_by(key) {
+            return None;
+        }
+        connections.reserved.insert(key);
+        Some(ConnectionIdReservation {
+            key,
+            connections: self.connections.clone(),
+        })
+    }
+
+    /// Ask the clients connected to `database_identity` as `identity` with `connection_id` to close,
+    /// as a new connection of `identity` takes their place,
+    /// waiting up to `timeout` for them to deregister, and returning how many there were.
+    ///
+    /// Connections deregister once their actor has disconnected them from their module,
+    /// so the new connection doesn't race them over the client's row in `st_clients`.
+    /// Those of other identities, or connected to other databases, which share the connection id are left connected.
+    pub async fn replace_connection(
+        &self,
+        database_identity: Identity,
+        identity: Identity,
+        connection_id: ConnectionId,
+        timeout: Duration,
+    ) -> Result<usize, ConnectionReplaceError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let (replaced, removed) = {
+            let connections = self.connections.lock();
+            let replaced = connections
+                .by_id
+                .values()
+                .filter(|conn| {
+                    conn.database_identity == database_identity
+                        && conn.sender.id.identity == identity
+                        && conn.sender.id.connection_id == connection_id
+                })
+                .map(|conn| conn.sender.clone())
+                .collect::<Vec<_>>();
+            (replaced, connections.removed.clone())
+        };
+        for sender in &replaced {
+            log::info!("replacing client {} with a new connection", sender.id);
+            sender.request_close(CloseReason::Replaced);
+        }
+        WORKER_METRICS.ws_connections_replaced.inc_by(replaced.len() as u64);
+
+        loop {
+            // Listen before checking, so that a removal in between isn't missed.
+            let mut notified = pin!(removed.notified());
+            notified.as_mut().enable();
+            let registered = {
+                let connections = self.connections.lock();
+                replaced.iter().any(|sender| connections.by_id.contains_key(&sender.id))
+            };
+            if !registered {
+                return Ok(replaced.len());
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return Err(ConnectionReplaceError::TimedOut(timeout));
+            }
+        }
+    }
+
+    /// Returns a handle for sending messages to the client connected to this node
+    /// as `identity` with `connection_id`, if there is one.
+    pub fn connection_sender(&self, identity: Identity, connection_id: ConnectionId) -> Option<ConnectionSender> {
+        let connections = self.connections.lock();
+        if !connections.connection_ids.contains_key(&connection_id) {
+            return None;
+        }
+        connections
+            .by_id
+            .values()
+            .find(|conn| conn.sender.id.identity == identity && conn.sender.id.connection_id == connection_id)
+            .map(|conn| ConnectionSender::new(conn.sender.clone()))
+    }
+
+    /// Draw connection ids from `generate` until one is neither the reserved all-zeros id
+    /// nor in use by a client connected to this node.
+    ///
+    /// With random connection ids, drawing more than one is astronomically unlikely,
+    /// but a collision would have the module treat two clients as one.
+    ///
+    /// Note that the connection id isn't in use until the client [reserves](Self::reserve_connection_id) it,
+    /// so clients connecting concurrently could still draw the same id, though only one could reserve it.
+    pub fn generate_connection_id(&self, mut generate: impl FnMut() -> ConnectionId) -> ConnectionId {
+        loop {
+            let connection_id = generate();
+            if connection_id != ConnectionId::ZERO && !self.connection_id_in_use(connection_id) {
+                return connection_id;
+            }
+            log::warn!("generated connection id {connection_id} collides, generating another");
+            WORKER_METRICS.ws_connection_id_collisions.inc();
+        }
+    }
+
+    /// Decide whether a new connection may be accepted,
+    /// counting it as shed if not.
+    ///
+    /// This is checked before the websocket upgrade,
+    /// so that we reject new connections rather than disturbing existing ones.
+    ///
+    /// If accepted, the connection holds a slot until the returned permit is dropped,
+    /// which should be once the connection is registered, or given up on.
+    pub fn try_admit(&self) -> Result<AdmissionPermit, NodeOverloaded> {
+        let Some(limit) = self.limits.admission_limit() else {
+            return Ok(AdmissionPermit { reserved: None });
+        };
+        // Reserved under the lock of the connections, so that clients admitted at once can't overshoot the limit,
+        // and slots are only released once their connection is counted.
+        let connections = self.connections.lock();
+        if connections.by_id.len() + self.reserved_slots.load(Relaxed) < limit {
+            self.reserved_slots.fetch_add(1, Relaxed);
+            return Ok(AdmissionPermit {
+                reserved: Some(self.reserved_slots.clone()),
+            });
+        }
+        drop(connections);
+        WORKER_METRICS.ws_connections_shed.inc();
+        Err(NodeOverloaded {
+            limit,
+            retry_after: self.limits.retry_after,
+        })
+    }
+
+    /// Decide whether a new connection from `addr` may be accepted, given [`ConnectionLimits::per_ip_limit`],
+    /// counting it as shed if not.
+    ///
+    /// If accepted, the connection counts against the limit of its address until the returned permit is dropped,
+    /// which should be held for as long as the connection is served, from before its websocket upgrade.
+    pub fn admit_address(&self, addr: Option<IpAddr>) -> Result<AddressPermit, AddressOverloaded> {
+        let (Some(limit), Some(addr)) = (self.limits.per_ip_limit, addr) else {
+            return Ok(AddressPermit { counted: None });
+        };
+        let mut counts = self.address_counts.lock();
+        let count = counts.entry(addr).or_default();
+        if *count >= limit {
+            WORKER_METRICS.ws_connections_shed_per_ip.inc();
+            return Err(AddressOverloaded {
+                addr,
+                limit,
+                retry_after: self.limits.retry_after,
+            });
+        }
+        *count += 1;
+        Ok(AddressPermit {
+            counted: Some((addr, self.address_counts.clone())),
+        })
+    }
+
+    /// Track `client`, connected to `database_identity` from `addr`, as connected until the returned guard is dropped.
+    ///
+    /// If this puts the node above its hard limit and [`ConnectionLimits::evict_idlest`] is set,
+    /// the longest idle connections, other than `client`, are asked to close.
+    pub fn register(
+        &self,
+        client: Arc<ClientConnectionSender>,
+        database_identity: Identity,
+        addr: Option<IpAddr>,
+    ) -> ClientRegistration {
+        self.register_connection(client, database_identity, addr, None)
+    }
+
+    /// Like [`Self::register`], but also disconnect `client` from its module
+    /// when the returned guard is dropped, or the connection evicted as [stale](Self::evict_stale),
+    /// unless it has already been disconnected.
+    ///
+    /// Registering and arranging for the disconnect at once means that,
+    /// however the client's actor dies, the client can't be left registered or connected to the module.
+    pub fn register_client(&self, client: &ClientConnection, addr: Option<IpAddr>) -> ClientRegistration {
+        let database_identity = client.module.info().database_identity;
+        self.register_connection(client.sender(), database_identity, addr, Some(client.module_watcher()))
+    }
+
+    fn register_connection(
+        &self,
+        client: Arc<ClientConnectionSender>,
+        database_identity: Identity,
+        addr: Option<IpAddr>,
+        module: Option<watch::Receiver<ModuleHost>>,
+    ) -> ClientRegistration {
+        let id = client.id;
+        self.presence.connected(id.identity, database_identity);
+        self.unique_identities.record(database_identity, &id.identity);
+        let mut connections = self.connections.lock();
+        connections.insert(
+            id,
+            Connection {
+                sender: client,
+                database_identity,
+                addr,
+                module,
+            },
+        );
+
+        if let (true, Some(hard_limit)) = (self.limits.evict_idlest, self.limits.hard_limit) {
+            // Connections which were already asked to close are on their way out,
+            // so don't count them against the limit.
+            let mut candidates: Vec<_> = connections
+                .by_id
+                .values()
+                .map(|c| &c.sender)
+                .filter(|c| c.id != id && c.close_reason().is_none())
+                .collect();
+            let excess = (candidates.len() + 1).saturating_sub(hard_limit);
+            if excess > 0 {
+                candidates.sort_unstable_by_key(|c| std::cmp::Reverse(c.idle_time()));
+                for victim in &candidates[..excess.min(candidates.len())] {
+                    log::warn!(
+                        "evicting idle client {} as node is above its hard connection limit",
+                        victim.id
+                    );
+                    victim.request_close(CloseReason::Overloaded);
+                    WORKER_METRICS.ws_connections_evicted.inc();
+                }
+            }
+        }
+
+        // A client admitted just before the node began draining is sent away like the rest.
+        if let Some(Draining { retry_after }) = *self.draining.lock() {
+            connections.by_id[&id]
+                .sender
+                .request_close(CloseReason::Draining { retry_after });
+        }
+
+        ClientRegistration {
+            id,
+            database_identity,
+            connections: self.connections.clone(),
+            presence: self.presence.clone(),
+            reconnect_tokens: self.reconnect_tokens.clone(),
+        }
+    }
+
+    /// Ask every connection to `database_identity` whose address satisfies `denied` to close,
+    /// returning the number of connections asked.
+    ///
+    /// Connections with an unknown address are passed `None`.
+    pub fn close_denied(&self, database_identity: &Identity, denied: impl Fn(Option<IpAddr>) -> bool) -> usize {
+        let connections = self.connections.lock();
+        let mut closed = 0;
+        for conn in connections.by_id.values() {
+            if conn.database_identity == *database_identity && denied(conn.addr) {
+                log::info!("closing client {} as its address is now denied", conn.sender.id);
+                conn.sender.request_close(CloseReason::Denied);
+                self.reconnect_tokens
+                    .revoke_identity(&conn.sender.id.identity, database_identity);
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    /// Ask the client connected to `database_identity` on this node
+    /// as `identity` with `connection_id` to close, as it was kicked by an admin for `reason`,
+    /// returning whether there was such a client.
+    ///
+    /// The connection closes like any other, so its module sees it disconnect exactly once.
+    pub fn kick(
+        &self,
+        database_identity: &Identity,
+        identity: Identity,
+        connection_id: ConnectionId,
+        reason: Option<Arc<str>>,
+    ) -> bool {
+        let connections = self.connections.lock();
+        let Some(conn) = connections.by_id.values().find(|conn| {
+            conn.database_identity == *database_identity
+                && conn.sender.id.identity == identity
+                && conn.sender.id.connection_id == connection_id
+        }) else {
+            return false;
+        };
+        log::info!("closing client {} as it was kicked by an admin", conn.sender.id);
+        conn.sender.request_close(CloseReason::KickedByAdmin(reason));
+        // Revoked right away, rather than once the connection closes, lest the client reconnect before then.
+        self.reconnect_tokens.revoke_identity(&identity, database_identity);
+        true
+    }
+
+    /// Begin draining the node, e.g. before it's restarted:
+    /// ask every connection to close, telling its client to reconnect after `retry_after`, if given,
+    /// and have [`Self::draining`] report it, so that new connections are refused.
+    ///
+    /// Returns the number of connections asked to close.
+    /// As a connection is only ever asked to close once, draining an already draining node
+    /// changes the `retry_after` of just the connections refused from then on.
+    pub fn drain(&self, retry_after: Option<Duration>) -> usize {
+        *self.draining.lock() = Some(Draining { retry_after });
+        let connections = self.connections.lock();
+        for conn in connections.by_id.values() {
+            conn.sender.request_close(CloseReason::Draining { retry_after });
+        }
+        log::info!("draining node, asking {} client(s) to close", connections.by_id.len());
+        connections.by_id.len()
+    }
+
+    /// Whether the node is [draining](Self::drain), and if so, how.
+    pub fn draining(&self) -> Option<Draining> {
+        *self.draining.lock()
+    }
+
+    /// Evict the connections whose actor has finished without deregistering them,
+    /// and which have been idle for longer than [`WebSocketOptions::stale_connection_grace`],
+    /// disconnecting them from their modules, and returning the number evicted.
+    ///
+    /// Normally, the [`ClientRegistration`] of a connection deregisters it as its actor finishes,
+    /// but should the registration be leaked, e.g. by the actor dying abnormally,
+    /// its connection would count against the node's limits forever.
+    pub fn evict_stale(&self) -> usize {
+        evict_stale(
+            &self.connections,
+            &self.presence,
+            self.websocket_options.stale_connection_grace,
+        )
+    }
+
+    /// Spawn a task which calls [`Self::evict_stale`]
+    /// every [`WebSocketOptions::stale_connection_sweep_interval`], until it's aborted.
+    ///
+    /// The task also removes the expired metric series of clients, see [`Self::client_series`].
+    pub fn spawn_stale_sweeper(&self) -> AbortHandle {
+        let connections = self.connections.clone();
+        let presence = self.presence.clone();
+        let client_series = self.client_series.clone();
+        let WebSocketOptions {
+            stale_connection_sweep_interval: period,
+            stale_connection_grace: grace,
+            ..
+        } = self.websocket_options;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                evict_stale(&connections, &presence, grace);
+                client_series.sweep();
+            }
+        })
+        .abort_handle()
+    }
+
+    /// Spawn a task which hands the pending [`Self::unique_identities`] to `persist`
+    /// every [`uniques::FLUSH_INTERVAL`], until it's aborted.
+    ///
+    /// Sketches which `persist` fails to persist are kept pending, to be handed to it again.
+    pub fn spawn_uniques_flusher(
+        &self,
+        persist: impl Fn(Identity, Day, &IdentitySketch) -> anyhow::Result<()> + Send + 'static,
+    ) -> AbortHandle {
+        let unique_identities = self.unique_identities.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(uniques::FLUSH_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                for (database_identity, day, sketch) in unique_identities.take_pending() {
+                    if let Err(e) = persist(database_identity, day, &sketch) {
+                        log::warn!("failed to persist the unique identities of {database_identity}: {e:#}");
+                        unique_identities.restore(database_identity, day, &sketch);
+                    }
+                }
+            }
+        })
+        .abort_handle()
+    }
+}
+
+fn evict_stale(connections: &Connections, presence: &PresenceIndex, grace: Duration) -> usize {
+    let stale: Vec<_> = {
+        let mut connections = connections.lock();
+        let ids: Vec<_> = connections
+            .by_id
+            .iter()
+            .filter(|(_, conn)| conn.is_stale(grace))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.iter().filter_map(|id| connections.remove(id)).collect()
+    };
+    let evicted = stale.len();
+    for conn in stale {
+        let id = conn.sender.id;
+        log::warn!(
+            "evicting stale client {id} of database {}, as its actor died without deregistering it",
+            conn.database_identity
+        );
+        WORKER_METRICS.ws_connections_stale_evicted.inc();
+        presence.disconnected(id.identity, conn.database_identity);
+        conn.disconnect();
+    }
+    evicted
+}
+
+/// Holds a connection id for a client yet to register until dropped, see [`ClientActorIndex::reserve_connection_id`].
+#[must_use]
+pub struct ConnectionIdReservation {
+    key: ConnectionIdKey,
+    connections: Connections,
+}
+
+impl ConnectionIdReservation {
+    pub fn connection_id(&self) -> ConnectionId {
+        self.key.connection_id
+    }
+}
+
+impl Drop for ConnectionIdReservation {
+    fn drop(&mut self) {
+        self.connections.lock().reserved.remove(&self.key);
+    }
+}
+
+/// Deregisters a client from the [`ClientActorIndex`] when dropped.
+pub struct ClientRegistration {
+    id: ClientActorId,
+    database_identity: Identity,
+    connections: Connections,
+    presence: Arc<PresenceIndex>,
+    reconnect_tokens: Arc<ReconnectTokens>,
+}
+
+impl ClientRegistration {
+    /// Track `client` in place of the registered client,
+    /// which it has taken over the connection of by re-authenticating.
+    pub fn reauthenticated(&mut self, client: Arc<ClientConnectionSender>) {
+        let id = client.id;
+        let mut connections = self.connections.lock();
+        let Some(mut conn) = connections.remove(&self.id) else {
+            // The connection was evicted as stale, so there's nothing to track.
+            self.id = id;
+            return;
+        };
+        conn.sender = client;
+        connections.insert(id, conn);
+        drop(connections);
+        self.presence.disconnected(self.id.identity, self.database_identity);
+        self.presence.connected(id.identity, self.database_identity);
+        self.id = id;
+    }
+}
+
+impl Drop for ClientRegistration {
+    fn drop(&mut self) {
+        // Unless the connection was already evicted as stale, which did the rest.
+        let Some(conn) = self.connections.lock().remove(&self.id) else {
+            return;
+        };
+        self.presence.disconnected(self.id.identity, self.database_identity);
+        // However the client was made to leave, e.g. kicked by the module, it can't come back by reconnect token.
+        if conn
+            .sender
+            .close_reason()
+            .is_some_and(|reason| reason.revokes_reconnect())
+        {
+            self.reconnect_tokens
+                .revoke_identity(&self.id.identity, &self.database_identity);
+        }
+        conn.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientConfig, ReconnectGrant, ReconnectTokenError};
+
+    fn client(name: u64) -> Arc<ClientConnectionSender> {
+        let id = ClientActorId {
+            identity: Identity::ZERO,
+            connection_id: ConnectionId::from_u128(name as u128 + 1),
+            name: ClientName(name),
+        };
+        Arc::new(ClientConnectionSender::dummy(id, ClientConfig::for_test()))
+    }
+
+    fn limits(soft_limit: usize, hard_limit: usize, evict_idlest: bool) -> ConnectionLimits {
+        ConnectionLimits {
+            soft_limit: Some(soft_limit),
+            hard_limit: Some(hard_limit),
+            evict_idlest,
+            ..<_>::default()
+        }
+    }
+
+    #[test]
+    fn sheds_new_connections_at_soft_limit() {
+        let index = ClientActorIndex::with_limits(limits(2, 3, false));
+        let _a = index.register(client(0), Identity::ZERO, None);
+        drop(index.try_admit().unwrap());
+        let b = index.register(client(1), Identity::ZERO, None);
+
+        let err = index.try_admit().unwrap_err();
+        assert_eq!(err.limit, 2);
+
+        drop(b);
+        assert_eq!(index.num_connections(), 1);
+        drop(index.try_admit().unwrap());
+    }
+
+    #[test]
+    fn admitted_connections_hold_their_slot_until_registered() {
+        let index = ClientActorIndex::with_limits(limits(2, 3, false));
+        let first = index.try_admit().unwrap();
+        let _second = index.try_admit().unwrap();
+        // Neither is registered yet, but both are counted.
+        assert_eq!(index.try_admit().unwrap_err().limit, 2);
+
+        let _a = index.register(client(0), Identity::ZERO, None);
+        drop(first);
+        assert!(index.try_admit().is_err());
+    }
+
+    #[test]
+    fn sheds_new_connections_at_hard_limit_below_soft_limit() {
+        let index = ClientActorIndex::with_limits(limits(3, 1, false));
+        let _a = index.register(client(0), Identity::ZERO, None);
+        assert_eq!(index.try_admit().unwrap_err().limit, 1);
+    }
+
+    #[test]
+    fn sheds_connections_from_an_address_at_its_limit() {
+        let index = ClientActorIndex::with_limits(ConnectionLimits {
+            per_ip_limit: Some(2),
+            ..<_>::default()
+        });
+        let (a, b) = ("10.0.0.1".parse().ok(), "10.0.0.2".parse().ok());
+        let first = index.admit_address(a).unwrap();
+        let _second = index.admit_address(a).unwrap();
+        let err = index.admit_address(a).unwrap_err();
+        assert_eq!(err.limit, 2);
+        assert_eq!(Some(err.addr), a);
+
+        // Other addresses, and unknown ones, have limits of their own.
+        let _other = index.admit_address(b).unwrap();
+        for _ in 0..3 {
+            let _unknown = index.admit_address(None).unwrap();
+        }
+
+        drop(first);
+        let _third = index.admit_address(a).unwrap();
+        assert!(index.admit_address(a).is_err());
+    }
+
+    #[test]
+    fn evicts_idlest_above_hard_limit() {
+        let index = ClientActorIndex::with_limits(limits(1, 2, true));
+        let (a, b, c) = (client(0), client(1), client(2));
+        let _regs = [
+            index.register(a.clone(), Identity::ZERO, None),
+            index.register(b.clone(), Identity::ZERO, None),
+        ];
+        // `a` is the idlest, as `b` has just sent us something.
+        std::thread::sleep(Duration::from_millis(5));
+        b.record_activity();
+
+        let _c = index.register(c.clone(), Identity::ZERO, None);
+        assert_eq!(a.close_reason(), Some(CloseReason::Overloaded));
+        assert_eq!(b.close_reason(), None);
+        assert_eq!(c.close_reason(), None);
+    }
+
+    #[test]
+    fn does_not_evict_without_evict_idlest() {
+        let index = ClientActorIndex::with_limits(limits(1, 1, false));
+        let (a, b) = (client(0), client(1));
+        let _regs = [
+            index.register(a.clone(), Identity::ZERO, None),
+            index.register(b.clone(), Identity::ZERO, None),
+        ];
+        assert_eq!(a.close_reason(), None);
+        assert_eq!(b.close_reason(), None);
+    }
+
+    #[test]
+    fn closes_connections_from_denied_addresses() {
+        let index = ClientActorIndex::new();
+        let (a, b, c, d) = (client(0), client(1), client(2), client(3));
+        let denied: IpAddr = "2001:db8::1".parse().unwrap();
+        let allowed: IpAddr = "10.0.0.1".parse().unwrap();
+        let _regs = [
+            index.register(a.clone(), Identity::ZERO, Some(denied)),
+            index.register(b.clone(), Identity::ZERO, Some(allowed)),
+            index.register(c.clone(), Identity::ONE, Some(denied)),
+            index.register(d.clone(), Identity::ZERO, None),
+        ];
+
+        let closed = index.close_denied(&Identity::ZERO, |addr| addr.is_none_or(|addr| addr == denied));
+        assert_eq!(closed, 2);
+        assert_eq!(a.close_reason(), Some(CloseReason::Denied));
+        assert_eq!(b.close_reason(), None);
+        assert_eq!(c.close_reason(), None);
+        assert_eq!(d.close_reason(), Some(CloseReason::Denied));
+    }
+
+    #[test]
+    fn kicks_only_the_client_of_the_database_with_the_identity_and_connection_id() {
+        let index = ClientActorIndex::new();
+        let (a, b) = (client(0), client(1));
+        let _regs = [
+            index.register(a.clone(), Identity::ZERO, None),
+            index.register(b.clone(), Identity::ONE, None),
+        ];
+
+        assert!(!index.kick(&Identity::ZERO, Identity::ONE, a.id.connection_id, None));
+        assert!(!index.kick(&Identity::ZERO, Identity::ZERO, b.id.connection_id, None));
+        assert_eq!(a.close_reason(), None);
+
+        assert!(index.kick(&Identity::ZERO, Identity::ZERO, a.id.connection_id, Some("spam".into())));
+        assert_eq!(a.close_reason(), Some(CloseReason::KickedByAdmin(Some("spam".into()))));
+        assert_eq!(b.close_reason(), None);
+    }
+
+    #[test]
+    fn kicking_a_client_revokes_its_reconnect_tokens() {
+        let index = ClientActorIndex::new();
+        let a = client(0);
+        let _reg = index.register(a.clone(), Identity::ZERO, None);
+        let grant = ReconnectGrant {
+            identity: a.id.identity,
+            subject: "subject".into(),
+            issuer: "issuer".into(),
+            token: "credentials".into(),
+            expires_at: None,
+            database_identity: Identity::ZERO,
+        };
+        let token = index.reconnect_tokens().issue(grant, Duration::from_secs(60));
+
+        assert!(index.kick(&Identity::ZERO, a.id.identity, a.id.connection_id, None));
+        assert_eq!(
+            index.reconnect_tokens().redeem(&token, &Identity::ZERO),
+            Err(ReconnectTokenError::Spent)
+        );
+    }
+
+    #[test]
+    fn draining_closes_every_connection_including_late_ones() {
+        let index = ClientActorIndex::new();
+        let (a, b, c) = (client(0), client(1), client(2));
+        let _regs = [
+            index.register(a.clone(), Identity::ZERO, None),
+            index.register(b.clone(), Identity::ONE, None),
+        ];
+        assert_eq!(index.draining(), None);
+
+        let retry_after = Some(Duration::from_secs(30));
+        assert_eq!(index.drain(retry_after), 2);
+        assert_eq!(index.draining(), Some(Draining { retry_after }));
+        let draining = Some(CloseReason::Draining { retry_after });
+        assert_eq!(a.close_reason(), draining);
+        assert_eq!(b.close_reason(), draining);
+
+        // A client admitted before the node began draining is closed as it registers.
+        let _c = index.register(c.clone(), Identity::ZERO, None);
+        assert_eq!(c.close_reason(), draining);
+    }
+
+    #[tokio::test]
+    async fn replacing_a_connection_waits_for_it_to_deregister() {
+        let index = ClientActorIndex::new();
+        let a = client(0);
+        let connection_id = a.id.connection_id;
+        let reg = index.register(a.clone(), Identity::ZERO, None);
+
+        // Only the client itself can replace its connection, and only to the same database.
+        let other = index.replace_connection(Identity::ZERO, Identity::ONE, connection_id, Duration::from_secs(1));
+        assert_eq!(other.await, Ok(0));
+        let other_db = index.replace_connection(Identity::ONE, Identity::ZERO, connection_id, Duration::from_secs(1));
+        assert_eq!(other_db.await, Ok(0));
+        assert_eq!(a.close_reason(), None);
+
+        // The connection deregisters once it's been asked to close, as its actor would.
+        let actor = tokio::spawn({
+            let a = a.clone();
+            async move {
+                a.close_requested().await;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                drop(reg);
+            }
+        });
+        let replaced = index.replace_connection(Identity::ZERO, Identity::ZERO, connection_id, Duration::from_secs(5));
+        assert_eq!(replaced.await, Ok(1));
+        assert_eq!(a.close_reason(), Some(CloseReason::Replaced));
+        assert!(!index.connection_id_in_use(connection_id));
+        actor.await.unwrap();
+
+        // A connection which never goes away can't be replaced.
+        let b = client(1);
+        let _reg = index.register(b.clone(), Identity::ZERO, None);
+        let stuck = index.replace_connection(
+            Identity::ZERO,
+            Identity::ZERO,
+            b.id.connection_id,
+            Duration::from_millis(20),
+        );
+        assert_eq!(
+            stuck.await,
+            Err(ConnectionReplaceError::TimedOut(Duration::from_millis(20)))
+        );
+        assert_eq!(b.close_reason(), Some(CloseReason::Replaced));
+    }
+
+    #[test]
+    fn trusted_proxies_are_parsed_with_the_config() {
+        let options: NetworkOptions = toml::from_str(r#"trusted-proxies = ["10.1.2.3/8", " 192.168.1.1 "]"#).unwrap();
+        let proxies = options
+            .trusted_proxies
+            .iter()
+            .map(|net| net.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(proxies, ["10.0.0.0/8", "192.168.1.1/32"]);
+
+        // A range which doesn't parse is refused, rather than ignored as the node starts.
+        let err = toml::from_str::<NetworkOptions>(r#"trusted-proxies = ["10.0.0.0/33"]"#).unwrap_err();
+        assert!(err.to_string().contains("Invalid address range: 10.0.0.0/33"), "{err}");
+    }
+
+    #[test]
+    fn databases_can_only_lower_message_size_limits() {
+        let node = WebSocketOptions {
+            max_message_size: 1024,
+            max_frame_size: None,
+            ..<_>::default()
+        };
+        let options = node.with_message_size_limits(&MessageSizeLimits::default());
+        assert_eq!((options.max_message_size, options.max_frame_size), (1024, None));
+
+        let lower = MessageSizeLimits {
+            max_message_size: Some(512),
+            max_frame_size: Some(256),
+        };
+        let options = node.with_message_size_limits(&lower);
+        assert_eq!((options.max_message_size, options.max_frame_size), (512, Some(256)));
+
+        let higher = MessageSizeLimits {
+            max_message_size: Some(4096),
+            max_frame_size: Some(4096),
+        };
+        let node = WebSocketOptions {
+            max_frame_size: Some(2048),
+            ..node
+        };
+        let options = node.with_message_size_limits(&higher);
+        assert_eq!((options.max_message_size, options.max_frame_size), (1024, Some(2048)));
+    }
+
+    #[test]
+    fn lists_the_clients_of_a_database() {
+        let index = ClientActorIndex::new();
+        let (a, b, c) = (client(0), client(1), client(2));
+        let regs = [
+            index.register(a.clone(), Identity::ZERO, None),
+            index.register(b.clone(), Identity::ONE, None),
+            index.register(c.clone(), Identity::ZERO, None),
+        ];
+        let mut listed: Vec<_> = index.clients(&Identity::ZERO).iter().map(|c| c.id).collect();
+        listed.sort_by_key(|id| id.name.0);
+        assert_eq!(listed, [a.id, c.id]);
+        drop(regs);
+        assert!(index.clients(&Identity::ZERO).is_empty());
+    }
+
+    #[test]
+    fn pages_through_the_clients_of_a_database_by_connection_id() {
+        let index = ClientActorIndex::new();
+        let clients: Vec<_> = (0..5).map(client).collect();
+        let addr = Some(IpAddr::from([10, 0, 0, 1]));
+        let mut regs: Vec<_> = clients
+            .iter()
+            .rev()
+            .map(|c| index.register(c.clone(), Identity::ZERO, addr))
+            .collect();
+        let _other = index.register(client(5), Identity::ONE, None);
+        let page_ids = |page: &[ListedClient]| page.iter().map(|c| c.sender.id).collect::<Vec<_>>();
+
+        let first = index.clients_page(&Identity::ZERO, None, 2);
+        assert_eq!(page_ids(&first), [clients[0].id, clients[1].id]);
+        assert_eq!(first[0].addr, addr);
+
+        // A client of the page before disconnecting doesn't shift the next page.
+        drop(regs.pop());
+        let after = first.last().map(|c| c.sender.id.connection_id);
+        let second = index.clients_page(&Identity::ZERO, after, 2);
+        assert_eq!(page_ids(&second), [clients[2].id, clients[3].id]);
+
+        let after = second.last().map(|c| c.sender.id.connection_id);
+        let last = index.clients_page(&Identity::ZERO, after, 2);
+        assert_eq!(page_ids(&last), [clients[4].id]);
+    }
+
+    #[test]
+    fn reauthenticated_registration_tracks_the_new_client() {
+        let index = ClientActorIndex::new();
+        let (a, b) = (client(0), client(1));
+        let mut reg = index.register(a.clone(), Identity::ZERO, None);
+        reg.reauthenticated(b.clone());
+        assert_eq!(index.num_connections(), 1);
+
+        // Close requests reach the client which took over.
+        assert_eq!(index.close_denied(&Identity::ZERO, |_| true), 1);
+        assert_eq!(a.close_reason(), None);
+        assert_eq!(b.close_reason(), Some(CloseReason::Denied));
+
+        drop(reg);
+        assert_eq!(index.num_connections(), 0);
+        assert!(!index.connection_id_in_use(b.id.connection_id));
+    }
+
+    #[test]
+    fn generated_connection_ids_skip_those_in_use() {
+        let index = ClientActorIndex::new();
+        let a = client(0);
+        let reg = index.register(a.clone(), Identity::ZERO, None);
+        assert!(index.connection_id_in_use(a.id.connection_id));
+        assert!(index.connection_id_in_use_by(Identity::ZERO, a.id.identity, a.id.connection_id));
+        assert!(!index.connection_id_in_use_by(Identity::ZERO, Identity::ONE, a.id.connection_id));
+
+        let mut draws = vec![ConnectionId::from_u128(7), a.id.connection_id, ConnectionId::ZERO];
+        let generated = index.generate_connection_id(|| draws.pop().unwrap());
+        assert_eq!(generated, ConnectionId::from_u128(7));
+        assert!(draws.is_empty());
+
+        drop(reg);
+        assert_eq!(index.generate_connection_id(|| a.id.connection_id), a.id.connection_id);
+    }
+
+    #[test]
+    fn connection_senders_are_found_while_registered() {
+        let index = ClientActorIndex::new();
+        let a = client(0);
+        let reg = index.register(a.clone(), Identity::ZERO, None);
+
+        let sender = index.connection_sender(a.id.identity, a.id.connection_id).unwrap();
+        assert_eq!(sender.id(), a.id);
+        assert!(index.connection_sender(Identity::ONE, a.id.connection_id).is_none());
+        assert!(index
+            .connection_sender(a.id.identity, ConnectionId::from_u128(7))
+            .is_none());
+
+        drop(reg);
+        assert!(index.connection_sender(a.id.identity, a.id.connection_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn evicts_connections_whose_actor_died_without_deregistering() {
+        let index = ClientActorIndex::new().with_websocket_options(WebSocketOptions {
+            stale_connection_grace: Duration::from_millis(5),
+            ..<_>::default()
+        });
+        let (a, b, c) = (client(0), client(1), client(2));
+        // The actors of dummy clients finish right away.
+        while !(a.actor_finished() && b.actor_finished() && c.actor_finished()) {
+            tokio::task::yield_now().await;
+        }
+        std::mem::forget(index.register(a.clone(), Identity::ZERO, None));
+        let reg_b = index.register(b.clone(), Identity::ZERO, None);
+        let reg_c = index.register(c.clone(), Identity::ZERO, None);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // `c` is still within its grace period.
+        c.record_activity();
+
+        assert_eq!(index.evict_stale(), 2);
+        assert_eq!(index.num_connections(), 1);
+        assert!(!index.connection_id_in_use(a.id.connection_id));
+        assert!(!index.connection_id_in_use(b.id.connection_id));
+
+        // Dropping the registration of an evicted connection leaves the others be.
+        drop(reg_b);
+        assert!(index.connection_id_in_use(c.id.connection_id));
+        drop(reg_c);
+        assert_eq!(index.num_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn aborting_an_actor_before_it_runs_deregisters_its_client() {
+        let index = ClientActorIndex::new();
+        // As in `ClientConnection::spawn`, the actor's task is spawned before its future is made.
+        let (fut_tx, fut_rx) = tokio::sync::oneshot::channel::<futures::future::BoxFuture<'static, ()>>();
+        let task = tokio::spawn(async move {
+            if let Ok(fut) = fut_rx.await {
+                fut.await
+            }
+        });
+        let registration = index.register(client(0), Identity::ZERO, None);
+        let actor = async move {
+            let _registration = registration;
+            std::future::pending::<()>().await
+        };
+        let _ = fut_tx.send(Box::pin(actor));
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert_eq!(index.num_connections(), 0);
+    }
+
+    #[test]
+    fn connection_ids_are_reserved_per_database_and_identity() {
+        let index = ClientActorIndex::new();
+        let a = client(0);
+        let (db, other_db) = (Identity::ZERO, Identity::ONE);
+        let (identity, connection_id) = (a.id.identity, a.id.connection_id);
+
+        // Only one of the clients connecting concurrently with a connection id can take it.
+        let reservation = index.reserve_connection_id(db, identity, connection_id).unwrap();
+        assert!(index.reserve_connection_id(db, identity, connection_id).is_none());
+        assert!(index.connection_id_in_use(connection_id));
+
+        // It stays taken as the client registers and gives up its reservation.
+        let reg = index.register(a.clone(), db, None);
+        drop(reservation);
+        assert!(index.reserve_connection_id(db, identity, connection_id).is_none());
+
+        // A client of the same identity may use the same connection id with another database,
+        // as may one of another identity with the same database.
+        let on_other_db = index.reserve_connection_id(other_db, identity, connection_id).unwrap();
+        let b = ClientActorId {
+            name: ClientName(1),
+            ..a.id
+        };
+        let b = Arc::new(ClientConnectionSender::dummy(b, ClientConfig::for_test()));
+        let _other_db_reg = index.register(b, other_db, None);
+        drop(on_other_db);
+        assert!(index.connection_id_in_use_by(other_db, identity, connection_id));
+        assert!(index.reserve_connection_id(db, Identity::ONE, connection_id).is_some());
+
+        drop(reg);
+        assert!(!index.connection_id_in_use_by(db, identity, connection_id));
+        assert!(index.reserve_connection_id(db, identity, connection_id).is_some());
+    }
 }
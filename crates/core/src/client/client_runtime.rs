@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::io;
+
+use tokio::runtime::{Handle, Runtime};
+use tokio::task::JoinHandle;
+
+/// Where the tasks serving websocket clients are spawned,
+/// i.e. the upgrade of their connections and their actors.
+///
+/// By default, they're spawned on the runtime of the caller, as by [`tokio::spawn`].
+/// An embedder may rather provide a dedicated runtime,
+/// so that many connections, even idle ones, don't compete with module execution for the same workers.
+///
+/// Either way, the tasks talk to their module over the same channels,
+/// which work across runtimes.
+#[derive(Clone, Debug, Default)]
+pub struct ClientRuntime {
+    handle: Option<Handle>,
+}
+
+impl ClientRuntime {
+    /// Spawn on the runtime of the caller.
+    pub fn current() -> Self {
+        Self::default()
+    }
+
+    /// Spawn on the runtime of `handle`.
+    pub fn dedicated(handle: Handle) -> Self {
+        Self { handle: Some(handle) }
+    }
+
+    /// Build a multi-threaded runtime of `worker_threads` to spawn on, which lives as long as the process.
+    pub fn with_worker_threads(worker_threads: usize) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .thread_name("client-runtime")
+            .enable_all()
+            .build()?;
+        // A runtime can't be dropped from within another, as the node's would be,
+        // so it's never dropped at all.
+        let runtime: &'static Runtime = Box::leak(Box::new(runtime));
+        Ok(Self::dedicated(runtime.handle().clone()))
+    }
+
+    /// Whether tasks are spawned on a runtime of their own, rather than the caller's.
+    pub fn is_dedicated(&self) -> bool {
+        self.handle.is_some()
+    }
+
+    /// Spawn `future` on this runtime.
+    ///
+    /// Panics if this is the caller's runtime but there is none, as [`tokio::spawn`] does.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.handle {
+            Some(handle) => handle.spawn(future),
+            None => tokio::spawn(future),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tasks_are_spawned_on_the_dedicated_runtime() {
+        let runtime = ClientRuntime::with_worker_threads(1).unwrap();
+        assert!(runtime.is_dedicated());
+        let thread = runtime
+            .spawn(async { std::thread::current().name().map(str::to_owned) })
+            .await
+            .unwrap();
+        assert_eq!(thread.as_deref(), Some("client-runtime"));
+
+        let current = ClientRuntime::current();
+        assert!(!current.is_dedicated());
+        let thread = current.spawn(async { std::thread::current().name().map(str::to_owned) });
+        assert_ne!(thread.await.unwrap().as_deref(), Some("client-runtime"));
+    }
+}
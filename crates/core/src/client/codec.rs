@@ -0,0 +1,1211 @@
+use super::messages::{InUseSerializeBuffer, SerializeBuffer, SwitchedServerMessage, ToProtocol};
+use super::{DataMessage, MessageHandleError, Protocol};
+use crate::db::relational_db::RelationalDB;
+use crate::execution_context::Workload;
+use crate::host::ReducerArgs;
+use crate::messages::websocket::{self as ws, ClientMessage};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use bytes::{BufMut, Bytes};
+use bytestring::ByteString;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use spacetimedb_client_api_messages::e2e;
+use spacetimedb_client_api_messages::websocket::{
+    Compression, FormatSwitch, ProtocolVersion, TextEnvelope, BIN_PROTOCOL, TEXT_PROTOCOL,
+};
+use spacetimedb_lib::de::serde::DeserializeWrapper;
+use spacetimedb_lib::resolved_type_via_v9;
+use spacetimedb_lib::ser::serde::SerializeWrapper;
+use spacetimedb_sats::{bsatn, AlgebraicType, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+/// The wire encoding of a websocket connection.
+///
+/// A codec is selected once per connection, based on its [`ClientConfig`](super::ClientConfig),
+/// and is used by the websocket actor to encode every [`ws::ServerMessage`] it sends
+/// and to decode every [`ClientMessage`] it receives.
+pub trait ProtocolCodec: fmt::Debug + Send + Sync {
+    /// The [`Protocol`] whose format rows and reducer arguments are encoded in.
+    fn protocol(&self) -> Protocol;
+
+    /// Encode `msg` into a [`DataMessage`], writing it into `buffer`.
+    ///
+    /// `compression` is the client's desired (conditional) compression algorithm.
+    ///
+    /// If `msg` can't be encoded, `buffer` is handed back, cleared, for the next message.
+    fn encode(&self, buffer: SerializeBuffer, msg: SwitchedServerMessage, compression: Compression) -> EncodeResult;
+
+    /// Decode a message received from the client.
+    ///
+    /// By default, the message is decoded according to whether it is a text or binary frame,
+    /// regardless of the protocol negotiated for the connection.
+    fn decode(&self, message: DataMessage) -> Result<DecodedMessage, MessageHandleError> {
+        match message {
+            DataMessage::Text(text) => decode_text(text),
+            DataMessage::Binary(message_buf) => decode_binary(message_buf),
+        }
+    }
+
+    /// Rotate the keys with which messages are encrypted, if they are.
+    ///
+    /// Called on every liveness check of the connection.
+    fn rotate_keys(&self) {}
+
+    /// Whether this codec encodes messages just as that of any other connection with the same [`ClientConfig`],
+    /// so that the connection may share the encodings of its updates with theirs,
+    /// see [`SharedEncoding`](super::messages::SharedEncoding).
+    ///
+    /// Codecs which encrypt don't, as each connection has keys of its own.
+    fn shares_encodings(&self) -> bool {
+        true
+    }
+
+    /// This codec, but compressing messages only beyond `compression_threshold` bytes,
+    /// see [`BinaryCodec::compression_threshold`].
+    ///
+    /// Messages encrypted by the returned codec are under the same keys as by this one.
+    fn with_compression_threshold(&self, compression_threshold: usize) -> Arc<dyn ProtocolCodec>;
+}
+
+impl dyn ProtocolCodec {
+    /// Convert `msg` to this codec's [`Protocol`] and [`encode`](ProtocolCodec::encode) it.
+    pub fn serialize(
+        &self,
+        buffer: SerializeBuffer,
+        msg: impl ToProtocol<Encoded = SwitchedServerMessage>,
+        compression: Compression,
+    ) -> EncodeResult {
+        self.encode(buffer, msg.to_protocol(self.protocol()), compression)
+    }
+
+    /// [`Serialize`](Self::serialize) `msg` on the blocking pool, rather than on the calling task,
+    /// so that encoding and compressing a large message doesn't stall the task, nor the other tasks of its worker.
+    ///
+    /// The buffer moves to the blocking pool and back along with the message, so it can be reclaimed as ever.
+    pub async fn serialize_blocking(
+        self: Arc<Self>,
+        buffer: SerializeBuffer,
+        msg: impl ToProtocol<Encoded = SwitchedServerMessage> + Send + 'static,
+        compression: Compression,
+    ) -> EncodeResult {
+        let encode = tokio::task::spawn_blocking(move || self.serialize(buffer, msg, compression));
+        match encode.await {
+            Ok(encoded) => encoded,
+            // Encoding panicked, which it would have done inline as well.
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+/// The outcome of [`ProtocolCodec::encode`].
+pub type EncodeResult = Result<(InUseSerializeBuffer, DataMessage), EncodeFailure>;
+
+/// Why a message couldn't be encoded.
+#[derive(thiserror::Error, Debug)]
+pub enum EncodeError {
+    #[error("failed to encode message as JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("failed to encode message as BSATN: {0}")]
+    Bsatn(#[from] bsatn::EncodeError),
+}
+
+/// A message which couldn't be encoded, along with the buffer it was to be written into.
+pub struct EncodeFailure {
+    /// The buffer, cleared, to reuse for the next message.
+    pub buffer: SerializeBuffer,
+    pub error: EncodeError,
+}
+
+/// What a connection does with a message which can't be encoded for its protocol.
+///
+/// Configured per database by the `close_on_encode_error` system variable,
+/// which is read as each connection opens.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncodeErrorPolicy {
+    /// Drop the message, telling the client with a `MessageDropped`.
+    #[default]
+    Skip,
+    /// Close the connection.
+    Close,
+}
+
+impl EncodeErrorPolicy {
+    /// The policy of the database `db`.
+    pub fn of_database(db: &RelationalDB) -> Self {
+        match db.with_read_only(Workload::Internal, |tx| db.close_on_encode_error(tx)) {
+            Ok(true) => Self::Close,
+            Ok(false) => Self::Skip,
+            Err(e) => {
+                log::error!(
+                    "failed to read `close_on_encode_error` of {}: {e}",
+                    db.database_identity()
+                );
+                Self::default()
+            }
+        }
+    }
+}
+
+/// How the reasons of the close frames sent to a client, and the errors reported to it, are formatted.
+///
+/// Configured per database by the `legacy_errors` system variable,
+/// which is read as each connection opens, and announced to the client as it connects.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// The plain-text close reasons and error strings of older versions,
+    /// which existing SDKs match on.
+    #[default]
+    Legacy,
+    /// Machine-readable close reasons and errors, e.g. JSON close payloads.
+    Structured,
+}
+
+impl ErrorFormat {
+    /// The format of the database `db`.
+    pub fn of_database(db: &RelationalDB) -> Self {
+        match db.with_read_only(Workload::Internal, |tx| db.legacy_errors(tx)) {
+            Ok(true) => Self::Legacy,
+            Ok(false) => Self::Structured,
+            Err(e) => {
+                log::error!("failed to read `legacy_errors` of {}: {e}", db.database_identity());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Legacy => "legacy",
+            Self::Structured => "structured",
+        }
+    }
+}
+
+/// Encode `msg` as JSON, framed according to `version`.
+fn encode_json<T: Serialize + ?Sized>(version: ProtocolVersion, mut buffer: SerializeBuffer, msg: &T) -> EncodeResult {
+    let out = (&mut buffer.uncompressed).writer();
+    let msg = SerializeWrapper::from_ref(msg);
+    let res = match version {
+        ProtocolVersion::V1 => serde_json::to_writer(out, msg),
+        ProtocolVersion::V2 => serde_json::to_writer(
+            out,
+            &TextEnvelope {
+                compression: Compression::None,
+                message: msg,
+            },
+        ),
+    };
+    if let Err(e) = res {
+        return Err(buffer.failed(e.into()));
+    }
+
+    let (in_use, out) = buffer.uncompressed();
+    // SAFETY: `serde_json::to_writer` states that:
+    // > "Serialization guarantees it only feeds valid UTF-8 sequences to the writer."
+    let msg_json = unsafe { ByteString::from_bytes_unchecked(out) };
+    Ok((in_use, msg_json.into()))
+}
+
+/// Encode `msg` as BSATN, conditionally compressed according to `compression` and the settings of `codec`,
+/// and prefixed with the tag of the compression actually applied.
+fn encode_bsatn<T: Serialize + ?Sized>(
+    codec: &BinaryCodec,
+    mut buffer: SerializeBuffer,
+    msg: &T,
+    compression: Compression,
+) -> EncodeResult {
+    // First write the tag so that we avoid shifting the entire message at the end.
+    let srv_msg = match buffer.write_with_tag(Compression::None.tag(), |w| bsatn::to_writer(w.into_inner(), msg)) {
+        Ok(srv_msg) => srv_msg,
+        Err(e) => return Err(buffer.failed(e.into())),
+    };
+
+    // Conditionally compress the message, depending on its serialized length.
+    let (in_use, msg_bytes) =
+        match ws::decide_compression_with_threshold(srv_msg.len(), compression, codec.compression_threshold) {
+            Compression::None => buffer.uncompressed(),
+            compression @ Compression::Brotli => buffer.compress_with_tag(compression.tag(), ws::brotli_compress),
+            compression @ Compression::Gzip => buffer.compress_with_tag(compression.tag(), ws::gzip_compress),
+            compression @ Compression::Zstd => buffer.compress_with_tag(compression.tag(), |bytes, out| {
+                ws::zstd_compress_with_level(bytes, out, codec.zstd_level)
+            }),
+        };
+    Ok((in_use, msg_bytes.into()))
+}
+
+/// The codec for [`Protocol::Text`], encoding messages as JSON,
+/// framed according to the negotiated [`ProtocolVersion`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextCodec(pub ProtocolVersion);
+
+impl ProtocolCodec for TextCodec {
+    fn protocol(&self) -> Protocol {
+        Protocol::Text
+    }
+
+    fn encode(&self, buffer: SerializeBuffer, msg: SwitchedServerMessage, compression: Compression) -> EncodeResult {
+        match msg {
+            FormatSwitch::Json(msg) => encode_json(self.0, buffer, &msg),
+            msg @ FormatSwitch::Bsatn(_) => BinaryCodec::default().encode(buffer, msg, compression),
+        }
+    }
+
+    fn with_compression_threshold(&self, _: usize) -> Arc<dyn ProtocolCodec> {
+        // Text messages are never compressed.
+        Arc::new(*self)
+    }
+}
+
+/// The codec for [`Protocol::Binary`], encoding messages as BSATN,
+/// conditionally compressed according to the client's preference,
+/// and prefixed with the tag of the [`Compression`] actually applied.
+///
+/// The framing is the same in every [`ProtocolVersion`],
+/// which only differ in the compressions clients may ask for.
+#[derive(Debug, Clone, Copy)]
+pub struct BinaryCodec {
+    /// The level at which messages are compressed, should the client ask for [`Compression::Zstd`].
+    pub zstd_level: i32,
+    /// The length, in bytes, beyond which messages are compressed, should the client ask for compression.
+    /// Shorter messages are sent uncompressed, and tagged as such.
+    pub compression_threshold: usize,
+}
+
+impl Default for BinaryCodec {
+    fn default() -> Self {
+        Self {
+            zstd_level: ws::DEFAULT_ZSTD_LEVEL,
+            compression_threshold: ws::DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl ProtocolCodec for BinaryCodec {
+    fn protocol(&self) -> Protocol {
+        Protocol::Binary
+    }
+
+    fn encode(&self, buffer: SerializeBuffer, msg: SwitchedServerMessage, compression: Compression) -> EncodeResult {
+        match msg {
+            FormatSwitch::Bsatn(msg) => encode_bsatn(self, buffer, &msg, compression),
+            msg @ FormatSwitch::Json(_) => TextCodec::default().encode(buffer, msg, compression),
+        }
+    }
+
+    fn with_compression_threshold(&self, compression_threshold: usize) -> Arc<dyn ProtocolCodec> {
+        Arc::new(Self {
+            compression_threshold,
+            ..*self
+        })
+    }
+}
+
+/// Wraps the codec of a connection which negotiated end-to-end encryption,
+/// sealing every message after `inner` encodes, and so compresses, it,
+/// and opening every message before `inner` decodes it.
+///
+/// See [`e2e`] for the negotiation and framing.
+pub struct EncryptedCodec {
+    inner: Arc<dyn ProtocolCodec>,
+    /// Shared with the codecs derived from this one by [`ProtocolCodec::with_compression_threshold`].
+    session: Arc<Mutex<e2e::Session>>,
+}
+
+impl EncryptedCodec {
+    pub fn new(inner: Arc<dyn ProtocolCodec>, session: e2e::Session) -> Self {
+        Self {
+            inner,
+            session: Arc::new(Mutex::new(session)),
+        }
+    }
+}
+
+impl fmt::Debug for EncryptedCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedCodec")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProtocolCodec for EncryptedCodec {
+    fn protocol(&self) -> Protocol {
+        self.inner.protocol()
+    }
+
+    fn encode(&self, buffer: SerializeBuffer, msg: SwitchedServerMessage, compression: Compression) -> EncodeResult {
+        let (in_use, msg) = self.inner.encode(buffer, msg, compression)?;
+        let mut session = self.session.lock();
+        let msg = match msg {
+            DataMessage::Text(text) => {
+                let envelope = session.sealer.seal_to_vec(text.as_bytes());
+                ByteString::from(BASE64_STANDARD.encode(envelope)).into()
+            }
+            DataMessage::Binary(bytes) => Bytes::from(session.sealer.seal_to_vec(&bytes)).into(),
+        };
+        Ok((in_use, msg))
+    }
+
+    fn decode(&self, message: DataMessage) -> Result<DecodedMessage, MessageHandleError> {
+        let message = match message {
+            DataMessage::Text(text) => {
+                let envelope = BASE64_STANDARD.decode(text.as_bytes())?;
+                let plaintext = self.session.lock().opener.open(&envelope)?;
+                let text = String::from_utf8(plaintext).map_err(|_| e2e::E2eError::Decrypt)?;
+                DataMessage::Text(text.into())
+            }
+            DataMessage::Binary(bytes) => DataMessage::Binary(self.session.lock().opener.open(&bytes)?.into()),
+        };
+        self.inner.decode(message)
+    }
+
+    fn rotate_keys(&self) {
+        self.session.lock().sealer.rotate();
+    }
+
+    fn shares_encodings(&self) -> bool {
+        false
+    }
+
+    fn with_compression_threshold(&self, compression_threshold: usize) -> Arc<dyn ProtocolCodec> {
+        Arc::new(Self {
+            inner: self.inner.with_compression_threshold(compression_threshold),
+            session: self.session.clone(),
+        })
+    }
+}
+
+/// A [`ClientMessage`] decoded by a [`ProtocolCodec`].
+pub struct DecodedMessage {
+    pub message: ClientMessage<ReducerArgs>,
+    /// The paths of fields in the message which this server doesn't know about, and so ignored,
+    /// e.g. `CallReducer.some_new_field`.
+    ///
+    /// Always empty for BSATN-encoded messages, which are not self-describing.
+    pub unknown_fields: Vec<String>,
+}
+
+impl From<ClientMessage<ReducerArgs>> for DecodedMessage {
+    fn from(message: ClientMessage<ReducerArgs>) -> Self {
+        Self {
+            message,
+            unknown_fields: Vec::new(),
+        }
+    }
+}
+
+/// The resolved type of [`ClientMessage`], used to find unknown fields in JSON messages.
+static CLIENT_MESSAGE_TYPE: Lazy<AlgebraicType> = Lazy::new(resolved_type_via_v9::<ClientMessage<Box<str>>>);
+
+/// Decode a JSON-encoded [`ClientMessage`].
+///
+/// Unknown fields are ignored and reported in [`DecodedMessage::unknown_fields`],
+/// and absent optional fields, e.g. those added since the client was written, are `None`,
+/// while an unknown message type is a [`MessageHandleError::UnsupportedMessage`].
+fn decode_text(text: ByteString) -> Result<DecodedMessage, MessageHandleError> {
+    // TODO(breaking): this should ideally be &serde_json::RawValue, not json-nested-in-string
+    // Decoding is strict, so this succeeds for any message without unknown fields.
+    if let Ok(DeserializeWrapper(message)) = serde_json::from_str::<DeserializeWrapper<ClientMessage<Cow<str>>>>(&text)
+    {
+        return Ok(message
+            .map_args(|s| {
+                ReducerArgs::Json(match s {
+                    Cow::Borrowed(s) => text.slice_ref(s),
+                    Cow::Owned(string) => string.into(),
+                })
+            })
+            .into());
+    }
+
+    // Otherwise, strip any unknown fields, fill in absent optional ones, and try again.
+    let mut value = serde_json::from_str::<serde_json::Value>(&text)?;
+    let variants = &CLIENT_MESSAGE_TYPE
+        .as_sum()
+        .expect("`ClientMessage` is a sum type")
+        .variants;
+    if let Some((message_type, _)) = value
+        .as_object()
+        .filter(|msg| msg.len() == 1)
+        .and_then(|msg| msg.iter().next())
+    {
+        if !variants.iter().any(|var| var.has_name(message_type)) {
+            return Err(MessageHandleError::UnsupportedMessage {
+                message_type: message_type.clone(),
+                protocol: TEXT_PROTOCOL,
+            });
+        }
+    }
+    let mut unknown_fields = Vec::new();
+    strip_unknown_fields(
+        &mut value,
+        &CLIENT_MESSAGE_TYPE,
+        &mut String::new(),
+        &mut unknown_fields,
+    );
+    let DeserializeWrapper(message) = serde_json::from_value::<DeserializeWrapper<ClientMessage<String>>>(value)?;
+    Ok(DecodedMessage {
+        message: message.map_args(|s| ReducerArgs::Json(s.into())),
+        unknown_fields,
+    })
+}
+
+/// Remove the fields of the JSON `value` which aren't in `ty`, pushing their paths to `unknown`,
+/// and add the optional fields of `ty` which are absent from `value`, as `null`, i.e. `None`.
+///
+/// `path` is the path to `value` within the message.
+/// Parts of `value` which don't match the shape of `ty` are left for deserialization to reject.
+fn strip_unknown_fields(
+    value: &mut serde_json::Value,
+    ty: &AlgebraicType,
+    path: &mut String,
+    unknown: &mut Vec<String>,
+) {
+    use serde_json::Value;
+    match (ty, value) {
+        (AlgebraicType::Product(ty), Value::Object(fields)) => {
+            fields.retain(|name, _| {
+                let known = ty.elements.iter().any(|elem| elem.has_name(name));
+                if !known {
+                    unknown.push(join_path(path, name));
+                }
+                known
+            });
+            for (name, value) in &mut *fields {
+                let elem = ty.elements.iter().find(|elem| elem.has_name(name)).unwrap();
+                strip_unknown_fields_at(name, value, &elem.algebraic_type, path, unknown);
+            }
+            for elem in ty
+                .elements
+                .iter()
+                .filter(|elem| elem.algebraic_type.as_option().is_some())
+            {
+                if let Some(name) = elem.name() {
+                    fields.entry(name).or_insert(Value::Null);
+                }
+            }
+        }
+        (AlgebraicType::Product(ty), Value::Array(elems)) => {
+            for (i, (value, elem)) in elems.iter_mut().zip(&*ty.elements).enumerate() {
+                strip_unknown_fields_at(i, value, &elem.algebraic_type, path, unknown);
+            }
+        }
+        (AlgebraicType::Sum(ty), Value::Object(variant)) => {
+            // Anything alongside the one known variant, e.g. the `none` of an option, is unknown.
+            let is_variant = |name: &str| ty.variants.iter().any(|var| var.has_name(name));
+            if variant.len() > 1 && variant.keys().filter(|name| is_variant(name)).count() == 1 {
+                variant.retain(|name, _| {
+                    let known = is_variant(name);
+                    if !known {
+                        unknown.push(join_path(path, name));
+                    }
+                    known
+                });
+            }
+            if variant.len() == 1 {
+                let (name, value) = variant.iter_mut().next().unwrap();
+                if let Some(var) = ty.variants.iter().find(|var| var.has_name(name)) {
+                    strip_unknown_fields_at(name, value, &var.algebraic_type, path, unknown);
+                }
+            }
+        }
+        (AlgebraicType::Array(ty), Value::Array(elems)) => {
+            for (i, value) in elems.iter_mut().enumerate() {
+                strip_unknown_fields_at(i, value, &ty.elem_ty, path, unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// [`strip_unknown_fields`] of `value`, found at `name` within `path`.
+fn strip_unknown_fields_at(
+    name: impl fmt::Display,
+    value: &mut serde_json::Value,
+    ty: &AlgebraicType,
+    path: &mut String,
+    unknown: &mut Vec<String>,
+) {
+    let len = path.len();
+    *path = join_path(path, name);
+    strip_unknown_fields(value, ty, path, unknown);
+    path.truncate(len);
+}
+
+fn join_path(path: &str, name: impl fmt::Display) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{path}.{name}")
+    }
+}
+
+/// Decode a BSATN-encoded [`ClientMessage`].
+fn decode_binary(message_buf: Bytes) -> Result<DecodedMessage, MessageHandleError> {
+    match bsatn::from_slice::<ClientMessage<&[u8]>>(&message_buf) {
+        Ok(message) => Ok(message
+            .map_args(|b| ReducerArgs::Bsatn(message_buf.slice_ref(b)))
+            .into()),
+        Err(e) => {
+            // The first byte is the tag of the message type.
+            let num_types = CLIENT_MESSAGE_TYPE.as_sum().map_or(0, |ty| ty.variants.len());
+            match message_buf.first() {
+                Some(&tag) if usize::from(tag) >= num_types => Err(MessageHandleError::UnsupportedMessage {
+                    message_type: format!("{tag:#x}"),
+                    protocol: BIN_PROTOCOL,
+                }),
+                _ => Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::messages::{IdentityTokenMessage, OneOffQueryResponseMessage, SharedEncoding};
+    use crate::client::{ClientConfig, ProtocolViolation};
+    use crate::messages::websocket::{
+        Ack, Authenticate, CallReducer, CallReducerFlags, GetConnectionStatus, GrantCredits, OlderRows, OneOffQuery,
+        Query, QueryId, RefreshToken, Subscribe, SubscribeMulti, SubscribeSingle, SubscribeWindow, Unsubscribe,
+        UnsubscribeMulti, UpdateSettings, SERVER_MSG_COMPRESSION_TAG_BROTLI, SERVER_MSG_COMPRESSION_TAG_GZIP,
+        SERVER_MSG_COMPRESSION_TAG_NONE, SERVER_MSG_COMPRESSION_TAG_ZSTD,
+    };
+    use serde_json::Value;
+    use spacetimedb_client_api_messages::websocket::{BsatnFormat, JsonFormat, WebsocketFormat};
+    use spacetimedb_lib::{ConnectionId, Identity, TimeDuration};
+    use spacetimedb_sats::ser::{SerializeArray, SerializeNamedProduct, Serializer};
+
+    fn samples() -> Vec<ClientMessage<Box<str>>> {
+        let query_id = QueryId::new(7);
+        vec![
+            ClientMessage::CallReducer(CallReducer {
+                reducer: "add".into(),
+                args: "[1, 2]".into(),
+                request_id: 1,
+                flags: CallReducerFlags::FullUpdate,
+                traceparent: None,
+            }),
+            ClientMessage::Subscribe(Subscribe {
+                query_strings: ["SELECT * FROM t".into()].into(),
+                request_id: 2,
+            }),
+            ClientMessage::OneOffQuery(OneOffQuery {
+                message_id: [1, 2, 3].into(),
+                query_string: "SELECT * FROM t".into(),
+            }),
+            ClientMessage::SubscribeSingle(SubscribeSingle {
+                query: "SELECT * FROM t".into(),
+                request_id: 3,
+                query_id,
+            }),
+            ClientMessage::SubscribeMulti(SubscribeMulti {
+                query_strings: ["SELECT * FROM t".into()].into(),
+                request_id: 4,
+                query_id,
+            }),
+            ClientMessage::Unsubscribe(Unsubscribe {
+                request_id: 5,
+                query_id,
+            }),
+            ClientMessage::UnsubscribeMulti(UnsubscribeMulti {
+                request_id: 6,
+                query_id,
+            }),
+            ClientMessage::ExplainSubscribeMulti(SubscribeMulti {
+                query_strings: ["SELECT * FROM t".into()].into(),
+                request_id: 7,
+                query_id,
+            }),
+            ClientMessage::Authenticate(Authenticate { token: "token".into() }),
+            ClientMessage::Ack(Ack { delivery_id: 8 }),
+            ClientMessage::GetConnectionStatus(GetConnectionStatus { request_id: 9 }),
+            ClientMessage::SubscribeWindow(SubscribeWindow {
+                query: "SELECT * FROM t".into(),
+                request_id: 10,
+                query_id,
+                initial: "last(50, id)".parse().unwrap(),
+                older_rows: OlderRows::Suppress,
+            }),
+            ClientMessage::UpdateSettings(UpdateSettings {
+                request_id: 11,
+                compression: Some(Compression::Gzip),
+                tx_update_full: Some(false),
+                compression_threshold: None,
+            }),
+            ClientMessage::RefreshToken(RefreshToken {
+                request_id: 12,
+                token: "token".into(),
+            }),
+            ClientMessage::Query(Query {
+                request_id: 13,
+                query_string: "SELECT * FROM t".into(),
+            }),
+            ClientMessage::GrantCredits(GrantCredits { credits: 14 }),
+        ]
+    }
+
+    fn decode_json(json: &Value) -> Result<DecodedMessage, MessageHandleError> {
+        TextCodec::default().decode(DataMessage::Text(json.to_string().into()))
+    }
+
+    fn to_bsatn(message: ClientMessage<ReducerArgs>) -> Vec<u8> {
+        let message = message.map_args(|args| match args {
+            ReducerArgs::Json(json) => json.to_string(),
+            _ => unreachable!("text messages have JSON args"),
+        });
+        bsatn::to_vec(&message).unwrap()
+    }
+
+    /// Collect the paths of every object nested in `value`, but not `value` itself.
+    fn nested_object_paths(value: &Value, path: &mut Vec<String>, paths: &mut Vec<Vec<String>>) {
+        let children: Vec<(String, &Value)> = match value {
+            Value::Object(fields) => {
+                if !path.is_empty() {
+                    paths.push(path.clone());
+                }
+                fields.iter().map(|(name, value)| (name.clone(), value)).collect()
+            }
+            Value::Array(elems) => elems.iter().enumerate().map(|(i, v)| (i.to_string(), v)).collect(),
+            _ => return,
+        };
+        for (name, child) in children {
+            path.push(name);
+            nested_object_paths(child, path, paths);
+            path.pop();
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored_at_every_level() {
+        for message in samples() {
+            let json = serde_json::to_value(SerializeWrapper::new(&message)).unwrap();
+            let expected = bsatn::to_vec(&message).unwrap();
+
+            let decoded = decode_json(&json).unwrap();
+            assert!(decoded.unknown_fields.is_empty());
+            assert_eq!(to_bsatn(decoded.message), expected);
+
+            let mut paths = Vec::new();
+            nested_object_paths(&json, &mut Vec::new(), &mut paths);
+            assert!(!paths.is_empty(), "{json}");
+            for path in paths {
+                let mut json = json.clone();
+                let object = json.pointer_mut(&format!("/{}", path.join("/"))).unwrap();
+                object
+                    .as_object_mut()
+                    .unwrap()
+                    .insert("from_the_future".into(), Value::Bool(true));
+
+                let decoded = decode_json(&json).unwrap_or_else(|e| panic!("{json}: {e}"));
+                assert_eq!(decoded.unknown_fields, [format!("{}.from_the_future", path.join("."))]);
+                assert_eq!(to_bsatn(decoded.message), expected);
+            }
+        }
+    }
+
+    fn message_dropped(kind: &str) -> ws::MessageDropped {
+        ws::MessageDropped {
+            reason: ws::MessageDropReason::EncodeError,
+            message_kind: kind.into(),
+        }
+    }
+
+    fn encode(
+        codec: &dyn ProtocolCodec,
+        version: ProtocolVersion,
+        compression: Compression,
+        kind: &str,
+    ) -> DataMessage {
+        let config = ClientConfig {
+            protocol: codec.protocol(),
+            version,
+            compression,
+            ..ClientConfig::for_test()
+        };
+        encode_into(codec, SerializeBuffer::new(config), compression, kind).1
+    }
+
+    fn encode_into(
+        codec: &dyn ProtocolCodec,
+        buffer: SerializeBuffer,
+        compression: Compression,
+        kind: &str,
+    ) -> (InUseSerializeBuffer, DataMessage) {
+        codec
+            .encode(buffer, message_dropped(kind).to_protocol(codec.protocol()), compression)
+            .unwrap_or_else(|failure| panic!("{}", failure.error))
+    }
+
+    /// The BSATN of `ServerMessage::MessageDropped(message_dropped("Kind"))`.
+    const MESSAGE_DROPPED_BSATN: &[u8] = &[
+        16, // `ServerMessage::MessageDropped`
+        0,  // `MessageDropReason::EncodeError`
+        4, 0, 0, 0, b'K', b'i', b'n', b'd', // message_kind
+    ];
+
+    #[test]
+    fn binary_framing_golden_vectors() {
+        for version in [ProtocolVersion::V1, ProtocolVersion::V2] {
+            // Small messages are never compressed.
+            let DataMessage::Binary(msg) = encode(&BinaryCodec::default(), version, Compression::Brotli, "Kind") else {
+                panic!("expected a binary message");
+            };
+            assert_eq!(msg[0], SERVER_MSG_COMPRESSION_TAG_NONE);
+            assert_eq!(&msg[1..], MESSAGE_DROPPED_BSATN);
+        }
+
+        // Large messages are tagged with the compression actually applied.
+        let kind = "Kind".repeat(1000);
+        let bsatn = bsatn::to_vec(&ws::ServerMessage::<ws::BsatnFormat>::MessageDropped(message_dropped(
+            &kind,
+        )))
+        .unwrap();
+        let cases = [
+            (
+                ProtocolVersion::V1,
+                Compression::Brotli,
+                ws::brotli_decompress as fn(&[u8]) -> _,
+                SERVER_MSG_COMPRESSION_TAG_BROTLI,
+            ),
+            (
+                ProtocolVersion::V1,
+                Compression::Gzip,
+                ws::gzip_decompress,
+                SERVER_MSG_COMPRESSION_TAG_GZIP,
+            ),
+            (
+                ProtocolVersion::V2,
+                Compression::Gzip,
+                ws::gzip_decompress,
+                SERVER_MSG_COMPRESSION_TAG_GZIP,
+            ),
+            (
+                ProtocolVersion::V2,
+                Compression::Zstd,
+                ws::zstd_decompress,
+                SERVER_MSG_COMPRESSION_TAG_ZSTD,
+            ),
+        ];
+        for (version, compression, decompress, tag) in cases {
+            let DataMessage::Binary(msg) = encode(&BinaryCodec::default(), version, compression, &kind) else {
+                panic!("expected a binary message");
+            };
+            assert_eq!(msg[0], tag, "{version:?} {compression:?}");
+            assert_eq!(Compression::from_tag(msg[0]), Some(compression));
+            assert_eq!(decompress(&msg[1..]).unwrap(), bsatn);
+        }
+    }
+
+    #[test]
+    fn zstd_compresses_at_the_configured_level() {
+        let kind = "Kind".repeat(1000);
+        let bsatn = bsatn::to_vec(&ws::ServerMessage::<ws::BsatnFormat>::MessageDropped(message_dropped(
+            &kind,
+        )))
+        .unwrap();
+        let encoded_len = |zstd_level| {
+            let config = ClientConfig {
+                zstd_level,
+                ..config(Protocol::Binary, ProtocolVersion::V2, Compression::Zstd)
+            };
+            let DataMessage::Binary(msg) = encode(&*config.codec(), config.version, config.compression, &kind) else {
+                panic!("expected a binary message");
+            };
+            assert_eq!(msg[0], SERVER_MSG_COMPRESSION_TAG_ZSTD);
+            assert_eq!(ws::zstd_decompress(&msg[1..]).unwrap(), bsatn, "level {zstd_level}");
+            msg.len()
+        };
+        assert!(encoded_len(19) <= encoded_len(ws::DEFAULT_ZSTD_LEVEL));
+    }
+
+    #[test]
+    fn small_messages_go_out_uncompressed_below_the_threshold() {
+        let token = || IdentityTokenMessage {
+            identity: Identity::ZERO,
+            token: "x".repeat(47).into(),
+            connection_id: ConnectionId::ZERO,
+        };
+        let bsatn = bsatn::to_vec(&ws::ServerMessage::<ws::BsatnFormat>::IdentityToken(token())).unwrap();
+        assert_eq!(bsatn.len(), 100);
+
+        let encode_token = |compression_threshold| {
+            let config = ClientConfig {
+                compression_threshold,
+                ..config(Protocol::Binary, ProtocolVersion::V1, Compression::Brotli)
+            };
+            let buffer = SerializeBuffer::new(config);
+            let (_, msg) = config
+                .codec()
+                .serialize(buffer, token(), config.compression)
+                .unwrap_or_else(|failure| panic!("{}", failure.error));
+            let DataMessage::Binary(msg) = msg else {
+                panic!("expected a binary message");
+            };
+            msg
+        };
+
+        // Even though the client asked for brotli, the default threshold leaves the token uncompressed.
+        let msg = encode_token(ws::DEFAULT_COMPRESSION_THRESHOLD);
+        assert_eq!(msg[0], SERVER_MSG_COMPRESSION_TAG_NONE);
+        assert_eq!(&msg[1..], bsatn);
+
+        // The threshold is compared to the serialized length.
+        assert_eq!(encode_token(100)[0], SERVER_MSG_COMPRESSION_TAG_NONE);
+        let msg = encode_token(99);
+        assert_eq!(msg[0], SERVER_MSG_COMPRESSION_TAG_BROTLI);
+        assert_eq!(ws::brotli_decompress(&msg[1..]).unwrap(), bsatn);
+    }
+
+    #[tokio::test]
+    async fn serializing_on_the_blocking_pool_matches_serializing_inline() {
+        let token = || IdentityTokenMessage {
+            identity: Identity::ZERO,
+            token: "x".repeat(4096).into(),
+            connection_id: ConnectionId::ZERO,
+        };
+        let config = config(Protocol::Binary, ProtocolVersion::V2, Compression::Zstd);
+        let codec = config.codec();
+        let (_, inline) = codec
+            .serialize(SerializeBuffer::new(config), token(), config.compression)
+            .unwrap_or_else(|failure| panic!("{}", failure.error));
+        let (msg_alloc, offloaded) = codec
+            .clone()
+            .serialize_blocking(SerializeBuffer::new(config), token(), config.compression)
+            .await
+            .unwrap_or_else(|failure| panic!("{}", failure.error));
+        let (DataMessage::Binary(inline), DataMessage::Binary(offloaded)) = (inline, offloaded) else {
+            panic!("expected binary messages");
+        };
+        assert_eq!(offloaded, inline);
+
+        // The buffer comes back from the blocking pool, to be reclaimed once the message is sent.
+        drop(offloaded);
+        assert!(msg_alloc.try_reclaim().is_some());
+    }
+
+    #[test]
+    fn a_shared_encoding_keeps_its_buffer_from_being_reclaimed() {
+        let token = IdentityTokenMessage {
+            identity: Identity::ZERO,
+            token: "x".repeat(4096).into(),
+            connection_id: ConnectionId::ZERO,
+        };
+        let config = config(Protocol::Binary, ProtocolVersion::V2, Compression::Zstd);
+        let (msg_alloc, msg) = config
+            .codec()
+            .serialize(SerializeBuffer::new(config), token, config.compression)
+            .unwrap_or_else(|failure| panic!("{}", failure.error));
+        let shared = SharedEncoding::default();
+        shared.set(&msg);
+        drop(msg);
+        assert!(msg_alloc.try_reclaim().is_none());
+
+        // The clients reusing the encoding have nothing to reclaim but the buffer they didn't use.
+        let unused = InUseSerializeBuffer::Unused(SerializeBuffer::new(config));
+        assert!(shared.get().is_some());
+        assert!(unused.try_reclaim().is_some());
+    }
+
+    #[test]
+    fn text_framing_golden_vectors() {
+        let message = r#"{"MessageDropped":{"reason":{"EncodeError":[]},"message_kind":"Kind"}}"#;
+        for (version, expected) in [
+            (ProtocolVersion::V1, message.to_owned()),
+            (
+                ProtocolVersion::V2,
+                format!(r#"{{"compression":"None","message":{message}}}"#),
+            ),
+        ] {
+            let codec = TextCodec(version);
+            let DataMessage::Text(msg) = encode(&codec, version, Compression::Brotli, "Kind") else {
+                panic!("expected a text message");
+            };
+            assert_eq!(&*msg, expected);
+        }
+    }
+
+    /// A value which fails to encode in either protocol, without writing much first:
+    /// JSON can't key a product element without a name,
+    /// and BSATN can't prefix an array longer than `u32::MAX` with its length.
+    struct Unencodable;
+
+    impl Serialize for Unencodable {
+        fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+            let mut prod = ser.serialize_named_product(1)?;
+            prod.serialize_element(None, &TooLong)?;
+            prod.end()
+        }
+    }
+
+    struct TooLong;
+
+    impl Serialize for TooLong {
+        fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+            ser.serialize_array(u32::MAX as usize + 1)?.end()
+        }
+    }
+
+    fn config(protocol: Protocol, version: ProtocolVersion, compression: Compression) -> ClientConfig {
+        ClientConfig {
+            protocol,
+            version,
+            compression,
+            ..ClientConfig::for_test()
+        }
+    }
+
+    /// Check that `failure` handed back a cleared `buffer`
+    /// which still encodes messages as `codec` should.
+    fn assert_reclaimed(codec: &dyn ProtocolCodec, failure: EncodeFailure, expected: DataMessage) {
+        let EncodeFailure { buffer, .. } = failure;
+        assert!(buffer.uncompressed.is_empty());
+        assert!(buffer.uncompressed.capacity() > 0);
+        let (in_use, msg) = encode_into(codec, buffer, Compression::None, "Kind");
+        match (msg, expected) {
+            (DataMessage::Text(msg), DataMessage::Text(expected)) => assert_eq!(msg, expected),
+            (DataMessage::Binary(msg), DataMessage::Binary(expected)) => assert_eq!(msg, expected),
+            (msg, expected) => panic!("expected {expected:?}, got {msg:?}"),
+        }
+        assert!(in_use.try_reclaim().is_some());
+    }
+
+    #[test]
+    fn json_encode_errors_hand_back_the_buffer() {
+        for version in [ProtocolVersion::V1, ProtocolVersion::V2] {
+            let buffer = SerializeBuffer::new(config(Protocol::Text, version, Compression::None));
+            let failure = encode_json(version, buffer, &Unencodable).err().unwrap();
+            assert!(matches!(failure.error, EncodeError::Json(_)), "{}", failure.error);
+            assert!(failure.error.to_string().contains("no name"), "{}", failure.error);
+
+            let codec = TextCodec(version);
+            let expected = encode(&codec, version, Compression::None, "Kind");
+            assert_reclaimed(&codec, failure, expected);
+        }
+    }
+
+    #[test]
+    fn bsatn_encode_errors_hand_back_the_buffer() {
+        for compression in [
+            Compression::None,
+            Compression::Brotli,
+            Compression::Gzip,
+            Compression::Zstd,
+        ] {
+            let buffer = SerializeBuffer::new(config(Protocol::Binary, ProtocolVersion::V2, compression));
+            let failure = encode_bsatn(&BinaryCodec::default(), buffer, &Unencodable, compression)
+                .err()
+                .unwrap();
+            assert!(matches!(failure.error, EncodeError::Bsatn(_)), "{}", failure.error);
+            assert!(failure.error.to_string().contains("len too long"), "{}", failure.error);
+
+            let expected = encode(&BinaryCodec::default(), ProtocolVersion::V2, Compression::None, "Kind");
+            assert_reclaimed(&BinaryCodec::default(), failure, expected);
+        }
+    }
+
+    #[test]
+    fn non_finite_floats_encode_as_json_null() {
+        let buffer = SerializeBuffer::new(config(Protocol::Text, ProtocolVersion::V1, Compression::None));
+        let (_, msg) = encode_json(ProtocolVersion::V1, buffer, &[f64::NAN, f64::INFINITY][..])
+            .unwrap_or_else(|failure| panic!("{}", failure.error));
+        let DataMessage::Text(msg) = msg else {
+            panic!("expected a text message");
+        };
+        assert_eq!(&*msg, "[null,null]");
+    }
+
+    #[test]
+    fn absent_optional_fields_are_none() {
+        let json = serde_json::json!({
+            "CallReducer": { "reducer": "add", "args": "[1, 2]", "request_id": 1, "flags": 0 }
+        });
+        let decoded = decode_json(&json).unwrap();
+        assert!(decoded.unknown_fields.is_empty());
+        assert_eq!(to_bsatn(decoded.message), bsatn::to_vec(&samples()[0]).unwrap());
+    }
+
+    #[test]
+    fn invalid_messages_are_still_rejected() {
+        let json = serde_json::json!({ "Unsubscribe": { "request_id": 5, "extra": 1 } });
+        assert!(matches!(decode_json(&json), Err(MessageHandleError::TextDecode(_))));
+    }
+
+    #[test]
+    fn unknown_message_types_are_unsupported() {
+        let json = serde_json::json!({ "FromTheFuture": { "request_id": 5 } });
+        let err = decode_json(&json).err().unwrap();
+        assert!(
+            matches!(&err, MessageHandleError::UnsupportedMessage { message_type, protocol }
+                if message_type == "FromTheFuture" && *protocol == TEXT_PROTOCOL),
+            "{err}"
+        );
+
+        let err = BinaryCodec::default()
+            .decode(DataMessage::Binary(Bytes::from_static(&[0xff])))
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&err, MessageHandleError::UnsupportedMessage { protocol, .. } if *protocol == BIN_PROTOCOL),
+            "{err}"
+        );
+    }
+
+    /// A server codec wrapping `inner` in end-to-end encryption, and the client's end of its session.
+    fn encrypted(inner: Arc<dyn ProtocolCodec>) -> (EncryptedCodec, e2e::Session) {
+        let (client, server) = (e2e::KeyPair::generate(), e2e::KeyPair::generate());
+        let client_public = client.public_key();
+        let client = client.agree(e2e::Role::Client, &server.public_key()).unwrap();
+        let server = server.agree(e2e::Role::Server, &client_public).unwrap();
+        (EncryptedCodec::new(inner, server), client)
+    }
+
+    #[test]
+    fn encrypted_text_round_trips() {
+        let version = ProtocolVersion::V2;
+        let (codec, mut client) = encrypted(Arc::new(TextCodec(version)));
+
+        let DataMessage::Text(plain) = encode(&TextCodec(version), version, Compression::None, "Kind") else {
+            panic!("expected a text message");
+        };
+        for _ in 0..2 {
+            let DataMessage::Text(msg) = encode(&codec, version, Compression::None, "Kind") else {
+                panic!("expected a text message");
+            };
+            let envelope = BASE64_STANDARD.decode(msg.as_bytes()).unwrap();
+            assert_eq!(client.opener.open(&envelope).unwrap(), plain.as_bytes()[..]);
+            codec.rotate_keys();
+        }
+        assert_eq!(client.opener.epoch(), 1);
+
+        for message in samples() {
+            let json = serde_json::to_value(SerializeWrapper::new(&message)).unwrap();
+            let envelope = client.sealer.seal_to_vec(json.to_string().as_bytes());
+            let decoded = codec
+                .decode(DataMessage::Text(BASE64_STANDARD.encode(envelope).into()))
+                .unwrap();
+            assert_eq!(to_bsatn(decoded.message), bsatn::to_vec(&message).unwrap());
+        }
+        // Unencrypted messages are rejected.
+        let json = r#"{"Unsubscribe":{"request_id":5,"query_id":{"id":7}}}"#;
+        let err = codec.decode(DataMessage::Text(json.into())).err().unwrap();
+        assert_eq!(err.violation(), Some(ProtocolViolation::Decode), "{err}");
+    }
+
+    #[test]
+    fn encrypted_binary_round_trips() {
+        let (codec, mut client) = encrypted(Arc::new(BinaryCodec::default()));
+
+        // Messages are compressed before being encrypted, so their compression tag is encrypted with them.
+        let kind = "Kind".repeat(1000);
+        let bsatn = bsatn::to_vec(&ws::ServerMessage::<ws::BsatnFormat>::MessageDropped(message_dropped(
+            &kind,
+        )))
+        .unwrap();
+        for _ in 0..2 {
+            let DataMessage::Binary(msg) = encode(&codec, ProtocolVersion::V2, Compression::Zstd, &kind) else {
+                panic!("expected a binary message");
+            };
+            assert!(msg.len() < bsatn.len());
+            let plaintext = client.opener.open(&msg).unwrap();
+            assert_eq!(plaintext[0], SERVER_MSG_COMPRESSION_TAG_ZSTD);
+            assert_eq!(ws::zstd_decompress(&plaintext[1..]).unwrap(), bsatn);
+            codec.rotate_keys();
+        }
+
+        let message = ClientMessage::<Box<str>>::Unsubscribe(Unsubscribe {
+            request_id: 5,
+            query_id: QueryId::new(7),
+        });
+        let expected = bsatn::to_vec(&message).unwrap();
+        let envelope = client.sealer.seal_to_vec(&expected);
+        let decoded = codec.decode(DataMessage::Binary(envelope.clone().into())).unwrap();
+        assert_eq!(to_bsatn(decoded.message), expected);
+
+        // Replayed messages are rejected.
+        let err = codec.decode(DataMessage::Binary(envelope.into())).err().unwrap();
+        assert!(
+            matches!(err, MessageHandleError::Decrypt(e2e::E2eError::Replayed)),
+            "{err}"
+        );
+    }
+
+    /// The encodings of a few messages by the encoder these codecs replaced,
+    /// which connections that negotiate neither V2 nor encryption must keep receiving byte for byte.
+    const BASELINE_ENCODINGS: &[(&str, &str)] = &[
+        (
+            "identity_token_text",
+            "7b224964656e74697479546f6b656e223a7b226964656e74697479223a7b225f5f6964656e746974795f5f223a22307831227d2c22746f6b656e223a22746f6b656e222c22636f6e6e656374696f6e5f6964223a7b225f5f636f6e6e656374696f6e5f69645f5f223a377d7d7d",
+        ),
+        (
+            "identity_token_binary",
+            "0003010000000000000000000000000000000000000000000000000000000000000005000000746f6b656e07000000000000000000000000000000",
+        ),
+        (
+            "large_identity_token_brotli",
+            "010bca040080aaaaaaea1f15f4a0a0979b9e8ffb410f0aa000aab001e7b243380e8801db7030664cc562a0586be6dd36d6df67cd5efa0c01006802000c",
+        ),
+        (
+            "large_identity_token_gzip",
+            "021f8b08000000000004ffedd62b0a00201084e11583780b8fe66b834110f4fed80d96adffa4c9c317c63bf9274791bd4ad333a6f69ae8ec80010c60000318c00006ec06c273c12e6d7d7c6495090000",
+        ),
+        (
+            "one_off_query_error_text",
+            "7b224f6e654f66665175657279526573706f6e7365223a7b226d6573736167655f6964223a22303130323033222c226572726f72223a7b22736f6d65223a226e6f2073756368207461626c653a20607460227d2c227461626c6573223a5b5d2c22746f74616c5f686f73745f657865637574696f6e5f6475726174696f6e223a7b225f5f74696d655f6475726174696f6e5f6d6963726f735f5f223a313530307d7d7d",
+        ),
+        (
+            "one_off_query_error_binary",
+            "00040300000001020300120000006e6f2073756368207461626c653a2060746000000000dc05000000000000",
+        ),
+    ];
+
+    #[test]
+    fn encoding_matches_the_baseline_encoder() {
+        fn token(token: String) -> IdentityTokenMessage {
+            IdentityTokenMessage {
+                identity: Identity::ONE,
+                token: token.into(),
+                connection_id: ConnectionId::from_u128(7),
+            }
+        }
+
+        fn query<F: WebsocketFormat>() -> OneOffQueryResponseMessage<F> {
+            OneOffQueryResponseMessage {
+                message_id: vec![1, 2, 3],
+                error: Some("no such table: `t`".into()),
+                results: vec![],
+                total_host_execution_duration: TimeDuration::from_micros(1500),
+            }
+        }
+
+        fn hex_of(
+            protocol: Protocol,
+            compression: Compression,
+            msg: impl ToProtocol<Encoded = SwitchedServerMessage>,
+        ) -> String {
+            let config = config(protocol, ProtocolVersion::V1, compression);
+            let (_, msg) = config
+                .codec()
+                .serialize(SerializeBuffer::new(config), msg, compression)
+                .unwrap_or_else(|failure| panic!("{}", failure.error));
+            match msg {
+                DataMessage::Text(text) => hex::encode(text.as_bytes()),
+                DataMessage::Binary(bin) => hex::encode(&bin),
+            }
+        }
+
+        let large = || "spacetimedb ".repeat(200);
+        let encodings = [
+            hex_of(Protocol::Text, Compression::None, token("token".into())),
+            hex_of(Protocol::Binary, Compression::Brotli, token("token".into())),
+            hex_of(Protocol::Binary, Compression::Brotli, token(large())),
+            hex_of(Protocol::Binary, Compression::Gzip, token(large())),
+            hex_of(Protocol::Text, Compression::None, query::<JsonFormat>()),
+            hex_of(Protocol::Binary, Compression::None, query::<BsatnFormat>()),
+        ];
+        for ((name, expected), encoded) in BASELINE_ENCODINGS.iter().zip(encodings) {
+            assert_eq!(encoded, *expected, "{name}");
+        }
+    }
+}
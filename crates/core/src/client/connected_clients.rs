@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+
+use parking_lot::Mutex;
+use spacetimedb_lib::ClientTarget;
+
+use super::{ClientActorId, ClientConnectionSender};
+
+/// The client connections to a single database on this node,
+/// so that its module can disconnect them.
+///
+/// Connections are held weakly,
+/// so that a connection whose actor went away without disconnecting is not kept alive.
+#[derive(Clone, Default)]
+pub struct ConnectedClients {
+    inner: Arc<Mutex<HashMap<ClientActorId, Weak<ClientConnectionSender>>>>,
+}
+
+impl ConnectedClients {
+    /// Register the connection of `sender`.
+    pub fn insert(&self, sender: &Arc<ClientConnectionSender>) {
+        let mut clients = self.inner.lock();
+        clients.retain(|_, conn| conn.strong_count() > 0);
+        clients.insert(sender.id, Arc::downgrade(sender));
+    }
+
+    /// Unregister the connection of `id`.
+    pub fn remove(&self, id: &ClientActorId) {
+        self.inner.lock().remove(id);
+    }
+
+    /// Returns the live connections matching `target`.
+    pub fn matching(&self, target: &ClientTarget) -> Vec<Arc<ClientConnectionSender>> {
+        self.inner
+            .lock()
+            .iter()
+            .filter(|(id, _)| match target {
+                ClientTarget::Identity(identity) => id.identity == *identity,
+                ClientTarget::ConnectionId(connection_id) => id.connection_id == *connection_id,
+            })
+            .filter_map(|(_, conn)| conn.upgrade())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientConfig, ClientName};
+    use spacetimedb_lib::{ConnectionId, Identity};
+
+    fn conn(identity: u8, connection_id: u128) -> Arc<ClientConnectionSender> {
+        let id = ClientActorId {
+            identity: Identity::from_byte_array([identity; 32]),
+            connection_id: ConnectionId::from_u128(connection_id),
+            name: ClientName(0),
+        };
+        Arc::new(ClientConnectionSender::dummy(id, ClientConfig::for_test()))
+    }
+
+    #[test]
+    fn matches_by_identity_or_connection_id() {
+        let clients = ConnectedClients::default();
+        let (a1, a2, b) = (conn(1, 1), conn(1, 2), conn(2, 3));
+        for c in [&a1, &a2, &b] {
+            clients.insert(c);
+        }
+
+        let by_identity = clients.matching(&ClientTarget::Identity(a1.id.identity));
+        assert_eq!(by_identity.len(), 2);
+        assert!(by_identity.iter().all(|c| c.id.identity == a1.id.identity));
+
+        let by_connection = clients.matching(&ClientTarget::ConnectionId(a2.id.connection_id));
+        assert_eq!(by_connection.len(), 1);
+        assert_eq!(by_connection[0].id, a2.id);
+
+        clients.remove(&a1.id);
+        assert_eq!(clients.matching(&ClientTarget::Identity(a1.id.identity)).len(), 1);
+    }
+
+    #[test]
+    fn dropped_connections_are_not_matched() {
+        let clients = ConnectedClients::default();
+        let a = conn(1, 1);
+        clients.insert(&a);
+        let identity = a.id.identity;
+        drop(a);
+        assert!(clients.matching(&ClientTarget::Identity(identity)).is_empty());
+    }
+}
@@ -0,0 +1,300 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::client_connection::EnqueueError;
+use super::messages::SerializableMessage;
+use super::{ClientActorId, ClientConnectionSender, ClientSendError};
+
+/// How long [`ConnectionSender`] waits before trying again to queue a message
+/// which didn't fit in the client's queue.
+const RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A handle for sending messages to a client connection from any thread,
+/// including synchronous code outside of any tokio runtime,
+/// e.g. an embedder's simulation thread or a reducer host call.
+///
+/// Obtained from [`ClientActorIndex::connection_sender`](super::ClientActorIndex::connection_sender)
+/// and cheaply cloneable.
+///
+/// Messages are queued on the same channel as [`ClientConnectionSender::send_message`],
+/// counting against the same [`SendQueueCapacity`](super::SendQueueCapacity) and metrics.
+/// Unlike `send_message`, which disconnects a client whose queue is full,
+/// a full queue is reported to the caller, who may wait for it to drain.
+///
+/// If the client re-authenticates as another identity,
+/// the handle reports [`ConnectionSendError::Disconnected`] and must be obtained again.
+#[derive(Clone, Debug)]
+pub struct ConnectionSender {
+    sender: Arc<ClientConnectionSender>,
+}
+
+/// Why a [`ConnectionSender`] didn't send a message.
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectionSendError {
+    /// The client's queue had no room for the message, which is handed back,
+    /// along with which bound of its [`SendQueueCapacity`](super::SendQueueCapacity) was hit,
+    /// either [`ClientSendError::QueueFullByCount`] or [`ClientSendError::QueueFullByBytes`].
+    #[error("{1}")]
+    Full(SerializableMessage, ClientSendError),
+    #[error("client disconnected")]
+    Disconnected,
+    #[error("client was not responding and is being disconnected")]
+    ConnectionClosing,
+}
+
+impl ConnectionSendError {
+    /// Why the message wasn't sent, as [`ClientConnectionSender::send_message`] would report it.
+    pub fn reason(&self) -> ClientSendError {
+        match self {
+            Self::Full(_, reason) => *reason,
+            Self::Disconnected => ClientSendError::Disconnected,
+            Self::ConnectionClosing => ClientSendError::ConnectionClosing,
+        }
+    }
+}
+
+impl From<EnqueueError> for ConnectionSendError {
+    fn from(e: EnqueueError) -> Self {
+        match e {
+            EnqueueError::Full { message, bound } => Self::Full(message, bound),
+            EnqueueError::Client(ClientSendError::Disconnected) => Self::Disconnected,
+            EnqueueError::Client(_) => Self::ConnectionClosing,
+        }
+    }
+}
+
+impl ConnectionSender {
+    pub fn new(sender: Arc<ClientConnectionSender>) -> Self {
+        Self { sender }
+    }
+
+    /// The connection messages are sent to.
+    pub fn id(&self) -> ClientActorId {
+        self.sender.id
+    }
+
+    /// Queue `message` for the client without waiting,
+    /// returning [`ConnectionSendError::Full`] if its queue has no room.
+    pub fn try_send(&self, message: impl Into<SerializableMessage>) -> Result<(), ConnectionSendError> {
+        self.recorded(self.enqueue(message.into()))
+    }
+
+    fn enqueue(&self, message: SerializableMessage) -> Result<(), ConnectionSendError> {
+        Ok(self.sender.try_enqueue(message)?)
+    }
+
+    /// Count the error of a send, if any, once it's returned to the caller,
+    /// rather than on each attempt to queue its message.
+    fn recorded(&self, res: Result<(), ConnectionSendError>) -> Result<(), ConnectionSendError> {
+        if let Err(e) = &res {
+            self.sender.record_send_error(e.reason());
+        }
+        res
+    }
+
+    /// Queue `message` for the client,
+    /// blocking the current thread for up to `timeout` until its queue has room.
+    ///
+    /// This can be called from any thread, including from within a tokio runtime,
+    /// but blocks that thread, so async code should use [`Self::send`] instead.
+    pub fn blocking_send(
+        &self,
+        message: impl Into<SerializableMessage>,
+        timeout: Duration,
+    ) -> Result<(), ConnectionSendError> {
+        let deadline = Instant::now() + timeout;
+        let mut message = message.into();
+        loop {
+            match self.enqueue(message) {
+                Err(ConnectionSendError::Full(m, reason)) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return self.recorded(Err(ConnectionSendError::Full(m, reason)));
+                    }
+                    message = m;
+                    std::thread::sleep(RETRY_INTERVAL.min(deadline - now));
+                }
+                res => return self.recorded(res),
+            }
+        }
+    }
+
+    /// Queue `message` for the client, waiting until its queue has room.
+    ///
+    /// Wrap this in a timeout to bound the wait.
+    pub async fn send(&self, message: impl Into<SerializableMessage>) -> Result<(), ConnectionSendError> {
+        let mut message = message.into();
+        loop {
+            match self.enqueue(message) {
+                Err(ConnectionSendError::Full(m, _)) => {
+                    message = m;
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+                res => return self.recorded(res),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::messages::ReconnectRequestedMessage;
+    use crate::client::{ClientConfig, MeteredReceiver, SendQueueCapacity};
+    use crate::util::jobs::JobCore;
+    use spacetimedb_lib::{Identity, Timestamp};
+
+    fn message(micros: i64) -> ReconnectRequestedMessage {
+        ReconnectRequestedMessage {
+            deadline: Timestamp::from_micros_since_unix_epoch(micros),
+            reconnect_token: None,
+        }
+    }
+
+    fn received(msg: SerializableMessage) -> i64 {
+        let SerializableMessage::ReconnectRequested(msg) = msg else {
+            panic!("unexpected message {msg:?}");
+        };
+        msg.deadline.to_micros_since_unix_epoch()
+    }
+
+    /// A sender for a connection whose queue holds a single message, along with its receiving end.
+    fn connection() -> (ConnectionSender, MeteredReceiver<SerializableMessage>) {
+        let capacity = SendQueueCapacity {
+            messages: 1,
+            ..<_>::default()
+        };
+        let id = ClientActorId::for_test(Identity::ZERO);
+        let (sender, rx) = ClientConnectionSender::dummy_with_capacity(id, ClientConfig::for_test(), capacity);
+        (ConnectionSender::new(Arc::new(sender)), rx)
+    }
+
+    #[tokio::test]
+    async fn try_send_reports_a_full_queue_without_disconnecting() {
+        let (sender, mut rx) = connection();
+        sender.try_send(message(1)).unwrap();
+        let bytes = rx.queued_bytes();
+
+        let Err(ConnectionSendError::Full(msg, reason)) = sender.try_send(message(2)) else {
+            panic!("expected the queue to be full");
+        };
+        assert_eq!(reason, ClientSendError::QueueFullByCount);
+        assert_eq!(received(msg), 2);
+        assert!(!sender.sender.is_cancelled());
+        assert_eq!(rx.queued_bytes(), bytes);
+
+        assert_eq!(received(rx.recv().await.unwrap()), 1);
+        assert_eq!(rx.queued_bytes(), 0);
+        sender.try_send(message(2)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn blocking_send_from_a_plain_thread_waits_for_room() {
+        let (sender, mut rx) = connection();
+        sender.try_send(message(1)).unwrap();
+
+        let thread = std::thread::spawn({
+            let sender = sender.clone();
+            move || sender.blocking_send(message(2), Duration::from_secs(10))
+        });
+        assert_eq!(received(rx.recv().await.unwrap()), 1);
+        thread.join().unwrap().unwrap();
+        assert_eq!(received(rx.recv().await.unwrap()), 2);
+    }
+
+    #[tokio::test]
+    async fn blocking_send_hands_back_the_message_on_timeout() {
+        let (sender, rx) = connection();
+        sender.try_send(message(1)).unwrap();
+
+        let thread = std::thread::spawn(move || sender.blocking_send(message(2), Duration::from_millis(10)));
+        let Err(ConnectionSendError::Full(msg, _)) = thread.join().unwrap() else {
+            panic!("expected the send to time out");
+        };
+        assert_eq!(received(msg), 2);
+        assert_eq!(rx.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn try_send_reports_a_full_queue_by_bytes() {
+        let capacity = SendQueueCapacity {
+            messages: 1000,
+            bytes: 1,
+        };
+        let id = ClientActorId::for_test(Identity::ZERO);
+        let (sender, _rx) = ClientConnectionSender::dummy_with_capacity(id, ClientConfig::for_test(), capacity);
+        let sender = ConnectionSender::new(Arc::new(sender));
+        // A single message larger than the byte bound is let through alone.
+        sender.try_send(message(1)).unwrap();
+        let err = sender.try_send(message(2)).unwrap_err();
+        assert_eq!(err.reason(), ClientSendError::QueueFullByBytes);
+    }
+
+    #[tokio::test]
+    async fn sends_to_a_disconnecting_connection_fail() {
+        let (sender, _rx) = connection();
+        sender.try_send(message(1)).unwrap();
+        // Overflowing the queue through `send_message` disconnects the client.
+        assert_eq!(
+            sender.sender.send_message(message(2)),
+            Err(ClientSendError::QueueFullByCount)
+        );
+        let err = sender.try_send(message(3)).unwrap_err();
+        assert!(matches!(err, ConnectionSendError::ConnectionClosing));
+        assert_eq!(err.reason(), ClientSendError::ConnectionClosing);
+    }
+
+    #[tokio::test]
+    async fn sends_to_a_closed_connection_fail() {
+        let (sender, rx) = connection();
+        drop(rx);
+        assert!(matches!(
+            sender.try_send(message(1)),
+            Err(ConnectionSendError::Disconnected)
+        ));
+        assert!(matches!(
+            sender.blocking_send(message(1), Duration::from_secs(10)),
+            Err(ConnectionSendError::Disconnected)
+        ));
+        assert!(matches!(
+            sender.send(message(1)).await,
+            Err(ConnectionSendError::Disconnected)
+        ));
+    }
+
+    // Reducers run on job threads, which block in place within the tokio runtime.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocking_send_from_a_reducer_host_call() {
+        let (sender, mut rx) = connection();
+        sender.try_send(message(1)).unwrap();
+
+        let job_thread = JobCore::default().start(|| (), |data| data);
+        let send = job_thread.run({
+            let sender = sender.clone();
+            move |_| sender.blocking_send(message(2), Duration::from_secs(10))
+        });
+        let recv = async {
+            let first = rx.recv().await.unwrap();
+            let second = rx.recv().await.unwrap();
+            (received(first), received(second))
+        };
+        let (sent, order) = tokio::join!(send, recv);
+        sent.unwrap_or_else(|_| panic!("the job thread closed")).unwrap();
+        assert_eq!(order, (1, 2));
+    }
+
+    #[tokio::test]
+    async fn send_waits_for_room() {
+        let (sender, mut rx) = connection();
+        sender.try_send(message(1)).unwrap();
+
+        let recv = async {
+            let first = rx.recv().await.unwrap();
+            let second = rx.recv().await.unwrap();
+            (received(first), received(second))
+        };
+        let (sent, order) = tokio::join!(sender.send(message(2)), recv);
+        sent.unwrap();
+        assert_eq!(order, (1, 2));
+    }
+}
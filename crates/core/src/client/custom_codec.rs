@@ -0,0 +1,99 @@
+//! A hook for prototyping [`ProtocolCodec`]s outside this crate, behind the `unstable-codecs` feature.
+//!
+//! A [`CodecFactory`] installed with [`set_codec_factory`] selects the codec of every connection,
+//! given its [`ClientConfig`] and the codec built into the host for it,
+//! which it may return as is, wrap, e.g. as [`EncryptedCodec`](super::EncryptedCodec) does, or replace.
+//! A codec which writes its own bytes rather than into the [`SerializeBuffer`](super::messages::SerializeBuffer)
+//! it's given may hand the buffer back as [`InUseSerializeBuffer::Unused`](super::messages::InUseSerializeBuffer::Unused).
+//!
+//! Neither the hook nor the [`ProtocolCodec`] trait are stable,
+//! and clients only speak the protocols negotiated by the subprotocols the host knows,
+//! so a codec selected here must still be understood by the clients it's selected for.
+
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+
+use super::{ClientConfig, ProtocolCodec};
+
+/// Selects the codec of a connection with a [`ClientConfig`], given the codec built into the host for it.
+pub type CodecFactory = dyn Fn(&ClientConfig, Arc<dyn ProtocolCodec>) -> Arc<dyn ProtocolCodec> + Send + Sync;
+
+static CODEC_FACTORY: OnceCell<Box<CodecFactory>> = OnceCell::new();
+
+/// A [`CodecFactory`] was already installed in this process.
+#[derive(thiserror::Error, Debug)]
+#[error("a codec factory is already set")]
+pub struct CodecFactoryAlreadySet;
+
+/// Install `factory` to select the codec of every connection opened from now on.
+///
+/// Connections which negotiate end-to-end encryption have the codec it selects wrapped in an
+/// [`EncryptedCodec`](super::EncryptedCodec).
+/// Only one factory may be installed per process.
+pub fn set_codec_factory(factory: Box<CodecFactory>) -> Result<(), CodecFactoryAlreadySet> {
+    CODEC_FACTORY.set(factory).map_err(|_| CodecFactoryAlreadySet)
+}
+
+/// The codec the installed [`CodecFactory`] selects for `config`, if one is installed.
+pub(super) fn select(
+    config: &ClientConfig,
+    builtin: impl FnOnce() -> Arc<dyn ProtocolCodec>,
+) -> Option<Arc<dyn ProtocolCodec>> {
+    CODEC_FACTORY.get().map(|factory| factory(config, builtin()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::messages::{SerializeBuffer, SwitchedServerMessage};
+    use crate::client::{EncodeResult, Protocol};
+    use crate::messages::websocket::Compression;
+
+    /// Tells apart the configs the factory of these tests selects a prototype for,
+    /// so that those of other tests in the process keep their built-in codecs.
+    const PROTOTYPE_THRESHOLD: usize = 4321;
+
+    #[derive(Debug)]
+    struct Prototype(Arc<dyn ProtocolCodec>);
+
+    impl ProtocolCodec for Prototype {
+        fn protocol(&self) -> Protocol {
+            self.0.protocol()
+        }
+
+        fn encode(
+            &self,
+            buffer: SerializeBuffer,
+            msg: SwitchedServerMessage,
+            compression: Compression,
+        ) -> EncodeResult {
+            self.0.encode(buffer, msg, compression)
+        }
+
+        fn with_compression_threshold(&self, compression_threshold: usize) -> Arc<dyn ProtocolCodec> {
+            Arc::new(Prototype(self.0.with_compression_threshold(compression_threshold)))
+        }
+    }
+
+    #[test]
+    fn installed_factory_selects_the_codecs_of_connections() {
+        let installed = set_codec_factory(Box::new(|config, builtin| {
+            if config.compression_threshold == PROTOTYPE_THRESHOLD {
+                Arc::new(Prototype(builtin))
+            } else {
+                builtin
+            }
+        }));
+        assert!(installed.is_ok());
+
+        let prototyped = ClientConfig {
+            compression_threshold: PROTOTYPE_THRESHOLD,
+            ..ClientConfig::for_test()
+        };
+        assert!(format!("{:?}", prototyped.codec()).starts_with("Prototype(BinaryCodec"));
+        assert!(format!("{:?}", ClientConfig::for_test().codec()).starts_with("BinaryCodec"));
+
+        assert!(set_codec_factory(Box::new(|_, builtin| builtin)).is_err());
+    }
+}
@@ -0,0 +1,559 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use prometheus::{IntCounter, IntGauge};
+use spacetimedb_lib::{ConnectionId, Identity};
+
+use super::messages::{AcknowledgedUpdateMessage, TransactionUpdateMessage};
+use super::{ClientActorId, Protocol};
+use crate::messages::websocket::ReplayBufferStats;
+use crate::worker_metrics::WORKER_METRICS;
+
+/// The most deliveries kept pending for a single identity,
+/// beyond which the oldest are forgotten.
+pub const MAX_PENDING_DELIVERIES: usize = 1024;
+
+/// How long a delivery is kept pending before it expires, having never been acknowledged.
+pub const PENDING_DELIVERY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How often expired deliveries are swept, see [`PendingDeliveries::spawn_sweeper`].
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most identities and deliveries a single sweep looks at,
+/// so that a sweep never holds the lock for long, however many deliveries are pending.
+const SWEEP_BUDGET: usize = 1024;
+
+/// How many of the identities with the most bytes pending have a gauge of their own.
+const TOP_IDENTITIES: usize = 10;
+
+/// The acknowledged updates delivered to the clients of a single database on this node
+/// which the clients haven't yet acknowledged,
+/// so that they can be delivered again when a client reconnects.
+///
+/// Deliveries are pending for an identity rather than a connection,
+/// as a client reconnects under a new connection id.
+/// They're held in memory, and so do not survive a restart of the node,
+/// and expire after [`PENDING_DELIVERY_TTL`], see [`Self::spawn_sweeper`].
+#[derive(Clone, Default)]
+pub struct PendingDeliveries {
+    inner: Arc<Mutex<DeliveryState>>,
+}
+
+impl std::fmt::Debug for PendingDeliveries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Shared by every connection to the database, so not worth locking to print.
+        f.debug_struct("PendingDeliveries").finish_non_exhaustive()
+    }
+}
+
+#[derive(Default)]
+struct DeliveryState {
+    /// The id of the most recent delivery.
+    last_id: u64,
+    /// Pending deliveries by the identity they were delivered to.
+    by_identity: BTreeMap<Identity, IdentityDeliveries>,
+    /// The bytes of all pending deliveries.
+    bytes: usize,
+    /// The identity the last sweep stopped at, after which the next one starts.
+    sweep_cursor: Option<Identity>,
+    metrics: Option<DeliveryMetrics>,
+}
+
+/// The deliveries pending for a single identity.
+#[derive(Default)]
+struct IdentityDeliveries {
+    /// Oldest first.
+    deliveries: VecDeque<PendingDelivery>,
+    bytes: usize,
+}
+
+struct PendingDelivery {
+    delivery_id: u64,
+    /// The connection the update was last delivered to, which alone may acknowledge it.
+    connection_id: ConnectionId,
+    protocol: Protocol,
+    update: TransactionUpdateMessage,
+    /// When the update was first delivered.
+    delivered_at: Instant,
+    /// The estimated size of the update.
+    bytes: usize,
+}
+
+/// The deliveries pending for an identity, see [`PendingDeliveries::by_identity`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityPending {
+    pub identity: Identity,
+    pub deliveries: usize,
+    pub bytes: usize,
+    /// How long ago the oldest of the deliveries was made.
+    pub oldest: Duration,
+}
+
+/// The metrics of the deliveries pending for the clients of a database.
+struct DeliveryMetrics {
+    database_identity: Identity,
+    persisted: IntCounter,
+    acknowledged: IntCounter,
+    redelivered: IntCounter,
+    expired: IntCounter,
+    overrun: IntCounter,
+    purged: IntCounter,
+    bytes: IntGauge,
+    /// The identities with a `replay_buffer_identity_bytes` gauge of their own.
+    top_identities: Vec<Identity>,
+}
+
+impl DeliveryMetrics {
+    fn new(database_identity: Identity) -> Self {
+        let event = |event| {
+            WORKER_METRICS
+                .pending_deliveries
+                .with_label_values(&database_identity, event)
+        };
+        Self {
+            database_identity,
+            persisted: event("persisted"),
+            acknowledged: event("acknowledged"),
+            redelivered: event("redelivered"),
+            expired: event("expired"),
+            overrun: event("overrun"),
+            purged: event("purged"),
+            bytes: WORKER_METRICS
+                .replay_buffer_bytes
+                .with_label_values(&database_identity, "pending_deliveries"),
+            top_identities: vec![],
+        }
+    }
+}
+
+impl Drop for DeliveryMetrics {
+    fn drop(&mut self) {
+        let _ = WORKER_METRICS
+            .replay_buffer_bytes
+            .remove_label_values(&self.database_identity, "pending_deliveries");
+        for identity in &self.top_identities {
+            let _ = WORKER_METRICS
+                .replay_buffer_identity_bytes
+                .remove_label_values(&self.database_identity, identity);
+        }
+    }
+}
+
+impl DeliveryState {
+    /// Note that `delivery`, pending for `identity`, no longer is, returning it.
+    fn removed(&mut self, identity: &Identity, delivery: PendingDelivery) -> PendingDelivery {
+        if let Some(pending) = self.by_identity.get_mut(identity) {
+            pending.bytes -= delivery.bytes;
+            if pending.deliveries.is_empty() {
+                self.by_identity.remove(identity);
+            }
+        }
+        self.bytes -= delivery.bytes;
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes.set(self.bytes as i64);
+        }
+        delivery
+    }
+
+    fn count(&self, event: impl FnOnce(&DeliveryMetrics) -> &IntCounter, n: usize) {
+        if let Some(metrics) = &self.metrics {
+            event(metrics).inc_by(n as u64);
+        }
+    }
+}
+
+/// Why an acknowledgment was ignored.
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AckError {
+    #[error("no such delivery is pending")]
+    Unknown,
+    #[error("the update was delivered to another connection")]
+    WrongConnection,
+}
+
+impl AckError {
+    /// The label of the error in metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::WrongConnection => "wrong_connection",
+        }
+    }
+}
+
+impl PendingDeliveries {
+    /// The deliveries pending for the clients of the database `database_identity`,
+    /// which are reported in its metrics.
+    pub fn for_database(database_identity: Identity) -> Self {
+        let state = DeliveryState {
+            metrics: Some(DeliveryMetrics::new(database_identity)),
+            ..<_>::default()
+        };
+        Self {
+            inner: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Record the delivery of `update` to the connection `id`, in `protocol`,
+    /// returning the message to deliver it with.
+    pub fn deliver(
+        &self,
+        id: &ClientActorId,
+        protocol: Protocol,
+        update: TransactionUpdateMessage,
+    ) -> AcknowledgedUpdateMessage {
+        let state = &mut *self.inner.lock();
+        state.last_id += 1;
+        let delivery_id = state.last_id;
+        let pending = state.by_identity.entry(id.identity).or_default();
+        let forgotten = if pending.deliveries.len() >= MAX_PENDING_DELIVERIES {
+            pending.deliveries.pop_front()
+        } else {
+            None
+        };
+        let delivery = PendingDelivery {
+            delivery_id,
+            connection_id: id.connection_id,
+            protocol,
+            update: update.clone(),
+            delivered_at: Instant::now(),
+            bytes: update.database_update.num_bytes(),
+        };
+        pending.bytes += delivery.bytes;
+        state.bytes += delivery.bytes;
+        pending.deliveries.push_back(delivery);
+        state.count(|metrics| &metrics.persisted, 1);
+        if let Some(forgotten) = forgotten {
+            log::warn!(
+                "forgetting delivery {} to {}, which has {MAX_PENDING_DELIVERIES} pending deliveries",
+                forgotten.delivery_id,
+                id.identity
+            );
+            state.removed(&id.identity, forgotten);
+            state.count(|metrics| &metrics.overrun, 1);
+        }
+        if let Some(metrics) = &state.metrics {
+            metrics.bytes.set(state.bytes as i64);
+        }
+        AcknowledgedUpdateMessage { delivery_id, update }
+    }
+
+    /// Record that the connection `id` acknowledged the delivery `delivery_id`,
+    /// which is then no longer pending.
+    pub fn acknowledge(&self, id: &ClientActorId, delivery_id: u64) -> Result<(), AckError> {
+        let mut state = self.inner.lock();
+        let pending = state.by_identity.get_mut(&id.identity).ok_or(AckError::Unknown)?;
+        let index = pending
+            .deliveries
+            .iter()
+            .position(|delivery| delivery.delivery_id == delivery_id)
+            .ok_or(AckError::Unknown)?;
+        if pending.deliveries[index].connection_id != id.connection_id {
+            return Err(AckError::WrongConnection);
+        }
+        let delivery = pending.deliveries.remove(index).unwrap();
+        state.removed(&id.identity, delivery);
+        state.count(|metrics| &metrics.acknowledged, 1);
+        Ok(())
+    }
+
+    /// Hand the deliveries pending for the identity of `id` in `protocol` over to the connection `id`,
+    /// returning the messages to deliver them again with, oldest first.
+    ///
+    /// Deliveries made in another protocol stay pending for a connection using that protocol.
+    pub fn redeliver(&self, id: &ClientActorId, protocol: Protocol) -> Vec<AcknowledgedUpdateMessage> {
+        let mut state = self.inner.lock();
+        let Some(pending) = state.by_identity.get_mut(&id.identity) else {
+            return vec![];
+        };
+        let messages = pending
+            .deliveries
+            .iter_mut()
+            .filter(|delivery| delivery.protocol == protocol)
+            .map(|delivery| {
+                delivery.connection_id = id.connection_id;
+                AcknowledgedUpdateMessage {
+                    delivery_id: delivery.delivery_id,
+                    update: delivery.update.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        state.count(|metrics| &metrics.redelivered, messages.len());
+        messages
+    }
+
+    /// Forget the deliveries pending for `identity`, returning how many there were,
+    /// so that none is delivered again when a client of the identity reconnects.
+    pub fn purge(&self, identity: &Identity) -> usize {
+        let mut state = self.inner.lock();
+        let Some(pending) = state.by_identity.remove(identity) else {
+            return 0;
+        };
+        state.bytes -= pending.bytes;
+        if let Some(metrics) = &state.metrics {
+            metrics.bytes.set(state.bytes as i64);
+        }
+        let purged = pending.deliveries.len();
+        state.count(|metrics| &metrics.purged, purged);
+        purged
+    }
+
+    /// Expire the deliveries made longer than `ttl` before `now`,
+    /// looking at no more than `budget` identities and deliveries,
+    /// and returning how many expired.
+    ///
+    /// Each sweep picks up from the identity where the last one stopped,
+    /// so that every identity is swept in turn, however many deliveries are pending.
+    fn sweep(&self, now: Instant, ttl: Duration, budget: usize) -> usize {
+        let mut state = self.inner.lock();
+        let mut cursor = state.sweep_cursor;
+        let (mut work, mut expired) = (0, 0);
+        for _ in 0..state.by_identity.len() {
+            let after = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+            let next = (state.by_identity.range((after, Bound::Unbounded)).next())
+                .or_else(|| state.by_identity.iter().next())
+                .map(|(identity, _)| *identity);
+            let Some(identity) = next else {
+                break;
+            };
+            work += 1;
+            loop {
+                let pending = &mut state.by_identity.get_mut(&identity).unwrap().deliveries;
+                let is_expired =
+                    |delivery: &PendingDelivery| now.saturating_duration_since(delivery.delivered_at) >= ttl;
+                if !pending.front().is_some_and(is_expired) {
+                    break;
+                }
+                if work >= budget {
+                    // Come back to this identity next time, as it may have more to expire.
+                    state.sweep_cursor = cursor;
+                    state.count(|metrics| &metrics.expired, expired);
+                    return expired;
+                }
+                let delivery = pending.pop_front().unwrap();
+                state.removed(&identity, delivery);
+                work += 1;
+                expired += 1;
+                if !state.by_identity.contains_key(&identity) {
+                    break;
+                }
+            }
+            cursor = Some(identity);
+            if work >= budget {
+                break;
+            }
+        }
+        state.sweep_cursor = cursor;
+        state.count(|metrics| &metrics.expired, expired);
+        expired
+    }
+
+    /// Sweep expired deliveries every [`SWEEP_INTERVAL`], until these deliveries are dropped.
+    pub fn spawn_sweeper(&self) {
+        let deliveries = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SWEEP_INTERVAL).await;
+                let Some(inner) = deliveries.upgrade() else {
+                    break;
+                };
+                PendingDeliveries { inner }.sweep(Instant::now(), PENDING_DELIVERY_TTL, SWEEP_BUDGET);
+            }
+        });
+    }
+
+    /// The identities with pending deliveries, those with the most bytes pending first.
+    pub fn by_identity(&self) -> Vec<IdentityPending> {
+        let now = Instant::now();
+        let state = self.inner.lock();
+        let mut identities = state
+            .by_identity
+            .iter()
+            .map(|(identity, pending)| IdentityPending {
+                identity: *identity,
+                deliveries: pending.deliveries.len(),
+                bytes: pending.bytes,
+                oldest: pending
+                    .deliveries
+                    .iter()
+                    .map(|delivery| now.saturating_duration_since(delivery.delivered_at))
+                    .max()
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        identities.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.identity.cmp(&b.identity)));
+        identities
+    }
+
+    /// The [`ReplayBufferStats`] of the pending deliveries, leaving those of parked sessions to the caller.
+    pub fn stats(&self) -> ReplayBufferStats {
+        let state = self.inner.lock();
+        ReplayBufferStats {
+            pending_deliveries: state
+                .by_identity
+                .values()
+                .map(|pending| pending.deliveries.len() as u64)
+                .sum(),
+            pending_delivery_bytes: state.bytes as u64,
+            pending_delivery_identities: state.by_identity.len() as u32,
+            ..<_>::default()
+        }
+    }
+
+    /// Give the identities with the most bytes pending a `replay_buffer_identity_bytes` gauge of their own,
+    /// removing those of the identities which no longer are among them.
+    pub fn update_gauges(&self) {
+        let top = self.by_identity().into_iter().take(TOP_IDENTITIES).collect::<Vec<_>>();
+        let mut state = self.inner.lock();
+        let Some(metrics) = &mut state.metrics else {
+            return;
+        };
+        let gauge = |identity| {
+            WORKER_METRICS
+                .replay_buffer_identity_bytes
+                .with_label_values(&metrics.database_identity, identity)
+        };
+        for identity in &metrics.top_identities {
+            if !top.iter().any(|pending| pending.identity == *identity) {
+                let _ = WORKER_METRICS
+                    .replay_buffer_identity_bytes
+                    .remove_label_values(&metrics.database_identity, identity);
+            }
+        }
+        for pending in &top {
+            gauge(&pending.identity).set(pending.bytes as i64);
+        }
+        metrics.top_identities = top.into_iter().map(|pending| pending.identity).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::messages::{SubscriptionUpdateMessage, UpdateDetail};
+    use crate::client::ClientName;
+    use spacetimedb_lib::Timestamp;
+
+    fn conn(identity: u8, connection_id: u128) -> ClientActorId {
+        ClientActorId {
+            identity: Identity::from_byte_array([identity; 32]),
+            connection_id: ConnectionId::from_u128(connection_id),
+            name: ClientName(0),
+        }
+    }
+
+    fn update() -> TransactionUpdateMessage {
+        TransactionUpdateMessage {
+            event: None,
+            database_update: SubscriptionUpdateMessage::default_for_protocol(Protocol::Binary, None),
+            committed_at: Timestamp::UNIX_EPOCH,
+            detail: UpdateDetail::FULL,
+            shared: None,
+        }
+    }
+
+    fn redelivered_ids(deliveries: &PendingDeliveries, id: &ClientActorId) -> Vec<u64> {
+        deliveries
+            .redeliver(id, Protocol::Binary)
+            .into_iter()
+            .map(|msg| msg.delivery_id)
+            .collect()
+    }
+
+    #[test]
+    fn unacknowledged_deliveries_are_redelivered_on_reconnect() {
+        let deliveries = PendingDeliveries::default();
+        let (first, reconnected) = (conn(1, 1), conn(1, 2));
+        let a = deliveries.deliver(&first, Protocol::Binary, update()).delivery_id;
+        let b = deliveries.deliver(&first, Protocol::Binary, update()).delivery_id;
+        assert_ne!(a, b);
+        deliveries.acknowledge(&first, a).unwrap();
+
+        assert_eq!(redelivered_ids(&deliveries, &reconnected), [b]);
+        // Once redelivered, only the new connection may acknowledge the update.
+        assert_eq!(deliveries.acknowledge(&first, b), Err(AckError::WrongConnection));
+        deliveries.acknowledge(&reconnected, b).unwrap();
+        assert!(redelivered_ids(&deliveries, &reconnected).is_empty());
+    }
+
+    #[test]
+    fn unknown_and_foreign_acks_are_ignored() {
+        let deliveries = PendingDeliveries::default();
+        let (alice, bob) = (conn(1, 1), conn(2, 2));
+        let id = deliveries.deliver(&alice, Protocol::Binary, update()).delivery_id;
+
+        assert_eq!(deliveries.acknowledge(&alice, id + 1), Err(AckError::Unknown));
+        assert_eq!(deliveries.acknowledge(&bob, id), Err(AckError::Unknown));
+        assert_eq!(deliveries.acknowledge(&conn(1, 3), id), Err(AckError::WrongConnection));
+        deliveries.acknowledge(&alice, id).unwrap();
+        assert_eq!(deliveries.acknowledge(&alice, id), Err(AckError::Unknown));
+    }
+
+    #[test]
+    fn deliveries_stay_with_their_protocol() {
+        let deliveries = PendingDeliveries::default();
+        let id = deliveries.deliver(&conn(1, 1), Protocol::Binary, update()).delivery_id;
+        assert!(deliveries.redeliver(&conn(1, 2), Protocol::Text).is_empty());
+        assert_eq!(redelivered_ids(&deliveries, &conn(1, 3)), [id]);
+    }
+
+    #[test]
+    fn oldest_deliveries_are_forgotten() {
+        let deliveries = PendingDeliveries::default();
+        let client = conn(1, 1);
+        let first = deliveries.deliver(&client, Protocol::Binary, update()).delivery_id;
+        for _ in 0..MAX_PENDING_DELIVERIES {
+            deliveries.deliver(&client, Protocol::Binary, update());
+        }
+        let redelivered = redelivered_ids(&deliveries, &client);
+        assert_eq!(redelivered.len(), MAX_PENDING_DELIVERIES);
+        assert!(!redelivered.contains(&first));
+    }
+
+    #[test]
+    fn expired_deliveries_are_swept_within_the_budget() {
+        let deliveries = PendingDeliveries::default();
+        let (alice, bob) = (conn(1, 1), conn(2, 2));
+        for _ in 0..3 {
+            deliveries.deliver(&alice, Protocol::Binary, update());
+        }
+        for _ in 0..2 {
+            deliveries.deliver(&bob, Protocol::Binary, update());
+        }
+
+        let ttl = Duration::from_secs(60);
+        assert_eq!(deliveries.sweep(Instant::now(), ttl, 100), 0);
+
+        // Visiting an identity and expiring a delivery each take one of the budget.
+        let later = Instant::now() + ttl;
+        let sweeps = std::iter::repeat_with(|| deliveries.sweep(later, ttl, 3))
+            .take(4)
+            .collect::<Vec<_>>();
+        assert_eq!(sweeps, [2, 1, 2, 0]);
+        assert!(deliveries.by_identity().is_empty());
+        assert_eq!(deliveries.stats().pending_delivery_bytes, 0);
+    }
+
+    #[test]
+    fn purged_identities_have_nothing_redelivered() {
+        let deliveries = PendingDeliveries::default();
+        let (alice, bob) = (conn(1, 1), conn(2, 2));
+        deliveries.deliver(&alice, Protocol::Binary, update());
+        deliveries.deliver(&alice, Protocol::Binary, update());
+        let kept = deliveries.deliver(&bob, Protocol::Binary, update()).delivery_id;
+
+        let pending = deliveries.by_identity();
+        assert_eq!(pending.len(), 2);
+        assert_eq!((pending[0].identity, pending[0].deliveries), (alice.identity, 2));
+        assert_eq!(deliveries.stats().pending_deliveries, 3);
+
+        assert_eq!(deliveries.purge(&alice.identity), 2);
+        assert_eq!(deliveries.purge(&alice.identity), 0);
+        assert!(redelivered_ids(&deliveries, &conn(1, 3)).is_empty());
+        assert_eq!(redelivered_ids(&deliveries, &conn(2, 3)), [kept]);
+        assert_eq!(deliveries.stats().pending_deliveries, 1);
+    }
+}
@@ -0,0 +1,559 @@
+//! Shedding new work while the node is under load, rather than degrading the work it already does.
+//!
+//! The load of the node is sampled from its [worker metrics](WORKER_METRICS):
+//! the busy ratio of its tokio workers and the number of client messages waiting to be handled.
+//! Once the load reaches the thresholds of a [`LoadLevel`], the node enters it,
+//! refusing new subscriptions, and at the higher level, new connections too,
+//! while the subscriptions and connections it has are left alone.
+//! The node leaves a level only once its load has fallen below a fraction of the level's thresholds,
+//! see [`LoadAdmissionConfig::recovery_ratio`], so that admission doesn't flap around a threshold.
+//!
+//! The thresholds are read from the `[load-admission]` section of `config.toml`,
+//! and may be [reloaded](LoadAdmission::reload) while the node runs.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use prometheus::core::Collector;
+use spacetimedb_client_api_messages::websocket::RetryHint;
+use spacetimedb_paths::server::ConfigToml;
+
+use super::ErrorFormat;
+use crate::config::{parse_config, watch_config_toml};
+use crate::worker_metrics::WORKER_METRICS;
+
+/// How often the load of the node is sampled, at most.
+///
+/// The busy ratio of the tokio workers is itself only updated every few seconds.
+pub const LOAD_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The load at or above which the node enters a [`LoadLevel`].
+///
+/// The node is loaded once it reaches any of the thresholds which are set.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct LoadThresholds {
+    /// The average busy ratio of the node's tokio workers, between 0 and 1.
+    ///
+    /// Only reported by nodes built with `tokio_unstable`, and ignored otherwise.
+    pub busy_ratio: Option<f64>,
+    /// The number of client messages waiting to be handled, across all clients of the node.
+    pub queued_messages: Option<u64>,
+}
+
+impl LoadThresholds {
+    fn is_set(&self) -> bool {
+        self.busy_ratio.is_some() || self.queued_messages.is_some()
+    }
+
+    /// Whether `load` reaches any of the thresholds, scaled by `scale`.
+    fn reached_by(&self, load: &NodeLoad, scale: f64) -> bool {
+        let busy = matches!((self.busy_ratio, load.busy_ratio), (Some(max), Some(busy)) if busy >= max * scale);
+        let queued = self
+            .queued_messages
+            .is_some_and(|max| load.queued_messages as f64 >= max as f64 * scale);
+        busy || queued
+    }
+}
+
+/// When the node sheds new work because of its load.
+///
+/// Read from the `[load-admission]` section of `config.toml`.
+/// No thresholds are set by default, so nothing is shed.
+#[serde_with::serde_as]
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct LoadAdmissionConfig {
+    /// The load at which new subscriptions are refused, see [`LoadLevel::ShedSubscriptions`].
+    pub shed_subscriptions: LoadThresholds,
+    /// The load at which new connections are refused too, see [`LoadLevel::ShedUpgrades`].
+    ///
+    /// Each threshold must be no lower than that of [`Self::shed_subscriptions`], if both are set.
+    pub shed_upgrades: LoadThresholds,
+    /// The fraction of its thresholds the load must fall below for the node to leave a level.
+    ///
+    /// Defaults to 0.8, and must be between 0 and 1, exclusive.
+    pub recovery_ratio: f64,
+    /// How long a client whose work was shed should wait before retrying.
+    ///
+    /// Defaults to 5s.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "retry-after-secs")]
+    pub retry_after: Duration,
+}
+
+impl Default for LoadAdmissionConfig {
+    fn default() -> Self {
+        Self {
+            shed_subscriptions: <_>::default(),
+            shed_upgrades: <_>::default(),
+            recovery_ratio: 0.8,
+            retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A rule of [`LoadAdmissionConfig::validate`] which a config breaks.
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum LoadAdmissionError {
+    #[error("`{level}.busy-ratio` of {value} is not between 0 and 1")]
+    BusyRatioOutOfBounds { level: &'static str, value: f64 },
+    #[error(
+        "`recovery-ratio` of {0} is not between 0 and 1, exclusive, so admission would flap around the thresholds"
+    )]
+    RecoveryRatioOutOfBounds(f64),
+    #[error("`shed-upgrades.{name}` of {upgrades} is lower than `shed-subscriptions.{name}` of {subscriptions}, so connections would be shed before subscriptions")]
+    UpgradesBelowSubscriptions {
+        name: &'static str,
+        upgrades: f64,
+        subscriptions: f64,
+    },
+}
+
+/// The `config.toml` sections a [`LoadAdmissionConfig`] is read from.
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct LoadAdmissionConfigFile {
+    #[serde(default)]
+    load_admission: LoadAdmissionConfig,
+}
+
+impl LoadAdmissionConfig {
+    /// Read the config from the `config.toml` at `path`, returning `None` if there's no such file.
+    ///
+    /// The config isn't validated, see [`Self::validate`].
+    pub fn read(path: &Path) -> anyhow::Result<Option<Self>> {
+        Ok(parse_config::<LoadAdmissionConfigFile>(path)?.map(|file| file.load_admission))
+    }
+
+    /// Check that the thresholds can work together, returning the first rule they break, if any.
+    pub fn validate(&self) -> Result<(), LoadAdmissionError> {
+        for (level, thresholds) in [
+            ("shed-subscriptions", &self.shed_subscriptions),
+            ("shed-upgrades", &self.shed_upgrades),
+        ] {
+            if let Some(value) = thresholds.busy_ratio.filter(|ratio| !(0.0..=1.0).contains(ratio)) {
+                return Err(LoadAdmissionError::BusyRatioOutOfBounds { level, value });
+            }
+        }
+        if !(self.recovery_ratio > 0.0 && self.recovery_ratio < 1.0) {
+            return Err(LoadAdmissionError::RecoveryRatioOutOfBounds(self.recovery_ratio));
+        }
+
+        let (subscriptions, upgrades) = (&self.shed_subscriptions, &self.shed_upgrades);
+        let ordered = |name, subscriptions: Option<f64>, upgrades: Option<f64>| match (subscriptions, upgrades) {
+            (Some(subscriptions), Some(upgrades)) if upgrades < subscriptions => {
+                Err(LoadAdmissionError::UpgradesBelowSubscriptions {
+                    name,
+                    upgrades,
+                    subscriptions,
+                })
+            }
+            _ => Ok(()),
+        };
+        ordered("busy-ratio", subscriptions.busy_ratio, upgrades.busy_ratio)?;
+        let queued = |thresholds: &LoadThresholds| thresholds.queued_messages.map(|max| max as f64);
+        ordered("queued-messages", queued(subscriptions), queued(upgrades))?;
+        Ok(())
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.shed_subscriptions.is_set() || self.shed_upgrades.is_set()
+    }
+}
+
+/// The load of the node, as sampled from its worker metrics.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NodeLoad {
+    /// The average busy ratio of the node's tokio workers, if reported.
+    pub busy_ratio: Option<f64>,
+    /// The number of client messages waiting to be handled, across all clients of the node.
+    pub queued_messages: u64,
+}
+
+impl NodeLoad {
+    /// The current load, as last reported to [`WORKER_METRICS`].
+    pub fn sample() -> Self {
+        let busy_ratio = WORKER_METRICS
+            .tokio_busy_ratio_avg
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_gauge().get_value())
+            .reduce(f64::max);
+        let queued_messages: f64 = WORKER_METRICS
+            .total_incoming_queue_length
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_gauge().get_value())
+            .sum();
+        Self {
+            busy_ratio,
+            queued_messages: queued_messages.max(0.0) as u64,
+        }
+    }
+}
+
+/// The new work the node sheds because of its load, in increasing order of load.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoadLevel {
+    /// Nothing is shed.
+    #[default]
+    Normal,
+    /// New subscriptions are refused.
+    ShedSubscriptions,
+    /// New subscriptions and new connections are refused.
+    ShedUpgrades,
+}
+
+impl LoadLevel {
+    /// The level the node is at under `load`, having been at `self`.
+    ///
+    /// A level is entered once the load reaches its thresholds,
+    /// and kept until the load falls below their [recovery ratio](LoadAdmissionConfig::recovery_ratio).
+    fn next(self, config: &LoadAdmissionConfig, load: &NodeLoad) -> Self {
+        let level_of = |scale_of: &dyn Fn(Self) -> f64| {
+            if config.shed_upgrades.reached_by(load, scale_of(Self::ShedUpgrades)) {
+                Self::ShedUpgrades
+            } else if config
+                .shed_subscriptions
+                .reached_by(load, scale_of(Self::ShedSubscriptions))
+            {
+                Self::ShedSubscriptions
+            } else {
+                Self::Normal
+            }
+        };
+        // The levels at or below the current one are held onto down to the recovery ratio.
+        let held = level_of(&|level| if level <= self { config.recovery_ratio } else { 1.0 });
+        held.min(self).max(level_of(&|_| 1.0))
+    }
+}
+
+/// Returned by [`LoadAdmission::admit_subscription`] and [`LoadAdmission::admit_upgrade`]
+/// when new work should be shed.
+#[derive(thiserror::Error, Debug)]
+#[error("node is under load")]
+pub struct NodeUnderLoad {
+    /// How long the client should wait before retrying.
+    pub retry_after: Duration,
+}
+
+/// The error a subscription refused under load is answered with, encoded as JSON.
+#[derive(serde::Serialize)]
+struct UnderLoadError {
+    reason: &'static str,
+    retry: RetryHint,
+    retry_after_secs: u64,
+}
+
+impl NodeUnderLoad {
+    /// The message of the error a client whose subscription was refused is sent, formatted as `format`.
+    pub fn subscription_error(&self, format: ErrorFormat) -> Box<str> {
+        match format {
+            ErrorFormat::Legacy => "server under load, retry with backoff".into(),
+            ErrorFormat::Structured => serde_json::to_string(&UnderLoadError {
+                reason: "server under load",
+                retry: RetryHint::Backoff,
+                retry_after_secs: self.retry_after.as_secs(),
+            })
+            .unwrap()
+            .into(),
+        }
+    }
+}
+
+/// Decides whether the node takes on new subscriptions and connections, given its load.
+///
+/// Only valid configs are ever current.
+#[derive(Default)]
+pub struct LoadAdmission {
+    config: Mutex<Arc<LoadAdmissionConfig>>,
+    state: Mutex<LoadState>,
+}
+
+#[derive(Default)]
+struct LoadState {
+    level: LoadLevel,
+    /// When the load was last sampled, if it has been since the config was last replaced.
+    sampled_at: Option<Instant>,
+}
+
+impl LoadAdmission {
+    /// Admission by `config`, if it's valid.
+    pub fn new(config: LoadAdmissionConfig) -> Result<Self, LoadAdmissionError> {
+        config.validate()?;
+        log::info!("load admission: {config:?}");
+        Ok(Self {
+            config: Mutex::new(Arc::new(config)),
+            state: <_>::default(),
+        })
+    }
+
+    /// The current config.
+    pub fn config(&self) -> Arc<LoadAdmissionConfig> {
+        self.config.lock().clone()
+    }
+
+    /// Replace the current config with `config`, if it's valid.
+    ///
+    /// Otherwise, the current config is kept.
+    /// The load is sampled again against the new thresholds at the next check.
+    pub fn reload(&self, config: LoadAdmissionConfig) -> Result<(), LoadAdmissionError> {
+        config.validate()?;
+        log::info!("reloaded load admission: {config:?}");
+        *self.config.lock() = Arc::new(config);
+        self.state.lock().sampled_at = None;
+        Ok(())
+    }
+
+    /// Reload the config from `config_toml` whenever it's modified, for as long as `self` lives.
+    ///
+    /// Configs which can't be read or are invalid are logged and ignored.
+    pub fn watch(self: &Arc<Self>, config_toml: ConfigToml) {
+        watch_config_toml(self, config_toml, |this, config_toml| {
+            match LoadAdmissionConfig::read(config_toml.as_ref()) {
+                Ok(Some(config)) => {
+                    if let Err(e) = this.reload(config) {
+                        log::error!("not reloading load admission, keeping the current thresholds: {e}");
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("failed to read load admission from config.toml: {e:#}"),
+            }
+        });
+    }
+
+    /// The level the node is at, sampling its load if it hasn't been for [`LOAD_SAMPLE_INTERVAL`].
+    pub fn level(&self) -> LoadLevel {
+        let mut state = self.state.lock();
+        if state.sampled_at.is_none_or(|at| at.elapsed() >= LOAD_SAMPLE_INTERVAL) {
+            let config = self.config();
+            // Without thresholds, there's no need to look at the load at all.
+            let load = config.is_enabled().then(NodeLoad::sample).unwrap_or_default();
+            self.update(&mut state, &config, &load);
+        }
+        state.level
+    }
+
+    /// Move the node to the level it's at under `load`.
+    fn update(&self, state: &mut LoadState, config: &LoadAdmissionConfig, load: &NodeLoad) {
+        let level = state.level.next(config, load);
+        if level != state.level {
+            log::warn!(
+                "node load level changed from {:?} to {level:?} at {load:?}",
+                state.level
+            );
+        }
+        state.level = level;
+        state.sampled_at = Some(Instant::now());
+        WORKER_METRICS.load_admission_level.set(level as i64);
+    }
+
+    /// Decide whether a new subscription may be added, counting it as shed if not.
+    pub fn admit_subscription(&self) -> Result<(), NodeUnderLoad> {
+        if self.level() < LoadLevel::ShedSubscriptions {
+            return Ok(());
+        }
+        WORKER_METRICS.ws_load_shed_subscriptions.inc();
+        Err(self.under_load())
+    }
+
+    /// Decide whether a new connection may be accepted, counting it as shed if not.
+    ///
+    /// This is checked before the websocket upgrade, like [`ClientActorIndex::try_admit`](super::ClientActorIndex::try_admit).
+    pub fn admit_upgrade(&self) -> Result<(), NodeUnderLoad> {
+        if self.level() < LoadLevel::ShedUpgrades {
+            return Ok(());
+        }
+        WORKER_METRICS.ws_load_shed_upgrades.inc();
+        Err(self.under_load())
+    }
+
+    fn under_load(&self) -> NodeUnderLoad {
+        NodeUnderLoad {
+            retry_after: self.config().retry_after,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(queued_messages: u64) -> NodeLoad {
+        NodeLoad {
+            busy_ratio: None,
+            queued_messages,
+        }
+    }
+
+    fn config() -> LoadAdmissionConfig {
+        LoadAdmissionConfig {
+            shed_subscriptions: LoadThresholds {
+                busy_ratio: Some(0.8),
+                queued_messages: Some(1000),
+            },
+            shed_upgrades: LoadThresholds {
+                busy_ratio: Some(0.95),
+                queued_messages: Some(5000),
+            },
+            recovery_ratio: 0.5,
+            ..<_>::default()
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid_and_shed_nothing() {
+        let config = LoadAdmissionConfig::default();
+        assert_eq!(config.validate(), Ok(()));
+        let load = NodeLoad {
+            busy_ratio: Some(1.0),
+            queued_messages: u64::MAX,
+        };
+        assert_eq!(LoadLevel::Normal.next(&config, &load), LoadLevel::Normal);
+        assert!(LoadAdmission::default().admit_upgrade().is_ok());
+    }
+
+    #[test]
+    fn levels_are_left_only_below_the_recovery_ratio() {
+        let config = config();
+        let mut level = LoadLevel::Normal;
+        let mut levels = vec![];
+        for queued_messages in [999, 1000, 600, 500, 5000, 4000, 2500, 2499, 499] {
+            level = level.next(&config, &queued(queued_messages));
+            levels.push(level);
+        }
+        use LoadLevel::*;
+        assert_eq!(
+            levels,
+            [
+                Normal,
+                ShedSubscriptions,
+                // Below the threshold, but not below half of it.
+                ShedSubscriptions,
+                ShedSubscriptions,
+                ShedUpgrades,
+                ShedUpgrades,
+                ShedUpgrades,
+                // Below half of the upgrade threshold, yet still above the subscription threshold.
+                ShedSubscriptions,
+                Normal,
+            ]
+        );
+    }
+
+    #[test]
+    fn any_signal_reaching_a_threshold_is_load() {
+        let config = config();
+        let busy = |busy_ratio| NodeLoad {
+            busy_ratio: Some(busy_ratio),
+            queued_messages: 0,
+        };
+        assert_eq!(LoadLevel::Normal.next(&config, &busy(0.79)), LoadLevel::Normal);
+        assert_eq!(LoadLevel::Normal.next(&config, &busy(0.96)), LoadLevel::ShedUpgrades);
+        assert_eq!(
+            LoadLevel::ShedUpgrades.next(&config, &busy(0.45)),
+            LoadLevel::ShedSubscriptions
+        );
+        assert_eq!(LoadLevel::ShedUpgrades.next(&config, &busy(0.3)), LoadLevel::Normal);
+        // Nodes which don't report their busy ratio are judged by their queues alone.
+        assert_eq!(
+            LoadLevel::Normal.next(&config, &queued(1000)),
+            LoadLevel::ShedSubscriptions
+        );
+    }
+
+    #[test]
+    fn shedding_is_counted_by_kind() {
+        let admission = LoadAdmission::new(config()).unwrap();
+        let mut state = admission.state.lock();
+        admission.update(&mut state, &config(), &queued(1000));
+        drop(state);
+
+        let subscriptions = WORKER_METRICS.ws_load_shed_subscriptions.get();
+        let upgrades = WORKER_METRICS.ws_load_shed_upgrades.get();
+        assert!(admission.admit_upgrade().is_ok());
+        let err = admission.admit_subscription().unwrap_err();
+        assert_eq!(err.retry_after, Duration::from_secs(5));
+        assert!(WORKER_METRICS.ws_load_shed_subscriptions.get() > subscriptions);
+        assert!(WORKER_METRICS.ws_load_shed_upgrades.get() >= upgrades);
+
+        let payload: serde_json::Value =
+            serde_json::from_str(&err.subscription_error(ErrorFormat::Structured)).unwrap();
+        assert_eq!(payload["reason"], "server under load");
+        assert_eq!(payload["retry"], "backoff");
+        assert_eq!(payload["retry_after_secs"], 5);
+        assert_eq!(
+            &*err.subscription_error(ErrorFormat::Legacy),
+            "server under load, retry with backoff"
+        );
+    }
+
+    #[test]
+    fn reloading_applies_the_new_thresholds_at_the_next_check() {
+        let always = LoadAdmissionConfig {
+            shed_upgrades: LoadThresholds {
+                queued_messages: Some(0),
+                ..<_>::default()
+            },
+            ..<_>::default()
+        };
+        let admission = LoadAdmission::default();
+        assert_eq!(admission.level(), LoadLevel::Normal);
+        admission.reload(always).unwrap();
+        assert_eq!(admission.level(), LoadLevel::ShedUpgrades);
+        assert!(admission.admit_upgrade().is_err());
+        assert!(admission.admit_subscription().is_err());
+
+        // Invalid configs are refused, keeping the current one.
+        let invalid = LoadAdmissionConfig {
+            recovery_ratio: 1.0,
+            ..<_>::default()
+        };
+        assert_eq!(
+            admission.reload(invalid),
+            Err(LoadAdmissionError::RecoveryRatioOutOfBounds(1.0))
+        );
+        assert_eq!(admission.config().shed_upgrades.queued_messages, Some(0));
+
+        admission.reload(LoadAdmissionConfig::default()).unwrap();
+        assert_eq!(admission.level(), LoadLevel::Normal);
+    }
+
+    #[test]
+    fn upgrades_are_shed_no_earlier_than_subscriptions() {
+        let config = LoadAdmissionConfig {
+            shed_upgrades: LoadThresholds {
+                queued_messages: Some(10),
+                ..<_>::default()
+            },
+            ..config()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(LoadAdmissionError::UpgradesBelowSubscriptions {
+                name: "queued-messages",
+                upgrades: 10.0,
+                subscriptions: 1000.0,
+            })
+        );
+        let config = LoadAdmissionConfig {
+            shed_subscriptions: LoadThresholds {
+                busy_ratio: Some(1.5),
+                ..<_>::default()
+            },
+            ..<_>::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(LoadAdmissionError::BusyRatioOutOfBounds {
+                level: "shed-subscriptions",
+                value: 1.5,
+            })
+        );
+    }
+}
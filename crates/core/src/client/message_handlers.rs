@@ -1,16 +1,23 @@
-use super::messages::{SubscriptionUpdateMessage, SwitchedServerMessage, ToProtocol, TransactionUpdateMessage};
-use super::{ClientConnection, DataMessage, Protocol};
+use super::messages::{
+    RequestErrorMessage, SettingsUpdatedMessage, SwitchedServerMessage, ToProtocol, TransactionUpdateMessage,
+    UpdateDetail,
+};
+use super::{ClientConnection, DataMessage, DecodedMessage, Protocol};
 use crate::energy::EnergyQuanta;
 use crate::execution_context::WorkloadType;
+use crate::host::module_host::ClientConnectedError;
 use crate::host::module_host::{EventStatus, ModuleEvent, ModuleFunctionCall};
-use crate::host::{ReducerArgs, ReducerId};
+use crate::host::{ReducerCallError, ReducerId};
 use crate::identity::Identity;
-use crate::messages::websocket::{CallReducer, ClientMessage, OneOffQuery};
+use crate::messages::websocket::{
+    Ack, Authenticate, CallReducer, ClientMessage, GetConnectionStatus, GrantCredits, OneOffQuery, Query, RefreshToken,
+    RequestErrorCode, UpdateSettings,
+};
 use crate::worker_metrics::WORKER_METRICS;
-use spacetimedb_lib::de::serde::DeserializeWrapper;
+use parking_lot::Mutex;
+use spacetimedb_client_api_messages::e2e;
 use spacetimedb_lib::identity::RequestId;
 use spacetimedb_lib::{bsatn, ConnectionId, Timestamp};
-use std::borrow::Cow;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -20,35 +27,139 @@ pub enum MessageHandleError {
     BinaryDecode(#[from] bsatn::DecodeError),
     #[error(transparent)]
     TextDecode(#[from] serde_json::Error),
+    /// A text frame which isn't valid UTF-8.
+    #[error("text frame is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
     #[error(transparent)]
     Base64Decode(#[from] base64::DecodeError),
+    /// The message couldn't be opened with the keys of an end-to-end encrypted connection.
+    #[error(transparent)]
+    Decrypt(#[from] e2e::E2eError),
+    #[error("unsupported message type `{message_type}`, this server supports protocol `{protocol}`")]
+    UnsupportedMessage {
+        message_type: String,
+        protocol: &'static str,
+    },
 
     #[error(transparent)]
     Execution(#[from] MessageExecutionError),
+    /// The client re-authenticated as an identity the module refused to connect.
+    #[error(transparent)]
+    ClientConnected(#[from] ClientConnectedError),
+}
+
+impl MessageHandleError {
+    /// The protocol violation of the client which sent the message, if it was one,
+    /// rather than a failure to execute a well-formed message.
+    pub fn violation(&self) -> Option<ProtocolViolation> {
+        match self {
+            Self::BinaryDecode(_)
+            | Self::TextDecode(_)
+            | Self::InvalidUtf8(_)
+            | Self::Base64Decode(_)
+            | Self::Decrypt(_) => Some(ProtocolViolation::Decode),
+            Self::UnsupportedMessage { .. } => Some(ProtocolViolation::ProtocolMismatch),
+            Self::Execution(_) | Self::ClientConnected(_) => None,
+        }
+    }
+
+    /// The [`RequestErrorMessage`] telling the client of this error, if it's one it can recover from.
+    ///
+    /// Errors it can't, e.g. speaking another protocol than the one negotiated, close the connection,
+    /// and failures to execute a message which have no [`RequestErrorCode`]
+    /// are replied to like a failed reducer call.
+    pub fn request_error(&self) -> Option<RequestErrorMessage> {
+        let (code, request_id) = match self {
+            Self::BinaryDecode(_) | Self::TextDecode(_) | Self::InvalidUtf8(_) | Self::Base64Decode(_) => {
+                (RequestErrorCode::DecodeFailure, None)
+            }
+            Self::Execution(err) => (err.request_error_code()?, err.request_id),
+            Self::Decrypt(_) | Self::UnsupportedMessage { .. } | Self::ClientConnected(_) => return None,
+        };
+        let message = match self {
+            Self::Execution(err) => format!("{:#}", err.err),
+            _ => format!("{self:#}"),
+        };
+        Some(RequestErrorMessage {
+            code,
+            request_id,
+            message: message.into(),
+        })
+    }
 }
 
-pub async fn handle(client: &ClientConnection, message: DataMessage, timer: Instant) -> Result<(), MessageHandleError> {
+/// The ways in which a client can violate the websocket protocol,
+/// as counted against its connection and in metrics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum ProtocolViolation {
+    /// The client sent a message which couldn't be decoded.
+    Decode,
+    /// The client sent a message of a type its protocol doesn't support.
+    ProtocolMismatch,
+    /// The client sent a message larger than it may.
+    Oversized,
+}
+
+/// What's left to do after a message was handled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HandleOutcome {
+    Handled,
+    /// The message was handled, but is of a legacy kind, named `kind`,
+    /// which the caller may want to tell the client to stop sending.
+    HandledLegacy {
+        kind: &'static str,
+    },
+    /// The client asked to re-authenticate with `token`,
+    /// see [`ClientConnection::reauthenticate`].
+    ///
+    /// Validating the token is left to the caller,
+    /// which must do so before handling any further messages from the client.
+    Authenticate {
+        token: Box<str>,
+    },
+    /// The client asked to replace its token with `token`, for the same identity, in reply to `request_id`,
+    /// see [`ClientConnection::set_auth_expiry`].
+    ///
+    /// As for [`HandleOutcome::Authenticate`], validating the token is left to the caller.
+    RefreshToken {
+        request_id: u32,
+        token: Box<str>,
+    },
+    /// The client asked for the status of its connection, in reply to `request_id`.
+    ///
+    /// Much of the status is known only to the caller, e.g. the websocket's queues,
+    /// so answering is left to it.
+    GetConnectionStatus {
+        request_id: u32,
+    },
+    /// The client granted `credits` to spend on sending it updates.
+    ///
+    /// The credits are kept by the caller, which sends the client its updates,
+    /// so granting them is left to it.
+    GrantCredits {
+        credits: u64,
+    },
+}
+
+pub async fn handle(
+    client: &ClientConnection,
+    message: DataMessage,
+    timer: Instant,
+) -> Result<HandleOutcome, MessageHandleError> {
     client.observe_websocket_request_message(&message);
 
-    let message = match message {
-        DataMessage::Text(text) => {
-            // TODO(breaking): this should ideally be &serde_json::RawValue, not json-nested-in-string
-            let DeserializeWrapper(message) =
-                serde_json::from_str::<DeserializeWrapper<ClientMessage<Cow<str>>>>(&text)?;
-            message.map_args(|s| {
-                ReducerArgs::Json(match s {
-                    Cow::Borrowed(s) => text.slice_ref(s),
-                    Cow::Owned(string) => string.into(),
-                })
-            })
-        }
-        DataMessage::Binary(message_buf) => bsatn::from_slice::<ClientMessage<&[u8]>>(&message_buf)?
-            .map_args(|b| ReducerArgs::Bsatn(message_buf.slice_ref(b))),
-    };
+    let DecodedMessage {
+        message,
+        unknown_fields,
+    } = client.codec().decode(message)?;
 
     let mod_info = client.module.info();
     let mod_metrics = &mod_info.metrics;
     let database_identity = mod_info.database_identity;
+    if !unknown_fields.is_empty() {
+        report_unknown_fields(&database_identity, &unknown_fields);
+    }
     let db = &client.module.replica_ctx().relational_db;
     let record_metrics = |wl| {
         move |metrics| {
@@ -60,14 +171,45 @@ pub async fn handle(client: &ClientConnection, message: DataMessage, timer: Inst
     let sub_metrics = record_metrics(WorkloadType::Subscribe);
     let unsub_metrics = record_metrics(WorkloadType::Unsubscribe);
 
+    // Failures to call a reducer, to update the connection's settings, or to run a `Query`,
+    // are replied to like a reducer failing, so they must reference the request they're replying to.
+    let request_id = match &message {
+        ClientMessage::CallReducer(call) => Some(call.request_id),
+        ClientMessage::UpdateSettings(update) => Some(update.request_id),
+        ClientMessage::Query(query) => Some(query.request_id),
+        _ => None,
+    };
+    let outcome = match &message {
+        ClientMessage::Subscribe(_) => HandleOutcome::HandledLegacy { kind: "Subscribe" },
+        _ => HandleOutcome::Handled,
+    };
+    // New subscriptions are refused while the node is under load,
+    // leaving existing subscriptions and other requests, e.g. reducer calls, alone.
+    let new_subscription = match &message {
+        ClientMessage::Subscribe(subscription) => Some((subscription.request_id, None)),
+        ClientMessage::SubscribeMulti(subscription) => Some((subscription.request_id, Some(subscription.query_id))),
+        ClientMessage::SubscribeSingle(subscription) => Some((subscription.request_id, Some(subscription.query_id))),
+        ClientMessage::SubscribeWindow(subscription) => Some((subscription.request_id, Some(subscription.query_id))),
+        _ => None,
+    };
+    if let Some((request_id, query_id)) = new_subscription {
+        if client.shed_subscription(request_id, query_id, timer) {
+            return Ok(outcome);
+        }
+    }
     let res = match message {
         ClientMessage::CallReducer(CallReducer {
             ref reducer,
             args,
             request_id,
             flags,
+            traceparent,
         }) => {
-            let res = client.call_reducer(reducer, args, request_id, timer, flags).await;
+            // A malformed traceparent starts a new trace, rather than failing the call.
+            let traceparent = traceparent.and_then(|traceparent| traceparent.parse().ok());
+            let res = client
+                .call_reducer(reducer, args, request_id, timer, flags, traceparent)
+                .await;
             WORKER_METRICS
                 .request_round_trip
                 .with_label_values(&WorkloadType::Reducer, &database_identity, reducer)
@@ -87,6 +229,16 @@ pub async fn handle(client: &ClientConnection, message: DataMessage, timer: Inst
                 .observe(timer.elapsed().as_secs_f64());
             res.map_err(|e| (None, None, e.into()))
         }
+        ClientMessage::ExplainSubscribeMulti(subscription) => {
+            let res = client
+                .explain_subscribe_multi(subscription, timer)
+                .await
+                .map(sub_metrics);
+            mod_metrics
+                .request_round_trip_subscribe
+                .observe(timer.elapsed().as_secs_f64());
+            res.map_err(|e| (None, None, e.into()))
+        }
         ClientMessage::UnsubscribeMulti(request) => {
             let res = client.unsubscribe_multi(request, timer).await.map(unsub_metrics);
             mod_metrics
@@ -101,6 +253,13 @@ pub async fn handle(client: &ClientConnection, message: DataMessage, timer: Inst
                 .observe(timer.elapsed().as_secs_f64());
             res.map_err(|e| (None, None, e.into()))
         }
+        ClientMessage::SubscribeWindow(subscription) => {
+            let res = client.subscribe_window(subscription, timer).await.map(sub_metrics);
+            mod_metrics
+                .request_round_trip_subscribe
+                .observe(timer.elapsed().as_secs_f64());
+            res.map_err(|e| (None, None, e.into()))
+        }
         ClientMessage::Unsubscribe(request) => {
             let res = client.unsubscribe(request, timer).await.map(unsub_metrics);
             mod_metrics
@@ -119,7 +278,7 @@ pub async fn handle(client: &ClientConnection, message: DataMessage, timer: Inst
             query_string: query,
             message_id,
         }) => {
-            let res = match client.config.protocol {
+            let res = match client.config().protocol {
                 Protocol::Binary => client.one_off_query_bsatn(&query, &message_id, timer).await,
                 Protocol::Text => client.one_off_query_json(&query, &message_id, timer).await,
             };
@@ -128,16 +287,87 @@ pub async fn handle(client: &ClientConnection, message: DataMessage, timer: Inst
                 .observe(timer.elapsed().as_secs_f64());
             res.map_err(|err| (None, None, err))
         }
+        ClientMessage::Query(Query {
+            request_id,
+            query_string: query,
+        }) => {
+            let res = match client.config().protocol {
+                Protocol::Binary => client.query_bsatn(&query, request_id, timer).await,
+                Protocol::Text => client.query_json(&query, request_id, timer).await,
+            };
+            mod_metrics
+                .request_round_trip_sql
+                .observe(timer.elapsed().as_secs_f64());
+            res.map_err(|err| (None, None, err))
+        }
+        ClientMessage::Authenticate(Authenticate { token }) => return Ok(HandleOutcome::Authenticate { token }),
+        ClientMessage::RefreshToken(RefreshToken { request_id, token }) => {
+            return Ok(HandleOutcome::RefreshToken { request_id, token })
+        }
+        ClientMessage::GetConnectionStatus(GetConnectionStatus { request_id }) => {
+            return Ok(HandleOutcome::GetConnectionStatus { request_id })
+        }
+        ClientMessage::GrantCredits(GrantCredits { credits }) => return Ok(HandleOutcome::GrantCredits { credits }),
+        ClientMessage::Ack(Ack { delivery_id }) => {
+            if let Err(e) = client.acknowledge(delivery_id) {
+                log::debug!("ignoring ack of delivery {delivery_id} from {}: {e}", client.id);
+                WORKER_METRICS
+                    .ws_ignored_acks
+                    .with_label_values(&database_identity, e.as_str())
+                    .inc();
+            }
+            return Ok(HandleOutcome::Handled);
+        }
+        ClientMessage::UpdateSettings(update) => match client.update_settings(&update) {
+            Ok(config) => {
+                let UpdateSettings { request_id, .. } = update;
+                let ack = SettingsUpdatedMessage {
+                    request_id,
+                    compression: config.compression,
+                    tx_update_full: config.tx_update_full,
+                    compression_threshold: config.compression_threshold as u64,
+                };
+                client.send_message(ack).map_err(|e| (None, None, e.into()))
+            }
+            Err(e) => {
+                let rejected = RequestRejected {
+                    code: RequestErrorCode::InvalidSettings,
+                    message: e.to_string(),
+                };
+                Err((None, None, rejected.into()))
+            }
+        },
     };
     res.map_err(|(reducer, reducer_id, err)| MessageExecutionError {
         reducer: reducer.cloned(),
         reducer_id,
         caller_identity: client.id.identity,
         caller_connection_id: Some(client.id.connection_id),
+        request_id,
         err,
     })?;
 
-    Ok(())
+    Ok(outcome)
+}
+
+/// The minimum time between logs of unknown fields in client messages, across all clients.
+const UNKNOWN_FIELDS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+static LAST_UNKNOWN_FIELDS_LOG: Mutex<Option<Instant>> = parking_lot::const_mutex(None);
+
+/// Count the fields of a client message which were ignored because this server doesn't know them,
+/// e.g. because the client's SDK is newer than the server.
+fn report_unknown_fields(database_identity: &Identity, unknown_fields: &[String]) {
+    WORKER_METRICS
+        .client_message_unknown_fields
+        .with_label_values(database_identity)
+        .inc_by(unknown_fields.len() as u64);
+
+    let mut last_log = LAST_UNKNOWN_FIELDS_LOG.lock();
+    if last_log.is_none_or(|at| at.elapsed() >= UNKNOWN_FIELDS_LOG_INTERVAL) {
+        *last_log = Some(Instant::now());
+        log::debug!("ignoring unknown fields in client message to {database_identity}: {unknown_fields:?}");
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -147,11 +377,25 @@ pub struct MessageExecutionError {
     pub reducer_id: Option<ReducerId>,
     pub caller_identity: Identity,
     pub caller_connection_id: Option<ConnectionId>,
+    /// The ID of the request which failed, if it was a reducer call, an update of the connection's settings, or a `Query`.
+    pub request_id: Option<RequestId>,
     #[source]
     pub err: anyhow::Error,
 }
 
 impl MessageExecutionError {
+    /// The [`RequestErrorCode`] of this failure, if it's one the client can recover from.
+    pub fn request_error_code(&self) -> Option<RequestErrorCode> {
+        if let Some(err) = self.err.downcast_ref::<ReducerCallError>() {
+            return match err {
+                ReducerCallError::NoSuchReducer => Some(RequestErrorCode::NoSuchReducer),
+                ReducerCallError::Args(_) => Some(RequestErrorCode::ArgsMismatch),
+                _ => None,
+            };
+        }
+        self.err.downcast_ref::<RequestRejected>().map(|rejected| rejected.code)
+    }
+
     fn into_event(self) -> ModuleEvent {
         ModuleEvent {
             timestamp: Timestamp::now(),
@@ -165,19 +409,86 @@ impl MessageExecutionError {
             status: EventStatus::Failed(format!("{:#}", self.err)),
             energy_quanta_used: EnergyQuanta::ZERO,
             host_execution_duration: Duration::ZERO,
-            request_id: Some(RequestId::default()),
+            request_id: self.request_id,
             timer: None,
+            acknowledged: false,
+            trace_id: None,
         }
     }
 }
 
+/// A message which was refused without being handled, for a reason the client can recover from,
+/// e.g. as it sent messages faster than it may.
+///
+/// Carried by the [`MessageExecutionError`] the client is replied to with,
+/// so that it can be told apart by [`MessageExecutionError::request_error_code`].
+#[derive(thiserror::Error, Debug)]
+#[error("{message}")]
+pub struct RequestRejected {
+    pub code: RequestErrorCode,
+    pub message: String,
+}
+
 impl ToProtocol for MessageExecutionError {
     type Encoded = SwitchedServerMessage;
     fn to_protocol(self, protocol: super::Protocol) -> Self::Encoded {
-        TransactionUpdateMessage {
-            event: Some(Arc::new(self.into_event())),
-            database_update: SubscriptionUpdateMessage::default_for_protocol(protocol, None),
+        // No transaction was committed, so the update is stamped with when the message failed.
+        let event = self.into_event();
+        let failed_at = event.timestamp;
+        TransactionUpdateMessage::reply(Arc::new(event), failed_at, protocol, UpdateDetail::FULL, None)
+            .to_protocol(protocol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn execution_error(err: anyhow::Error, request_id: Option<RequestId>) -> MessageHandleError {
+        MessageExecutionError {
+            reducer: None,
+            reducer_id: None,
+            caller_identity: Identity::ZERO,
+            caller_connection_id: None,
+            request_id,
+            err,
         }
-        .to_protocol(protocol)
+        .into()
+    }
+
+    fn code(e: &MessageHandleError) -> Option<(RequestErrorCode, Option<RequestId>)> {
+        e.request_error().map(|error| (error.code, error.request_id))
+    }
+
+    #[test]
+    fn recoverable_errors_have_request_error_codes() {
+        let decode = bsatn::from_slice::<u32>(&[]).unwrap_err().into();
+        assert_eq!(code(&decode), Some((RequestErrorCode::DecodeFailure, None)));
+
+        let no_such_reducer = execution_error(ReducerCallError::NoSuchReducer.into(), Some(7));
+        assert_eq!(code(&no_such_reducer), Some((RequestErrorCode::NoSuchReducer, Some(7))));
+
+        let rejected = RequestRejected {
+            code: RequestErrorCode::RateLimited,
+            message: "too many messages".into(),
+        };
+        let rejected = execution_error(rejected.into(), None);
+        assert_eq!(code(&rejected), Some((RequestErrorCode::RateLimited, None)));
+        assert_eq!(&*rejected.request_error().unwrap().message, "too many messages");
+    }
+
+    #[test]
+    fn other_errors_have_no_request_error_codes() {
+        let failed = execution_error(anyhow::anyhow!("the query is invalid"), None);
+        assert_eq!(code(&failed), None);
+
+        let lifecycle = execution_error(ReducerCallError::NotClientCallable.into(), Some(1));
+        assert_eq!(code(&lifecycle), None);
+
+        let mismatch = MessageHandleError::UnsupportedMessage {
+            message_type: "CallReducer".into(),
+            protocol: "v2",
+        };
+        assert_eq!(code(&mismatch), None);
     }
 }
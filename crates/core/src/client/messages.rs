@@ -1,20 +1,18 @@
-use super::{ClientConfig, DataMessage, Protocol};
+use super::codec::{EncodeError, EncodeFailure};
+use super::{ClientConfig, DataMessage, Protocol, SizeHint};
 use crate::execution_context::WorkloadType;
 use crate::host::module_host::{EventStatus, ModuleEvent};
 use crate::host::ArgsTuple;
 use crate::messages::websocket as ws;
 use bytes::{BufMut, Bytes, BytesMut};
-use bytestring::ByteString;
 use derive_more::From;
+use parking_lot::Mutex;
 use spacetimedb_client_api_messages::websocket::{
-    BsatnFormat, Compression, FormatSwitch, JsonFormat, OneOffTable, RowListLen, WebsocketFormat,
-    SERVER_MSG_COMPRESSION_TAG_BROTLI, SERVER_MSG_COMPRESSION_TAG_GZIP, SERVER_MSG_COMPRESSION_TAG_NONE,
+    BsatnFormat, ByteListLen, Compression, FormatSwitch, JsonFormat, OneOffTable, RowListLen, WebsocketFormat,
 };
 use spacetimedb_lib::identity::RequestId;
-use spacetimedb_lib::ser::serde::SerializeWrapper;
-use spacetimedb_lib::{ConnectionId, TimeDuration};
+use spacetimedb_lib::{ConnectionId, Identity, TimeDuration, Timestamp};
 use spacetimedb_primitives::TableId;
-use spacetimedb_sats::bsatn;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -26,7 +24,7 @@ pub trait ToProtocol {
     fn to_protocol(self, protocol: Protocol) -> Self::Encoded;
 }
 
-pub(super) type SwitchedServerMessage = FormatSwitch<ws::ServerMessage<BsatnFormat>, ws::ServerMessage<JsonFormat>>;
+pub type SwitchedServerMessage = FormatSwitch<ws::ServerMessage<BsatnFormat>, ws::ServerMessage<JsonFormat>>;
 pub(super) type SwitchedDbUpdate = FormatSwitch<ws::DatabaseUpdate<BsatnFormat>, ws::DatabaseUpdate<JsonFormat>>;
 
 /// The initial size of a `serialize` buffer.
@@ -34,9 +32,9 @@ pub(super) type SwitchedDbUpdate = FormatSwitch<ws::DatabaseUpdate<BsatnFormat>,
 /// and this should be more than enough in the common case.
 const SERIALIZE_BUFFER_INIT_CAP: usize = 4096;
 
-/// A buffer used by [`serialize`]
+/// A buffer used by [`ProtocolCodec::encode`](super::ProtocolCodec::encode)
 pub struct SerializeBuffer {
-    uncompressed: BytesMut,
+    pub(super) uncompressed: BytesMut,
     compressed: BytesMut,
 }
 
@@ -55,7 +53,7 @@ impl SerializeBuffer {
     }
 
     /// Take the uncompressed message as the one to use.
-    fn uncompressed(self) -> (InUseSerializeBuffer, Bytes) {
+    pub(super) fn uncompressed(self) -> (InUseSerializeBuffer, Bytes) {
         let uncompressed = self.uncompressed.freeze();
         let in_use = InUseSerializeBuffer::Uncompressed {
             uncompressed: uncompressed.clone(),
@@ -65,17 +63,25 @@ impl SerializeBuffer {
     }
 
     /// Write uncompressed data with a leading tag.
-    fn write_with_tag<F>(&mut self, tag: u8, write: F) -> &[u8]
+    pub(super) fn write_with_tag<F, E>(&mut self, tag: u8, write: F) -> Result<&[u8], E>
     where
-        F: FnOnce(bytes::buf::Writer<&mut BytesMut>),
+        F: FnOnce(bytes::buf::Writer<&mut BytesMut>) -> Result<(), E>,
     {
         self.uncompressed.put_u8(tag);
-        write((&mut self.uncompressed).writer());
-        &self.uncompressed[1..]
+        write((&mut self.uncompressed).writer())?;
+        Ok(&self.uncompressed[1..])
+    }
+
+    /// Discard whatever was written of a message which failed to encode with `error`,
+    /// so that the buffer can be reused.
+    pub(super) fn failed(mut self, error: EncodeError) -> EncodeFailure {
+        self.uncompressed.clear();
+        self.compressed.clear();
+        EncodeFailure { buffer: self, error }
     }
 
     /// Compress the data from a `write_with_tag` call, and change the tag.
-    fn compress_with_tag(
+    pub(super) fn compress_with_tag(
         self,
         tag: u8,
         write: impl FnOnce(&[u8], &mut bytes::buf::Writer<BytesMut>),
@@ -92,16 +98,25 @@ impl SerializeBuffer {
     }
 }
 
-type BytesMutWriter<'a> = bytes::buf::Writer<&'a mut BytesMut>;
-
 pub enum InUseSerializeBuffer {
-    Uncompressed { uncompressed: Bytes, compressed: BytesMut },
-    Compressed { uncompressed: BytesMut, compressed: Bytes },
+    Uncompressed {
+        uncompressed: Bytes,
+        compressed: BytesMut,
+    },
+    Compressed {
+        uncompressed: BytesMut,
+        compressed: Bytes,
+    },
+    /// The buffer went unused, as the message's [`SharedEncoding`] was reused.
+    Unused(SerializeBuffer),
 }
 
 impl InUseSerializeBuffer {
+    /// Take back the buffer, unless the message encoded into it is still referred to,
+    /// e.g. by the [`SharedEncoding`] other clients reuse.
     pub fn try_reclaim(self) -> Option<SerializeBuffer> {
         let (mut uncompressed, mut compressed) = match self {
+            Self::Unused(buffer) => return Some(buffer),
             Self::Uncompressed {
                 uncompressed,
                 compressed,
@@ -120,41 +135,24 @@ impl InUseSerializeBuffer {
     }
 }
 
-/// Serialize `msg` into a [`DataMessage`] containing a [`ws::ServerMessage`].
+/// The encoding of a message broadcast to many clients,
+/// shared by those of them which would encode it identically,
+/// i.e. those with the same [`ClientConfig`] sent the same copy of the message.
 ///
-/// If `protocol` is [`Protocol::Binary`],
-/// the message will be conditionally compressed by this method according to `compression`.
-pub fn serialize(
-    mut buffer: SerializeBuffer,
-    msg: impl ToProtocol<Encoded = SwitchedServerMessage>,
-    config: ClientConfig,
-) -> (InUseSerializeBuffer, DataMessage) {
-    match msg.to_protocol(config.protocol) {
-        FormatSwitch::Json(msg) => {
-            let out: BytesMutWriter<'_> = (&mut buffer.uncompressed).writer();
-            serde_json::to_writer(out, &SerializeWrapper::new(msg))
-                .expect("should be able to json encode a `ServerMessage`");
-
-            let (in_use, out) = buffer.uncompressed();
-            // SAFETY: `serde_json::to_writer` states that:
-            // > "Serialization guarantees it only feeds valid UTF-8 sequences to the writer."
-            let msg_json = unsafe { ByteString::from_bytes_unchecked(out) };
-            (in_use, msg_json.into())
-        }
-        FormatSwitch::Bsatn(msg) => {
-            // First write the tag so that we avoid shifting the entire message at the end.
-            let srv_msg = buffer.write_with_tag(SERVER_MSG_COMPRESSION_TAG_NONE, |w| {
-                bsatn::to_writer(w.into_inner(), &msg).unwrap()
-            });
+/// The first of them to encode the message keeps its encoding here for the rest to reuse,
+/// so the message is encoded and compressed once, rather than once for each of them.
+#[derive(Clone, Debug, Default)]
+pub struct SharedEncoding(Arc<Mutex<Option<DataMessage>>>);
 
-            // Conditionally compress the message.
-            let (in_use, msg_bytes) = match ws::decide_compression(srv_msg.len(), config.compression) {
-                Compression::None => buffer.uncompressed(),
-                Compression::Brotli => buffer.compress_with_tag(SERVER_MSG_COMPRESSION_TAG_BROTLI, ws::brotli_compress),
-                Compression::Gzip => buffer.compress_with_tag(SERVER_MSG_COMPRESSION_TAG_GZIP, ws::gzip_compress),
-            };
-            (in_use, msg_bytes.into())
-        }
+impl SharedEncoding {
+    /// The encoding kept by the first client to encode the message, if any has yet.
+    pub fn get(&self) -> Option<DataMessage> {
+        self.0.lock().clone()
+    }
+
+    /// Keep `encoded` for the other clients to reuse, unless one of them got there first.
+    pub fn set(&self, encoded: &DataMessage) {
+        self.0.lock().get_or_insert_with(|| encoded.clone());
     }
 }
 
@@ -162,27 +160,160 @@ pub fn serialize(
 pub enum SerializableMessage {
     QueryBinary(OneOffQueryResponseMessage<BsatnFormat>),
     QueryText(OneOffQueryResponseMessage<JsonFormat>),
+    QueryRowsBinary(QueryRowsMessage<BsatnFormat>),
+    QueryRowsText(QueryRowsMessage<JsonFormat>),
     Identity(IdentityTokenMessage),
+    DatabaseStats(DatabaseStatsMessage),
+    ReducerTimings(ReducerTimingsMessage),
+    ReconnectRequested(ReconnectRequestedMessage),
     Subscribe(SubscriptionUpdateMessage),
     Subscription(SubscriptionMessage),
     TxUpdate(TransactionUpdateMessage),
+    AcknowledgedUpdate(AcknowledgedUpdateMessage),
+    ConnectionStatus(ConnectionStatusMessage),
+    DeprecationNotice(DeprecationNoticeMessage),
+    CallProgress(CallProgressMessage),
+    ConnectionDegraded(ConnectionDegradedMessage),
+    ResyncRequired(ResyncRequiredMessage),
+    SettingsUpdated(SettingsUpdatedMessage),
+    TokenRefreshed(TokenRefreshedMessage),
+    Capabilities(ServerCapabilitiesMessage),
+}
+
+/// The kind of [`ws::ServerMessage`] a [`SerializableMessage`] is sent as,
+/// e.g. to tell apart the row counts of initial subscriptions and of updates in metrics.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, strum::AsRefStr, strum::IntoStaticStr)]
+pub enum MessageKind {
+    InitialSubscription,
+    TransactionUpdate,
+    TransactionUpdateLight,
+    IdentityToken,
+    OneOffQueryResponse,
+    SubscribeApplied,
+    UnsubscribeApplied,
+    SubscriptionError,
+    SubscribeMultiApplied,
+    UnsubscribeMultiApplied,
+    SubscribeMultiExplained,
+    DatabaseStats,
+    ReducerTimings,
+    ReconnectRequested,
+    AcknowledgedUpdate,
+    ConnectionStatus,
+    DeprecationNotice,
+    SubscribeWindowApplied,
+    CallProgress,
+    ConnectionDegraded,
+    ResyncRequired,
+    RequestError,
+    SettingsUpdated,
+    TokenRefreshed,
+    ServerCapabilities,
+    QueryRows,
 }
 
 impl SerializableMessage {
+    /// The encoding the message shares with those sent to other clients, if it's part of a broadcast.
+    pub fn shared_encoding(&self) -> Option<&SharedEncoding> {
+        match self {
+            Self::TxUpdate(msg) => msg.shared.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The number of rows the message carries, summed over all of its tables and queries,
+    /// counting both inserts and deletes,
+    /// or `None` if the message isn't meant to carry rows.
     pub fn num_rows(&self) -> Option<usize> {
         match self {
             Self::QueryBinary(msg) => Some(msg.num_rows()),
             Self::QueryText(msg) => Some(msg.num_rows()),
+            Self::QueryRowsBinary(msg) => Some(msg.rows.len()),
+            Self::QueryRowsText(msg) => Some(msg.rows.len()),
             Self::Subscribe(msg) => Some(msg.num_rows()),
-            Self::Subscription(msg) => Some(msg.num_rows()),
+            Self::Subscription(msg) => msg.num_rows(),
             Self::TxUpdate(msg) => Some(msg.num_rows()),
-            Self::Identity(_) => None,
+            Self::AcknowledgedUpdate(msg) => Some(msg.update.num_rows()),
+            Self::Identity(_)
+            | Self::DatabaseStats(_)
+            | Self::ReducerTimings(_)
+            | Self::ReconnectRequested(_)
+            | Self::ConnectionStatus(_)
+            | Self::DeprecationNotice(_)
+            | Self::CallProgress(_)
+            | Self::ConnectionDegraded(_)
+            | Self::ResyncRequired(_)
+            | Self::SettingsUpdated(_)
+            | Self::TokenRefreshed(_)
+            | Self::Capabilities(_) => None,
+        }
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Self::QueryBinary(_) | Self::QueryText(_) => MessageKind::OneOffQueryResponse,
+            Self::QueryRowsBinary(_) | Self::QueryRowsText(_) => MessageKind::QueryRows,
+            Self::Identity(_) => MessageKind::IdentityToken,
+            Self::DatabaseStats(_) => MessageKind::DatabaseStats,
+            Self::ReducerTimings(_) => MessageKind::ReducerTimings,
+            Self::ReconnectRequested(_) => MessageKind::ReconnectRequested,
+            Self::ConnectionStatus(_) => MessageKind::ConnectionStatus,
+            Self::DeprecationNotice(_) => MessageKind::DeprecationNotice,
+            Self::CallProgress(_) => MessageKind::CallProgress,
+            Self::ConnectionDegraded(_) => MessageKind::ConnectionDegraded,
+            Self::ResyncRequired(_) => MessageKind::ResyncRequired,
+            Self::SettingsUpdated(_) => MessageKind::SettingsUpdated,
+            Self::TokenRefreshed(_) => MessageKind::TokenRefreshed,
+            Self::Capabilities(_) => MessageKind::ServerCapabilities,
+            Self::Subscribe(_) => MessageKind::InitialSubscription,
+            Self::Subscription(msg) => match &msg.result {
+                SubscriptionResult::Subscribe(_) => MessageKind::SubscribeApplied,
+                SubscriptionResult::Unsubscribe(_) => MessageKind::UnsubscribeApplied,
+                SubscriptionResult::Error(_) => MessageKind::SubscriptionError,
+                SubscriptionResult::SubscribeMulti(_) => MessageKind::SubscribeMultiApplied,
+                SubscriptionResult::UnsubscribeMulti(_) => MessageKind::UnsubscribeMultiApplied,
+                SubscriptionResult::SubscribeWindow(_) => MessageKind::SubscribeWindowApplied,
+                SubscriptionResult::SubscribeMultiExplained(_) => MessageKind::SubscribeMultiExplained,
+            },
+            Self::TxUpdate(msg) => match msg.event {
+                Some(_) => MessageKind::TransactionUpdate,
+                None => MessageKind::TransactionUpdateLight,
+            },
+            Self::AcknowledgedUpdate(_) => MessageKind::AcknowledgedUpdate,
+        }
+    }
+
+    /// When the transaction which caused the message committed, if it's a transaction update.
+    pub fn committed_at(&self) -> Option<Timestamp> {
+        match self {
+            Self::TxUpdate(msg) => Some(msg.committed_at),
+            Self::AcknowledgedUpdate(msg) => Some(msg.update.committed_at),
+            Self::QueryBinary(_)
+            | Self::QueryText(_)
+            | Self::QueryRowsBinary(_)
+            | Self::QueryRowsText(_)
+            | Self::Identity(_)
+            | Self::DatabaseStats(_)
+            | Self::ReducerTimings(_)
+            | Self::ReconnectRequested(_)
+            | Self::Subscribe(_)
+            | Self::Subscription(_)
+            | Self::ConnectionStatus(_)
+            | Self::DeprecationNotice(_)
+            | Self::CallProgress(_)
+            | Self::ConnectionDegraded(_)
+            | Self::ResyncRequired(_)
+            | Self::SettingsUpdated(_)
+            | Self::TokenRefreshed(_)
+            | Self::Capabilities(_) => None,
         }
     }
 
     pub fn workload(&self) -> Option<WorkloadType> {
         match self {
-            Self::QueryBinary(_) | Self::QueryText(_) => Some(WorkloadType::Sql),
+            Self::QueryBinary(_) | Self::QueryText(_) | Self::QueryRowsBinary(_) | Self::QueryRowsText(_) => {
+                Some(WorkloadType::Sql)
+            }
             Self::Subscribe(_) => Some(WorkloadType::Subscribe),
             Self::Subscription(msg) => match &msg.result {
                 SubscriptionResult::Subscribe(_) => Some(WorkloadType::Subscribe),
@@ -190,23 +321,86 @@ impl SerializableMessage {
                 SubscriptionResult::Error(_) => None,
                 SubscriptionResult::SubscribeMulti(_) => Some(WorkloadType::Subscribe),
                 SubscriptionResult::UnsubscribeMulti(_) => Some(WorkloadType::Unsubscribe),
+                SubscriptionResult::SubscribeWindow(_) => Some(WorkloadType::Subscribe),
+                SubscriptionResult::SubscribeMultiExplained(_) => Some(WorkloadType::Subscribe),
             },
-            Self::TxUpdate(_) => Some(WorkloadType::Update),
-            Self::Identity(_) => None,
+            Self::TxUpdate(_) | Self::AcknowledgedUpdate(_) => Some(WorkloadType::Update),
+            Self::Identity(_)
+            | Self::DatabaseStats(_)
+            | Self::ReducerTimings(_)
+            | Self::ReconnectRequested(_)
+            | Self::ConnectionStatus(_)
+            | Self::DeprecationNotice(_)
+            | Self::CallProgress(_)
+            | Self::ConnectionDegraded(_)
+            | Self::ResyncRequired(_)
+            | Self::SettingsUpdated(_)
+            | Self::TokenRefreshed(_)
+            | Self::Capabilities(_) => None,
         }
     }
 }
 
+/// Estimated bytes a message takes up besides its rows.
+const MESSAGE_OVERHEAD_ESTIMATE: usize = 64;
+
+impl SizeHint for SerializableMessage {
+    fn size_hint(&self) -> usize {
+        let payload = match self {
+            Self::QueryBinary(msg) => msg.num_bytes(),
+            Self::QueryText(msg) => msg.num_bytes(),
+            Self::QueryRowsBinary(msg) => msg.table_name.len() + msg.rows.num_bytes(),
+            Self::QueryRowsText(msg) => msg.table_name.len() + msg.rows.num_bytes(),
+            Self::Identity(msg) => msg.token.len(),
+            Self::ReconnectRequested(msg) => msg.reconnect_token.as_ref().map_or(0, |token| token.len()),
+            Self::ConnectionStatus(msg) => msg.protocol.len() + msg.compression.len(),
+            Self::DeprecationNotice(msg) => msg.feature.len() + msg.message.len(),
+            Self::CallProgress(msg) => msg.payload.len(),
+            Self::ResyncRequired(msg) => msg.query_ids.len() * std::mem::size_of::<ws::QueryId>(),
+            Self::Capabilities(msg) => msg
+                .features
+                .iter()
+                .chain(&*msg.database_features)
+                .map(|name| name.len())
+                .sum(),
+            Self::DatabaseStats(_)
+            | Self::ReducerTimings(_)
+            | Self::ConnectionDegraded(_)
+            | Self::SettingsUpdated(_)
+            | Self::TokenRefreshed(_) => 0,
+            Self::Subscribe(msg) => msg.num_bytes(),
+            Self::Subscription(msg) => msg.num_bytes(),
+            Self::TxUpdate(msg) => msg.database_update.num_bytes(),
+            Self::AcknowledgedUpdate(msg) => msg.update.database_update.num_bytes(),
+        };
+        MESSAGE_OVERHEAD_ESTIMATE + payload
+    }
+}
+
 impl ToProtocol for SerializableMessage {
     type Encoded = SwitchedServerMessage;
     fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
         match self {
             SerializableMessage::QueryBinary(msg) => msg.to_protocol(protocol),
             SerializableMessage::QueryText(msg) => msg.to_protocol(protocol),
+            SerializableMessage::QueryRowsBinary(msg) => msg.to_protocol(protocol),
+            SerializableMessage::QueryRowsText(msg) => msg.to_protocol(protocol),
             SerializableMessage::Identity(msg) => msg.to_protocol(protocol),
+            SerializableMessage::DatabaseStats(msg) => msg.to_protocol(protocol),
+            SerializableMessage::ReducerTimings(msg) => msg.to_protocol(protocol),
+            SerializableMessage::ReconnectRequested(msg) => msg.to_protocol(protocol),
+            SerializableMessage::ConnectionStatus(msg) => msg.to_protocol(protocol),
+            SerializableMessage::DeprecationNotice(msg) => msg.to_protocol(protocol),
+            SerializableMessage::CallProgress(msg) => msg.to_protocol(protocol),
+            SerializableMessage::ConnectionDegraded(msg) => msg.to_protocol(protocol),
+            SerializableMessage::ResyncRequired(msg) => msg.to_protocol(protocol),
+            SerializableMessage::SettingsUpdated(msg) => msg.to_protocol(protocol),
+            SerializableMessage::TokenRefreshed(msg) => msg.to_protocol(protocol),
+            SerializableMessage::Capabilities(msg) => msg.to_protocol(protocol),
             SerializableMessage::Subscribe(msg) => msg.to_protocol(protocol),
             SerializableMessage::TxUpdate(msg) => msg.to_protocol(protocol),
             SerializableMessage::Subscription(msg) => msg.to_protocol(protocol),
+            SerializableMessage::AcknowledgedUpdate(msg) => msg.to_protocol(protocol),
         }
     }
 }
@@ -223,15 +417,276 @@ impl ToProtocol for IdentityTokenMessage {
     }
 }
 
-#[derive(Debug)]
+pub type ServerCapabilitiesMessage = ws::ServerCapabilities;
+
+impl ToProtocol for ServerCapabilitiesMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::ServerCapabilities(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::ServerCapabilities(self)),
+        }
+    }
+}
+
+pub type DatabaseStatsMessage = ws::DatabaseStats;
+
+impl ToProtocol for DatabaseStatsMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::DatabaseStats(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::DatabaseStats(self)),
+        }
+    }
+}
+
+pub type ReducerTimingsMessage = ws::ReducerTimings;
+
+impl ToProtocol for ReducerTimingsMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::ReducerTimings(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::ReducerTimings(self)),
+        }
+    }
+}
+
+pub type ReconnectRequestedMessage = ws::ReconnectRequested;
+
+impl ToProtocol for ReconnectRequestedMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::ReconnectRequested(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::ReconnectRequested(self)),
+        }
+    }
+}
+
+pub type ConnectionStatusMessage = ws::ConnectionStatus;
+
+impl ToProtocol for ConnectionStatusMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::ConnectionStatus(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::ConnectionStatus(self)),
+        }
+    }
+}
+
+pub type MessageDroppedMessage = ws::MessageDropped;
+
+impl ToProtocol for MessageDroppedMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::MessageDropped(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::MessageDropped(self)),
+        }
+    }
+}
+
+pub type DeprecationNoticeMessage = ws::DeprecationNotice;
+
+impl ToProtocol for DeprecationNoticeMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::DeprecationNotice(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::DeprecationNotice(self)),
+        }
+    }
+}
+
+pub type CallProgressMessage = ws::CallProgress;
+
+impl ToProtocol for CallProgressMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::CallProgress(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::CallProgress(self)),
+        }
+    }
+}
+
+pub type ConnectionDegradedMessage = ws::ConnectionDegraded;
+
+impl ToProtocol for ConnectionDegradedMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::ConnectionDegraded(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::ConnectionDegraded(self)),
+        }
+    }
+}
+
+pub type RequestErrorMessage = ws::RequestError;
+
+impl ToProtocol for RequestErrorMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::RequestError(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::RequestError(self)),
+        }
+    }
+}
+
+pub type SettingsUpdatedMessage = ws::SettingsUpdated;
+
+impl ToProtocol for SettingsUpdatedMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::SettingsUpdated(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::SettingsUpdated(self)),
+        }
+    }
+}
+
+pub type TokenRefreshedMessage = ws::TokenRefreshed;
+
+impl ToProtocol for TokenRefreshedMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::TokenRefreshed(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::TokenRefreshed(self)),
+        }
+    }
+}
+
+pub type ResyncRequiredMessage = ws::ResyncRequired;
+
+impl ToProtocol for ResyncRequiredMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        match protocol {
+            Protocol::Text => FormatSwitch::Json(ws::ServerMessage::ResyncRequired(self)),
+            Protocol::Binary => FormatSwitch::Bsatn(ws::ServerMessage::ResyncRequired(self)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct TransactionUpdateMessage {
     /// The event that caused this update.
     /// When `None`, this is a light update.
     pub event: Option<Arc<ModuleEvent>>,
     pub database_update: SubscriptionUpdateMessage,
+    /// When the transaction which caused this update committed, or rolled back,
+    /// according to the server's wall clock.
+    pub committed_at: Timestamp,
+    /// What a full update carries of its event, beyond its outcome.
+    pub detail: UpdateDetail,
+    /// The encoding of the update shared with the other clients sent the same copy of it, if any.
+    ///
+    /// Only ever set for clients with the same [`ClientConfig`],
+    /// and never for updates carrying anything of a client's own, e.g. replies to its calls.
+    pub shared: Option<SharedEncoding>,
+}
+
+/// What a full [`TransactionUpdateMessage`] carries of its event, beyond its outcome,
+/// as asked for by the client it's sent to.
+///
+/// What's left out is zeroed, as the fields of [`ws::TransactionUpdate`] aren't optional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateDetail {
+    /// Whether the update carries the arguments of the reducer call, and the identity and connection id of its caller,
+    /// see [`ClientConfig::include_caller_info`].
+    pub caller_info: bool,
+    /// Whether the update carries when the reducer ran, and for how long,
+    /// see [`ClientConfig::include_timings`].
+    pub timings: bool,
+}
+
+impl UpdateDetail {
+    /// Everything there is to carry.
+    pub const FULL: Self = Self {
+        caller_info: true,
+        timings: true,
+    };
+
+    /// The detail of the updates sent to a client with `config` for other clients' calls.
+    pub fn for_client(config: &ClientConfig) -> Self {
+        Self {
+            caller_info: config.include_caller_info,
+            timings: config.include_timings,
+        }
+    }
+
+    /// The detail of the replies sent to a client with `config` for its own calls,
+    /// which always carry the call, as the client's SDK needs it to run the call's callbacks.
+    pub fn for_reply(config: &ClientConfig) -> Self {
+        Self {
+            caller_info: true,
+            timings: config.include_timings,
+        }
+    }
 }
 
 impl TransactionUpdateMessage {
+    /// The reply to the caller of the reducer which caused `event`,
+    /// carrying `update`, the caller's own subscription updates, if any, and `detail` of the event.
+    ///
+    /// Every call a client makes gets exactly one reply,
+    /// which reports the outcome of the call regardless of [`ClientConfig::tx_update_full`],
+    /// as a light update has no room for it.
+    pub fn reply(
+        event: Arc<ModuleEvent>,
+        committed_at: Timestamp,
+        protocol: Protocol,
+        detail: UpdateDetail,
+        update: Option<SubscriptionUpdateMessage>,
+    ) -> Self {
+        let database_update =
+            update.unwrap_or_else(|| SubscriptionUpdateMessage::default_for_protocol(protocol, event.request_id));
+        Self {
+            event: Some(event),
+            database_update,
+            committed_at,
+            detail,
+            shared: None,
+        }
+    }
+
+    /// Fold `next`, the update following this one, into this one,
+    /// which becomes a light update carrying the rows of both,
+    /// as sent to a client which fell behind, see [`SendBacklogAction::Coalesce`].
+    ///
+    /// [`SendBacklogAction::Coalesce`]: crate::messages::control_db::SendBacklogAction::Coalesce
+    pub fn coalesce(&mut self, next: Self) {
+        fn extend<F: WebsocketFormat>(update: &mut ws::DatabaseUpdate<F>, next: ws::DatabaseUpdate<F>) {
+            for table in next.tables {
+                match update.tables.iter_mut().find(|t| t.table_id == table.table_id) {
+                    Some(existing) => {
+                        existing.num_rows += table.num_rows;
+                        existing.updates.extend(table.updates);
+                    }
+                    None => update.tables.push(table),
+                }
+            }
+        }
+
+        self.event = None;
+        self.shared = None;
+        self.database_update.request_id = None;
+        self.committed_at = next.committed_at;
+        match self
+            .database_update
+            .database_update
+            .zip_mut(next.database_update.database_update)
+        {
+            FormatSwitch::Bsatn((update, next)) => extend(update, next),
+            FormatSwitch::Json((update, next)) => extend(update, next),
+        }
+    }
+
     fn num_rows(&self) -> usize {
         self.database_update.num_rows()
     }
@@ -242,9 +697,12 @@ impl ToProtocol for TransactionUpdateMessage {
     fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
         fn convert<F: WebsocketFormat>(
             event: Option<Arc<ModuleEvent>>,
+            detail: UpdateDetail,
             request_id: u32,
             update: ws::DatabaseUpdate<F>,
             conv_args: impl FnOnce(&ArgsTuple) -> F::Single,
+            // What's sent in place of the arguments when they're left out, i.e. those of a call without any.
+            no_args: F::Single,
         ) -> ws::ServerMessage<F> {
             let Some(event) = event else {
                 return ws::ServerMessage::TransactionUpdateLight(ws::TransactionUpdateLight { request_id, update });
@@ -256,37 +714,95 @@ impl ToProtocol for TransactionUpdateMessage {
                 EventStatus::OutOfEnergy => ws::UpdateStatus::OutOfEnergy,
             };
 
-            let args = conv_args(&event.function_call.args);
+            let (args, caller_identity, caller_connection_id) = match detail.caller_info {
+                true => (
+                    conv_args(&event.function_call.args),
+                    event.caller_identity,
+                    event.caller_connection_id.unwrap_or(ConnectionId::ZERO),
+                ),
+                false => (no_args, Identity::ZERO, ConnectionId::ZERO),
+            };
+            let (timestamp, total_host_execution_duration) = match detail.timings {
+                true => (event.timestamp, event.host_execution_duration.into()),
+                false => (Timestamp::UNIX_EPOCH, TimeDuration::ZERO),
+            };
 
             let tx_update = ws::TransactionUpdate {
-                timestamp: event.timestamp,
+                timestamp,
                 status,
-                caller_identity: event.caller_identity,
+                caller_identity,
                 reducer_call: ws::ReducerCallInfo {
                     reducer_name: event.function_call.reducer.to_owned().into(),
                     reducer_id: event.function_call.reducer_id.into(),
                     args,
                     request_id,
+                    trace_id: event.trace_id.map(|trace_id| trace_id.to_string().into()),
                 },
                 energy_quanta_used: event.energy_quanta_used,
-                total_host_execution_duration: event.host_execution_duration.into(),
-                caller_connection_id: event.caller_connection_id.unwrap_or(ConnectionId::ZERO),
+                total_host_execution_duration,
+                caller_connection_id,
             };
 
             ws::ServerMessage::TransactionUpdate(tx_update)
         }
 
-        let TransactionUpdateMessage { event, database_update } = self;
+        let TransactionUpdateMessage {
+            event,
+            database_update,
+            detail,
+            ..
+        } = self;
         let update = database_update.database_update;
         protocol.assert_matches_format_switch(&update);
-        let request_id = database_update.request_id.unwrap_or(0);
+        // The request ID of a full update is part of the reply to the caller, i.e. of the event,
+        // rather than of the row updates it happens to carry.
+        let request_id = match &event {
+            Some(event) => event.request_id,
+            None => database_update.request_id,
+        }
+        .unwrap_or(0);
         match update {
-            FormatSwitch::Bsatn(update) => FormatSwitch::Bsatn(convert(event, request_id, update, |args| {
-                Vec::from(args.get_bsatn().clone()).into()
-            })),
-            FormatSwitch::Json(update) => {
-                FormatSwitch::Json(convert(event, request_id, update, |args| args.get_json().clone()))
-            }
+            FormatSwitch::Bsatn(update) => FormatSwitch::Bsatn(convert(
+                event,
+                detail,
+                request_id,
+                update,
+                |args| Vec::from(args.get_bsatn().clone()).into(),
+                <_>::default(),
+            )),
+            FormatSwitch::Json(update) => FormatSwitch::Json(convert(
+                event,
+                detail,
+                request_id,
+                update,
+                |args| args.get_json().clone(),
+                "[]".into(),
+            )),
+        }
+    }
+}
+
+/// A full [`TransactionUpdateMessage`] which the client must acknowledge,
+/// see [`ws::AcknowledgedUpdate`].
+#[derive(Debug, Clone)]
+pub struct AcknowledgedUpdateMessage {
+    pub delivery_id: u64,
+    pub update: TransactionUpdateMessage,
+}
+
+impl ToProtocol for AcknowledgedUpdateMessage {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, protocol: Protocol) -> Self::Encoded {
+        fn wrap<F: WebsocketFormat>(delivery_id: u64, msg: ws::ServerMessage<F>) -> ws::ServerMessage<F> {
+            let ws::ServerMessage::TransactionUpdate(update) = msg else {
+                unreachable!("an acknowledged update should be a full update")
+            };
+            ws::ServerMessage::AcknowledgedUpdate(ws::AcknowledgedUpdate { delivery_id, update })
+        }
+
+        match self.update.to_protocol(protocol) {
+            FormatSwitch::Bsatn(msg) => FormatSwitch::Bsatn(wrap(self.delivery_id, msg)),
+            FormatSwitch::Json(msg) => FormatSwitch::Json(wrap(self.delivery_id, msg)),
         }
     }
 }
@@ -324,6 +840,14 @@ impl SubscriptionUpdateMessage {
             FormatSwitch::Json(x) => x.num_rows(),
         }
     }
+
+    /// The bytes of the rows the update carries.
+    pub(crate) fn num_bytes(&self) -> usize {
+        match &self.database_update {
+            FormatSwitch::Bsatn(x) => x.num_bytes(),
+            FormatSwitch::Json(x) => x.num_bytes(),
+        }
+    }
 }
 
 impl ToProtocol for SubscriptionUpdateMessage {
@@ -364,6 +888,20 @@ pub struct SubscriptionRows {
     pub table_rows: FormatSwitch<ws::TableUpdate<BsatnFormat>, ws::TableUpdate<JsonFormat>>,
 }
 
+/// The initial rows of an explained subscription, and the plans chosen for its queries.
+#[derive(Debug, Clone)]
+pub struct ExplainedSubscriptionData {
+    pub data: SubscriptionData,
+    pub plans: Box<[Box<str>]>,
+}
+
+/// The rows in the window of a windowed subscription, and the window itself.
+#[derive(Debug, Clone)]
+pub struct SubscriptionWindowRows {
+    pub rows: SubscriptionRows,
+    pub window: ws::AppliedWindow,
+}
+
 #[derive(Debug, Clone)]
 pub struct SubscriptionError {
     pub table_id: Option<TableId>,
@@ -377,6 +915,8 @@ pub enum SubscriptionResult {
     Error(SubscriptionError),
     SubscribeMulti(SubscriptionData),
     UnsubscribeMulti(SubscriptionData),
+    SubscribeWindow(SubscriptionWindowRows),
+    SubscribeMultiExplained(ExplainedSubscriptionData),
 }
 
 #[derive(Debug, Clone)]
@@ -402,13 +942,41 @@ fn subscription_data_rows(rows: &SubscriptionData) -> usize {
 }
 
 impl SubscriptionMessage {
-    fn num_rows(&self) -> usize {
+    fn num_rows(&self) -> Option<usize> {
+        match &self.result {
+            SubscriptionResult::Subscribe(x) | SubscriptionResult::Unsubscribe(x) => Some(num_rows_in(x)),
+            SubscriptionResult::SubscribeWindow(x) => Some(num_rows_in(&x.rows)),
+            SubscriptionResult::SubscribeMulti(x) | SubscriptionResult::UnsubscribeMulti(x) => {
+                Some(subscription_data_rows(x))
+            }
+            SubscriptionResult::SubscribeMultiExplained(x) => Some(subscription_data_rows(&x.data)),
+            SubscriptionResult::Error(_) => None,
+        }
+    }
+
+    fn num_bytes(&self) -> usize {
         match &self.result {
-            SubscriptionResult::Subscribe(x) => num_rows_in(x),
-            SubscriptionResult::SubscribeMulti(x) => subscription_data_rows(x),
-            SubscriptionResult::UnsubscribeMulti(x) => subscription_data_rows(x),
-            SubscriptionResult::Unsubscribe(x) => num_rows_in(x),
-            _ => 0,
+            SubscriptionResult::Subscribe(x) | SubscriptionResult::Unsubscribe(x) => match &x.table_rows {
+                FormatSwitch::Bsatn(x) => x.num_bytes(),
+                FormatSwitch::Json(x) => x.num_bytes(),
+            },
+            SubscriptionResult::SubscribeWindow(x) => match &x.rows.table_rows {
+                FormatSwitch::Bsatn(x) => x.num_bytes(),
+                FormatSwitch::Json(x) => x.num_bytes(),
+            },
+            SubscriptionResult::SubscribeMulti(x) | SubscriptionResult::UnsubscribeMulti(x) => match &x.data {
+                FormatSwitch::Bsatn(x) => x.num_bytes(),
+                FormatSwitch::Json(x) => x.num_bytes(),
+            },
+            SubscriptionResult::SubscribeMultiExplained(x) => {
+                let plans: usize = x.plans.iter().map(|plan| plan.len()).sum();
+                plans
+                    + match &x.data.data {
+                        FormatSwitch::Bsatn(x) => x.num_bytes(),
+                        FormatSwitch::Json(x) => x.num_bytes(),
+                    }
+            }
+            SubscriptionResult::Error(x) => x.message.len(),
         }
     }
 }
@@ -483,6 +1051,39 @@ impl ToProtocol for SubscriptionMessage {
                     ),
                 }
             }
+            SubscriptionResult::SubscribeWindow(SubscriptionWindowRows { rows: result, window }) => {
+                protocol.assert_matches_format_switch(&result.table_rows);
+                match result.table_rows {
+                    FormatSwitch::Bsatn(table_rows) => FormatSwitch::Bsatn(
+                        ws::SubscribeWindowApplied {
+                            total_host_execution_duration_micros,
+                            request_id,
+                            query_id,
+                            rows: ws::SubscribeRows {
+                                table_id: result.table_id,
+                                table_name: result.table_name,
+                                table_rows,
+                            },
+                            window,
+                        }
+                        .into(),
+                    ),
+                    FormatSwitch::Json(table_rows) => FormatSwitch::Json(
+                        ws::SubscribeWindowApplied {
+                            total_host_execution_duration_micros,
+                            request_id,
+                            query_id,
+                            rows: ws::SubscribeRows {
+                                table_id: result.table_id,
+                                table_name: result.table_name,
+                                table_rows,
+                            },
+                            window,
+                        }
+                        .into(),
+                    ),
+                }
+            }
             SubscriptionResult::Error(error) => {
                 let msg = ws::SubscriptionError {
                     total_host_execution_duration_micros,
@@ -519,6 +1120,31 @@ impl ToProtocol for SubscriptionMessage {
                     ),
                 }
             }
+            SubscriptionResult::SubscribeMultiExplained(ExplainedSubscriptionData { data: result, plans }) => {
+                protocol.assert_matches_format_switch(&result.data);
+                match result.data {
+                    FormatSwitch::Bsatn(data) => FormatSwitch::Bsatn(
+                        ws::SubscribeMultiExplained {
+                            total_host_execution_duration_micros,
+                            request_id,
+                            query_id,
+                            update: data,
+                            plans,
+                        }
+                        .into(),
+                    ),
+                    FormatSwitch::Json(data) => FormatSwitch::Json(
+                        ws::SubscribeMultiExplained {
+                            total_host_execution_duration_micros,
+                            request_id,
+                            query_id,
+                            update: data,
+                            plans,
+                        }
+                        .into(),
+                    ),
+                }
+            }
             SubscriptionResult::UnsubscribeMulti(result) => {
                 protocol.assert_matches_format_switch(&result.data);
                 match result.data {
@@ -558,6 +1184,11 @@ impl<F: WebsocketFormat> OneOffQueryResponseMessage<F> {
     fn num_rows(&self) -> usize {
         self.results.iter().map(|table| table.rows.len()).sum()
     }
+
+    fn num_bytes(&self) -> usize {
+        let rows: usize = self.results.iter().map(|table| table.rows.num_bytes()).sum();
+        rows + self.error.as_ref().map_or(0, |e| e.len())
+    }
 }
 
 impl ToProtocol for OneOffQueryResponseMessage<BsatnFormat> {
@@ -583,3 +1214,330 @@ fn convert<F: WebsocketFormat>(msg: OneOffQueryResponseMessage<F>) -> ws::Server
         total_host_execution_duration: msg.total_host_execution_duration,
     })
 }
+
+/// A chunk of the rows matched by a query sent with `Query`.
+pub type QueryRowsMessage<F> = ws::QueryRows<F>;
+
+impl ToProtocol for QueryRowsMessage<BsatnFormat> {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, _: Protocol) -> Self::Encoded {
+        FormatSwitch::Bsatn(ws::ServerMessage::QueryRows(self))
+    }
+}
+
+impl ToProtocol for QueryRowsMessage<JsonFormat> {
+    type Encoded = SwitchedServerMessage;
+    fn to_protocol(self, _: Protocol) -> Self::Encoded {
+        FormatSwitch::Json(ws::ServerMessage::QueryRows(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy::EnergyQuanta;
+    use crate::host::module_host::ModuleFunctionCall;
+    use spacetimedb_client_api_messages::websocket::{QueryUpdate, SingleQueryUpdate};
+    use spacetimedb_sats::product;
+
+    fn rows<F: WebsocketFormat>(n: u64) -> (F::List, u64) {
+        F::encode_list((0..n).map(|i| product![i]))
+    }
+
+    fn chunk<F: WebsocketFormat>(inserts: u64, deletes: u64) -> SingleQueryUpdate<F> {
+        let (inserts, num_inserts) = rows::<F>(inserts);
+        let (deletes, num_deletes) = rows::<F>(deletes);
+        SingleQueryUpdate {
+            update: F::into_query_update(
+                QueryUpdate {
+                    deletes,
+                    inserts,
+                    columns: Box::default(),
+                },
+                Compression::None,
+            ),
+            num_rows: num_inserts + num_deletes,
+        }
+    }
+
+    fn table_update<F: WebsocketFormat>(table_id: u32, chunks: &[(u64, u64)]) -> ws::TableUpdate<F> {
+        let mut update = ws::TableUpdate::empty(TableId(table_id), format!("table_{table_id}").into());
+        for &(inserts, deletes) in chunks {
+            update.push(chunk(inserts, deletes));
+        }
+        update
+    }
+
+    /// An update of 10 rows, spread over two tables, one of them in two chunks.
+    fn database_update<F: WebsocketFormat>() -> ws::DatabaseUpdate<F> {
+        [table_update(1, &[(3, 0), (2, 0)]), table_update(2, &[(4, 1)])]
+            .into_iter()
+            .collect()
+    }
+
+    fn switched<B, J>(bsatn: impl FnOnce() -> B, json: impl FnOnce() -> J) -> [FormatSwitch<B, J>; 2] {
+        [FormatSwitch::Bsatn(bsatn()), FormatSwitch::Json(json())]
+    }
+
+    fn subscription(result: SubscriptionResult) -> SerializableMessage {
+        SubscriptionMessage {
+            timer: None,
+            request_id: Some(1),
+            query_id: Some(ws::QueryId::new(1)),
+            result,
+        }
+        .into()
+    }
+
+    fn assert_rows(msg: SerializableMessage, kind: MessageKind, num_rows: Option<usize>) {
+        assert_eq!(msg.kind(), kind);
+        assert_eq!(msg.num_rows(), num_rows, "rows of {kind:?}");
+    }
+
+    #[test]
+    fn initial_subscription_counts_every_table_and_chunk() {
+        for database_update in switched(database_update::<BsatnFormat>, database_update::<JsonFormat>) {
+            let msg = SubscriptionUpdateMessage {
+                database_update,
+                request_id: Some(1),
+                timer: None,
+            };
+            assert_rows(msg.into(), MessageKind::InitialSubscription, Some(10));
+        }
+    }
+
+    #[test]
+    fn subscribe_multi_counts_every_table_and_chunk() {
+        for data in switched(database_update::<BsatnFormat>, database_update::<JsonFormat>) {
+            let result = SubscriptionResult::SubscribeMulti(SubscriptionData { data: data.clone() });
+            assert_rows(subscription(result), MessageKind::SubscribeMultiApplied, Some(10));
+            let result = SubscriptionResult::SubscribeMultiExplained(ExplainedSubscriptionData {
+                data: SubscriptionData { data: data.clone() },
+                plans: ["scan t".into()].into(),
+            });
+            assert_rows(subscription(result), MessageKind::SubscribeMultiExplained, Some(10));
+            let result = SubscriptionResult::UnsubscribeMulti(SubscriptionData { data });
+            assert_rows(subscription(result), MessageKind::UnsubscribeMultiApplied, Some(10));
+        }
+    }
+
+    #[test]
+    fn subscribe_single_counts_every_chunk() {
+        let chunks = [(3, 0), (2, 0)];
+        for table_rows in switched(|| table_update(1, &chunks), || table_update(1, &chunks)) {
+            let rows = SubscriptionRows {
+                table_id: TableId(1),
+                table_name: "table_1".into(),
+                table_rows,
+            };
+            let result = SubscriptionResult::Subscribe(rows.clone());
+            assert_rows(subscription(result), MessageKind::SubscribeApplied, Some(5));
+            let result = SubscriptionResult::Unsubscribe(rows.clone());
+            assert_rows(subscription(result), MessageKind::UnsubscribeApplied, Some(5));
+            let window = ws::AppliedWindow {
+                initial: "last(5, id)".parse().unwrap(),
+                has_older_rows: true,
+                older_rows: ws::OlderRows::Deliver,
+            };
+            let result = SubscriptionResult::SubscribeWindow(SubscriptionWindowRows { rows, window });
+            assert_rows(subscription(result), MessageKind::SubscribeWindowApplied, Some(5));
+        }
+    }
+
+    #[test]
+    fn subscription_errors_carry_no_rows() {
+        let result = SubscriptionResult::Error(SubscriptionError {
+            table_id: None,
+            message: "error".into(),
+        });
+        assert_rows(subscription(result), MessageKind::SubscriptionError, None);
+    }
+
+    #[test]
+    fn transaction_updates_count_inserts_and_deletes() {
+        for database_update in switched(database_update::<BsatnFormat>, database_update::<JsonFormat>) {
+            let msg = TransactionUpdateMessage {
+                event: None,
+                database_update: SubscriptionUpdateMessage {
+                    database_update,
+                    request_id: None,
+                    timer: None,
+                },
+                committed_at: Timestamp::UNIX_EPOCH,
+                detail: UpdateDetail::FULL,
+                shared: None,
+            };
+            assert_rows(msg.into(), MessageKind::TransactionUpdateLight, Some(10));
+        }
+    }
+
+    #[test]
+    fn coalesced_updates_are_light_and_carry_the_rows_of_each() {
+        for database_update in switched(database_update::<BsatnFormat>, database_update::<JsonFormat>) {
+            let update = |database_update, committed_at| TransactionUpdateMessage {
+                event: None,
+                database_update: SubscriptionUpdateMessage {
+                    database_update,
+                    request_id: Some(1),
+                    timer: None,
+                },
+                committed_at,
+                detail: UpdateDetail::FULL,
+                shared: None,
+            };
+            let later = Timestamp::UNIX_EPOCH + TimeDuration::from_micros(1);
+            let mut coalesced = update(database_update.clone(), Timestamp::UNIX_EPOCH);
+            coalesced.coalesce(update(database_update, later));
+            assert_eq!(coalesced.committed_at, later);
+            assert_eq!(coalesced.database_update.request_id, None);
+            // The tables of both updates are merged, rather than repeated.
+            let num_tables = match &coalesced.database_update.database_update {
+                FormatSwitch::Bsatn(update) => update.tables.len(),
+                FormatSwitch::Json(update) => update.tables.len(),
+            };
+            assert_eq!(num_tables, 2);
+            assert_rows(coalesced.into(), MessageKind::TransactionUpdateLight, Some(20));
+        }
+    }
+
+    #[test]
+    fn acknowledged_updates_count_inserts_and_deletes() {
+        for database_update in switched(database_update::<BsatnFormat>, database_update::<JsonFormat>) {
+            let update = TransactionUpdateMessage {
+                event: None,
+                database_update: SubscriptionUpdateMessage {
+                    database_update,
+                    request_id: None,
+                    timer: None,
+                },
+                committed_at: Timestamp::UNIX_EPOCH,
+                detail: UpdateDetail::FULL,
+                shared: None,
+            };
+            let msg = AcknowledgedUpdateMessage { delivery_id: 1, update };
+            assert_rows(msg.into(), MessageKind::AcknowledgedUpdate, Some(10));
+        }
+    }
+
+    #[test]
+    fn only_transaction_updates_carry_their_commit_time() {
+        let committed_at = Timestamp::from_micros_since_unix_epoch(1_000_000);
+        let update = TransactionUpdateMessage {
+            event: None,
+            database_update: SubscriptionUpdateMessage::default_for_protocol(Protocol::Binary, None),
+            committed_at,
+            detail: UpdateDetail::FULL,
+            shared: None,
+        };
+        let acknowledged = AcknowledgedUpdateMessage {
+            delivery_id: 1,
+            update: update.clone(),
+        };
+        assert_eq!(SerializableMessage::from(update).committed_at(), Some(committed_at));
+        assert_eq!(
+            SerializableMessage::from(acknowledged).committed_at(),
+            Some(committed_at)
+        );
+
+        let subscription = SubscriptionUpdateMessage::default_for_protocol(Protocol::Binary, None);
+        assert_eq!(SerializableMessage::from(subscription).committed_at(), None);
+    }
+
+    #[test]
+    fn full_updates_leave_out_what_their_client_goes_without() {
+        let event = Arc::new(ModuleEvent {
+            timestamp: Timestamp::from_micros_since_unix_epoch(1_000_000),
+            caller_identity: Identity::from_byte_array([1; 32]),
+            caller_connection_id: Some(ConnectionId::from_u128(1)),
+            function_call: ModuleFunctionCall::default(),
+            status: EventStatus::Committed(<_>::default()),
+            energy_quanta_used: EnergyQuanta::ZERO,
+            host_execution_duration: std::time::Duration::from_millis(5),
+            request_id: Some(7),
+            timer: None,
+            acknowledged: false,
+            trace_id: None,
+        });
+        let update = |detail| {
+            let message =
+                TransactionUpdateMessage::reply(event.clone(), Timestamp::UNIX_EPOCH, Protocol::Binary, detail, None);
+            let FormatSwitch::Bsatn(ws::ServerMessage::TransactionUpdate(update)) =
+                message.to_protocol(Protocol::Binary)
+            else {
+                panic!("a reply should be a full update");
+            };
+            update
+        };
+
+        let full = update(UpdateDetail::FULL);
+        assert_eq!(full.caller_identity, event.caller_identity);
+        assert_eq!(full.caller_connection_id, ConnectionId::from_u128(1));
+        assert_eq!(full.timestamp, event.timestamp);
+        assert_eq!(full.total_host_execution_duration, TimeDuration::from_micros(5_000));
+
+        let trimmed = update(UpdateDetail {
+            caller_info: false,
+            timings: false,
+        });
+        assert_eq!(trimmed.caller_identity, Identity::ZERO);
+        assert_eq!(trimmed.caller_connection_id, ConnectionId::ZERO);
+        assert_eq!(trimmed.timestamp, Timestamp::UNIX_EPOCH);
+        assert_eq!(trimmed.total_host_execution_duration, TimeDuration::ZERO);
+        // Which call the update replies to is carried regardless.
+        assert_eq!(trimmed.reducer_call.request_id, 7);
+    }
+
+    #[test]
+    fn one_off_queries_count_every_table() {
+        fn response<F: WebsocketFormat>() -> OneOffQueryResponseMessage<F> {
+            OneOffQueryResponseMessage {
+                message_id: vec![],
+                error: None,
+                results: [3, 4]
+                    .map(|n| OneOffTable {
+                        table_name: "table".into(),
+                        rows: rows::<F>(n).0,
+                    })
+                    .into(),
+                total_host_execution_duration: TimeDuration::ZERO,
+            }
+        }
+        assert_rows(
+            response::<BsatnFormat>().into(),
+            MessageKind::OneOffQueryResponse,
+            Some(7),
+        );
+        assert_rows(
+            response::<JsonFormat>().into(),
+            MessageKind::OneOffQueryResponse,
+            Some(7),
+        );
+    }
+
+    #[test]
+    fn query_rows_count_their_chunk() {
+        fn chunk<F: WebsocketFormat>() -> QueryRowsMessage<F> {
+            QueryRowsMessage {
+                request_id: 1,
+                table_name: "table".into(),
+                rows: rows::<F>(5).0,
+                last: false,
+                total_host_execution_duration: TimeDuration::ZERO,
+            }
+        }
+        let binary: SerializableMessage = chunk::<BsatnFormat>().into();
+        assert!(matches!(binary.workload(), Some(WorkloadType::Sql)));
+        assert_rows(binary, MessageKind::QueryRows, Some(5));
+        assert_rows(chunk::<JsonFormat>().into(), MessageKind::QueryRows, Some(5));
+    }
+
+    #[test]
+    fn other_messages_carry_no_rows() {
+        let identity = IdentityTokenMessage {
+            identity: <_>::default(),
+            token: "token".into(),
+            connection_id: ConnectionId::ZERO,
+        };
+        assert_rows(identity.into(), MessageKind::IdentityToken, None);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+use parking_lot::Mutex;
+use spacetimedb_lib::Identity;
+use tokio::sync::broadcast;
+
+/// How many presence changes a subscriber may lag behind before missing some.
+const PRESENCE_EVENTS_CAPACITY: usize = 1024;
+
+/// Tracks which identities are connected to which databases on this node,
+/// so that databases which opt in can publish the presence of their clients.
+///
+/// Presence is only ever visible to the owner of a publishing database,
+/// and identities may opt out of having their presence published at all.
+///
+/// Registrations and opt-outs are held in memory,
+/// and so do not survive a restart of the node.
+pub struct PresenceIndex {
+    state: Mutex<PresenceState>,
+    events: broadcast::Sender<PresenceEvent>,
+}
+
+#[derive(Default)]
+struct PresenceState {
+    /// Databases publishing presence, mapped to their owners.
+    publishing: HashMap<Identity, Identity>,
+    /// Identities which don't want their presence published.
+    opted_out: HashSet<Identity>,
+    /// The number of connections of each client identity to each database.
+    connections: HashMap<Identity, HashMap<Identity, usize>>,
+}
+
+/// A client identity coming online or going offline in a publishing database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PresenceEvent {
+    pub identity: Identity,
+    pub database_identity: Identity,
+    pub owner_identity: Identity,
+    pub online: bool,
+}
+
+impl Default for PresenceIndex {
+    fn default() -> Self {
+        Self {
+            state: <_>::default(),
+            events: broadcast::Sender::new(PRESENCE_EVENTS_CAPACITY),
+        }
+    }
+}
+
+impl PresenceIndex {
+    /// Start publishing the presence of clients of `database_identity`,
+    /// visible to `owner_identity`.
+    pub fn publish(&self, database_identity: Identity, owner_identity: Identity) {
+        self.state.lock().publishing.insert(database_identity, owner_identity);
+    }
+
+    /// Stop publishing the presence of clients of `database_identity`.
+    pub fn unpublish(&self, database_identity: &Identity) {
+        self.state.lock().publishing.remove(database_identity);
+    }
+
+    /// Returns whether `database_identity` publishes the presence of its clients.
+    pub fn is_publishing(&self, database_identity: &Identity) -> bool {
+        self.state.lock().publishing.contains_key(database_identity)
+    }
+
+    /// Set whether `identity` has opted out of having its presence published.
+    pub fn set_opted_out(&self, identity: Identity, opted_out: bool) {
+        let mut state = self.state.lock();
+        if opted_out {
+            state.opted_out.insert(identity);
+        } else {
+            state.opted_out.remove(&identity);
+        }
+    }
+
+    /// Record a new connection of `identity` to `database_identity`.
+    pub(super) fn connected(&self, identity: Identity, database_identity: Identity) {
+        let mut state = self.state.lock();
+        let count = state
+            .connections
+            .entry(identity)
+            .or_default()
+            .entry(database_identity)
+            .or_default();
+        *count += 1;
+        let first = *count == 1;
+        if first {
+            self.notify(&state, identity, database_identity, true);
+        }
+    }
+
+    /// Record that a connection of `identity` to `database_identity` has closed.
+    pub(super) fn disconnected(&self, identity: Identity, database_identity: Identity) {
+        let mut state = self.state.lock();
+        let Entry::Occupied(mut databases) = state.connections.entry(identity) else {
+            return;
+        };
+        let Entry::Occupied(mut count) = databases.get_mut().entry(database_identity) else {
+            return;
+        };
+        *count.get_mut() -= 1;
+        if *count.get() > 0 {
+            return;
+        }
+        count.remove();
+        if databases.get().is_empty() {
+            databases.remove();
+        }
+        self.notify(&state, identity, database_identity, false);
+    }
+
+    fn notify(&self, state: &PresenceState, identity: Identity, database_identity: Identity, online: bool) {
+        if state.opted_out.contains(&identity) {
+            return;
+        }
+        if let Some(&owner_identity) = state.publishing.get(&database_identity) {
+            // No subscribers is fine.
+            let _ = self.events.send(PresenceEvent {
+                identity,
+                database_identity,
+                owner_identity,
+                online,
+            });
+        }
+    }
+
+    /// Returns, for each of `identities`, the publishing databases owned by `viewer`
+    /// which that identity is currently connected to.
+    pub fn query(&self, viewer: &Identity, identities: &[Identity]) -> Vec<(Identity, Vec<Identity>)> {
+        let state = self.state.lock();
+        identities
+            .iter()
+            .map(|identity| {
+                let databases = state
+                    .connections
+                    .get(identity)
+                    .filter(|_| !state.opted_out.contains(identity))
+                    .into_iter()
+                    .flat_map(|databases| databases.keys())
+                    .filter(|db| state.publishing.get(*db) == Some(viewer))
+                    .copied()
+                    .collect();
+                (*identity, databases)
+            })
+            .collect()
+    }
+
+    /// Subscribe to presence changes in all publishing databases.
+    ///
+    /// Callers must filter events by [`PresenceEvent::owner_identity`]
+    /// to respect the visibility rules of [`Self::query`].
+    pub fn subscribe(&self) -> broadcast::Receiver<PresenceEvent> {
+        self.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> Identity {
+        Identity::from_byte_array([n; 32])
+    }
+
+    #[test]
+    fn presence_only_visible_to_owner_of_publishing_database() {
+        let index = PresenceIndex::default();
+        let (owner, stranger, client) = (id(1), id(2), id(3));
+        let (published, unpublished) = (id(10), id(11));
+        index.publish(published, owner);
+        index.connected(client, published);
+        index.connected(client, unpublished);
+
+        assert_eq!(index.query(&owner, &[client]), [(client, vec![published])]);
+        assert_eq!(index.query(&stranger, &[client]), [(client, vec![])]);
+
+        index.set_opted_out(client, true);
+        assert_eq!(index.query(&owner, &[client]), [(client, vec![])]);
+    }
+
+    #[test]
+    fn events_on_first_connect_and_last_disconnect() {
+        let index = PresenceIndex::default();
+        let (owner, client, db) = (id(1), id(3), id(10));
+        index.publish(db, owner);
+        let mut events = index.subscribe();
+
+        index.connected(client, db);
+        index.connected(client, db);
+        index.disconnected(client, db);
+        index.disconnected(client, db);
+
+        let online = events.try_recv().unwrap();
+        assert!(online.online && online.identity == client && online.owner_identity == owner);
+        assert!(!events.try_recv().unwrap().online);
+        assert!(events.try_recv().is_err());
+        assert_eq!(index.query(&owner, &[client]), [(client, vec![])]);
+    }
+}
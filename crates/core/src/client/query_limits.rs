@@ -0,0 +1,352 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use prometheus::IntGauge;
+use spacetimedb_lib::{ConnectionId, Identity};
+
+use crate::worker_metrics::WORKER_METRICS;
+
+/// Caps on how many one-off queries may be in flight at once,
+/// over a single connection and over all connections to a database,
+/// and on how long those sent with `Query` may run and how many rows they may match.
+///
+/// These are independent of any limits on reducer calls,
+/// so that read-heavy and write-heavy clients are held back separately.
+/// `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OneOffQueryLimits {
+    pub per_connection: Option<usize>,
+    pub per_database: Option<usize>,
+    pub timeout: Option<Duration>,
+    pub max_rows: Option<usize>,
+    /// How many rows of a query sent with `Query` are sent per message.
+    /// `None` means all of them are sent in one.
+    pub chunk_rows: Option<usize>,
+}
+
+/// The scope of a [`OneOffQueryLimits`] limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryLimitScope {
+    Connection,
+    Database,
+}
+
+impl QueryLimitScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Connection => "connection",
+            Self::Database => "database",
+        }
+    }
+}
+
+impl fmt::Display for QueryLimitScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A one-off query was refused because too many were already in flight.
+///
+/// Clients can recognize this by the `too many concurrent queries` prefix of its message,
+/// and retry once some of their queries have completed.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[error("too many concurrent queries: at most {limit} may be in flight per {scope}")]
+pub struct TooManyConcurrentQueries {
+    pub scope: QueryLimitScope,
+    pub limit: usize,
+}
+
+/// A one-off query sent with `Query` was cut short by its [`OneOffQueryLimits`].
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryCutShort {
+    #[error("query ran for longer than {0:?}")]
+    Timeout(Duration),
+    #[error("query matched more than {0} rows")]
+    TooManyRows(usize),
+}
+
+/// The number of rows a query may match between checks of its [`QueryBudget`]'s deadline,
+/// sparing it a look at the clock for every row.
+const ROWS_PER_DEADLINE_CHECK: usize = 256;
+
+/// Holds a running query to the [`OneOffQueryLimits::timeout`] and [`OneOffQueryLimits::max_rows`] of its limits.
+#[derive(Debug)]
+pub struct QueryBudget {
+    deadline: Option<(Instant, Duration)>,
+    max_rows: Option<usize>,
+    rows: usize,
+}
+
+impl QueryBudget {
+    /// Start counting a query which started running at `started` against `limits`.
+    pub fn new(limits: &OneOffQueryLimits, started: Instant) -> Self {
+        Self {
+            deadline: limits.timeout.map(|timeout| (started + timeout, timeout)),
+            max_rows: limits.max_rows,
+            rows: 0,
+        }
+    }
+
+    /// Fail if the query has run for longer than it may.
+    pub fn check_deadline(&self) -> Result<(), QueryCutShort> {
+        match self.deadline {
+            Some((deadline, timeout)) if Instant::now() >= deadline => Err(QueryCutShort::Timeout(timeout)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a row matched by the query, failing if it's one more than the query may match,
+    /// or, every so often, if the query has run for longer than it may.
+    pub fn count_row(&mut self) -> Result<(), QueryCutShort> {
+        self.rows += 1;
+        if let Some(max_rows) = self.max_rows.filter(|&max_rows| self.rows > max_rows) {
+            return Err(QueryCutShort::TooManyRows(max_rows));
+        }
+        if self.rows % ROWS_PER_DEADLINE_CHECK == 0 {
+            self.check_deadline()?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the one-off queries in flight over a connection or to a database.
+#[derive(Debug, Default)]
+pub struct InFlightQueries {
+    count: AtomicUsize,
+    gauge: Option<IntGauge>,
+    /// The labels of `gauge` in `one_off_queries_in_flight_per_connection`,
+    /// which are removed once the connection is gone.
+    connection_labels: Option<(Identity, ConnectionId)>,
+}
+
+impl InFlightQueries {
+    /// Counts the queries over the connection `connection_id` to `database_identity`.
+    pub fn for_connection(database_identity: Identity, connection_id: ConnectionId) -> Self {
+        let gauge = WORKER_METRICS
+            .one_off_queries_in_flight_per_connection
+            .with_label_values(&database_identity, &connection_id);
+        Self {
+            count: AtomicUsize::new(0),
+            gauge: Some(gauge),
+            connection_labels: Some((database_identity, connection_id)),
+        }
+    }
+
+    /// Counts the queries to `database_identity`, over all of its connections.
+    pub fn for_database(database_identity: Identity) -> Self {
+        let gauge = WORKER_METRICS
+            .one_off_queries_in_flight
+            .with_label_values(&database_identity);
+        Self {
+            count: AtomicUsize::new(0),
+            gauge: Some(gauge),
+            connection_labels: None,
+        }
+    }
+
+    /// The number of queries in flight.
+    pub fn in_flight(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Count a query as in flight until the returned guard is dropped,
+    /// unless `limit` queries are already in flight.
+    fn try_start(&self, limit: Option<usize>) -> Option<InFlightQuery<'_>> {
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                limit.is_none_or(|limit| n < limit).then_some(n + 1)
+            })
+            .ok()?;
+        if let Some(gauge) = &self.gauge {
+            gauge.inc();
+        }
+        Some(InFlightQuery(self))
+    }
+}
+
+impl Drop for InFlightQueries {
+    fn drop(&mut self) {
+        if let Some((database_identity, connection_id)) = &self.connection_labels {
+            let _ = WORKER_METRICS
+                .one_off_queries_in_flight_per_connection
+                .remove_label_values(database_identity, connection_id);
+        }
+    }
+}
+
+/// A query counted by an [`InFlightQueries`], until this is dropped.
+#[derive(Debug)]
+struct InFlightQuery<'a>(&'a InFlightQueries);
+
+impl Drop for InFlightQuery<'_> {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::AcqRel);
+        if let Some(gauge) = &self.0.gauge {
+            gauge.dec();
+        }
+    }
+}
+
+/// Permission to run a one-off query, counting it as in flight over its connection and to its database
+/// until this is dropped.
+#[derive(Debug)]
+pub struct OneOffQueryPermit<'a> {
+    _connection: InFlightQuery<'a>,
+    _database: InFlightQuery<'a>,
+}
+
+/// Count a one-off query as in flight over `connection` and to `database`,
+/// or refuse it if either is already at its limit in `limits`.
+pub fn start_one_off_query<'a>(
+    connection: &'a InFlightQueries,
+    database: &'a InFlightQueries,
+    limits: OneOffQueryLimits,
+) -> Result<OneOffQueryPermit<'a>, TooManyConcurrentQueries> {
+    let too_many = |scope, limit: Option<usize>| TooManyConcurrentQueries {
+        scope,
+        limit: limit.unwrap_or_default(),
+    };
+    let _connection = connection
+        .try_start(limits.per_connection)
+        .ok_or_else(|| too_many(QueryLimitScope::Connection, limits.per_connection))?;
+    let _database = database
+        .try_start(limits.per_database)
+        .ok_or_else(|| too_many(QueryLimitScope::Database, limits.per_database))?;
+    Ok(OneOffQueryPermit { _connection, _database })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: OneOffQueryLimits = OneOffQueryLimits {
+        per_connection: Some(2),
+        per_database: Some(3),
+        timeout: None,
+        max_rows: None,
+        chunk_rows: None,
+    };
+
+    fn refusal(res: Result<OneOffQueryPermit<'_>, TooManyConcurrentQueries>) -> Option<QueryLimitScope> {
+        res.err().map(|e| e.scope)
+    }
+
+    #[test]
+    fn queries_are_refused_over_either_limit() {
+        let database = InFlightQueries::default();
+        let [a, b, c] = [(); 3].map(|_| InFlightQueries::default());
+
+        // `a` saturates its own allowance, but not the database's.
+        let a1 = start_one_off_query(&a, &database, LIMITS).unwrap();
+        let _a2 = start_one_off_query(&a, &database, LIMITS).unwrap();
+        assert_eq!(
+            refusal(start_one_off_query(&a, &database, LIMITS)),
+            Some(QueryLimitScope::Connection)
+        );
+        // The refused query isn't counted against the database.
+        assert_eq!(database.in_flight(), 2);
+
+        // `b` takes the database's last slot, after which everyone is refused.
+        let _b1 = start_one_off_query(&b, &database, LIMITS).unwrap();
+        assert_eq!(
+            refusal(start_one_off_query(&c, &database, LIMITS)),
+            Some(QueryLimitScope::Database)
+        );
+        // ... without counting the refused query against its connection.
+        assert_eq!(c.in_flight(), 0);
+
+        drop(a1);
+        assert_eq!((a.in_flight(), database.in_flight()), (1, 2));
+        let _c1 = start_one_off_query(&c, &database, LIMITS).unwrap();
+    }
+
+    #[test]
+    fn a_saturating_connection_leaves_room_for_others() {
+        let database = InFlightQueries::default();
+        let connections = [(); 3].map(|_| InFlightQueries::default());
+        let limits = OneOffQueryLimits {
+            per_connection: Some(2),
+            per_database: Some(6),
+            ..<_>::default()
+        };
+
+        // The first connection floods the database with queries, but only two get through.
+        let mut permits = (0..100)
+            .filter_map(|_| start_one_off_query(&connections[0], &database, limits).ok())
+            .collect::<Vec<_>>();
+        assert_eq!(permits.len(), 2);
+
+        // Each other connection still gets its full allowance.
+        for conn in &connections[1..] {
+            permits.extend((0..100).filter_map(|_| start_one_off_query(conn, &database, limits).ok()));
+        }
+        assert_eq!(permits.len(), 6);
+        assert!(connections.iter().all(|conn| conn.in_flight() == 2));
+
+        drop(permits);
+        assert_eq!(database.in_flight(), 0);
+        assert!(connections.iter().all(|conn| conn.in_flight() == 0));
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let (database, conn) = (InFlightQueries::default(), InFlightQueries::default());
+        let permits = (0..1000)
+            .map(|_| start_one_off_query(&conn, &database, OneOffQueryLimits::default()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(database.in_flight(), permits.len());
+    }
+
+    #[test]
+    fn refusals_name_the_limit() {
+        let err = TooManyConcurrentQueries {
+            scope: QueryLimitScope::Connection,
+            limit: 4,
+        };
+        assert_eq!(
+            err.to_string(),
+            "too many concurrent queries: at most 4 may be in flight per connection"
+        );
+    }
+
+    #[test]
+    fn budgets_cut_queries_short() {
+        let limits = OneOffQueryLimits {
+            max_rows: Some(3),
+            ..<_>::default()
+        };
+        let mut budget = QueryBudget::new(&limits, Instant::now());
+        for _ in 0..3 {
+            budget.count_row().unwrap();
+        }
+        assert_eq!(budget.count_row(), Err(QueryCutShort::TooManyRows(3)));
+
+        let limits = OneOffQueryLimits {
+            timeout: Some(Duration::from_secs(1)),
+            ..<_>::default()
+        };
+        let budget = QueryBudget::new(&limits, Instant::now());
+        assert_eq!(budget.check_deadline(), Ok(()));
+        let mut late = QueryBudget::new(&limits, Instant::now() - Duration::from_secs(2));
+        assert_eq!(
+            late.check_deadline(),
+            Err(QueryCutShort::Timeout(Duration::from_secs(1)))
+        );
+        // Rows are counted without looking at the clock, until enough of them have been.
+        for _ in 1..ROWS_PER_DEADLINE_CHECK {
+            late.count_row().unwrap();
+        }
+        assert!(late.count_row().is_err());
+    }
+
+    #[test]
+    fn budgets_are_unlimited_by_default() {
+        let mut budget = QueryBudget::new(&OneOffQueryLimits::default(), Instant::now() - Duration::from_secs(60));
+        assert_eq!(budget.check_deadline(), Ok(()));
+        for _ in 0..10_000 {
+            budget.count_row().unwrap();
+        }
+    }
+}
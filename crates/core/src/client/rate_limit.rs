@@ -0,0 +1,165 @@
+use std::time::{Duration, Instant};
+
+/// The rate at which a client may send messages, enforced by a [`TokenBucket`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MessageRateLimit {
+    /// How many messages a client may send per second, on average.
+    pub rate: f64,
+    /// How many messages a client may send at once, after sending none for a while.
+    pub burst: u32,
+}
+
+impl MessageRateLimit {
+    /// The limit for a client of a database, which may override the `node`'s limit,
+    /// if any, with a `rate` and `burst` of its own, see `SET client_message_rate`.
+    ///
+    /// A database `rate` of zero lifts the limit for the database.
+    /// Without a `burst` of its own, a database gets the node's, or as many messages as it may send per second.
+    pub fn resolve(node: Option<Self>, rate: Option<u64>, burst: Option<u64>) -> Option<Self> {
+        let rate = match rate {
+            Some(0) => return None,
+            Some(rate) => rate as f64,
+            None => node?.rate,
+        };
+        let burst = burst
+            .map(|burst| burst.min(u32::MAX as u64) as u32)
+            .or(node.map(|node| node.burst))
+            .unwrap_or(rate.ceil() as u32);
+        Some(Self {
+            rate,
+            burst: burst.max(1),
+        })
+    }
+}
+
+/// What becomes of a message received from a client beyond its [`MessageRateLimit`].
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageThrottle {
+    /// The message is handled, but nothing more is read from the client until it's back within its limit,
+    /// which pushes back on it through its socket.
+    #[default]
+    Delay,
+    /// The message is dropped, and the client sent an error telling it when to retry.
+    Reject,
+}
+
+/// Counts the messages of a client against its [`MessageRateLimit`].
+///
+/// The bucket holds up to `burst` tokens, refilled at `rate` tokens per second, one of which each message takes.
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: MessageRateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket for `limit`.
+    pub fn new(limit: MessageRateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            refilled_at: now,
+        }
+    }
+
+    pub fn limit(&self) -> MessageRateLimit {
+        self.limit
+    }
+
+    /// Take a token for a message received at `now`, even if there's none left,
+    /// returning how long until the bucket is no longer in debt, if it is.
+    ///
+    /// Used under [`MessageThrottle::Delay`], so that the message is handled, but the next one waits.
+    pub fn take(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        self.tokens -= 1.0;
+        (self.tokens < 0.0).then(|| self.time_to(0.0))
+    }
+
+    /// Take a token for a message received at `now`,
+    /// or return how long until there's one to take.
+    ///
+    /// Used under [`MessageThrottle::Reject`], so that the message is dropped if there's none.
+    pub fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.time_to(1.0))
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.rate).min(self.limit.burst as f64);
+        self.refilled_at = now;
+    }
+
+    /// How long until the bucket holds `tokens`.
+    fn time_to(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64((tokens - self.tokens).max(0.0) / self.limit.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: MessageRateLimit = MessageRateLimit { rate: 10.0, burst: 5 };
+
+    #[test]
+    fn buckets_allow_bursts_then_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, start);
+        for _ in 0..5 {
+            assert_eq!(bucket.try_take(start), Ok(()));
+        }
+        assert_eq!(bucket.try_take(start), Err(Duration::from_millis(100)));
+        assert_eq!(bucket.try_take(start + Duration::from_millis(110)), Ok(()));
+        assert!(bucket.try_take(start + Duration::from_millis(160)).is_err());
+
+        // Refilling stops at the burst.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..5 {
+            assert_eq!(bucket.try_take(later), Ok(()));
+        }
+        assert!(bucket.try_take(later).is_err());
+    }
+
+    #[test]
+    fn delayed_messages_put_the_bucket_in_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, start);
+        for _ in 0..5 {
+            assert_eq!(bucket.take(start), None);
+        }
+        assert_eq!(bucket.take(start), Some(Duration::from_millis(100)));
+        assert_eq!(bucket.take(start), Some(Duration::from_millis(200)));
+        assert_eq!(bucket.take(start + Duration::from_millis(350)), None);
+    }
+
+    #[test]
+    fn databases_override_the_nodes_limit() {
+        assert_eq!(MessageRateLimit::resolve(None, None, None), None);
+        assert_eq!(MessageRateLimit::resolve(Some(LIMIT), None, None), Some(LIMIT));
+        assert_eq!(MessageRateLimit::resolve(Some(LIMIT), Some(0), Some(10)), None);
+        assert_eq!(
+            MessageRateLimit::resolve(Some(LIMIT), Some(100), None),
+            Some(MessageRateLimit { rate: 100.0, burst: 5 })
+        );
+        assert_eq!(
+            MessageRateLimit::resolve(None, Some(100), None),
+            Some(MessageRateLimit {
+                rate: 100.0,
+                burst: 100
+            })
+        );
+        assert_eq!(
+            MessageRateLimit::resolve(None, Some(100), Some(0)),
+            Some(MessageRateLimit { rate: 100.0, burst: 1 })
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use spacetimedb_lib::Identity;
+
+/// Issues and redeems reconnect tokens.
+///
+/// A reconnect token is handed to a client when its websocket connection is accepted,
+/// and lets it reconnect to the same database within a short time
+/// without its credentials being validated and its identity resolved again.
+///
+/// Tokens are signed with a secret generated when the node starts,
+/// and each can be redeemed at most once.
+/// Outstanding tokens are held in memory,
+/// and so do not survive a restart of the node.
+pub struct ReconnectTokens {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    state: Mutex<ReconnectState>,
+}
+
+#[derive(Default)]
+struct ReconnectState {
+    /// Outstanding grants by the nonce of their token.
+    grants: HashMap<String, (ReconnectGrant, Instant)>,
+    /// Nonces in the order they were issued, to purge expired grants.
+    issued: VecDeque<(Instant, String)>,
+}
+
+/// What redeeming a reconnect token restores.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReconnectGrant {
+    pub identity: Identity,
+    pub subject: String,
+    pub issuer: String,
+    /// The credentials the client originally authenticated with.
+    pub token: String,
+    /// When `token` expires, if it does, which reconnecting doesn't extend.
+    pub expires_at: Option<SystemTime>,
+    /// The database the token may be used to reconnect to.
+    pub database_identity: Identity,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ReconnectTokenError {
+    #[error("reconnect token is invalid")]
+    Invalid,
+    #[error("reconnect token has expired")]
+    Expired,
+    #[error("reconnect token has already been used or was revoked")]
+    Spent,
+    #[error("reconnect token was issued for a different database")]
+    WrongDatabase,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReconnectClaims {
+    nonce: String,
+    exp: u64,
+}
+
+impl Default for ReconnectTokens {
+    fn default() -> Self {
+        let secret: [u8; 32] = rand::random();
+        Self {
+            encoding_key: EncodingKey::from_secret(&secret),
+            decoding_key: DecodingKey::from_secret(&secret),
+            state: <_>::default(),
+        }
+    }
+}
+
+impl ReconnectTokens {
+    /// Issue a token which can be redeemed for `grant` once, within `ttl`.
+    pub fn issue(&self, grant: ReconnectGrant, ttl: Duration) -> String {
+        let nonce = format!("{:032x}", rand::random::<u128>());
+        let exp = SystemTime::now() + ttl;
+        let claims = ReconnectClaims {
+            nonce: nonce.clone(),
+            exp: exp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        };
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+            .expect("signing reconnect claims with an HMAC key can't fail");
+
+        let now = Instant::now();
+        let expires = now + ttl;
+        let mut state = self.state.lock();
+        state.purge_expired(now);
+        state.grants.insert(nonce.clone(), (grant, expires));
+        state.issued.push_back((expires, nonce));
+        token
+    }
+
+    /// The grant `token` was issued for, if it's still redeemable, without redeeming it,
+    /// e.g. to authenticate a request which goes on to [redeem](Self::redeem) it.
+    pub fn grant(&self, token: &str) -> Result<ReconnectGrant, ReconnectTokenError> {
+        let nonce = self.verify(token)?;
+        let state = self.state.lock();
+        let (grant, expires) = state.grants.get(&nonce).ok_or(ReconnectTokenError::Spent)?;
+        if *expires <= Instant::now() {
+            return Err(ReconnectTokenError::Expired);
+        }
+        Ok(grant.clone())
+    }
+
+    /// Redeem `token` for the grant it was issued for, to reconnect to `database_identity`,
+    /// after which it's no longer redeemable.
+    ///
+    /// A token issued for another database is refused without being spent.
+    pub fn redeem(&self, token: &str, database_identity: &Identity) -> Result<ReconnectGrant, ReconnectTokenError> {
+        let nonce = self.verify(token)?;
+        let mut state = self.state.lock();
+        let Entry::Occupied(entry) = state.grants.entry(nonce) else {
+            return Err(ReconnectTokenError::Spent);
+        };
+        let (grant, expires) = entry.get();
+        if grant.database_identity != *database_identity {
+            return Err(ReconnectTokenError::WrongDatabase);
+        }
+        if *expires <= Instant::now() {
+            return Err(ReconnectTokenError::Expired);
+        }
+        Ok(entry.remove().0)
+    }
+
+    /// Revoke all outstanding tokens for `identity` to reconnect to `database_identity`,
+    /// e.g. because it was kicked from the database.
+    pub fn revoke_identity(&self, identity: &Identity, database_identity: &Identity) {
+        self.state
+            .lock()
+            .grants
+            .retain(|_, (grant, _)| grant.identity != *identity || grant.database_identity != *database_identity);
+    }
+
+    /// Verify that `token` was signed by this node, and hasn't expired, returning its nonce.
+    fn verify(&self, token: &str) -> Result<String, ReconnectTokenError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let claims = jsonwebtoken::decode::<ReconnectClaims>(token, &self.decoding_key, &validation)
+            .map_err(|e| match e.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => ReconnectTokenError::Expired,
+                _ => ReconnectTokenError::Invalid,
+            })?
+            .claims;
+        Ok(claims.nonce)
+    }
+}
+
+impl ReconnectState {
+    fn purge_expired(&mut self, now: Instant) {
+        while let Some((expires, _)) = self.issued.front() {
+            if *expires > now {
+                break;
+            }
+            let (_, nonce) = self.issued.pop_front().unwrap();
+            self.grants.remove(&nonce);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn grant(identity: u8, database: u8) -> ReconnectGrant {
+        ReconnectGrant {
+            identity: Identity::from_byte_array([identity; 32]),
+            subject: "subject".into(),
+            issuer: "localhost".into(),
+            token: "creds".into(),
+            expires_at: None,
+            database_identity: db(database),
+        }
+    }
+
+    fn db(database: u8) -> Identity {
+        Identity::from_byte_array([database; 32])
+    }
+
+    #[test]
+    fn tokens_are_single_use() {
+        let tokens = ReconnectTokens::default();
+        let token = tokens.issue(grant(1, 10), TTL);
+        assert_eq!(tokens.redeem(&token, &db(10)), Ok(grant(1, 10)));
+        assert_eq!(tokens.redeem(&token, &db(10)), Err(ReconnectTokenError::Spent));
+    }
+
+    #[test]
+    fn tokens_are_bound_to_their_node() {
+        let token = ReconnectTokens::default().issue(grant(1, 10), TTL);
+        assert_eq!(
+            ReconnectTokens::default().redeem(&token, &db(10)),
+            Err(ReconnectTokenError::Invalid)
+        );
+        assert_eq!(
+            ReconnectTokens::default().redeem("not-a-token", &db(10)),
+            Err(ReconnectTokenError::Invalid)
+        );
+    }
+
+    #[test]
+    fn tokens_expire() {
+        let tokens = ReconnectTokens::default();
+        let token = tokens.issue(grant(1, 10), Duration::ZERO);
+        assert!(matches!(
+            tokens.redeem(&token, &db(10)),
+            Err(ReconnectTokenError::Expired | ReconnectTokenError::Spent)
+        ));
+
+        // Expired grants are purged as new tokens are issued.
+        tokens.issue(grant(1, 10), TTL);
+        assert_eq!(tokens.state.lock().grants.len(), 1);
+    }
+
+    #[test]
+    fn tokens_are_bound_to_their_database() {
+        let tokens = ReconnectTokens::default();
+        let token = tokens.issue(grant(1, 10), TTL);
+        assert_eq!(tokens.redeem(&token, &db(11)), Err(ReconnectTokenError::WrongDatabase));
+        // Presenting it to the wrong database doesn't spend it, nor does looking up its grant.
+        assert_eq!(tokens.grant(&token), Ok(grant(1, 10)));
+        assert_eq!(tokens.redeem(&token, &db(10)), Ok(grant(1, 10)));
+        assert_eq!(tokens.grant(&token), Err(ReconnectTokenError::Spent));
+    }
+
+    #[test]
+    fn revoking_an_identity_revokes_its_tokens_for_the_database() {
+        let tokens = ReconnectTokens::default();
+        let revoked = tokens.issue(grant(1, 10), TTL);
+        let other_database = tokens.issue(grant(1, 11), TTL);
+        let other_identity = tokens.issue(grant(2, 10), TTL);
+        tokens.revoke_identity(&Identity::from_byte_array([1; 32]), &db(10));
+        assert_eq!(tokens.redeem(&revoked, &db(10)), Err(ReconnectTokenError::Spent));
+        assert_eq!(tokens.redeem(&other_database, &db(11)), Ok(grant(1, 11)));
+        assert_eq!(tokens.redeem(&other_identity, &db(10)), Ok(grant(2, 10)));
+    }
+}
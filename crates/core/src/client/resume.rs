@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use spacetimedb_client_api_messages::websocket::ProtocolVersion;
+use spacetimedb_lib::Identity;
+use tokio::sync::oneshot;
+
+use super::{ClientActorId, ClientConfig, ClientConnectionSender, Protocol};
+
+/// The sessions of clients which lost their connection, kept so that a reconnecting client can resume them.
+///
+/// A client which asked for its session to be resumable is handed a resume token when it connects.
+/// Should its connection drop, rather than disconnecting the client from its module,
+/// the connection's actor parks its session under that token, see [`Self::park`],
+/// keeping the client's subscriptions and queueing the messages sent to it meanwhile.
+/// A reconnecting client which presents the token, see [`Self::resume`],
+/// hands its new socket of type `S` over to the parked actor,
+/// which then delivers what was queued instead of the client subscribing anew.
+///
+/// Sessions are held in memory, and so do not survive a restart of the node.
+pub struct ResumableSessions<S> {
+    sessions: Mutex<HashMap<String, ParkedEntry<S>>>,
+}
+
+struct ParkedEntry<S> {
+    id: ClientActorId,
+    database_identity: Identity,
+    config: ClientConfig,
+    /// The sender of the session, whose queue holds the messages to replay to the client.
+    sender: Arc<ClientConnectionSender>,
+    resume: oneshot::Sender<S>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ResumeError {
+    /// The session was never parked, or has since been given up on,
+    /// e.g. because it expired, too many messages were queued for it, or it was purged.
+    #[error("resume token is unknown or its session has expired")]
+    Unknown,
+    #[error("resume token was issued for another identity, database or protocol")]
+    Mismatch,
+}
+
+impl<S> Default for ResumableSessions<S> {
+    fn default() -> Self {
+        Self {
+            sessions: <_>::default(),
+        }
+    }
+}
+
+/// A new resume token, to hand to a client whose session may be parked under it.
+pub fn new_resume_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+impl<S> ResumableSessions<S> {
+    /// Park the session of the client of `database_identity` with `sender` under `token`,
+    /// until a client resumes it or [`ParkedSession::wait`] gives up on it.
+    pub fn park(
+        &self,
+        token: String,
+        sender: Arc<ClientConnectionSender>,
+        database_identity: Identity,
+    ) -> ParkedSession<'_, S> {
+        let (resume, resumed) = oneshot::channel();
+        let entry = ParkedEntry {
+            id: sender.id,
+            database_identity,
+            config: sender.config(),
+            sender,
+            resume,
+        };
+        self.sessions.lock().insert(token.clone(), entry);
+        ParkedSession {
+            sessions: self,
+            token,
+            resumed,
+        }
+    }
+
+    /// Take over the session parked under `token` on behalf of a client of `identity`,
+    /// connecting to `database_identity` with `protocol` and `version`, which must be those of the session.
+    ///
+    /// Once taken over, the session is no longer given up on,
+    /// and waits for the socket handed to it by [`Resumption::hand_over`].
+    pub fn resume(
+        &self,
+        token: &str,
+        identity: Identity,
+        database_identity: Identity,
+        protocol: Protocol,
+        version: ProtocolVersion,
+    ) -> Result<Resumption<S>, ResumeError> {
+        let mut sessions = self.sessions.lock();
+        let entry = sessions.get(token).ok_or(ResumeError::Unknown)?;
+        if entry.id.identity != identity
+            || entry.database_identity != database_identity
+            || (entry.config.protocol, entry.config.version) != (protocol, version)
+        {
+            return Err(ResumeError::Mismatch);
+        }
+        let entry = sessions.remove(token).unwrap();
+        Ok(Resumption {
+            id: entry.id,
+            config: entry.config,
+            resume: entry.resume,
+        })
+    }
+
+    /// How many sessions are parked.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many sessions of clients of `database_identity` are parked,
+    /// and the bytes of the messages queued for them meanwhile.
+    pub fn buffered(&self, database_identity: &Identity) -> (usize, usize) {
+        let sessions = self.sessions.lock();
+        sessions
+            .values()
+            .filter(|entry| entry.database_identity == *database_identity)
+            .fold((0, 0), |(count, bytes), entry| {
+                (count + 1, bytes + entry.sender.queued().1)
+            })
+    }
+
+    /// Give up on the sessions parked for the clients of `database_identity` connected as `identity`,
+    /// returning how many there were.
+    ///
+    /// A client resuming one of them is then told its session can't be resumed,
+    /// and connects anew, with nothing replayed to it.
+    pub fn purge(&self, database_identity: &Identity, identity: &Identity) -> usize {
+        let mut sessions = self.sessions.lock();
+        let before = sessions.len();
+        sessions.retain(|_, entry| entry.database_identity != *database_identity || entry.id.identity != *identity);
+        before - sessions.len()
+    }
+
+    /// Remove the session parked under `token`, returning whether it was still parked,
+    /// i.e. hadn't been taken over by a client resuming it.
+    fn withdraw(&self, token: &str) -> bool {
+        self.sessions.lock().remove(token).is_some()
+    }
+}
+
+/// A session parked by [`ResumableSessions::park`], withdrawn once dropped.
+pub struct ParkedSession<'a, S> {
+    sessions: &'a ResumableSessions<S>,
+    token: String,
+    resumed: oneshot::Receiver<S>,
+}
+
+impl<S> ParkedSession<'_, S> {
+    /// Wait for a client to resume the session, returning the socket it connected with,
+    /// or for `give_up` to complete, returning `None`.
+    ///
+    /// Also returns `None` should the session be [purged](ResumableSessions::purge),
+    /// or the client resuming it fail to connect after taking it over.
+    ///
+    /// A session taken over just as `give_up` completes still waits for its socket,
+    /// which it only doesn't get if the resuming client's connection failed.
+    pub async fn wait(&mut self, give_up: impl Future<Output = ()>) -> Option<S> {
+        tokio::select! {
+            socket = &mut self.resumed => return socket.ok(),
+            () = give_up => {}
+        }
+        if self.sessions.withdraw(&self.token) {
+            return None;
+        }
+        (&mut self.resumed).await.ok()
+    }
+}
+
+impl<S> Drop for ParkedSession<'_, S> {
+    fn drop(&mut self) {
+        self.sessions.withdraw(&self.token);
+    }
+}
+
+/// A parked session taken over by a resuming client, see [`ResumableSessions::resume`].
+pub struct Resumption<S> {
+    /// The client whose session is resumed.
+    pub id: ClientActorId,
+    /// The configuration the client connected with in the first place, which still applies.
+    pub config: ClientConfig,
+    resume: oneshot::Sender<S>,
+}
+
+impl<S> Resumption<S> {
+    /// Hand `socket` over to the parked session,
+    /// or return it should the session have been torn down in the meantime.
+    pub fn hand_over(self, socket: S) -> Result<(), S> {
+        self.resume.send(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn id(identity: u8) -> ClientActorId {
+        ClientActorId::for_test(Identity::from_byte_array([identity; 32]))
+    }
+
+    fn sender(identity: u8) -> Arc<ClientConnectionSender> {
+        Arc::new(ClientConnectionSender::dummy(id(identity), ClientConfig::for_test()))
+    }
+
+    const DB: Identity = Identity::ZERO;
+
+    #[tokio::test]
+    async fn parked_sessions_are_handed_the_resuming_socket() {
+        let sessions = ResumableSessions::<u32>::default();
+        let token = new_resume_token();
+        let config = ClientConfig::for_test();
+        let mut parked = sessions.park(token.clone(), sender(1), DB);
+
+        assert_eq!(
+            sessions
+                .resume(&token, id(2).identity, DB, config.protocol, config.version)
+                .err(),
+            Some(ResumeError::Mismatch)
+        );
+        let resumption = sessions
+            .resume(&token, id(1).identity, DB, config.protocol, config.version)
+            .unwrap();
+        assert_eq!(resumption.id, id(1));
+        // Once taken over, the session can't be resumed again.
+        assert_eq!(
+            sessions
+                .resume(&token, id(1).identity, DB, config.protocol, config.version)
+                .err(),
+            Some(ResumeError::Unknown)
+        );
+
+        // Even if the session is given up on right away, it's resumed, having been taken over.
+        resumption.hand_over(7).unwrap();
+        assert_eq!(parked.wait(async {}).await, Some(7));
+    }
+
+    #[tokio::test]
+    async fn sessions_given_up_on_cant_be_resumed() {
+        let sessions = ResumableSessions::<u32>::default();
+        let config = ClientConfig::for_test();
+        let mut parked = sessions.park("token".into(), sender(1), DB);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(parked.wait(tokio::time::sleep(Duration::from_millis(1))).await, None);
+        assert!(sessions.is_empty());
+        assert_eq!(
+            sessions
+                .resume("token", id(1).identity, DB, config.protocol, config.version)
+                .err(),
+            Some(ResumeError::Unknown)
+        );
+
+        // Dropping a parked session withdraws it too.
+        drop(sessions.park("other".into(), sender(1), DB));
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn purged_sessions_cant_be_resumed() {
+        let sessions = ResumableSessions::<u32>::default();
+        let config = ClientConfig::for_test();
+        let mut parked = sessions.park("token".into(), sender(1), DB);
+        let _other = sessions.park("other".into(), sender(2), DB);
+        assert_eq!(sessions.buffered(&DB).0, 2);
+
+        assert_eq!(sessions.purge(&DB, &id(1).identity), 1);
+        assert_eq!(sessions.buffered(&DB).0, 1);
+        // The parked session gives up without waiting, and the client has nothing to resume.
+        assert_eq!(parked.wait(std::future::pending()).await, None);
+        assert_eq!(
+            sessions
+                .resume("token", id(1).identity, DB, config.protocol, config.version)
+                .err(),
+            Some(ResumeError::Unknown)
+        );
+    }
+}
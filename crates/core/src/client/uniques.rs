@@ -0,0 +1,253 @@
+//! Approximate counts of the unique identities connecting to each database, per day,
+//! for reporting e.g. daily and weekly active users without keeping every connection.
+//!
+//! The identities connecting to a database on a day are counted by an [`IdentitySketch`],
+//! a HyperLogLog sketch of a fixed [`SKETCH_SIZE`], whatever the number of identities.
+//! Sketches are recorded in memory as clients connect,
+//! and periodically [taken](UniqueIdentities::take_pending) to be merged into those persisted by the control state.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+use spacetimedb_lib::Identity;
+use spacetimedb_sats::hash::hash_bytes;
+
+/// The number of bits of an identity's hash which choose its register.
+const PRECISION: u32 = 12;
+
+/// The size of an [`IdentitySketch`], in bytes, one per register.
+pub const SKETCH_SIZE: usize = 1 << PRECISION;
+
+/// The relative standard error of [`IdentitySketch::estimate`], i.e. `1.04 / sqrt(SKETCH_SIZE)`.
+///
+/// Estimates are within this fraction of the true count about 68% of the time,
+/// and within twice it about 95% of the time.
+pub const RELATIVE_STANDARD_ERROR: f64 = 0.01625;
+
+/// How many days of sketches are kept for a database, counting back from its latest.
+pub const RETENTION_DAYS: u32 = 400;
+
+/// How often the pending sketches of a node should be merged into those persisted.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A day, as the number of days since the Unix epoch, in UTC.
+pub type Day = u32;
+
+/// The day of `time`.
+pub fn day_of(time: SystemTime) -> Day {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (secs / (24 * 60 * 60)) as Day
+}
+
+/// A HyperLogLog sketch of a set of identities, from which the size of the set can be estimated.
+#[derive(Clone, PartialEq, Eq)]
+pub struct IdentitySketch {
+    registers: Box<[u8]>,
+}
+
+impl Default for IdentitySketch {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; SKETCH_SIZE].into(),
+        }
+    }
+}
+
+impl std::fmt::Debug for IdentitySketch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdentitySketch")
+            .field("estimate", &self.estimate())
+            .finish()
+    }
+}
+
+impl IdentitySketch {
+    /// Reads a sketch from the bytes of [`Self::as_bytes`], if they are one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        (bytes.len() == SKETCH_SIZE).then(|| Self {
+            registers: bytes.into(),
+        })
+    }
+
+    /// The registers of the sketch, e.g. to persist it.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// Add `identity` to the set.
+    pub fn insert(&mut self, identity: &Identity) {
+        let hash = hash_bytes(identity.to_byte_array());
+        let hash = u64::from_le_bytes(hash.data[..8].try_into().unwrap());
+        let register = (hash >> (64 - PRECISION)) as usize;
+        // The position of the first set bit of the rest of the hash, capped should the rest be all zeros.
+        let rank = ((hash << PRECISION).leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+        let current = &mut self.registers[register];
+        *current = (*current).max(rank);
+    }
+
+    /// Add the identities of `other` to the set.
+    pub fn merge(&mut self, other: &Self) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    /// Estimate the number of identities in the set,
+    /// with a relative standard error of [`RELATIVE_STANDARD_ERROR`].
+    pub fn estimate(&self) -> u64 {
+        let m = SKETCH_SIZE as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&rank| 2f64.powi(-(rank as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        // Small sets are better estimated by the number of registers left empty.
+        let estimate = if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        };
+        estimate.round() as u64
+    }
+}
+
+/// The sketches of the identities connecting to each database on this node,
+/// which have yet to be merged into those persisted.
+///
+/// At most [`RETENTION_DAYS`] of sketches are held per database,
+/// so memory is bounded by the number of databases, not of identities.
+#[derive(Default)]
+pub struct UniqueIdentities {
+    pending: Mutex<HashMap<Identity, BTreeMap<Day, IdentitySketch>>>,
+}
+
+impl UniqueIdentities {
+    /// Record that `identity` connected to `database_identity` today.
+    pub fn record(&self, database_identity: Identity, identity: &Identity) {
+        self.record_on(database_identity, identity, day_of(SystemTime::now()))
+    }
+
+    /// Record that `identity` connected to `database_identity` on `day`.
+    pub fn record_on(&self, database_identity: Identity, identity: &Identity, day: Day) {
+        let mut pending = self.pending.lock();
+        let days = pending.entry(database_identity).or_default();
+        days.entry(day).or_default().insert(identity);
+        // Drop the days which have fallen out of the retention.
+        let latest = days.last_key_value().map_or(day, |(&latest, _)| latest);
+        while let Some(oldest) = days
+            .first_entry()
+            .filter(|oldest| oldest.key() + RETENTION_DAYS <= latest)
+        {
+            oldest.remove();
+        }
+    }
+
+    /// Merge `sketch` of the identities which connected to `database_identity` on `day` back into the pending sketches,
+    /// e.g. as it couldn't be persisted.
+    pub fn restore(&self, database_identity: Identity, day: Day, sketch: &IdentitySketch) {
+        let mut pending = self.pending.lock();
+        pending
+            .entry(database_identity)
+            .or_default()
+            .entry(day)
+            .or_default()
+            .merge(sketch);
+    }
+
+    /// Take the pending sketches, as `(database_identity, day, sketch)`, leaving none.
+    pub fn take_pending(&self) -> Vec<(Identity, Day, IdentitySketch)> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        pending
+            .into_iter()
+            .flat_map(|(database_identity, days)| {
+                days.into_iter()
+                    .map(move |(day, sketch)| (database_identity, day, sketch))
+            })
+            .collect()
+    }
+
+    /// Returns the pending sketches of `database_identity` for `days`, which have yet to be persisted.
+    pub fn pending(&self, database_identity: &Identity, days: RangeInclusive<Day>) -> Vec<(Day, IdentitySketch)> {
+        let pending = self.pending.lock();
+        pending.get(database_identity).map_or_else(Vec::new, |sketches| {
+            sketches
+                .range(days)
+                .map(|(&day, sketch)| (day, sketch.clone()))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(n: u64) -> Identity {
+        Identity::from_u256(n.into())
+    }
+
+    #[test]
+    fn estimates_are_within_the_error_bound() {
+        for n in [0, 1, 100, 10_000, 50_000] {
+            let mut sketch = IdentitySketch::default();
+            for i in 0..n {
+                sketch.insert(&identity(i));
+                // Repeat connections don't count.
+                sketch.insert(&identity(i));
+            }
+            let error = (sketch.estimate() as f64 - n as f64).abs();
+            assert!(
+                error <= 4.0 * RELATIVE_STANDARD_ERROR * n as f64 + 1.0,
+                "{n}: {sketch:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn merged_sketches_count_the_union() {
+        let (mut a, mut b) = (IdentitySketch::default(), IdentitySketch::default());
+        (0..1000).for_each(|i| a.insert(&identity(i)));
+        (500..1500).for_each(|i| b.insert(&identity(i)));
+        a.merge(&b);
+        let error = (a.estimate() as f64 - 1500.0).abs();
+        assert!(error <= 4.0 * RELATIVE_STANDARD_ERROR * 1500.0, "{a:?}");
+
+        assert_eq!(IdentitySketch::from_bytes(a.as_bytes()), Some(a));
+        assert_eq!(IdentitySketch::from_bytes(&[0; 16]), None);
+    }
+
+    #[test]
+    fn pending_sketches_are_bounded_and_taken() {
+        let uniques = UniqueIdentities::default();
+        let db = identity(u64::MAX);
+        uniques.record_on(db, &identity(1), 10);
+        uniques.record_on(db, &identity(2), 10);
+        uniques.record_on(db, &identity(1), 11);
+        let pending = uniques.pending(&db, 0..=11);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].1.estimate(), 2);
+        assert!(uniques.pending(&identity(0), 0..=11).is_empty());
+
+        // Days beyond the retention are dropped.
+        uniques.record_on(db, &identity(1), 10 + RETENTION_DAYS);
+        let days: Vec<_> = uniques
+            .pending(&db, 0..=Day::MAX)
+            .into_iter()
+            .map(|(day, _)| day)
+            .collect();
+        assert_eq!(days, [11, 10 + RETENTION_DAYS]);
+
+        let taken = uniques.take_pending();
+        assert_eq!(taken.len(), 2);
+        assert!(uniques.take_pending().is_empty());
+
+        // Sketches which couldn't be persisted can be restored.
+        let (db, day, sketch) = &taken[0];
+        uniques.restore(*db, *day, sketch);
+        assert_eq!(uniques.pending(db, *day..=*day)[0].1, *sketch);
+    }
+}
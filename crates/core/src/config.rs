@@ -1,6 +1,11 @@
 use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
 use std::{fmt, io};
 
+use crate::client::{ConnectionLimits, NetworkOptions, WebSocketOptions};
+use crate::host::PublishOptions;
+use crate::subscription::fanout::FanoutOptions;
 use spacetimedb_lib::ConnectionId;
 use spacetimedb_paths::cli::{ConfigDir, PrivKeyPath, PubKeyPath};
 use spacetimedb_paths::server::{ConfigToml, MetadataTomlPath};
@@ -16,6 +21,37 @@ pub fn parse_config<T: serde::de::DeserializeOwned>(path: &Path) -> anyhow::Resu
     }
 }
 
+/// How often `config.toml` is checked for changes, see [`watch_config_toml`].
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Call `reload` with `target` whenever `config_toml` is modified, checking every [`RELOAD_INTERVAL`],
+/// for as long as `target` lives.
+///
+/// Must be called from within a tokio runtime.
+pub fn watch_config_toml<T: Send + Sync + 'static>(
+    target: &Arc<T>,
+    config_toml: ConfigToml,
+    reload: impl Fn(&T, &ConfigToml) + Send + 'static,
+) {
+    let target: Weak<T> = Arc::downgrade(target);
+    let modified_at = |path: &ConfigToml| -> Option<SystemTime> { path.0.metadata().and_then(|m| m.modified()).ok() };
+    let mut prev_time = modified_at(&config_toml);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+            let Some(target) = target.upgrade() else { break };
+            let Some(modified) = modified_at(&config_toml) else {
+                continue;
+            };
+            if prev_time.is_some_and(|prev| modified <= prev) {
+                continue;
+            }
+            prev_time = Some(modified);
+            reload(&target, &config_toml);
+        }
+    });
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct MetadataFile {
     pub version: semver::Version,
@@ -107,6 +143,16 @@ pub struct ConfigFile {
     pub certificate_authority: Option<CertificateAuthority>,
     #[serde(default)]
     pub logs: LogConfig,
+    #[serde(default)]
+    pub connection_limits: ConnectionLimits,
+    #[serde(default)]
+    pub websocket: WebSocketOptions,
+    #[serde(default)]
+    pub network: NetworkOptions,
+    #[serde(default)]
+    pub publish: PublishOptions,
+    #[serde(default)]
+    pub fanout: FanoutOptions,
 }
 
 impl ConfigFile {
@@ -27,6 +27,7 @@ use crate::{
 use anyhow::anyhow;
 use core::{convert::Infallible, ops::RangeBounds};
 use itertools::Itertools;
+use spacetimedb_client_api_messages::websocket::{DatabaseStats, TableStats};
 use spacetimedb_data_structures::map::{HashSet, IntMap};
 use spacetimedb_lib::{
     db::auth::{StAccess, StTableType},
@@ -776,6 +777,29 @@ impl CommittedState {
         (table, blob_store, pool)
     }
 
+    /// Compute [`DatabaseStats`] from the bookkeeping of each table,
+    /// in time proportional to the number of tables and indexes, rather than rows.
+    pub fn database_stats(&self) -> DatabaseStats {
+        let tables: Box<[_]> = self
+            .tables
+            .values()
+            .map(|table| TableStats {
+                table_name: table.schema.table_name.clone(),
+                rows: table.num_rows(),
+                bytes: table.bytes_occupied_overestimate() as u64,
+                indexes: table.num_indices() as u32,
+                index_bytes: table.bytes_used_by_index_keys(),
+            })
+            .collect();
+        let total_bytes = tables.iter().map(|table| table.bytes + table.index_bytes).sum();
+        DatabaseStats {
+            tables,
+            total_bytes,
+            firehose: None,
+            replay_buffers: <_>::default(),
+        }
+    }
+
     pub fn report_data_size(&self, database_identity: Identity) {
         use crate::db::db_metrics::data_size::DATA_SIZE_METRICS;
 
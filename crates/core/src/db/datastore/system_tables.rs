@@ -11,6 +11,7 @@
 //! - Use [`st_fields_enum`] to define its column enum.
 //! - Register its schema in [`system_module_def`], making sure to call `validate_system_table` at the end of the function.
 
+use spacetimedb_expr::limits::QueryLimit;
 use spacetimedb_lib::db::auth::{StAccess, StTableType};
 use spacetimedb_lib::db::raw_def::v9::{btree, RawSql};
 use spacetimedb_lib::db::raw_def::*;
@@ -986,6 +987,45 @@ pub const ST_VARNAME_SLOW_QRY: &str = "slow_ad_hoc_query_ms";
 pub const ST_VARNAME_SLOW_SUB: &str = "slow_subscription_query_ms";
 /// A system variable that defines a threshold for logging slow tx updates.
 pub const ST_VARNAME_SLOW_INC: &str = "slow_tx_update_ms";
+/// A system variable that, when true, closes a client connection
+/// instead of skipping a message which fails to encode.
+pub const ST_VARNAME_CLOSE_ON_ENCODE_ERROR: &str = "close_on_encode_error";
+/// A system variable that, when true, redacts the parameter values of queries
+/// in dumps of a client's subscriptions.
+pub const ST_VARNAME_REDACT_SUBSCRIPTION_PARAMETERS: &str = "redact_subscription_parameters";
+/// A system variable that defines the maximum length, in bytes, of queries and subscriptions,
+/// which can't be raised above [`MAX_SQL_LENGTH`](spacetimedb_expr::limits::MAX_SQL_LENGTH).
+pub const ST_VARNAME_MAX_QUERY_LENGTH: &str = QueryLimit::Length.var_name();
+/// A system variable that defines the maximum number of predicates in queries and subscriptions.
+pub const ST_VARNAME_MAX_QUERY_PREDICATES: &str = QueryLimit::Predicates.var_name();
+/// A system variable that defines the maximum number of joins in queries and subscriptions.
+pub const ST_VARNAME_MAX_QUERY_JOINS: &str = QueryLimit::Joins.var_name();
+/// A system variable that, when true, which it is by default,
+/// keeps sending clients the plain-text close reasons and error strings of older versions,
+/// rather than structured ones.
+pub const ST_VARNAME_LEGACY_ERRORS: &str = "legacy_errors";
+/// A system variable that defines how often, in milliseconds, a running reducer is checked on,
+/// which bounds how late it's interrupted, applied as the module is next instantiated.
+pub const ST_VARNAME_EPOCH_INTERVAL: &str = "epoch_interval_ms";
+/// A system variable that defines how long, in milliseconds, a reducer may run
+/// before it's interrupted, applied as the module is next instantiated.
+pub const ST_VARNAME_MAX_REDUCER_DURATION: &str = "max_reducer_duration_ms";
+/// A system variable that lists, as comma-separated hex identities,
+/// the clients which always get metric series labeled with their identity.
+pub const ST_VARNAME_CLIENT_METRICS_ALLOWLIST: &str = "client_metrics_allowlist";
+/// A system variable that defines how many messages per second a client may send,
+/// overriding the node's limit, or lifting it if zero.
+pub const ST_VARNAME_CLIENT_MESSAGE_RATE: &str = "client_message_rate";
+/// A system variable that defines how many messages a client may send at once,
+/// within its [ST_VARNAME_CLIENT_MESSAGE_RATE].
+pub const ST_VARNAME_CLIENT_MESSAGE_BURST: &str = "client_message_burst";
+/// A system variable that lists, comma-separated, the only reducers clients may call, if set.
+pub const ST_VARNAME_CLIENT_REDUCER_ALLOWLIST: &str = "client_reducer_allowlist";
+/// A system variable that lists, comma-separated, reducers clients may not call.
+pub const ST_VARNAME_CLIENT_REDUCER_DENYLIST: &str = "client_reducer_denylist";
+/// A system variable that, when true, lets the database owner call any reducer,
+/// including those not callable by clients.
+pub const ST_VARNAME_OWNER_BYPASSES_REDUCER_VISIBILITY: &str = "owner_bypasses_reducer_visibility";
 
 /// The name of a system variable in `st_var`
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -994,6 +1034,20 @@ pub enum StVarName {
     SlowQryThreshold,
     SlowSubThreshold,
     SlowIncThreshold,
+    CloseOnEncodeError,
+    RedactSubscriptionParameters,
+    MaxQueryLength,
+    MaxQueryPredicates,
+    MaxQueryJoins,
+    LegacyErrors,
+    EpochInterval,
+    MaxReducerDuration,
+    ClientMetricsAllowlist,
+    ClientMessageRate,
+    ClientMessageBurst,
+    ClientReducerAllowlist,
+    ClientReducerDenylist,
+    OwnerBypassesReducerVisibility,
 }
 impl From<StVarName> for &'static str {
     fn from(value: StVarName) -> Self {
@@ -1002,6 +1056,20 @@ impl From<StVarName> for &'static str {
             StVarName::SlowQryThreshold => ST_VARNAME_SLOW_QRY,
             StVarName::SlowSubThreshold => ST_VARNAME_SLOW_SUB,
             StVarName::SlowIncThreshold => ST_VARNAME_SLOW_INC,
+            StVarName::CloseOnEncodeError => ST_VARNAME_CLOSE_ON_ENCODE_ERROR,
+            StVarName::RedactSubscriptionParameters => ST_VARNAME_REDACT_SUBSCRIPTION_PARAMETERS,
+            StVarName::MaxQueryLength => ST_VARNAME_MAX_QUERY_LENGTH,
+            StVarName::MaxQueryPredicates => ST_VARNAME_MAX_QUERY_PREDICATES,
+            StVarName::MaxQueryJoins => ST_VARNAME_MAX_QUERY_JOINS,
+            StVarName::LegacyErrors => ST_VARNAME_LEGACY_ERRORS,
+            StVarName::EpochInterval => ST_VARNAME_EPOCH_INTERVAL,
+            StVarName::MaxReducerDuration => ST_VARNAME_MAX_REDUCER_DURATION,
+            StVarName::ClientMetricsAllowlist => ST_VARNAME_CLIENT_METRICS_ALLOWLIST,
+            StVarName::ClientMessageRate => ST_VARNAME_CLIENT_MESSAGE_RATE,
+            StVarName::ClientMessageBurst => ST_VARNAME_CLIENT_MESSAGE_BURST,
+            StVarName::ClientReducerAllowlist => ST_VARNAME_CLIENT_REDUCER_ALLOWLIST,
+            StVarName::ClientReducerDenylist => ST_VARNAME_CLIENT_REDUCER_DENYLIST,
+            StVarName::OwnerBypassesReducerVisibility => ST_VARNAME_OWNER_BYPASSES_REDUCER_VISIBILITY,
         }
     }
 }
@@ -1020,6 +1088,20 @@ impl FromStr for StVarName {
             ST_VARNAME_SLOW_QRY => Ok(StVarName::SlowQryThreshold),
             ST_VARNAME_SLOW_SUB => Ok(StVarName::SlowSubThreshold),
             ST_VARNAME_SLOW_INC => Ok(StVarName::SlowIncThreshold),
+            ST_VARNAME_CLOSE_ON_ENCODE_ERROR => Ok(StVarName::CloseOnEncodeError),
+            ST_VARNAME_REDACT_SUBSCRIPTION_PARAMETERS => Ok(StVarName::RedactSubscriptionParameters),
+            ST_VARNAME_MAX_QUERY_LENGTH => Ok(StVarName::MaxQueryLength),
+            ST_VARNAME_MAX_QUERY_PREDICATES => Ok(StVarName::MaxQueryPredicates),
+            ST_VARNAME_MAX_QUERY_JOINS => Ok(StVarName::MaxQueryJoins),
+            ST_VARNAME_LEGACY_ERRORS => Ok(StVarName::LegacyErrors),
+            ST_VARNAME_EPOCH_INTERVAL => Ok(StVarName::EpochInterval),
+            ST_VARNAME_MAX_REDUCER_DURATION => Ok(StVarName::MaxReducerDuration),
+            ST_VARNAME_CLIENT_METRICS_ALLOWLIST => Ok(StVarName::ClientMetricsAllowlist),
+            ST_VARNAME_CLIENT_MESSAGE_RATE => Ok(StVarName::ClientMessageRate),
+            ST_VARNAME_CLIENT_MESSAGE_BURST => Ok(StVarName::ClientMessageBurst),
+            ST_VARNAME_CLIENT_REDUCER_ALLOWLIST => Ok(StVarName::ClientReducerAllowlist),
+            ST_VARNAME_CLIENT_REDUCER_DENYLIST => Ok(StVarName::ClientReducerDenylist),
+            ST_VARNAME_OWNER_BYPASSES_REDUCER_VISIBILITY => Ok(StVarName::OwnerBypassesReducerVisibility),
             _ => Err(anyhow::anyhow!("Invalid system variable {}", s)),
         }
     }
@@ -1039,7 +1121,21 @@ impl StVarName {
             StVarName::RowLimit
             | StVarName::SlowQryThreshold
             | StVarName::SlowSubThreshold
-            | StVarName::SlowIncThreshold => AlgebraicType::U64,
+            | StVarName::SlowIncThreshold
+            | StVarName::MaxQueryLength
+            | StVarName::MaxQueryPredicates
+            | StVarName::MaxQueryJoins
+            | StVarName::EpochInterval
+            | StVarName::MaxReducerDuration
+            | StVarName::ClientMessageRate
+            | StVarName::ClientMessageBurst => AlgebraicType::U64,
+            StVarName::CloseOnEncodeError
+            | StVarName::RedactSubscriptionParameters
+            | StVarName::LegacyErrors
+            | StVarName::OwnerBypassesReducerVisibility => AlgebraicType::Bool,
+            StVarName::ClientMetricsAllowlist
+            | StVarName::ClientReducerAllowlist
+            | StVarName::ClientReducerDenylist => AlgebraicType::String,
         }
     }
 }
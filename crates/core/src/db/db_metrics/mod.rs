@@ -103,17 +103,17 @@ metrics_group!(
 
         #[name = reducer_wasmtime_fuel_used]
         #[help = "The total wasmtime fuel used"]
-        #[labels(db: Identity, reducer: str)]
+        #[labels(db: Identity, reducer: str, transport: str)]
         pub reducer_wasmtime_fuel_used: IntCounterVec,
 
         #[name = reducer_wasm_time_usec]
         #[help = "The total runtime of reducer calls"]
-        #[labels(db: Identity, reducer: str)]
+        #[labels(db: Identity, reducer: str, transport: str)]
         pub reducer_duration_usec: IntCounterVec,
 
         #[name = reducer_abi_time_usec]
         #[help = "The total time spent in reducer ABI calls"]
-        #[labels(db: Identity, reducer: str)]
+        #[labels(db: Identity, reducer: str, transport: str)]
         pub reducer_abi_time_usec: IntCounterVec,
 
         #[name = spacetime_num_delta_queries_evaluated]
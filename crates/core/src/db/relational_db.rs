@@ -30,6 +30,7 @@ use fs2::FileExt;
 use futures::channel::mpsc;
 use futures::StreamExt;
 use parking_lot::RwLock;
+use spacetimedb_client_api_messages::websocket::DatabaseStats;
 use spacetimedb_commitlog as commitlog;
 use spacetimedb_durability::{self as durability, TxOffset};
 use spacetimedb_lib::db::auth::StAccess;
@@ -691,6 +692,13 @@ impl RelationalDB {
         self.inner.heap_usage()
     }
 
+    /// Storage statistics of this database, as of the last committed transaction.
+    ///
+    /// Cheap to compute, as it only reads bookkeeping maintained by each table.
+    pub fn database_stats(&self) -> DatabaseStats {
+        self.inner.committed_state.read().database_stats()
+    }
+
     /// Update data size metrics.
     pub fn update_data_size_metrics(&self) {
         let cs = self.inner.committed_state.read();
@@ -1472,6 +1480,110 @@ impl RelationalDB {
         Ok(None)
     }
 
+    /// Read the value of [ST_VARNAME_CLOSE_ON_ENCODE_ERROR] from `st_var`,
+    /// defaulting to `false`.
+    pub(crate) fn close_on_encode_error(&self, tx: &Tx) -> Result<bool, DBError> {
+        if let Some(StVarValue::Bool(close)) = self.read_var(tx, StVarName::CloseOnEncodeError)? {
+            return Ok(close);
+        }
+        Ok(false)
+    }
+
+    /// Read the value of [ST_VARNAME_REDACT_SUBSCRIPTION_PARAMETERS] from `st_var`,
+    /// defaulting to `false`.
+    pub(crate) fn redact_subscription_parameters(&self, tx: &Tx) -> Result<bool, DBError> {
+        if let Some(StVarValue::Bool(redact)) = self.read_var(tx, StVarName::RedactSubscriptionParameters)? {
+            return Ok(redact);
+        }
+        Ok(false)
+    }
+
+    /// Read the value of [ST_VARNAME_LEGACY_ERRORS] from `st_var`,
+    /// defaulting to `true`.
+    pub(crate) fn legacy_errors(&self, tx: &Tx) -> Result<bool, DBError> {
+        if let Some(StVarValue::Bool(legacy)) = self.read_var(tx, StVarName::LegacyErrors)? {
+            return Ok(legacy);
+        }
+        Ok(true)
+    }
+
+    /// Read the value of [ST_VARNAME_EPOCH_INTERVAL] from `st_var`
+    pub(crate) fn epoch_interval_ms(&self, tx: &Tx) -> Result<Option<u64>, DBError> {
+        if let Some(StVarValue::U64(ms)) = self.read_var(tx, StVarName::EpochInterval)? {
+            return Ok(Some(ms));
+        }
+        Ok(None)
+    }
+
+    /// Read the value of [ST_VARNAME_MAX_REDUCER_DURATION] from `st_var`
+    pub(crate) fn max_reducer_duration_ms(&self, tx: &Tx) -> Result<Option<u64>, DBError> {
+        if let Some(StVarValue::U64(ms)) = self.read_var(tx, StVarName::MaxReducerDuration)? {
+            return Ok(Some(ms));
+        }
+        Ok(None)
+    }
+
+    /// Read the values of [ST_VARNAME_CLIENT_MESSAGE_RATE] and [ST_VARNAME_CLIENT_MESSAGE_BURST] from `st_var`.
+    pub(crate) fn client_message_rate(&self, tx: &Tx) -> Result<(Option<u64>, Option<u64>), DBError> {
+        let read = |name| match self.read_var(tx, name)? {
+            Some(StVarValue::U64(value)) => Ok::<_, DBError>(Some(value)),
+            _ => Ok(None),
+        };
+        Ok((
+            read(StVarName::ClientMessageRate)?,
+            read(StVarName::ClientMessageBurst)?,
+        ))
+    }
+
+    /// Read the value of [ST_VARNAME_CLIENT_METRICS_ALLOWLIST] from `st_var`,
+    /// defaulting to no identity.
+    ///
+    /// Entries which aren't hex identities are ignored.
+    pub(crate) fn client_metrics_allowlist(&self, tx: &Tx) -> Result<Vec<Identity>, DBError> {
+        let Some(StVarValue::String(list)) = self.read_var(tx, StVarName::ClientMetricsAllowlist)? else {
+            return Ok(Vec::new());
+        };
+        Ok(list
+            .split(',')
+            .filter_map(|entry| Identity::from_hex(entry.trim()).ok())
+            .collect())
+    }
+
+    /// Read the value of [ST_VARNAME_CLIENT_REDUCER_ALLOWLIST] from `st_var`,
+    /// defaulting to no limit.
+    pub(crate) fn client_reducer_allowlist(&self, tx: &Tx) -> Result<Option<Vec<String>>, DBError> {
+        let Some(StVarValue::String(list)) = self.read_var(tx, StVarName::ClientReducerAllowlist)? else {
+            return Ok(None);
+        };
+        Ok(Some(Self::split_reducer_names(&list)))
+    }
+
+    /// Read the value of [ST_VARNAME_CLIENT_REDUCER_DENYLIST] from `st_var`,
+    /// defaulting to no reducer.
+    pub(crate) fn client_reducer_denylist(&self, tx: &Tx) -> Result<Vec<String>, DBError> {
+        let Some(StVarValue::String(list)) = self.read_var(tx, StVarName::ClientReducerDenylist)? else {
+            return Ok(Vec::new());
+        };
+        Ok(Self::split_reducer_names(&list))
+    }
+
+    fn split_reducer_names(list: &str) -> Vec<String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// Read the value of [ST_VARNAME_OWNER_BYPASSES_REDUCER_VISIBILITY] from `st_var`,
+    /// defaulting to `false`.
+    pub(crate) fn owner_bypasses_reducer_visibility(&self, tx: &Tx) -> Result<bool, DBError> {
+        if let Some(StVarValue::Bool(bypass)) = self.read_var(tx, StVarName::OwnerBypassesReducerVisibility)? {
+            return Ok(bypass);
+        }
+        Ok(false)
+    }
+
     /// Read the value of a system variable from `st_var`
     pub(crate) fn read_var(&self, tx: &Tx, name: StVarName) -> Result<Option<StVarValue>, DBError> {
         if let Some(row_ref) = self
@@ -2279,6 +2391,43 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_database_stats_only_count_committed_rows() -> ResultTest<()> {
+        let stdb = TestDB::durable()?;
+
+        let stats = |stdb: &RelationalDB| {
+            let stats = stdb.database_stats();
+            let table = stats
+                .tables
+                .iter()
+                .find(|t| &*t.table_name == "MyTable")
+                .cloned()
+                .unwrap();
+            (table, stats.total_bytes)
+        };
+
+        let mut tx = begin_mut_tx(&stdb);
+        let table_id = stdb.create_table(&mut tx, my_table(AlgebraicType::I32))?;
+        stdb.commit_tx(tx)?;
+        assert_eq!(stats(&stdb).0.rows, 0);
+
+        // Rows are only counted once committed.
+        let mut tx = begin_mut_tx(&stdb);
+        insert_three_i32s(&stdb, &mut tx, table_id)?;
+        let _ = stdb.rollback_mut_tx(tx);
+        assert_eq!(stats(&stdb).0.rows, 0);
+
+        let mut tx = begin_mut_tx(&stdb);
+        insert_three_i32s(&stdb, &mut tx, table_id)?;
+        stdb.commit_tx(tx)?;
+
+        let (table, total_bytes) = stats(&stdb);
+        assert_eq!(table.rows, 3);
+        assert!(table.bytes > 0);
+        assert!(total_bytes >= table.bytes + table.index_bytes);
+        Ok(())
+    }
+
     #[test]
     fn test_post_commit() -> ResultTest<()> {
         let stdb = TestDB::durable()?;
@@ -1,7 +1,10 @@
-use super::module_host::{EventStatus, ModuleHost, ModuleInfo, NoSuchModule};
+use super::module_host::{EventStatus, ModuleExitCause, ModuleHost, ModuleInfo, NoSuchModule};
+use super::publish::{PublishMode, PublishOptions};
 use super::scheduler::SchedulerStarter;
+use super::trace_context::TraceParent;
 use super::wasmtime::WasmtimeRuntime;
 use super::{Scheduler, UpdateDatabaseResult};
+use crate::client::{InFlightQueries, PendingDeliveries};
 use crate::database_logger::DatabaseLogger;
 use crate::db::datastore::traits::Program;
 use crate::db::db_metrics::data_size::DATA_SIZE_METRICS;
@@ -11,7 +14,9 @@ use crate::db::{self, spawn_tx_metrics_recorder};
 use crate::energy::{EnergyMonitor, EnergyQuanta, NullEnergyMonitor};
 use crate::messages::control_db::{Database, HostType};
 use crate::module_host_context::ModuleCreationContext;
-use crate::replica_context::ReplicaContext;
+use crate::replica_context::{FeatureFlags, ReplicaContext};
+use crate::subscription::fanout::{FanoutBudget, FanoutOptions};
+use crate::subscription::firehose::Firehose;
 use crate::subscription::module_subscription_actor::ModuleSubscriptions;
 use crate::subscription::module_subscription_manager::{spawn_send_worker, SubscriptionManager};
 use crate::util::asyncify;
@@ -30,6 +35,7 @@ use spacetimedb_paths::FromPathUnchecked;
 use spacetimedb_sats::hash::Hash;
 use spacetimedb_schema::def::ModuleDef;
 use spacetimedb_table::page_pool::PagePool;
+use std::collections::HashMap;
 use std::future::Future;
 use std::ops::Deref;
 use std::sync::Arc;
@@ -102,6 +108,16 @@ pub struct HostController {
     runtimes: Arc<HostRuntimes>,
     /// The CPU cores that are reserved for ModuleHost operations to run on.
     db_cores: JobCores,
+    /// How modules are updated when a database is published to.
+    publish_options: PublishOptions,
+    /// The budget the send workers of all databases fan subscription updates out within.
+    fanout: Arc<FanoutBudget>,
+    /// The firehoses of databases, keyed by database identity,
+    /// which outlive their modules so that their sinks stay attached across launches.
+    firehoses: Arc<Mutex<HashMap<Identity, Firehose>>>,
+    /// The features turned on for databases, keyed by database identity,
+    /// which outlive their modules as their firehoses do.
+    features: Arc<Mutex<HashMap<Identity, FeatureFlags>>>,
 }
 
 struct HostRuntimes {
@@ -120,6 +136,8 @@ pub struct ReducerCallResult {
     pub outcome: ReducerOutcome,
     pub energy_used: EnergyQuanta,
     pub execution_duration: Duration,
+    /// Names the span of the call, e.g. to echo to HTTP callers.
+    pub traceparent: TraceParent,
 }
 
 impl ReducerCallResult {
@@ -170,6 +188,7 @@ impl From<&EventStatus> for ReducerOutcome {
 }
 
 impl HostController {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         data_dir: Arc<ServerDataDir>,
         default_config: db::Config,
@@ -177,6 +196,8 @@ impl HostController {
         energy_monitor: Arc<impl EnergyMonitor>,
         durability: Arc<dyn DurabilityProvider>,
         db_cores: JobCores,
+        publish_options: PublishOptions,
+        fanout_options: FanoutOptions,
     ) -> Self {
         Self {
             hosts: <_>::default(),
@@ -188,9 +209,42 @@ impl HostController {
             data_dir,
             page_pool: PagePool::new(default_config.page_pool_max_size),
             db_cores,
+            publish_options,
+            fanout: Arc::new(FanoutBudget::new(fanout_options)),
+            firehoses: <_>::default(),
+            features: <_>::default(),
         }
     }
 
+    /// The firehose of the database `database_identity`,
+    /// to which a sink may be attached whether or not its module is running.
+    pub fn firehose(&self, database_identity: Identity) -> Firehose {
+        self.firehoses
+            .lock()
+            .entry(database_identity)
+            .or_insert_with(|| Firehose::new(database_identity))
+            .clone()
+    }
+
+    /// Detach the sink of the firehose of the database `database_identity`, if any, and forget the firehose,
+    /// e.g. as the database has been deleted.
+    pub fn remove_firehose(&self, database_identity: &Identity) {
+        if let Some(firehose) = self.firehoses.lock().remove(database_identity) {
+            firehose.set(None);
+        }
+    }
+
+    /// The features turned on for the database `database_identity`,
+    /// which may be set whether or not its module is running.
+    pub fn features(&self, database_identity: Identity) -> FeatureFlags {
+        self.features.lock().entry(database_identity).or_default().clone()
+    }
+
+    /// Forget the features turned on for the database `database_identity`, e.g. as the database has been deleted.
+    pub fn remove_features(&self, database_identity: &Identity) {
+        self.features.lock().remove(database_identity);
+    }
+
     /// Replace the [`ProgramStorage`] used by this controller.
     pub fn set_program_storage(&mut self, ps: ProgramStorage) {
         self.program_storage = ps;
@@ -401,6 +455,7 @@ impl HostController {
                     this.energy_monitor.clone(),
                     this.unregister_fn(replica_id),
                     this.db_cores.take(),
+                    &this.publish_options,
                 )
                 .await?;
 
@@ -503,6 +558,7 @@ impl HostController {
                         this.energy_monitor.clone(),
                         this.unregister_fn(replica_id),
                         this.db_cores.take(),
+                        &this.publish_options,
                     )
                     .await?;
                 match update_result {
@@ -527,13 +583,17 @@ impl HostController {
 
     /// Release all resources of the [`ModuleHost`] identified by `replica_id`,
     /// and deregister it from the controller.
+    ///
+    /// Client connections watching the host observe that it exited because of `cause`,
+    /// unless another cause was recorded first.
     #[tracing::instrument(level = "trace", skip_all)]
-    pub async fn exit_module_host(&self, replica_id: u64) -> Result<(), anyhow::Error> {
+    pub async fn exit_module_host(&self, replica_id: u64, cause: ModuleExitCause) -> Result<(), anyhow::Error> {
         trace!("exit module host {}", replica_id);
         let lock = self.hosts.lock().remove(&replica_id);
         if let Some(lock) = lock {
             if let Some(host) = lock.write_owned().await.take() {
                 let module = host.module.borrow().clone();
+                let _ = module.info().exit_cause.set(cause);
                 module.exit().await;
                 let table_names = module.info().module_def.tables().map(|t| t.name.deref());
                 remove_database_gauges(&module.info().database_identity, table_names);
@@ -616,9 +676,12 @@ async fn make_replica_ctx(
     database: Database,
     replica_id: u64,
     relational_db: Arc<RelationalDB>,
+    fanout: Arc<FanoutBudget>,
+    firehose: Firehose,
+    features: FeatureFlags,
 ) -> anyhow::Result<ReplicaContext> {
     let logger = tokio::task::block_in_place(move || Arc::new(DatabaseLogger::open_today(path.module_logs())));
-    let send_worker_queue = spawn_send_worker(Some(database.database_identity));
+    let send_worker_queue = spawn_send_worker(Some(database.database_identity), fanout);
     let subscriptions = Arc::new(parking_lot::RwLock::new(SubscriptionManager::new(
         send_worker_queue.clone(),
     )));
@@ -628,6 +691,7 @@ async fn make_replica_ctx(
         subscriptions,
         send_worker_queue,
         database.owner_identity,
+        firehose,
     );
 
     // If an error occurs when evaluating a subscription,
@@ -643,12 +707,21 @@ async fn make_replica_ctx(
         }
     });
 
+    let one_off_queries = Arc::new(InFlightQueries::for_database(database.database_identity));
+    let deliveries = PendingDeliveries::for_database(database.database_identity);
+    deliveries.spawn_sweeper();
     Ok(ReplicaContext {
         database,
         replica_id,
         logger,
         subscriptions,
         relational_db,
+        clients: <_>::default(),
+        deliveries,
+        one_off_queries,
+        module_generation: <_>::default(),
+        publish_gate: <_>::default(),
+        features,
     })
 }
 
@@ -716,13 +789,24 @@ async fn launch_module(
     replica_dir: ReplicaDir,
     runtimes: Arc<HostRuntimes>,
     core: JobCore,
+    fanout: Arc<FanoutBudget>,
+    firehose: Firehose,
+    features: FeatureFlags,
 ) -> anyhow::Result<(Program, LaunchedModule)> {
     let db_identity = database.database_identity;
     let host_type = database.host_type;
 
-    let replica_ctx = make_replica_ctx(replica_dir, database, replica_id, relational_db)
-        .await
-        .map(Arc::new)?;
+    let replica_ctx = make_replica_ctx(
+        replica_dir,
+        database,
+        replica_id,
+        relational_db,
+        fanout,
+        firehose,
+        features,
+    )
+    .await
+    .map(Arc::new)?;
     let (scheduler, scheduler_starter) = Scheduler::open(replica_ctx.relational_db.clone());
     let (program, module_host) = make_module_host(
         runtimes.clone(),
@@ -880,6 +964,8 @@ impl Host {
             None => (load_program(program_storage, database.initial_program).await?, true),
         };
 
+        let firehose = host_controller.firehose(database.database_identity);
+        let features = host_controller.features(database.database_identity);
         let (program, launched) = launch_module(
             database,
             replica_id,
@@ -890,6 +976,9 @@ impl Host {
             replica_dir,
             runtimes.clone(),
             host_controller.db_cores.take(),
+            host_controller.fanout.clone(),
+            firehose,
+            features,
         )
         .await?;
 
@@ -970,6 +1059,7 @@ impl Host {
             page_pool,
         )?;
 
+        let firehose = Firehose::new(database.database_identity);
         let (program, launched) = launch_module(
             database,
             0,
@@ -983,6 +1073,12 @@ impl Host {
             phony_replica_dir,
             runtimes.clone(),
             core,
+            // This module has no clients to fan updates out to,
+            // nor a firehose to tee them into.
+            <_>::default(),
+            firehose,
+            // Nor are any features turned on for it.
+            <_>::default(),
         )
         .await?;
 
@@ -1006,6 +1102,11 @@ impl Host {
     /// otherwise it stays the same.
     ///
     /// Either way, the [`UpdateDatabaseResult`] is returned.
+    ///
+    /// If `publish.mode` is [`PublishMode::Coordinated`],
+    /// reducer calls from clients are refused from when the new module is ready to be migrated to,
+    /// until the subscriptions it invalidates have been dropped.
+    #[allow(clippy::too_many_arguments)]
     async fn update_module(
         &mut self,
         runtimes: Arc<HostRuntimes>,
@@ -1014,6 +1115,7 @@ impl Host {
         energy_monitor: Arc<dyn EnergyMonitor>,
         on_panic: impl Fn() + Send + Sync + 'static,
         core: JobCore,
+        publish: &PublishOptions,
     ) -> anyhow::Result<UpdateDatabaseResult> {
         let replica_ctx = &self.replica_ctx;
         let (scheduler, scheduler_starter) = Scheduler::open(self.replica_ctx.relational_db.clone());
@@ -1033,6 +1135,25 @@ impl Host {
         // Get the old module info to diff against when building a migration plan.
         let old_module_info = self.module.borrow().info.clone();
 
+        let pause = match publish.mode {
+            PublishMode::Coordinated => {
+                let pause = replica_ctx
+                    .publish_gate
+                    .pause(replica_ctx.database_identity, publish)
+                    .await;
+                if !pause.drained() {
+                    warn!(
+                        "updating `{}` with {} reducer calls still in flight after {:?}",
+                        replica_ctx.database_identity,
+                        replica_ctx.publish_gate.in_flight(),
+                        publish.drain_timeout
+                    );
+                }
+                Some(pause)
+            }
+            PublishMode::Immediate => None,
+        };
+
         let update_result = update_module(&replica_ctx.relational_db, &module, program, old_module_info).await?;
         trace!("update result: {update_result:?}");
         // Only replace the module + scheduler if the update succeeded.
@@ -1040,7 +1161,21 @@ impl Host {
         if update_result.was_successful() {
             self.scheduler = scheduler;
             scheduler_starter.start(&module)?;
+            // Mark the old module as superseded before notifying watchers,
+            // so that they can measure how long it took them to notice.
+            let _ = self.module.borrow().info.superseded_at.set(Instant::now());
             let old_module = self.module.send_replace(module);
+            if pause.is_some() {
+                let subscriptions = replica_ctx.subscriptions.clone();
+                let dropped = asyncify(move || subscriptions.remove_invalid_subscriptions()).await;
+                if dropped > 0 {
+                    info!(
+                        "dropped {dropped} subscriptions to `{}` invalidated by the update",
+                        replica_ctx.database_identity
+                    );
+                }
+            }
+            drop(pause);
             old_module.exit().await;
         }
 
@@ -1054,6 +1189,8 @@ impl Host {
 
 impl Drop for Host {
     fn drop(&mut self) {
+        // Dropping `self.module` will notify watchers that the module has exited.
+        let _ = self.module.borrow().info.superseded_at.set(Instant::now());
         self.disk_metrics_recorder_task.abort();
         self.tx_metrics_recorder_task.abort();
     }
@@ -1146,4 +1283,5 @@ where
         .data_size_blob_store_bytes_used_by_blobs
         .remove_label_values(db);
     let _ = WORKER_METRICS.wasm_memory_bytes.remove_label_values(db);
+    let _ = WORKER_METRICS.one_off_queries_in_flight.remove_label_values(db);
 }
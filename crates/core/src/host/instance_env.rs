@@ -1,4 +1,6 @@
+use super::progress::ProgressSink;
 use super::scheduler::{get_schedule_from_row, ScheduleError, Scheduler};
+use crate::client::{ClientConnectionSender, CloseReason};
 use crate::database_logger::{BacktraceProvider, LogLevel, Record};
 use crate::db::datastore::locking_tx_datastore::MutTxId;
 use crate::db::relational_db::{MutTx, RelationalDB};
@@ -7,7 +9,7 @@ use crate::replica_context::ReplicaContext;
 use core::mem;
 use parking_lot::{Mutex, MutexGuard};
 use smallvec::SmallVec;
-use spacetimedb_lib::Timestamp;
+use spacetimedb_lib::{ClientTarget, ReducerTransport, Timestamp};
 use spacetimedb_primitives::{ColId, ColList, IndexId, TableId};
 use spacetimedb_sats::{
     bsatn::{self, ToBsatn},
@@ -26,6 +28,12 @@ pub struct InstanceEnv {
     pub tx: TxSlot,
     /// The timestamp the current reducer began running.
     pub start_time: Timestamp,
+    /// How the current reducer call arrived.
+    pub transport: ReducerTransport,
+    /// The client connections the current reducer asked to disconnect.
+    pub disconnects: PendingDisconnects,
+    /// The caller to which the current reducer's progress is sent.
+    pub progress: ProgressSink,
 }
 
 #[derive(Clone, Default)]
@@ -33,6 +41,17 @@ pub struct TxSlot {
     inner: Arc<Mutex<Option<MutTxId>>>,
 }
 
+/// Client connections to close once the current transaction commits.
+///
+/// These are dropped rather than closed if the transaction is rolled back.
+#[derive(Clone, Default)]
+pub struct PendingDisconnects {
+    inner: Arc<Mutex<Vec<PendingDisconnect>>>,
+}
+
+/// A connection to close, and the reason it's closed with.
+type PendingDisconnect = (Arc<ClientConnectionSender>, CloseReason);
+
 /// The maximum number of chunks stored in a single [`ChunkPool`].
 ///
 /// When returning a chunk to the pool via [`ChunkPool::put`],
@@ -167,12 +186,16 @@ impl InstanceEnv {
             scheduler,
             tx: TxSlot::default(),
             start_time: Timestamp::now(),
+            transport: ReducerTransport::default(),
+            disconnects: PendingDisconnects::default(),
+            progress: ProgressSink::default(),
         }
     }
 
     /// Signal to this `InstanceEnv` that a reducer call is beginning.
-    pub fn start_reducer(&mut self, ts: Timestamp) {
+    pub fn start_reducer(&mut self, ts: Timestamp, transport: ReducerTransport) {
         self.start_time = ts;
+        self.transport = transport;
     }
 
     fn get_tx(&self) -> Result<impl DerefMut<Target = MutTxId> + '_, GetTxError> {
@@ -372,6 +395,41 @@ impl InstanceEnv {
         Ok(stdb.delete_by_rel(tx, table_id, relation))
     }
 
+    /// Disconnects the clients matching the BSATN-encoded [`ClientTarget`] `target`
+    /// once the current transaction commits,
+    /// telling them they were kicked by the module for `reason`.
+    ///
+    /// Returns the number of connections which matched.
+    ///
+    /// Errors with `GetTxError` if not in a transaction
+    /// and `DecodeValue` if `target` couldn't be decoded.
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn disconnect_client(&self, target: &[u8], reason: &str) -> Result<u32, NodesError> {
+        self.get_tx()?;
+        let target = bsatn::from_slice::<ClientTarget>(target).map_err(NodesError::DecodeValue)?;
+
+        let conns = self.replica_ctx.clients.matching(&target);
+        let matched = conns.len() as u32;
+        let reason = CloseReason::KickedByModule(reason.into());
+        self.disconnects
+            .inner
+            .lock()
+            .extend(conns.into_iter().map(|conn| (conn, reason.clone())));
+        Ok(matched)
+    }
+
+    /// Sends `payload` to the caller of the current reducer as its progress, unless it's dropped,
+    /// whether or not the current transaction goes on to commit.
+    ///
+    /// Returns whether the progress was sent.
+    ///
+    /// Errors with `GetTxError` if not in a transaction.
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn report_progress(&self, payload: &[u8]) -> Result<bool, NodesError> {
+        self.get_tx()?;
+        Ok(self.progress.report(payload))
+    }
+
     /// Returns the `table_id` associated with the given `table_name`.
     ///
     /// Errors with `GetTxError` if not in a transaction
@@ -491,6 +549,20 @@ impl TxSlot {
     }
 }
 
+impl PendingDisconnects {
+    /// Close the pending connections, as the transaction committed.
+    pub fn apply(&self) {
+        for (conn, reason) in self.inner.lock().drain(..) {
+            conn.request_close(reason);
+        }
+    }
+
+    /// Forget the pending connections, as the transaction was rolled back.
+    pub fn clear(&self) {
+        self.inner.lock().clear();
+    }
+}
+
 #[derive(Debug)]
 pub struct GetTxError;
 impl From<GetTxError> for NodesError {
@@ -549,6 +621,12 @@ mod test {
                 logger: Arc::new(temp_logger()?),
                 subscriptions: subs,
                 relational_db,
+                clients: <_>::default(),
+                deliveries: <_>::default(),
+                one_off_queries: <_>::default(),
+                module_generation: <_>::default(),
+                publish_gate: <_>::default(),
+                features: <_>::default(),
             },
             runtime,
         ))
@@ -564,6 +642,9 @@ mod test {
                 scheduler,
                 tx: TxSlot::default(),
                 start_time: Timestamp::now(),
+                transport: ReducerTransport::default(),
+                disconnects: PendingDisconnects::default(),
+                progress: ProgressSink::default(),
             },
             runtime,
         ))
@@ -0,0 +1,154 @@
+//! How the reducers of a database's module are metered while they run.
+//!
+//! Wasm modules are metered by fuel, which bounds each reducer call by its energy budget,
+//! and by epoch interruption, which checks on a running reducer at an interval,
+//! warning that it's still running and, past its database's `max_reducer_duration_ms`, interrupting it.
+//! A runaway reducer is therefore interrupted up to an interval late,
+//! all the while blocking the ordered lane of the connection which called it.
+//!
+//! The interval and the limit are set per database by the `epoch_interval_ms` and `max_reducer_duration_ms`
+//! system variables, which are read as the module is instantiated,
+//! so changes apply to the instances created after them, e.g. once an instance traps.
+
+use std::time::Duration;
+
+use crate::db::relational_db::RelationalDB;
+use crate::execution_context::Workload;
+
+/// How often the epoch of the wasm engine advances,
+/// the finest interval at which running reducers can be checked on.
+pub const EPOCH_TICK_LENGTH: Duration = Duration::from_millis(10);
+
+/// How often running reducers are checked on, unless their database sets `epoch_interval_ms`.
+pub const DEFAULT_EPOCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How reducers are interrupted by the epoch of the wasm engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EpochInterruption {
+    /// How many epoch ticks pass between checks on a running reducer, at least one.
+    pub interval_ticks: u64,
+    /// How long a reducer may run before it's interrupted at a check, if limited.
+    pub max_reducer_duration: Option<Duration>,
+}
+
+impl Default for EpochInterruption {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl EpochInterruption {
+    /// Interruption every `interval_ms`, rounded up to a whole number of [`EPOCH_TICK_LENGTH`]s,
+    /// or every [`DEFAULT_EPOCH_INTERVAL`], of reducers running longer than `max_reducer_duration_ms`, if any.
+    pub fn new(interval_ms: Option<u64>, max_reducer_duration_ms: Option<u64>) -> Self {
+        let interval = interval_ms.map_or(DEFAULT_EPOCH_INTERVAL, Duration::from_millis);
+        let interval_ticks = interval.as_nanos().div_ceil(EPOCH_TICK_LENGTH.as_nanos()).max(1);
+        Self {
+            interval_ticks: interval_ticks.try_into().unwrap_or(u64::MAX),
+            max_reducer_duration: max_reducer_duration_ms.map(Duration::from_millis),
+        }
+    }
+
+    /// The interruption configured by the system variables of `db`,
+    /// or the default, should they fail to be read.
+    pub fn of_database(db: &RelationalDB) -> Self {
+        let vars = db.with_read_only(Workload::Internal, |tx| {
+            Ok::<_, crate::error::DBError>((db.epoch_interval_ms(tx)?, db.max_reducer_duration_ms(tx)?))
+        });
+        match vars {
+            Ok((interval_ms, max_reducer_duration_ms)) => Self::new(interval_ms, max_reducer_duration_ms),
+            Err(e) => {
+                log::error!(
+                    "failed to read the epoch interruption of {}: {e}",
+                    db.database_identity()
+                );
+                Self::default()
+            }
+        }
+    }
+
+    /// How often a running reducer is checked on.
+    pub fn interval(&self) -> Duration {
+        EPOCH_TICK_LENGTH.saturating_mul(self.interval_ticks.try_into().unwrap_or(u32::MAX))
+    }
+
+    /// Whether a reducer which has been running for `elapsed` should be interrupted.
+    pub fn should_interrupt(&self, elapsed: Duration) -> bool {
+        self.max_reducer_duration.is_some_and(|max| elapsed >= max)
+    }
+}
+
+/// How the reducers of a database's module are metered, as applied to its next instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionMetering {
+    /// Whether reducers are metered by fuel, bounding each call by its energy budget.
+    pub fuel: bool,
+    /// How reducers are interrupted by epoch, if they are.
+    pub epoch: Option<EpochInterruption>,
+}
+
+impl ExecutionMetering {
+    /// The metering of the wasm modules of `db`, which consume fuel and are interrupted by epoch.
+    pub fn of_database(db: &RelationalDB) -> Self {
+        Self {
+            fuel: true,
+            epoch: Some(EpochInterruption::of_database(db)),
+        }
+    }
+
+    /// The name of the kind of metering, one of `fuel+epoch`, `fuel`, `epoch` or `none`.
+    pub fn mode(&self) -> &'static str {
+        match (self.fuel, self.epoch.is_some()) {
+            (true, true) => "fuel+epoch",
+            (true, false) => "fuel",
+            (false, true) => "epoch",
+            (false, false) => "none",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::datastore::system_tables::StVarName;
+    use crate::db::relational_db::tests_utils::{with_auto_commit, TestDB};
+
+    #[test]
+    fn intervals_are_rounded_up_to_whole_ticks() {
+        let interval = |ms| EpochInterruption::new(ms, None).interval();
+        assert_eq!(interval(None), DEFAULT_EPOCH_INTERVAL);
+        assert_eq!(interval(Some(0)), EPOCH_TICK_LENGTH);
+        assert_eq!(interval(Some(1)), EPOCH_TICK_LENGTH);
+        assert_eq!(interval(Some(25)), 3 * EPOCH_TICK_LENGTH);
+        assert_eq!(
+            EpochInterruption::new(Some(u64::MAX), None).interval_ticks,
+            u64::MAX.div_ceil(10)
+        );
+    }
+
+    #[test]
+    fn reducers_are_interrupted_only_past_the_limit() {
+        let second = Duration::from_secs(1);
+        assert!(!EpochInterruption::default().should_interrupt(3600 * second));
+        let limited = EpochInterruption::new(None, Some(100));
+        assert!(!limited.should_interrupt(Duration::from_millis(99)));
+        assert!(limited.should_interrupt(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn metering_is_configured_by_system_variables() -> anyhow::Result<()> {
+        let db = TestDB::durable()?;
+        let metering = ExecutionMetering::of_database(&db);
+        assert_eq!(metering.mode(), "fuel+epoch");
+        assert_eq!(metering.epoch, Some(EpochInterruption::default()));
+
+        with_auto_commit(&db, |tx| {
+            db.write_var(tx, StVarName::EpochInterval, "50")?;
+            db.write_var(tx, StVarName::MaxReducerDuration, "200")
+        })?;
+        let epoch = ExecutionMetering::of_database(&db).epoch.unwrap();
+        assert_eq!(epoch.interval(), Duration::from_millis(50));
+        assert_eq!(epoch.max_reducer_duration, Some(Duration::from_millis(200)));
+        Ok(())
+    }
+}
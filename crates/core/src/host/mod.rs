@@ -12,9 +12,15 @@ use spacetimedb_schema::def::deserialize::ReducerArgsDeserializeSeed;
 
 mod disk_storage;
 mod host_controller;
+pub mod metering;
 #[allow(clippy::too_many_arguments)]
 pub mod module_host;
+pub mod module_schema;
+pub mod progress;
+pub mod publish;
+pub mod reducer_visibility;
 pub mod scheduler;
+pub mod trace_context;
 pub mod wasmtime;
 // Visible for integration testing.
 pub mod instance_env;
@@ -25,7 +31,8 @@ pub use host_controller::{
     extract_schema, DurabilityProvider, ExternalDurability, ExternalStorage, HostController, ProgramStorage,
     ReducerCallResult, ReducerOutcome, StartSnapshotWatcher,
 };
-pub use module_host::{ModuleHost, NoSuchModule, ReducerCallError, UpdateDatabaseResult};
+pub use module_host::{ModuleExitCause, ModuleHost, NoSuchModule, ReducerCallError, UpdateDatabaseResult};
+pub use publish::{PublishMode, PublishOptions, Publishing};
 pub use scheduler::Scheduler;
 
 #[derive(Debug)]
@@ -141,6 +148,10 @@ pub enum AbiCall {
     ConsoleTimerStart,
     ConsoleTimerEnd,
     Identity,
+    DisconnectClient,
+    ReducerTransport,
+    ReportProgress,
+    DatabaseFeatures,
 
     VolatileNonatomicScheduleImmediate,
 }
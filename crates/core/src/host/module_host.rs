@@ -1,22 +1,31 @@
+use super::metering::ExecutionMetering;
+use super::module_schema::ModuleSchema;
+use super::publish::Publishing;
+use super::reducer_visibility::ClientReducerPolicy;
+use super::trace_context::{ReducerTrace, TraceId, TraceParent};
 use super::{ArgsTuple, InvalidReducerArguments, ReducerArgs, ReducerCallResult, ReducerId, ReducerOutcome, Scheduler};
-use crate::client::messages::{OneOffQueryResponseMessage, SerializableMessage};
-use crate::client::{ClientActorId, ClientConnectionSender};
+use crate::client::messages::{OneOffQueryResponseMessage, QueryRowsMessage, SerializableMessage};
+use crate::client::{
+    ClientActorId, ClientConfig, ClientConnectionSender, ClientName, EncodeErrorPolicy, ErrorFormat, MessageRateLimit,
+    OneOffQueryLimits, PendingDeliveries, Protocol, QueryBudget,
+};
 use crate::database_logger::{LogLevel, Record};
 use crate::db::datastore::locking_tx_datastore::MutTxId;
 use crate::db::datastore::traits::{IsolationLevel, Program, TxData};
 use crate::energy::EnergyQuanta;
 use crate::error::DBError;
-use crate::estimation::estimate_rows_scanned;
+use crate::estimation::{estimate_rows_scanned, row_estimate};
 use crate::execution_context::{ExecutionContext, ReducerContext, Workload, WorkloadType};
 use crate::hash::Hash;
 use crate::identity::Identity;
 use crate::messages::control_db::Database;
 use crate::replica_context::ReplicaContext;
-use crate::sql::ast::SchemaViewer;
+use crate::sql::ast::{record_query_limit_rejection, SchemaViewer};
 use crate::sql::parser::RowLevelExpr;
-use crate::subscription::execute_plan;
+use crate::subscription::dump::ClientSubscriptions;
 use crate::subscription::module_subscription_actor::ModuleSubscriptions;
 use crate::subscription::tx::DeltaTx;
+use crate::subscription::{execute_plan, execute_plan_in_chunks};
 use crate::util::asyncify;
 use crate::util::jobs::{JobCore, JobThread, JobThreadClosed, WeakJobThread};
 use crate::vm::check_row_limit;
@@ -26,8 +35,10 @@ use bytes::Bytes;
 use derive_more::From;
 use indexmap::IndexSet;
 use itertools::Itertools;
-use prometheus::{Histogram, IntGauge};
-use spacetimedb_client_api_messages::websocket::{ByteListLen, Compression, OneOffTable, QueryUpdate, WebsocketFormat};
+use prometheus::{Gauge, Histogram, IntGauge};
+use spacetimedb_client_api_messages::websocket::{
+    ByteListLen, Compression, DatabaseStats, OneOffTable, QueryUpdate, ReducerTimings, WebsocketFormat,
+};
 use spacetimedb_data_structures::error_stream::ErrorStream;
 use spacetimedb_data_structures::map::{HashCollectionExt as _, IntMap};
 use spacetimedb_execution::pipelined::PipelinedProject;
@@ -35,7 +46,10 @@ use spacetimedb_lib::db::raw_def::v9::Lifecycle;
 use spacetimedb_lib::identity::{AuthCtx, RequestId};
 use spacetimedb_lib::metrics::ExecutionMetrics;
 use spacetimedb_lib::ConnectionId;
+use spacetimedb_lib::ReducerTransport;
 use spacetimedb_lib::Timestamp;
+use spacetimedb_physical_plan::explain::Explain;
+use spacetimedb_physical_plan::plan::ProjectPlan;
 use spacetimedb_primitives::TableId;
 use spacetimedb_query::compile_subscription;
 use spacetimedb_sats::ProductValue;
@@ -43,9 +57,10 @@ use spacetimedb_schema::auto_migrate::AutoMigrateError;
 use spacetimedb_schema::def::deserialize::ReducerArgsDeserializeSeed;
 use spacetimedb_schema::def::{ModuleDef, ReducerDef};
 use spacetimedb_schema::schema::{Schema, TableSchema};
+use spacetimedb_subscription::SubscriptionPlan;
 use spacetimedb_vm::relation::RelValue;
 use std::fmt;
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, OnceLock, Weak};
 use std::time::{Duration, Instant};
 
 #[derive(Debug, Default, Clone, From)]
@@ -133,12 +148,18 @@ impl UpdatesRelValue<'_> {
         !(self.deletes.is_empty() && self.inserts.is_empty())
     }
 
-    pub fn encode<F: WebsocketFormat>(&self) -> (F::QueryUpdate, u64, usize) {
+    /// Encode the updates, whose rows carry the columns in `columns`,
+    /// as in [`QueryUpdate::columns`].
+    pub fn encode<F: WebsocketFormat>(&self, columns: Box<[u16]>) -> (F::QueryUpdate, u64, usize) {
         let (deletes, nr_del) = F::encode_list(self.deletes.iter());
         let (inserts, nr_ins) = F::encode_list(self.inserts.iter());
         let num_rows = nr_del + nr_ins;
         let num_bytes = deletes.num_bytes() + inserts.num_bytes();
-        let qu = QueryUpdate { deletes, inserts };
+        let qu = QueryUpdate {
+            deletes,
+            inserts,
+            columns,
+        };
         // We don't compress individual table updates.
         // Previously we were, but the benefits, if any, were unclear.
         // Note, each message is still compressed before being sent to clients,
@@ -171,6 +192,76 @@ pub struct ModuleFunctionCall {
     pub args: ArgsTuple,
 }
 
+/// Times the phases of a reducer call, to report them to the caller as [`ReducerTimings`].
+///
+/// Each phase ends when the next one is marked as begun,
+/// so the phases add up to the total.
+#[derive(Clone, Copy, Debug)]
+pub struct ReducerPhaseTimer {
+    started: Instant,
+    phase_started: Instant,
+    decode: Duration,
+    wait: Duration,
+    execute: Duration,
+    commit: Duration,
+}
+
+impl ReducerPhaseTimer {
+    /// Start timing a call, which begins by decoding its arguments.
+    pub fn start() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            phase_started: now,
+            decode: Duration::ZERO,
+            wait: Duration::ZERO,
+            execute: Duration::ZERO,
+            commit: Duration::ZERO,
+        }
+    }
+
+    fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.phase_started;
+        self.phase_started = now;
+        elapsed
+    }
+
+    /// The arguments were decoded, and the call now waits for the module.
+    pub fn decoded(&mut self) {
+        self.decode = self.lap();
+    }
+
+    /// The module began running the call.
+    pub fn began_executing(&mut self) {
+        self.wait = self.lap();
+    }
+
+    /// The reducer returned, and its transaction is about to be committed.
+    pub fn executed(&mut self) {
+        self.execute = self.lap();
+    }
+
+    /// The transaction was committed, and subscriptions are about to be evaluated.
+    pub fn committed(&mut self) {
+        self.commit = self.lap();
+    }
+
+    /// Subscriptions were evaluated, which ends the call.
+    pub fn finish(mut self, request_id: RequestId) -> ReducerTimings {
+        let subscription_eval = self.lap();
+        ReducerTimings {
+            request_id,
+            total: (self.phase_started - self.started).into(),
+            decode: self.decode.into(),
+            wait: self.wait.into(),
+            execute: self.execute.into(),
+            commit: self.commit.into(),
+            subscription_eval: subscription_eval.into(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ModuleEvent {
     pub timestamp: Timestamp,
@@ -182,6 +273,11 @@ pub struct ModuleEvent {
     pub host_execution_duration: Duration,
     pub request_id: Option<RequestId>,
     pub timer: Option<Instant>,
+    /// Whether clients which opted into acknowledged delivery must acknowledge the update of this event,
+    /// see [`ReducerDef::acknowledged`](spacetimedb_schema::def::ReducerDef::acknowledged).
+    pub acknowledged: bool,
+    /// The trace of the reducer call, if this event is one.
+    pub trace_id: Option<TraceId>,
 }
 
 /// Information about a running module.
@@ -196,12 +292,22 @@ pub struct ModuleInfo {
     pub database_identity: Identity,
     /// The hash of the module.
     pub module_hash: Hash,
+    /// The module's schema, as served to clients.
+    pub schema: ModuleSchema,
     /// Allows subscribing to module logs.
     pub log_tx: tokio::sync::broadcast::Sender<bytes::Bytes>,
     /// Subscriptions to this module.
     pub subscriptions: ModuleSubscriptions,
     /// Metrics handles for this module.
     pub metrics: ModuleMetrics,
+    /// When this module was replaced by an update or exited,
+    /// used to measure how long it takes client connections to notice.
+    pub superseded_at: OnceLock<Instant>,
+    /// Why this module exited, if that was recorded before it did.
+    ///
+    /// The first cause recorded wins.
+    /// A module which exits without a recorded cause is taken to have crashed.
+    pub exit_cause: OnceLock<ModuleExitCause>,
 }
 
 impl fmt::Debug for ModuleInfo {
@@ -211,6 +317,7 @@ impl fmt::Debug for ModuleInfo {
             .field("owner_identity", &self.owner_identity)
             .field("database_identity", &self.database_identity)
             .field("module_hash", &self.module_hash)
+            .field("generation", &self.schema.generation)
             .finish()
     }
 }
@@ -220,6 +327,11 @@ pub struct ModuleMetrics {
     pub connected_clients: IntGauge,
     pub ws_clients_spawned: IntGauge,
     pub ws_clients_aborted: IntGauge,
+    pub ws_clients_cleaned_up_before_start: IntGauge,
+    pub module_watchers: IntGauge,
+    pub module_update_observe_latency: Histogram,
+    pub module_exit_observe_latency: Histogram,
+    pub module_exit_last_observed: Gauge,
     pub request_round_trip_subscribe: Histogram,
     pub request_round_trip_unsubscribe: Histogram,
     pub request_round_trip_sql: Histogram,
@@ -230,6 +342,12 @@ impl ModuleMetrics {
         let connected_clients = WORKER_METRICS.connected_clients.with_label_values(db);
         let ws_clients_spawned = WORKER_METRICS.ws_clients_spawned.with_label_values(db);
         let ws_clients_aborted = WORKER_METRICS.ws_clients_aborted.with_label_values(db);
+        let ws_clients_cleaned_up_before_start =
+            WORKER_METRICS.ws_clients_cleaned_up_before_start.with_label_values(db);
+        let module_watchers = WORKER_METRICS.module_watchers.with_label_values(db);
+        let module_update_observe_latency = WORKER_METRICS.module_update_observe_latency.with_label_values(db);
+        let module_exit_observe_latency = WORKER_METRICS.module_exit_observe_latency.with_label_values(db);
+        let module_exit_last_observed = WORKER_METRICS.module_exit_last_observed.with_label_values(db);
         let request_round_trip_subscribe =
             WORKER_METRICS
                 .request_round_trip
@@ -245,6 +363,11 @@ impl ModuleMetrics {
             connected_clients,
             ws_clients_spawned,
             ws_clients_aborted,
+            ws_clients_cleaned_up_before_start,
+            module_watchers,
+            module_update_observe_latency,
+            module_exit_observe_latency,
+            module_exit_last_observed,
             request_round_trip_subscribe,
             request_round_trip_unsubscribe,
             request_round_trip_sql,
@@ -260,18 +383,23 @@ impl ModuleInfo {
         owner_identity: Identity,
         database_identity: Identity,
         module_hash: Hash,
+        generation: u64,
         log_tx: tokio::sync::broadcast::Sender<bytes::Bytes>,
         subscriptions: ModuleSubscriptions,
     ) -> Arc<Self> {
         let metrics = ModuleMetrics::new(&database_identity);
+        let schema = ModuleSchema::new(&module_def, module_hash, generation);
         Arc::new(ModuleInfo {
             module_def,
             owner_identity,
             database_identity,
             module_hash,
+            schema,
             log_tx,
             subscriptions,
             metrics,
+            superseded_at: OnceLock::new(),
+            exit_cause: OnceLock::new(),
         })
     }
 }
@@ -383,9 +511,10 @@ fn init_database(
             None
         }
 
-        Some((reducer_id, _)) => {
+        Some((reducer_id, reducer_def)) => {
             logger.info("Invoking `init` reducer");
             let caller_identity = replica_ctx.database.owner_identity;
+            let trace = ReducerTrace::start(&reducer_def.name, None);
             Some(inst.call_reducer(
                 Some(tx),
                 CallReducerParams {
@@ -397,6 +526,9 @@ fn init_database(
                     timer: None,
                     reducer_id,
                     args: ArgsTuple::nullary(),
+                    phase_timer: None,
+                    transport: ReducerTransport::Internal,
+                    trace,
                 },
             ))
         }
@@ -415,6 +547,12 @@ pub struct CallReducerParams {
     pub timer: Option<Instant>,
     pub reducer_id: ReducerId,
     pub args: ArgsTuple,
+    /// Set if `client` asked for the [`ReducerTimings`] of its calls.
+    pub phase_timer: Option<ReducerPhaseTimer>,
+    /// How the call arrived, as told to the module and recorded in metrics.
+    pub transport: ReducerTransport,
+    /// The trace the spans of the call are recorded in.
+    pub trace: ReducerTrace,
 }
 
 // TODO: figure out how we want to handle traps. maybe it should just not return to the LendingPool and
@@ -494,10 +632,43 @@ impl UpdateDatabaseResult {
     }
 }
 
+/// How a query passed to [`ModuleHost::explain_query`] would be evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryKind {
+    /// As a subscription, which must only join on indexed columns.
+    Subscription,
+    /// As a one-off query.
+    OneOff,
+}
+
 #[derive(thiserror::Error, Debug)]
 #[error("no such module")]
 pub struct NoSuchModule;
 
+/// Why a [`ModuleHost`] exited, as observed by the client connections watching it.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModuleExitCause {
+    /// The module crashed, or exited without a cause being recorded.
+    #[error("module crashed")]
+    Crashed,
+    /// The database was cleared to be replaced by a publish.
+    #[error("module replaced by a publish")]
+    Published,
+    /// The database was deleted.
+    #[error("database deleted")]
+    Deleted,
+}
+
+impl ModuleExitCause {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Crashed => "crash",
+            Self::Published => "publish",
+            Self::Deleted => "delete",
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ReducerCallError {
     #[error(transparent)]
@@ -510,6 +681,10 @@ pub enum ReducerCallError {
     ScheduleReducerNotFound,
     #[error("can't directly call special {0:?} lifecycle reducer")]
     LifecycleReducer(Lifecycle),
+    #[error("reducer not callable by clients")]
+    NotClientCallable,
+    #[error(transparent)]
+    Publishing(#[from] Publishing),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -534,6 +709,17 @@ pub enum ClientConnectedError {
     OutOfEnergy,
 }
 
+impl ClientConnectedError {
+    /// Whether connecting again may succeed without the client doing anything differently,
+    /// e.g. because the module was restarting or being published.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::DBError(_) | Self::ReducerCall(ReducerCallError::NoSuchModule(_) | ReducerCallError::Publishing(_))
+        )
+    }
+}
+
 impl ModuleHost {
     pub(super) fn new(module: impl Module, on_panic: impl Fn() + Send + Sync + 'static, core: JobCore) -> Self {
         let info = module.info();
@@ -660,6 +846,8 @@ impl ModuleHost {
                     caller_identity,
                     Some(caller_connection_id),
                     None,
+                    ReducerTransport::Internal,
+                    None,
                     None,
                     None,
                     reducer_id,
@@ -789,6 +977,8 @@ impl ModuleHost {
                     caller_identity,
                     Some(caller_connection_id),
                     None,
+                    ReducerTransport::Internal,
+                    None,
                     None,
                     None,
                     reducer_id,
@@ -832,17 +1022,30 @@ impl ModuleHost {
         caller_identity: Identity,
         caller_connection_id: Option<ConnectionId>,
         client: Option<Arc<ClientConnectionSender>>,
+        transport: ReducerTransport,
         request_id: Option<RequestId>,
         timer: Option<Instant>,
+        traceparent: Option<TraceParent>,
         reducer_id: ReducerId,
         reducer_def: &ReducerDef,
         args: ReducerArgs,
     ) -> Result<ReducerCallResult, ReducerCallError> {
+        let trace = ReducerTrace::start(&reducer_def.name, traceparent);
+        let mut phase_timer = client
+            .as_ref()
+            .filter(|client| client.config().reducer_timings)
+            .map(|_| ReducerPhaseTimer::start());
         let reducer_seed = ReducerArgsDeserializeSeed(self.info.module_def.typespace().with_type(reducer_def));
         let args = args.into_tuple(reducer_seed)?;
+        if let Some(phase_timer) = &mut phase_timer {
+            phase_timer.decoded();
+        }
         let caller_connection_id = caller_connection_id.unwrap_or(ConnectionId::ZERO);
 
+        // Ends once the call is off the queue and on the module's thread.
+        let queue_wait = tracing::trace_span!(parent: &trace.span, "queue_wait");
         self.call(&reducer_def.name, move |inst| {
+            drop(queue_wait);
             inst.call_reducer(
                 None,
                 CallReducerParams {
@@ -854,6 +1057,9 @@ impl ModuleHost {
                     timer,
                     reducer_id,
                     args,
+                    phase_timer,
+                    transport,
+                    trace,
                 },
             )
         })
@@ -866,8 +1072,10 @@ impl ModuleHost {
         caller_identity: Identity,
         caller_connection_id: Option<ConnectionId>,
         client: Option<Arc<ClientConnectionSender>>,
+        transport: ReducerTransport,
         request_id: Option<RequestId>,
         timer: Option<Instant>,
+        traceparent: Option<TraceParent>,
         reducer_name: &str,
         args: ReducerArgs,
     ) -> Result<ReducerCallResult, ReducerCallError> {
@@ -880,12 +1088,25 @@ impl ModuleHost {
             if let Some(lifecycle) = reducer_def.lifecycle {
                 return Err(ReducerCallError::LifecycleReducer(lifecycle));
             }
+            let caller_is_owner = caller_identity == self.info.owner_identity;
+            if !self.client_reducer_policy().await.permits(reducer_def, caller_is_owner) {
+                return Err(ReducerCallError::NotClientCallable);
+            }
+            // Hold the call back from a coordinated publish until it has completed.
+            let _admitted = self.replica_ctx().publish_gate.admit().inspect_err(|_| {
+                WORKER_METRICS
+                    .publish_refused_reducer_calls
+                    .with_label_values(&self.info.database_identity)
+                    .inc();
+            })?;
             self.call_reducer_inner(
                 caller_identity,
                 caller_connection_id,
                 client,
+                transport,
                 request_id,
                 timer,
+                traceparent,
                 reducer_id,
                 reducer_def,
                 args,
@@ -1045,7 +1266,8 @@ impl ModuleHost {
                         _,
                         table_name,
                         _,
-                    ) = compile_subscription(&query, &tx, &auth)?;
+                    ) = compile_subscription(&query, &tx, &auth)
+                        .inspect_err(|err| record_query_limit_rejection(&db.database_identity(), "one_off", err))?;
 
                     // Optimize each fragment
                     let optimized = plans
@@ -1113,6 +1335,245 @@ impl ModuleHost {
         Ok(())
     }
 
+    /// Execute a one-off query sent with `Query`, as [`Self::one_off_query`] does,
+    /// but within the timeout and row cap of `limits`,
+    /// sending the results to the given client in chunks of [`OneOffQueryLimits::chunk_rows`].
+    ///
+    /// Unlike [`Self::one_off_query`], an error with the query itself is returned rather than sent to the client,
+    /// in which case none of its rows have been sent.
+    #[tracing::instrument(level = "trace", skip_all)]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn query<F: WebsocketFormat>(
+        &self,
+        caller_identity: Identity,
+        query: String,
+        client: Arc<ClientConnectionSender>,
+        request_id: u32,
+        limits: OneOffQueryLimits,
+        timer: Instant,
+        into_message: impl Fn(QueryRowsMessage<F>) -> SerializableMessage + Send + 'static,
+    ) -> Result<(), anyhow::Error> {
+        let replica_ctx = self.replica_ctx();
+        let db = replica_ctx.relational_db.clone();
+        let subscriptions = replica_ctx.subscriptions.clone();
+        let auth = AuthCtx::new(replica_ctx.owner_identity, caller_identity);
+        log::debug!("One-off query {request_id}: {query}");
+        let metrics = asyncify(move || {
+            db.with_read_only(Workload::Sql, |tx| {
+                let mut budget = QueryBudget::new(&limits, Instant::now());
+                let schema = SchemaViewer::new(&*tx, &auth);
+
+                let (plans, _, table_name, _) = compile_subscription(&query, &schema, &auth)
+                    .inspect_err(|err| record_query_limit_rejection(&db.database_identity(), "one_off", err))?;
+                let optimized = plans
+                    .into_iter()
+                    .map(|plan| plan.optimize())
+                    .collect::<Result<Vec<_>, _>>()?;
+                check_row_limit(
+                    &optimized,
+                    &db,
+                    &schema,
+                    |plan, tx| estimate_rows_scanned(tx, plan),
+                    &auth,
+                )?;
+                let optimized = optimized.into_iter().map(PipelinedProject::from).collect::<Vec<_>>();
+
+                let (chunks, metrics) =
+                    execute_plan_in_chunks::<_, F>(&optimized, &DeltaTx::from(&*tx), limits.chunk_rows, &mut budget)?;
+
+                let total_host_execution_duration = timer.elapsed().into();
+                let last = chunks.len() - 1;
+                for (i, rows) in chunks.into_iter().enumerate() {
+                    let chunk = QueryRowsMessage {
+                        request_id,
+                        table_name: table_name.clone(),
+                        rows,
+                        last: i == last,
+                        total_host_execution_duration,
+                    };
+                    subscriptions.send_client_message(client.clone(), into_message(chunk), tx)?;
+                }
+                Ok::<ExecutionMetrics, anyhow::Error>(metrics)
+            })
+        })
+        .await?;
+
+        replica_ctx
+            .relational_db
+            .exec_counters_for(WorkloadType::Sql)
+            .record(&metrics);
+
+        Ok(())
+    }
+
+    /// Compile `query` as a `kind` query and describe the plans chosen for it, without running it.
+    ///
+    /// As with [`Self::one_off_query`], a query may compile down to several plans,
+    /// one per row level security rule on the table it returns.
+    pub async fn explain_query(
+        &self,
+        caller_identity: Identity,
+        query: String,
+        kind: QueryKind,
+    ) -> Result<Vec<Explain>, anyhow::Error> {
+        let replica_ctx = self.replica_ctx();
+        let db = replica_ctx.relational_db.clone();
+        let auth = AuthCtx::new(replica_ctx.owner_identity, caller_identity);
+        log::debug!("Explain query: {query}");
+        asyncify(move || {
+            db.with_read_only(Workload::Sql, |tx| {
+                let tx = SchemaViewer::new(tx, &auth);
+                let plans: Vec<ProjectPlan> = match kind {
+                    QueryKind::Subscription => SubscriptionPlan::compile(&query, &tx, &auth)?
+                        .0
+                        .iter()
+                        .map(|plan| plan.optimized_physical_plan().clone())
+                        .collect(),
+                    QueryKind::OneOff => {
+                        let (plans, ..) = compile_subscription(&query, &tx, &auth)?;
+                        plans
+                            .into_iter()
+                            .map(|plan| plan.optimize())
+                            .collect::<Result<Vec<_>, _>>()?
+                    }
+                };
+                Ok(plans
+                    .iter()
+                    .map(|plan| {
+                        Explain::with_estimates(
+                            plan,
+                            |plan| row_estimate(&tx, plan),
+                            |plan| estimate_rows_scanned(&tx, plan),
+                        )
+                    })
+                    .collect())
+            })
+        })
+        .await
+    }
+
+    /// Storage statistics of this module's database, and the health of its firehose.
+    pub async fn database_stats(&self) -> DatabaseStats {
+        let db = self.replica_ctx().relational_db.clone();
+        let mut stats = asyncify(move || db.database_stats()).await;
+        stats.firehose = self.subscriptions().firehose().health();
+        stats.replay_buffers = self.replica_ctx().deliveries.stats();
+        stats
+    }
+
+    /// What connections to this module's database do with messages which fail to encode.
+    pub async fn encode_error_policy(&self) -> EncodeErrorPolicy {
+        let db = self.replica_ctx().relational_db.clone();
+        asyncify(move || EncodeErrorPolicy::of_database(&db)).await
+    }
+
+    /// How the reducers of this module's database are metered, as applied to its next instance.
+    pub async fn execution_metering(&self) -> ExecutionMetering {
+        let db = self.replica_ctx().relational_db.clone();
+        asyncify(move || ExecutionMetering::of_database(&db)).await
+    }
+
+    /// How errors are reported to the clients of this module's database.
+    pub async fn error_format(&self) -> ErrorFormat {
+        let db = self.replica_ctx().relational_db.clone();
+        asyncify(move || ErrorFormat::of_database(&db)).await
+    }
+
+    /// Which reducers the clients of this module's database may call.
+    pub async fn client_reducer_policy(&self) -> ClientReducerPolicy {
+        let db = self.replica_ctx().relational_db.clone();
+        asyncify(move || ClientReducerPolicy::of_database(&db)).await
+    }
+
+    /// Whether `identity` is on the `client_metrics_allowlist` of this module's database,
+    /// getting metric series of its own whenever it connects.
+    pub async fn is_client_metrics_allowlisted(&self, identity: Identity) -> bool {
+        let db = self.replica_ctx().relational_db.clone();
+        asyncify(
+            move || match db.with_read_only(Workload::Internal, |tx| db.client_metrics_allowlist(tx)) {
+                Ok(allowlist) => allowlist.contains(&identity),
+                Err(e) => {
+                    log::error!(
+                        "failed to read `client_metrics_allowlist` of {}: {e}",
+                        db.database_identity()
+                    );
+                    false
+                }
+            },
+        )
+        .await
+    }
+
+    /// The rate at which a client of this module's database may send messages,
+    /// being the `node`'s, unless the database sets its own `client_message_rate` and `client_message_burst`.
+    pub async fn client_message_rate_limit(&self, node: Option<MessageRateLimit>) -> Option<MessageRateLimit> {
+        let db = self.replica_ctx().relational_db.clone();
+        asyncify(
+            move || match db.with_read_only(Workload::Internal, |tx| db.client_message_rate(tx)) {
+                Ok((rate, burst)) => MessageRateLimit::resolve(node, rate, burst),
+                Err(e) => {
+                    log::error!(
+                        "failed to read `client_message_rate` of {}: {e}",
+                        db.database_identity()
+                    );
+                    node
+                }
+            },
+        )
+        .await
+    }
+
+    /// Dump the subscriptions of the client connected as `connection_id`, if any.
+    pub async fn client_subscriptions(
+        &self,
+        connection_id: ConnectionId,
+    ) -> Result<Option<ClientSubscriptions>, DBError> {
+        let subscriptions = self.subscriptions().clone();
+        asyncify(move || subscriptions.dump_client_subscriptions(connection_id)).await
+    }
+
+    /// Register the subscriptions of `dump` under a new test connection of the same identity,
+    /// returning the dump of the test connection.
+    ///
+    /// The test connection has no client, and messages sent to it are discarded,
+    /// but its queries are evaluated and counted like those of any other connection.
+    /// It stays subscribed until this module exits.
+    pub async fn restore_client_subscriptions(
+        &self,
+        dump: ClientSubscriptions,
+    ) -> Result<ClientSubscriptions, DBError> {
+        let id = ClientActorId {
+            identity: dump.identity,
+            connection_id: ConnectionId::from_le_byte_array(rand::random()),
+            name: ClientName(0),
+        };
+        let config = ClientConfig {
+            protocol: Protocol::Binary,
+            version: <_>::default(),
+            compression: <_>::default(),
+            tx_update_full: true,
+            reducer_timings: false,
+            acknowledged_delivery: false,
+            request_errors: false,
+            ..ClientConfig::for_test()
+        };
+        let (sender, mut rx) = ClientConnectionSender::dummy_with_capacity(id, config, <_>::default());
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+
+        let subscriptions = self.subscriptions().clone();
+        asyncify(move || {
+            subscriptions.restore_client_subscriptions(Arc::new(sender), &dump)?;
+            let restored = subscriptions.dump_client_subscriptions(id.connection_id)?;
+            Ok(restored.unwrap_or_else(|| ClientSubscriptions {
+                identity: id.identity,
+                connection_id: id.connection_id,
+                redacted: false,
+                subscriptions: vec![],
+            }))
+        })
+        .await
+    }
+
     /// FIXME(jgilles): this is a temporary workaround for deleting not currently being supported
     /// for tables without primary keys. It is only used in the benchmarks.
     /// Note: this doesn't drop the table, it just clears it!
@@ -1146,6 +1607,11 @@ impl ModuleHost {
         &self.replica_ctx().database
     }
 
+    /// The acknowledged updates held on this node for the clients of this module's database.
+    pub fn pending_deliveries(&self) -> &PendingDeliveries {
+        &self.replica_ctx().deliveries
+    }
+
     pub(crate) fn replica_ctx(&self) -> &ReplicaContext {
         self.module.replica_ctx()
     }
@@ -0,0 +1,203 @@
+use bytes::Bytes;
+use spacetimedb_lib::db::raw_def::v9::{RawModuleDefV9, TableAccess};
+use spacetimedb_lib::hash::{hash_bytes, Hash};
+use spacetimedb_lib::sats::{AlgebraicType, AlgebraicTypeRef};
+use spacetimedb_lib::{bsatn, sats};
+use spacetimedb_schema::def::ModuleDef;
+use std::collections::HashSet;
+
+/// What clients are told about the schema of a module,
+/// serialized once when the module is created rather than for every request,
+/// e.g. while thousands of clients reconnect at once.
+///
+/// Owned by the module's [`ModuleInfo`](super::module_host::ModuleInfo),
+/// and so replaced along with the module when a publish updates it.
+/// As everything here describes the same module,
+/// a client is never served the schema of one module alongside the hash of another.
+#[derive(Debug)]
+pub struct ModuleSchema {
+    /// Counts the modules created for the database on this node, starting at 1,
+    /// so that a client can tell whether a publish happened between two requests.
+    ///
+    /// Generations aren't persisted, so are only comparable along with [`Self::module_hash`].
+    pub generation: u64,
+    /// The hash of the module's program.
+    pub module_hash: Hash,
+    /// The hash of the BSATN encoding of the module's [`RawModuleDefV9`],
+    /// which changes only if the schema does.
+    pub digest: Hash,
+    /// The JSON encoding of the module's [`RawModuleDefV9`], as served by the schema endpoint.
+    pub v9_json: Bytes,
+    /// Like [`Self::v9_json`], but without the module's private tables,
+    /// as served to those who don't own the database.
+    pub public_v9_json: Bytes,
+}
+
+impl ModuleSchema {
+    pub fn new(module_def: &ModuleDef, module_hash: Hash, generation: u64) -> Self {
+        let raw = RawModuleDefV9::from(module_def.clone());
+        let digest = hash_bytes(bsatn::to_vec(&raw).expect("should be able to bsatn encode a module def"));
+        let to_json = |raw| -> Bytes {
+            serde_json::to_vec(&sats::serde::SerdeWrapper(raw))
+                .expect("should be able to json encode a module def")
+                .into()
+        };
+        let public_v9_json = to_json(without_private_tables(raw.clone()));
+        let v9_json = to_json(raw);
+        Self {
+            generation,
+            module_hash,
+            digest,
+            v9_json,
+            public_v9_json,
+        }
+    }
+}
+
+/// `raw` without its private tables, nor the names and, unless used by what remains, the types of their rows,
+/// so that those who may not access the tables learn nothing of them.
+///
+/// The typespace keeps its length, so that the remaining refs into it still hold.
+fn without_private_tables(mut raw: RawModuleDefV9) -> RawModuleDefV9 {
+    let (private, public) = raw
+        .tables
+        .into_iter()
+        .partition::<Vec<_>, _>(|table| table.table_access == TableAccess::Private);
+    raw.tables = public;
+    let private_rows: HashSet<_> = private.iter().map(|table| table.product_type_ref).collect();
+    raw.types.retain(|ty| !private_rows.contains(&ty.ty));
+
+    // Find the types still in use, from the remaining tables, type names and reducers.
+    let mut used = HashSet::new();
+    let mut pending = Vec::new();
+    for table in &raw.tables {
+        collect_refs(&AlgebraicType::Ref(table.product_type_ref), &mut pending);
+    }
+    for ty in &raw.types {
+        collect_refs(&AlgebraicType::Ref(ty.ty), &mut pending);
+    }
+    for reducer in &raw.reducers {
+        for param in reducer.params.elements.iter() {
+            collect_refs(&param.algebraic_type, &mut pending);
+        }
+    }
+    while let Some(r) = pending.pop() {
+        if used.insert(r) {
+            if let Some(ty) = raw.typespace.types.get(r.idx()) {
+                collect_refs(ty, &mut pending);
+            }
+        }
+    }
+
+    for r in private_rows.difference(&used) {
+        if let Some(ty) = raw.typespace.types.get_mut(r.idx()) {
+            *ty = AlgebraicType::unit();
+        }
+    }
+    raw
+}
+
+/// Push the refs within `ty` onto `refs`.
+fn collect_refs(ty: &AlgebraicType, refs: &mut Vec<AlgebraicTypeRef>) {
+    match ty {
+        AlgebraicType::Ref(r) => refs.push(*r),
+        AlgebraicType::Sum(sum) => sum.variants.iter().for_each(|v| collect_refs(&v.algebraic_type, refs)),
+        AlgebraicType::Product(product) => product
+            .elements
+            .iter()
+            .for_each(|e| collect_refs(&e.algebraic_type, refs)),
+        AlgebraicType::Array(array) => collect_refs(&array.elem_ty, refs),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spacetimedb_lib::db::raw_def::v9::RawModuleDefV9Builder;
+    use spacetimedb_lib::de::serde::DeserializeWrapper;
+    use spacetimedb_lib::{AlgebraicType, ProductType};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::watch;
+
+    fn module_def(table: &str) -> ModuleDef {
+        let mut builder = RawModuleDefV9Builder::new();
+        builder.build_table_with_new_type(table, ProductType::from([("id", AlgebraicType::U64)]), true);
+        builder.finish().try_into().unwrap()
+    }
+
+    #[test]
+    fn schema_is_served_as_the_endpoint_always_did() {
+        let def = module_def("person");
+        let schema = ModuleSchema::new(&def, hash_bytes("program"), 1);
+        let expected = serde_json::to_vec(&sats::serde::SerdeWrapper(RawModuleDefV9::from(def.clone()))).unwrap();
+        assert_eq!(schema.v9_json, expected);
+
+        // The digest follows the schema, not the program.
+        assert_eq!(
+            ModuleSchema::new(&def, hash_bytes("other program"), 2).digest,
+            schema.digest
+        );
+        assert_ne!(
+            ModuleSchema::new(&module_def("pet"), hash_bytes("program"), 2).digest,
+            schema.digest
+        );
+    }
+
+    #[test]
+    fn private_tables_are_left_out_of_the_public_schema() {
+        let mut builder = RawModuleDefV9Builder::new();
+        builder.build_table_with_new_type("person", ProductType::from([("id", AlgebraicType::U64)]), true);
+        builder
+            .build_table_with_new_type("bucket", ProductType::from([("tokens", AlgebraicType::U32)]), true)
+            .with_access(TableAccess::Private);
+        let def: ModuleDef = builder.finish().try_into().unwrap();
+        let schema = ModuleSchema::new(&def, hash_bytes("program"), 1);
+
+        let full = String::from_utf8(schema.v9_json.to_vec()).unwrap();
+        assert!(full.contains("bucket") && full.contains("tokens"));
+        let public = String::from_utf8(schema.public_v9_json.to_vec()).unwrap();
+        assert!(public.contains("person"));
+        assert!(!public.contains("bucket") && !public.contains("tokens"), "{public}");
+
+        // What's left is still a valid module.
+        let DeserializeWrapper(raw) = serde_json::from_str::<DeserializeWrapper<RawModuleDefV9>>(&public).unwrap();
+        let public_def = ModuleDef::try_from(raw).unwrap();
+        assert_eq!(public_def.tables().count(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publish_mid_storm_never_mixes_modules() {
+        let old = Arc::new(ModuleSchema::new(&module_def("person"), hash_bytes("old"), 1));
+        let new = Arc::new(ModuleSchema::new(&module_def("pet"), hash_bytes("new"), 2));
+        let expected: HashMap<_, _> = [&old, &new]
+            .map(|schema| (schema.module_hash, (schema.digest, schema.generation)))
+            .into();
+
+        // Stands in for the channel through which the host hands out its current module.
+        let (module_tx, module_rx) = watch::channel(old);
+        let storm: Vec<_> = (0..16)
+            .map(|_| {
+                let module_rx = module_rx.clone();
+                tokio::spawn(async move {
+                    let mut served = Vec::new();
+                    for _ in 0..1000 {
+                        let schema = module_rx.borrow().clone();
+                        served.push((schema.module_hash, schema.digest, schema.generation));
+                        tokio::task::yield_now().await;
+                    }
+                    served
+                })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+        module_tx.send_replace(new);
+
+        for client in storm {
+            for (module_hash, digest, generation) in client.await.unwrap() {
+                assert_eq!(expected[&module_hash], (digest, generation));
+            }
+        }
+    }
+}
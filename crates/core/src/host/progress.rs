@@ -0,0 +1,204 @@
+//! Progress reported by reducers while they run, forwarded to their callers.
+//!
+//! A long-running reducer may report its progress any number of times, as values the host doesn't interpret,
+//! each of which is sent to the connection which called the reducer as a [`CallProgress`](crate::messages::websocket::CallProgress)
+//! right away, rather than once the reducer's transaction commits.
+//! Progress is therefore not rolled back with the transaction,
+//! and is queued directly for the caller,
+//! outside of the ordered broadcast of transaction updates, which it intentionally precedes.
+//!
+//! Progress is best-effort: a call may report [`PROGRESS_BURST`] values at once,
+//! and [`PROGRESS_PER_SEC`] a second after that, beyond which values are dropped,
+//! as are those reported while the caller's queue is [under pressure](ClientConnectionSender::is_under_pressure).
+
+use crate::client::messages::CallProgressMessage;
+use crate::client::{ClientConnectionSender, ConnectionSender};
+use crate::worker_metrics::WORKER_METRICS;
+use parking_lot::Mutex;
+use spacetimedb_lib::identity::RequestId;
+use spacetimedb_lib::Identity;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// How many progress values a reducer call may report at once.
+pub const PROGRESS_BURST: u32 = 10;
+
+/// How many progress values a second a reducer call may report, once it has used up its [`PROGRESS_BURST`].
+pub const PROGRESS_PER_SEC: u32 = 20;
+
+/// Why reported progress wasn't sent to the caller.
+///
+/// Counted per database, by variant, in the `reducer_progress_dropped` metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum ProgressDropped {
+    /// The call reported progress more often than allowed.
+    RateLimited,
+    /// The caller's queue was under pressure.
+    QueuePressure,
+    /// The caller's queue was full, or the caller disconnected.
+    NotQueued,
+}
+
+/// The caller of the current reducer call, to which its progress is sent.
+///
+/// Shared between the host calls of a module instance and the code calling its reducers.
+#[derive(Clone, Default)]
+pub struct ProgressSink {
+    inner: Arc<Mutex<Option<ProgressTarget>>>,
+}
+
+struct ProgressTarget {
+    client: Arc<ClientConnectionSender>,
+    request_id: RequestId,
+    database_identity: Identity,
+    /// How many more values may be reported right now,
+    /// replenished at [`PROGRESS_PER_SEC`] up to [`PROGRESS_BURST`].
+    allowance: f64,
+    replenished_at: Instant,
+}
+
+impl ProgressTarget {
+    /// Take one value from the allowance, if there's one left as of `now`.
+    fn take_allowance(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.replenished_at).as_secs_f64();
+        self.allowance = (self.allowance + elapsed * PROGRESS_PER_SEC as f64).min(PROGRESS_BURST as f64);
+        self.replenished_at = now;
+        if self.allowance < 1.0 {
+            return false;
+        }
+        self.allowance -= 1.0;
+        true
+    }
+
+    fn send(&mut self, payload: &[u8], now: Instant) -> Result<(), ProgressDropped> {
+        if !self.take_allowance(now) {
+            return Err(ProgressDropped::RateLimited);
+        }
+        if self.client.is_under_pressure() {
+            return Err(ProgressDropped::QueuePressure);
+        }
+        let message = CallProgressMessage {
+            request_id: self.request_id,
+            payload: payload.to_vec().into(),
+        };
+        // Unlike `send_message`, this doesn't disconnect a caller whose queue is full,
+        // as progress isn't worth disconnecting over.
+        ConnectionSender::new(self.client.clone())
+            .try_send(message)
+            .map_err(|_| ProgressDropped::NotQueued)
+    }
+}
+
+impl ProgressSink {
+    /// Begin sending the progress of a reducer call to `client`, as that of its call `request_id`,
+    /// or to no one, if either is `None`, e.g. when the reducer was scheduled
+    /// or its caller didn't ask to be told of its success.
+    pub fn begin(
+        &self,
+        client: Option<Arc<ClientConnectionSender>>,
+        request_id: Option<RequestId>,
+        database_identity: Identity,
+    ) {
+        *self.inner.lock() = Option::zip(client, request_id).map(|(client, request_id)| ProgressTarget {
+            client,
+            request_id,
+            database_identity,
+            allowance: PROGRESS_BURST as f64,
+            replenished_at: Instant::now(),
+        });
+    }
+
+    /// Stop sending progress, as the reducer call has returned.
+    pub fn end(&self) {
+        *self.inner.lock() = None;
+    }
+
+    /// Send `payload` to the caller of the current reducer call, unless it's dropped.
+    ///
+    /// Returns whether it was sent.
+    pub fn report(&self, payload: &[u8]) -> bool {
+        self.report_at(payload, Instant::now())
+    }
+
+    fn report_at(&self, payload: &[u8], now: Instant) -> bool {
+        let mut inner = self.inner.lock();
+        let Some(target) = &mut *inner else {
+            return false;
+        };
+        match target.send(payload, now) {
+            Ok(()) => true,
+            Err(dropped) => {
+                WORKER_METRICS
+                    .reducer_progress_dropped
+                    .with_label_values(&target.database_identity, dropped.as_ref())
+                    .inc();
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::messages::SerializableMessage;
+    use crate::client::{ClientActorId, ClientConfig, MeteredReceiver, SendQueueCapacity};
+    use std::time::Duration;
+
+    fn caller(messages: usize) -> (Arc<ClientConnectionSender>, MeteredReceiver<SerializableMessage>) {
+        let capacity = SendQueueCapacity {
+            messages,
+            ..<_>::default()
+        };
+        let id = ClientActorId::for_test(Identity::ZERO);
+        let (sender, rx) = ClientConnectionSender::dummy_with_capacity(id, ClientConfig::for_test(), capacity);
+        (Arc::new(sender), rx)
+    }
+
+    #[tokio::test]
+    async fn progress_is_sent_to_the_caller_within_the_rate_limit() {
+        let (client, mut rx) = caller(1024);
+        let sink = ProgressSink::default();
+        sink.begin(Some(client), Some(7), Identity::ZERO);
+
+        let start = Instant::now();
+        let sent = (0..PROGRESS_BURST + 5)
+            .filter(|&i| sink.report_at(&i.to_le_bytes(), start))
+            .count();
+        assert_eq!(sent, PROGRESS_BURST as usize);
+
+        // The allowance is replenished over time.
+        let later = start + Duration::from_secs(1) / PROGRESS_PER_SEC;
+        assert!(sink.report_at(b"later", later));
+        assert!(!sink.report_at(b"too soon", later));
+
+        let Some(SerializableMessage::CallProgress(first)) = rx.recv().await else {
+            panic!("expected progress to be queued");
+        };
+        assert_eq!(first.request_id, 7);
+        assert_eq!(&first.payload[..], 0u32.to_le_bytes());
+        assert_eq!(rx.len(), PROGRESS_BURST as usize);
+
+        // Nothing is sent once the call has returned.
+        sink.end();
+        assert!(!sink.report_at(b"done", later + Duration::from_secs(1)));
+        assert_eq!(rx.len(), PROGRESS_BURST as usize);
+    }
+
+    #[tokio::test]
+    async fn progress_is_dropped_under_pressure_or_without_a_caller() {
+        let (client, rx) = caller(4);
+        let sink = ProgressSink::default();
+        sink.begin(Some(client.clone()), Some(1), Identity::ZERO);
+        let sent = (0..4u32).filter(|i| sink.report(&i.to_le_bytes())).count();
+        // Progress stops once the queue is more than half full, leaving room for the call's reply.
+        assert_eq!(sent, 3);
+        assert_eq!(rx.len(), 3);
+        assert!(client.is_under_pressure());
+
+        // Scheduled reducers have no one to report to.
+        sink.begin(None, None, Identity::ZERO);
+        assert!(!sink.report(b"progress"));
+    }
+}
@@ -0,0 +1,227 @@
+//! Coordinated publishes, which pause the reducer calls of clients
+//! while a database's module is updated, rather than letting them race the update.
+//!
+//! See [`PublishMode::Coordinated`] for the steps of such a publish.
+
+use std::time::{Duration, Instant};
+
+use spacetimedb_lib::Identity;
+use tokio::sync::watch;
+
+use crate::worker_metrics::WORKER_METRICS;
+
+/// How a node updates the module of a database which is already running.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PublishMode {
+    /// Stop admitting reducer calls from clients,
+    /// refusing them with a [`Publishing`] error,
+    /// wait for the calls in flight to complete, up to [`PublishOptions::drain_timeout`],
+    /// swap in the new module,
+    /// and drop the subscriptions it invalidated, telling their clients why,
+    /// before admitting calls again.
+    #[default]
+    Coordinated,
+    /// Swap in the new module as soon as it has been migrated to,
+    /// leaving calls in flight to complete against the old module, or fail,
+    /// and subscriptions as they are.
+    Immediate,
+}
+
+/// Node-wide tuning of module updates.
+///
+/// Read from the `[publish]` section of `config.toml`.
+#[serde_with::serde_as]
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PublishOptions {
+    pub mode: PublishMode,
+    /// How long a coordinated publish waits for reducer calls in flight to complete,
+    /// after which it updates the module regardless.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(rename = "drain-timeout-ms")]
+    pub drain_timeout: Duration,
+    /// How long clients whose reducer calls are refused during a coordinated publish
+    /// are told to wait before retrying.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(rename = "retry-after-ms")]
+    pub retry_after: Duration,
+}
+
+impl Default for PublishOptions {
+    fn default() -> Self {
+        Self {
+            mode: PublishMode::Coordinated,
+            drain_timeout: Duration::from_secs(5),
+            retry_after: Duration::from_millis(500),
+        }
+    }
+}
+
+/// A reducer call was refused because the database is being published to.
+///
+/// Clients can recognize this by the `publishing` prefix of its message,
+/// and retry the call after `retry_after`.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[error("publishing, retry in {}ms", retry_after.as_millis())]
+pub struct Publishing {
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Default)]
+struct GateState {
+    /// The number of reducer calls admitted which have yet to complete.
+    in_flight: usize,
+    /// While a publish is in progress, how long refused callers are told to wait.
+    paused: Option<Duration>,
+}
+
+/// Admits the reducer calls of clients to a database, unless it is being published to.
+///
+/// The gate outlives the modules of the database, so that calls admitted by one module
+/// are waited for before the next one is swapped in.
+#[derive(Debug)]
+pub struct PublishGate {
+    state: watch::Sender<GateState>,
+}
+
+impl Default for PublishGate {
+    fn default() -> Self {
+        Self {
+            state: watch::Sender::new(GateState::default()),
+        }
+    }
+}
+
+impl PublishGate {
+    /// Count a reducer call as in flight until the returned guard is dropped,
+    /// or refuse it if a publish is in progress.
+    pub fn admit(&self) -> Result<AdmittedCall<'_>, Publishing> {
+        let mut refused = None;
+        self.state.send_if_modified(|state| match state.paused {
+            Some(retry_after) => {
+                refused = Some(Publishing { retry_after });
+                false
+            }
+            None => {
+                state.in_flight += 1;
+                true
+            }
+        });
+        match refused {
+            Some(err) => Err(err),
+            None => Ok(AdmittedCall(self)),
+        }
+    }
+
+    /// The number of reducer calls in flight.
+    pub fn in_flight(&self) -> usize {
+        self.state.borrow().in_flight
+    }
+
+    /// Stop admitting reducer calls to the database `database_identity`,
+    /// refusing them with `options.retry_after`,
+    /// and wait up to `options.drain_timeout` for the calls in flight to complete.
+    ///
+    /// Calls are admitted again once the returned pause is dropped.
+    pub async fn pause(&self, database_identity: Identity, options: &PublishOptions) -> PublishPause<'_> {
+        let started = Instant::now();
+        self.state.send_modify(|state| state.paused = Some(options.retry_after));
+        let mut rx = self.state.subscribe();
+        let drained = tokio::time::timeout(options.drain_timeout, rx.wait_for(|state| state.in_flight == 0))
+            .await
+            .is_ok();
+        PublishPause {
+            gate: self,
+            database_identity,
+            started,
+            drained,
+        }
+    }
+}
+
+/// A reducer call admitted by a [`PublishGate`], counted as in flight until this is dropped.
+#[derive(Debug)]
+pub struct AdmittedCall<'a>(&'a PublishGate);
+
+impl Drop for AdmittedCall<'_> {
+    fn drop(&mut self) {
+        self.0.state.send_modify(|state| state.in_flight -= 1);
+    }
+}
+
+/// A publish in progress, during which a [`PublishGate`] refuses reducer calls.
+///
+/// Dropping this admits calls again, and records how long they were paused for.
+#[derive(Debug)]
+pub struct PublishPause<'a> {
+    gate: &'a PublishGate,
+    database_identity: Identity,
+    started: Instant,
+    drained: bool,
+}
+
+impl PublishPause<'_> {
+    /// Whether every call in flight completed before the drain timeout.
+    pub fn drained(&self) -> bool {
+        self.drained
+    }
+}
+
+impl Drop for PublishPause<'_> {
+    fn drop(&mut self) {
+        self.gate.state.send_modify(|state| state.paused = None);
+        WORKER_METRICS
+            .publish_pause_duration
+            .with_label_values(
+                &self.database_identity,
+                if self.drained { "drained" } else { "timed_out" },
+            )
+            .observe(self.started.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: PublishOptions = PublishOptions {
+        mode: PublishMode::Coordinated,
+        drain_timeout: Duration::from_millis(50),
+        retry_after: Duration::from_millis(250),
+    };
+
+    #[tokio::test]
+    async fn calls_are_refused_while_paused() {
+        let gate = PublishGate::default();
+        let pause = gate.pause(Identity::ZERO, &OPTIONS).await;
+        assert!(pause.drained());
+        let err = gate.admit().unwrap_err();
+        assert_eq!(err.to_string(), "publishing, retry in 250ms");
+
+        drop(pause);
+        let _call = gate.admit().unwrap();
+        assert_eq!(gate.in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn pausing_waits_for_calls_in_flight() {
+        let gate = PublishGate::default();
+        let call = gate.admit().unwrap();
+        let (pause, ()) = tokio::join!(gate.pause(Identity::ZERO, &OPTIONS), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(call);
+        });
+        assert!(pause.drained());
+        assert_eq!(gate.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn pausing_gives_up_on_calls_at_the_deadline() {
+        let gate = PublishGate::default();
+        let _stuck = gate.admit().unwrap();
+        let pause = gate.pause(Identity::ZERO, &OPTIONS).await;
+        assert!(!pause.drained());
+        assert!(gate.admit().is_err());
+    }
+}
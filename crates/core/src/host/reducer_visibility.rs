@@ -0,0 +1,139 @@
+//! Which reducers of a database's module its clients may call.
+//!
+//! A module declares reducers which clients can't call with `client_callable = false`,
+//! leaving them to be scheduled or called by other reducers.
+//! A database narrows what its clients may call further with two system variables,
+//! `client_reducer_allowlist` and `client_reducer_denylist`, each a comma-separated list of reducer names.
+//! Its owner is held to the same rules, unless it sets `owner_bypasses_reducer_visibility`,
+//! e.g. to trigger a scheduled reducer by hand.
+//!
+//! The variables are read as each call arrives, so changes apply to the next call.
+
+use spacetimedb_schema::def::ReducerDef;
+
+use crate::db::relational_db::RelationalDB;
+use crate::execution_context::Workload;
+
+/// Which reducers the clients of a database may call, beyond those its module declares callable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientReducerPolicy {
+    /// The reducers clients may call, if limited.
+    pub allowlist: Option<Vec<String>>,
+    /// The reducers clients may not call.
+    pub denylist: Vec<String>,
+    /// Whether the database owner may call any reducer, regardless of the other rules.
+    pub owner_bypass: bool,
+}
+
+impl ClientReducerPolicy {
+    /// The policy configured by the system variables of `db`,
+    /// or the default, which only applies the module's declarations, should they fail to be read.
+    pub fn of_database(db: &RelationalDB) -> Self {
+        let vars = db.with_read_only(Workload::Internal, |tx| {
+            Ok::<_, crate::error::DBError>(Self {
+                allowlist: db.client_reducer_allowlist(tx)?,
+                denylist: db.client_reducer_denylist(tx)?,
+                owner_bypass: db.owner_bypasses_reducer_visibility(tx)?,
+            })
+        });
+        vars.unwrap_or_else(|e| {
+            log::error!(
+                "failed to read the client reducer policy of {}: {e}",
+                db.database_identity()
+            );
+            Self::default()
+        })
+    }
+
+    /// Whether a client, the database owner if `caller_is_owner`, may call `reducer`.
+    pub fn permits(&self, reducer: &ReducerDef, caller_is_owner: bool) -> bool {
+        if caller_is_owner && self.owner_bypass {
+            return true;
+        }
+        let name = &*reducer.name;
+        reducer.client_callable
+            && self
+                .allowlist
+                .as_ref()
+                .is_none_or(|allowed| allowed.iter().any(|r| r == name))
+            && !self.denylist.iter().any(|r| r == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::datastore::system_tables::StVarName;
+    use crate::db::relational_db::tests_utils::{with_auto_commit, TestDB};
+    use spacetimedb_lib::db::raw_def::v9::RawModuleDefV9Builder;
+    use spacetimedb_lib::ProductType;
+    use spacetimedb_schema::def::ModuleDef;
+
+    fn module() -> ModuleDef {
+        let mut builder = RawModuleDefV9Builder::new();
+        builder.add_reducer("public", ProductType::unit(), None);
+        builder.add_reducer("other", ProductType::unit(), None);
+        builder.add_reducer("internal", ProductType::unit(), None);
+        builder.set_reducer_client_callable("internal", false);
+        builder.finish().try_into().expect("valid module")
+    }
+
+    #[test]
+    fn module_declarations_bind_the_owner_unless_it_bypasses() {
+        let module = module();
+        let internal = module.reducer("internal").unwrap();
+        let policy = ClientReducerPolicy::default();
+        assert!(policy.permits(module.reducer("public").unwrap(), false));
+        assert!(!policy.permits(internal, false));
+        assert!(!policy.permits(internal, true));
+
+        let policy = ClientReducerPolicy {
+            owner_bypass: true,
+            ..<_>::default()
+        };
+        assert!(!policy.permits(internal, false));
+        assert!(policy.permits(internal, true));
+    }
+
+    #[test]
+    fn lists_narrow_what_clients_may_call() {
+        let module = module();
+        let [public, other, internal] = ["public", "other", "internal"].map(|r| module.reducer(r).unwrap());
+
+        let allowing = ClientReducerPolicy {
+            allowlist: Some(vec!["public".into(), "internal".into()]),
+            ..<_>::default()
+        };
+        assert!(allowing.permits(public, false));
+        assert!(!allowing.permits(other, false));
+        assert!(!allowing.permits(internal, false));
+
+        let denying = ClientReducerPolicy {
+            denylist: vec!["public".into()],
+            ..<_>::default()
+        };
+        assert!(!denying.permits(public, false));
+        assert!(denying.permits(other, false));
+    }
+
+    #[test]
+    fn policy_is_configured_by_system_variables() -> anyhow::Result<()> {
+        let db = TestDB::durable()?;
+        assert_eq!(ClientReducerPolicy::of_database(&db), ClientReducerPolicy::default());
+
+        with_auto_commit(&db, |tx| {
+            db.write_var(tx, StVarName::ClientReducerAllowlist, "public, other")?;
+            db.write_var(tx, StVarName::ClientReducerDenylist, "other")?;
+            db.write_var(tx, StVarName::OwnerBypassesReducerVisibility, "true")
+        })?;
+        assert_eq!(
+            ClientReducerPolicy::of_database(&db),
+            ClientReducerPolicy {
+                allowlist: Some(vec!["public".into(), "other".into()]),
+                denylist: vec!["other".into()],
+                owner_bypass: true,
+            }
+        );
+        Ok(())
+    }
+}
@@ -7,6 +7,7 @@ use rustc_hash::FxHashMap;
 use spacetimedb_client_api_messages::energy::EnergyQuanta;
 use spacetimedb_lib::scheduler::ScheduleAt;
 use spacetimedb_lib::ConnectionId;
+use spacetimedb_lib::ReducerTransport;
 use spacetimedb_lib::Timestamp;
 use spacetimedb_primitives::{ColId, TableId};
 use spacetimedb_sats::{bsatn::ToBsatn as _, AlgebraicValue};
@@ -19,6 +20,7 @@ use super::module_host::ModuleEvent;
 use super::module_host::ModuleFunctionCall;
 use super::module_host::{CallReducerParams, WeakModuleHost};
 use super::module_host::{DatabaseUpdate, EventStatus};
+use super::trace_context::ReducerTrace;
 use super::{ModuleHost, ReducerArgs, ReducerCallError};
 use crate::db::datastore::locking_tx_datastore::MutTxId;
 use crate::db::datastore::system_tables::{StFields, StScheduledFields, ST_SCHEDULED_ID};
@@ -353,6 +355,9 @@ impl SchedulerActor {
                     timer: None,
                     reducer_id,
                     args: reducer_args,
+                    phase_timer: None,
+                    transport: ReducerTransport::Scheduled,
+                    trace: ReducerTrace::start(&reducer, None),
                 }))
             }
             QueueItem::VolatileNonatomicImmediate { reducer_name, args } => {
@@ -371,6 +376,10 @@ impl SchedulerActor {
                     timer: None,
                     reducer_id,
                     args: reducer_args,
+                    phase_timer: None,
+                    // Scheduled to run immediately by another reducer.
+                    transport: ReducerTransport::Internal,
+                    trace: ReducerTrace::start(&reducer_name, None),
                 }))
             }
         };
@@ -468,6 +477,8 @@ fn commit_and_broadcast_deletion_event(tx: MutTxId, module_host: ModuleHost) {
         host_execution_duration: Duration::from_millis(0),
         request_id: None,
         timer: None,
+        acknowledged: false,
+        trace_id: None,
     };
 
     if let Err(e) = module_host
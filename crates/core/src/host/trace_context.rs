@@ -0,0 +1,280 @@
+//! [W3C trace context](https://www.w3.org/TR/trace-context/) for reducer calls.
+//!
+//! A caller may pass a `traceparent` with its call,
+//! in which case the spans the host records for the call
+//! (waiting for the module, executing the reducer, committing, fanning out updates)
+//! belong to the caller's distributed trace, under a [`ReducerTrace::span`] whose parent is the caller's span.
+//! Calls without one start a new trace.
+//! Either way, the trace id is echoed back to the caller, so that it can link its logs to the call.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Identifies a distributed trace.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct TraceId(pub [u8; 16]);
+
+/// Identifies a span within a [`TraceId`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SpanId(pub [u8; 8]);
+
+impl TraceId {
+    /// A random, and therefore valid, i.e. not all-zero, trace id.
+    pub fn random() -> Self {
+        Self(random_nonzero())
+    }
+}
+
+impl SpanId {
+    /// A random, and therefore valid, i.e. not all-zero, span id.
+    pub fn random() -> Self {
+        Self(random_nonzero())
+    }
+}
+
+fn random_nonzero<const N: usize>() -> [u8; N] {
+    std::iter::repeat_with(rand::random::<[u8; N]>)
+        .find(|id| *id != [0; N])
+        .unwrap()
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Display for SpanId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// A `traceparent`, as sent by a caller, or as sent by us to whoever we call.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TraceParent {
+    pub trace_id: TraceId,
+    /// The span of the caller which made the call.
+    pub parent_id: SpanId,
+    /// The trace flags, of which only the lowest bit, `sampled`, is defined.
+    pub flags: u8,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("malformed traceparent")]
+pub struct InvalidTraceParent;
+
+/// Parses lowercase hex into exactly `N` bytes.
+fn parse_hex<const N: usize>(s: &str) -> Result<[u8; N], InvalidTraceParent> {
+    if s.bytes().any(|b| b.is_ascii_uppercase()) {
+        return Err(InvalidTraceParent);
+    }
+    let mut bytes = [0; N];
+    hex::decode_to_slice(s, &mut bytes).map_err(|_| InvalidTraceParent)?;
+    Ok(bytes)
+}
+
+impl FromStr for TraceParent {
+    type Err = InvalidTraceParent;
+
+    /// Parses `<version>-<trace-id>-<parent-id>-<flags>`.
+    ///
+    /// Later versions may append fields, which are ignored,
+    /// but version `ff` and all-zero ids are invalid.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split('-');
+        let mut next = || parts.next().ok_or(InvalidTraceParent);
+        let [version] = parse_hex::<1>(next()?)?;
+        let trace_id = parse_hex(next()?)?;
+        let parent_id = parse_hex(next()?)?;
+        let [flags] = parse_hex::<1>(next()?)?;
+        let rest_ok = match version {
+            0 => parts.next().is_none(),
+            0xff => false,
+            _ => true,
+        };
+        if !rest_ok || trace_id == [0; 16] || parent_id == [0; 8] {
+            return Err(InvalidTraceParent);
+        }
+        Ok(Self {
+            trace_id: TraceId(trace_id),
+            parent_id: SpanId(parent_id),
+            flags,
+        })
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{}-{}-{:02x}", self.trace_id, self.parent_id, self.flags)
+    }
+}
+
+/// The trace context of one reducer call on this host.
+#[derive(Clone, Debug)]
+pub struct ReducerTrace {
+    pub trace_id: TraceId,
+    /// The id of [`Self::span`].
+    pub span_id: SpanId,
+    /// The caller's span, if it supplied a `traceparent`.
+    pub parent_id: Option<SpanId>,
+    flags: u8,
+    /// The root span of the call on this host,
+    /// under which the phases of the call are recorded.
+    pub span: tracing::Span,
+}
+
+impl ReducerTrace {
+    /// Start tracing a call to `reducer`,
+    /// as part of the trace of `parent`, or of a new trace if `None`.
+    pub fn start(reducer: &str, parent: Option<TraceParent>) -> Self {
+        let trace_id = parent.map_or_else(TraceId::random, |parent| parent.trace_id);
+        let parent_id = parent.map(|parent| parent.parent_id);
+        let span_id = SpanId::random();
+        let span = tracing::info_span!(
+            "reducer_call",
+            reducer,
+            %trace_id,
+            %span_id,
+            parent_span_id = parent_id.map(tracing::field::display),
+        );
+        Self {
+            trace_id,
+            span_id,
+            parent_id,
+            flags: parent.map_or(0, |parent| parent.flags),
+            span,
+        }
+    }
+
+    /// The `traceparent` naming this call as the parent,
+    /// e.g. to reply to an HTTP caller with.
+    pub fn traceparent(&self) -> TraceParent {
+        TraceParent {
+            trace_id: self.trace_id,
+            parent_id: self.span_id,
+            flags: self.flags,
+        }
+    }
+}
+
+/// A test exporter, recording the spans created while it's the default subscriber.
+#[cfg(test)]
+pub(crate) mod test_exporter {
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, Layer};
+    use tracing_subscriber::prelude::*;
+    use tracing_subscriber::registry::LookupSpan;
+
+    #[derive(Clone, Debug)]
+    pub struct ExportedSpan {
+        pub name: &'static str,
+        /// The names of the ancestors of this span, innermost first.
+        pub ancestors: Vec<&'static str>,
+        pub fields: HashMap<&'static str, String>,
+    }
+
+    #[derive(Clone, Default)]
+    pub struct Exporter(Arc<Mutex<Vec<ExportedSpan>>>);
+
+    struct FieldVisitor<'a>(&'a mut HashMap<&'static str, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Exporter {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).expect("new spans are registered");
+            let ancestors = span.scope().skip(1).map(|span| span.name()).collect();
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            self.0.lock().push(ExportedSpan {
+                name: attrs.metadata().name(),
+                ancestors,
+                fields,
+            });
+        }
+    }
+
+    impl Exporter {
+        /// Export the spans created on this thread until the returned guard is dropped.
+        pub fn set_default(&self) -> tracing::subscriber::DefaultGuard {
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(self.clone()))
+        }
+
+        /// The spans exported so far named `name`.
+        pub fn spans(&self, name: &str) -> Vec<ExportedSpan> {
+            self.0.lock().iter().filter(|span| span.name == name).cloned().collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_exporter::Exporter;
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn traceparents_round_trip() {
+        let parent: TraceParent = TRACEPARENT.parse().unwrap();
+        assert_eq!(parent.trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(parent.parent_id.to_string(), "00f067aa0ba902b7");
+        assert_eq!(parent.flags, 1);
+        assert_eq!(parent.to_string(), TRACEPARENT);
+
+        // Later versions may append fields.
+        let future = "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-what-the-future-holds";
+        assert_eq!(future.parse::<TraceParent>().unwrap().trace_id, parent.trace_id);
+    }
+
+    #[test]
+    fn malformed_traceparents_are_rejected() {
+        for malformed in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(malformed.parse::<TraceParent>(), Err(InvalidTraceParent), "{malformed}");
+        }
+    }
+
+    #[test]
+    fn calls_join_the_callers_trace_or_start_their_own() {
+        let exporter = Exporter::default();
+        let _guard = exporter.set_default();
+
+        let parent: TraceParent = TRACEPARENT.parse().unwrap();
+        let trace = ReducerTrace::start("transfer", Some(parent));
+        assert_eq!(trace.trace_id, parent.trace_id);
+        assert_eq!(trace.parent_id, Some(parent.parent_id));
+        assert_eq!(trace.traceparent().parent_id, trace.span_id);
+        assert_eq!(trace.traceparent().flags, 1);
+
+        let fresh = ReducerTrace::start("transfer", None);
+        assert_ne!(fresh.trace_id, parent.trace_id);
+        assert_eq!(fresh.parent_id, None);
+
+        let spans = exporter.spans("reducer_call");
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].fields["trace_id"], parent.trace_id.to_string());
+        assert_eq!(spans[0].fields["span_id"], trace.span_id.to_string());
+        assert_eq!(spans[0].fields["parent_span_id"], parent.parent_id.to_string());
+        assert_eq!(spans[1].fields["trace_id"], fresh.trace_id.to_string());
+        assert!(!spans[1].fields.contains_key("parent_span_id"));
+    }
+}
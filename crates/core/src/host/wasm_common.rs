@@ -340,7 +340,7 @@ pub(super) type TimingSpanSet = ResourceSlab<TimingSpanIdx>;
 pub fn err_to_errno(err: &NodesError) -> Option<NonZeroU16> {
     match err {
         NodesError::NotInTransaction => Some(errno::NOT_IN_TRANSACTION),
-        NodesError::DecodeRow(_) => Some(errno::BSATN_DECODE_ERROR),
+        NodesError::DecodeRow(_) | NodesError::DecodeValue(_) => Some(errno::BSATN_DECODE_ERROR),
         NodesError::TableNotFound => Some(errno::NO_SUCH_TABLE),
         NodesError::IndexNotFound => Some(errno::NO_SUCH_INDEX),
         NodesError::IndexNotUnique => Some(errno::INDEX_NOT_UNIQUE),
@@ -392,6 +392,10 @@ macro_rules! abi_funcs {
             "spacetime_10.0"::datastore_btree_scan_bsatn,
             "spacetime_10.0"::datastore_delete_by_btree_scan_bsatn,
             "spacetime_10.0"::identity,
+            "spacetime_10.1"::disconnect_client,
+            "spacetime_10.2"::reducer_transport,
+            "spacetime_10.3"::report_progress,
+            "spacetime_10.4"::database_features,
 
             // unstable:
             "spacetime_10.0"::volatile_nonatomic_schedule_immediate,
@@ -28,7 +28,7 @@ use crate::util::prometheus_handle::HistogramExt;
 use crate::worker_metrics::WORKER_METRICS;
 use spacetimedb_lib::buffer::DecodeError;
 use spacetimedb_lib::identity::AuthCtx;
-use spacetimedb_lib::{bsatn, ConnectionId, RawModuleDef, Timestamp};
+use spacetimedb_lib::{bsatn, ConnectionId, RawModuleDef, ReducerTransport, Timestamp};
 
 use super::*;
 
@@ -165,6 +165,7 @@ impl<T: WasmModule> WasmModuleHostActor<T> {
             replica_context.owner_identity,
             replica_context.database_identity,
             module_hash,
+            replica_context.next_module_generation(),
             log_tx,
             replica_context.subscriptions.clone(),
         );
@@ -308,7 +309,9 @@ impl<T: WasmInstance> ModuleInstance for WasmModuleInstance<T> {
     }
 
     fn call_reducer(&mut self, tx: Option<MutTxId>, params: CallReducerParams) -> ReducerCallResult {
-        crate::callgrind_flag::invoke_allowing_callgrind(|| self.call_reducer_with_tx(tx, params))
+        // Record the spans of the call in its trace.
+        let span = params.trace.span.clone();
+        span.in_scope(|| crate::callgrind_flag::invoke_allowing_callgrind(|| self.call_reducer_with_tx(tx, params)))
     }
 }
 
@@ -340,6 +343,9 @@ impl<T: WasmInstance> WasmModuleInstance<T> {
             reducer_id,
             args,
             timer,
+            mut phase_timer,
+            transport,
+            trace,
         } = params;
         let caller_connection_id_opt = (caller_connection_id != ConnectionId::ZERO).then_some(caller_connection_id);
 
@@ -371,27 +377,40 @@ impl<T: WasmInstance> WasmModuleInstance<T> {
             caller_connection_id: &caller_connection_id,
             timestamp,
             arg_bytes: args.get_bsatn().clone(),
+            transport,
         };
 
         // Before we take the lock, do some `with_label_values`.
-        let metric_reducer_plus_query_duration = WORKER_METRICS
-            .reducer_plus_query_duration
-            .with_label_values(&database_identity, op.name);
-        let metric_reducer_wasmtime_fuel_used = DB_METRICS
-            .reducer_wasmtime_fuel_used
-            .with_label_values(&database_identity, reducer_name);
-        let metric_reducer_duration_usec = DB_METRICS
-            .reducer_duration_usec
-            .with_label_values(&database_identity, reducer_name);
-        let metric_reducer_abi_time_usec = DB_METRICS
-            .reducer_abi_time_usec
-            .with_label_values(&database_identity, reducer_name);
-
+        let metric_reducer_plus_query_duration = WORKER_METRICS.reducer_plus_query_duration.with_label_values(
+            &database_identity,
+            op.name,
+            transport.as_str(),
+        );
+        let metric_reducer_wasmtime_fuel_used = DB_METRICS.reducer_wasmtime_fuel_used.with_label_values(
+            &database_identity,
+            reducer_name,
+            transport.as_str(),
+        );
+        let metric_reducer_duration_usec =
+            DB_METRICS
+                .reducer_duration_usec
+                .with_label_values(&database_identity, reducer_name, transport.as_str());
+        let metric_reducer_abi_time_usec =
+            DB_METRICS
+                .reducer_abi_time_usec
+                .with_label_values(&database_identity, reducer_name, transport.as_str());
+
+        if let Some(phase_timer) = &mut phase_timer {
+            phase_timer.began_executing();
+        }
         let workload = Workload::Reducer(ReducerContext::from(op.clone()));
         let tx = tx.unwrap_or_else(|| stdb.begin_mut_tx(IsolationLevel::Serializable, workload));
         let _guard = metric_reducer_plus_query_duration.with_timer(tx.timer);
 
         let mut tx_slot = self.instance.instance_env().tx.clone();
+        // Progress is sent to the caller as the reducer reports it, until it returns.
+        let progress = self.instance.instance_env().progress.clone();
+        progress.begin(client.clone(), request_id, database_identity);
 
         let reducer_span = tracing::trace_span!(
             "run_reducer",
@@ -402,6 +421,7 @@ impl<T: WasmInstance> WasmModuleInstance<T> {
         .entered();
 
         let (mut tx, result) = tx_slot.set(tx, || self.instance.call_reducer(op, budget));
+        progress.end();
 
         let ExecuteResult {
             energy,
@@ -509,21 +529,34 @@ impl<T: WasmInstance> WasmModuleInstance<T> {
             host_execution_duration: timings.total_duration,
             request_id,
             timer,
+            acknowledged: reducer_def.acknowledged,
+            trace_id: Some(trace.trace_id),
         };
+        if let Some(phase_timer) = &mut phase_timer {
+            phase_timer.executed();
+        }
         let (event, _) = match self
             .info
             .subscriptions
-            .commit_and_broadcast_event(client, event, tx)
+            .commit_and_broadcast_event_timed(client, event, tx, phase_timer)
             .unwrap()
         {
             Ok(ev) => ev,
             Err(WriteConflict) => todo!("Write skew, you need to implement retries my man, T-dawg."),
         };
 
+        // Only kick the clients the reducer disconnected if its transaction committed.
+        let disconnects = &self.instance.instance_env().disconnects;
+        match event.status {
+            EventStatus::Committed(_) => disconnects.apply(),
+            _ => disconnects.clear(),
+        }
+
         ReducerCallResult {
             outcome: ReducerOutcome::from(&event.status),
             energy_used: energy.used,
             execution_duration: timings.total_duration,
+            traceparent: trace.traceparent(),
         }
     }
 
@@ -543,6 +576,8 @@ pub struct ReducerOp<'a> {
     pub timestamp: Timestamp,
     /// The BSATN-serialized arguments passed to the reducer.
     pub arg_bytes: Bytes,
+    /// How the call arrived.
+    pub transport: ReducerTransport,
 }
 
 impl From<ReducerOp<'_>> for execution_context::ReducerContext {
@@ -554,6 +589,7 @@ impl From<ReducerOp<'_>> for execution_context::ReducerContext {
             caller_connection_id,
             timestamp,
             arg_bytes,
+            transport: _,
         }: ReducerOp<'_>,
     ) -> Self {
         Self {
@@ -1,5 +1,4 @@
 use std::borrow::Cow;
-use std::time::Duration;
 
 use anyhow::Context;
 use spacetimedb_paths::server::{ServerDataDir, WasmtimeCacheDir};
@@ -7,6 +6,7 @@ use wasmtime::{Engine, Linker, Module, StoreContext, StoreContextMut};
 
 use crate::energy::{EnergyQuanta, ReducerBudget};
 use crate::error::NodesError;
+use crate::host::metering::EPOCH_TICK_LENGTH;
 use crate::module_host_context::ModuleCreationContext;
 
 mod wasm_instance_env;
@@ -24,10 +24,6 @@ pub struct WasmtimeRuntime {
     linker: Box<Linker<WasmInstanceEnv>>,
 }
 
-const EPOCH_TICK_LENGTH: Duration = Duration::from_millis(10);
-
-const EPOCH_TICKS_PER_SECOND: u64 = Duration::from_secs(1).div_duration_f64(EPOCH_TICK_LENGTH) as u64;
-
 impl WasmtimeRuntime {
     pub fn new(data_dir: Option<&ServerDataDir>) -> Self {
         let mut config = wasmtime::Config::new();
@@ -12,7 +12,7 @@ use crate::host::wasm_common::{
 };
 use crate::host::AbiCall;
 use anyhow::Context as _;
-use spacetimedb_lib::Timestamp;
+use spacetimedb_lib::{ReducerTransport, Timestamp};
 use spacetimedb_primitives::{errno, ColId};
 use wasmtime::{AsContext, Caller, StoreContextMut};
 
@@ -140,7 +140,13 @@ impl WasmInstanceEnv {
     ///
     /// Returns the handle used by reducers to read from `args`
     /// as well as the handle used to write the error message, if any.
-    pub fn start_reducer(&mut self, name: &str, args: bytes::Bytes, ts: Timestamp) -> (u32, u32) {
+    pub fn start_reducer(
+        &mut self,
+        name: &str,
+        args: bytes::Bytes,
+        ts: Timestamp,
+        transport: ReducerTransport,
+    ) -> (u32, u32) {
         let errors = self.setup_standard_bytes_sink();
 
         // Pass an invalid source when the reducer args were empty.
@@ -154,7 +160,7 @@ impl WasmInstanceEnv {
 
         self.reducer_start = Instant::now();
         name.clone_into(&mut self.reducer_name);
-        self.instance_env.start_reducer(ts);
+        self.instance_env.start_reducer(ts, transport);
 
         (args, errors)
     }
@@ -1229,6 +1235,91 @@ impl WasmInstanceEnv {
             Ok(())
         })
     }
+
+    /// Disconnects the clients matching the BSATN-encoded `ClientTarget`
+    /// in `target = target_ptr[..target_len]`
+    /// once the current transaction commits,
+    /// telling them they were kicked by the module
+    /// for the UTF-8 `reason = reason_ptr[..reason_len]`.
+    ///
+    /// The number of connections which matched is written to `out`.
+    ///
+    /// # Traps
+    ///
+    /// Traps if:
+    ///
+    /// - `target_ptr` is NULL or `target` is not in bounds of WASM memory.
+    /// - `reason_ptr` is NULL or `reason` is not in bounds of WASM memory.
+    /// - `reason` is not valid UTF-8.
+    /// - `out` is NULL or `out[..size_of::<u32>()]` is not in bounds of WASM memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error:
+    ///
+    /// - `NOT_IN_TRANSACTION`, when called outside of a transaction.
+    /// - `BSATN_DECODE_ERROR`, when `target` cannot be decoded to a `ClientTarget`.
+    pub fn disconnect_client(
+        caller: Caller<'_, Self>,
+        target_ptr: WasmPtr<u8>,
+        target_len: u32,
+        reason_ptr: WasmPtr<u8>,
+        reason_len: u32,
+        out: WasmPtr<u32>,
+    ) -> RtResult<u32> {
+        Self::cvt_ret(caller, AbiCall::DisconnectClient, out, |caller| {
+            let (mem, env) = Self::mem_env(caller);
+            let target = mem.deref_slice(target_ptr, target_len)?;
+            let reason = mem.deref_str(reason_ptr, reason_len)?;
+            Ok(env.instance_env.disconnect_client(target, reason)?)
+        })
+    }
+
+    /// Returns the tag of the `ReducerTransport` the current reducer call arrived on.
+    pub fn reducer_transport(caller: Caller<'_, Self>) -> RtResult<u32> {
+        Self::with_span(caller, AbiCall::ReducerTransport, |caller| {
+            Ok(caller.data().instance_env.transport.to_u32())
+        })
+    }
+
+    /// Returns the mask of the `DatabaseFeatures` the database's owner turned on for it.
+    pub fn database_features(caller: Caller<'_, Self>) -> RtResult<u64> {
+        Self::with_span(caller, AbiCall::DatabaseFeatures, |caller| {
+            Ok(caller.data().instance_env.replica_ctx.features.get().bits())
+        })
+    }
+
+    /// Sends the bytes `payload = payload_ptr[..payload_len]`
+    /// to the caller of the current reducer as its progress, while the reducer is still running,
+    /// whether or not the current transaction goes on to commit.
+    ///
+    /// Progress may be dropped, e.g. when reported too often.
+    /// Writes `1` to `out` if it was sent and `0` if it wasn't.
+    ///
+    /// # Traps
+    ///
+    /// Traps if:
+    ///
+    /// - `payload_ptr` is NULL or `payload` is not in bounds of WASM memory.
+    /// - `out` is NULL or `out[..size_of::<u32>()]` is not in bounds of WASM memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error:
+    ///
+    /// - `NOT_IN_TRANSACTION`, when called outside of a transaction.
+    pub fn report_progress(
+        caller: Caller<'_, Self>,
+        payload_ptr: WasmPtr<u8>,
+        payload_len: u32,
+        out: WasmPtr<u32>,
+    ) -> RtResult<u32> {
+        Self::cvt_ret(caller, AbiCall::ReportProgress, out, |caller| {
+            let (mem, env) = Self::mem_env(caller);
+            let payload = mem.deref_slice(payload_ptr, payload_len)?;
+            Ok(env.instance_env.report_progress(payload)? as u32)
+        })
+    }
 }
 
 impl<T> BacktraceProvider for wasmtime::StoreContext<'_, T> {
@@ -1,9 +1,10 @@
 use self::module_host_actor::ReducerOp;
 
 use super::wasm_instance_env::WasmInstanceEnv;
-use super::{Mem, WasmtimeFuel, EPOCH_TICKS_PER_SECOND};
+use super::{Mem, WasmtimeFuel};
 use crate::energy::ReducerBudget;
 use crate::host::instance_env::InstanceEnv;
+use crate::host::metering::EpochInterruption;
 use crate::host::wasm_common::module_host_actor::{DescribeError, InitializationError};
 use crate::host::wasm_common::*;
 use crate::util::string_from_utf8_lossy_owned;
@@ -34,7 +35,7 @@ impl WasmtimeModule {
         WasmtimeModule { module }
     }
 
-    pub const IMPLEMENTED_ABI: abi::VersionTuple = abi::VersionTuple::new(10, 0);
+    pub const IMPLEMENTED_ABI: abi::VersionTuple = abi::VersionTuple::new(10, 2);
 
     pub(super) fn link_imports(linker: &mut Linker<WasmInstanceEnv>) -> anyhow::Result<()> {
         const { assert!(WasmtimeModule::IMPLEMENTED_ABI.major == spacetimedb_lib::MODULE_ABI_MAJOR_VERSION) };
@@ -89,6 +90,7 @@ impl module_host_actor::WasmInstancePre for WasmtimeModule {
     type Instance = WasmtimeInstance;
 
     fn instantiate(&self, env: InstanceEnv, func_names: &FuncNames) -> Result<Self::Instance, InitializationError> {
+        let epoch = EpochInterruption::of_database(&env.replica_ctx.relational_db);
         let env = WasmInstanceEnv::new(env);
         let mut store = Store::new(self.module.module().engine(), env);
         let instance = self
@@ -99,18 +101,32 @@ impl module_host_actor::WasmInstancePre for WasmtimeModule {
         let mem = Mem::extract(&instance, &mut store).unwrap();
         store.data_mut().instantiate(mem);
 
-        store.epoch_deadline_callback(|store| {
+        // The start of the call last warned about, and after how many seconds.
+        let mut warned = (store.data().reducer_start(), 0);
+        store.epoch_deadline_callback(move |store| {
             let env = store.data();
             let database = env.instance_env().replica_ctx.database_identity;
             let reducer = env.reducer_name();
-            let dur = env.reducer_start().elapsed();
-            tracing::warn!(reducer, ?database, "Wasm has been running for {dur:?}");
-            Ok(wasmtime::UpdateDeadline::Continue(EPOCH_TICKS_PER_SECOND))
+            let start = env.reducer_start();
+            let dur = start.elapsed();
+            if epoch.should_interrupt(dur) {
+                tracing::warn!(reducer, ?database, "Interrupting wasm after it ran for {dur:?}");
+                anyhow::bail!(
+                    "`{reducer}` was interrupted after running for {dur:?}, exceeding `max_reducer_duration_ms`"
+                );
+            }
+            // However often the call is checked on, warn about it at most once a second.
+            let secs = dur.as_secs();
+            if secs > 0 && (warned.0 != start || warned.1 < secs) {
+                warned = (start, secs);
+                tracing::warn!(reducer, ?database, "Wasm has been running for {dur:?}");
+            }
+            Ok(wasmtime::UpdateDeadline::Continue(epoch.interval_ticks))
         });
 
         // Note: this budget is just for initializers
         set_store_fuel(&mut store, ReducerBudget::DEFAULT_BUDGET.into());
-        store.set_epoch_deadline(EPOCH_TICKS_PER_SECOND);
+        store.set_epoch_deadline(epoch.interval_ticks);
 
         for preinit in &func_names.preinits {
             let func = instance.get_typed_func::<(), ()>(&mut store, preinit).unwrap();
@@ -143,6 +159,7 @@ impl module_host_actor::WasmInstancePre for WasmtimeModule {
             store,
             instance,
             call_reducer,
+            epoch,
         })
     }
 }
@@ -153,6 +170,8 @@ pub struct WasmtimeInstance {
     store: Store<WasmInstanceEnv>,
     instance: Instance,
     call_reducer: CallReducerType,
+    /// How the reducers of this instance are interrupted, as configured when it was instantiated.
+    epoch: EpochInterruption,
 }
 
 impl module_host_actor::WasmInstance for WasmtimeInstance {
@@ -203,14 +222,17 @@ impl module_host_actor::WasmInstance for WasmtimeInstance {
         // otherwise, we'd return something like `used: i128::MAX - u64::MAX`, which is inaccurate.
         set_store_fuel(store, budget.into());
         let original_fuel = get_store_fuel(store);
-        store.set_epoch_deadline(EPOCH_TICKS_PER_SECOND);
+        store.set_epoch_deadline(self.epoch.interval_ticks);
 
         // Prepare sender identity and connection ID, as LITTLE-ENDIAN byte arrays.
         let [sender_0, sender_1, sender_2, sender_3] = bytemuck::must_cast(op.caller_identity.to_byte_array());
         let [conn_id_0, conn_id_1] = bytemuck::must_cast(op.caller_connection_id.as_le_byte_array());
 
         // Prepare arguments to the reducer + the error sink & start timings.
-        let (args_source, errors_sink) = store.data_mut().start_reducer(op.name, op.arg_bytes, op.timestamp);
+        let (args_source, errors_sink) =
+            store
+                .data_mut()
+                .start_reducer(op.name, op.arg_bytes, op.timestamp, op.transport);
 
         let call_result = self.call_reducer.call(
             &mut *store,
@@ -1,3 +1,6 @@
+use std::time::Duration;
+
+use spacetimedb_client_api_messages::websocket::Compression;
 use spacetimedb_lib::Identity;
 use spacetimedb_sats::de::Deserialize;
 use spacetimedb_sats::hash::Hash;
@@ -38,6 +41,214 @@ pub struct Database {
     pub initial_program: Hash,
 }
 
+/// Network access rules of a database, set by its owner.
+///
+/// Ranges are in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`,
+/// and are matched against the address of the client as seen by the node's trusted proxies.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkAcl {
+    /// If non-empty, only clients within one of these ranges are admitted.
+    pub allow: Vec<String>,
+    /// Clients within these ranges are refused, even if within an `allow` range.
+    pub deny: Vec<String>,
+    /// Refuse clients as if the database did not exist, rather than with `403 Forbidden`.
+    pub deny_as_not_found: bool,
+}
+
+impl NetworkAcl {
+    /// Returns whether this ACL has no rules, i.e. admits every client.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// Defaults for the config of clients connecting to a database over websocket, set by its owner,
+/// e.g. to have third-party clients use compression without each of them asking for it.
+///
+/// Each setting applies to clients which don't choose it for themselves when connecting,
+/// or to every client if it's forced.
+/// Unset settings are left to the client, or to the node's defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientConfigDefaults {
+    /// The compression of messages to clients.
+    ///
+    /// Not applied to clients whose protocol version doesn't support it.
+    pub compression: Option<ClientDefault<Compression>>,
+    /// Whether clients are sent light transaction updates.
+    pub light: Option<ClientDefault<bool>>,
+}
+
+/// A setting of [`ClientConfigDefaults`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientDefault<T> {
+    pub value: T,
+    /// Whether `value` applies even to clients which choose otherwise.
+    pub forced: bool,
+}
+
+impl<T: Copy> ClientDefault<T> {
+    /// Resolve the setting for a client which asked for `requested`, if anything,
+    /// given the database's `default`, if any, and the node's `fallback`.
+    ///
+    /// A setting the client asked for wins over the database's default, unless the default is forced.
+    pub fn resolve(default: Option<Self>, requested: Option<T>, fallback: T) -> T {
+        match (default, requested) {
+            (Some(Self { value, forced: true }), _) => value,
+            (_, Some(requested)) => requested,
+            (Some(Self { value, forced: false }), None) => value,
+            (None, None) => fallback,
+        }
+    }
+}
+
+impl ClientConfigDefaults {
+    /// Returns whether no settings are defaulted.
+    pub fn is_empty(&self) -> bool {
+        let Self { compression, light } = self;
+        compression.is_none() && light.is_none()
+    }
+}
+
+/// Overrides of the timeouts after which a database's unresponsive clients are disconnected, set by its owner,
+/// e.g. to give clients on poor networks longer to respond.
+///
+/// Unset timeouts are the node's defaults.
+/// Either way, the timeouts of a client are clamped to the bounds set by the node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionTimeouts {
+    /// How often clients are pinged, in seconds,
+    /// and so how long they have to answer a ping before they're disconnected.
+    pub liveness_timeout_secs: Option<u64>,
+    /// How long sending a batch of messages or a ping to a client may take, in seconds,
+    /// before it's disconnected.
+    pub send_timeout_secs: Option<u64>,
+}
+
+impl ConnectionTimeouts {
+    /// Returns whether no timeouts are overridden.
+    pub fn is_empty(&self) -> bool {
+        let Self {
+            liveness_timeout_secs,
+            send_timeout_secs,
+        } = self;
+        liveness_timeout_secs.is_none() && send_timeout_secs.is_none()
+    }
+}
+
+/// Limits on the size of the messages a database's clients may send, set by its owner,
+/// e.g. to guard a database which only takes small messages against floods of large ones.
+///
+/// Unset limits are the node's.
+/// Either way, a database can only lower the node's limits, never raise them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageSizeLimits {
+    /// The largest message a client may send, in bytes.
+    pub max_message_size: Option<u64>,
+    /// The largest websocket frame a client may send, in bytes,
+    /// of which a message may be split over several.
+    pub max_frame_size: Option<u64>,
+}
+
+impl MessageSizeLimits {
+    /// Returns whether no limits are set.
+    pub fn is_empty(&self) -> bool {
+        let Self {
+            max_message_size,
+            max_frame_size,
+        } = self;
+        max_message_size.is_none() && max_frame_size.is_none()
+    }
+}
+
+/// What becomes of a database's clients which fall behind on the messages sent to them, set by its owner.
+///
+/// A client falls behind once the messages queued for it exceed either threshold,
+/// well before it fills the queue and is disconnected regardless.
+/// Without thresholds, clients are only ever disconnected for filling their queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SendBacklogPolicy {
+    pub action: SendBacklogAction,
+    /// How many messages may be queued for a client.
+    pub max_messages: Option<u64>,
+    /// How many bytes of messages may be queued for a client.
+    pub max_bytes: Option<u64>,
+}
+
+impl SendBacklogPolicy {
+    /// Returns whether no thresholds are set.
+    pub fn is_empty(&self) -> bool {
+        let Self {
+            action: _,
+            max_messages,
+            max_bytes,
+        } = self;
+        max_messages.is_none() && max_bytes.is_none()
+    }
+
+    /// Returns whether a client with `len` messages, of `bytes` bytes, queued for it is behind.
+    pub fn is_behind(&self, len: usize, bytes: usize) -> bool {
+        self.max_messages.is_some_and(|max| len as u64 > max) || self.max_bytes.is_some_and(|max| bytes as u64 > max)
+    }
+}
+
+/// What becomes of a client which fell behind, see [`SendBacklogPolicy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendBacklogAction {
+    /// The connection is closed as too slow.
+    #[default]
+    Close,
+    /// The client is sent light updates, each coalescing the transaction updates queued together,
+    /// until it catches up.
+    Coalesce,
+    /// Updates aren't computed for the client until it catches up,
+    /// when it's sent a `ResyncRequired` naming the subscriptions which missed updates.
+    Resync,
+}
+
+/// What becomes of a database's clients whose token expires while they're connected, set by its owner.
+///
+/// A client stays connected past its token's expiry by refreshing its token over the connection,
+/// which it may do until the grace period after the expiry is up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthExpiryPolicy {
+    pub action: AuthExpiryAction,
+    /// How many seconds past its token's expiry a client stays connected.
+    pub grace_secs: u64,
+}
+
+impl AuthExpiryPolicy {
+    /// Returns whether this is the policy of databases which don't set one.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// How long past its token's expiry a client stays connected.
+    pub fn grace(&self) -> Duration {
+        Duration::from_secs(self.grace_secs)
+    }
+}
+
+/// What becomes of a client whose token expired, see [`AuthExpiryPolicy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthExpiryAction {
+    /// The connection is closed as its auth expired.
+    #[default]
+    Close,
+    /// The connection stays open, the token having only been checked as the client connected.
+    Ignore,
+}
+
+/// The firehose of a database, set by its owner,
+/// to which a copy of every committed transaction update is sent.
+///
+/// See [`FirehoseTarget`](crate::subscription::firehose::FirehoseTarget) for the targets.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirehoseConfig {
+    pub target: String,
+}
+
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct DatabaseStatus {
     pub state: String,
@@ -1,10 +1,14 @@
 use super::database_logger::DatabaseLogger;
+use crate::client::{ConnectedClients, InFlightQueries, PendingDeliveries};
 use crate::db::relational_db::RelationalDB;
 use crate::error::DBError;
+use crate::host::publish::PublishGate;
 use crate::messages::control_db::Database;
 use crate::subscription::module_subscription_actor::ModuleSubscriptions;
+use spacetimedb_lib::DatabaseFeatures;
 use std::io;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 pub type Result<T> = anyhow::Result<T>;
@@ -17,9 +21,43 @@ pub struct ReplicaContext {
     pub logger: Arc<DatabaseLogger>,
     pub subscriptions: ModuleSubscriptions,
     pub relational_db: Arc<RelationalDB>,
+    /// The clients connected to the database on this node.
+    pub clients: ConnectedClients,
+    /// The acknowledged updates its clients have yet to acknowledge.
+    pub deliveries: PendingDeliveries,
+    /// The one-off queries in flight to the database, over all of its connections.
+    pub one_off_queries: Arc<InFlightQueries>,
+    /// The generation of the most recently created module of the database,
+    /// see [`ModuleSchema::generation`](crate::host::module_schema::ModuleSchema::generation).
+    pub module_generation: Arc<AtomicU64>,
+    /// Admits the reducer calls of clients, unless the database is being published to.
+    pub publish_gate: Arc<PublishGate>,
+    /// The features its owner turned on for the database, as seen by its module.
+    pub features: FeatureFlags,
+}
+
+/// The [`DatabaseFeatures`] turned on for a database, shared by the node with the database's module,
+/// which outlive the module so that they're kept across launches.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureFlags(Arc<AtomicU64>);
+
+impl FeatureFlags {
+    pub fn get(&self) -> DatabaseFeatures {
+        DatabaseFeatures::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Replace the features, which the module sees from its next reducer call.
+    pub fn set(&self, features: DatabaseFeatures) {
+        self.0.store(features.bits(), Ordering::Relaxed);
+    }
 }
 
 impl ReplicaContext {
+    /// Returns the generation of a module being created for the database.
+    pub fn next_module_generation(&self) -> u64 {
+        self.module_generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
     /// The number of bytes on disk occupied by the database's durability layer.
     ///
     /// An in-memory database will return `Ok(0)`.
@@ -51,6 +89,7 @@ impl ReplicaContext {
     pub fn update_gauges(&self) {
         self.relational_db.update_data_size_metrics();
         self.subscriptions.update_gauges();
+        self.deliveries.update_gauges();
     }
 }
 
@@ -1,15 +1,22 @@
 use crate::db::datastore::locking_tx_datastore::state_view::StateView;
-use crate::db::datastore::system_tables::{StRowLevelSecurityFields, ST_ROW_LEVEL_SECURITY_ID};
+use crate::db::datastore::system_tables::{
+    StRowLevelSecurityFields, StVarFields, StVarName, StVarRow, ST_ROW_LEVEL_SECURITY_ID, ST_VAR_ID,
+};
 use crate::db::relational_db::{MutTx, RelationalDB, Tx};
 use crate::error::{DBError, PlanError};
+use crate::worker_metrics::WORKER_METRICS;
 use anyhow::Context;
 use spacetimedb_data_structures::map::{HashCollectionExt as _, IntMap};
 use spacetimedb_expr::check::SchemaView;
+use spacetimedb_expr::errors::TypingError;
+use spacetimedb_expr::limits::QueryLimits;
 use spacetimedb_expr::statement::compile_sql_stmt;
 use spacetimedb_lib::db::auth::StAccess;
 use spacetimedb_lib::db::error::RelationError;
 use spacetimedb_lib::identity::AuthCtx;
 use spacetimedb_lib::relation::{ColExpr, FieldName};
+use spacetimedb_lib::st_var::StVarValue;
+use spacetimedb_lib::Identity;
 use spacetimedb_primitives::{ColId, TableId};
 use spacetimedb_sats::{AlgebraicType, AlgebraicValue};
 use spacetimedb_schema::schema::{ColumnSchema, TableSchema};
@@ -511,6 +518,18 @@ impl<T: StateView> SchemaView for SchemaViewer<'_, T> {
             .cloned()
     }
 
+    fn is_inaccessible(&self, name: &str) -> bool {
+        let AuthCtx { owner, caller } = self.auth;
+        caller != owner
+            && self
+                .tx
+                .table_id_from_name(name)
+                .ok()
+                .flatten()
+                .and_then(|table_id| self.tx.get_schema(table_id))
+                .is_some_and(|schema| schema.table_access == StAccess::Private)
+    }
+
     fn rls_rules_for_table(&self, table_id: TableId) -> anyhow::Result<Vec<Box<str>>> {
         self.tx
             .iter_by_col_eq(
@@ -537,6 +556,27 @@ impl<T: StateView> SchemaView for SchemaViewer<'_, T> {
             })
             .collect::<anyhow::Result<_>>()
     }
+
+    fn query_limits(&self) -> QueryLimits {
+        let read_var = |name: StVarName| match self
+            .tx
+            .iter_by_col_eq(ST_VAR_ID, StVarFields::Name, &name.into())
+            .ok()?
+            .next()
+            .map(StVarRow::try_from)
+        {
+            Some(Ok(StVarRow {
+                value: StVarValue::U64(max),
+                ..
+            })) => Some(max.try_into().unwrap_or(usize::MAX)),
+            _ => None,
+        };
+        QueryLimits::new(
+            read_var(StVarName::MaxQueryLength),
+            read_var(StVarName::MaxQueryPredicates),
+            read_var(StVarName::MaxQueryJoins),
+        )
+    }
 }
 
 impl<'a, T> SchemaViewer<'a, T> {
@@ -545,6 +585,17 @@ impl<'a, T> SchemaViewer<'a, T> {
     }
 }
 
+/// Count `err` in [`WORKER_METRICS`] if it rejected a query from `source`,
+/// e.g. `"subscription"`, for exceeding the [`QueryLimits`] of the database.
+pub fn record_query_limit_rejection(database_identity: &Identity, source: &str, err: &anyhow::Error) {
+    if let Some(TypingError::QueryLimit(exceeded)) = err.downcast_ref() {
+        WORKER_METRICS
+            .query_limit_rejections
+            .with_label_values(database_identity, source, exceeded.limit.as_str())
+            .inc();
+    }
+}
+
 pub trait TableSchemaView {
     fn find_table(&self, db: &RelationalDB, t: Table) -> Result<Arc<TableSchema>, PlanError>;
 }
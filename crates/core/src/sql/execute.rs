@@ -1,7 +1,7 @@
 use std::sync::Arc;
 use std::time::Duration;
 
-use super::ast::SchemaViewer;
+use super::ast::{record_query_limit_rejection, SchemaViewer};
 use crate::db::datastore::locking_tx_datastore::state_view::StateView;
 use crate::db::datastore::traits::IsolationLevel;
 use crate::db::relational_db::{RelationalDB, Tx};
@@ -140,6 +140,8 @@ pub fn execute_sql(
                 host_execution_duration: Duration::ZERO,
                 request_id: None,
                 timer: None,
+                acknowledged: false,
+                trace_id: None,
             };
             match subs.unwrap().commit_and_broadcast_event(None, event, tx).unwrap() {
                 Ok(_) => res,
@@ -190,6 +192,7 @@ pub fn run(
     // If it turns out to be a query, we downgrade the tx.
     let (tx, stmt) = db.with_auto_rollback(db.begin_mut_tx(IsolationLevel::Serializable, Workload::Sql), |tx| {
         compile_sql_stmt(sql_text, &SchemaViewer::new(tx, &auth), &auth)
+            .inspect_err(|err| record_query_limit_rejection(&db.database_identity(), "sql", err))
     })?;
 
     let mut metrics = ExecutionMetrics::default();
@@ -281,6 +284,8 @@ pub fn run(
                         host_execution_duration: Duration::ZERO,
                         request_id: None,
                         timer: None,
+                        acknowledged: false,
+                        trace_id: None,
                     },
                     tx,
                 )
@@ -315,6 +320,7 @@ pub(crate) mod tests {
     };
     use crate::db::relational_db::tests_utils::{begin_tx, insert, with_auto_commit, TestDB};
     use crate::vm::tests::create_table_with_rows;
+    use crate::worker_metrics::WORKER_METRICS;
     use itertools::Itertools;
     use pretty_assertions::assert_eq;
     use spacetimedb_lib::bsatn::ToBsatn;
@@ -1237,6 +1243,80 @@ pub(crate) mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_private_tables_are_only_accessible_to_the_owner() -> ResultTest<()> {
+        let db = TestDB::durable()?;
+        let cols = &[("a", AlgebraicType::U8)];
+        db.create_table_for_test("public", cols, &[])?;
+        db.create_table_for_test_with_access("private", cols, &[], StAccess::Private)?;
+
+        let owner = Identity::from_claims("issuer", "owner");
+        let client = Identity::from_claims("issuer", "client");
+        let owner_auth = AuthCtx::new(owner, owner);
+        let client_auth = AuthCtx::new(owner, client);
+
+        let run = |sql, auth| run(&db, sql, auth, None, &mut vec![]);
+
+        assert!(run("SELECT * FROM public", client_auth).is_ok());
+        for sql in [
+            "SELECT * FROM private",
+            "SELECT public.* FROM public JOIN private ON public.a = private.a",
+            "SELECT private.* FROM private JOIN public ON private.a = public.a",
+            "DELETE FROM private",
+        ] {
+            assert!(run(sql, owner_auth).is_ok(), "{sql}");
+            let Err(err) = run(sql, client_auth) else {
+                panic!("{sql}: expected the private table to be inaccessible");
+            };
+            let err = err.to_string();
+            assert!(err.contains("Table `private` is not accessible"), "{sql}: {err}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_limits() -> ResultTest<()> {
+        let db = TestDB::durable()?;
+        db.create_table_for_test("T", &[("a", AlgebraicType::U8), ("b", AlgebraicType::U8)], &[])?;
+        db.create_table_for_test("S", &[("a", AlgebraicType::U8)], &[])?;
+
+        let rejections = |limit| {
+            WORKER_METRICS
+                .query_limit_rejections
+                .with_label_values(&db.database_identity(), "sql", limit)
+                .get()
+        };
+        let run = |sql| run_for_testing(&db, sql);
+
+        let complex = "SELECT T.* FROM T JOIN S ON T.a = S.a WHERE T.b = 1 AND T.b = 2";
+        assert!(run(complex).is_ok());
+
+        run("SET max_query_predicates = 2")?;
+        let rejected = rejections("predicates");
+        let err = run(complex).unwrap_err().to_string();
+        assert!(
+            err.contains("Query has 3 predicates, exceeding the limit `max_query_predicates` of 2"),
+            "{err}"
+        );
+        assert_eq!(rejections("predicates"), rejected + 1);
+        assert!(run("SELECT * FROM T WHERE a = 1 AND b = 2").is_ok());
+
+        run("SET max_query_joins = 0")?;
+        let err = run("SELECT T.* FROM T JOIN S ON T.a = S.a").unwrap_err().to_string();
+        assert!(
+            err.contains("Query has 1 joins, exceeding the limit `max_query_joins` of 0"),
+            "{err}"
+        );
+
+        run("SET max_query_length = 30")?;
+        let err = run("SELECT * FROM T WHERE a = 1 AND b = 2").unwrap_err().to_string();
+        assert!(err.contains("exceeding the limit `max_query_length` of 30"), "{err}");
+        assert!(run("SELECT * FROM T").is_ok());
+
+        Ok(())
+    }
+
     // Verify we don't return rows on DML
     #[test]
     fn test_row_dml() -> ResultTest<()> {
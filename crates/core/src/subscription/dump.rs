@@ -0,0 +1,128 @@
+//! Dumps of a client's subscriptions, for debugging and support tooling.
+//!
+//! A dump captures which queries a connection has registered,
+//! so that the same set can be registered again, e.g. on a local instance,
+//! to reproduce reports of missing updates.
+
+use spacetimedb_lib::{ConnectionId, Identity, SpacetimeType, Timestamp};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::keywords::Keyword;
+use sqlparser::tokenizer::{Token, Tokenizer};
+
+/// The subscriptions of a single client connection.
+#[derive(Debug, Clone, PartialEq, Eq, SpacetimeType)]
+#[sats(crate = spacetimedb_lib)]
+pub struct ClientSubscriptions {
+    pub identity: Identity,
+    pub connection_id: ConnectionId,
+    /// Whether parameter values were redacted from this dump,
+    /// as requested by the database's `redact_subscription_parameters` system variable.
+    ///
+    /// The queries of a redacted dump can't be registered again.
+    pub redacted: bool,
+    pub subscriptions: Vec<SubscriptionDump>,
+}
+
+/// A set of queries the client subscribed to in a single request.
+#[derive(Debug, Clone, PartialEq, Eq, SpacetimeType)]
+#[sats(crate = spacetimedb_lib)]
+pub struct SubscriptionDump {
+    /// The id the client chose for this subscription,
+    /// or `None` for its legacy subscription, of which it has at most one.
+    pub query_id: Option<u32>,
+    pub queries: Vec<QueryDump>,
+}
+
+/// A query in a [`SubscriptionDump`].
+#[derive(Debug, Clone, PartialEq, Eq, SpacetimeType)]
+#[sats(crate = spacetimedb_lib)]
+pub struct QueryDump {
+    /// The query text, with whitespace and comments collapsed,
+    /// and literals replaced by `?` if the dump is redacted.
+    pub sql: String,
+    /// The equality selections on which the query is parameterized.
+    pub parameters: Vec<QueryParameter>,
+    /// When the client first subscribed to this query.
+    pub registered_at: Timestamp,
+    /// The number of rows sent to the client in transaction updates for this query,
+    /// not counting the rows it was sent when it subscribed.
+    pub rows_delivered: u64,
+}
+
+/// An equality selection `table_id.col_id = value` of a [`QueryDump`].
+#[derive(Debug, Clone, PartialEq, Eq, SpacetimeType)]
+#[sats(crate = spacetimedb_lib)]
+pub struct QueryParameter {
+    pub table_id: u32,
+    pub col_id: u16,
+    /// The value, in SATN, or `None` if the dump is redacted.
+    pub value: Option<String>,
+}
+
+/// Normalize the query text `sql` for a dump,
+/// replacing its literals with `?` if `redact` is set.
+///
+/// If `sql` can't be tokenized, it is returned as is, or as `?` if `redact` is set.
+pub(crate) fn normalize_sql(sql: &str, redact: bool) -> String {
+    let Ok(tokens) = Tokenizer::new(&PostgreSqlDialect {}, sql).tokenize() else {
+        return if redact { "?".into() } else { sql.into() };
+    };
+    let mut normalized = String::with_capacity(sql.len());
+    let mut space = false;
+    for token in tokens {
+        match token {
+            Token::Whitespace(_) => {
+                space = true;
+                continue;
+            }
+            Token::EOF => break,
+            _ => {}
+        }
+        if space && !normalized.is_empty() {
+            normalized.push(' ');
+        }
+        space = false;
+        if redact && is_literal(&token) {
+            normalized.push('?');
+        } else {
+            normalized.push_str(&token.to_string());
+        }
+    }
+    normalized
+}
+
+fn is_literal(token: &Token) -> bool {
+    match token {
+        Token::Number(..)
+        | Token::SingleQuotedString(_)
+        | Token::NationalStringLiteral(_)
+        | Token::EscapedStringLiteral(_)
+        | Token::HexStringLiteral(_) => true,
+        Token::Word(word) => matches!(word.keyword, Keyword::TRUE | Keyword::FALSE),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_sql;
+
+    #[test]
+    fn normalizing_collapses_whitespace_and_comments() {
+        let sql = "  SELECT *\n\tFROM   t -- all of them\nWHERE t.id = 5 ";
+        assert_eq!(normalize_sql(sql, false), "SELECT * FROM t WHERE t.id = 5");
+    }
+
+    #[test]
+    fn redacting_replaces_literals() {
+        let sql = "SELECT t.* FROM t JOIN s ON t.id = s.id WHERE s.name = 'alice' AND s.age > 30 AND s.ok = true";
+        assert_eq!(
+            normalize_sql(sql, true),
+            "SELECT t.* FROM t JOIN s ON t.id = s.id WHERE s.name = ? AND s.age > ? AND s.ok = ?"
+        );
+        assert_eq!(
+            normalize_sql("SELECT * FROM t WHERE t.owner = :sender", true),
+            "SELECT * FROM t WHERE t.owner = :sender"
+        );
+    }
+}
@@ -254,7 +254,11 @@ impl ExecutionUnit {
 
         (!inserts.is_empty()).then(|| {
             let deletes = F::List::default();
-            let qu = QueryUpdate { deletes, inserts };
+            let qu = QueryUpdate {
+                deletes,
+                inserts,
+                columns: Box::default(),
+            };
             let update = F::into_query_update(qu, compression);
             TableUpdate::new(
                 self.return_table(),
@@ -0,0 +1,140 @@
+//! A node-wide budget for fanning out subscription updates to clients.
+//!
+//! A single commit to a table with many subscribers makes its database's send worker
+//! enqueue a message for every one of them.
+//! Done in a tight loop, this hogs a Tokio worker, and delays everything else on the node.
+//! Instead, send workers take enqueues from a shared [`FanoutBudget`], in batches,
+//! yielding between them, and waiting for the budget to replenish when it runs out.
+//!
+//! Commits don't wait for the fan-out of their updates,
+//! which happens asynchronously in the send worker,
+//! and every connection still receives its updates in commit order.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Node-wide tuning of the fan-out of subscription updates.
+///
+/// Read from the `[fanout]` section of `config.toml`.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct FanoutOptions {
+    /// How many messages the send workers of all databases may enqueue per millisecond, together.
+    ///
+    /// This is also the size of the largest batch a send worker enqueues before yielding.
+    /// With 0, the default, fan-out is unlimited.
+    /// A node whose large fan-outs delay its other work might set it to a few hundred.
+    pub enqueues_per_ms: u32,
+}
+
+/// A token bucket of enqueues, shared by the send workers of every database on the node.
+///
+/// The [`Default`] budget is unlimited.
+#[derive(Debug, Default)]
+pub struct FanoutBudget {
+    /// The refill rate, per millisecond, and capacity of the bucket, if limited.
+    enqueues_per_ms: Option<u32>,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self {
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+        }
+    }
+}
+
+impl Bucket {
+    /// Refill the bucket at `per_ms` tokens per millisecond, up to `per_ms`,
+    /// then take up to `wanted` tokens,
+    /// or return how long until one is available.
+    fn take(&mut self, per_ms: u32, wanted: usize) -> Result<usize, Duration> {
+        let per_ms = per_ms as f64;
+        let now = Instant::now();
+        let elapsed_ms = now.duration_since(self.refilled_at).as_secs_f64() * 1000.0;
+        self.tokens = (self.tokens + elapsed_ms * per_ms).min(per_ms);
+        self.refilled_at = now;
+        if self.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - self.tokens) / per_ms / 1000.0));
+        }
+        let taken = (self.tokens as usize).min(wanted);
+        self.tokens -= taken as f64;
+        Ok(taken)
+    }
+}
+
+impl FanoutBudget {
+    pub fn new(options: FanoutOptions) -> Self {
+        let enqueues_per_ms = (options.enqueues_per_ms != 0).then_some(options.enqueues_per_ms);
+        let bucket = Bucket {
+            tokens: enqueues_per_ms.unwrap_or_default() as f64,
+            refilled_at: Instant::now(),
+        };
+        Self {
+            enqueues_per_ms,
+            bucket: Mutex::new(bucket),
+        }
+    }
+
+    /// Take up to `wanted` enqueues from the budget,
+    /// waiting until at least one is available,
+    /// and returning the number taken.
+    ///
+    /// Yields to the runtime either way, so that callers looping over batches don't hog a worker.
+    pub async fn acquire(&self, wanted: usize) -> usize {
+        let Some(per_ms) = self.enqueues_per_ms else {
+            tokio::task::yield_now().await;
+            return wanted;
+        };
+        loop {
+            // Bind the result, so that the lock is released before awaiting.
+            let taken = self.bucket.lock().take(per_ms, wanted);
+            match taken {
+                Ok(taken) => {
+                    tokio::task::yield_now().await;
+                    return taken;
+                }
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unlimited_budgets_grant_everything() {
+        let budget = FanoutBudget::default();
+        assert_eq!(budget.acquire(20_000).await, 20_000);
+        assert_eq!(budget.acquire(20_000).await, 20_000);
+
+        // A node which doesn't configure its fan-out isn't throttled.
+        let budget = FanoutBudget::new(FanoutOptions::default());
+        assert_eq!(budget.acquire(20_000).await, 20_000);
+    }
+
+    #[tokio::test]
+    async fn batches_are_capped_and_paced() {
+        let budget = FanoutBudget::new(FanoutOptions { enqueues_per_ms: 10 });
+        let start = Instant::now();
+        let mut taken = 0;
+        while taken < 200 {
+            let batch = budget.acquire(200 - taken).await;
+            assert!((1..=10).contains(&batch), "{batch}");
+            taken += batch;
+        }
+        // The first 10 enqueues are available immediately, the rest at 10 per millisecond.
+        assert!(start.elapsed() >= Duration::from_millis(19), "{:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,612 @@
+//! Per-database export of every committed transaction update, the "firehose",
+//! e.g. for feeding a database's changes into an analytics pipeline
+//! without a websocket client which could fall behind.
+//!
+//! A database's owner may attach one [`FirehoseSink`] to it, chosen by a [`FirehoseTarget`].
+//! The subscription broadcaster [tees](Firehose::tee) each committed update into the firehose's own queue,
+//! of at most [`FIREHOSE_QUEUE_CAPACITY`] updates, from which a task serializes and sends them to the sink.
+//! Updates which find the queue full, or which the sink fails to send, are dropped and counted,
+//! so that a slow or broken sink never holds up delivery to clients.
+//!
+//! Each update is sent as a single line of JSON, see [`FirehoseRecord`].
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use serde::Serialize;
+use spacetimedb_client_api_messages::websocket::FirehoseStats;
+use spacetimedb_lib::{Identity, Timestamp};
+use spacetimedb_paths::server::FirehoseDir;
+use spacetimedb_sats::ser::serde::SerializeWrapper;
+use spacetimedb_sats::ProductValue;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::host::module_host::{EventStatus, ModuleEvent};
+use crate::worker_metrics::WORKER_METRICS;
+
+/// How many updates may be queued for a firehose's sink before further updates are dropped.
+pub const FIREHOSE_QUEUE_CAPACITY: usize = 1024;
+
+/// Where a firehose sends the updates of its database.
+///
+/// Implemented in-tree by the sinks of [`FirehoseTarget::File`] and [`FirehoseTarget::Unix`],
+/// and by embedders for [`FirehoseTarget::External`] targets, e.g. message brokers.
+#[async_trait]
+pub trait FirehoseSink: Send + Sync {
+    /// Send `update`, a single line of JSON terminated by a newline.
+    ///
+    /// Updates are sent one at a time, in the order their transactions committed.
+    /// An update which fails to send is dropped, and the next one is sent as usual,
+    /// so a sink which holds a connection should reconnect as needed.
+    async fn send(&self, update: &[u8]) -> anyhow::Result<()>;
+}
+
+/// Why a firehose couldn't be attached to a database.
+#[derive(Error, Debug)]
+pub enum FirehoseError {
+    #[error("invalid firehose target `{0}`: expected `file:<name>`, `unix:<name>` or `<scheme>:<address>`")]
+    InvalidTarget(String),
+    #[error("invalid firehose target `{0}`: the name must be a single path component")]
+    InvalidName(String),
+    #[error("no sink is available for firehose target `{0}`")]
+    Unsupported(String),
+    #[error("failed to open firehose sink `{target}`: {source:#}")]
+    Open {
+        target: String,
+        #[source]
+        source: anyhow::Error,
+    },
+}
+
+/// The sink of a firehose, as chosen by the owner of its database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FirehoseTarget {
+    /// `file:<name>`: append updates to the file `name` in the node's [`FirehoseDir`].
+    File(String),
+    /// `unix:<name>`: write updates to the stream socket `name` in the node's [`FirehoseDir`],
+    /// which the consumer of the updates listens on.
+    Unix(String),
+    /// `<scheme>:<address>`, for any other scheme: send updates to a sink provided by the embedder, if any.
+    External(String),
+}
+
+impl FromStr for FirehoseTarget {
+    type Err = FirehoseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((scheme, rest)) = s.split_once(':').filter(|(scheme, rest)| {
+            !rest.is_empty()
+                && scheme.chars().next().is_some_and(|first| first.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }) else {
+            return Err(FirehoseError::InvalidTarget(s.to_owned()));
+        };
+        // The names of in-tree sinks are confined to the firehose directory,
+        // so that an owner can't write to arbitrary files or sockets of the node.
+        let name = || {
+            let single_component = !rest.contains(['/', '\\']) && rest != "." && rest != "..";
+            single_component
+                .then(|| rest.to_owned())
+                .ok_or_else(|| FirehoseError::InvalidName(s.to_owned()))
+        };
+        match scheme {
+            "file" => name().map(Self::File),
+            "unix" => name().map(Self::Unix),
+            _ => Ok(Self::External(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for FirehoseTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(name) => write!(f, "file:{name}"),
+            Self::Unix(name) => write!(f, "unix:{name}"),
+            Self::External(target) => f.write_str(target),
+        }
+    }
+}
+
+impl FirehoseTarget {
+    /// Open the in-tree sink of this target, in `dir`.
+    ///
+    /// Returns `None` for [`FirehoseTarget::External`] targets, which only embedders can open.
+    pub fn open_in_tree(&self, dir: &FirehoseDir) -> Option<Arc<dyn FirehoseSink>> {
+        match self {
+            Self::File(name) => Some(Arc::new(FileSink::new(dir.0.join(name)))),
+            Self::Unix(name) => Some(Arc::new(UnixSocketSink::new(dir.0.join(name)))),
+            Self::External(_) => None,
+        }
+    }
+}
+
+/// A [`FirehoseSink`] which appends updates to a file,
+/// creating it on the first update, and reopening it after a failed write.
+pub struct FileSink {
+    path: PathBuf,
+    file: tokio::sync::Mutex<Option<tokio::fs::File>>,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: <_>::default(),
+        }
+    }
+
+    async fn open(path: &Path) -> io::Result<tokio::fs::File> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::OpenOptions::new().create(true).append(true).open(path).await
+    }
+}
+
+#[async_trait]
+impl FirehoseSink for FileSink {
+    async fn send(&self, update: &[u8]) -> anyhow::Result<()> {
+        let mut file = self.file.lock().await;
+        let open = match &mut *file {
+            Some(open) => open,
+            closed @ None => closed.insert(Self::open(&self.path).await?),
+        };
+        // tokio writes files from a blocking task, which only a flush waits for and reports the errors of.
+        let written = match open.write_all(update).await {
+            Ok(()) => open.flush().await,
+            err => err,
+        };
+        if let Err(e) = written {
+            *file = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+/// A [`FirehoseSink`] which writes updates to a unix stream socket,
+/// connecting on the first update, and reconnecting after a failed write.
+pub struct UnixSocketSink {
+    path: PathBuf,
+    #[cfg(unix)]
+    stream: tokio::sync::Mutex<Option<tokio::net::UnixStream>>,
+}
+
+impl UnixSocketSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            #[cfg(unix)]
+            stream: <_>::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl FirehoseSink for UnixSocketSink {
+    #[cfg(unix)]
+    async fn send(&self, update: &[u8]) -> anyhow::Result<()> {
+        let mut stream = self.stream.lock().await;
+        let connected = match &mut *stream {
+            Some(connected) => connected,
+            disconnected @ None => disconnected.insert(tokio::net::UnixStream::connect(&self.path).await?),
+        };
+        if let Err(e) = connected.write_all(update).await {
+            *stream = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    async fn send(&self, _update: &[u8]) -> anyhow::Result<()> {
+        anyhow::bail!(
+            "unix sockets are not supported on this platform: {}",
+            self.path.display()
+        )
+    }
+}
+
+/// An update, as sent to a [`FirehoseSink`], serialized as JSON.
+#[derive(Serialize)]
+pub struct FirehoseRecord<'a> {
+    pub database_identity: Identity,
+    /// When the transaction committed, in microseconds since the Unix epoch.
+    pub committed_at_micros: i64,
+    /// The reducer which committed the transaction, if any.
+    pub reducer: &'a str,
+    pub caller_identity: Identity,
+    /// The rows inserted into and deleted from each table the transaction changed.
+    pub tables: Vec<FirehoseTableUpdate<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct FirehoseTableUpdate<'a> {
+    pub table_name: &'a str,
+    pub inserts: &'a SerializeWrapper<[ProductValue]>,
+    pub deletes: &'a SerializeWrapper<[ProductValue]>,
+}
+
+/// A committed update waiting in the queue of a firehose.
+struct QueuedUpdate {
+    event: Arc<ModuleEvent>,
+    committed_at: Timestamp,
+}
+
+impl QueuedUpdate {
+    /// Serialize this update as a line of JSON.
+    fn to_line(&self, database_identity: Identity) -> serde_json::Result<Vec<u8>> {
+        let EventStatus::Committed(update) = &self.event.status else {
+            unreachable!("only committed updates are queued")
+        };
+        let record = FirehoseRecord {
+            database_identity,
+            committed_at_micros: self.committed_at.to_micros_since_unix_epoch(),
+            reducer: &self.event.function_call.reducer,
+            caller_identity: self.event.caller_identity,
+            tables: update
+                .tables
+                .iter()
+                .map(|table| FirehoseTableUpdate {
+                    table_name: &table.table_name,
+                    inserts: SerializeWrapper::from_ref(&table.inserts[..]),
+                    deletes: SerializeWrapper::from_ref(&table.deletes[..]),
+                })
+                .collect(),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+/// The health of an attached sink, shared with the task sending to it.
+#[derive(Default)]
+struct SinkHealth {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    last_error: Mutex<Option<Box<str>>>,
+}
+
+impl SinkHealth {
+    fn drop_update(&self, database_identity: &Identity, reason: &str) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        WORKER_METRICS
+            .firehose_dropped
+            .with_label_values(database_identity, reason)
+            .inc();
+    }
+}
+
+struct AttachedSink {
+    queue: mpsc::Sender<QueuedUpdate>,
+    health: Arc<SinkHealth>,
+}
+
+/// The firehose of a database, to which a [`FirehoseSink`] may be attached, or not.
+///
+/// Cloning a `Firehose` yields a handle to the same firehose.
+/// A database's firehose outlives its module, so that a sink stays attached across module updates and restarts.
+#[derive(Clone)]
+pub struct Firehose {
+    database_identity: Identity,
+    sink: Arc<Mutex<Option<AttachedSink>>>,
+}
+
+impl Firehose {
+    /// A firehose for the database `database_identity`, with no sink attached.
+    pub fn new(database_identity: Identity) -> Self {
+        Self {
+            database_identity,
+            sink: <_>::default(),
+        }
+    }
+
+    /// Attach `sink` to this firehose, replacing any attached sink,
+    /// or detach the attached sink if `sink` is `None`.
+    ///
+    /// Takes effect from the next committed update.
+    /// The updates already queued for a replaced sink are still sent to it, then it's dropped.
+    /// The health of the firehose is reset.
+    ///
+    /// Must be called within a tokio runtime, on which the updates are sent.
+    pub fn set(&self, sink: Option<Arc<dyn FirehoseSink>>) {
+        let attached = sink.map(|sink| {
+            let (queue, rx) = mpsc::channel(FIREHOSE_QUEUE_CAPACITY);
+            let health = Arc::new(SinkHealth::default());
+            tokio::spawn(send_updates(self.database_identity, rx, sink, health.clone()));
+            AttachedSink { queue, health }
+        });
+        *self.sink.lock() = attached;
+    }
+
+    /// Returns whether a sink is attached.
+    pub fn is_attached(&self) -> bool {
+        self.sink.lock().is_some()
+    }
+
+    /// Queue `event` for the attached sink, if any, or drop it if the queue is full.
+    ///
+    /// Only updates of committed transactions are sent.
+    /// This never blocks, as serializing and sending the update happen on the firehose's own task.
+    pub fn tee(&self, event: &Arc<ModuleEvent>, committed_at: Timestamp) {
+        if !matches!(event.status, EventStatus::Committed(_)) {
+            return;
+        }
+        let sink = self.sink.lock();
+        let Some(sink) = &*sink else {
+            return;
+        };
+        let update = QueuedUpdate {
+            event: event.clone(),
+            committed_at,
+        };
+        if sink.queue.try_send(update).is_err() {
+            sink.health.drop_update(&self.database_identity, "queue_full");
+        }
+    }
+
+    /// The health of the attached sink, or `None` if none is attached.
+    pub fn health(&self) -> Option<FirehoseStats> {
+        let sink = self.sink.lock();
+        let AttachedSink { queue, health } = sink.as_ref()?;
+        let stats = FirehoseStats {
+            queue_depth: (queue.max_capacity() - queue.capacity()) as u32,
+            sent: health.sent.load(Ordering::Relaxed),
+            dropped: health.dropped.load(Ordering::Relaxed),
+            last_error: health.last_error.lock().clone(),
+        };
+        Some(stats)
+    }
+}
+
+/// Serialize the updates queued in `rx` and send them to `sink`, until the sink is detached.
+async fn send_updates(
+    database_identity: Identity,
+    mut rx: mpsc::Receiver<QueuedUpdate>,
+    sink: Arc<dyn FirehoseSink>,
+    health: Arc<SinkHealth>,
+) {
+    while let Some(update) = rx.recv().await {
+        let result = match update.to_line(database_identity) {
+            Ok(line) => sink.send(&line).await,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(()) => {
+                health.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                log::warn!("firehose of database {database_identity} failed to send an update: {e:#}");
+                *health.last_error.lock() = Some(format!("{e:#}").into());
+                health.drop_update(&database_identity, "send_failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy::EnergyQuanta;
+    use crate::host::module_host::{DatabaseTableUpdate, DatabaseUpdate, ModuleFunctionCall};
+    use spacetimedb_lib::ConnectionId;
+    use spacetimedb_paths::FromPathUnchecked;
+    use spacetimedb_primitives::TableId;
+    use spacetimedb_sats::product;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    fn event(status: EventStatus) -> Arc<ModuleEvent> {
+        Arc::new(ModuleEvent {
+            timestamp: Timestamp::UNIX_EPOCH,
+            caller_identity: Identity::ONE,
+            caller_connection_id: Some(ConnectionId::ZERO),
+            function_call: ModuleFunctionCall {
+                reducer: "add".into(),
+                ..<_>::default()
+            },
+            status,
+            energy_quanta_used: EnergyQuanta::ZERO,
+            host_execution_duration: Duration::ZERO,
+            request_id: None,
+            timer: None,
+            acknowledged: false,
+            trace_id: None,
+        })
+    }
+
+    fn committed(inserts: Vec<ProductValue>) -> Arc<ModuleEvent> {
+        event(EventStatus::Committed(DatabaseUpdate {
+            tables: vec![DatabaseTableUpdate {
+                table_id: TableId(4096),
+                table_name: "person".into(),
+                inserts: inserts.into(),
+                deletes: [].into(),
+            }],
+        }))
+    }
+
+    /// A sink which records the updates sent to it,
+    /// and holds them up until released.
+    #[derive(Default)]
+    struct RecordingSink {
+        sent: Mutex<Vec<Vec<u8>>>,
+        release: Notify,
+        blocked: bool,
+    }
+
+    #[async_trait]
+    impl FirehoseSink for RecordingSink {
+        async fn send(&self, update: &[u8]) -> anyhow::Result<()> {
+            if self.blocked {
+                self.release.notified().await;
+            }
+            self.sent.lock().push(update.to_vec());
+            Ok(())
+        }
+    }
+
+    struct FailingSink;
+
+    #[async_trait]
+    impl FirehoseSink for FailingSink {
+        async fn send(&self, _update: &[u8]) -> anyhow::Result<()> {
+            anyhow::bail!("broker unavailable")
+        }
+    }
+
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn targets_are_parsed_and_confined_to_the_firehose_dir() {
+        let parse = |s: &str| s.parse::<FirehoseTarget>();
+        assert_eq!(
+            parse("file:events.jsonl").unwrap(),
+            FirehoseTarget::File("events.jsonl".into())
+        );
+        assert_eq!(
+            parse("unix:events.sock").unwrap(),
+            FirehoseTarget::Unix("events.sock".into())
+        );
+        assert_eq!(
+            parse("kafka://broker:9092/events").unwrap(),
+            FirehoseTarget::External("kafka://broker:9092/events".into())
+        );
+        assert_eq!(parse("file:events.jsonl").unwrap().to_string(), "file:events.jsonl");
+
+        for invalid in ["", "events", "file:", ":events", "9p:events"] {
+            assert!(
+                matches!(parse(invalid), Err(FirehoseError::InvalidTarget(_))),
+                "{invalid}"
+            );
+        }
+        for escaping in ["file:../events", "file:/etc/passwd", "unix:a/b", "file:..", "file:a\\b"] {
+            assert!(
+                matches!(parse(escaping), Err(FirehoseError::InvalidName(_))),
+                "{escaping}"
+            );
+        }
+
+        let dir = FirehoseDir::from_path_unchecked("/data/firehose");
+        assert!(parse("file:events").unwrap().open_in_tree(&dir).is_some());
+        assert!(parse("kafka://broker").unwrap().open_in_tree(&dir).is_none());
+    }
+
+    #[tokio::test]
+    async fn committed_updates_are_sent_as_json_lines() {
+        let firehose = Firehose::new(Identity::ZERO);
+        assert!(firehose.health().is_none());
+        // Nothing is queued without a sink.
+        firehose.tee(&committed(vec![product![1u32]]), Timestamp::UNIX_EPOCH);
+
+        let sink = Arc::new(RecordingSink::default());
+        firehose.set(Some(sink.clone()));
+        firehose.tee(&committed(vec![product![2u32, "alice"]]), Timestamp::UNIX_EPOCH);
+        firehose.tee(&event(EventStatus::OutOfEnergy), Timestamp::UNIX_EPOCH);
+        settle().await;
+
+        let sent = sink.sent.lock().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].ends_with(b"\n"));
+        let record: serde_json::Value = serde_json::from_slice(&sent[0]).unwrap();
+        assert_eq!(record["reducer"], "add");
+        assert_eq!(record["committed_at_micros"], 0);
+        assert_eq!(record["tables"][0]["table_name"], "person");
+        assert_eq!(record["tables"][0]["inserts"], serde_json::json!([[2, "alice"]]));
+        assert_eq!(record["tables"][0]["deletes"], serde_json::json!([]));
+
+        let health = firehose.health().unwrap();
+        assert_eq!((health.sent, health.dropped, health.queue_depth), (1, 0, 0));
+
+        // Detaching the sink takes effect right away.
+        firehose.set(None);
+        assert!(firehose.health().is_none());
+        firehose.tee(&committed(vec![product![3u32]]), Timestamp::UNIX_EPOCH);
+        settle().await;
+        assert_eq!(sink.sent.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_slow_sink_drops_updates_rather_than_blocking() {
+        let firehose = Firehose::new(Identity::ZERO);
+        let sink = Arc::new(RecordingSink {
+            blocked: true,
+            ..<_>::default()
+        });
+        firehose.set(Some(sink.clone()));
+
+        // One update is taken by the sending task, the rest fill the queue.
+        let total = FIREHOSE_QUEUE_CAPACITY as u64 + 11;
+        for i in 0..total {
+            firehose.tee(&committed(vec![product![i]]), Timestamp::UNIX_EPOCH);
+            settle().await;
+        }
+        let health = firehose.health().unwrap();
+        assert_eq!(health.queue_depth as usize, FIREHOSE_QUEUE_CAPACITY);
+        assert_eq!(health.dropped, 10);
+        assert_eq!(health.sent, 0);
+
+        // Once the sink catches up, the queued updates are sent.
+        sink.release.notify_one();
+        settle().await;
+        assert_eq!(firehose.health().unwrap().sent, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_sends_are_dropped_and_reported() {
+        let firehose = Firehose::new(Identity::ZERO);
+        firehose.set(Some(Arc::new(FailingSink)));
+        firehose.tee(&committed(vec![product![1u32]]), Timestamp::UNIX_EPOCH);
+        firehose.tee(&committed(vec![product![2u32]]), Timestamp::UNIX_EPOCH);
+        settle().await;
+
+        let health = firehose.health().unwrap();
+        assert_eq!((health.sent, health.dropped), (0, 2));
+        assert_eq!(health.last_error.as_deref(), Some("broker unavailable"));
+    }
+
+    #[tokio::test]
+    async fn file_sinks_append_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = FirehoseDir::from_path_unchecked(dir.path().join("firehose"));
+        let sink = FirehoseTarget::File("events".into()).open_in_tree(&dir).unwrap();
+        sink.send(b"{\"a\":1}\n").await.unwrap();
+        sink.send(b"{\"a\":2}\n").await.unwrap();
+        let written = std::fs::read_to_string(dir.0.join("events")).unwrap();
+        assert_eq!(written, "{\"a\":1}\n{\"a\":2}\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_sinks_reconnect() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = FirehoseDir::from_path_unchecked(dir.path());
+        let sink = FirehoseTarget::Unix("events.sock".into()).open_in_tree(&dir).unwrap();
+        // No one is listening yet.
+        assert!(sink.send(b"lost\n").await.is_err());
+
+        let listener = tokio::net::UnixListener::bind(dir.0.join("events.sock")).unwrap();
+        sink.send(b"{\"a\":1}\n").await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut line = [0; 8];
+        stream.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"{\"a\":1}\n");
+    }
+}
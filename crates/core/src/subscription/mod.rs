@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use module_subscription_manager::Plan;
+use projection::column_set;
 use prometheus::IntCounter;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use spacetimedb_client_api_messages::websocket::{
@@ -10,8 +11,10 @@ use spacetimedb_client_api_messages::websocket::{
 use spacetimedb_execution::{pipelined::PipelinedProject, Datastore, DeltaStore};
 use spacetimedb_lib::{metrics::ExecutionMetrics, Identity};
 use spacetimedb_primitives::TableId;
+use spacetimedb_subscription::ColumnProjection;
 
 use crate::{
+    client::QueryBudget,
     db::{datastore::locking_tx_datastore::datastore::MetricsRecorder, db_metrics::DB_METRICS},
     error::DBError,
     execution_context::WorkloadType,
@@ -19,13 +22,18 @@ use crate::{
 };
 
 pub mod delta;
+pub mod dump;
 pub mod execution_unit;
+pub mod fanout;
+pub mod firehose;
 pub mod module_subscription_actor;
 pub mod module_subscription_manager;
+pub mod projection;
 pub mod query;
 #[allow(clippy::module_inception)] // it's right this isn't ideal :/
 pub mod subscription;
 pub mod tx;
+pub mod window;
 
 #[derive(Debug)]
 pub struct ExecutionCounters {
@@ -95,6 +103,19 @@ impl MetricsRecorder for ExecutionCounters {
 
 /// Execute a subscription query
 pub fn execute_plan<Tx, F>(plan_fragments: &[PipelinedProject], tx: &Tx) -> Result<(F::List, u64, ExecutionMetrics)>
+where
+    Tx: Datastore + DeltaStore,
+    F: WebsocketFormat,
+{
+    execute_projected_plan::<Tx, F>(plan_fragments, None, tx)
+}
+
+/// Like [`execute_plan`], but encode only the columns selected by `projection`, if any.
+fn execute_projected_plan<Tx, F>(
+    plan_fragments: &[PipelinedProject],
+    projection: Option<&ColumnProjection>,
+    tx: &Tx,
+) -> Result<(F::List, u64, ExecutionMetrics)>
 where
     Tx: Datastore + DeltaStore,
     F: WebsocketFormat,
@@ -109,12 +130,60 @@ where
         })?;
     }
 
-    let (list, n) = F::encode_list(rows.into_iter());
+    let (list, n) = match projection {
+        None => F::encode_list(rows.into_iter()),
+        Some(projection) => F::encode_list(rows.iter().map(|row| projection.project(row.to_product_value()))),
+    };
     metrics.bytes_scanned += list.num_bytes();
     metrics.bytes_sent_to_clients += list.num_bytes();
     Ok((list, n, metrics))
 }
 
+/// Like [`execute_plan`], but count each row against `budget`, stopping with its error once the query is over budget,
+/// and encode the rows in lists of at most `chunk_rows` each, or in a single list if `None`.
+///
+/// At least one list is returned, which is empty if the query matched no rows.
+pub fn execute_plan_in_chunks<Tx, F>(
+    plan_fragments: &[PipelinedProject],
+    tx: &Tx,
+    chunk_rows: Option<usize>,
+    budget: &mut QueryBudget,
+) -> Result<(Vec<F::List>, ExecutionMetrics)>
+where
+    Tx: Datastore + DeltaStore,
+    F: WebsocketFormat,
+{
+    let mut rows = vec![];
+    let mut metrics = ExecutionMetrics::default();
+
+    for fragment in plan_fragments {
+        budget.check_deadline()?;
+        fragment.execute(tx, &mut metrics, &mut |row| {
+            budget.count_row()?;
+            rows.push(row);
+            Ok(())
+        })?;
+    }
+    budget.check_deadline()?;
+
+    let chunk_rows = chunk_rows.unwrap_or(rows.len()).max(1);
+    let mut rows = rows.into_iter();
+    let mut chunks = vec![];
+    loop {
+        let (list, n) = F::encode_list(rows.by_ref().take(chunk_rows));
+        if n == 0 && !chunks.is_empty() {
+            break;
+        }
+        metrics.bytes_scanned += list.num_bytes();
+        metrics.bytes_sent_to_clients += list.num_bytes();
+        chunks.push(list);
+        if (n as usize) < chunk_rows {
+            break;
+        }
+    }
+    Ok((chunks, metrics))
+}
+
 /// When collecting a table update are we inserting or deleting rows?
 /// For unsubscribe operations, we need to delete rows.
 #[derive(Debug, Clone, Copy)]
@@ -123,11 +192,13 @@ pub enum TableUpdateType {
     Unsubscribe,
 }
 
-/// Execute a subscription query and collect the results in a [TableUpdate]
+/// Execute a subscription query and collect the results in a [TableUpdate],
+/// with only the columns selected by `projection`, if any.
 pub fn collect_table_update<Tx, F>(
     plan_fragments: &[PipelinedProject],
     table_id: TableId,
     table_name: Box<str>,
+    projection: Option<&ColumnProjection>,
     tx: &Tx,
     update_type: TableUpdateType,
 ) -> Result<(TableUpdate<F>, ExecutionMetrics)>
@@ -135,16 +206,19 @@ where
     Tx: Datastore + DeltaStore,
     F: WebsocketFormat,
 {
-    execute_plan::<Tx, F>(plan_fragments, tx).map(|(rows, num_rows, metrics)| {
+    execute_projected_plan::<Tx, F>(plan_fragments, projection, tx).map(|(rows, num_rows, metrics)| {
         let empty = F::List::default();
+        let columns = column_set(projection);
         let qu = match update_type {
             TableUpdateType::Subscribe => QueryUpdate {
                 deletes: empty,
                 inserts: rows,
+                columns,
             },
             TableUpdateType::Unsubscribe => QueryUpdate {
                 deletes: rows,
                 inserts: empty,
+                columns,
             },
         };
         // We will compress the outer server message,
@@ -183,7 +257,16 @@ where
                 .clone()
                 .optimize()
                 .map(|plan| (sql, PipelinedProject::from(plan)))
-                .and_then(|(_, plan)| collect_table_update(&[plan], table_id, (&**table_name).into(), tx, update_type))
+                .and_then(|(_, optimized)| {
+                    collect_table_update(
+                        &[optimized],
+                        table_id,
+                        (&**table_name).into(),
+                        plan.projection(),
+                        tx,
+                        update_type,
+                    )
+                })
                 .map_err(|err| DBError::WithSql {
                     sql: sql.into(),
                     error: Box::new(DBError::Other(err)),
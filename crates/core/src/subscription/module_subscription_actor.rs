@@ -1,13 +1,17 @@
+use super::dump::ClientSubscriptions;
 use super::execution_unit::QueryHash;
+use super::firehose::Firehose;
 use super::module_subscription_manager::{
     spawn_send_worker, BroadcastError, BroadcastQueue, Plan, SubscriptionGaugeStats, SubscriptionManager,
 };
 use super::query::compile_query_with_hashes;
 use super::tx::DeltaTx;
+use super::window::{Window, WindowBounds};
 use super::{collect_table_update, TableUpdateType};
 use crate::client::messages::{
-    SerializableMessage, SubscriptionData, SubscriptionError, SubscriptionMessage, SubscriptionResult,
-    SubscriptionRows, SubscriptionUpdateMessage, TransactionUpdateMessage,
+    ExplainedSubscriptionData, SerializableMessage, SubscriptionData, SubscriptionError, SubscriptionMessage,
+    SubscriptionResult, SubscriptionRows, SubscriptionUpdateMessage, SubscriptionWindowRows, TransactionUpdateMessage,
+    UpdateDetail,
 };
 use crate::client::{ClientActorId, ClientConnectionSender, Protocol};
 use crate::db::datastore::locking_tx_datastore::tx::TxId;
@@ -16,8 +20,8 @@ use crate::db::relational_db::{MutTx, RelationalDB, Tx};
 use crate::error::DBError;
 use crate::estimation::estimate_rows_scanned;
 use crate::execution_context::{Workload, WorkloadType};
-use crate::host::module_host::{DatabaseUpdate, EventStatus, ModuleEvent};
-use crate::messages::websocket::Subscribe;
+use crate::host::module_host::{DatabaseUpdate, EventStatus, ModuleEvent, ReducerPhaseTimer};
+use crate::messages::websocket::{QueryId, Subscribe};
 use crate::subscription::execute_plans;
 use crate::subscription::query::is_subscribe_to_all_tables;
 use crate::util::prometheus_handle::IntGaugeExt;
@@ -26,13 +30,13 @@ use crate::worker_metrics::WORKER_METRICS;
 use parking_lot::RwLock;
 use prometheus::{Histogram, HistogramTimer, IntCounter, IntGauge};
 use spacetimedb_client_api_messages::websocket::{
-    self as ws, BsatnFormat, FormatSwitch, JsonFormat, SubscribeMulti, SubscribeSingle, TableUpdate, Unsubscribe,
-    UnsubscribeMulti,
+    self as ws, BsatnFormat, FormatSwitch, InitialWindow, JsonFormat, OlderRows, SubscribeMulti, SubscribeSingle,
+    SubscribeWindow, TableUpdate, Unsubscribe, UnsubscribeMulti,
 };
 use spacetimedb_execution::pipelined::PipelinedProject;
 use spacetimedb_lib::identity::AuthCtx;
 use spacetimedb_lib::metrics::ExecutionMetrics;
-use spacetimedb_lib::Identity;
+use spacetimedb_lib::{ConnectionId, Identity, Timestamp};
 use std::{sync::Arc, time::Instant};
 
 type Subscriptions = Arc<RwLock<SubscriptionManager>>;
@@ -46,6 +50,8 @@ pub struct ModuleSubscriptions {
     broadcast_queue: BroadcastQueue,
     owner_identity: Identity,
     stats: Arc<SubscriptionGauges>,
+    /// Sent a copy of every committed update, if a sink is attached.
+    firehose: Firehose,
 }
 
 #[derive(Debug, Clone)]
@@ -164,6 +170,7 @@ impl ModuleSubscriptions {
         subscriptions: Subscriptions,
         broadcast_queue: BroadcastQueue,
         owner_identity: Identity,
+        firehose: Firehose,
     ) -> Self {
         let db = &relational_db.database_identity();
         let stats = Arc::new(SubscriptionGauges::new(db));
@@ -174,6 +181,7 @@ impl ModuleSubscriptions {
             broadcast_queue,
             owner_identity,
             stats,
+            firehose,
         }
     }
 
@@ -188,13 +196,16 @@ impl ModuleSubscriptions {
     /// Construct a new [`ModuleSubscriptions`] for use in testing,
     /// running its send worker on the dynamically enclosing [`tokio::runtime::Runtime`]
     pub fn for_test_enclosing_runtime(db: Arc<RelationalDB>) -> ModuleSubscriptions {
-        let send_worker_queue = spawn_send_worker(None);
-        ModuleSubscriptions::new(
-            db,
-            SubscriptionManager::for_test_without_metrics_arc_rwlock(),
-            send_worker_queue,
-            Identity::ZERO,
-        )
+        // Updates and other messages share a send worker, as they do in a host, so they're sent in order.
+        let send_worker_queue = spawn_send_worker(None, <_>::default());
+        let subscriptions = Arc::new(RwLock::new(SubscriptionManager::new(send_worker_queue.clone())));
+        let firehose = Firehose::new(db.database_identity());
+        ModuleSubscriptions::new(db, subscriptions, send_worker_queue, Identity::ZERO, firehose)
+    }
+
+    /// The firehose of the database, see [`Firehose`].
+    pub fn firehose(&self) -> &Firehose {
+        &self.firehose
     }
 
     // Recompute gauges to update metrics.
@@ -245,11 +256,75 @@ impl ModuleSubscriptions {
 
         let tx = DeltaTx::from(tx);
 
-        Ok(match sender.config.protocol {
-            Protocol::Binary => collect_table_update(&plans, table_id, table_name.into(), &tx, update_type)
-                .map(|(table_update, metrics)| (FormatSwitch::Bsatn(table_update), metrics)),
-            Protocol::Text => collect_table_update(&plans, table_id, table_name.into(), &tx, update_type)
-                .map(|(table_update, metrics)| (FormatSwitch::Json(table_update), metrics)),
+        Ok(match sender.config().protocol {
+            Protocol::Binary => collect_table_update(
+                &plans,
+                table_id,
+                table_name.into(),
+                query.projection(),
+                &tx,
+                update_type,
+            )
+            .map(|(table_update, metrics)| (FormatSwitch::Bsatn(table_update), metrics)),
+            Protocol::Text => collect_table_update(
+                &plans,
+                table_id,
+                table_name.into(),
+                query.projection(),
+                &tx,
+                update_type,
+            )
+            .map(|(table_update, metrics)| (FormatSwitch::Json(table_update), metrics)),
+        }?)
+    }
+
+    /// Like [`Self::evaluate_initial_subscription`],
+    /// but only compute the query results in `window`.
+    fn evaluate_initial_window(
+        &self,
+        sender: Arc<ClientConnectionSender>,
+        query: Arc<Plan>,
+        tx: &TxId,
+        auth: &AuthCtx,
+        window: &InitialWindow,
+        older_rows: OlderRows,
+    ) -> Result<(SubscriptionUpdate, WindowBounds, ExecutionMetrics), DBError> {
+        let table_id = query.subscribed_table_id();
+        let table_name = query.subscribed_table_name();
+        let schema = self.relational_db.schema_for_table(tx, table_id)?;
+        let window = Window::resolve(&schema, window).map_err(|err| DBError::Other(err.into()))?;
+
+        check_row_limit(
+            &[&query],
+            &self.relational_db,
+            tx,
+            |plan, tx| {
+                plan.plans_fragments()
+                    .map(|plan_fragment| estimate_rows_scanned(tx, plan_fragment.optimized_physical_plan()))
+                    .fold(0, |acc, rows_scanned| acc.saturating_add(rows_scanned))
+            },
+            auth,
+        )?;
+
+        let plans = query
+            .plans_fragments()
+            .map(|fragment| fragment.optimized_physical_plan())
+            .cloned()
+            .map(|plan| plan.optimize())
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(PipelinedProject::from)
+            .collect::<Vec<_>>();
+
+        let tx = DeltaTx::from(tx);
+
+        Ok(match sender.config().protocol {
+            Protocol::Binary => window
+                .collect_table_update(&plans, table_id, table_name.into(), query.projection(), &tx, older_rows)
+                .map(|(table_update, bounds, metrics)| (FormatSwitch::Bsatn(table_update), bounds, metrics)),
+            Protocol::Text => window
+                .collect_table_update(&plans, table_id, table_name.into(), query.projection(), &tx, older_rows)
+                .map(|(table_update, bounds, metrics)| (FormatSwitch::Json(table_update), bounds, metrics)),
         }?)
     }
 
@@ -274,7 +349,7 @@ impl ModuleSubscriptions {
         )?;
 
         let tx = DeltaTx::from(tx);
-        match sender.config.protocol {
+        match sender.config().protocol {
             Protocol::Binary => {
                 let (update, metrics) = execute_plans(queries, &tx, update_type)?;
                 Ok((FormatSwitch::Bsatn(update), metrics))
@@ -379,6 +454,115 @@ impl ModuleSubscriptions {
         Ok(Some(metrics))
     }
 
+    /// Add a subscription to a single query,
+    /// initially sending only the rows in the window `request.initial`.
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn add_window_subscription(
+        &self,
+        sender: Arc<ClientConnectionSender>,
+        request: SubscribeWindow,
+        timer: Instant,
+        _assert: Option<AssertTxFn>,
+    ) -> Result<Option<ExecutionMetrics>, DBError> {
+        // Send an error message to the client
+        let send_err_msg = |message| {
+            self.broadcast_queue.send_client_message(
+                sender.clone(),
+                SubscriptionMessage {
+                    request_id: Some(request.request_id),
+                    query_id: Some(request.query_id),
+                    timer: Some(timer),
+                    result: SubscriptionResult::Error(SubscriptionError {
+                        table_id: None,
+                        message,
+                    }),
+                },
+            )
+        };
+
+        let sql = request.query;
+        let auth = AuthCtx::new(self.owner_identity, sender.id.identity);
+        let hash = QueryHash::from_string(&sql, auth.caller, false);
+        let hash_with_param = QueryHash::from_string(&sql, auth.caller, true);
+
+        let tx = scopeguard::guard(self.relational_db.begin_tx(Workload::Subscribe), |tx| {
+            let (tx_metrics, reducer) = self.relational_db.release_tx(tx);
+            self.relational_db.report_read_tx_metrics(reducer, tx_metrics);
+        });
+
+        let existing_query = {
+            let guard = self.subscriptions.read();
+            guard.query(&hash)
+        };
+
+        let query = return_on_err_with_sql!(
+            existing_query.map(Ok).unwrap_or_else(|| compile_query_with_hashes(
+                &auth,
+                &tx,
+                &sql,
+                hash,
+                hash_with_param
+            )
+            .map(Arc::new)),
+            sql,
+            send_err_msg
+        );
+
+        let (table_rows, bounds, metrics) = return_on_err_with_sql!(
+            self.evaluate_initial_window(
+                sender.clone(),
+                query.clone(),
+                &tx,
+                &auth,
+                &request.initial,
+                request.older_rows
+            ),
+            query.sql(),
+            send_err_msg
+        );
+
+        // As in `add_single_subscription`, the subscription lock is acquired after `eval`,
+        // and the response is queued while still holding the read lock on the database.
+        let mut subscriptions = self.subscriptions.write();
+        subscriptions.add_windowed_subscription(
+            sender.clone(),
+            query.clone(),
+            request.query_id,
+            bounds.filter.clone(),
+        )?;
+
+        #[cfg(test)]
+        if let Some(assert) = _assert {
+            assert(&tx);
+        }
+
+        let window = ws::AppliedWindow {
+            initial: request.initial,
+            has_older_rows: bounds.has_older_rows,
+            older_rows: match bounds.filter {
+                Some(_) => OlderRows::Suppress,
+                None => OlderRows::Deliver,
+            },
+        };
+        let _ = self.broadcast_queue.send_client_message(
+            sender.clone(),
+            SubscriptionMessage {
+                request_id: Some(request.request_id),
+                query_id: Some(request.query_id),
+                timer: Some(timer),
+                result: SubscriptionResult::SubscribeWindow(SubscriptionWindowRows {
+                    rows: SubscriptionRows {
+                        table_id: query.subscribed_table_id(),
+                        table_name: query.subscribed_table_name().into(),
+                        table_rows,
+                    },
+                    window,
+                }),
+            },
+        );
+        Ok(Some(metrics))
+    }
+
     /// Remove a subscription for a single query.
     pub fn remove_single_subscription(
         &self,
@@ -636,13 +820,36 @@ impl ModuleSubscriptions {
         self.broadcast_queue.send_client_message(recipient, message)
     }
 
-    #[tracing::instrument(level = "trace", skip_all)]
     pub fn add_multi_subscription(
         &self,
         sender: Arc<ClientConnectionSender>,
         request: SubscribeMulti,
         timer: Instant,
         _assert: Option<AssertTxFn>,
+    ) -> Result<Option<ExecutionMetrics>, DBError> {
+        self.subscribe_multi(sender, request, None, timer, _assert)
+    }
+
+    /// Like [`Self::add_multi_subscription`],
+    /// but sends the client the `plans` chosen for its queries along with the initial rows.
+    pub fn add_explained_multi_subscription(
+        &self,
+        sender: Arc<ClientConnectionSender>,
+        request: SubscribeMulti,
+        plans: Box<[Box<str>]>,
+        timer: Instant,
+    ) -> Result<Option<ExecutionMetrics>, DBError> {
+        self.subscribe_multi(sender, request, Some(plans), timer, None)
+    }
+
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn subscribe_multi(
+        &self,
+        sender: Arc<ClientConnectionSender>,
+        request: SubscribeMulti,
+        plans: Option<Box<[Box<str>]>>,
+        timer: Instant,
+        _assert: Option<AssertTxFn>,
     ) -> Result<Option<ExecutionMetrics>, DBError> {
         // Send an error message to the client
         let send_err_msg = |message| {
@@ -736,13 +943,18 @@ impl ModuleSubscriptions {
 
         // Holding a write lock on `self.subscriptions` would also be sufficient.
 
+        let data = SubscriptionData { data: update };
+        let result = match plans {
+            Some(plans) => SubscriptionResult::SubscribeMultiExplained(ExplainedSubscriptionData { data, plans }),
+            None => SubscriptionResult::SubscribeMulti(data),
+        };
         let _ = self.broadcast_queue.send_client_message(
             sender.clone(),
             SubscriptionMessage {
                 request_id: Some(request.request_id),
                 query_id: Some(request.query_id),
                 timer: Some(timer),
-                result: SubscriptionResult::SubscribeMulti(SubscriptionData { data: update }),
+                result,
             },
         );
 
@@ -793,7 +1005,7 @@ impl ModuleSubscriptions {
         drop(compile_timer);
 
         let tx = DeltaTx::from(&*tx);
-        let (database_update, metrics) = match sender.config.protocol {
+        let (database_update, metrics) = match sender.config().protocol {
             Protocol::Binary => execute_plans(&queries, &tx, TableUpdateType::Subscribe)
                 .map(|(table_update, metrics)| (FormatSwitch::Bsatn(table_update), metrics))?,
             Protocol::Text => execute_plans(&queries, &tx, TableUpdateType::Subscribe)
@@ -840,6 +1052,111 @@ impl ModuleSubscriptions {
         Ok(metrics)
     }
 
+    /// Dump the subscriptions of the client connected as `connection_id`, if any,
+    /// redacting the values of query parameters if the database sets `redact_subscription_parameters`.
+    pub fn dump_client_subscriptions(
+        &self,
+        connection_id: ConnectionId,
+    ) -> Result<Option<ClientSubscriptions>, DBError> {
+        let db = &self.relational_db;
+        let redact = db.with_read_only(Workload::Internal, |tx| db.redact_subscription_parameters(tx))?;
+        Ok(self.subscriptions.read().client_subscriptions(connection_id, redact))
+    }
+
+    /// Register each subscription of `dump` for `sender`, as if it had requested them itself.
+    ///
+    /// Queries which fail to compile or evaluate are reported to `sender`, as usual,
+    /// and not registered.
+    pub fn restore_client_subscriptions(
+        &self,
+        sender: Arc<ClientConnectionSender>,
+        dump: &ClientSubscriptions,
+    ) -> Result<(), DBError> {
+        if dump.redacted {
+            return Err(anyhow::anyhow!("cannot register the queries of a redacted subscription dump").into());
+        }
+        for (request_id, subscription) in (0..).zip(&dump.subscriptions) {
+            let query_strings = subscription
+                .queries
+                .iter()
+                .map(|query| query.sql.as_str().into())
+                .collect();
+            match subscription.query_id {
+                None => {
+                    let request = Subscribe {
+                        query_strings,
+                        request_id,
+                    };
+                    self.add_legacy_subscriber(sender.clone(), request, Instant::now(), None)?;
+                }
+                Some(query_id) => {
+                    let request = SubscribeMulti {
+                        query_strings,
+                        request_id,
+                        query_id: QueryId::new(query_id),
+                    };
+                    self.add_multi_subscription(sender.clone(), request, Instant::now(), None)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the subscriptions invalidated by an update of the module,
+    /// i.e. those with a query which no longer compiles against the database's schema,
+    /// or which reads a table or index the update removed.
+    ///
+    /// Each client is sent an error for each of its subscriptions dropped.
+    /// Returns the number of subscriptions dropped.
+    pub fn remove_invalid_subscriptions(&self) -> usize {
+        let db = &self.relational_db;
+        // Always lock the db before the subscription lock to avoid deadlocks.
+        let tx = scopeguard::guard(db.begin_tx(Workload::Internal), |tx| {
+            let (tx_metrics, reducer) = db.release_tx(tx);
+            db.report_read_tx_metrics(reducer, tx_metrics);
+        });
+
+        let check = |query: &Plan, identity| -> Result<(), String> {
+            let auth = AuthCtx::new(self.owner_identity, identity);
+            compile_query_with_hashes(&auth, &tx, query.sql(), query.hash(), query.hash())
+                .map_err(|err| format!("`{}` no longer compiles: {err}", query.sql()))?;
+            for (table_id, index_id) in query.index_ids() {
+                let schema = db
+                    .schema_for_table(&tx, table_id)
+                    .map_err(|_| format!("`{}` reads a table which no longer exists", query.sql()))?;
+                if !schema.indexes.iter().any(|index| index.index_id == index_id) {
+                    return Err(format!(
+                        "`{}` reads an index of `{}` which no longer exists",
+                        query.sql(),
+                        schema.table_name
+                    ));
+                }
+            }
+            Ok(())
+        };
+        let invalid = self.subscriptions.write().remove_invalid_subscriptions(check);
+
+        for sub in &invalid {
+            let _ = self.broadcast_queue.send_client_message(
+                sub.client.clone(),
+                SubscriptionMessage {
+                    request_id: None,
+                    query_id: sub.query_id,
+                    timer: None,
+                    result: SubscriptionResult::Error(SubscriptionError {
+                        table_id: None,
+                        message: format!("subscription dropped by a module update: {}", sub.error).into(),
+                    }),
+                },
+            );
+        }
+        WORKER_METRICS
+            .publish_invalidated_subscriptions
+            .with_label_values(&db.database_identity())
+            .inc_by(invalid.len() as u64);
+        invalid.len()
+    }
+
     pub fn remove_subscriber(&self, client_id: ClientActorId) {
         let mut subscriptions = self.subscriptions.write();
         subscriptions.remove_all_subscriptions(&(client_id.identity, client_id.connection_id));
@@ -850,10 +1167,23 @@ impl ModuleSubscriptions {
     /// The returned [`ExecutionMetrics`] are reported in this method via `report_tx_metrics`.
     /// They are returned for testing purposes but should not be reported separately.
     pub fn commit_and_broadcast_event(
+        &self,
+        caller: Option<Arc<ClientConnectionSender>>,
+        event: ModuleEvent,
+        tx: MutTx,
+    ) -> Result<Result<(Arc<ModuleEvent>, ExecutionMetrics), WriteConflict>, DBError> {
+        self.commit_and_broadcast_event_timed(caller, event, tx, None)
+    }
+
+    /// Like [`Self::commit_and_broadcast_event`],
+    /// but if `phase_timer` is set, also time committing and evaluating subscriptions,
+    /// and send the caller the resulting [`ReducerTimings`](crate::messages::websocket::ReducerTimings) after its reply.
+    pub fn commit_and_broadcast_event_timed(
         &self,
         caller: Option<Arc<ClientConnectionSender>>,
         mut event: ModuleEvent,
         tx: MutTx,
+        mut phase_timer: Option<ReducerPhaseTimer>,
     ) -> Result<Result<(Arc<ModuleEvent>, ExecutionMetrics), WriteConflict>, DBError> {
         let database_identity = self.relational_db.database_identity();
         let subscription_metrics = SubscriptionMetrics::new(&database_identity, &WorkloadType::Update);
@@ -868,6 +1198,7 @@ impl ModuleSubscriptions {
         };
 
         let stdb = &self.relational_db;
+        let commit_span = tracing::trace_span!("commit").entered();
         // Downgrade mutable tx.
         // We'll later ensure tx is released/cleaned up once out of scope.
         let (read_tx, tx_data, tx_metrics_mut) = match &mut event.status {
@@ -883,8 +1214,13 @@ impl ModuleSubscriptions {
                 (tx, None, tx_metrics)
             }
         };
+        drop(commit_span);
 
+        let committed_at = Timestamp::now();
         let tx_data = tx_data.map(Arc::new);
+        if let Some(phase_timer) = &mut phase_timer {
+            phase_timer.committed();
+        }
 
         // When we're done with this method, release the tx and report metrics.
         let mut read_tx = scopeguard::guard(read_tx, |tx| {
@@ -901,24 +1237,35 @@ impl ModuleSubscriptions {
 
         let event = Arc::new(event);
         let mut update_metrics: ExecutionMetrics = ExecutionMetrics::default();
+        let timed_caller = phase_timer.and_then(|timer| Some((timer, caller.clone()?)));
 
         match &event.status {
             EventStatus::Committed(_) => {
-                update_metrics = subscriptions.eval_updates_sequential(&delta_read_tx, event.clone(), caller);
+                update_metrics =
+                    subscriptions.eval_updates_sequential(&delta_read_tx, event.clone(), caller, committed_at);
+                // Still holding the lock on `subscriptions`,
+                // so that the firehose receives updates in the order they were broadcast.
+                self.firehose.tee(&event, committed_at);
             }
-            EventStatus::Failed(_) => {
+            EventStatus::Failed(_) | EventStatus::OutOfEnergy => {
                 if let Some(client) = caller {
-                    let message = TransactionUpdateMessage {
-                        event: Some(event.clone()),
-                        database_update: SubscriptionUpdateMessage::default_for_protocol(client.config.protocol, None),
-                    };
-
+                    let message = TransactionUpdateMessage::reply(
+                        event.clone(),
+                        committed_at,
+                        client.config().protocol,
+                        UpdateDetail::for_reply(&client.config()),
+                        None,
+                    );
                     let _ = self.broadcast_queue.send_client_message(client, message);
                 } else {
                     log::trace!("Reducer failed but there is no client to send the failure to!")
                 }
             }
-            EventStatus::OutOfEnergy => {} // ?
+        }
+
+        if let Some((phase_timer, caller)) = timed_caller {
+            let timings = phase_timer.finish(event.request_id.unwrap_or_default());
+            let _ = self.broadcast_queue.send_client_message(caller, timings);
         }
 
         // Merge in the subscription evaluation metrics.
@@ -932,31 +1279,42 @@ pub struct WriteConflict;
 
 #[cfg(test)]
 mod tests {
-    use super::{AssertTxFn, ModuleSubscriptions};
+    use super::{AssertTxFn, Firehose, ModuleSubscriptions};
     use crate::client::messages::{
-        SerializableMessage, SubscriptionData, SubscriptionError, SubscriptionMessage, SubscriptionResult,
-        SubscriptionUpdateMessage, TransactionUpdateMessage,
+        SerializableMessage, SharedEncoding, SubscriptionData, SubscriptionError, SubscriptionMessage,
+        SubscriptionResult, SubscriptionRows, SubscriptionUpdateMessage, SubscriptionWindowRows, ToProtocol,
+        TransactionUpdateMessage,
     };
-    use crate::client::{ClientActorId, ClientConfig, ClientConnectionSender, ClientName, MeteredReceiver, Protocol};
-    use crate::db::datastore::system_tables::{StRowLevelSecurityRow, ST_ROW_LEVEL_SECURITY_ID};
+    use crate::client::{
+        ClientActorId, ClientConfig, ClientConnectionSender, ClientName, DataMessage, EncryptedCodec, MeteredReceiver,
+        Protocol, SendQueueCapacity,
+    };
+    use crate::db::datastore::system_tables::{StRowLevelSecurityRow, StVarName, ST_ROW_LEVEL_SECURITY_ID};
     use crate::db::relational_db::tests_utils::{
         begin_mut_tx, begin_tx, insert, with_auto_commit, with_read_only, TestDB,
     };
     use crate::db::relational_db::RelationalDB;
     use crate::error::DBError;
-    use crate::host::module_host::{DatabaseUpdate, EventStatus, ModuleEvent, ModuleFunctionCall};
+    use crate::host::module_host::{DatabaseUpdate, EventStatus, ModuleEvent, ModuleFunctionCall, ReducerPhaseTimer};
+    use crate::host::trace_context::test_exporter::Exporter;
+    use crate::host::trace_context::{ReducerTrace, TraceParent};
+    use crate::messages::control_db::{SendBacklogAction, SendBacklogPolicy};
     use crate::messages::websocket as ws;
     use crate::sql::execute::run;
+    use crate::subscription::dump::ClientSubscriptions;
+    use crate::subscription::fanout::{FanoutBudget, FanoutOptions};
     use crate::subscription::module_subscription_manager::{spawn_send_worker, SubscriptionManager};
     use crate::subscription::query::compile_read_only_query;
     use crate::subscription::TableUpdateType;
     use hashbrown::HashMap;
     use itertools::Itertools;
+    use parking_lot::RwLock;
     use pretty_assertions::assert_matches;
+    use spacetimedb_client_api_messages::e2e;
     use spacetimedb_client_api_messages::energy::EnergyQuanta;
     use spacetimedb_client_api_messages::websocket::{
-        CompressableQueryUpdate, Compression, FormatSwitch, QueryId, Subscribe, SubscribeMulti, SubscribeSingle,
-        TableUpdate, Unsubscribe, UnsubscribeMulti,
+        CompressableQueryUpdate, Compression, FormatSwitch, OlderRows, QueryId, ResyncRequired, Subscribe,
+        SubscribeMulti, SubscribeSingle, SubscribeWindow, TableUpdate, Unsubscribe, UnsubscribeMulti, UpdateSettings,
     };
     use spacetimedb_execution::dml::MutDatastore;
     use spacetimedb_lib::bsatn::ToBsatn;
@@ -979,12 +1337,13 @@ mod tests {
         let client = ClientActorId::for_test(Identity::ZERO);
         let config = ClientConfig::for_test();
         let sender = Arc::new(ClientConnectionSender::dummy(client, config));
-        let send_worker_queue = spawn_send_worker(None);
+        let send_worker_queue = spawn_send_worker(None, <_>::default());
         let module_subscriptions = ModuleSubscriptions::new(
             db.clone(),
             SubscriptionManager::for_test_without_metrics_arc_rwlock(),
             send_worker_queue,
             owner,
+            Firehose::new(db.database_identity()),
         );
 
         let subscribe = Subscribe {
@@ -1050,6 +1409,8 @@ mod tests {
             host_execution_duration: Duration::from_millis(0),
             request_id: None,
             timer: None,
+            acknowledged: false,
+            trace_id: None,
         }
     }
 
@@ -1084,6 +1445,10 @@ mod tests {
                 protocol: Protocol::Binary,
                 compression,
                 tx_update_full: true,
+                reducer_timings: false,
+                acknowledged_delivery: false,
+                request_errors: false,
+                ..ClientConfig::for_test()
             },
         );
         (Arc::new(sender), rx)
@@ -1357,6 +1722,33 @@ mod tests {
         Ok(())
     }
 
+    /// Test that explained subscriptions deliver their plans with their initial rows
+    #[tokio::test]
+    async fn explained_subscribe_multi_delivers_plans_with_rows() -> anyhow::Result<()> {
+        let client_id = client_id_from_u8(1);
+        let (tx, mut rx) = client_connection(client_id);
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+
+        db.create_table_for_test("t", &[("x", AlgebraicType::U8)], &[])?;
+
+        let plans: Box<[Box<str>]> = ["scan t".into()].into();
+        let request = multi_subscribe(&["select * from t"], 1);
+        subs.add_explained_multi_subscription(tx, request, plans.clone(), Instant::now())?;
+
+        let Some(SerializableMessage::Subscription(SubscriptionMessage {
+            result: SubscriptionResult::SubscribeMultiExplained(explained),
+            ..
+        })) = rx.recv().await
+        else {
+            panic!("expected a `SubscribeMultiExplained`");
+        };
+        assert_eq!(explained.plans, plans);
+
+        Ok(())
+    }
+
     /// Test that clients receive error messages on unsubscribe
     #[tokio::test]
     async fn unsubscribe_single_error() -> anyhow::Result<()> {
@@ -1875,6 +2267,48 @@ mod tests {
         Ok(())
     }
 
+    /// Test that a query selecting some columns is sent only those,
+    /// including when its rows are deleted.
+    #[tokio::test]
+    async fn test_updates_for_column_projection() -> anyhow::Result<()> {
+        let (tx, mut rx) = client_connection(client_id_from_u8(1));
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let schema = [
+            ("id", AlgebraicType::U8),
+            ("name", AlgebraicType::String),
+            ("blob", AlgebraicType::bytes()),
+        ];
+        let t_id = db.create_table_for_test("t", &schema, &[])?;
+
+        subscribe_multi(&subs, &["select id, name from t"], tx, &mut 0)?;
+        assert_matches!(rx.recv().await, Some(SerializableMessage::Subscription(_)));
+
+        let schema = ProductType::from([AlgebraicType::U8, AlgebraicType::String, AlgebraicType::bytes()]);
+        let row = |name: &str, blob: &[u8]| product![1_u8, name, blob];
+
+        // The blob is left out of the row sent
+        commit_tx(&db, &subs, [], [(t_id, row("alice", &[7; 2048]))])?;
+        assert_tx_update_for_table(&mut rx, t_id, &schema, [row("alice", &[])], []).await;
+
+        // Updating only the blob sends nothing,
+        // so the next update is that of the name
+        commit_tx(
+            &db,
+            &subs,
+            [(t_id, row("alice", &[7; 2048]))],
+            [(t_id, row("alice", &[8]))],
+        )?;
+        commit_tx(&db, &subs, [(t_id, row("alice", &[8]))], [(t_id, row("bob", &[8]))])?;
+        assert_tx_update_for_table(&mut rx, t_id, &schema, [row("bob", &[])], [row("alice", &[])]).await;
+
+        // The row deleted is the one the client was sent
+        commit_tx(&db, &subs, [(t_id, row("bob", &[8]))], [])?;
+        assert_tx_update_for_table(&mut rx, t_id, &schema, [], [row("bob", &[])]).await;
+        Ok(())
+    }
+
     /// Test that we do not compress within a [TransactionUpdateMessage].
     /// The message itself is compressed before being sent over the wire,
     /// but we don't care about that for this test.
@@ -1925,25 +2359,152 @@ mod tests {
         Ok(())
     }
 
-    /// In this test we subscribe to a join query, update the lhs table,
-    /// and assert that the server sends the correct delta to the client.
+    /// The encoding the next update received from `rx` shares with other clients, if any.
+    async fn shared_encoding(rx: &mut MeteredReceiver<SerializableMessage>) -> Option<SharedEncoding> {
+        match rx.recv().await {
+            Some(SerializableMessage::TxUpdate(update)) => update.shared,
+            msg => panic!("expected a transaction update, got {msg:?}"),
+        }
+    }
+
+    /// Test that clients subscribed to the same queries, with the same config, share the encoding of their updates,
+    /// while a client with a config of its own encodes its updates itself.
     #[tokio::test]
-    async fn test_update_for_join() -> anyhow::Result<()> {
-        async fn test_subscription_updates(queries: &[&'static str]) -> anyhow::Result<()> {
-            // Establish a client connection
-            let (sender, mut rx) = client_connection(client_id_from_u8(1));
+    async fn test_updates_share_encodings_by_config() -> anyhow::Result<()> {
+        let (tx_a, mut rx_a) = client_connection(client_id_from_u8(1));
+        let (tx_b, mut rx_b) = client_connection(client_id_from_u8(2));
+        let (tx_c, mut rx_c) = client_connection_with_compression(client_id_from_u8(3), Compression::Brotli);
 
-            let db = relational_db()?;
-            let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let table_id = db.create_table_for_test("t", &[("x", AlgebraicType::U64)], &[])?;
 
-            let p_schema = [("id", AlgebraicType::U64), ("signed_in", AlgebraicType::Bool)];
-            let l_schema = [
-                ("id", AlgebraicType::U64),
-                ("x", AlgebraicType::U64),
-                ("z", AlgebraicType::U64),
-            ];
+        let mut query_ids = 0;
+        for (tx, rx) in [(tx_a, &mut rx_a), (tx_b, &mut rx_b), (tx_c, &mut rx_c)] {
+            subscribe_multi(&subs, &["select * from t"], tx, &mut query_ids)?;
+            assert!(matches!(rx.recv().await, Some(SerializableMessage::Subscription(_))));
+        }
+        commit_tx(&db, &subs, [], [(table_id, product![1_u64])])?;
 
-            let p_id = db.create_table_for_test("p", &p_schema, &[0.into()])?;
+        let a = shared_encoding(&mut rx_a).await.expect("a should share its encoding");
+        let b = shared_encoding(&mut rx_b).await.expect("b should share its encoding");
+        assert!(shared_encoding(&mut rx_c).await.is_none());
+
+        // Whichever of them encodes its update first does so for both.
+        assert!(b.get().is_none());
+        a.set(&DataMessage::Binary(b"update".to_vec().into()));
+        assert!(matches!(b.get(), Some(DataMessage::Binary(bytes)) if bytes[..] == b"update"[..]));
+        Ok(())
+    }
+
+    /// Test that a client whose messages are encrypted end-to-end never shares the encoding of its updates,
+    /// with plaintext clients nor other encrypted ones, even with the same config and queries.
+    #[tokio::test]
+    async fn test_encrypted_clients_dont_share_encodings() -> anyhow::Result<()> {
+        fn encrypted_client_connection(
+            client_id: ClientActorId,
+        ) -> (Arc<ClientConnectionSender>, MeteredReceiver<SerializableMessage>) {
+            let (plaintext, rx) = client_connection(client_id);
+            let session = e2e::KeyPair::generate()
+                .agree(e2e::Role::Server, &e2e::KeyPair::generate().public_key())
+                .unwrap();
+            let codec = Arc::new(EncryptedCodec::new(plaintext.codec(), session));
+            let sender = Arc::into_inner(plaintext).unwrap().with_codec(codec);
+            (Arc::new(sender), rx)
+        }
+
+        let (tx_a, mut rx_a) = client_connection(client_id_from_u8(1));
+        let (tx_b, mut rx_b) = client_connection(client_id_from_u8(2));
+        let (tx_c, mut rx_c) = encrypted_client_connection(client_id_from_u8(3));
+        let (tx_d, mut rx_d) = encrypted_client_connection(client_id_from_u8(4));
+        assert_eq!(tx_a.config(), tx_c.config());
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let table_id = db.create_table_for_test("t", &[("x", AlgebraicType::U64)], &[])?;
+
+        let mut query_ids = 0;
+        for (tx, rx) in [
+            (tx_a, &mut rx_a),
+            (tx_b, &mut rx_b),
+            (tx_c, &mut rx_c),
+            (tx_d, &mut rx_d),
+        ] {
+            subscribe_multi(&subs, &["select * from t"], tx, &mut query_ids)?;
+            assert!(matches!(rx.recv().await, Some(SerializableMessage::Subscription(_))));
+        }
+        commit_tx(&db, &subs, [], [(table_id, product![1_u64])])?;
+
+        // The plaintext clients still share with each other.
+        let a = shared_encoding(&mut rx_a).await.expect("a should share its encoding");
+        let b = shared_encoding(&mut rx_b).await.expect("b should share its encoding");
+        a.set(&DataMessage::Binary(b"update".to_vec().into()));
+        assert!(b.get().is_some());
+        assert!(shared_encoding(&mut rx_c).await.is_none());
+        assert!(shared_encoding(&mut rx_d).await.is_none());
+        Ok(())
+    }
+
+    /// Test that a client which switches to light updates mid-session is sent light updates from then on,
+    /// while the full update already queued for it is left as it is.
+    #[tokio::test]
+    async fn test_settings_changes_apply_to_updates_queued_after_them() -> anyhow::Result<()> {
+        let capacity = SendQueueCapacity {
+            messages: 4,
+            ..<_>::default()
+        };
+        let (sender, mut rx) =
+            ClientConnectionSender::dummy_with_capacity(client_id_from_u8(1), ClientConfig::for_test(), capacity);
+        let sender = Arc::new(sender);
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let table_id = db.create_table_for_test("t", &[("x", AlgebraicType::U64)], &[])?;
+
+        subscribe_multi(&subs, &["select * from t"], sender.clone(), &mut 0)?;
+        assert!(matches!(rx.recv().await, Some(SerializableMessage::Subscription(_))));
+
+        commit_tx(&db, &subs, [], [(table_id, product![1_u64])])?;
+        while rx.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let light = UpdateSettings {
+            request_id: 1,
+            compression: None,
+            tx_update_full: Some(false),
+            compression_threshold: None,
+        };
+        sender.update_settings(&light, &<_>::default())?;
+        commit_tx(&db, &subs, [], [(table_id, product![2_u64])])?;
+
+        for full in [true, false] {
+            match rx.recv().await {
+                Some(SerializableMessage::TxUpdate(update)) => assert_eq!(update.event.is_some(), full),
+                msg => panic!("expected a transaction update, got {msg:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// In this test we subscribe to a join query, update the lhs table,
+    /// and assert that the server sends the correct delta to the client.
+    #[tokio::test]
+    async fn test_update_for_join() -> anyhow::Result<()> {
+        async fn test_subscription_updates(queries: &[&'static str]) -> anyhow::Result<()> {
+            // Establish a client connection
+            let (sender, mut rx) = client_connection(client_id_from_u8(1));
+
+            let db = relational_db()?;
+            let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+
+            let p_schema = [("id", AlgebraicType::U64), ("signed_in", AlgebraicType::Bool)];
+            let l_schema = [
+                ("id", AlgebraicType::U64),
+                ("x", AlgebraicType::U64),
+                ("z", AlgebraicType::U64),
+            ];
+
+            let p_id = db.create_table_for_test("p", &p_schema, &[0.into()])?;
             let l_id = db.create_table_for_test("l", &l_schema, &[0.into()])?;
 
             subscribe_multi(&subs, queries, sender, &mut 0)?;
@@ -2659,9 +3220,696 @@ mod tests {
             "SELECT public.* FROM public JOIN private ON public.a = private.a WHERE private.a = 1",
             "SELECT private.* FROM private JOIN public ON private.a = public.a WHERE public.a = 1",
         ] {
-            assert!(subscribe(sql).is_err(),);
+            // The query fails as soon as the private table is named, before its schema is looked at.
+            let err = subscribe(sql).unwrap_err().to_string();
+            assert!(err.contains("Table `private` is not accessible"), "{sql}: {err}");
         }
 
         Ok(())
     }
+
+    /// Run a sequence of reducer calls from a client subscribed to some of the rows they touch,
+    /// returning the `(status, request_id)` of every reply the client receives.
+    async fn call_replies(tx_update_full: bool) -> anyhow::Result<Vec<(&'static str, u32)>> {
+        let client_id = client_id_from_u8(1);
+        let (sender, mut rx) = ClientConnectionSender::dummy_with_channel(
+            client_id,
+            ClientConfig {
+                protocol: Protocol::Binary,
+                compression: Compression::None,
+                tx_update_full,
+                reducer_timings: false,
+                acknowledged_delivery: false,
+                request_errors: false,
+                ..ClientConfig::for_test()
+            },
+        );
+        let sender = Arc::new(sender);
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let t_id = db.create_table_for_test("t", &[("x", AlgebraicType::U8)], &[])?;
+
+        subscribe_multi(&subs, &["select * from t where x = 0"], sender.clone(), &mut 0)?;
+        assert_matches!(rx.recv().await, Some(SerializableMessage::Subscription(_)));
+
+        let committed = || EventStatus::Committed(DatabaseUpdate::default());
+        let calls = [
+            // Changes no subscribed rows.
+            (committed(), Some(1_u8)),
+            // Changes a subscribed row.
+            (committed(), Some(0)),
+            // Changes nothing at all.
+            (committed(), None),
+            (EventStatus::Failed("error".into()), Some(0)),
+            (EventStatus::OutOfEnergy, Some(0)),
+        ];
+
+        let mut replies = vec![];
+        for (request_id, (status, row)) in (1..).zip(calls) {
+            let mut tx = begin_mut_tx(&db);
+            if let Some(x) = row {
+                db.insert(&mut tx, t_id, &bsatn::to_vec(&product![x])?)?;
+            }
+            let event = ModuleEvent {
+                caller_identity: client_id.identity,
+                caller_connection_id: Some(client_id.connection_id),
+                status,
+                request_id: Some(request_id),
+                ..module_event()
+            };
+            assert!(matches!(
+                subs.commit_and_broadcast_event(Some(sender.clone()), event, tx),
+                Ok(Ok(_))
+            ));
+
+            // A second reply would overflow the test client's channel and cancel it,
+            // so we'd time out waiting for the reply to the next call.
+            let Ok(Some(SerializableMessage::TxUpdate(msg))) =
+                tokio::time::timeout(Duration::from_secs(5), rx.recv()).await
+            else {
+                panic!("expected a reply to call {request_id}");
+            };
+            let FormatSwitch::Bsatn(ws::ServerMessage::TransactionUpdate(update)) = msg.to_protocol(Protocol::Binary)
+            else {
+                panic!("expected a full transaction update in reply to call {request_id}");
+            };
+            let status = match update.status {
+                ws::UpdateStatus::Committed(_) => "committed",
+                ws::UpdateStatus::Failed(_) => "failed",
+                ws::UpdateStatus::OutOfEnergy => "out of energy",
+            };
+            replies.push((status, update.reducer_call.request_id));
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(rx.is_empty(), "expected exactly one reply per call");
+        Ok(replies)
+    }
+
+    /// Test that the caller of a reducer gets exactly one reply per call,
+    /// reporting its outcome and request id, whether or not it asked for light updates.
+    #[tokio::test]
+    async fn test_call_replies_do_not_depend_on_light_mode() -> anyhow::Result<()> {
+        let expected = [
+            ("committed", 1),
+            ("committed", 2),
+            ("committed", 3),
+            ("failed", 4),
+            ("out of energy", 5),
+        ];
+        assert_eq!(call_replies(true).await?, expected);
+        assert_eq!(call_replies(false).await?, expected);
+        Ok(())
+    }
+
+    /// Test that the spans of a traced reducer call, from commit through fan-out,
+    /// are recorded under the span of the call, in the caller's trace,
+    /// and that the reply to the caller carries the id of the trace.
+    #[tokio::test]
+    async fn test_traced_call_spans_and_reply() -> anyhow::Result<()> {
+        let exporter = Exporter::default();
+        let _guard = exporter.set_default();
+
+        let client_id = client_id_from_u8(1);
+        let (sender, mut rx) = client_connection(client_id);
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let t_id = db.create_table_for_test("t", &[("x", AlgebraicType::U8)], &[])?;
+
+        subscribe_multi(&subs, &["select * from t"], sender.clone(), &mut 0)?;
+        assert_matches!(rx.recv().await, Some(SerializableMessage::Subscription(_)));
+
+        let traceparent: TraceParent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse()?;
+        let trace = ReducerTrace::start("insert", Some(traceparent));
+        let mut tx = begin_mut_tx(&db);
+        db.insert(&mut tx, t_id, &bsatn::to_vec(&product![0_u8])?)?;
+        let event = ModuleEvent {
+            caller_identity: client_id.identity,
+            caller_connection_id: Some(client_id.connection_id),
+            trace_id: Some(trace.trace_id),
+            ..module_event()
+        };
+
+        // Commit within the span of the call, as the host does.
+        let committed = trace
+            .span
+            .in_scope(|| subs.commit_and_broadcast_event(Some(sender), event, tx));
+        assert!(matches!(committed, Ok(Ok(_))));
+
+        let Some(SerializableMessage::TxUpdate(msg)) = rx.recv().await else {
+            panic!("expected a reply to the call");
+        };
+        let FormatSwitch::Bsatn(ws::ServerMessage::TransactionUpdate(update)) = msg.to_protocol(Protocol::Binary)
+        else {
+            panic!("expected a full transaction update in reply to the call");
+        };
+        assert_eq!(
+            update.reducer_call.trace_id.as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+
+        let [call] = &exporter.spans("reducer_call")[..] else {
+            panic!("expected exactly one reducer call span");
+        };
+        assert_eq!(call.fields["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(call.fields["parent_span_id"], "00f067aa0ba902b7");
+        for phase in ["commit", "fanout"] {
+            let spans = exporter.spans(phase);
+            assert!(!spans.is_empty(), "expected a {phase} span");
+            for span in spans {
+                assert!(span.ancestors.contains(&"reducer_call"), "{span:?}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Call a reducer from a client which may have asked for reducer timings,
+    /// returning the messages the client receives.
+    async fn timed_call(reducer_timings: bool) -> anyhow::Result<Vec<SerializableMessage>> {
+        let client_id = client_id_from_u8(1);
+        let config = ClientConfig {
+            reducer_timings,
+            ..ClientConfig::for_test()
+        };
+        let (sender, mut rx) = ClientConnectionSender::dummy_with_capacity(client_id, config, <_>::default());
+        let sender = Arc::new(sender);
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let t_id = db.create_table_for_test("t", &[("x", AlgebraicType::U8)], &[])?;
+
+        // Time the call the way the host would, as far as it gets before committing.
+        let mut phase_timer = ReducerPhaseTimer::start();
+        std::thread::sleep(Duration::from_millis(2));
+        phase_timer.decoded();
+        phase_timer.began_executing();
+        let mut tx = begin_mut_tx(&db);
+        db.insert(&mut tx, t_id, &bsatn::to_vec(&product![0_u8])?)?;
+        std::thread::sleep(Duration::from_millis(2));
+        phase_timer.executed();
+
+        let event = ModuleEvent {
+            caller_identity: client_id.identity,
+            caller_connection_id: Some(client_id.connection_id),
+            request_id: Some(7),
+            ..module_event()
+        };
+        let phase_timer = reducer_timings.then_some(phase_timer);
+        assert!(matches!(
+            subs.commit_and_broadcast_event_timed(Some(sender), event, tx, phase_timer),
+            Ok(Ok(_))
+        ));
+
+        let mut messages = vec![];
+        while let Ok(Some(msg)) = tokio::time::timeout(Duration::from_millis(100), rx.recv()).await {
+            messages.push(msg);
+        }
+        Ok(messages)
+    }
+
+    /// Test that a caller which asked for reducer timings gets them after its reply,
+    /// with phases adding up to the total, and that other callers don't.
+    #[tokio::test]
+    async fn test_reducer_timings_follow_the_reply() -> anyhow::Result<()> {
+        let messages = timed_call(true).await?;
+        let [SerializableMessage::TxUpdate(_), SerializableMessage::ReducerTimings(timings)] = &messages[..] else {
+            panic!("expected a reply and then timings, got {messages:?}");
+        };
+        assert_eq!(timings.request_id, 7);
+
+        let phases = [
+            timings.decode,
+            timings.wait,
+            timings.execute,
+            timings.commit,
+            timings.subscription_eval,
+        ];
+        let sum: Duration = phases.iter().map(|phase| phase.to_duration().unwrap()).sum();
+        let total = timings.total.to_duration().unwrap();
+        assert!(total.abs_diff(sum) < Duration::from_micros(10), "{sum:?} != {total:?}");
+        assert!(timings.decode.to_duration().unwrap() >= Duration::from_millis(2));
+        assert!(timings.execute.to_duration().unwrap() >= Duration::from_millis(2));
+
+        let messages = timed_call(false).await?;
+        assert!(matches!(&messages[..], [SerializableMessage::TxUpdate(_)]));
+        Ok(())
+    }
+
+    /// Test that a dump of a client's subscriptions counts the rows it was sent for each query,
+    /// redacts parameters when asked to, and can be registered again for another connection.
+    #[tokio::test]
+    async fn test_dump_and_restore_client_subscriptions() -> anyhow::Result<()> {
+        let client_id = client_id_from_u8(1);
+        let (sender, mut rx) = client_connection(client_id);
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let table_id = db.create_table_for_test("t", &[("a", AlgebraicType::U8)], &[])?;
+
+        let mut query_ids = 0;
+        subscribe_multi(
+            &subs,
+            &["select * from t", "select  *  from t where a = 1"],
+            sender,
+            &mut query_ids,
+        )?;
+        assert!(matches!(rx.recv().await, Some(SerializableMessage::Subscription(_))));
+
+        commit_tx(&db, &subs, [], [(table_id, product![1u8]), (table_id, product![2u8])])?;
+        assert!(matches!(rx.recv().await, Some(SerializableMessage::TxUpdate(_))));
+
+        let dump = subs.dump_client_subscriptions(client_id.connection_id)?.unwrap();
+        assert_eq!(dump.identity, client_id.identity);
+        assert!(!dump.redacted);
+        let [subscription] = &dump.subscriptions[..] else {
+            panic!("expected a single subscription, got {dump:?}");
+        };
+        assert_eq!(subscription.query_id, Some(1));
+        let [scan, select] = &subscription.queries[..] else {
+            panic!("expected two queries, got {subscription:?}");
+        };
+        assert_eq!((&*scan.sql, scan.rows_delivered), ("select * from t", 2));
+        assert!(scan.parameters.is_empty());
+        assert_eq!(
+            (&*select.sql, select.rows_delivered),
+            ("select * from t where a = 1", 1)
+        );
+        assert_eq!(select.parameters[0].value.as_deref(), Some("1"));
+
+        let (other, _rx) = client_connection(client_id_from_u8(2));
+        subs.restore_client_subscriptions(other.clone(), &dump)?;
+        let restored = subs.dump_client_subscriptions(other.id.connection_id)?.unwrap();
+        let queries = |dump: &ClientSubscriptions| {
+            dump.subscriptions
+                .iter()
+                .flat_map(|sub| sub.queries.iter().map(move |query| (sub.query_id, query.sql.clone())))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(queries(&restored), queries(&dump));
+        assert!(restored.subscriptions[0]
+            .queries
+            .iter()
+            .all(|query| query.rows_delivered == 0));
+
+        with_auto_commit(&db, |tx| {
+            db.write_var(tx, StVarName::RedactSubscriptionParameters, "true")
+        })?;
+        let redacted = subs.dump_client_subscriptions(client_id.connection_id)?.unwrap();
+        assert!(redacted.redacted);
+        let select = &redacted.subscriptions[0].queries[1];
+        assert_eq!(select.sql, "select * from t where a = ?");
+        assert_eq!(select.parameters[0].value, None);
+        assert!(subs.restore_client_subscriptions(other, &redacted).is_err());
+        Ok(())
+    }
+
+    /// Subscribe to the rows of `sql` in `window` as a client
+    fn subscribe_window(
+        subs: &ModuleSubscriptions,
+        sql: &'static str,
+        window: &str,
+        older_rows: OlderRows,
+        sender: Arc<ClientConnectionSender>,
+        counter: &mut u32,
+    ) -> anyhow::Result<()> {
+        *counter += 1;
+        let request = SubscribeWindow {
+            query: sql.into(),
+            request_id: 0,
+            query_id: QueryId::new(*counter),
+            initial: window.parse()?,
+            older_rows,
+        };
+        subs.add_window_subscription(sender, request, Instant::now(), None)?;
+        Ok(())
+    }
+
+    /// Pull a message from receiver and assert that it is a `SubscribeWindowApplied`,
+    /// returning its rows in order and its window.
+    async fn window_applied(
+        rx: &mut MeteredReceiver<SerializableMessage>,
+        schema: &ProductType,
+    ) -> (Vec<ProductValue>, ws::AppliedWindow) {
+        let Some(SerializableMessage::Subscription(SubscriptionMessage {
+            result:
+                SubscriptionResult::SubscribeWindow(SubscriptionWindowRows {
+                    rows:
+                        SubscriptionRows {
+                            table_rows: FormatSwitch::Bsatn(table_rows),
+                            ..
+                        },
+                    window,
+                }),
+            ..
+        })) = rx.recv().await
+        else {
+            panic!("expected a SubscribeWindowApplied");
+        };
+        let rows = table_rows
+            .updates
+            .into_iter()
+            .flat_map(|update| {
+                let CompressableQueryUpdate::Uncompressed(update) = update else {
+                    panic!("expected an uncompressed table update")
+                };
+                // The rows of the list borrow from it, so are decoded before it's dropped.
+                update
+                    .inserts
+                    .into_iter()
+                    .map(|bytes| ProductValue::decode(schema, &mut &*bytes).unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        (rows, window)
+    }
+
+    /// Test that windowed subscriptions are sent the newest rows,
+    /// and updates to older rows only if they asked for them.
+    #[tokio::test]
+    async fn test_window_subscription() -> anyhow::Result<()> {
+        let (suppressing, mut rx_suppressing) = client_connection(client_id_from_u8(1));
+        let (delivering, mut rx_delivering) = client_connection(client_id_from_u8(2));
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+
+        // Create a table `messages` with an index on `sent`
+        let schema = [("text", AlgebraicType::String), ("sent", AlgebraicType::U64)];
+        let table_id = db.create_table_for_test("messages", &schema, &[1.into()])?;
+        let message = |sent: u64| product![format!("message {sent}"), sent];
+        commit_tx(&db, &subs, [], [4, 1, 5, 3, 2].map(|sent| (table_id, message(sent))))?;
+
+        let sql = "select * from messages";
+        let mut query_ids = 0;
+        subscribe_window(
+            &subs,
+            sql,
+            "last(3, sent)",
+            OlderRows::Suppress,
+            suppressing,
+            &mut query_ids,
+        )?;
+        subscribe_window(
+            &subs,
+            sql,
+            "last(3, sent)",
+            OlderRows::Deliver,
+            delivering,
+            &mut query_ids,
+        )?;
+
+        let schema = ProductType::from([AlgebraicType::String, AlgebraicType::U64]);
+        let newest = vec![message(5), message(4), message(3)];
+        let (rows, window) = window_applied(&mut rx_suppressing, &schema).await;
+        assert_eq!(rows, newest);
+        assert_eq!(window.initial.to_string(), "last(3, sent)");
+        assert!(window.has_older_rows);
+        assert_eq!(window.older_rows, OlderRows::Suppress);
+        let (rows, window) = window_applied(&mut rx_delivering, &schema).await;
+        assert_eq!(rows, newest);
+        assert_eq!(window.older_rows, OlderRows::Deliver);
+
+        // Updates within or after the window are sent to both.
+        commit_tx(&db, &subs, [(table_id, message(3))], [(table_id, message(6))])?;
+        for rx in [&mut rx_suppressing, &mut rx_delivering] {
+            assert_tx_update_for_table(rx, table_id, &schema, [message(6)], [message(3)]).await;
+        }
+
+        // Updates to older rows are only sent to the client which didn't suppress them.
+        commit_tx(&db, &subs, [(table_id, message(1))], [(table_id, message(7))])?;
+        assert_tx_update_for_table(&mut rx_suppressing, table_id, &schema, [message(7)], []).await;
+        assert_tx_update_for_table(&mut rx_delivering, table_id, &schema, [message(7)], [message(1)]).await;
+
+        commit_tx(&db, &subs, [(table_id, message(2))], [])?;
+        assert_tx_update_for_table(&mut rx_delivering, table_id, &schema, [], [message(2)]).await;
+        assert!(rx_suppressing.is_empty());
+        Ok(())
+    }
+
+    /// Test that a window of fewer rows than the query matches has nothing to suppress
+    #[tokio::test]
+    async fn test_window_subscription_without_older_rows() -> anyhow::Result<()> {
+        let (sender, mut rx) = client_connection(client_id_from_u8(1));
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let table_id = db.create_table_for_test("t", &[("id", AlgebraicType::U64)], &[0.into()])?;
+        commit_tx(&db, &subs, [], [(table_id, product![2u64]), (table_id, product![1u64])])?;
+
+        let mut query_ids = 0;
+        subscribe_window(
+            &subs,
+            "select * from t",
+            "last(5, id)",
+            OlderRows::Suppress,
+            sender,
+            &mut query_ids,
+        )?;
+
+        let schema = ProductType::from([AlgebraicType::U64]);
+        let (rows, window) = window_applied(&mut rx, &schema).await;
+        assert_eq!(rows, [product![2u64], product![1u64]]);
+        assert!(!window.has_older_rows);
+        assert_eq!(window.older_rows, OlderRows::Deliver);
+        Ok(())
+    }
+
+    /// Test that windows are refused for columns which can't be ordered by
+    #[tokio::test]
+    async fn test_window_subscription_requires_an_index() -> anyhow::Result<()> {
+        let (sender, mut rx) = client_connection(client_id_from_u8(1));
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        db.create_table_for_test(
+            "t",
+            &[("id", AlgebraicType::U64), ("x", AlgebraicType::U64)],
+            &[0.into()],
+        )?;
+
+        let sql = "select * from t";
+        let mut query_ids = 0;
+        for (window, error) in [
+            ("last(5, x)", "cannot order `t` by `x`: the column is not indexed"),
+            ("last(5, y)", "cannot order `t` by `y`: no such column"),
+            ("last(0, id)", "a window must hold at least one row"),
+        ] {
+            subscribe_window(&subs, sql, window, OlderRows::Deliver, sender.clone(), &mut query_ids)?;
+            let Some(SerializableMessage::Subscription(SubscriptionMessage {
+                result: SubscriptionResult::Error(SubscriptionError { message, .. }),
+                ..
+            })) = rx.recv().await
+            else {
+                panic!("expected a subscription error for {window}");
+            };
+            assert!(message.contains(sql) && message.contains(error), "{message}");
+        }
+        Ok(())
+    }
+
+    /// Test that a module update drops the subscriptions it invalidates, and only those
+    #[tokio::test]
+    async fn test_invalid_subscriptions_are_dropped() -> anyhow::Result<()> {
+        let (sender, mut rx) = client_connection(client_id_from_u8(1));
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let t_id = db.create_table_for_test("t", &[("id", AlgebraicType::U64)], &[0.into()])?;
+        let s_id = db.create_table_for_test("s", &[("id", AlgebraicType::U64)], &[0.into()])?;
+
+        let mut query_ids = 0;
+        for sql in ["select t.* from t join s on t.id = s.id", "select * from t"] {
+            subscribe_multi(&subs, &[sql], sender.clone(), &mut query_ids)?;
+            assert_matches!(
+                rx.recv().await,
+                Some(SerializableMessage::Subscription(SubscriptionMessage {
+                    result: SubscriptionResult::SubscribeMulti(_),
+                    ..
+                }))
+            );
+        }
+        assert_eq!(subs.remove_invalid_subscriptions(), 0);
+
+        // Remove the index the join reads, as a module update could.
+        with_auto_commit(&db, |tx| {
+            let index_id = db.schema_for_table_mut(tx, s_id)?.indexes[0].index_id;
+            db.drop_index(tx, index_id)
+        })?;
+        assert_eq!(subs.remove_invalid_subscriptions(), 1);
+
+        let Some(SerializableMessage::Subscription(SubscriptionMessage {
+            query_id: Some(query_id),
+            result: SubscriptionResult::Error(SubscriptionError { message, .. }),
+            ..
+        })) = rx.recv().await
+        else {
+            panic!("expected an error for the dropped subscription");
+        };
+        assert_eq!(query_id, QueryId::new(1));
+        assert!(message.contains("subscription dropped by a module update"), "{message}");
+
+        // The other subscription is still sent updates.
+        commit_tx(&db, &subs, [], [(t_id, product![1u64])])?;
+        let schema = ProductType::from([AlgebraicType::U64]);
+        assert_tx_update_for_table(&mut rx, t_id, &schema, [product![1u64]], []).await;
+        Ok(())
+    }
+
+    /// Test that a commit with many subscribers doesn't wait for their updates to be enqueued,
+    /// and that its caller's reply isn't delayed by them,
+    /// while the fan-out budget holds them back,
+    /// and that every subscriber still receives its updates in commit order.
+    #[tokio::test]
+    async fn test_fanout_does_not_delay_callers() -> anyhow::Result<()> {
+        const SUBSCRIBERS: u8 = 100;
+
+        let db = relational_db()?;
+        // Enqueueing an update for every subscriber takes at least 100ms.
+        let fanout = Arc::new(FanoutBudget::new(FanoutOptions { enqueues_per_ms: 1 }));
+        let send_worker_queue = spawn_send_worker(None, fanout);
+        let subs = ModuleSubscriptions::new(
+            db.clone(),
+            Arc::new(RwLock::new(SubscriptionManager::new(send_worker_queue.clone()))),
+            send_worker_queue,
+            Identity::ZERO,
+            Firehose::new(db.database_identity()),
+        );
+        let t_id = db.create_table_for_test("t", &[("x", AlgebraicType::U8)], &[])?;
+        let schema = ProductType::from([AlgebraicType::U8]);
+
+        // Each subscriber is sent both updates before it's read, so it must have room to queue them.
+        let mut subscribers = vec![];
+        for i in 0..SUBSCRIBERS {
+            let config = ClientConfig {
+                protocol: Protocol::Binary,
+                compression: Compression::None,
+                tx_update_full: true,
+                acknowledged_delivery: false,
+                request_errors: false,
+                ..ClientConfig::for_test()
+            };
+            let (sender, mut rx) =
+                ClientConnectionSender::dummy_with_capacity(client_id_from_u8(i), config, <_>::default());
+            subscribe_multi(&subs, &["select * from t"], Arc::new(sender), &mut 0)?;
+            assert!(matches!(rx.recv().await, Some(SerializableMessage::Subscription(_))));
+            subscribers.push(rx);
+        }
+        let (caller, mut caller_rx) = client_connection(client_id_from_u8(SUBSCRIBERS));
+
+        for x in [1_u8, 2] {
+            let mut tx = begin_mut_tx(&db);
+            db.insert(&mut tx, t_id, &bsatn::to_vec(&product![x])?)?;
+            let event = ModuleEvent {
+                caller_identity: caller.id.identity,
+                caller_connection_id: Some(caller.id.connection_id),
+                ..module_event()
+            };
+            let start = Instant::now();
+            assert!(matches!(
+                subs.commit_and_broadcast_event(Some(caller.clone()), event, tx),
+                Ok(Ok(_))
+            ));
+            assert!(start.elapsed() < Duration::from_millis(100), "{:?}", start.elapsed());
+        }
+
+        // The caller of the first commit is replied to before most subscribers are sent its update.
+        assert!(matches!(caller_rx.recv().await, Some(SerializableMessage::TxUpdate(_))));
+        let waiting = subscribers.iter().filter(|rx| rx.is_empty()).count();
+        assert!(
+            waiting > SUBSCRIBERS as usize / 2,
+            "only {waiting} subscribers are waiting"
+        );
+
+        for rx in &mut subscribers {
+            assert_tx_update_for_table(rx, t_id, &schema, [product![1_u8]], []).await;
+            assert_tx_update_for_table(rx, t_id, &schema, [product![2_u8]], []).await;
+        }
+        assert!(matches!(caller_rx.recv().await, Some(SerializableMessage::TxUpdate(_))));
+        Ok(())
+    }
+
+    /// Test that the queries of clients too far behind under a resync backlog policy aren't evaluated,
+    /// and that the clients are told to resync them once they catch up.
+    ///
+    /// Needs a multi-threaded tokio runtime so that the send worker can run in parallel.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn stalled_subscribers_are_skipped_then_resynced() -> anyhow::Result<()> {
+        const SUBSCRIBERS: u8 = 10;
+
+        let db = relational_db()?;
+        let subs = ModuleSubscriptions::for_test_enclosing_runtime(db.clone());
+        let t_id = db.create_table_for_test("t", &[("x", AlgebraicType::U8)], &[])?;
+        let schema = ProductType::from([AlgebraicType::U8]);
+
+        // A client is behind as soon as anything is queued for it.
+        let policy = SendBacklogPolicy {
+            action: SendBacklogAction::Resync,
+            max_messages: Some(0),
+            max_bytes: None,
+        };
+        let capacity = SendQueueCapacity {
+            messages: 4,
+            ..<_>::default()
+        };
+
+        // Each subscriber has a query of its own, so a tenth of the queries are for the stalled subscriber.
+        // It's subscribed first, so its subscription is queued before the others are received.
+        let mut subscribers = vec![];
+        for i in 0..SUBSCRIBERS {
+            let (sender, mut rx) = ClientConnectionSender::dummy_with_capacity(
+                client_id_from_u8(i),
+                ClientConfig {
+                    protocol: Protocol::Binary,
+                    compression: Compression::None,
+                    tx_update_full: true,
+                    acknowledged_delivery: false,
+                    request_errors: false,
+                    ..ClientConfig::for_test()
+                },
+                capacity,
+            );
+            let sender = Arc::new(sender.with_send_backlog(policy));
+            let sql: &'static str = format!("select * from t where x = {i}").leak();
+            subscribe_multi(&subs, &[sql], sender, &mut 0)?;
+            if i > 0 {
+                assert!(matches!(rx.recv().await, Some(SerializableMessage::Subscription(_))));
+            }
+            subscribers.push(rx);
+        }
+        assert_eq!(subscribers[0].len(), 1);
+
+        let rows = || (0..SUBSCRIBERS).map(|x| (t_id, product![x]));
+        let stalled = commit_tx(&db, &subs, [], rows())?;
+        for (x, rx) in (0_u8..).zip(&mut subscribers).skip(1) {
+            assert_tx_update_for_table(rx, t_id, &schema, [product![x]], []).await;
+        }
+        // The stalled subscriber is sent nothing, not even an update it couldn't receive.
+        assert_eq!(subscribers[0].len(), 1);
+
+        // Once it catches up, it's told to resync before it's sent the next update.
+        assert!(matches!(
+            subscribers[0].recv().await,
+            Some(SerializableMessage::Subscription(_))
+        ));
+        let caught_up = commit_tx(&db, &subs, rows(), [])?;
+        match subscribers[0].recv().await {
+            Some(SerializableMessage::ResyncRequired(notice)) => assert_eq!(
+                notice,
+                ResyncRequired {
+                    query_ids: [QueryId::new(1)].into(),
+                    legacy: false,
+                }
+            ),
+            message => panic!("expected a resync notice, got {message:?}"),
+        }
+        assert_tx_update_for_table(&mut subscribers[0], t_id, &schema, [], [product![0_u8]]).await;
+
+        // Not evaluating the stalled subscriber's query saved a tenth of the work.
+        assert!(stalled.rows_scanned < caught_up.rows_scanned);
+        assert!(stalled.bytes_sent_to_clients < caught_up.bytes_sent_to_clients);
+
+        Ok(())
+    }
 }
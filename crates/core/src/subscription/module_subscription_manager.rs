@@ -1,35 +1,41 @@
+use super::dump::{normalize_sql, ClientSubscriptions, QueryDump, QueryParameter, SubscriptionDump};
 use super::execution_unit::QueryHash;
 use super::tx::DeltaTx;
 use crate::client::messages::{
-    SerializableMessage, SubscriptionError, SubscriptionMessage, SubscriptionResult, SubscriptionUpdateMessage,
-    TransactionUpdateMessage,
+    ResyncRequiredMessage, SerializableMessage, SharedEncoding, SubscriptionError, SubscriptionMessage,
+    SubscriptionResult, SubscriptionUpdateMessage, TransactionUpdateMessage, UpdateDetail,
 };
-use crate::client::{ClientConnectionSender, Protocol};
+use crate::client::{ClientConfig, ClientConnectionSender, ClientSendError, Protocol};
 use crate::db::datastore::locking_tx_datastore::state_view::StateView;
 use crate::error::DBError;
-use crate::host::module_host::{DatabaseTableUpdate, ModuleEvent, UpdatesRelValue};
+use crate::host::module_host::{DatabaseTableUpdate, EventStatus, ModuleEvent, UpdatesRelValue};
 use crate::messages::websocket::{self as ws, TableUpdate};
 use crate::subscription::delta::eval_delta;
+use crate::subscription::fanout::FanoutBudget;
+use crate::subscription::projection::{column_set, project_updates};
+use crate::subscription::window::OlderRowsFilter;
 use crate::worker_metrics::WORKER_METRICS;
 use core::mem;
 use hashbrown::hash_map::OccupiedError;
 use hashbrown::{HashMap, HashSet};
-use parking_lot::RwLock;
-use prometheus::IntGauge;
+use parking_lot::{Mutex, RwLock};
+use prometheus::{Histogram, IntCounter, IntGauge};
 use spacetimedb_client_api_messages::websocket::{
     BsatnFormat, CompressableQueryUpdate, FormatSwitch, JsonFormat, QueryId, QueryUpdate, SingleQueryUpdate,
     WebsocketFormat,
 };
 use spacetimedb_data_structures::map::{Entry, IntMap};
 use spacetimedb_lib::metrics::ExecutionMetrics;
-use spacetimedb_lib::{AlgebraicValue, ConnectionId, Identity, ProductValue};
+use spacetimedb_lib::{AlgebraicValue, ConnectionId, Identity, ProductValue, Timestamp};
 use spacetimedb_primitives::{ColId, IndexId, TableId};
-use spacetimedb_subscription::{JoinEdge, SubscriptionPlan, TableName};
+use spacetimedb_sats::satn::Satn;
+use spacetimedb_subscription::{ColumnProjection, JoinEdge, SubscriptionPlan, TableName};
 use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc;
+use tracing::Instrument;
 
 /// Clients are uniquely identified by their Identity and ConnectionId.
 /// Identity is insufficient because different ConnectionIds can use the same Identity.
@@ -75,6 +81,11 @@ impl Plan {
         self.plans[0].subscribed_table_name()
     }
 
+    /// The columns this subscription selects, if not whole rows.
+    pub fn projection(&self) -> Option<&ColumnProjection> {
+        self.plans[0].projection()
+    }
+
     /// Returns the index ids from which this subscription reads
     pub fn index_ids(&self) -> impl Iterator<Item = (TableId, IndexId)> {
         self.plans
@@ -131,12 +142,23 @@ struct ClientInfo {
     subscription_ref_count: HashMap<QueryHash, usize>,
     // This should be removed when we migrate to SubscribeSingle.
     legacy_subscriptions: HashSet<QueryHash>,
+    /// Stats for each query the client is subscribed to, by any of its subscriptions.
+    query_stats: HashMap<QueryHash, ClientQueryStats>,
+    /// For each query the client subscribed to with a window, suppressing updates to older rows,
+    /// the filter leaving those rows out.
+    ///
+    /// Subscribing to the same query again, windowed or not, removes its filter,
+    /// as the client is then sent the query's rows without it.
+    older_rows_filters: HashMap<QueryHash, OlderRowsFilter>,
     /// This flag is set if an error occurs during a tx update.
     /// It will be cleaned up async or on resubscribe.
     ///
     /// [`Arc`]ed so that this can be updated by the [`SendWorker`]
     /// and observed by [`SubscriptionManager::remove_dropped_clients`].
     dropped: Arc<AtomicBool>,
+    /// The subscriptions which missed updates while the client was behind,
+    /// shared with the [`SendWorker`], which tells the client to resync them.
+    resync: Arc<PendingResync>,
 }
 
 impl ClientInfo {
@@ -146,10 +168,51 @@ impl ClientInfo {
             subscriptions: HashMap::default(),
             subscription_ref_count: HashMap::default(),
             legacy_subscriptions: HashSet::default(),
+            query_stats: HashMap::default(),
+            older_rows_filters: HashMap::default(),
             dropped: Arc::new(AtomicBool::new(false)),
+            resync: Arc::default(),
+        }
+    }
+
+    /// Note that the client missed an update to the query `hash` while behind,
+    /// so that every subscription of the client to it is resynced.
+    fn skip_update(&self, hash: &QueryHash) {
+        let query_ids = self
+            .subscriptions
+            .iter()
+            .filter(|(_, hashes)| hashes.contains(hash))
+            .map(|((_, query_id), _)| *query_id);
+        self.resync.mark(query_ids, self.legacy_subscriptions.contains(hash));
+    }
+
+    /// Start tracking stats for `hash`, unless the client was already subscribed to it.
+    fn track_query(&mut self, hash: QueryHash) {
+        self.query_stats.entry(hash).or_insert_with(ClientQueryStats::new);
+    }
+
+    /// Stop tracking stats for `hash` if the client is no longer subscribed to it.
+    fn untrack_query_if_unsubscribed(&mut self, hash: &QueryHash) {
+        if !self.subscription_ref_count.contains_key(hash) && !self.legacy_subscriptions.contains(hash) {
+            self.query_stats.remove(hash);
+            self.older_rows_filters.remove(hash);
+        }
+    }
+
+    /// Count `num_rows` sent to the client for the query `hash`.
+    fn record_rows_delivered(&self, hash: &QueryHash, num_rows: u64) {
+        if let Some(stats) = self.query_stats.get(hash) {
+            stats.rows_delivered.fetch_add(num_rows, Ordering::Relaxed);
         }
     }
 
+    /// Tell the client's connection how many subscriptions it has,
+    /// counting each of its legacy queries as a subscription.
+    fn report_subscriptions(&self) {
+        self.outbound_ref
+            .set_subscriptions(self.subscriptions.len() + self.legacy_subscriptions.len());
+    }
+
     /// Check that the subscription ref count matches the actual number of subscriptions.
     #[cfg(test)]
     fn assert_ref_count_consistency(&self) {
@@ -174,6 +237,73 @@ impl ClientInfo {
     }
 }
 
+/// The subscriptions of a client which missed updates while it was behind,
+/// under [`SendBacklogAction::Resync`](crate::messages::control_db::SendBacklogAction::Resync),
+/// which it's told to resync once it catches up.
+///
+/// Marked while evaluating updates under a read lock, and taken by the [`SendWorker`].
+#[derive(Debug, Default)]
+struct PendingResync(Mutex<PendingResyncState>);
+
+#[derive(Debug, Default)]
+struct PendingResyncState {
+    query_ids: HashSet<ClientQueryId>,
+    legacy: bool,
+}
+
+impl PendingResync {
+    fn mark(&self, query_ids: impl Iterator<Item = ClientQueryId>, legacy: bool) {
+        let mut state = self.0.lock();
+        state.query_ids.extend(query_ids);
+        state.legacy |= legacy;
+    }
+
+    /// The client unsubscribed from `query_id`, so there's nothing to resync for it.
+    fn forget(&self, query_id: &ClientQueryId) {
+        self.0.lock().query_ids.remove(query_id);
+    }
+
+    /// Take the notice telling the client which subscriptions to resync, if any missed updates.
+    fn take(&self) -> Option<ResyncRequiredMessage> {
+        let PendingResyncState { query_ids, legacy } = mem::take(&mut *self.0.lock());
+        if query_ids.is_empty() && !legacy {
+            return None;
+        }
+        let mut query_ids = query_ids.into_iter().collect::<Box<[_]>>();
+        query_ids.sort_unstable_by_key(|query_id| query_id.id);
+        Some(ResyncRequiredMessage { query_ids, legacy })
+    }
+}
+
+/// When a client subscribed to a query, and how many rows it has been sent for it since.
+#[derive(Debug)]
+struct ClientQueryStats {
+    registered_at: Timestamp,
+    /// Rows sent in transaction updates, not counting the initial rows of the subscription.
+    ///
+    /// Atomic so that it can be counted while evaluating updates under a read lock.
+    rows_delivered: AtomicU64,
+}
+
+impl ClientQueryStats {
+    fn new() -> Self {
+        Self {
+            registered_at: Timestamp::now(),
+            rows_delivered: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A subscription removed by [`SubscriptionManager::remove_invalid_subscriptions`].
+#[derive(Debug)]
+pub struct InvalidSubscription {
+    pub client: Client,
+    /// The id of the subscription, or `None` for the client's legacy subscription.
+    pub query_id: Option<QueryId>,
+    /// Why one of its queries is no longer valid.
+    pub error: String,
+}
+
 /// For each query that has subscribers, we track a set of legacy subscribers and individual subscriptions.
 #[derive(Debug)]
 struct QueryState {
@@ -504,6 +634,9 @@ struct ClientUpdate {
     table_id: TableId,
     table_name: TableName,
     update: FormatSwitch<SingleQueryUpdate<BsatnFormat>, SingleQueryUpdate<JsonFormat>>,
+    /// The query whose update this is, if it's the same copy sent to every subscriber of the query,
+    /// rather than the client's own, e.g. of a windowed subscription.
+    shared_query: Option<QueryHash>,
 }
 
 /// The computed incremental update queries with sufficient information
@@ -515,8 +648,14 @@ struct ClientUpdate {
 struct ComputedQueries {
     updates: Vec<ClientUpdate>,
     errs: Vec<(ClientId, Box<str>)>,
+    /// The clients which missed an update while behind, once per query.
+    skipped: Vec<ClientId>,
     event: Arc<ModuleEvent>,
     caller: Option<Arc<ClientConnectionSender>>,
+    committed_at: Timestamp,
+    /// Created in the span of the transaction, e.g. of a reducer call,
+    /// so that its fan-out is recorded in the same trace.
+    fanout_span: tracing::Span,
 }
 
 // Wraps a sender so that it will increment a gauge.
@@ -564,6 +703,8 @@ enum SendWorkerMessage {
         ///
         /// Will be updated by [`SendWorker::run`] and read by [`SubscriptionManager::remove_dropped_clients`].
         dropped: Arc<AtomicBool>,
+        /// Shared handle on the subscriptions to resync in the [`ClientInfo`].
+        resync: Arc<PendingResync>,
         outbound_ref: Client,
     },
 
@@ -598,7 +739,7 @@ impl SubscriptionManager {
     }
 
     pub fn for_test_without_metrics() -> Self {
-        Self::new(SendWorker::spawn_new(None))
+        Self::new(SendWorker::spawn_new(None, <_>::default()))
     }
 
     pub fn new(send_worker_queue: BroadcastQueue) -> Self {
@@ -637,6 +778,58 @@ impl SubscriptionManager {
         }
     }
 
+    /// Dump the subscriptions of the client connected as `connection_id`, if any,
+    /// redacting the values of query parameters if `redact` is set.
+    pub fn client_subscriptions(&self, connection_id: ConnectionId, redact: bool) -> Option<ClientSubscriptions> {
+        let (&(identity, connection_id), ci) = self
+            .clients
+            .iter()
+            .find(|((_, id), ci)| *id == connection_id && !ci.dropped.load(Ordering::Acquire))?;
+
+        let dump_queries = |hashes: &HashSet<QueryHash>| {
+            let mut queries = hashes
+                .iter()
+                .filter_map(|hash| Some((self.queries.get(hash)?, ci.query_stats.get(hash)?)))
+                .map(|(state, stats)| QueryDump {
+                    sql: normalize_sql(state.query.sql(), redact),
+                    parameters: state
+                        .search_args()
+                        .map(|(table_id, col_id, value)| QueryParameter {
+                            table_id: table_id.0,
+                            col_id: col_id.0,
+                            value: (!redact).then(|| value.to_satn()),
+                        })
+                        .collect(),
+                    registered_at: stats.registered_at,
+                    rows_delivered: stats.rows_delivered.load(Ordering::Relaxed),
+                })
+                .collect::<Vec<_>>();
+            queries.sort_by(|a, b| a.sql.cmp(&b.sql));
+            queries
+        };
+
+        let legacy = (!ci.legacy_subscriptions.is_empty()).then(|| SubscriptionDump {
+            query_id: None,
+            queries: dump_queries(&ci.legacy_subscriptions),
+        });
+        let mut subscriptions = ci
+            .subscriptions
+            .iter()
+            .map(|((_, query_id), hashes)| SubscriptionDump {
+                query_id: Some(query_id.id),
+                queries: dump_queries(hashes),
+            })
+            .collect::<Vec<_>>();
+        subscriptions.sort_by_key(|sub| sub.query_id);
+
+        Some(ClientSubscriptions {
+            identity,
+            connection_id,
+            redacted: redact,
+            subscriptions: legacy.into_iter().chain(subscriptions).collect(),
+        })
+    }
+
     /// Add a new [`ClientInfo`] to the `clients` map, and broadcast a message along `send_worker_tx`
     /// that the [`SendWorker`] should also add this client.
     ///
@@ -653,6 +846,7 @@ impl SubscriptionManager {
                 .send(SendWorkerMessage::AddClient {
                     client_id,
                     dropped: info.dropped.clone(),
+                    resync: info.resync.clone(),
                     outbound_ref,
                 })
                 .expect("send worker has panicked, or otherwise dropped its recv queue!");
@@ -731,7 +925,10 @@ impl SubscriptionManager {
                     queries_to_remove.push(*query_hash);
                 }
             }
-            ci.legacy_subscriptions.clear();
+            for query_hash in mem::take(&mut ci.legacy_subscriptions) {
+                ci.untrack_query_if_unsubscribed(&query_hash);
+            }
+            ci.report_subscriptions();
             for query_hash in queries_to_remove {
                 self.queries.remove(&query_hash);
             }
@@ -749,6 +946,59 @@ impl SubscriptionManager {
         }
     }
 
+    /// Remove every subscription which includes a query `check` rejects,
+    /// e.g. because a module update removed a table or index it reads.
+    ///
+    /// Each query is checked once, on behalf of any of its subscribers,
+    /// with `check` returning why it is no longer valid.
+    pub fn remove_invalid_subscriptions(
+        &mut self,
+        mut check: impl FnMut(&Plan, Identity) -> Result<(), String>,
+    ) -> Vec<InvalidSubscription> {
+        let invalid = self
+            .queries
+            .iter()
+            .filter_map(|(hash, state)| {
+                let &(identity, _) = state.all_clients().next()?;
+                check(&state.query, identity).err().map(|err| (*hash, err))
+            })
+            .collect::<HashMap<_, _>>();
+        if invalid.is_empty() {
+            return vec![];
+        }
+
+        let first_invalid = |hashes: &HashSet<QueryHash>| hashes.iter().find_map(|hash| invalid.get(hash));
+        let mut removed = vec![];
+        for (client_id, ci) in &self.clients {
+            if let Some(err) = first_invalid(&ci.legacy_subscriptions) {
+                removed.push((*client_id, None, err.clone()));
+            }
+            for ((_, query_id), hashes) in &ci.subscriptions {
+                if let Some(err) = first_invalid(hashes) {
+                    removed.push((*client_id, Some(*query_id), err.clone()));
+                }
+            }
+        }
+
+        removed
+            .into_iter()
+            .filter_map(|(client_id, query_id, error)| {
+                let client = self.clients.get(&client_id)?.outbound_ref.clone();
+                match query_id {
+                    None => self.remove_legacy_subscriptions(&client_id),
+                    Some(query_id) => {
+                        self.remove_subscription(client_id, query_id).ok()?;
+                    }
+                }
+                Some(InvalidSubscription {
+                    client,
+                    query_id,
+                    error,
+                })
+            })
+            .collect()
+    }
+
     /// Remove a single subscription for a client.
     /// This will return an error if the client does not have a subscription with the given query id.
     pub fn remove_subscription(&mut self, client_id: ClientId, query_id: ClientQueryId) -> Result<Vec<Query>, DBError> {
@@ -767,6 +1017,8 @@ impl SubscriptionManager {
         let Some(query_hashes) = ci.subscriptions.remove(&subscription_id) else {
             return Err(anyhow::anyhow!("Subscription not found: {:?}", subscription_id).into());
         };
+        ci.resync.forget(&query_id);
+        ci.report_subscriptions();
         let mut queries_to_return = Vec::new();
         for hash in query_hashes {
             let remaining_refs = {
@@ -782,6 +1034,7 @@ impl SubscriptionManager {
             }
             // The client is no longer subscribed to this query.
             ci.subscription_ref_count.remove(&hash);
+            ci.untrack_query_if_unsubscribed(&hash);
             let Some(query_state) = self.queries.get_mut(&hash) else {
                 return Err(anyhow::anyhow!("Query state not found for query hash: {:?}", hash).into());
             };
@@ -810,6 +1063,32 @@ impl SubscriptionManager {
         self.add_subscription_multi(client, vec![query], query_id).map(|_| ())
     }
 
+    /// Adds a single windowed subscription for a client,
+    /// leaving updates to rows older than its window out with `filter`, if any.
+    ///
+    /// If the client is already subscribed to the query, by any of its subscriptions,
+    /// it keeps being sent all of the query's updates.
+    pub fn add_windowed_subscription(
+        &mut self,
+        client: Client,
+        query: Query,
+        query_id: ClientQueryId,
+        filter: Option<OlderRowsFilter>,
+    ) -> Result<(), DBError> {
+        let client_id = (client.id.identity, client.id.connection_id);
+        let hash = query.hash();
+        let new_queries = self.add_subscription_multi(client, vec![query], query_id)?;
+        let Some(filter) = filter.filter(|_| !new_queries.is_empty()) else {
+            return Ok(());
+        };
+        if let Some(ci) = self.clients.get_mut(&client_id) {
+            if !ci.legacy_subscriptions.contains(&hash) {
+                ci.older_rows_filters.insert(hash, filter);
+            }
+        }
+        Ok(())
+    }
+
     pub fn add_subscription_multi(
         &mut self,
         client: Client,
@@ -873,6 +1152,8 @@ impl SubscriptionManager {
             let entry = ci.subscription_ref_count.entry(hash).or_insert(0);
             *entry += 1;
             let is_new_entry = *entry == 1;
+            ci.query_stats.entry(hash).or_insert_with(ClientQueryStats::new);
+            ci.older_rows_filters.remove(&hash);
 
             let inserted = query_state.subscriptions.insert(client_id);
             // This should arguably crash the server, as it indicates a bug.
@@ -883,6 +1164,7 @@ impl SubscriptionManager {
                 new_queries.push(query.clone());
             }
         }
+        ci.report_subscriptions();
 
         #[cfg(test)]
         {
@@ -914,6 +1196,8 @@ impl SubscriptionManager {
         for unit in queries {
             let hash = unit.hash();
             ci.legacy_subscriptions.insert(hash);
+            ci.track_query(hash);
+            ci.older_rows_filters.remove(&hash);
             let query_state = self
                 .queries
                 .entry(hash)
@@ -927,6 +1211,7 @@ impl SubscriptionManager {
             );
             query_state.legacy_subscribers.insert(client_id);
         }
+        ci.report_subscriptions();
     }
 
     // Update the mapping from table id to related queries by removing the given query.
@@ -1002,6 +1287,7 @@ impl SubscriptionManager {
         };
 
         debug_assert!(client_info.legacy_subscriptions.is_empty());
+        client_info.outbound_ref.set_subscriptions(0);
         let mut queries_to_remove = Vec::new();
         for query_hash in client_info.subscription_ref_count.keys() {
             let Some(query_state) = self.queries.get_mut(query_hash) else {
@@ -1098,12 +1384,15 @@ impl SubscriptionManager {
     /// However, in order to optimize for the common case of small updates,
     /// we removed rayon and switched to a single-threaded execution,
     /// which removed significant overhead associated with thread switching.
+    ///
+    /// The updates are stamped with `committed_at`, when the transaction of `event` committed.
     #[tracing::instrument(level = "trace", skip_all)]
     pub fn eval_updates_sequential(
         &self,
         tx: &DeltaTx,
         event: Arc<ModuleEvent>,
         caller: Option<Arc<ClientConnectionSender>>,
+        committed_at: Timestamp,
     ) -> ExecutionMetrics {
         use FormatSwitch::{Bsatn, Json};
 
@@ -1115,6 +1404,7 @@ impl SubscriptionManager {
         struct FoldState {
             updates: Vec<ClientUpdate>,
             errs: Vec<(ClientId, Box<str>)>,
+            skipped: Vec<ClientId>,
             metrics: ExecutionMetrics,
         }
 
@@ -1139,7 +1429,12 @@ impl SubscriptionManager {
             })
         }
 
-        let FoldState { updates, errs, metrics } = tables
+        let FoldState {
+            updates,
+            errs,
+            skipped,
+            metrics,
+        } = tables
             .iter()
             .filter(|table| !table.inserts.is_empty() || !table.deletes.is_empty())
             .flat_map(|table_update| {
@@ -1189,12 +1484,13 @@ impl SubscriptionManager {
 
                 fn memo_encode<F: WebsocketFormat>(
                     updates: &UpdatesRelValue<'_>,
+                    projection: Option<&ColumnProjection>,
                     memory: &mut Option<(F::QueryUpdate, u64, usize)>,
                     metrics: &mut ExecutionMetrics,
                 ) -> SingleQueryUpdate<F> {
                     let (update, num_rows, num_bytes) = memory
                         .get_or_insert_with(|| {
-                            let encoded = updates.encode::<F>();
+                            let encoded = updates.encode::<F>(column_set(projection));
                             // The first time we insert into this map, we call encode.
                             // This is when we serialize the rows to BSATN/JSON.
                             // Hence this is where we increment `bytes_scanned`.
@@ -1210,8 +1506,35 @@ impl SubscriptionManager {
                     SingleQueryUpdate { update, num_rows }
                 }
 
+                fn encode<F: WebsocketFormat>(
+                    updates: &UpdatesRelValue<'_>,
+                    projection: Option<&ColumnProjection>,
+                    metrics: &mut ExecutionMetrics,
+                ) -> SingleQueryUpdate<F> {
+                    let (update, num_rows, num_bytes) = updates.encode::<F>(column_set(projection));
+                    metrics.bytes_scanned += num_bytes;
+                    metrics.bytes_sent_to_clients += num_bytes;
+                    SingleQueryUpdate { update, num_rows }
+                }
+
                 let clients_for_query = qstate.all_clients();
 
+                // Clients too far behind to accept updates miss this one, and are resynced once they catch up.
+                // Should none of the query's subscribers accept updates, the query isn't even evaluated.
+                let skip_update = |skipped: &mut Vec<ClientId>, id: &ClientId| {
+                    self.clients[id].skip_update(&qstate.query.hash);
+                    skipped.push(*id);
+                };
+                if !qstate
+                    .all_clients()
+                    .any(|id| self.clients[id].outbound_ref.accepts_updates())
+                {
+                    for id in clients_for_query {
+                        skip_update(&mut acc.skipped, id);
+                    }
+                    return acc;
+                }
+
                 match eval_delta(tx, &mut acc.metrics, plan) {
                     Err(err) => {
                         tracing::error!(
@@ -1232,26 +1555,69 @@ impl SubscriptionManager {
                     Ok(None) => {}
                     // The query did return updates - process them and add them to the accumulator
                     Ok(Some(delta_updates)) => {
-                        let row_iter = clients_for_query.map(|id| {
-                            let client = &self.clients[id].outbound_ref;
-                            let update = match client.config.protocol {
-                                Protocol::Binary => Bsatn(memo_encode::<BsatnFormat>(
-                                    &delta_updates,
-                                    &mut ops_bin_uncompressed,
-                                    &mut acc.metrics,
-                                )),
-                                Protocol::Text => Json(memo_encode::<JsonFormat>(
-                                    &delta_updates,
-                                    &mut ops_json,
-                                    &mut acc.metrics,
-                                )),
+                        let projection = plan.projection();
+                        // Queries which select only some columns leave out the rest,
+                        // and the updates which only touched those.
+                        let projected = projection.map(|projection| project_updates(projection, &delta_updates));
+                        let row_iter = clients_for_query.filter_map(|id| {
+                            let client_info = &self.clients[id];
+                            let client = &client_info.outbound_ref;
+                            if !client.accepts_updates() {
+                                skip_update(&mut acc.skipped, id);
+                                return None;
+                            }
+                            let (update, shared_query) = match client_info.older_rows_filters.get(&qstate.query.hash) {
+                                // Windowed subscriptions which leave out older rows get their own copy.
+                                // Their filter may read columns which aren't selected, so it goes first.
+                                Some(filter) => {
+                                    let mut updates = filter.apply(&delta_updates)?;
+                                    if let Some(projection) = projection {
+                                        updates = project_updates(projection, &updates)?;
+                                    }
+                                    let update = match client.config().protocol {
+                                        Protocol::Binary => {
+                                            Bsatn(encode::<BsatnFormat>(&updates, projection, &mut acc.metrics))
+                                        }
+                                        Protocol::Text => {
+                                            Json(encode::<JsonFormat>(&updates, projection, &mut acc.metrics))
+                                        }
+                                    };
+                                    (update, None)
+                                }
+                                None => {
+                                    let updates = match &projected {
+                                        Some(projected) => projected.as_ref()?,
+                                        None => &delta_updates,
+                                    };
+                                    let update = match client.config().protocol {
+                                        Protocol::Binary => Bsatn(memo_encode::<BsatnFormat>(
+                                            updates,
+                                            projection,
+                                            &mut ops_bin_uncompressed,
+                                            &mut acc.metrics,
+                                        )),
+                                        Protocol::Text => Json(memo_encode::<JsonFormat>(
+                                            updates,
+                                            projection,
+                                            &mut ops_json,
+                                            &mut acc.metrics,
+                                        )),
+                                    };
+                                    (update, Some(qstate.query.hash))
+                                }
+                            };
+                            let num_rows = match &update {
+                                Bsatn(update) => update.num_rows,
+                                Json(update) => update.num_rows,
                             };
-                            ClientUpdate {
+                            client_info.record_rows_delivered(&qstate.query.hash, num_rows);
+                            Some(ClientUpdate {
                                 id: *id,
                                 table_id,
                                 table_name: table_name.clone(),
                                 update,
-                            }
+                                shared_query,
+                            })
                         });
                         acc.updates.extend(row_iter);
                     }
@@ -1260,6 +1626,8 @@ impl SubscriptionManager {
                 acc
             });
 
+        drop(span);
+
         // We've now finished all of the work which needs to read from the datastore,
         // so get this work off the main thread and over to the `send_worker`,
         // then return ASAP in order to unlock the datastore and start running the next transaction.
@@ -1268,13 +1636,14 @@ impl SubscriptionManager {
             .send(SendWorkerMessage::Broadcast(ComputedQueries {
                 updates,
                 errs,
+                skipped,
                 event,
                 caller,
+                committed_at,
+                fanout_span: tracing::trace_span!("fanout"),
             }))
             .expect("send worker has panicked, or otherwise dropped its recv queue!");
 
-        drop(span);
-
         metrics
     }
 }
@@ -1286,6 +1655,8 @@ struct SendWorkerClient {
     /// [`Arc`]ed so that this can be updated by [`Self::run`]
     /// and observed by [`SubscriptionManager::remove_dropped_clients`].
     dropped: Arc<AtomicBool>,
+    /// The subscriptions the client is to resync once it catches up.
+    resync: Arc<PendingResync>,
     outbound_ref: Client,
 }
 
@@ -1333,6 +1704,24 @@ struct SendWorker {
     /// A map (re)used by [`SendWorker::send_one_computed_queries`]
     /// to avoid creating new allocations.
     table_updates_client_id: HashMap<ClientId, SwitchedDbUpdate>,
+
+    /// The node-wide budget of enqueues this worker fans updates out within.
+    fanout: Arc<FanoutBudget>,
+
+    /// `subscription_fanout_duration` metric labeled for this database's `Identity`,
+    /// if there is one, as for `queue_length_metric`.
+    fanout_duration_metric: Option<Histogram>,
+
+    /// The clients which missed updates while behind, and are yet to be told to resync.
+    resyncing: HashSet<ClientId>,
+
+    /// `subscription_updates_skipped` metric labeled for this database's `Identity`,
+    /// if there is one, as for `queue_length_metric`.
+    updates_skipped_metric: Option<IntCounter>,
+
+    /// `ws_send_backlog_actions` metric labeled for this database's `Identity` and the `resynced` action,
+    /// if there is one, as for `queue_length_metric`.
+    resynced_metric: Option<IntCounter>,
 }
 
 impl Drop for SendWorker {
@@ -1341,6 +1730,12 @@ impl Drop for SendWorker {
             let _ = WORKER_METRICS
                 .subscription_send_queue_length
                 .remove_label_values(&identity);
+            let _ = WORKER_METRICS
+                .subscription_fanout_duration
+                .remove_label_values(&identity);
+            let _ = WORKER_METRICS
+                .subscription_updates_skipped
+                .remove_label_values(&identity);
         }
     }
 }
@@ -1378,15 +1773,25 @@ impl BroadcastQueue {
         Ok(())
     }
 }
-pub fn spawn_send_worker(metric_database_identity: Option<Identity>) -> BroadcastQueue {
-    SendWorker::spawn_new(metric_database_identity)
+pub fn spawn_send_worker(metric_database_identity: Option<Identity>, fanout: Arc<FanoutBudget>) -> BroadcastQueue {
+    SendWorker::spawn_new(metric_database_identity, fanout)
 }
 impl SendWorker {
     fn new(
         rx: mpsc::UnboundedReceiver<SendWorkerMessage>,
         queue_length_metric: Option<IntGauge>,
         database_identity_to_clean_up_metric: Option<Identity>,
+        fanout: Arc<FanoutBudget>,
     ) -> Self {
+        let fanout_duration_metric = database_identity_to_clean_up_metric
+            .map(|identity| WORKER_METRICS.subscription_fanout_duration.with_label_values(&identity));
+        let updates_skipped_metric = database_identity_to_clean_up_metric
+            .map(|identity| WORKER_METRICS.subscription_updates_skipped.with_label_values(&identity));
+        let resynced_metric = database_identity_to_clean_up_metric.map(|identity| {
+            WORKER_METRICS
+                .ws_send_backlog_actions
+                .with_label_values(&identity, "resynced")
+        });
         Self {
             rx,
             queue_length_metric,
@@ -1394,20 +1799,25 @@ impl SendWorker {
             database_identity_to_clean_up_metric,
             table_updates_client_id_table_id: <_>::default(),
             table_updates_client_id: <_>::default(),
+            fanout,
+            fanout_duration_metric,
+            resyncing: <_>::default(),
+            updates_skipped_metric,
+            resynced_metric,
         }
     }
 
     // Spawn a new send worker.
     // If a `metric_database_identity` is provided, we will decrement the corresponding
     // `subscription_send_queue_length` metric, and clean it up on drop.
-    fn spawn_new(metric_database_identity: Option<Identity>) -> BroadcastQueue {
+    fn spawn_new(metric_database_identity: Option<Identity>, fanout: Arc<FanoutBudget>) -> BroadcastQueue {
         let metric = metric_database_identity.map(|identity| {
             WORKER_METRICS
                 .subscription_send_queue_length
                 .with_label_values(&identity)
         });
         let (send_worker_tx, rx) = mpsc::unbounded_channel();
-        tokio::spawn(Self::new(rx, metric.clone(), metric_database_identity).run());
+        tokio::spawn(Self::new(rx, metric.clone(), metric_database_identity, fanout).run());
         BroadcastQueue(SenderWithGauge::new(send_worker_tx, metric))
     }
 
@@ -1421,36 +1831,52 @@ impl SendWorker {
                 SendWorkerMessage::AddClient {
                     client_id,
                     dropped,
+                    resync,
                     outbound_ref,
                 } => {
-                    self.clients
-                        .insert(client_id, SendWorkerClient { dropped, outbound_ref });
+                    self.clients.insert(
+                        client_id,
+                        SendWorkerClient {
+                            dropped,
+                            resync,
+                            outbound_ref,
+                        },
+                    );
                 }
                 SendWorkerMessage::SendMessage { recipient, message } => {
-                    let _ = recipient.send_message(message);
+                    send_to_client(&recipient, message);
                 }
                 SendWorkerMessage::RemoveClient(client_id) => {
                     self.clients.remove(&client_id);
+                    self.resyncing.remove(&client_id);
                 }
                 SendWorkerMessage::Broadcast(queries) => {
-                    self.send_one_computed_queries(queries);
+                    let span = queries.fanout_span.clone();
+                    self.send_one_computed_queries(queries).instrument(span).await;
                 }
             }
         }
     }
 
-    fn send_one_computed_queries(
+    async fn send_one_computed_queries(
         &mut self,
         ComputedQueries {
             updates,
             errs,
+            skipped,
             event,
             caller,
+            committed_at,
+            fanout_span: _,
         }: ComputedQueries,
     ) {
         use FormatSwitch::{Bsatn, Json};
 
+        // Clients which caught up since missing updates are told to resync before they're sent newer ones.
+        self.resync_caught_up_clients(skipped);
+
         let clients_with_errors = errs.iter().map(|(id, _)| id).collect::<HashSet<_>>();
+        let mut shared = SharedEncodings::default();
 
         let span = tracing::info_span!("eval_incr_group_messages_by_client");
 
@@ -1469,6 +1895,7 @@ impl SendWorker {
             .filter(|upd| !self.is_client_dropped_or_cancelled(&upd.id))
             // Filter out clients whose subscriptions failed
             .filter(|upd| !clients_with_errors.contains(&upd.id))
+            .inspect(|upd| shared.record(upd))
             // Do the aggregation.
             .fold(client_table_id_updates, |mut tables, upd| {
                 match tables.entry((upd.id, upd.table_id)) {
@@ -1506,38 +1933,74 @@ impl SendWorker {
         drop(clients_with_errors);
         drop(span);
 
-        let _span = tracing::info_span!("eval_send").entered();
+        let span = tracing::info_span!("eval_send");
 
         // We might have a known caller that hasn't been hidden from here..
         // This caller may have subscribed to some query.
         // If they haven't, we'll send them an empty update.
-        // Regardless, the update that we send to the caller, if we send any,
-        // is a full tx update, rather than a light one.
-        // That is, in the case of the caller, we don't respect the light setting.
+        // Regardless, the caller is sent a reply reporting the outcome of their call,
+        // even if they asked for light updates.
+        // The reply is sent outside of the fan-out budget,
+        // so that callers aren't delayed by the number of subscribers.
         if let Some(caller) = caller {
+            let _span = span.enter();
             let caller_id = (caller.id.identity, caller.id.connection_id);
             let database_update = client_id_updates
                 .remove(&caller_id)
-                .map(|update| SubscriptionUpdateMessage::from_event_and_update(&event, update))
-                .unwrap_or_else(|| {
-                    SubscriptionUpdateMessage::default_for_protocol(caller.config.protocol, event.request_id)
-                });
-            let message = TransactionUpdateMessage {
-                event: Some(event.clone()),
+                .map(|update| SubscriptionUpdateMessage::from_event_and_update(&event, update));
+            let acknowledged = must_acknowledge(&caller, &event);
+            let config = caller.config();
+            let message = TransactionUpdateMessage::reply(
+                event.clone(),
+                committed_at,
+                config.protocol,
+                UpdateDetail::for_reply(&config),
                 database_update,
-            };
-            send_to_client(&caller, message);
+            );
+            send_update_to_client(&caller, message, acknowledged);
         }
 
-        // Send all the other updates.
-        for (id, update) in client_id_updates.drain() {
-            let database_update = SubscriptionUpdateMessage::from_event_and_update(&event, update);
-            let client = self.clients[&id].outbound_ref.clone();
-            // Conditionally send out a full update or a light one otherwise.
-            let event = client.config.tx_update_full.then(|| event.clone());
-            let message = TransactionUpdateMessage { event, database_update };
-            send_to_client(&client, message);
+        // Clients subscribed to the same queries, with the same config, are sent the same update,
+        // so they share its encoding, rather than each of them encoding it anew,
+        // unless their codec encodes it as no other's does, e.g. encrypting it.
+        for id in client_id_updates.keys() {
+            let client = &self.clients[id].outbound_ref;
+            if !must_acknowledge(client, &event) && client.shares_encodings() {
+                shared.group(*id, client.config());
+            }
+        }
+
+        // Send all the other updates, in batches as the node's fan-out budget allows.
+        // Updates are only ever sent by this worker, in commit order,
+        // so waiting on the budget doesn't reorder any client's updates.
+        let backlog = &WORKER_METRICS.subscription_fanout_backlog;
+        backlog.add(client_id_updates.len() as i64);
+        let mut pending = client_id_updates.drain();
+        while pending.len() > 0 {
+            let batch = self.fanout.acquire(pending.len()).await;
+            let _span = span.enter();
+            for (id, update) in pending.by_ref().take(batch) {
+                let database_update = SubscriptionUpdateMessage::from_event_and_update(&event, update);
+                let client = self.clients[&id].outbound_ref.clone();
+                // Conditionally send out a full update or a light one otherwise.
+                // An acknowledged update is always a full one.
+                let acknowledged = must_acknowledge(&client, &event);
+                // The client may have changed its settings since it was grouped,
+                // in which case it no longer shares its group's encoding.
+                let config = client.config();
+                let event = (config.tx_update_full || acknowledged).then(|| event.clone());
+                let message = TransactionUpdateMessage {
+                    event,
+                    database_update,
+                    committed_at,
+                    detail: UpdateDetail::for_client(&config),
+                    shared: shared.get(&id, &config),
+                };
+                send_update_to_client(&client, message, acknowledged);
+            }
+            backlog.sub(batch as i64);
         }
+        drop(pending);
 
         // Put back the aggregation maps into the worker.
         self.table_updates_client_id_table_id = client_table_id_updates;
@@ -1561,12 +2024,128 @@ impl SendWorker {
                 );
             }
         }
+
+        if let Some(metric) = &self.fanout_duration_metric {
+            let elapsed = Timestamp::now().duration_since(committed_at).unwrap_or_default();
+            metric.observe(elapsed.as_secs_f64());
+        }
+    }
+}
+
+/// The encodings of a transaction's updates shared by the clients sent the same copy of them,
+/// i.e. the same updates of the same queries, with the same [`ClientConfig`], see [`SharedEncoding`].
+///
+/// Only clients whose codec [shares encodings](crate::client::ProtocolCodec::shares_encodings) are grouped.
+#[derive(Default)]
+struct SharedEncodings {
+    /// The queries each client's update is made of, in order,
+    /// or `None` should any of it be the client's own.
+    queries: HashMap<ClientId, Option<Vec<QueryHash>>>,
+    /// The group of clients sent the same update each client is in, and its config when grouped.
+    groups_by_client: HashMap<ClientId, (usize, ClientConfig)>,
+    groups: HashMap<(Vec<QueryHash>, ClientConfig), usize>,
+    /// The number of clients in each group, and the encoding they share.
+    encodings: Vec<(usize, SharedEncoding)>,
+}
+
+impl SharedEncodings {
+    /// Record that `upd` is part of its client's update.
+    fn record(&mut self, upd: &ClientUpdate) {
+        let queries = self.queries.entry(upd.id).or_insert_with(|| Some(Vec::new()));
+        match (queries, upd.shared_query) {
+            (Some(queries), Some(hash)) => queries.push(hash),
+            (queries, _) => *queries = None,
+        }
+    }
+
+    /// Put the client `id`, with `config`, in the group of clients sent the same update,
+    /// unless any of its update is its own.
+    fn group(&mut self, id: ClientId, config: ClientConfig) {
+        let Some(Some(queries)) = self.queries.remove(&id) else {
+            return;
+        };
+        let next = self.encodings.len();
+        let group = *self.groups.entry((queries, config)).or_insert(next);
+        if group == next {
+            self.encodings.push((0, SharedEncoding::default()));
+        }
+        self.encodings[group].0 += 1;
+        self.groups_by_client.insert(id, (group, config));
+    }
+
+    /// The encoding the update of client `id` shares with the rest of its group, if the group has a rest,
+    /// and the client's `config` is still the one it was grouped with.
+    fn get(&self, id: &ClientId, config: &ClientConfig) -> Option<SharedEncoding> {
+        let (group, grouped_with) = self.groups_by_client.get(id)?;
+        let (clients, encoding) = &self.encodings[*group];
+        (*clients > 1 && grouped_with == config).then(|| encoding.clone())
+    }
+}
+
+impl SendWorker {
+    /// Tell the clients which missed updates while behind, those which just did among them,
+    /// and have since caught up, which of their subscriptions to resync.
+    ///
+    /// Clients are checked as each transaction is broadcast,
+    /// so a client which catches up is told to resync along with the next transaction's updates.
+    fn resync_caught_up_clients(&mut self, skipped: Vec<ClientId>) {
+        if let Some(metric) = &self.updates_skipped_metric {
+            metric.inc_by(skipped.len() as u64);
+        }
+        self.resyncing.extend(skipped);
+        let (clients, resynced_metric) = (&self.clients, &self.resynced_metric);
+        self.resyncing.retain(|id| {
+            let Some(client) = clients.get(id) else {
+                return false;
+            };
+            if !client.outbound_ref.accepts_updates() {
+                return true;
+            }
+            if let Some(notice) = client.resync.take() {
+                send_to_client(&client.outbound_ref, notice);
+                if let Some(metric) = resynced_metric {
+                    metric.inc();
+                }
+            }
+            false
+        });
     }
 }
 
 fn send_to_client(client: &ClientConnectionSender, message: impl Into<SerializableMessage>) {
     if let Err(e) = client.send_message(message) {
-        tracing::warn!(%client.id, "failed to send update message to client: {e}")
+        log_send_error(client, e, "failed to send update message to client")
+    }
+}
+
+/// Log that a message couldn't be sent to `client`, because of `e`.
+///
+/// Clients which have disconnected, or are being disconnected, are still in the worker's subscriptions
+/// until their removal is processed, so only the client being kicked is worth a warning.
+fn log_send_error(client: &ClientConnectionSender, e: ClientSendError, context: &str) {
+    if e.is_queue_full() {
+        tracing::warn!(%client.id, "{context}: {e}")
+    } else {
+        tracing::debug!(%client.id, "{context}: {e}")
+    }
+}
+
+/// Whether `client` must acknowledge the update of `event`,
+/// i.e. it opted into acknowledged delivery and `event` committed an acknowledged reducer.
+fn must_acknowledge(client: &ClientConnectionSender, event: &ModuleEvent) -> bool {
+    client.config().acknowledged_delivery && event.acknowledged && matches!(event.status, EventStatus::Committed(_))
+}
+
+fn send_update_to_client(client: &ClientConnectionSender, message: TransactionUpdateMessage, acknowledged: bool) {
+    if !acknowledged {
+        return send_to_client(client, message);
+    }
+    if let Err(e) = client.send_acknowledged(message) {
+        log_send_error(
+            client,
+            e,
+            "failed to send acknowledged update to client, it remains pending",
+        )
     }
 }
 
@@ -1703,6 +2282,38 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_subscription_count_is_reported_to_the_client() -> ResultTest<()> {
+        let db = TestDB::durable()?;
+
+        create_table(&db, "T")?;
+        create_table(&db, "U")?;
+        let plan_t = compile_plan(&db, "select * from T")?;
+        let plan_u = compile_plan(&db, "select * from U")?;
+
+        let client = Arc::new(client(0));
+        let client_id = (client.id.identity, client.id.connection_id);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _rt = runtime.enter();
+
+        let mut subscriptions = SubscriptionManager::for_test_without_metrics();
+        subscriptions.add_subscription_multi(client.clone(), vec![plan_t.clone(), plan_u.clone()], QueryId::new(1))?;
+        subscriptions.add_subscription(client.clone(), plan_t.clone(), QueryId::new(2))?;
+        assert_eq!(client.subscriptions(), 2);
+
+        subscriptions.set_legacy_subscription(client.clone(), [plan_t, plan_u]);
+        assert_eq!(client.subscriptions(), 4);
+
+        subscriptions.remove_subscription(client_id, QueryId::new(1))?;
+        assert_eq!(client.subscriptions(), 3);
+
+        subscriptions.remove_all_subscriptions(&client_id);
+        assert_eq!(client.subscriptions(), 0);
+
+        Ok(())
+    }
+
     #[test]
     fn test_unsubscribe_with_unknown_query_id_fails() -> ResultTest<()> {
         let db = TestDB::durable()?;
@@ -2439,10 +3050,12 @@ mod tests {
             host_execution_duration: Duration::default(),
             request_id: None,
             timer: None,
+            acknowledged: false,
+            trace_id: None,
         });
 
         db.with_read_only(Workload::Update, |tx| {
-            subscriptions.eval_updates_sequential(&(&*tx).into(), event, Some(Arc::new(client0)))
+            subscriptions.eval_updates_sequential(&(&*tx).into(), event, Some(Arc::new(client0)), Timestamp::now())
         });
 
         runtime.block_on(async move {
@@ -0,0 +1,132 @@
+//! Subscriptions which select a list of columns rather than whole rows,
+//! e.g. `SELECT id, name FROM player`,
+//! and are sent only those columns, as described by a [`ColumnProjection`].
+
+use crate::host::module_host::UpdatesRelValue;
+use hashbrown::HashMap;
+use spacetimedb_lib::ProductValue;
+use spacetimedb_subscription::ColumnProjection;
+use spacetimedb_vm::relation::RelValue;
+
+/// The column set sent with the rows of a query in a `QueryUpdate`,
+/// i.e. the positions of the columns selected by `projection`,
+/// or an empty list if the query selects whole rows.
+pub fn column_set(projection: Option<&ColumnProjection>) -> Box<[u16]> {
+    projection
+        .map(|projection| projection.columns().iter().map(|col| col.0).collect())
+        .unwrap_or_default()
+}
+
+/// `updates` with only the columns selected by `projection`,
+/// or `None` if that leaves nothing to send.
+///
+/// An update which changes only columns that aren't selected
+/// deletes and inserts the same projected row, so both are left out.
+pub fn project_updates<'a>(
+    projection: &ColumnProjection,
+    updates: &UpdatesRelValue<'_>,
+) -> Option<UpdatesRelValue<'a>> {
+    let project = |rows: &[RelValue<'_>]| {
+        rows.iter()
+            .map(|row| projection.project(row.clone().into_product_value()))
+            .collect::<Vec<_>>()
+    };
+    let mut deletes = project(&updates.deletes);
+    let mut inserts = project(&updates.inserts);
+
+    // Rows are a bag, so cancel out each delete with at most one insert of the same row.
+    let mut deleted = HashMap::<ProductValue, usize>::new();
+    for row in &deletes {
+        *deleted.entry(row.clone()).or_default() += 1;
+    }
+    let mut unchanged = HashMap::<ProductValue, usize>::new();
+    inserts.retain(|row| match deleted.get_mut(row) {
+        Some(n) if *n > 0 => {
+            *n -= 1;
+            *unchanged.entry(row.clone()).or_default() += 1;
+            false
+        }
+        _ => true,
+    });
+    deletes.retain(|row| match unchanged.get_mut(row) {
+        Some(n) if *n > 0 => {
+            *n -= 1;
+            false
+        }
+        _ => true,
+    });
+
+    let projected = UpdatesRelValue {
+        deletes: deletes.into_iter().map(RelValue::Projection).collect(),
+        inserts: inserts.into_iter().map(RelValue::Projection).collect(),
+    };
+    projected.has_updates().then_some(projected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spacetimedb_lib::AlgebraicType;
+    use spacetimedb_primitives::{ColId, ColSet};
+    use spacetimedb_sats::product;
+
+    /// Selects the `id` and `name` of `(id: u64, name: String, blob: Vec<u8>)` rows.
+    fn projection() -> ColumnProjection {
+        let types = [AlgebraicType::U64, AlgebraicType::String, AlgebraicType::bytes()];
+        ColumnProjection::new([ColId(0), ColId(1)].into_iter().collect::<ColSet>(), types.iter())
+    }
+
+    fn row(id: u64, name: &str, blob: &[u8]) -> RelValue<'static> {
+        RelValue::Projection(product![id, name, blob])
+    }
+
+    #[test]
+    fn unselected_columns_are_sent_as_placeholders() {
+        let updates = UpdatesRelValue {
+            deletes: vec![],
+            inserts: vec![row(1, "alice", &[7; 2048])],
+        };
+        let projected = project_updates(&projection(), &updates).unwrap();
+        assert_eq!(projected.inserts, [row(1, "alice", &[])]);
+        assert_eq!(column_set(Some(&projection())), [0, 1].into());
+        assert!(column_set(None).is_empty());
+    }
+
+    #[test]
+    fn updates_to_unselected_columns_are_suppressed() {
+        let updates = UpdatesRelValue {
+            deletes: vec![row(1, "alice", &[1]), row(2, "bob", &[2])],
+            inserts: vec![row(1, "alice", &[3]), row(2, "carol", &[2])],
+        };
+        let projected = project_updates(&projection(), &updates).unwrap();
+        assert_eq!(projected.deletes, [row(2, "bob", &[])]);
+        assert_eq!(projected.inserts, [row(2, "carol", &[])]);
+
+        let blob_only = UpdatesRelValue {
+            deletes: vec![row(1, "alice", &[1])],
+            inserts: vec![row(1, "alice", &[3])],
+        };
+        assert!(project_updates(&projection(), &blob_only).is_none());
+    }
+
+    #[test]
+    fn deletes_match_the_rows_delivered() {
+        let delivered = project_updates(
+            &projection(),
+            &UpdatesRelValue {
+                deletes: vec![],
+                inserts: vec![row(1, "alice", &[1])],
+            },
+        )
+        .unwrap();
+        let deleted = project_updates(
+            &projection(),
+            &UpdatesRelValue {
+                deletes: vec![row(1, "alice", &[3])],
+                inserts: vec![],
+            },
+        )
+        .unwrap();
+        assert_eq!(deleted.deletes, delivered.inserts);
+    }
+}
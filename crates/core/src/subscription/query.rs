@@ -1,6 +1,6 @@
 use crate::db::relational_db::{RelationalDB, Tx};
 use crate::error::{DBError, SubscriptionError};
-use crate::sql::ast::SchemaViewer;
+use crate::sql::ast::{record_query_limit_rejection, SchemaViewer};
 use crate::sql::compiler::compile_sql;
 use crate::subscription::subscription::SupportedQuery;
 use once_cell::sync::Lazy;
@@ -104,8 +104,10 @@ pub fn compile_query_with_hashes(
         return Err(SubscriptionError::Empty.into());
     }
 
+    let database_identity = tx.ctx.database_identity();
     let tx = SchemaViewer::new(tx, auth);
-    let (plans, has_param) = SubscriptionPlan::compile(input, &tx, auth)?;
+    let (plans, has_param) = SubscriptionPlan::compile(input, &tx, auth)
+        .inspect_err(|err| record_query_limit_rejection(&database_identity, "subscription", err))?;
 
     if auth.is_owner() || has_param {
         // Note that when generating hashes for queries from owners,
@@ -146,6 +148,7 @@ pub fn classify(expr: &QueryExpr) -> Option<Supported> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::datastore::system_tables::StVarName;
     use crate::db::relational_db::tests_utils::{
         begin_mut_tx, begin_tx, insert, with_auto_commit, with_read_only, TestDB,
     };
@@ -159,6 +162,7 @@ mod tests {
     use crate::subscription::tx::DeltaTx;
     use crate::vm::tests::create_table_with_rows;
     use crate::vm::DbProgram;
+    use crate::worker_metrics::WORKER_METRICS;
     use itertools::Itertools;
     use spacetimedb_client_api_messages::websocket::{BsatnFormat, Compression};
     use spacetimedb_lib::bsatn;
@@ -420,6 +424,40 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_query_limits() -> ResultTest<()> {
+        let db = TestDB::durable()?;
+
+        let schema = &[("n", AlgebraicType::U64), ("data", AlgebraicType::U64)];
+        db.create_table_for_test("a", schema, &[])?;
+        db.create_table_for_test("b", schema, &[])?;
+        with_auto_commit(&db, |tx| db.write_var(tx, StVarName::MaxQueryJoins, "0"))?;
+
+        let compile = |sql| {
+            let tx = begin_tx(&db);
+            let hash = QueryHash::from_string(sql, Identity::ZERO, false);
+            compile_query_with_hashes(&AuthCtx::for_testing(), &tx, sql, hash, hash)
+        };
+        let rejections = || {
+            WORKER_METRICS
+                .query_limit_rejections
+                .with_label_values(&db.database_identity(), "subscription", "joins")
+                .get()
+        };
+
+        let rejected = rejections();
+        let err = compile("SELECT b.* FROM b JOIN a ON b.n = a.n")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Query has 1 joins, exceeding the limit `max_query_joins` of 0"),
+            "{err}"
+        );
+        assert_eq!(rejections(), rejected + 1);
+        assert!(compile("SELECT * FROM b WHERE data > 200").is_ok());
+        Ok(())
+    }
+
     #[test]
     fn test_eval_incr_for_index_scan() -> ResultTest<()> {
         let db = TestDB::durable()?;
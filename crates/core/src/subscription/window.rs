@@ -0,0 +1,237 @@
+//! Windowed subscriptions, which are initially sent only the newest rows of their query,
+//! as requested by a [`SubscribeWindow`](spacetimedb_client_api_messages::websocket::SubscribeWindow) message.
+
+use crate::host::module_host::UpdatesRelValue;
+use crate::subscription::projection::column_set;
+use anyhow::Result;
+use spacetimedb_client_api_messages::websocket::{
+    ByteListLen, Compression, InitialWindow, OlderRows, QueryUpdate, SingleQueryUpdate, TableUpdate, WebsocketFormat,
+};
+use spacetimedb_execution::{pipelined::PipelinedProject, Datastore, DeltaStore};
+use spacetimedb_lib::metrics::ExecutionMetrics;
+use spacetimedb_primitives::{ColId, TableId};
+use spacetimedb_sats::AlgebraicValue;
+use spacetimedb_schema::schema::TableSchema;
+use spacetimedb_subscription::ColumnProjection;
+use spacetimedb_vm::relation::RelValue;
+
+/// Why a windowed subscription couldn't be planned.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum WindowError {
+    #[error("a window must hold at least one row")]
+    Empty,
+    #[error("cannot order `{table}` by `{column}`: no such column")]
+    NoSuchColumn { table: Box<str>, column: Box<str> },
+    #[error("cannot order `{table}` by `{column}`: the column is not indexed, add an index on `{column}` to subscribe to its last rows")]
+    MissingIndex { table: Box<str>, column: Box<str> },
+}
+
+/// An [`InitialWindow`] resolved against the table returned by its query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    col_id: ColId,
+    limit: usize,
+}
+
+impl Window {
+    /// Resolve `initial` against the `schema` of the table returned by the query.
+    ///
+    /// The column to order by must be the first column of some index of the table,
+    /// so that paging back through older rows with one-off queries is cheap.
+    pub fn resolve(schema: &TableSchema, initial: &InitialWindow) -> Result<Self, WindowError> {
+        let names = || (schema.table_name.clone(), initial.order_by.clone());
+        if initial.limit == 0 {
+            return Err(WindowError::Empty);
+        }
+        let Some(col_id) = schema.get_column_id_by_name(&initial.order_by) else {
+            let (table, column) = names();
+            return Err(WindowError::NoSuchColumn { table, column });
+        };
+        let indexed = schema
+            .indexes
+            .iter()
+            .any(|index| index.index_algorithm.columns().iter().next() == Some(col_id));
+        if !indexed {
+            let (table, column) = names();
+            return Err(WindowError::MissingIndex { table, column });
+        }
+        Ok(Self {
+            col_id,
+            limit: initial.limit as usize,
+        })
+    }
+
+    fn key(&self, row: &RelValue<'_>) -> AlgebraicValue {
+        row.read_column(self.col_id.idx())
+            .expect("windows are resolved against the schema of the rows they order")
+            .into_owned()
+    }
+
+    /// Keep only the newest rows of `rows`, ordering them newest first.
+    ///
+    /// Returns the value of the window's column for the oldest row kept,
+    /// if any rows were left out.
+    fn select<'a>(&self, rows: Vec<RelValue<'a>>) -> (Vec<RelValue<'a>>, Option<AlgebraicValue>) {
+        let mut keyed = rows.into_iter().map(|row| (self.key(&row), row)).collect::<Vec<_>>();
+        let newest_first = |(a, _): &(AlgebraicValue, _), (b, _): &(AlgebraicValue, _)| b.cmp(a);
+        let truncated = keyed.len() > self.limit;
+        if truncated {
+            keyed.select_nth_unstable_by(self.limit - 1, newest_first);
+            keyed.truncate(self.limit);
+        }
+        keyed.sort_by(newest_first);
+        let oldest = keyed.last().filter(|_| truncated).map(|(key, _)| key.clone());
+        (keyed.into_iter().map(|(_, row)| row).collect(), oldest)
+    }
+
+    /// Execute a subscription query and collect the rows in the window in a [`TableUpdate`],
+    /// with only the columns selected by `projection`, if any.
+    ///
+    /// Also returns the filter to apply to later updates for the client,
+    /// if it asked for updates to rows older than the window to be suppressed,
+    /// and the query has such rows.
+    pub fn collect_table_update<Tx, F>(
+        &self,
+        plan_fragments: &[PipelinedProject],
+        table_id: TableId,
+        table_name: Box<str>,
+        projection: Option<&ColumnProjection>,
+        tx: &Tx,
+        older_rows: OlderRows,
+    ) -> Result<(TableUpdate<F>, WindowBounds, ExecutionMetrics)>
+    where
+        Tx: Datastore + DeltaStore,
+        F: WebsocketFormat,
+    {
+        let mut rows = vec![];
+        let mut metrics = ExecutionMetrics::default();
+        for fragment in plan_fragments {
+            fragment.execute(tx, &mut metrics, &mut |row| {
+                rows.push(row.into());
+                Ok(())
+            })?;
+        }
+        let (rows, oldest) = self.select(rows);
+
+        let (inserts, num_rows) = match projection {
+            None => F::encode_list(rows.iter()),
+            Some(projection) => {
+                F::encode_list(rows.into_iter().map(|row| projection.project(row.into_product_value())))
+            }
+        };
+        metrics.bytes_scanned += inserts.num_bytes();
+        metrics.bytes_sent_to_clients += inserts.num_bytes();
+        let update = F::into_query_update(
+            QueryUpdate {
+                deletes: F::List::default(),
+                inserts,
+                columns: column_set(projection),
+            },
+            Compression::None,
+        );
+        let bounds = WindowBounds {
+            has_older_rows: oldest.is_some(),
+            filter: oldest
+                .filter(|_| older_rows == OlderRows::Suppress)
+                .map(|oldest| OlderRowsFilter {
+                    col_id: self.col_id,
+                    oldest,
+                }),
+        };
+        Ok((
+            TableUpdate::new(table_id, table_name, SingleQueryUpdate { update, num_rows }),
+            bounds,
+            metrics,
+        ))
+    }
+}
+
+/// What lies outside of the window of a windowed subscription.
+#[derive(Debug, Clone)]
+pub struct WindowBounds {
+    /// Whether the query matched rows older than those in the window.
+    pub has_older_rows: bool,
+    /// Leaves out the updates to those rows, if the client asked for that.
+    pub filter: Option<OlderRowsFilter>,
+}
+
+/// Leaves out updates to the rows older than the window of a subscription,
+/// i.e. those ordered before the oldest row the client was initially sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OlderRowsFilter {
+    col_id: ColId,
+    oldest: AlgebraicValue,
+}
+
+impl OlderRowsFilter {
+    fn is_older(&self, row: &RelValue<'_>) -> bool {
+        row.read_column(self.col_id.idx())
+            .is_some_and(|value| *value < self.oldest)
+    }
+
+    /// `updates` without the rows older than the window,
+    /// or `None` if that leaves nothing to send.
+    pub fn apply<'a>(&self, updates: &UpdatesRelValue<'a>) -> Option<UpdatesRelValue<'a>> {
+        let keep = |rows: &[RelValue<'a>]| rows.iter().filter(|row| !self.is_older(row)).cloned().collect();
+        let kept = UpdatesRelValue {
+            deletes: keep(&updates.deletes),
+            inserts: keep(&updates.inserts),
+        };
+        kept.has_updates().then_some(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spacetimedb_sats::product;
+
+    fn rows(keys: &[u64]) -> Vec<RelValue<'static>> {
+        keys.iter().map(|&key| RelValue::Projection(product![key])).collect()
+    }
+
+    fn keys(rows: &[RelValue<'_>]) -> Vec<u64> {
+        let window = Window {
+            col_id: 0.into(),
+            limit: 1,
+        };
+        rows.iter()
+            .map(|row| window.key(row).as_u64().copied().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn windows_keep_the_newest_rows() {
+        let window = Window {
+            col_id: 0.into(),
+            limit: 3,
+        };
+        let (kept, oldest) = window.select(rows(&[4, 9, 1, 7, 3, 8]));
+        assert_eq!(keys(&kept), [9, 8, 7]);
+        assert_eq!(oldest, Some(AlgebraicValue::U64(7)));
+
+        let (kept, oldest) = window.select(rows(&[2, 5, 1]));
+        assert_eq!(keys(&kept), [5, 2, 1]);
+        assert_eq!(oldest, None);
+    }
+
+    #[test]
+    fn filters_leave_out_older_rows() {
+        let filter = OlderRowsFilter {
+            col_id: 0.into(),
+            oldest: AlgebraicValue::U64(7),
+        };
+        let updates = UpdatesRelValue {
+            deletes: rows(&[3, 7]),
+            inserts: rows(&[6, 10]),
+        };
+        let kept = filter.apply(&updates).unwrap();
+        assert_eq!((keys(&kept.deletes), keys(&kept.inserts)), (vec![7], vec![10]));
+
+        let older = UpdatesRelValue {
+            deletes: rows(&[1]),
+            inserts: rows(&[2]),
+        };
+        assert!(filter.apply(&older).is_none());
+    }
+}
@@ -0,0 +1,334 @@
+//! Metric series of individual websocket clients, kept to a bounded number.
+//!
+//! Labeling series by client identity would create a series per client which ever connected,
+//! so only some clients get series of their own:
+//! those on their database's allowlist, set by its owner with `SET client_metrics_allowlist`,
+//! and a random sample of the others, up to a node-wide cap.
+//! Every client is still counted in the series of its database.
+//! The series of a client are removed a while after its last connection is gone.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use prometheus::{Gauge, IntCounter, IntGauge};
+use spacetimedb_lib::Identity;
+
+use super::WORKER_METRICS;
+
+/// Which clients get series of their own, and for how long.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientSeriesConfig {
+    /// The fraction of the clients not on their database's allowlist which get series, from 0 to 1.
+    pub sample_rate: f64,
+    /// How many sampled clients may have series at once, over all databases.
+    ///
+    /// Allowlisted clients don't count against the cap, being bounded by their databases' allowlists.
+    pub max_sampled_clients: usize,
+    /// How long the series of a client are kept after its last connection is gone.
+    pub ttl: Duration,
+}
+
+impl Default for ClientSeriesConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 0.0,
+            max_sampled_clients: 1000,
+            ttl: Duration::from_secs(300),
+        }
+    }
+}
+
+/// A client, by the database it's connected to and its identity.
+type ClientKey = (Identity, Identity);
+
+/// Decides which clients get series of their own, and removes them once expired.
+#[derive(Debug, Default)]
+pub struct ClientSeriesSampler {
+    config: ClientSeriesConfig,
+    state: Mutex<SamplerState>,
+}
+
+#[derive(Debug, Default)]
+struct SamplerState {
+    clients: HashMap<ClientKey, ClientEntry>,
+    /// The clients without live connections, by when their series expire.
+    expiring: VecDeque<(Instant, ClientKey)>,
+    /// How many of `clients` were sampled rather than allowlisted.
+    sampled: usize,
+}
+
+#[derive(Debug)]
+struct ClientEntry {
+    /// How many connections of the client hold its series.
+    live: usize,
+    /// When the last connection of the client went away, if none are live.
+    released_at: Option<Instant>,
+    sampled: bool,
+}
+
+impl ClientSeriesSampler {
+    pub fn new(config: ClientSeriesConfig) -> Self {
+        Self {
+            config,
+            state: <_>::default(),
+        }
+    }
+
+    /// The series of `client_identity`'s connection to `database_identity`,
+    /// if the client is `allowlisted`, sampled, or already has series.
+    pub fn admit(
+        self: &Arc<Self>,
+        database_identity: Identity,
+        client_identity: Identity,
+        allowlisted: bool,
+    ) -> Option<ClientSeries> {
+        let sampled = || rand::random::<f64>() < self.config.sample_rate;
+        self.admit_at(
+            Instant::now(),
+            (database_identity, client_identity),
+            allowlisted,
+            sampled,
+        )
+    }
+
+    fn admit_at(
+        self: &Arc<Self>,
+        now: Instant,
+        key: ClientKey,
+        allowlisted: bool,
+        sampled: impl FnOnce() -> bool,
+    ) -> Option<ClientSeries> {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        state.sweep(now, self.config.ttl);
+        match state.clients.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let entry = entry.get_mut();
+                entry.live += 1;
+                entry.released_at = None;
+            }
+            Entry::Vacant(entry) => {
+                let sampled = !allowlisted && state.sampled < self.config.max_sampled_clients && sampled();
+                if !allowlisted && !sampled {
+                    return None;
+                }
+                entry.insert(ClientEntry {
+                    live: 1,
+                    released_at: None,
+                    sampled,
+                });
+                state.sampled += sampled as usize;
+                WORKER_METRICS.ws_client_series_clients.set(state.clients.len() as i64);
+            }
+        }
+        drop(guard);
+        Some(ClientSeries::new(self.clone(), key))
+    }
+
+    /// Remove the series of the clients which expired.
+    pub fn sweep(&self) {
+        self.state.lock().sweep(Instant::now(), self.config.ttl);
+    }
+
+    /// How many clients have series.
+    pub fn len(&self) -> usize {
+        self.state.lock().clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn release(&self, key: ClientKey, now: Instant) {
+        let mut state = self.state.lock();
+        let Some(entry) = state.clients.get_mut(&key) else {
+            return;
+        };
+        entry.live -= 1;
+        if entry.live == 0 {
+            entry.released_at = Some(now);
+            state.expiring.push_back((now + self.config.ttl, key));
+        }
+    }
+}
+
+impl SamplerState {
+    fn sweep(&mut self, now: Instant, ttl: Duration) {
+        while let Some(&(expires, key)) = self.expiring.front() {
+            if expires > now {
+                break;
+            }
+            self.expiring.pop_front();
+            // Unless the client has connected again since, or went away again later.
+            let Entry::Occupied(entry) = self.clients.entry(key) else {
+                continue;
+            };
+            if entry.get().released_at.is_none_or(|at| at + ttl > now) {
+                continue;
+            }
+            self.sampled -= entry.remove().sampled as usize;
+            remove_series(key);
+        }
+        WORKER_METRICS.ws_client_series_clients.set(self.clients.len() as i64);
+    }
+}
+
+fn remove_series((database_identity, client_identity): ClientKey) {
+    let labels = (&database_identity, &client_identity);
+    let _ = WORKER_METRICS
+        .ws_client_bytes_sent
+        .remove_label_values(labels.0, labels.1);
+    let _ = WORKER_METRICS
+        .ws_client_bytes_received
+        .remove_label_values(labels.0, labels.1);
+    let _ = WORKER_METRICS
+        .ws_client_round_trip_seconds
+        .remove_label_values(labels.0, labels.1);
+    let _ = WORKER_METRICS
+        .ws_client_protocol_violations
+        .remove_label_values(labels.0, labels.1);
+    let _ = WORKER_METRICS
+        .ws_client_outgoing_queue_length
+        .remove_label_values(labels.0, labels.1);
+}
+
+/// The series of a client admitted by a [`ClientSeriesSampler`],
+/// held by one of its connections, which releases them once dropped.
+///
+/// The connections of a client share its series.
+#[derive(Debug)]
+pub struct ClientSeries {
+    /// Bytes of messages sent to the client.
+    pub bytes_sent: IntCounter,
+    /// Bytes of messages received from the client.
+    pub bytes_received: IntCounter,
+    /// The round trip time of the latest ping the client answered.
+    pub round_trip_seconds: Gauge,
+    /// Protocol violations the client committed.
+    pub protocol_violations: IntCounter,
+    /// How many messages were queued for the client, as of the latest send to it.
+    pub outgoing_queue_length: IntGauge,
+    sampler: Arc<ClientSeriesSampler>,
+    key: ClientKey,
+}
+
+impl ClientSeries {
+    fn new(sampler: Arc<ClientSeriesSampler>, key: ClientKey) -> Self {
+        let (database_identity, client_identity) = &key;
+        Self {
+            bytes_sent: WORKER_METRICS
+                .ws_client_bytes_sent
+                .with_label_values(database_identity, client_identity),
+            bytes_received: WORKER_METRICS
+                .ws_client_bytes_received
+                .with_label_values(database_identity, client_identity),
+            round_trip_seconds: WORKER_METRICS
+                .ws_client_round_trip_seconds
+                .with_label_values(database_identity, client_identity),
+            protocol_violations: WORKER_METRICS
+                .ws_client_protocol_violations
+                .with_label_values(database_identity, client_identity),
+            outgoing_queue_length: WORKER_METRICS
+                .ws_client_outgoing_queue_length
+                .with_label_values(database_identity, client_identity),
+            sampler,
+            key,
+        }
+    }
+}
+
+impl Drop for ClientSeries {
+    fn drop(&mut self) {
+        self.outgoing_queue_length.set(0);
+        self.sampler.release(self.key, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+
+    fn sampler(sample_rate: f64, max_sampled_clients: usize, ttl: Duration) -> Arc<ClientSeriesSampler> {
+        Arc::new(ClientSeriesSampler::new(ClientSeriesConfig {
+            sample_rate,
+            max_sampled_clients,
+            ttl,
+        }))
+    }
+
+    /// How many series of `database_identity` the per-client `bytes_sent` metric has.
+    fn series_of(database_identity: Identity) -> usize {
+        let database = database_identity.to_hex().to_string();
+        WORKER_METRICS
+            .ws_client_bytes_sent
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .filter(|metric| metric.get_label().iter().any(|label| label.get_value() == database))
+            .count()
+    }
+
+    #[test]
+    fn series_are_bounded_under_churn() {
+        let database_identity = Identity::from_byte_array([0xc1; 32]);
+        let sampler = sampler(0.1, 100, Duration::from_secs(60));
+        let start = Instant::now();
+        let mut max_series = 0;
+        for i in 0..100_000u32 {
+            let mut client = [0; 32];
+            client[..4].copy_from_slice(&i.to_le_bytes());
+            let key = (database_identity, Identity::from_byte_array(client));
+            let now = start + Duration::from_millis(i as u64);
+            let series = sampler.admit_at(now, key, false, || rand::random::<f64>() < 0.1);
+            if let Some(series) = &series {
+                series.bytes_sent.inc_by(10);
+            }
+            drop(series);
+            max_series = max_series.max(series_of(database_identity));
+        }
+        assert!(max_series <= 100, "{max_series} series");
+        assert!(sampler.len() <= 100);
+
+        // Once their ttl is up, the series of the clients which went away are removed.
+        sampler.admit_at(
+            start + Duration::from_secs(1000),
+            (database_identity, Identity::ZERO),
+            false,
+            || false,
+        );
+        assert_eq!(series_of(database_identity), 0);
+        assert!(sampler.is_empty());
+    }
+
+    #[test]
+    fn allowlisted_clients_always_get_series_which_expire_after_their_last_connection() {
+        let database_identity = Identity::from_byte_array([0xc2; 32]);
+        let client = (database_identity, Identity::from_byte_array([1; 32]));
+        let ttl = Duration::from_secs(60);
+        let sampler = sampler(0.0, 0, ttl);
+        let start = Instant::now();
+
+        assert!(sampler
+            .admit_at(start, (database_identity, Identity::ZERO), false, || true)
+            .is_none());
+        let first = sampler.admit_at(start, client, true, || false).unwrap();
+        let second = sampler.admit_at(start, client, true, || false).unwrap();
+        first.bytes_sent.inc();
+        second.bytes_sent.inc();
+        assert_eq!(first.bytes_sent.get(), 2);
+        drop(first);
+        drop(second);
+        sweep_at(&sampler, start + ttl / 2);
+        assert_eq!(series_of(database_identity), 1);
+        sweep_at(&sampler, start + ttl * 2);
+        assert_eq!(series_of(database_identity), 0);
+    }
+
+    fn sweep_at(sampler: &ClientSeriesSampler, now: Instant) {
+        sampler.state.lock().sweep(now, sampler.config.ttl);
+    }
+}
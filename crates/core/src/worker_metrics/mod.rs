@@ -1,7 +1,8 @@
+use crate::client::messages::MessageKind;
 use crate::execution_context::WorkloadType;
 use crate::hash::Hash;
 use once_cell::sync::Lazy;
-use prometheus::{GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec};
+use prometheus::{GaugeVec, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
 use spacetimedb_lib::{ConnectionId, Identity};
 use spacetimedb_metrics::metrics_group;
 use spacetimedb_sats::memory_usage::MemoryUsage;
@@ -9,6 +10,9 @@ use spacetimedb_table::page_pool::PagePool;
 use std::{sync::Once, time::Duration};
 use tokio::{spawn, time::sleep};
 
+mod client_series;
+pub use client_series::{ClientSeries, ClientSeriesConfig, ClientSeriesSampler};
+
 metrics_group!(
     pub struct WorkerMetrics {
         #[name = spacetime_worker_connected_clients]
@@ -26,19 +30,335 @@ metrics_group!(
         #[labels(database_identity: Identity)]
         pub ws_clients_aborted: IntGaugeVec,
 
+        #[name = spacetime_worker_ws_clients_cleaned_up_before_start]
+        #[help = "Number of ws client connections disconnected from their module before their actor started"]
+        #[labels(database_identity: Identity)]
+        pub ws_clients_cleaned_up_before_start: IntGaugeVec,
+
         #[name = spacetime_worker_ws_clients_closed_connection]
         #[help = "Number of ws client connections closed by the client as opposed to being termiated by the server"]
         #[labels(database_identity: Identity)]
         pub ws_clients_closed_connection: IntGaugeVec,
 
+        #[name = spacetime_worker_ws_client_send_errors_total]
+        #[help = "Number of messages not queued for a ws client, by why: queue_full_by_count, queue_full_by_bytes, connection_closing or disconnected"]
+        #[labels(database_identity: Identity, error: str)]
+        pub ws_client_send_errors: IntCounterVec,
+
+        #[name = spacetime_worker_ws_handshake_failures_total]
+        #[help = "Number of ws connections closed because their identity token couldn't be sent, by why"]
+        #[labels(database_identity: Identity, error: str)]
+        pub ws_handshake_failures: IntCounterVec,
+
+        #[name = spacetime_worker_ws_connections]
+        #[help = "Number of websocket connections currently open on this node, across all databases."]
+        pub ws_connections: IntGauge,
+
+        #[name = spacetime_worker_ws_connection_ids]
+        #[help = "Number of distinct connection ids among the websocket connections currently open on this node."]
+        pub ws_connection_ids: IntGauge,
+
+        #[name = spacetime_worker_ws_connection_id_collisions_total]
+        #[help = "Number of generated connection ids drawn again because they collided with an open connection."]
+        pub ws_connection_id_collisions: IntCounter,
+
+        #[name = spacetime_worker_ws_connections_soft_limit]
+        #[help = "Number of websocket connections above which new connections are shed. 0 if unlimited."]
+        pub ws_connections_soft_limit: IntGauge,
+
+        #[name = spacetime_worker_ws_connections_hard_limit]
+        #[help = "Maximum number of websocket connections this node will serve. 0 if unlimited."]
+        pub ws_connections_hard_limit: IntGauge,
+
+        #[name = spacetime_worker_ws_connections_shed_total]
+        #[help = "Number of new websocket connections rejected because the node was at its connection limit."]
+        pub ws_connections_shed: IntCounter,
+
+        #[name = spacetime_worker_ws_connections_shed_per_ip_total]
+        #[help = "Number of new websocket connections rejected because their address was at its connection limit."]
+        pub ws_connections_shed_per_ip: IntCounter,
+
+        #[name = spacetime_worker_ws_load_shed_subscriptions_total]
+        #[help = "Number of new subscriptions refused because the node was under load."]
+        pub ws_load_shed_subscriptions: IntCounter,
+
+        #[name = spacetime_worker_ws_load_shed_upgrades_total]
+        #[help = "Number of new websocket connections rejected because the node was under load."]
+        pub ws_load_shed_upgrades: IntCounter,
+
+        #[name = spacetime_worker_load_admission_level]
+        #[help = "The work the node sheds because of its load: 0 for none, 1 for new subscriptions, 2 for new subscriptions and connections."]
+        pub load_admission_level: IntGauge,
+
+        #[name = spacetime_worker_ws_connections_replaced_total]
+        #[help = "Number of websocket connections closed because a new connection of the same client took over their connection id."]
+        pub ws_connections_replaced: IntCounter,
+
+        #[name = spacetime_worker_ws_connect_outcomes_total]
+        #[help = "Number of websocket connections connected to their module, or not, by whether it took retries or failed for good."]
+        #[labels(outcome: str)]
+        pub ws_connect_outcomes: IntCounterVec,
+
+        #[name = spacetime_worker_ws_session_resumes_total]
+        #[help = "Number of sessions kept for clients which lost their connection to resume, by whether they were resumed or why they weren't."]
+        #[labels(database_identity: Identity, outcome: str)]
+        pub ws_session_resumes: IntCounterVec,
+
+        #[name = spacetime_worker_ws_session_resume_attempts_total]
+        #[help = "Number of clients which tried to resume a session, by whether it was kept for them (hit), wasn't, having expired, been purged or never kept (miss), or was another's (mismatch)."]
+        #[labels(database_identity: Identity, outcome: str)]
+        pub ws_session_resume_attempts: IntCounterVec,
+
+        #[name = spacetime_worker_replay_buffer_bytes]
+        #[help = "The estimated bytes held for the clients of a database to replay to them later, in the queues of parked sessions and in pending acknowledged deliveries."]
+        #[labels(database_identity: Identity, buffer: str)]
+        pub replay_buffer_bytes: IntGaugeVec,
+
+        #[name = spacetime_worker_replay_buffer_identity_bytes]
+        #[help = "The estimated bytes of the acknowledged deliveries pending for the identities of a database with the most of them."]
+        #[labels(database_identity: Identity, identity: Identity)]
+        pub replay_buffer_identity_bytes: IntGaugeVec,
+
+        #[name = spacetime_worker_pending_deliveries_total]
+        #[help = "Number of acknowledged updates kept pending for their clients, by what became of them: persisted when delivered, acknowledged, redelivered, expired, overrun by newer ones or purged."]
+        #[labels(database_identity: Identity, event: str)]
+        pub pending_deliveries: IntCounterVec,
+
+        #[name = spacetime_worker_ws_connections_evicted_total]
+        #[help = "Number of idle websocket connections closed because the node was above its hard connection limit."]
+        pub ws_connections_evicted: IntCounter,
+
+        #[name = spacetime_worker_ws_connections_stale_evicted_total]
+        #[help = "Number of websocket connections evicted from the client index because their actor died without deregistering them."]
+        pub ws_connections_stale_evicted: IntCounter,
+
+        #[name = spacetime_worker_ws_send_flush_seconds]
+        #[help = "Time taken to serialize and send a batch of messages to a websocket client, by batch size."]
+        #[labels(database_identity: Identity, batch_size: str)]
+        pub ws_send_flush_seconds: HistogramVec,
+
+        #[name = spacetime_worker_ws_send_serialize_seconds]
+        #[help = "Time spent serializing a batch of messages to send to a websocket client."]
+        #[labels(database_identity: Identity)]
+        pub ws_send_serialize_seconds: HistogramVec,
+
+        #[name = spacetime_worker_ws_send_write_seconds]
+        #[help = "Time spent writing a batch of serialized messages to a websocket client's socket."]
+        #[labels(database_identity: Identity)]
+        pub ws_send_write_seconds: HistogramVec,
+
+        #[name = spacetime_worker_update_delivery_latency_seconds]
+        #[help = "Time from a transaction committing until its update is written to a websocket client, including queueing and serialization."]
+        #[labels(database_identity: Identity)]
+        #[buckets(100e-6, 500e-6, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1, 5, 10)]
+        pub update_delivery_latency_seconds: HistogramVec,
+
+        #[name = spacetime_worker_ws_incoming_queue_wait_seconds]
+        #[help = "Time messages from websocket clients wait in their incoming queue before being handled, by whether they're text or binary."]
+        #[labels(database_identity: Identity, message_kind: str)]
+        #[buckets(100e-6, 500e-6, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1, 5, 10)]
+        pub ws_incoming_queue_wait_seconds: HistogramVec,
+
+        #[name = spacetime_worker_ws_incoming_handle_seconds]
+        #[help = "Time taken to handle a message from a websocket client, once out of its incoming queue, by whether it's text or binary."]
+        #[labels(database_identity: Identity, message_kind: str)]
+        #[buckets(100e-6, 500e-6, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1, 5, 10)]
+        pub ws_incoming_handle_seconds: HistogramVec,
+
+        #[name = spacetime_worker_ws_message_poll_max_gap_seconds]
+        #[help = "The longest time a websocket connection's message in flight waited to be polled once woken, per connection."]
+        #[labels(database_identity: Identity)]
+        #[buckets(0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1, 5, 10)]
+        pub ws_message_poll_max_gap_seconds: HistogramVec,
+
+        #[name = spacetime_worker_ws_teardown_seconds]
+        #[help = "Time taken to tear down a websocket connection, from the start of closing it until the client is disconnected or abandoned."]
+        #[labels(database_identity: Identity)]
+        pub ws_teardown_seconds: HistogramVec,
+
+        #[name = spacetime_worker_ws_round_trip_seconds]
+        #[help = "Round trip time of the liveness pings sent to websocket clients, as echoed back in their pongs."]
+        #[labels(database_identity: Identity)]
+        #[buckets(0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1, 2.5, 5, 10)]
+        pub ws_round_trip_seconds: HistogramVec,
+
+        #[name = spacetime_worker_client_message_unknown_fields_total]
+        #[help = "Number of unknown fields ignored in JSON messages from clients, e.g. sent by newer SDKs."]
+        #[labels(database_identity: Identity)]
+        pub client_message_unknown_fields: IntCounterVec,
+
+        #[name = spacetime_worker_leader_lookup_failures_total]
+        #[help = "Number of failed database leader lookups, by whether the database was deleted concurrently, had no leader, or the control state failed."]
+        #[labels(reason: str)]
+        pub leader_lookup_failures: IntCounterVec,
+
+        #[name = spacetime_worker_module_watchers]
+        #[help = "Number of client connections watching a database's module host for updates."]
+        #[labels(database_identity: Identity)]
+        pub module_watchers: IntGaugeVec,
+
+        #[name = spacetime_worker_module_update_observe_latency_sec]
+        #[help = "Time between a module host being replaced and a client connection observing the new module."]
+        #[labels(database_identity: Identity)]
+        pub module_update_observe_latency: HistogramVec,
+
+        #[name = spacetime_worker_module_exit_observe_latency_sec]
+        #[help = "Time between a module host exiting and a client connection observing that it has exited."]
+        #[labels(database_identity: Identity)]
+        pub module_exit_observe_latency: HistogramVec,
+
+        #[name = spacetime_worker_module_exit_last_observed_sec]
+        #[help = "Time between the most recent module host exit and the last client connection observing it."]
+        #[labels(database_identity: Identity)]
+        pub module_exit_last_observed: GaugeVec,
+
+        #[name = spacetime_worker_publish_pause_sec]
+        #[help = "Time reducer calls from clients were refused during a coordinated publish, by whether the calls in flight drained before the deadline."]
+        #[labels(database_identity: Identity, outcome: str)]
+        pub publish_pause_duration: HistogramVec,
+
+        #[name = spacetime_worker_publish_refused_reducer_calls_total]
+        #[help = "Number of reducer calls from clients refused because a coordinated publish was in progress."]
+        #[labels(database_identity: Identity)]
+        pub publish_refused_reducer_calls: IntCounterVec,
+
+        #[name = spacetime_worker_publish_invalidated_subscriptions_total]
+        #[help = "Number of subscriptions dropped because a module update invalidated one of their queries."]
+        #[labels(database_identity: Identity)]
+        pub publish_invalidated_subscriptions: IntCounterVec,
+
+        #[name = spacetime_worker_ws_oversized_messages_total]
+        #[help = "Number of messages received from websocket clients which were larger than the node accepts."]
+        #[labels(database_identity: Identity)]
+        pub ws_oversized_messages: IntCounterVec,
+
+        #[name = spacetime_worker_ws_protocol_violations_total]
+        #[help = "Number of protocol violations committed by websocket clients, by kind of violation."]
+        #[labels(database_identity: Identity, violation: str)]
+        pub ws_protocol_violations: IntCounterVec,
+
+        #[name = spacetime_worker_ws_client_series_clients]
+        #[help = "Number of websocket clients with metric series of their own, being allowlisted or sampled, including those whose series haven't expired yet."]
+        pub ws_client_series_clients: IntGauge,
+
+        #[name = spacetime_worker_ws_client_bytes_sent_total]
+        #[help = "Number of bytes sent to a websocket client with metric series of its own."]
+        #[labels(database_identity: Identity, client_identity: Identity)]
+        pub ws_client_bytes_sent: IntCounterVec,
+
+        #[name = spacetime_worker_ws_client_bytes_received_total]
+        #[help = "Number of bytes received from a websocket client with metric series of its own."]
+        #[labels(database_identity: Identity, client_identity: Identity)]
+        pub ws_client_bytes_received: IntCounterVec,
+
+        #[name = spacetime_worker_ws_client_round_trip_seconds]
+        #[help = "Round trip time of the latest liveness ping answered by a websocket client with metric series of its own."]
+        #[labels(database_identity: Identity, client_identity: Identity)]
+        pub ws_client_round_trip_seconds: GaugeVec,
+
+        #[name = spacetime_worker_ws_client_protocol_violations_total]
+        #[help = "Number of protocol violations committed by a websocket client with metric series of its own."]
+        #[labels(database_identity: Identity, client_identity: Identity)]
+        pub ws_client_protocol_violations: IntCounterVec,
+
+        #[name = spacetime_worker_ws_client_outgoing_queue_length]
+        #[help = "Number of messages queued for a websocket client with metric series of its own, as of the latest message sent to it."]
+        #[labels(database_identity: Identity, client_identity: Identity)]
+        pub ws_client_outgoing_queue_length: IntGaugeVec,
+
+        #[name = spacetime_worker_reducer_progress_dropped_total]
+        #[help = "Number of progress values reported by reducers which weren't sent to their callers, by why not."]
+        #[labels(database_identity: Identity, reason: str)]
+        pub reducer_progress_dropped: IntCounterVec,
+
+        #[name = spacetime_worker_firehose_dropped_total]
+        #[help = "Number of committed updates which weren't sent to the firehose sink of their database, by why not."]
+        #[labels(database_identity: Identity, reason: str)]
+        pub firehose_dropped: IntCounterVec,
+
+        #[name = spacetime_worker_ws_incoming_queue_overflows_total]
+        #[help = "Number of messages received from websocket clients whose queue of messages to handle was full, by what became of them."]
+        #[labels(database_identity: Identity, action: str)]
+        pub ws_incoming_queue_overflows: IntCounterVec,
+
+        #[name = spacetime_worker_ws_throttled_messages_total]
+        #[help = "Number of messages received from websocket clients beyond their rate limit, by what became of them."]
+        #[labels(database_identity: Identity, action: str)]
+        pub ws_throttled_messages: IntCounterVec,
+
+        #[name = spacetime_worker_ws_degraded_connections_total]
+        #[help = "Number of websocket clients whose messages stopped arriving while they were still sent messages, by what became of them."]
+        #[labels(database_identity: Identity, action: str)]
+        pub ws_degraded_connections: IntCounterVec,
+
+        #[name = spacetime_worker_ws_send_backlog_actions_total]
+        #[help = "Number of times websocket clients fell behind on the messages sent to them, beyond what their database allows, by what became of them."]
+        #[labels(database_identity: Identity, action: str)]
+        pub ws_send_backlog_actions: IntCounterVec,
+
+        #[name = spacetime_worker_ws_auth_expired_closes_total]
+        #[help = "Number of websocket clients closed as their token expired without them refreshing it."]
+        #[labels(database_identity: Identity)]
+        pub ws_auth_expired_closes: IntCounterVec,
+
+        #[name = spacetime_worker_ws_deprecated_feature_connections_total]
+        #[help = "Number of websocket connections which used a deprecated feature, by feature."]
+        #[labels(database_identity: Identity, feature: str)]
+        pub ws_deprecated_feature_connections: IntCounterVec,
+
+        #[name = spacetime_worker_ws_encode_errors_total]
+        #[help = "Number of messages to websocket clients which failed to encode, by kind of message."]
+        #[labels(database_identity: Identity, message_kind: MessageKind)]
+        pub ws_encode_errors: IntCounterVec,
+
+        #[name = spacetime_worker_ws_encodes_offloaded_total]
+        #[help = "Number of messages to websocket clients large enough to be encoded on the blocking pool."]
+        #[labels(database_identity: Identity)]
+        pub ws_encodes_offloaded: IntCounterVec,
+
+        #[name = spacetime_worker_ws_encodes_shared_total]
+        #[help = "Number of broadcast messages to websocket clients which reused the encoding of another client's copy."]
+        #[labels(database_identity: Identity)]
+        pub ws_encodes_shared: IntCounterVec,
+
+        #[name = spacetime_worker_one_off_queries_in_flight]
+        #[help = "Number of one-off queries from websocket clients currently executing against a database."]
+        #[labels(database_identity: Identity)]
+        pub one_off_queries_in_flight: IntGaugeVec,
+
+        #[name = spacetime_worker_one_off_queries_in_flight_per_connection]
+        #[help = "Number of one-off queries currently executing for each websocket connection."]
+        #[labels(database_identity: Identity, connection_id: ConnectionId)]
+        pub one_off_queries_in_flight_per_connection: IntGaugeVec,
+
+        #[name = spacetime_worker_one_off_queries_refused_total]
+        #[help = "Number of one-off queries refused because too many were in flight, by the scope of the limit they hit."]
+        #[labels(database_identity: Identity, scope: str)]
+        pub one_off_queries_refused: IntCounterVec,
+
+        #[name = spacetime_worker_query_limit_rejections_total]
+        #[help = "Number of queries rejected for exceeding their database's limits on query size and complexity, by where they came from and the limit they exceeded."]
+        #[labels(database_identity: Identity, source: str, limit: str)]
+        pub query_limit_rejections: IntCounterVec,
+
+        #[name = spacetime_worker_ws_ignored_acks_total]
+        #[help = "Number of acknowledgments from websocket clients which were ignored, by whether the delivery was unknown or made to another connection."]
+        #[labels(database_identity: Identity, reason: str)]
+        pub ws_ignored_acks: IntCounterVec,
+
+        #[name = spacetime_worker_ws_module_exit_closes_total]
+        #[help = "Number of websocket connections closed because their module exited, by whether it crashed, was replaced by a publish or was deleted."]
+        #[labels(database_identity: Identity, cause: str)]
+        pub ws_module_exit_closes: IntCounterVec,
+
         #[name = spacetime_websocket_requests_total]
         #[help = "The cumulative number of websocket request messages"]
-        #[labels(database_identity: Identity, protocol: str)]
+        #[labels(database_identity: Identity, protocol: str, encryption: str)]
         pub websocket_requests: IntCounterVec,
 
         #[name = spacetime_websocket_request_msg_size]
         #[help = "The size of messages received on connected sessions"]
-        #[labels(database_identity: Identity, protocol: str)]
+        #[labels(database_identity: Identity, protocol: str, encryption: str)]
         pub websocket_request_msg_size: HistogramVec,
 
         #[name = jemalloc_active_bytes]
@@ -178,7 +498,7 @@ metrics_group!(
 
         #[name = spacetime_websocket_sent_msg_size_bytes]
         #[help = "The size of messages sent to connected sessions"]
-        #[labels(db: Identity, workload: WorkloadType)]
+        #[labels(db: Identity, workload: WorkloadType, message_kind: MessageKind)]
         // Prometheus histograms have default buckets,
         // which broadly speaking,
         // are tailored to measure the response time of a network service.
@@ -193,7 +513,7 @@ metrics_group!(
 
         #[name = spacetime_websocket_sent_num_rows]
         #[help = "The number of rows sent to connected sessions"]
-        #[labels(db: Identity, workload: WorkloadType)]
+        #[labels(db: Identity, workload: WorkloadType, message_kind: MessageKind)]
         // Prometheus histograms have default buckets,
         // which broadly speaking,
         // are tailored to measure the response time of a network service.
@@ -256,7 +576,7 @@ metrics_group!(
 
         #[name = spacetime_reducer_plus_query_duration_sec]
         #[help = "The time spent executing a reducer (in seconds), plus the time spent evaluating its subscription queries"]
-        #[labels(db: Identity, reducer: str)]
+        #[labels(db: Identity, reducer: str, transport: str)]
         pub reducer_plus_query_duration: HistogramVec,
 
         #[name = spacetime_num_bytes_sent_to_clients_total]
@@ -269,6 +589,20 @@ metrics_group!(
         #[labels(database_identity: Identity)]
         pub subscription_send_queue_length: IntGaugeVec,
 
+        #[name = spacetime_subscription_updates_skipped_total]
+        #[help = "The number of query updates not computed for clients too far behind to accept them, which are told to resync instead"]
+        #[labels(database_identity: Identity)]
+        pub subscription_updates_skipped: IntCounterVec,
+
+        #[name = spacetime_subscription_fanout_backlog]
+        #[help = "The number of subscription updates computed by send workers but not yet enqueued for their clients, across all databases, as held back by the fan-out budget"]
+        pub subscription_fanout_backlog: IntGauge,
+
+        #[name = spacetime_subscription_fanout_sec]
+        #[help = "The time from the commit of a transaction to the enqueueing of the last of its subscription updates"]
+        #[labels(database_identity: Identity)]
+        pub subscription_fanout_duration: HistogramVec,
+
         #[name = spacetime_total_incoming_queue_length]
         #[help = "The number of client -> server WebSocket messages waiting any client's incoming queue"]
         #[labels(db: Identity)]
@@ -278,6 +612,11 @@ metrics_group!(
         #[help = "The number of server -> client WebSocket messages waiting in any client's outgoing queue"]
         #[labels(db: Identity)]
         pub total_outgoing_queue_length: IntGaugeVec,
+
+        #[name = spacetime_total_outgoing_queue_bytes]
+        #[help = "The estimated size in bytes of the server -> client WebSocket messages waiting in any client's outgoing queue"]
+        #[labels(db: Identity)]
+        pub total_outgoing_queue_bytes: IntGaugeVec,
     }
 );
 
@@ -3,10 +3,11 @@ use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 
 use crate::expr::LeftDeepJoin;
-use crate::expr::{Expr, ProjectList, ProjectName, Relvar};
+use crate::expr::{Expr, FieldProject, ProjectList, ProjectName, Relvar};
+use crate::limits::QueryLimits;
 use spacetimedb_lib::identity::AuthCtx;
 use spacetimedb_lib::AlgebraicType;
-use spacetimedb_primitives::TableId;
+use spacetimedb_primitives::{ColId, ColSet, TableId};
 use spacetimedb_schema::schema::TableSchema;
 use spacetimedb_sql_parser::ast::BinOp;
 use spacetimedb_sql_parser::{
@@ -15,7 +16,7 @@ use spacetimedb_sql_parser::{
 };
 
 use super::{
-    errors::{DuplicateName, TypingError, Unresolved, Unsupported},
+    errors::{DuplicateName, TableNotAccessible, TypingError, Unresolved, Unsupported},
     expr::RelExpr,
     type_expr, type_proj, type_select,
 };
@@ -32,6 +33,29 @@ pub trait SchemaView {
     fn schema(&self, name: &str) -> Option<Arc<TableSchema>> {
         self.table_id(name).and_then(|table_id| self.schema_for_table(table_id))
     }
+
+    /// Whether `name` is a table which exists, but which the caller may not access,
+    /// i.e. a private table of a database it doesn't own.
+    ///
+    /// Such a table is otherwise invisible through this view.
+    fn is_inaccessible(&self, _name: &str) -> bool {
+        false
+    }
+
+    /// The limits on the size and complexity of the queries of this database.
+    fn query_limits(&self) -> QueryLimits {
+        QueryLimits::default()
+    }
+}
+
+/// Resolve the table `name`, unless there's no such table, or the caller may not access it.
+pub(crate) fn resolve_table(tx: &impl SchemaView, name: &str) -> TypingResult<Arc<TableSchema>> {
+    if tx.is_inaccessible(name) {
+        return Err(TableNotAccessible(name.to_owned()).into());
+    }
+    tx.schema(name)
+        .ok_or_else(|| Unresolved::table(name))
+        .map_err(TypingError::from)
 }
 
 #[derive(Default)]
@@ -117,9 +141,7 @@ pub trait TypeChecker {
     }
 
     fn type_relvar(tx: &impl SchemaView, name: &str) -> TypingResult<Arc<TableSchema>> {
-        tx.schema(name)
-            .ok_or_else(|| Unresolved::table(name))
-            .map_err(TypingError::from)
+        resolve_table(tx, name)
     }
 }
 
@@ -164,6 +186,62 @@ pub fn parse_and_type_sub(sql: &str, tx: &impl SchemaView, auth: &AuthCtx) -> Ty
     expect_table_type(SubChecker::type_ast(ast, tx)?).map(|plan| (plan, has_param))
 }
 
+/// Parse and type check a subscription query,
+/// which, unlike an RLS rule, may select a list of columns of a table rather than whole rows,
+/// e.g. `SELECT id, name FROM player WHERE ...`.
+///
+/// Such a query is typed as if it selected `*` from the same table.
+/// Also returns the columns it selects, always including the table's primary key,
+/// or `None` if it selects whole rows.
+pub fn parse_and_type_sub_with_columns(
+    sql: &str,
+    tx: &impl SchemaView,
+    auth: &AuthCtx,
+) -> TypingResult<(ProjectName, Option<ColSet>, bool)> {
+    let ast = parse_subscription(sql)?;
+    let has_param = ast.has_parameter();
+    let ast = ast.resolve_sender(auth.caller);
+    match SubChecker::type_ast(ast, tx)? {
+        ProjectList::List(mut inputs, fields) if inputs.len() == 1 => {
+            let (plan, columns) = expect_table_columns(inputs.pop().unwrap(), fields)?;
+            Ok((plan, columns, has_param))
+        }
+        expr => expect_table_type(expr).map(|plan| (plan, None, has_param)),
+    }
+}
+
+/// Returns an error unless `fields` are columns of the same table in `input`,
+/// selected under their own names.
+///
+/// Returns the plan selecting whole rows of that table,
+/// and the columns selected, plus its primary key, unless that's all of them.
+fn expect_table_columns(
+    input: RelExpr,
+    fields: Vec<(Box<str>, FieldProject)>,
+) -> TypingResult<(ProjectName, Option<ColSet>)> {
+    let Some(table) = fields.first().map(|(_, field)| field.table.clone()) else {
+        return Err(Unsupported::ReturnType.into());
+    };
+    if fields.iter().any(|(_, field)| field.table != table) {
+        return Err(Unsupported::SubscriptionColumns.into());
+    }
+    let plan = ProjectName::Some(input, table);
+    let schema = plan.return_table().ok_or(Unsupported::ReturnType)?;
+    if let Some((alias, _)) = fields
+        .iter()
+        .find(|(alias, field)| schema.columns()[field.field].col_name != *alias)
+    {
+        return Err(Unsupported::SubscriptionColumnAlias(alias.clone()).into());
+    }
+    let columns = fields
+        .iter()
+        .map(|(_, field)| ColId::from(field.field))
+        .chain(schema.primary_key)
+        .collect::<ColSet>();
+    let all = columns.len() as usize == schema.columns().len();
+    Ok((plan, (!all).then_some(columns)))
+}
+
 /// Returns an error if the input type is not a table type or relvar
 fn expect_table_type(expr: ProjectList) -> TypingResult<ProjectName> {
     match expr {
@@ -218,6 +296,11 @@ pub mod test_utils {
             })
         }
 
+        /// `p` stands for a private table of a database the caller doesn't own.
+        fn is_inaccessible(&self, name: &str) -> bool {
+            name == "p"
+        }
+
         fn rls_rules_for_table(&self, _: TableId) -> anyhow::Result<Vec<Box<str>>> {
             Ok(vec![])
         }
@@ -233,7 +316,7 @@ mod tests {
     use spacetimedb_lib::{identity::AuthCtx, AlgebraicType, ProductType};
     use spacetimedb_schema::def::ModuleDef;
 
-    use super::{SchemaView, TypingResult};
+    use super::{parse_and_type_sub_with_columns, SchemaView, TypingError, TypingResult};
 
     fn module_def() -> ModuleDef {
         build_module_def(vec![
@@ -277,6 +360,23 @@ mod tests {
         super::parse_and_type_sub(sql, tx, &AuthCtx::for_testing()).map(|(plan, _)| plan)
     }
 
+    #[test]
+    fn private_tables_are_not_accessible() {
+        let tx = SchemaViewer(module_def());
+        assert!(parse_and_type_sub("select t.* from t join s on t.u32 = s.u32", &tx).is_ok());
+        for sql in [
+            "select * from p",
+            "select * from p where false",
+            // Joining a private table leaks its rows, even if none of its columns are selected.
+            "select t.* from t join p on t.u32 = p.u32",
+            "select p.* from p join t on p.u32 = t.u32",
+            "select t.* from t join s on t.u32 = s.u32 join p on s.u32 = p.u32",
+        ] {
+            let result = parse_and_type_sub(sql, &tx);
+            assert!(matches!(result, Err(TypingError::NotAccessible(_))), "{sql}");
+        }
+    }
+
     #[test]
     fn valid_literals() {
         let tx = SchemaViewer(module_def());
@@ -506,7 +606,7 @@ mod tests {
             },
             TestCase {
                 sql: "select u32 from t",
-                msg: "Subscriptions must be typed to a single table",
+                msg: "Only client subscriptions may select columns",
             },
             TestCase {
                 sql: "select * from t join s",
@@ -537,4 +637,35 @@ mod tests {
             assert!(result.is_err(), "{msg}");
         }
     }
+
+    #[test]
+    fn subscriptions_select_columns() {
+        let tx = SchemaViewer(module_def());
+        let columns = |sql| {
+            parse_and_type_sub_with_columns(sql, &tx, &AuthCtx::for_testing())
+                .map(|(_, columns, _)| columns.map(|cols| cols.iter().map(|col| col.idx()).collect::<Vec<_>>()))
+        };
+
+        assert_eq!(columns("select * from t").unwrap(), None);
+        assert_eq!(
+            columns("select str, u8 from t where u32 = 1").unwrap(),
+            Some(vec![2, 16])
+        );
+        assert_eq!(
+            columns("select t.u32 from t join s on t.u32 = s.u32").unwrap(),
+            Some(vec![6])
+        );
+        assert_eq!(columns("select id, u32, arr, bytes from s").unwrap(), None);
+
+        for (sql, msg) in [
+            (
+                "select t.u32, s.u32 from t join s on t.u32 = s.u32",
+                "Columns of several tables",
+            ),
+            ("select u32 as x from t", "Renamed columns"),
+            ("select count(*) as n from t", "Aggregates"),
+        ] {
+            assert!(columns(sql).is_err(), "{msg}");
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use super::limits::QueryLimitExceeded;
 use super::statement::InvalidVar;
 use spacetimedb_lib::AlgebraicType;
 use spacetimedb_sats::algebraic_type::fmt::fmt_algebraic_type;
@@ -34,6 +35,14 @@ impl Unresolved {
     }
 }
 
+/// A query references a private table of a database the caller doesn't own.
+///
+/// Reported before anything else is checked about the query,
+/// so that the caller learns nothing of the table's schema.
+#[derive(Error, Debug)]
+#[error("Table `{0}` is not accessible")]
+pub struct TableNotAccessible(pub String);
+
 #[derive(Error, Debug)]
 pub enum InvalidWildcard {
     #[error("SELECT * is not supported for joins")]
@@ -46,6 +55,10 @@ pub enum Unsupported {
     ReturnType,
     #[error("Unsupported expression in projection")]
     ProjectExpr,
+    #[error("Subscriptions may only select columns of a single table")]
+    SubscriptionColumns,
+    #[error("Subscriptions may not rename columns, but `{0}` is not a column name")]
+    SubscriptionColumnAlias(Box<str>),
 }
 
 // TODO: It might be better to return the missing/extra fields
@@ -129,6 +142,8 @@ pub enum TypingError {
     #[error(transparent)]
     Unresolved(#[from] Unresolved),
     #[error(transparent)]
+    NotAccessible(#[from] TableNotAccessible),
+    #[error(transparent)]
     InvalidVar(#[from] InvalidVar),
     #[error(transparent)]
     InsertValues(#[from] InsertValuesError),
@@ -149,4 +164,6 @@ pub enum TypingError {
     DuplicateName(#[from] DuplicateName),
     #[error(transparent)]
     FilterReturnType(#[from] FilterReturnType),
+    #[error(transparent)]
+    QueryLimit(#[from] QueryLimitExceeded),
 }
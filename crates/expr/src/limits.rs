@@ -0,0 +1,256 @@
+//! Per-database limits on the size and complexity of queries,
+//! checked on the text and the syntax tree of a query,
+//! before it is type checked and planned.
+//!
+//! There is no limit on the size of `IN` lists, as the grammar has none.
+//! Membership is written as a disjunction, each operand of which counts as a predicate.
+
+use spacetimedb_sql_parser::{
+    ast::{sql::SqlAst, sub, SqlExpr, SqlFrom},
+    parser::{sql::parse_sql, sub::parse_subscription},
+};
+use thiserror::Error;
+
+use crate::{check::TypingResult, errors::TypingError};
+
+/// DIRTY HACK ALERT: Maximum allowed length, in UTF-8 bytes, of SQL queries.
+/// Any query longer than this will be rejected, whatever the database's [`QueryLimits`].
+/// This prevents a stack overflow when compiling queries with deeply-nested `AND` and `OR` conditions.
+pub const MAX_SQL_LENGTH: usize = 50_000;
+
+/// A limit on queries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryLimit {
+    /// The length of the query text, in UTF-8 bytes.
+    Length,
+    /// The number of predicates in the `WHERE` and `ON` clauses of the query,
+    /// counting each operand of an `AND` or `OR` separately.
+    Predicates,
+    /// The number of joins in the query.
+    Joins,
+}
+
+impl QueryLimit {
+    /// The name of the system variable which sets this limit.
+    pub const fn var_name(self) -> &'static str {
+        match self {
+            Self::Length => "max_query_length",
+            Self::Predicates => "max_query_predicates",
+            Self::Joins => "max_query_joins",
+        }
+    }
+
+    /// The unit in which this limit is measured.
+    fn unit(self) -> &'static str {
+        match self {
+            Self::Length => "bytes",
+            Self::Predicates => "predicates",
+            Self::Joins => "joins",
+        }
+    }
+
+    /// A short name for this limit, e.g. for labeling metrics.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Length => "length",
+            Self::Predicates => "predicates",
+            Self::Joins => "joins",
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Query has {measured} {}, exceeding the limit `{}` of {max}", limit.unit(), limit.var_name())]
+pub struct QueryLimitExceeded {
+    pub limit: QueryLimit,
+    pub max: usize,
+    pub measured: usize,
+}
+
+/// The limits on the queries of a database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// The maximum length of a query, in UTF-8 bytes,
+    /// which is never more than [`MAX_SQL_LENGTH`].
+    pub max_length: usize,
+    /// The maximum number of predicates in a query, if any.
+    pub max_predicates: Option<usize>,
+    /// The maximum number of joins in a query, if any.
+    pub max_joins: Option<usize>,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            max_length: MAX_SQL_LENGTH,
+            max_predicates: None,
+            max_joins: None,
+        }
+    }
+}
+
+fn check(limit: QueryLimit, max: Option<usize>, measured: usize) -> Result<(), QueryLimitExceeded> {
+    match max {
+        Some(max) if measured > max => Err(QueryLimitExceeded { limit, max, measured }),
+        _ => Ok(()),
+    }
+}
+
+/// The number of predicates in `expr`.
+fn predicates(expr: &SqlExpr) -> usize {
+    match expr {
+        SqlExpr::Log(a, b, _) => predicates(a) + predicates(b),
+        _ => 1,
+    }
+}
+
+/// The number of joins in `from`, and of predicates in their `ON` clauses.
+fn joins_and_predicates(from: &SqlFrom) -> (usize, usize) {
+    match from {
+        SqlFrom::Expr(..) => (0, 0),
+        SqlFrom::Join(_, _, joins) => (
+            joins.len(),
+            joins.iter().filter_map(|join| join.on.as_ref()).map(predicates).sum(),
+        ),
+    }
+}
+
+impl QueryLimits {
+    /// Limits with a maximum length of `max_length`, or [`MAX_SQL_LENGTH`] if that's smaller,
+    /// and the given maximum numbers of predicates and joins.
+    pub fn new(max_length: Option<usize>, max_predicates: Option<usize>, max_joins: Option<usize>) -> Self {
+        Self {
+            max_length: max_length.map_or(MAX_SQL_LENGTH, |max| max.min(MAX_SQL_LENGTH)),
+            max_predicates,
+            max_joins,
+        }
+    }
+
+    /// Check the length of `sql`, which is all that can be checked before parsing it.
+    pub fn check_length(&self, sql: &str) -> Result<(), QueryLimitExceeded> {
+        check(QueryLimit::Length, Some(self.max_length), sql.len())
+    }
+
+    fn check_complexity(&self, from: &SqlFrom, filter: Option<&SqlExpr>) -> Result<(), QueryLimitExceeded> {
+        let (joins, on_predicates) = joins_and_predicates(from);
+        check(QueryLimit::Joins, self.max_joins, joins)?;
+        check(
+            QueryLimit::Predicates,
+            self.max_predicates,
+            on_predicates + filter.map_or(0, predicates),
+        )
+    }
+
+    /// Check a subscription query against these limits,
+    /// returning an error if it's too long, too complex, or fails to parse.
+    pub fn check_sub(&self, sql: &str) -> TypingResult<()> {
+        self.check_length(sql)?;
+        let sub::SqlSelect { from, filter, .. } = parse_subscription(sql)?;
+        self.check_complexity(&from, filter.as_ref()).map_err(TypingError::from)
+    }
+
+    /// Check a sql statement against these limits,
+    /// returning an error if it's too long, too complex, or fails to parse.
+    pub fn check_sql(&self, sql: &str) -> TypingResult<()> {
+        self.check_length(sql)?;
+        let result = match parse_sql(sql)? {
+            SqlAst::Select(select) => self.check_complexity(&select.from, select.filter.as_ref()),
+            SqlAst::Update(update) => check(
+                QueryLimit::Predicates,
+                self.max_predicates,
+                update.filter.as_ref().map_or(0, predicates),
+            ),
+            SqlAst::Delete(delete) => check(
+                QueryLimit::Predicates,
+                self.max_predicates,
+                delete.filter.as_ref().map_or(0, predicates),
+            ),
+            SqlAst::Insert(_) | SqlAst::Set(_) | SqlAst::Show(_) => Ok(()),
+        };
+        result.map_err(TypingError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exceeded(limit: QueryLimit, max: usize, measured: usize) -> QueryLimitExceeded {
+        QueryLimitExceeded { limit, max, measured }
+    }
+
+    fn limit_exceeded(result: TypingResult<()>) -> QueryLimitExceeded {
+        match result {
+            Err(TypingError::QueryLimit(err)) => err,
+            result => panic!("expected a limit to be exceeded, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn length_is_checked_up_to_the_hard_maximum() {
+        let limits = QueryLimits::new(Some(20), None, None);
+        assert!(limits.check_sub("select * from t").is_ok());
+        let sql = "select * from t where a = 1";
+        assert_eq!(
+            limit_exceeded(limits.check_sub(sql)),
+            exceeded(QueryLimit::Length, 20, sql.len())
+        );
+        assert_eq!(limit_exceeded(limits.check_sql(sql)).limit, QueryLimit::Length);
+
+        // The length can't be raised above the hard maximum.
+        assert_eq!(QueryLimits::new(Some(usize::MAX), None, None), QueryLimits::default());
+        let long = format!("select * from t where a = '{}'", "x".repeat(MAX_SQL_LENGTH));
+        assert_eq!(
+            limit_exceeded(QueryLimits::default().check_sub(&long)).max,
+            MAX_SQL_LENGTH
+        );
+    }
+
+    #[test]
+    fn predicates_are_counted_in_filters_and_joins() {
+        let limits = QueryLimits::new(None, Some(3), None);
+        assert!(limits.check_sub("select * from t where a = 1 and (b = 2 or c)").is_ok());
+        assert_eq!(
+            limit_exceeded(limits.check_sub("select * from t where a = 1 and b = 2 or c = 3 or d = 4")),
+            exceeded(QueryLimit::Predicates, 3, 4)
+        );
+        assert_eq!(
+            limit_exceeded(
+                limits.check_sub("select t.* from t join s on t.id = s.id where t.a = 1 and s.b = 2 and s.c = 3")
+            ),
+            exceeded(QueryLimit::Predicates, 3, 4)
+        );
+        assert_eq!(
+            limit_exceeded(limits.check_sql("delete from t where a = 1 or a = 2 or a = 3 or a = 4")),
+            exceeded(QueryLimit::Predicates, 3, 4)
+        );
+        assert!(limits.check_sql("insert into t (a) values (1)").is_ok());
+    }
+
+    #[test]
+    fn joins_are_counted() {
+        let limits = QueryLimits::new(None, None, Some(1));
+        assert!(limits.check_sub("select t.* from t join s on t.id = s.id").is_ok());
+        assert_eq!(
+            limit_exceeded(limits.check_sql("select t.* from t join s on t.id = s.id join u on s.id = u.id")),
+            exceeded(QueryLimit::Joins, 1, 2)
+        );
+    }
+
+    #[test]
+    fn errors_name_the_limit_and_the_measurement() {
+        let err = exceeded(QueryLimit::Predicates, 3, 4);
+        assert_eq!(
+            err.to_string(),
+            "Query has 4 predicates, exceeding the limit `max_query_predicates` of 3"
+        );
+    }
+
+    #[test]
+    fn parse_errors_are_reported() {
+        assert!(matches!(
+            QueryLimits::default().check_sub("select from"),
+            Err(TypingError::ParseError(_))
+        ));
+    }
+}
@@ -16,11 +16,12 @@ use crate::{
     check::Relvars,
     errors::InvalidLiteral,
     expr::{FieldProject, ProjectList, RelExpr, Relvar},
+    limits::QueryLimit,
     type_limit,
 };
 
 use super::{
-    check::{SchemaView, TypeChecker, TypingResult},
+    check::{resolve_table, SchemaView, TypeChecker, TypingResult},
     errors::{InsertFieldsError, InsertValuesError, TypingError, UnexpectedType, Unresolved},
     expr::Expr,
     parse, type_expr, type_proj, type_select, StatementCtx, StatementSource,
@@ -91,10 +92,7 @@ pub fn type_insert(insert: SqlInsert, tx: &impl SchemaView) -> TypingResult<Tabl
         values,
     } = insert;
 
-    let schema = tx
-        .schema(&table_name)
-        .ok_or_else(|| Unresolved::table(&table_name))
-        .map_err(TypingError::from)?;
+    let schema = resolve_table(tx, &table_name)?;
 
     // Expect n fields
     let n = schema.columns().len();
@@ -155,10 +153,7 @@ pub fn type_delete(delete: SqlDelete, tx: &impl SchemaView) -> TypingResult<Tabl
         table: SqlIdent(table_name),
         filter,
     } = delete;
-    let from = tx
-        .schema(&table_name)
-        .ok_or_else(|| Unresolved::table(&table_name))
-        .map_err(TypingError::from)?;
+    let from = resolve_table(tx, &table_name)?;
     let mut vars = Relvars::default();
     vars.insert(table_name.clone(), from.clone());
     let expr = filter
@@ -177,10 +172,7 @@ pub fn type_update(update: SqlUpdate, tx: &impl SchemaView) -> TypingResult<Tabl
         assignments,
         filter,
     } = update;
-    let schema = tx
-        .schema(&table_name)
-        .ok_or_else(|| Unresolved::table(&table_name))
-        .map_err(TypingError::from)?;
+    let schema = resolve_table(tx, &table_name)?;
     let mut values = Vec::new();
     for SqlSet(SqlIdent(field), lit) in assignments {
         let ColumnSchema {
@@ -237,7 +229,13 @@ const VAR_SLOW_UPDATE: &str = "slow_tx_update_ms";
 const VAR_SLOW_SUB: &str = "slow_subscription_query_ms";
 
 fn is_var_valid(var: &str) -> bool {
-    var == VAR_ROW_LIMIT || var == VAR_SLOW_QUERY || var == VAR_SLOW_UPDATE || var == VAR_SLOW_SUB
+    var == VAR_ROW_LIMIT
+        || var == VAR_SLOW_QUERY
+        || var == VAR_SLOW_UPDATE
+        || var == VAR_SLOW_SUB
+        || [QueryLimit::Length, QueryLimit::Predicates, QueryLimit::Joins]
+            .iter()
+            .any(|limit| var == limit.var_name())
 }
 
 const ST_VAR_NAME: &str = "st_var";
@@ -361,7 +361,42 @@ pub struct RawRowLevelSecurityDefV9 {
 #[sats(crate = crate)]
 #[cfg_attr(feature = "test", derive(PartialEq, Eq, PartialOrd, Ord))]
 #[non_exhaustive]
-pub enum RawMiscModuleExportV9 {}
+pub enum RawMiscModuleExportV9 {
+    /// Declares whether a reducer may be called by clients.
+    ReducerVisibility(RawReducerVisibilityV9),
+    /// Declares whether clients must acknowledge the updates of a reducer.
+    ReducerDelivery(RawReducerDeliveryV9),
+}
+
+/// Declares whether a reducer may be called by clients.
+///
+/// Reducers without such a declaration are callable by clients.
+/// A reducer which is not callable by clients may still be scheduled,
+/// or called by the database owner.
+#[derive(Debug, Clone, SpacetimeType)]
+#[sats(crate = crate)]
+#[cfg_attr(feature = "test", derive(PartialEq, Eq, PartialOrd, Ord))]
+pub struct RawReducerVisibilityV9 {
+    /// The name of the reducer.
+    pub reducer: RawIdentifier,
+    /// Whether clients may call the reducer.
+    pub client_callable: bool,
+}
+
+/// Declares whether clients must acknowledge the updates of a reducer.
+///
+/// Reducers without such a declaration have their updates delivered fire-and-forget.
+/// The updates of an acknowledged reducer carry a delivery id to clients which opted in,
+/// and are delivered again when such a client reconnects without having acknowledged them.
+#[derive(Debug, Clone, SpacetimeType)]
+#[sats(crate = crate)]
+#[cfg_attr(feature = "test", derive(PartialEq, Eq, PartialOrd, Ord))]
+pub struct RawReducerDeliveryV9 {
+    /// The name of the reducer.
+    pub reducer: RawIdentifier,
+    /// Whether clients must acknowledge the updates of the reducer.
+    pub acknowledged: bool,
+}
 
 /// A type declaration.
 ///
@@ -608,6 +643,30 @@ impl RawModuleDefV9Builder {
         });
     }
 
+    /// Declare whether the reducer `name` may be called by clients.
+    ///
+    /// Reducers are callable by clients unless declared otherwise.
+    pub fn set_reducer_client_callable(&mut self, name: impl Into<RawIdentifier>, client_callable: bool) {
+        self.module
+            .misc_exports
+            .push(RawMiscModuleExportV9::ReducerVisibility(RawReducerVisibilityV9 {
+                reducer: name.into(),
+                client_callable,
+            }));
+    }
+
+    /// Declare whether clients must acknowledge the updates of the reducer `name`.
+    ///
+    /// Updates are delivered fire-and-forget unless declared otherwise.
+    pub fn set_reducer_acknowledged(&mut self, name: impl Into<RawIdentifier>, acknowledged: bool) {
+        self.module
+            .misc_exports
+            .push(RawMiscModuleExportV9::ReducerDelivery(RawReducerDeliveryV9 {
+                reducer: name.into(),
+                acknowledged,
+            }));
+    }
+
     /// Add a row-level security policy to the module.
     ///
     /// The `sql` expression should be a valid SQL expression that will be used to filter rows.
@@ -353,6 +353,165 @@ pub struct TypeAlias {
     pub ty: sats::AlgebraicTypeRef,
 }
 
+/// The client connections a module asks the host to disconnect.
+///
+/// Passed BSATN-encoded to the `disconnect_client` host call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SpacetimeType)]
+#[sats(crate = crate)]
+pub enum ClientTarget {
+    /// Every connection of this identity.
+    Identity(Identity),
+    /// The connection with this id.
+    ConnectionId(ConnectionId),
+}
+
+impl From<Identity> for ClientTarget {
+    fn from(identity: Identity) -> Self {
+        Self::Identity(identity)
+    }
+}
+
+impl From<ConnectionId> for ClientTarget {
+    fn from(connection_id: ConnectionId) -> Self {
+        Self::ConnectionId(connection_id)
+    }
+}
+
+/// How a reducer call reached the module.
+///
+/// Returned as a `u32` tag by the `reducer_transport` host call.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, SpacetimeType)]
+#[sats(crate = crate)]
+pub enum ReducerTransport {
+    /// The host called the reducer itself,
+    /// e.g. a lifecycle reducer, or a reducer another reducer scheduled to run immediately.
+    #[default]
+    Internal,
+    /// A client called the reducer over its websocket connection.
+    Websocket,
+    /// A client called the reducer through the HTTP call route.
+    Http,
+    /// The scheduler called the reducer for a row of its schedule table.
+    Scheduled,
+}
+
+impl ReducerTransport {
+    /// The tag of the transport in the `reducer_transport` host call.
+    pub const fn to_u32(self) -> u32 {
+        match self {
+            Self::Internal => 0,
+            Self::Websocket => 1,
+            Self::Http => 2,
+            Self::Scheduled => 3,
+        }
+    }
+
+    /// The transport tagged `tag` in the `reducer_transport` host call, if any.
+    pub const fn from_u32(tag: u32) -> Option<Self> {
+        Some(match tag {
+            0 => Self::Internal,
+            1 => Self::Websocket,
+            2 => Self::Http,
+            3 => Self::Scheduled,
+            _ => return None,
+        })
+    }
+
+    /// The label of the transport, e.g. in metrics.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Internal => "internal",
+            Self::Websocket => "websocket",
+            Self::Http => "http",
+            Self::Scheduled => "scheduled",
+        }
+    }
+}
+
+/// A feature of the protocol between a database and its clients, which the database's owner turns on for it,
+/// so that it can be rolled out one database at a time.
+///
+/// Connections negotiate the features of their database as they connect, and keep them until they reconnect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DatabaseFeature {
+    /// Clients are sent transaction updates as their delta to the client's subscriptions alone,
+    /// i.e. light updates, unless they ask for full updates or the database defaults otherwise.
+    DeltaUpdates,
+    /// Clients are sent the updates of acknowledged reducers as `AcknowledgedUpdate`s, whether or not they ask.
+    AcknowledgedDelivery,
+}
+
+impl DatabaseFeature {
+    pub const ALL: [Self; 2] = [Self::DeltaUpdates, Self::AcknowledgedDelivery];
+
+    /// The name of the feature, as set by the database's owner and sent to its clients.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::DeltaUpdates => "delta-updates",
+            Self::AcknowledgedDelivery => "acknowledged-delivery",
+        }
+    }
+
+    /// The feature named `name`, if any.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|feature| feature.name() == name)
+    }
+
+    /// The bit of the feature in a [`DatabaseFeatures`], e.g. as returned by the `database_features` host call.
+    const fn bit(self) -> u64 {
+        match self {
+            Self::DeltaUpdates => 1 << 0,
+            Self::AcknowledgedDelivery => 1 << 1,
+        }
+    }
+}
+
+/// A set of [`DatabaseFeature`]s.
+///
+/// Encoded as a mask of the bits of its features, e.g. by the `database_features` host call,
+/// in which bits of features unknown to the reader are ignored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DatabaseFeatures(u64);
+
+impl DatabaseFeatures {
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(self, feature: DatabaseFeature) -> bool {
+        self.0 & feature.bit() != 0
+    }
+
+    pub fn insert(&mut self, feature: DatabaseFeature) {
+        self.0 |= feature.bit();
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The known features of the set.
+    pub fn iter(self) -> impl Iterator<Item = DatabaseFeature> {
+        DatabaseFeature::ALL
+            .into_iter()
+            .filter(move |&feature| self.contains(feature))
+    }
+}
+
+impl FromIterator<DatabaseFeature> for DatabaseFeatures {
+    fn from_iter<I: IntoIterator<Item = DatabaseFeature>>(iter: I) -> Self {
+        let mut features = Self::default();
+        for feature in iter {
+            features.insert(feature);
+        }
+        features
+    }
+}
+
 /// Converts a hexadecimal string reference to a byte array.
 ///
 /// This function takes a reference to a hexadecimal string and attempts to convert it into a byte array.
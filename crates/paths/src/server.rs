@@ -55,6 +55,10 @@ impl ServerDataDir {
     pub fn replica(&self, replica_id: u64) -> ReplicaDir {
         ReplicaDir(self.0.join("replicas").joined_int(replica_id))
     }
+
+    pub fn firehose(&self) -> FirehoseDir {
+        FirehoseDir(self.0.join("firehose"))
+    }
 }
 
 path_type! {
@@ -97,6 +101,12 @@ path_type! {
     MetadataTomlPath: file
 }
 
+path_type! {
+    /// The directory in which the in-tree firehose sinks of databases write their updates,
+    /// and so the only directory to which database owners may point them.
+    FirehoseDir: dir
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum PidFileError {
     #[error("error while taking database lock on spacetime.pid")]
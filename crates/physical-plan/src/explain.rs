@@ -0,0 +1,411 @@
+//! A stable, structured description of the plans chosen by the optimizer.
+//!
+//! Used to show database owners how their queries will be evaluated,
+//! and by tests which guard against plans silently degrading to full table scans.
+
+use std::{fmt, ops::Bound, sync::Arc};
+
+use serde::Serialize;
+use spacetimedb_lib::{query::Delta, sats::satn::Satn, AlgebraicValue};
+use spacetimedb_primitives::{ColId, IndexId};
+use spacetimedb_schema::schema::TableSchema;
+
+use crate::plan::{
+    HashJoin, IxJoin, IxScan, Label, PhysicalExpr, PhysicalPlan, ProjectPlan, Sarg, TableScan, TupleField,
+};
+
+/// The plan chosen for a single query fragment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Explain {
+    /// The table whose rows are returned by the query.
+    pub returns: Box<str>,
+    /// The estimated number of rows scanned in order to evaluate the query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_cost: Option<u64>,
+    pub plan: PlanNode,
+}
+
+/// A single operator in an [`Explain`]ed plan.
+///
+/// Filters which are applied by an index scan, before rows are read,
+/// are listed under `index_filter`.
+/// Filters applied after rows are read are shown as a separate [`PlanNode::Filter`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum PlanNode {
+    TableScan {
+        table: Box<str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        delta: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        estimated_rows: Option<u64>,
+    },
+    IndexScan {
+        table: Box<str>,
+        index: Box<str>,
+        index_filter: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        delta: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        estimated_rows: Option<u64>,
+    },
+    IndexJoin {
+        table: Box<str>,
+        index: Box<str>,
+        on: String,
+        unique: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        delta: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        estimated_rows: Option<u64>,
+        input: Box<PlanNode>,
+    },
+    HashJoin {
+        on: String,
+        unique: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        estimated_rows: Option<u64>,
+        lhs: Box<PlanNode>,
+        rhs: Box<PlanNode>,
+    },
+    NestedLoopJoin {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        estimated_rows: Option<u64>,
+        lhs: Box<PlanNode>,
+        rhs: Box<PlanNode>,
+    },
+    Filter {
+        predicate: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        estimated_rows: Option<u64>,
+        input: Box<PlanNode>,
+    },
+}
+
+impl Explain {
+    /// Describe an optimized `plan` without any cardinality estimates.
+    pub fn new(plan: &ProjectPlan) -> Self {
+        Self::build(plan, &|_| None, None)
+    }
+
+    /// Describe an optimized `plan`,
+    /// annotating each operator with the number of rows it is estimated to return,
+    /// and the plan as a whole with the number of rows it is estimated to scan.
+    pub fn with_estimates(
+        plan: &ProjectPlan,
+        estimate_rows: impl Fn(&PhysicalPlan) -> u64,
+        estimate_rows_scanned: impl Fn(&PhysicalPlan) -> u64,
+    ) -> Self {
+        let cost = estimate_rows_scanned(plan.physical_plan());
+        Self::build(plan, &|plan| Some(estimate_rows(plan)), Some(cost))
+    }
+
+    fn build(plan: &ProjectPlan, estimate: &dyn Fn(&PhysicalPlan) -> Option<u64>, cost: Option<u64>) -> Self {
+        let tables = Tables::new(plan.physical_plan());
+        let returns = match plan {
+            ProjectPlan::None(_) => tables.0.first().map(|(_, schema)| schema.table_name.clone()),
+            ProjectPlan::Name(_, label, _) => tables.schema(label).map(|schema| schema.table_name.clone()),
+        };
+        Self {
+            returns: returns.unwrap_or_default(),
+            estimated_cost: cost,
+            plan: tables.node(plan.physical_plan(), estimate),
+        }
+    }
+}
+
+/// Maps the labels in a plan back to the tables they scan,
+/// so that fields can be rendered by name.
+struct Tables(Vec<(Label, Arc<TableSchema>)>);
+
+impl Tables {
+    fn new(plan: &PhysicalPlan) -> Self {
+        let mut tables = vec![];
+        plan.visit(&mut |plan| match plan {
+            PhysicalPlan::TableScan(TableScan { schema, .. }, label)
+            | PhysicalPlan::IxScan(IxScan { schema, .. }, label) => {
+                tables.push((*label, schema.clone()));
+            }
+            PhysicalPlan::IxJoin(IxJoin { rhs, rhs_label, .. }, _) => {
+                tables.push((*rhs_label, rhs.clone()));
+            }
+            _ => {}
+        });
+        Self(tables)
+    }
+
+    fn schema(&self, label: &Label) -> Option<&TableSchema> {
+        self.0.iter().find(|(l, _)| l == label).map(|(_, schema)| &**schema)
+    }
+
+    fn node(&self, plan: &PhysicalPlan, estimate: &dyn Fn(&PhysicalPlan) -> Option<u64>) -> PlanNode {
+        let estimated_rows = estimate(plan);
+        match plan {
+            PhysicalPlan::TableScan(TableScan { schema, limit, delta }, _) => PlanNode::TableScan {
+                table: schema.table_name.clone(),
+                delta: delta.map(delta_name),
+                limit: *limit,
+                estimated_rows,
+            },
+            PhysicalPlan::IxScan(
+                IxScan {
+                    schema,
+                    limit,
+                    delta,
+                    index_id,
+                    prefix,
+                    arg,
+                },
+                _,
+            ) => {
+                let mut index_filter: Vec<_> = prefix
+                    .iter()
+                    .map(|(col, value)| format!("{} = {}", column_name(schema, *col), value.to_satn()))
+                    .collect();
+                match arg {
+                    Sarg::Eq(col, value) => {
+                        index_filter.push(format!("{} = {}", column_name(schema, *col), value.to_satn()));
+                    }
+                    Sarg::Range(col, lower, upper) => {
+                        let col = column_name(schema, *col);
+                        index_filter.extend(bound(&col, lower, ">=", ">"));
+                        index_filter.extend(bound(&col, upper, "<=", "<"));
+                    }
+                }
+                PlanNode::IndexScan {
+                    table: schema.table_name.clone(),
+                    index: index_name(schema, *index_id),
+                    index_filter,
+                    delta: delta.map(delta_name),
+                    limit: *limit,
+                    estimated_rows,
+                }
+            }
+            PhysicalPlan::IxJoin(
+                IxJoin {
+                    lhs,
+                    rhs,
+                    rhs_index,
+                    rhs_field,
+                    unique,
+                    lhs_field,
+                    rhs_delta,
+                    ..
+                },
+                _,
+            ) => PlanNode::IndexJoin {
+                table: rhs.table_name.clone(),
+                index: index_name(rhs, *rhs_index),
+                on: format!("{} = {}", column_name(rhs, *rhs_field), self.field(lhs_field)),
+                unique: *unique,
+                delta: rhs_delta.map(delta_name),
+                estimated_rows,
+                input: Box::new(self.node(lhs, estimate)),
+            },
+            PhysicalPlan::HashJoin(
+                HashJoin {
+                    lhs,
+                    rhs,
+                    lhs_field,
+                    rhs_field,
+                    unique,
+                },
+                _,
+            ) => PlanNode::HashJoin {
+                on: format!("{} = {}", self.field(lhs_field), self.field(rhs_field)),
+                unique: *unique,
+                estimated_rows,
+                lhs: Box::new(self.node(lhs, estimate)),
+                rhs: Box::new(self.node(rhs, estimate)),
+            },
+            PhysicalPlan::NLJoin(lhs, rhs) => PlanNode::NestedLoopJoin {
+                estimated_rows,
+                lhs: Box::new(self.node(lhs, estimate)),
+                rhs: Box::new(self.node(rhs, estimate)),
+            },
+            PhysicalPlan::Filter(input, expr) => PlanNode::Filter {
+                predicate: self.expr(expr, true),
+                estimated_rows,
+                input: Box::new(self.node(input, estimate)),
+            },
+        }
+    }
+
+    fn field(&self, field: &TupleField) -> String {
+        match self.schema(&field.label) {
+            Some(schema) => column_name(schema, field.field_pos.into()),
+            None => format!("#{}.{}", field.label.0, field.field_pos),
+        }
+    }
+
+    fn expr(&self, expr: &PhysicalExpr, top_level: bool) -> String {
+        match expr {
+            PhysicalExpr::LogOp(op, exprs) => {
+                let exprs = exprs
+                    .iter()
+                    .map(|expr| self.expr(expr, false))
+                    .collect::<Vec<_>>()
+                    .join(&format!(" {op} "));
+                if top_level {
+                    exprs
+                } else {
+                    format!("({exprs})")
+                }
+            }
+            PhysicalExpr::BinOp(op, lhs, rhs) => {
+                format!("{} {op} {}", self.expr(lhs, false), self.expr(rhs, false))
+            }
+            PhysicalExpr::Value(value) => value.to_satn(),
+            PhysicalExpr::Field(field) => self.field(field),
+        }
+    }
+}
+
+fn delta_name(delta: Delta) -> &'static str {
+    match delta {
+        Delta::Inserts => "inserts",
+        Delta::Deletes => "deletes",
+    }
+}
+
+fn column_name(schema: &TableSchema, col: ColId) -> String {
+    match schema.get_column(col.idx()) {
+        Some(column) => format!("{}.{}", schema.table_name, column.col_name),
+        None => format!("{}.#{}", schema.table_name, col.0),
+    }
+}
+
+/// Indexes are always named in module schemas,
+/// but fall back to the id for schemas built by hand.
+fn index_name(schema: &TableSchema, index_id: IndexId) -> Box<str> {
+    schema
+        .indexes
+        .iter()
+        .find(|index| index.index_id == index_id && !index.index_name.is_empty())
+        .map(|index| index.index_name.clone())
+        .unwrap_or_else(|| format!("#{}", index_id.0).into())
+}
+
+fn bound(col: &str, bound: &Bound<AlgebraicValue>, inclusive: &str, exclusive: &str) -> Option<String> {
+    match bound {
+        Bound::Included(value) => Some(format!("{col} {inclusive} {}", value.to_satn())),
+        Bound::Excluded(value) => Some(format!("{col} {exclusive} {}", value.to_satn())),
+        Bound::Unbounded => None,
+    }
+}
+
+/// A line-oriented rendering, one operator per line,
+/// with inputs indented beneath the operator that consumes them.
+impl fmt::Display for Explain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Returns {}", self.returns)?;
+        if let Some(cost) = self.estimated_cost {
+            write!(f, " (cost={cost})")?;
+        }
+        writeln!(f)?;
+        self.plan.fmt_indented(f, 1)
+    }
+}
+
+impl fmt::Display for PlanNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+impl PlanNode {
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{:width$}", "", width = depth * 2)?;
+        let (estimated_rows, inputs): (_, Vec<&PlanNode>) = match self {
+            Self::TableScan {
+                table,
+                delta,
+                limit,
+                estimated_rows,
+            } => {
+                write!(f, "Table Scan on {table}")?;
+                fmt_delta_and_limit(f, *delta, *limit)?;
+                (estimated_rows, vec![])
+            }
+            Self::IndexScan {
+                table,
+                index,
+                index_filter,
+                delta,
+                limit,
+                estimated_rows,
+            } => {
+                write!(f, "Index Scan on {table} using {index}: {}", index_filter.join(" AND "))?;
+                fmt_delta_and_limit(f, *delta, *limit)?;
+                (estimated_rows, vec![])
+            }
+            Self::IndexJoin {
+                table,
+                index,
+                on,
+                unique,
+                delta,
+                estimated_rows,
+                input,
+            } => {
+                write!(f, "Index Join on {table} using {index}: {on}")?;
+                if *unique {
+                    write!(f, " (unique)")?;
+                }
+                fmt_delta_and_limit(f, *delta, None)?;
+                (estimated_rows, vec![&**input])
+            }
+            Self::HashJoin {
+                on,
+                unique,
+                estimated_rows,
+                lhs,
+                rhs,
+            } => {
+                write!(f, "Hash Join: {on}")?;
+                if *unique {
+                    write!(f, " (unique)")?;
+                }
+                (estimated_rows, vec![&**lhs, &**rhs])
+            }
+            Self::NestedLoopJoin {
+                estimated_rows,
+                lhs,
+                rhs,
+            } => {
+                write!(f, "Nested Loop Join")?;
+                (estimated_rows, vec![&**lhs, &**rhs])
+            }
+            Self::Filter {
+                predicate,
+                estimated_rows,
+                input,
+            } => {
+                write!(f, "Filter: {predicate}")?;
+                (estimated_rows, vec![&**input])
+            }
+        };
+        if let Some(rows) = estimated_rows {
+            write!(f, " (rows={rows})")?;
+        }
+        writeln!(f)?;
+        for input in inputs {
+            input.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+fn fmt_delta_and_limit(f: &mut fmt::Formatter<'_>, delta: Option<&str>, limit: Option<u64>) -> fmt::Result {
+    if let Some(delta) = delta {
+        write!(f, " [{delta}]")?;
+    }
+    if let Some(limit) = limit {
+        write!(f, " limit {limit}")?;
+    }
+    Ok(())
+}
@@ -1,4 +1,5 @@
 pub mod compile;
 pub mod dml;
+pub mod explain;
 pub mod plan;
 pub mod rules;
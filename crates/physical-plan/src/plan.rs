@@ -1215,6 +1215,7 @@ mod tests {
 
     use crate::{
         compile::{compile_select, compile_select_list},
+        explain::Explain,
         plan::{HashJoin, IxJoin, IxScan, PhysicalPlan, ProjectListPlan, Sarg, Semi, TupleField},
     };
 
@@ -2052,4 +2053,50 @@ mod tests {
         assert!(plan.plan_iter().any(|plan| plan.has_filter()));
         assert!(plan.plan_iter().any(|plan| plan.has_table_scan(None)));
     }
+
+    /// The rendered plan should show which index, if any, a query uses,
+    /// so that a regression to a full table scan shows up as a diff.
+    #[test]
+    fn explain() {
+        let t_id = TableId(1);
+
+        let t = Arc::new(schema(
+            t_id,
+            "t",
+            &[
+                ("w", AlgebraicType::U8),
+                ("x", AlgebraicType::U8),
+                ("y", AlgebraicType::U8),
+                ("z", AlgebraicType::U8),
+            ],
+            &[&[1], &[2, 3], &[1, 2, 3]],
+            &[],
+            None,
+        ));
+
+        let db = SchemaViewer {
+            schemas: vec![t.clone()],
+        };
+
+        let explain = |sql| {
+            let lp = parse_and_type_sub(sql, &db).unwrap();
+            Explain::new(&compile_select(lp).optimize().unwrap()).to_string()
+        };
+
+        assert_eq!(
+            explain("select * from t where x = 3 and y = 4 and z = 5"),
+            "\
+Returns t
+  Index Scan on t using #2: t.x = 3 AND t.y = 4 AND t.z = 5
+"
+        );
+        assert_eq!(
+            explain("select * from t where w = 3"),
+            "\
+Returns t
+  Filter: t.w = 3
+    Table Scan on t
+"
+        );
+    }
 }
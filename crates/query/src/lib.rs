@@ -5,8 +5,8 @@ use spacetimedb_execution::{
     Datastore, DeltaStore,
 };
 use spacetimedb_expr::{
-    check::{parse_and_type_sub, SchemaView},
-    expr::ProjectList,
+    check::{parse_and_type_sub, parse_and_type_sub_with_columns, SchemaView},
+    expr::{ProjectList, ProjectName},
     rls::{resolve_views_for_sql, resolve_views_for_sub},
     statement::{parse_and_type_sql, Statement, DML},
 };
@@ -15,24 +15,46 @@ use spacetimedb_physical_plan::{
     compile::{compile_dml_plan, compile_select, compile_select_list},
     plan::{ProjectListPlan, ProjectPlan},
 };
-use spacetimedb_primitives::TableId;
-
-/// DIRTY HACK ALERT: Maximum allowed length, in UTF-8 bytes, of SQL queries.
-/// Any query longer than this will be rejected.
-/// This prevents a stack overflow when compiling queries with deeply-nested `AND` and `OR` conditions.
-const MAX_SQL_LENGTH: usize = 50_000;
+use spacetimedb_primitives::{ColSet, TableId};
 
+/// Compile a subscription query,
+/// after checking it against the [`SchemaView::query_limits`] of the database,
+/// so that queries which are too long or too complex are rejected before they are planned.
 pub fn compile_subscription(
     sql: &str,
     tx: &impl SchemaView,
     auth: &AuthCtx,
 ) -> Result<(Vec<ProjectPlan>, TableId, Box<str>, bool)> {
-    if sql.len() > MAX_SQL_LENGTH {
-        bail!("SQL query exceeds maximum allowed length: \"{sql:.120}...\"")
-    }
+    tx.query_limits().check_sub(sql)?;
+
+    let (plan, has_param) = parse_and_type_sub(sql, tx, auth)?;
+    compile_sub_plan(plan, has_param, tx, auth)
+}
 
-    let (plan, mut has_param) = parse_and_type_sub(sql, tx, auth)?;
+/// Like [compile_subscription], but the query may select a list of columns,
+/// e.g. `SELECT id, name FROM player`.
+///
+/// The plans return whole rows, and the columns selected are returned alongside them,
+/// including the table's primary key, or `None` if the query selects whole rows.
+#[allow(clippy::type_complexity)]
+pub fn compile_subscription_with_columns(
+    sql: &str,
+    tx: &impl SchemaView,
+    auth: &AuthCtx,
+) -> Result<(Vec<ProjectPlan>, TableId, Box<str>, Option<ColSet>, bool)> {
+    tx.query_limits().check_sub(sql)?;
 
+    let (plan, columns, has_param) = parse_and_type_sub_with_columns(sql, tx, auth)?;
+    let (plans, return_id, return_name, has_param) = compile_sub_plan(plan, has_param, tx, auth)?;
+    Ok((plans, return_id, return_name, columns, has_param))
+}
+
+fn compile_sub_plan(
+    plan: ProjectName,
+    mut has_param: bool,
+    tx: &impl SchemaView,
+    auth: &AuthCtx,
+) -> Result<(Vec<ProjectPlan>, TableId, Box<str>, bool)> {
     let Some(return_id) = plan.return_table_id() else {
         bail!("Failed to determine TableId for query")
     };
@@ -50,11 +72,10 @@ pub fn compile_subscription(
     Ok((plan_fragments, return_id, return_name, has_param))
 }
 
-/// A utility for parsing and type checking a sql statement
+/// A utility for parsing and type checking a sql statement,
+/// which, like a subscription query, must be within the [`SchemaView::query_limits`] of the database
 pub fn compile_sql_stmt(sql: &str, tx: &impl SchemaView, auth: &AuthCtx) -> Result<Statement> {
-    if sql.len() > MAX_SQL_LENGTH {
-        bail!("SQL query exceeds maximum allowed length: \"{sql:.120}...\"")
-    }
+    tx.query_limits().check_sql(sql)?;
 
     match parse_and_type_sql(sql, tx, auth)? {
         stmt @ Statement::DML(_) => Ok(stmt),
@@ -307,7 +307,7 @@ pub fn ponder_auto_migrate<'def>(old: &'def ModuleDef, new: &'def ModuleDef) ->
 /// `Remove` means the item is present in the old `ModuleDef` but not the new.
 /// `MaybeChange` indicates the item is present in both.
 #[derive(Debug)]
-enum Diff<'def, T> {
+pub(crate) enum Diff<'def, T> {
     Add { new: &'def T },
     Remove { old: &'def T },
     MaybeChange { old: &'def T, new: &'def T },
@@ -315,7 +315,7 @@ enum Diff<'def, T> {
 
 /// Diff a collection of items, looking them up in both the old and new `ModuleDef` by their `ModuleDefLookup::Key`.
 /// Keys are required to be stable across migrations, which makes this possible.
-fn diff<'def, T: ModuleDefLookup, I: Iterator<Item = &'def T>>(
+pub(crate) fn diff<'def, T: ModuleDefLookup, I: Iterator<Item = &'def T>>(
     old: &'def ModuleDef,
     new: &'def ModuleDef,
     iter: impl Fn(&'def ModuleDef) -> I,
@@ -33,8 +33,9 @@ use spacetimedb_data_structures::map::HashMap;
 use spacetimedb_lib::db::raw_def;
 use spacetimedb_lib::db::raw_def::v9::{
     Lifecycle, RawConstraintDataV9, RawConstraintDefV9, RawIdentifier, RawIndexAlgorithm, RawIndexDefV9,
-    RawModuleDefV9, RawReducerDefV9, RawRowLevelSecurityDefV9, RawScheduleDefV9, RawScopedTypeNameV9, RawSequenceDefV9,
-    RawSql, RawTableDefV9, RawTypeDefV9, RawUniqueConstraintDataV9, TableAccess, TableType,
+    RawMiscModuleExportV9, RawModuleDefV9, RawReducerDefV9, RawReducerDeliveryV9, RawReducerVisibilityV9,
+    RawRowLevelSecurityDefV9, RawScheduleDefV9, RawScopedTypeNameV9, RawSequenceDefV9, RawSql, RawTableDefV9,
+    RawTypeDefV9, RawUniqueConstraintDataV9, TableAccess, TableType,
 };
 use spacetimedb_lib::{ProductType, RawModuleDef};
 use spacetimedb_primitives::{ColId, ColList, ColOrCols, ColSet, ReducerId, TableId};
@@ -343,11 +344,28 @@ impl From<ModuleDef> for RawModuleDefV9 {
             row_level_security_raw,
         } = val;
 
+        let misc_exports = reducers
+            .values()
+            .filter(|def| !def.client_callable)
+            .map(|def| {
+                RawMiscModuleExportV9::ReducerVisibility(RawReducerVisibilityV9 {
+                    reducer: def.name.clone().into(),
+                    client_callable: false,
+                })
+            })
+            .chain(reducers.values().filter(|def| def.acknowledged).map(|def| {
+                RawMiscModuleExportV9::ReducerDelivery(RawReducerDeliveryV9 {
+                    reducer: def.name.clone().into(),
+                    acknowledged: true,
+                })
+            }))
+            .collect();
+
         RawModuleDefV9 {
             tables: to_raw(tables),
             reducers: reducers.into_iter().map(|(_, def)| def.into()).collect(),
             types: to_raw(types),
-            misc_exports: vec![],
+            misc_exports,
             typespace,
             row_level_security: row_level_security_raw.into_iter().map(|(_, def)| def).collect(),
         }
@@ -913,6 +931,16 @@ pub struct ReducerDef {
 
     /// The special role of this reducer in the module lifecycle, if any.
     pub lifecycle: Option<Lifecycle>,
+
+    /// Whether clients may call this reducer directly.
+    ///
+    /// Reducers which are not client-callable may still be scheduled,
+    /// or called by the database owner.
+    pub client_callable: bool,
+
+    /// Whether clients which opted into acknowledged delivery
+    /// must acknowledge the updates of this reducer.
+    pub acknowledged: bool,
 }
 
 impl From<ReducerDef> for RawReducerDefV9 {
@@ -75,17 +75,14 @@ pub fn validate(def: RawModuleDefV9) -> Result<ModuleDef> {
         })
         .collect_all_errors::<HashMap<_, _>>();
 
-    // It's statically impossible for this assert to fire until `RawMiscModuleExportV9` grows some variants.
-    assert_eq!(
-        misc_exports.len(),
-        0,
-        "Misc module exports are not yet supported in ABI v9."
-    );
-
     let tables_types_reducers = (tables, types, reducers)
         .combine_errors()
-        .and_then(|(tables, types, reducers)| {
-            check_scheduled_reducers_exist(&tables, &reducers)?;
+        .and_then(|(tables, types, mut reducers)| {
+            (
+                check_scheduled_reducers_exist(&tables, &reducers),
+                apply_misc_exports(&mut reducers, misc_exports),
+            )
+                .combine_errors()?;
             Ok((tables, types, reducers))
         });
 
@@ -347,6 +344,8 @@ impl ModuleValidator<'_> {
                 recursive: false, // A ProductTypeDef not stored in a Typespace cannot be recursive.
             },
             lifecycle,
+            client_callable: true,
+            acknowledged: false,
         })
     }
 
@@ -898,6 +897,40 @@ fn check_scheduled_reducers_exist(
         .collect_all_errors()
 }
 
+/// Apply the misc exports of a module to the already-validated `reducers`.
+fn apply_misc_exports(
+    reducers: &mut IndexMap<Identifier, ReducerDef>,
+    misc_exports: Vec<RawMiscModuleExportV9>,
+) -> Result<()> {
+    misc_exports
+        .into_iter()
+        .map(|export| -> Result<()> {
+            match export {
+                RawMiscModuleExportV9::ReducerVisibility(RawReducerVisibilityV9 {
+                    reducer,
+                    client_callable,
+                }) => {
+                    let def = reducers
+                        .values_mut()
+                        .find(|def| *def.name == *reducer)
+                        .ok_or(ValidationError::MissingReducerForVisibility { reducer })?;
+                    def.client_callable = client_callable;
+                    Ok(())
+                }
+                RawMiscModuleExportV9::ReducerDelivery(RawReducerDeliveryV9 { reducer, acknowledged }) => {
+                    let def = reducers
+                        .values_mut()
+                        .find(|def| *def.name == *reducer)
+                        .ok_or(ValidationError::MissingReducerForDelivery { reducer })?;
+                    def.acknowledged = acknowledged;
+                    Ok(())
+                }
+                _ => unimplemented!("unknown misc export"),
+            }
+        })
+        .collect_all_errors()
+}
+
 #[cfg(test)]
 mod tests {
     use crate::def::validate::tests::{
@@ -917,7 +950,7 @@ mod tests {
     use spacetimedb_lib::ScheduleAt;
     use spacetimedb_primitives::{ColId, ColList, ColSet};
     use spacetimedb_sats::{AlgebraicType, AlgebraicTypeRef, ProductType};
-    use v9::{Lifecycle, RawIndexAlgorithm, RawModuleDefV9Builder, TableAccess, TableType};
+    use v9::{Lifecycle, RawIndexAlgorithm, RawModuleDefV9, RawModuleDefV9Builder, TableAccess, TableType};
 
     /// This test attempts to exercise every successful path in the validation code.
     #[test]
@@ -1641,4 +1674,60 @@ mod tests {
         assert!(def.lookup::<IndexDef>("wacky.index()").is_some());
         assert!(def.lookup::<SequenceDef>("wacky.sequence()").is_some());
     }
+
+    #[test]
+    fn reducer_visibility() {
+        let mut builder = RawModuleDefV9Builder::new();
+        builder.add_reducer("public_reducer", ProductType::unit(), None);
+        builder.add_reducer("internal_reducer", ProductType::unit(), None);
+        builder.set_reducer_client_callable("internal_reducer", false);
+
+        let def: ModuleDef = builder.finish().try_into().unwrap();
+        assert!(def.reducer("public_reducer").unwrap().client_callable);
+        assert!(!def.reducer("internal_reducer").unwrap().client_callable);
+
+        // The visibility survives a round-trip through the raw definition.
+        let raw: RawModuleDefV9 = def.into();
+        let def: ModuleDef = raw.try_into().unwrap();
+        assert!(!def.reducer("internal_reducer").unwrap().client_callable);
+    }
+
+    #[test]
+    fn reducer_delivery() {
+        let mut builder = RawModuleDefV9Builder::new();
+        builder.add_reducer("fill_order", ProductType::unit(), None);
+        builder.add_reducer("chat", ProductType::unit(), None);
+        builder.set_reducer_acknowledged("fill_order", true);
+
+        let def: ModuleDef = builder.finish().try_into().unwrap();
+        assert!(def.reducer("fill_order").unwrap().acknowledged);
+        assert!(!def.reducer("chat").unwrap().acknowledged);
+
+        // The delivery survives a round-trip through the raw definition.
+        let raw: RawModuleDefV9 = def.into();
+        let def: ModuleDef = raw.try_into().unwrap();
+        assert!(def.reducer("fill_order").unwrap().acknowledged);
+    }
+
+    #[test]
+    fn missing_reducer_for_delivery() {
+        let mut builder = RawModuleDefV9Builder::new();
+        builder.set_reducer_acknowledged("nonexistent", true);
+        let result: Result<ModuleDef> = builder.finish().try_into();
+
+        expect_error_matching!(result, ValidationError::MissingReducerForDelivery { reducer } => {
+            &reducer[..] == "nonexistent"
+        });
+    }
+
+    #[test]
+    fn missing_reducer_for_visibility() {
+        let mut builder = RawModuleDefV9Builder::new();
+        builder.set_reducer_client_callable("nonexistent", false);
+        let result: Result<ModuleDef> = builder.finish().try_into();
+
+        expect_error_matching!(result, ValidationError::MissingReducerForVisibility { reducer } => {
+            &reducer[..] == "nonexistent"
+        });
+    }
 }
@@ -110,6 +110,10 @@ pub enum ValidationError {
     TableTypeNameMismatch { table: Identifier },
     #[error("Schedule {schedule} refers to a scheduled reducer {reducer} that does not exist")]
     MissingScheduledReducer { schedule: Box<str>, reducer: Identifier },
+    #[error("Visibility declared for reducer {reducer} that does not exist")]
+    MissingReducerForVisibility { reducer: RawIdentifier },
+    #[error("Delivery declared for reducer {reducer} that does not exist")]
+    MissingReducerForDelivery { reducer: RawIdentifier },
     #[error("Scheduled reducer {reducer} expected to have type {expected}, but has type {actual}")]
     IncorrectScheduledReducerParams {
         reducer: RawIdentifier,
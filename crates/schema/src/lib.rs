@@ -7,4 +7,5 @@ pub mod def;
 pub mod error;
 pub mod identifier;
 pub mod schema;
+pub mod schema_diff;
 pub mod type_for_generate;
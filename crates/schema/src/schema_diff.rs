@@ -0,0 +1,689 @@
+//! A description of what changed between two versions of a module's schema,
+//! as seen by the clients of its database.
+//!
+//! Unlike an [`AutoMigratePlan`](crate::auto_migrate::AutoMigratePlan),
+//! which describes how to update a database's tables and refuses changes it can't make,
+//! a [`SchemaDiff`] describes every change, including those to reducers,
+//! and tags each as breaking or not,
+//! so that a client built against the old schema can tell whether it can keep running against the new one.
+//!
+//! A change is breaking if a client built against the old schema may fail against the new one,
+//! e.g. because it can no longer decode the rows of a table, call a reducer, or run a subscription query.
+
+use crate::auto_migrate::{diff, Diff};
+use crate::def::*;
+use crate::error::PrettyAlgebraicType;
+use crate::identifier::Identifier;
+use spacetimedb_data_structures::map::HashSet;
+use spacetimedb_lib::db::raw_def::v9::TableAccess;
+use spacetimedb_lib::AlgebraicType;
+use spacetimedb_sats::de::Deserialize;
+use spacetimedb_sats::ser::Serialize;
+use spacetimedb_sats::WithTypespace;
+
+/// The part of a module's schema a [`SchemaChange`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum SchemaItem {
+    Table,
+    Column,
+    Index,
+    Constraint,
+    Sequence,
+    Schedule,
+    Reducer,
+    RowLevelSecurity,
+}
+
+/// How a [`SchemaItem`] changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum ChangeKind {
+    /// The item is in the new schema, but not the old.
+    Added,
+    /// The item is in the old schema, but not the new.
+    Removed,
+    /// The item is in both schemas, but differs between them.
+    Changed,
+}
+
+/// A single change between two schemas.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SchemaChange {
+    pub item: SchemaItem,
+    /// The table the item belongs to, for columns, indexes, constraints, sequences and schedules.
+    pub table: Option<Box<str>>,
+    /// The name of the item, or, for row-level security, its SQL.
+    pub name: Box<str>,
+    pub kind: ChangeKind,
+    /// What changed, for [`ChangeKind::Changed`], e.g. `type: U32 -> U64`.
+    pub detail: Option<Box<str>>,
+    /// Whether a client built against the old schema may fail against the new one.
+    pub breaking: bool,
+}
+
+/// The changes between two versions of a module's schema, sorted by item, table and name.
+///
+/// Items added or removed along with their table, e.g. the columns of a new table, are part of its change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaDiff {
+    pub changes: Vec<SchemaChange>,
+}
+
+/// A compact [`SchemaDiff`], counting its changes by [`ChangeKind`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaDiffSummary {
+    pub added: u32,
+    pub removed: u32,
+    pub changed: u32,
+    /// Whether any of the changes is breaking.
+    pub breaking: bool,
+}
+
+impl SchemaDiff {
+    /// The changes from `old` to `new`.
+    pub fn between(old: &ModuleDef, new: &ModuleDef) -> Self {
+        let mut differ = Differ {
+            old,
+            new,
+            changes: Vec::new(),
+        };
+        differ.tables();
+        differ.table_items();
+        differ.reducers();
+        differ.row_level_security();
+        let mut changes = differ.changes;
+        changes.sort();
+        Self { changes }
+    }
+
+    /// Returns whether the schemas are the same.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns whether any of the changes is breaking.
+    pub fn is_breaking(&self) -> bool {
+        self.changes.iter().any(|change| change.breaking)
+    }
+
+    pub fn summary(&self) -> SchemaDiffSummary {
+        let mut summary = SchemaDiffSummary::default();
+        for change in &self.changes {
+            match change.kind {
+                ChangeKind::Added => summary.added += 1,
+                ChangeKind::Removed => summary.removed += 1,
+                ChangeKind::Changed => summary.changed += 1,
+            }
+            summary.breaking |= change.breaking;
+        }
+        summary
+    }
+}
+
+struct Differ<'def> {
+    old: &'def ModuleDef,
+    new: &'def ModuleDef,
+    changes: Vec<SchemaChange>,
+}
+
+impl<'def> Differ<'def> {
+    fn push(
+        &mut self,
+        item: SchemaItem,
+        table: Option<&str>,
+        name: &str,
+        kind: ChangeKind,
+        detail: Option<String>,
+        breaking: bool,
+    ) {
+        self.changes.push(SchemaChange {
+            item,
+            table: table.map(Into::into),
+            name: name.into(),
+            kind,
+            detail: detail.map(Into::into),
+            breaking,
+        });
+    }
+
+    /// Push the addition or removal of the item of `diff`, if it isn't in both schemas,
+    /// which is breaking if `added_breaks` or `removed_breaks` respectively.
+    fn added_or_removed<T>(
+        &mut self,
+        item: SchemaItem,
+        table: Option<&str>,
+        name: &str,
+        diff: &Diff<'def, T>,
+        (added_breaks, removed_breaks): (bool, bool),
+    ) {
+        match diff {
+            Diff::Add { .. } => self.push(item, table, name, ChangeKind::Added, None, added_breaks),
+            Diff::Remove { .. } => self.push(item, table, name, ChangeKind::Removed, None, removed_breaks),
+            Diff::MaybeChange { .. } => {}
+        }
+    }
+
+    fn tables(&mut self) {
+        for table_diff in diff(self.old, self.new, ModuleDef::tables) {
+            let name = diff_key(&table_diff, |table| &table.name[..]);
+            self.added_or_removed(SchemaItem::Table, None, name, &table_diff, (false, true));
+            if let Diff::MaybeChange { old, new } = table_diff {
+                self.table(old, new);
+            }
+        }
+    }
+
+    fn table(&mut self, old: &'def TableDef, new: &'def TableDef) {
+        let mut changed = |detail: String, breaking| {
+            self.push(
+                SchemaItem::Table,
+                None,
+                &old.name,
+                ChangeKind::Changed,
+                Some(detail),
+                breaking,
+            )
+        };
+        if old.table_access != new.table_access {
+            // A table made private can no longer be subscribed to.
+            let breaking = new.table_access == TableAccess::Private;
+            changed(
+                format!("access: {:?} -> {:?}", old.table_access, new.table_access),
+                breaking,
+            );
+        }
+        if old.table_type != new.table_type {
+            changed(format!("type: {:?} -> {:?}", old.table_type, new.table_type), true);
+        }
+        let primary_key = |table: &TableDef| {
+            table
+                .primary_key
+                .and_then(|col| table.get_column(col))
+                .map(|col| col.name.clone())
+        };
+        let (old_pk, new_pk) = (primary_key(old), primary_key(new));
+        if old_pk != new_pk {
+            let name = |pk: Option<Identifier>| pk.map_or_else(|| "none".to_owned(), |pk| pk.to_string());
+            changed(format!("primary key: {} -> {}", name(old_pk), name(new_pk)), true);
+        }
+
+        let table = Some(&old.name[..]);
+        let key = old.key();
+        for col_diff in diff(self.old, self.new, |def| {
+            def.lookup_expect::<TableDef>(key).columns.iter()
+        }) {
+            let name = diff_key(&col_diff, |col| &col.name[..]);
+            // Any change to the columns of a table changes how its rows are encoded.
+            self.added_or_removed(SchemaItem::Column, table, name, &col_diff, (true, true));
+            let Diff::MaybeChange {
+                old: old_col,
+                new: new_col,
+            } = col_diff
+            else {
+                continue;
+            };
+            let old_ty = resolve(self.old, &old_col.ty);
+            let new_ty = resolve(self.new, &new_col.ty);
+            if old_ty != new_ty {
+                let detail = format!(
+                    "type: {} -> {}",
+                    PrettyAlgebraicType(old_ty),
+                    PrettyAlgebraicType(new_ty)
+                );
+                self.push(SchemaItem::Column, table, name, ChangeKind::Changed, Some(detail), true);
+            }
+            if old_col.col_id != new_col.col_id {
+                let detail = format!("position: {} -> {}", old_col.col_id.idx(), new_col.col_id.idx());
+                self.push(SchemaItem::Column, table, name, ChangeKind::Changed, Some(detail), true);
+            }
+        }
+    }
+
+    /// Diff the indexes, constraints, sequences and schedules of the tables in both schemas.
+    fn table_items(&mut self) {
+        // The items of added and removed tables are part of their table's change.
+        let tables = |def: &'def ModuleDef| def.tables().map(|table| &table.name).collect::<HashSet<_>>();
+        let (old_tables, new_tables) = (tables(self.old), tables(self.new));
+        let (old, new) = (self.old, self.new);
+        let table_of = move |name: &str| -> Option<&'def str> {
+            let table = old
+                .stored_in_table_def(name)
+                .or_else(|| new.stored_in_table_def(name))?;
+            (old_tables.contains(&table.name) && new_tables.contains(&table.name)).then_some(&table.name[..])
+        };
+
+        for index_diff in diff(self.old, self.new, ModuleDef::indexes) {
+            let name = diff_key(&index_diff, |index| &*index.name);
+            let Some(table) = table_of(name) else { continue };
+            // Subscriptions may rely on an index, e.g. to join on its columns.
+            self.added_or_removed(SchemaItem::Index, Some(table), name, &index_diff, (false, true));
+            if let Diff::MaybeChange { old, new } = index_diff {
+                if old.accessor_name != new.accessor_name {
+                    let detail = format!("accessor: {:?} -> {:?}", old.accessor_name, new.accessor_name);
+                    self.push(
+                        SchemaItem::Index,
+                        Some(table),
+                        name,
+                        ChangeKind::Changed,
+                        Some(detail),
+                        true,
+                    );
+                }
+                if old.algorithm != new.algorithm {
+                    let detail = "algorithm".to_owned();
+                    self.push(
+                        SchemaItem::Index,
+                        Some(table),
+                        name,
+                        ChangeKind::Changed,
+                        Some(detail),
+                        true,
+                    );
+                }
+            }
+        }
+
+        for constraint_diff in diff(self.old, self.new, ModuleDef::constraints) {
+            let name = diff_key(&constraint_diff, |constraint| &*constraint.name);
+            let Some(table) = table_of(name) else { continue };
+            // Clients may look rows up by a unique constraint.
+            self.added_or_removed(
+                SchemaItem::Constraint,
+                Some(table),
+                name,
+                &constraint_diff,
+                (false, true),
+            );
+            if let Diff::MaybeChange { old, new } = constraint_diff {
+                if old != new {
+                    let detail = "columns".to_owned();
+                    self.push(
+                        SchemaItem::Constraint,
+                        Some(table),
+                        name,
+                        ChangeKind::Changed,
+                        Some(detail),
+                        true,
+                    );
+                }
+            }
+        }
+
+        // Sequences and schedules are the server's business alone.
+        for sequence_diff in diff(self.old, self.new, ModuleDef::sequences) {
+            let name = diff_key(&sequence_diff, |sequence| &*sequence.name);
+            let Some(table) = table_of(name) else { continue };
+            self.added_or_removed(SchemaItem::Sequence, Some(table), name, &sequence_diff, (false, false));
+            if let Diff::MaybeChange { old, new } = sequence_diff {
+                if old != new {
+                    let detail = "parameters".to_owned();
+                    self.push(
+                        SchemaItem::Sequence,
+                        Some(table),
+                        name,
+                        ChangeKind::Changed,
+                        Some(detail),
+                        false,
+                    );
+                }
+            }
+        }
+
+        for schedule_diff in diff(self.old, self.new, ModuleDef::schedules) {
+            let name = diff_key(&schedule_diff, |schedule| &*schedule.name);
+            let Some(table) = table_of(name) else { continue };
+            self.added_or_removed(SchemaItem::Schedule, Some(table), name, &schedule_diff, (false, false));
+            if let Diff::MaybeChange { old, new } = schedule_diff {
+                if old != new {
+                    let detail = format!("reducer: {} -> {}", old.reducer_name, new.reducer_name);
+                    self.push(
+                        SchemaItem::Schedule,
+                        Some(table),
+                        name,
+                        ChangeKind::Changed,
+                        Some(detail),
+                        false,
+                    );
+                }
+            }
+        }
+    }
+
+    fn reducers(&mut self) {
+        for reducer_diff in diff(self.old, self.new, ModuleDef::reducers) {
+            let name = diff_key(&reducer_diff, |reducer| &reducer.name[..]);
+            self.added_or_removed(SchemaItem::Reducer, None, name, &reducer_diff, (false, true));
+            let Diff::MaybeChange { old, new } = reducer_diff else {
+                continue;
+            };
+            let old_params = resolve(self.old, &AlgebraicType::Product(old.params.clone()));
+            let new_params = resolve(self.new, &AlgebraicType::Product(new.params.clone()));
+            let mut changed = |detail: String, breaking| {
+                self.push(
+                    SchemaItem::Reducer,
+                    None,
+                    name,
+                    ChangeKind::Changed,
+                    Some(detail),
+                    breaking,
+                )
+            };
+            if old_params != new_params {
+                let detail = format!(
+                    "params: {} -> {}",
+                    PrettyAlgebraicType(old_params),
+                    PrettyAlgebraicType(new_params)
+                );
+                changed(detail, true);
+            }
+            if old.lifecycle != new.lifecycle {
+                // Lifecycle reducers can't be called by clients.
+                let breaking = new.lifecycle.is_some();
+                changed(
+                    format!("lifecycle: {:?} -> {:?}", old.lifecycle, new.lifecycle),
+                    breaking,
+                );
+            }
+            if old.client_callable != new.client_callable {
+                let breaking = !new.client_callable;
+                changed(
+                    format!("client callable: {} -> {}", old.client_callable, new.client_callable),
+                    breaking,
+                );
+            }
+            if old.acknowledged != new.acknowledged {
+                changed(
+                    format!("acknowledged: {} -> {}", old.acknowledged, new.acknowledged),
+                    false,
+                );
+            }
+        }
+    }
+
+    /// Policies are matched by their SQL, so a changed policy is removed and added.
+    ///
+    /// Policies filter what clients see, but don't change how they see it.
+    fn row_level_security(&mut self) {
+        for rls_diff in diff(self.old, self.new, ModuleDef::row_level_security) {
+            let sql = diff_key(&rls_diff, |rls| &rls.sql[..]);
+            self.added_or_removed(SchemaItem::RowLevelSecurity, None, sql, &rls_diff, (false, false));
+        }
+    }
+}
+
+/// The name of the item of `diff`, by `name`.
+fn diff_key<'def, T>(diff: &Diff<'def, T>, name: impl Fn(&'def T) -> &'def str) -> &'def str {
+    match *diff {
+        Diff::Add { new } => name(new),
+        Diff::Remove { old } => name(old),
+        Diff::MaybeChange { old, .. } => name(old),
+    }
+}
+
+/// `ty` with the references into the typespace of `def` resolved, so that it can be compared across modules.
+fn resolve(def: &ModuleDef, ty: &AlgebraicType) -> AlgebraicType {
+    WithTypespace::new(def.typespace(), ty)
+        .resolve_refs()
+        .expect("valid ModuleDef must have valid type refs")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spacetimedb_lib::db::raw_def::v9::{btree, RawModuleDefV9Builder};
+    use spacetimedb_lib::{bsatn, ProductType};
+    use ChangeKind::*;
+    use SchemaItem::*;
+
+    fn module_def(build: impl FnOnce(&mut RawModuleDefV9Builder)) -> ModuleDef {
+        let mut builder = RawModuleDefV9Builder::new();
+        build(&mut builder);
+        builder.finish().try_into().expect("should be a valid module def")
+    }
+
+    fn person(builder: &mut RawModuleDefV9Builder, columns: ProductType, access: TableAccess) {
+        builder
+            .build_table_with_new_type("person", columns, true)
+            .with_auto_inc_primary_key(0)
+            .with_index(btree(0), "id")
+            .with_access(access)
+            .finish();
+    }
+
+    fn person_columns() -> ProductType {
+        ProductType::from([("id", AlgebraicType::U64), ("name", AlgebraicType::String)])
+    }
+
+    fn add_person(builder: &mut RawModuleDefV9Builder) {
+        builder.add_reducer("add_person", ProductType::from([("name", AlgebraicType::String)]), None);
+    }
+
+    /// The first version of a module, which the tests evolve.
+    fn v1(builder: &mut RawModuleDefV9Builder) {
+        person(builder, person_columns(), TableAccess::Public);
+        add_person(builder);
+    }
+
+    fn changes(diff: &SchemaDiff) -> Vec<(SchemaItem, &str, ChangeKind, bool)> {
+        diff.changes
+            .iter()
+            .map(|change| (change.item, &*change.name, change.kind, change.breaking))
+            .collect()
+    }
+
+    #[test]
+    fn the_same_schema_has_no_changes() {
+        let diff = SchemaDiff::between(&module_def(v1), &module_def(v1));
+        assert!(diff.is_empty());
+        assert_eq!(diff.summary(), SchemaDiffSummary::default());
+    }
+
+    #[test]
+    fn additions_are_not_breaking() {
+        let new = module_def(|builder| {
+            builder
+                .build_table_with_new_type("person", person_columns(), true)
+                .with_auto_inc_primary_key(0)
+                .with_index(btree(0), "id")
+                .with_index(btree(1), "name")
+                .with_access(TableAccess::Public)
+                .finish();
+            add_person(builder);
+            builder
+                .build_table_with_new_type("pet", ProductType::from([("id", AlgebraicType::U64)]), true)
+                .with_auto_inc_primary_key(0)
+                .with_index(btree(0), "id")
+                .with_access(TableAccess::Public)
+                .finish();
+            builder.add_reducer("add_pet", ProductType::unit(), None);
+            builder.add_row_level_security("SELECT * FROM pet");
+        });
+        let diff = SchemaDiff::between(&module_def(v1), &new);
+
+        // The columns, index, constraint and sequence of `pet` are part of adding it.
+        assert_eq!(
+            changes(&diff),
+            [
+                (Table, "pet", Added, false),
+                (Index, "person_name_idx_btree", Added, false),
+                (Reducer, "add_pet", Added, false),
+                (RowLevelSecurity, "SELECT * FROM pet", Added, false),
+            ]
+        );
+        assert_eq!(diff.changes[1].table.as_deref(), Some("person"));
+        assert!(!diff.is_breaking());
+        assert_eq!(
+            diff.summary(),
+            SchemaDiffSummary {
+                added: 4,
+                removed: 0,
+                changed: 0,
+                breaking: false,
+            }
+        );
+    }
+
+    #[test]
+    fn removals_are_breaking_unless_only_the_server_sees_them() {
+        let diff = SchemaDiff::between(&module_def(v1), &module_def(|_| {}));
+        assert_eq!(
+            changes(&diff),
+            [(Table, "person", Removed, true), (Reducer, "add_person", Removed, true)]
+        );
+
+        // Subscriptions may rely on an index, but not on a sequence.
+        let old = module_def(|builder| {
+            builder
+                .build_table_with_new_type("person", person_columns(), true)
+                .with_auto_inc_primary_key(0)
+                .with_index(btree(0), "id")
+                .with_index(btree(1), "name")
+                .with_access(TableAccess::Public)
+                .finish();
+            add_person(builder);
+        });
+        let new = module_def(|builder| {
+            builder
+                .build_table_with_new_type("person", person_columns(), true)
+                .with_primary_key(0)
+                .with_unique_constraint(0)
+                .with_index(btree(0), "id")
+                .with_access(TableAccess::Public)
+                .finish();
+            add_person(builder);
+        });
+        let diff = SchemaDiff::between(&old, &new);
+        assert_eq!(
+            changes(&diff),
+            [
+                (Index, "person_name_idx_btree", Removed, true),
+                (Sequence, "person_id_seq", Removed, false),
+            ]
+        );
+        assert_eq!(
+            diff.summary(),
+            SchemaDiffSummary {
+                added: 0,
+                removed: 2,
+                changed: 0,
+                breaking: true,
+            }
+        );
+    }
+
+    #[test]
+    fn column_changes_are_breaking() {
+        let new = module_def(|builder| {
+            let columns = ProductType::from([
+                ("id", AlgebraicType::U64),
+                ("nickname", AlgebraicType::String),
+                ("age", AlgebraicType::U8),
+            ]);
+            person(builder, columns, TableAccess::Public);
+            add_person(builder);
+        });
+        let diff = SchemaDiff::between(&module_def(v1), &new);
+        assert_eq!(
+            changes(&diff),
+            [
+                (Column, "age", Added, true),
+                (Column, "name", Removed, true),
+                (Column, "nickname", Added, true),
+            ]
+        );
+
+        let new = module_def(|builder| {
+            let columns = ProductType::from([("id", AlgebraicType::U32), ("name", AlgebraicType::String)]);
+            person(builder, columns, TableAccess::Public);
+            add_person(builder);
+        });
+        let diff = SchemaDiff::between(&module_def(v1), &new);
+        assert_eq!(changes(&diff), [(Column, "id", Changed, true)]);
+        assert_eq!(diff.changes[0].table.as_deref(), Some("person"));
+        assert_eq!(diff.changes[0].detail.as_deref(), Some("type: U64 -> U32"));
+    }
+
+    #[test]
+    fn types_are_compared_across_typespaces() {
+        let with_status = |banned: bool, padding: bool| {
+            module_def(move |builder| {
+                if padding {
+                    // Moves `status` along the typespace, which alone changes nothing.
+                    let padding = AlgebraicType::product([("x", AlgebraicType::U8)]);
+                    builder.add_algebraic_type([], "padding", padding, true);
+                }
+                let status = match banned {
+                    false => AlgebraicType::sum([("active", AlgebraicType::unit())]),
+                    true => AlgebraicType::sum([("active", AlgebraicType::unit()), ("banned", AlgebraicType::unit())]),
+                };
+                let status = builder.add_algebraic_type([], "status", status, true);
+                let columns = ProductType::from([("id", AlgebraicType::U64), ("status", status.into())]);
+                person(builder, columns, TableAccess::Public);
+            })
+        };
+        let old = with_status(false, false);
+        assert!(SchemaDiff::between(&old, &with_status(false, true)).is_empty());
+
+        // Old clients can't decode the new variant.
+        let diff = SchemaDiff::between(&old, &with_status(true, true));
+        assert_eq!(changes(&diff), [(Column, "status", Changed, true)]);
+    }
+
+    #[test]
+    fn visibility_changes_break_only_when_narrowing() {
+        let private = module_def(|builder| {
+            person(builder, person_columns(), TableAccess::Private);
+            add_person(builder);
+            builder.set_reducer_client_callable("add_person", false);
+        });
+        let public = module_def(v1);
+
+        let narrowed = SchemaDiff::between(&public, &private);
+        assert_eq!(
+            changes(&narrowed),
+            [(Table, "person", Changed, true), (Reducer, "add_person", Changed, true)]
+        );
+        assert_eq!(narrowed.changes[0].detail.as_deref(), Some("access: Public -> Private"));
+
+        let widened = SchemaDiff::between(&private, &public);
+        assert_eq!(
+            changes(&widened),
+            [
+                (Table, "person", Changed, false),
+                (Reducer, "add_person", Changed, false)
+            ]
+        );
+        assert!(!widened.is_breaking());
+    }
+
+    #[test]
+    fn reducer_parameter_changes_are_breaking() {
+        let new = module_def(|builder| {
+            person(builder, person_columns(), TableAccess::Public);
+            let params = ProductType::from([("name", AlgebraicType::String), ("age", AlgebraicType::U8)]);
+            builder.add_reducer("add_person", params, None);
+            builder.set_reducer_acknowledged("add_person", true);
+        });
+        let diff = SchemaDiff::between(&module_def(v1), &new);
+        let mut details = diff
+            .changes
+            .iter()
+            .map(|change| (change.detail.as_deref().unwrap(), change.breaking))
+            .collect::<Vec<_>>();
+        details.sort();
+        assert_eq!(
+            details,
+            [
+                ("acknowledged: false -> true", false),
+                ("params: (name: String) -> (name: String, age: U8)", true),
+            ]
+        );
+        assert!(diff.is_breaking());
+    }
+
+    #[test]
+    fn diffs_round_trip_through_bsatn() {
+        let diff = SchemaDiff::between(&module_def(v1), &module_def(|_| {}));
+        let bytes = bsatn::to_vec(&diff).unwrap();
+        assert_eq!(bsatn::from_slice::<SchemaDiff>(&bytes).unwrap(), diff);
+    }
+}
@@ -35,7 +35,7 @@ use futures_channel::mpsc;
 use http::Uri;
 use spacetimedb_client_api_messages::websocket as ws;
 use spacetimedb_client_api_messages::websocket::{BsatnFormat, CallReducerFlags, Compression};
-use spacetimedb_lib::{bsatn, ser::Serialize, ConnectionId, Identity};
+use spacetimedb_lib::{bsatn, ser::Serialize, ConnectionId, Identity, Timestamp};
 use std::{
     collections::HashMap,
     sync::{atomic::AtomicU32, Arc, Mutex as StdMutex, OnceLock},
@@ -139,6 +139,56 @@ impl<M: SpacetimeModule> DbContextImpl<M> {
                 Ok(())
             }
 
+            // The host will close the connection at `deadline`:
+            // let the user reconnect at a convenient moment before then.
+            ParsedMessage::ReconnectRequested {
+                deadline,
+                reconnect_token,
+            } => {
+                let mut inner = self.inner.lock().unwrap();
+                if let Some(on_reconnect_requested) = inner.on_reconnect_requested.take() {
+                    let ctx = <M::DbConnection as DbConnection>::new(self.clone());
+                    on_reconnect_requested(&ctx, deadline, reconnect_token.as_deref());
+                }
+                Ok(())
+            }
+
+            // The host will eventually stop supporting something the SDK does:
+            // let the developer know.
+            ParsedMessage::DeprecationNotice(ws::DeprecationNotice {
+                feature,
+                message,
+                removal_version,
+            }) => {
+                match removal_version {
+                    Some(version) => {
+                        log::warn!("Deprecated `{feature}`, to be removed in SpacetimeDB {version}: {message}")
+                    }
+                    None => log::warn!("Deprecated `{feature}`: {message}"),
+                }
+                Ok(())
+            }
+
+            // The host hasn't heard from the client in a while, though it's still sending to it,
+            // so the connection is effectively read-only: let the developer know.
+            ParsedMessage::ConnectionDegraded(ws::ConnectionDegraded { last_inbound_ms }) => {
+                log::warn!(
+                    "The host last received a message from this client {last_inbound_ms}ms ago, reconnecting is recommended"
+                );
+                Ok(())
+            }
+
+            // The host skipped updates while the client was behind, so the client cache is out of date:
+            // let the developer know which subscriptions to resubscribe to.
+            ParsedMessage::ResyncRequired(ws::ResyncRequired { query_ids, legacy }) => {
+                let query_ids = query_ids.iter().map(|query_id| query_id.id).collect::<Vec<_>>();
+                log::warn!(
+                    "The host skipped updates to the subscriptions {query_ids:?}{} while this client was behind, resubscribing is recommended",
+                    if legacy { " and the legacy subscription" } else { "" }
+                );
+                Ok(())
+            }
+
             // Subscription applied:
             // set the received state to store all the rows,
             // then invoke the on-applied and row callbacks.
@@ -176,6 +226,24 @@ impl<M: SpacetimeModule> DbContextImpl<M> {
                 }
                 Ok(())
             }
+            // Transaction update the host asked us to acknowledge:
+            // apply it as any other, then acknowledge it,
+            // so that it's not delivered again when we reconnect.
+            ParsedMessage::AcknowledgedUpdate {
+                delivery_id,
+                event,
+                db_update,
+            } => {
+                self.process_message(ParsedMessage::TransactionUpdate(event, db_update))?;
+                self.send_chan
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .ok_or(crate::Error::Disconnected)?
+                    .unbounded_send(ws::ClientMessage::Ack(ws::Ack { delivery_id }))
+                    .expect("Unable to send ack message: WS sender loop has dropped its recv channel");
+                Ok(())
+            }
             ParsedMessage::SubscribeApplied {
                 query_id,
                 initial_update,
@@ -343,6 +411,7 @@ impl<M: SpacetimeModule> DbContextImpl<M> {
                     args: args_bsatn.into(),
                     request_id: 0,
                     flags,
+                    traceparent: None,
                 });
                 self.send_chan
                     .lock()
@@ -686,6 +755,9 @@ type OnConnectErrorCallback<M> = Box<dyn FnOnce(&<M as SpacetimeModule>::ErrorCo
 type OnDisconnectCallback<M> =
     Box<dyn FnOnce(&<M as SpacetimeModule>::ErrorContext, Option<crate::Error>) + Send + 'static>;
 
+type OnReconnectRequestedCallback<M> =
+    Box<dyn FnOnce(&<M as SpacetimeModule>::DbConnection, Timestamp, Option<&str>) + Send + 'static>;
+
 /// All the stuff in a [`DbContextImpl`] which can safely be locked while invoking callbacks.
 pub(crate) struct DbContextImplInner<M: SpacetimeModule> {
     /// `Some` if not within the context of an outer runtime. The `Runtime` must
@@ -702,6 +774,7 @@ pub(crate) struct DbContextImplInner<M: SpacetimeModule> {
     // TODO: Make use of this to handle `ParsedMessage::Error` before receiving `IdentityToken`.
     on_connect_error: Option<OnConnectErrorCallback<M>>,
     on_disconnect: Option<OnDisconnectCallback<M>>,
+    on_reconnect_requested: Option<OnReconnectRequestedCallback<M>>,
 
     call_reducer_flags: CallReducerFlagsMap,
 }
@@ -746,6 +819,7 @@ pub struct DbConnectionBuilder<M: SpacetimeModule> {
     on_connect: Option<OnConnectCallback<M>>,
     on_connect_error: Option<OnConnectErrorCallback<M>>,
     on_disconnect: Option<OnDisconnectCallback<M>>,
+    on_reconnect_requested: Option<OnReconnectRequestedCallback<M>>,
 
     params: WsParams,
 }
@@ -793,6 +867,7 @@ impl<M: SpacetimeModule> DbConnectionBuilder<M> {
             on_connect: None,
             on_connect_error: None,
             on_disconnect: None,
+            on_reconnect_requested: None,
             params: <_>::default(),
         }
     }
@@ -860,6 +935,7 @@ but you must call one of them, or else the connection will never progress.
             on_connect: self.on_connect,
             on_connect_error: self.on_connect_error,
             on_disconnect: self.on_disconnect,
+            on_reconnect_requested: self.on_reconnect_requested,
             call_reducer_flags: <_>::default(),
         }));
 
@@ -937,6 +1013,27 @@ but you must call one of them, or else the connection will never progress.
         self
     }
 
+    /// Reconnect with a reconnect token the host issued to a previous connection,
+    /// e.g. the one passed to the [`Self::on_reconnect_requested`] callback.
+    ///
+    /// The token stands in for the credentials the previous connection authenticated with,
+    /// and can only be used once, to connect to the same database.
+    pub fn with_reconnect_token(mut self, reconnect_token: Option<impl Into<String>>) -> Self {
+        self.params.reconnect_token = reconnect_token.map(|token| token.into());
+        self
+    }
+
+    /// Opt into acknowledged delivery of the updates of reducers the module declared acknowledged.
+    ///
+    /// Each such update is acknowledged to the host once it's applied and its callbacks have run.
+    /// Updates the host didn't receive an acknowledgment for
+    /// are delivered again when connecting with the same identity,
+    /// after the `on_connect` callback.
+    pub fn with_acknowledged_delivery(mut self, acknowledged_delivery: bool) -> Self {
+        self.params.acknowledged_delivery = acknowledged_delivery;
+        self
+    }
+
     /// Register a callback to run when the connection is successfully initiated.
     ///
     /// The callback will receive three arguments:
@@ -990,6 +1087,29 @@ Instead of registering multiple `on_disconnect` callbacks, register a single cal
         self.on_disconnect = Some(Box::new(callback));
         self
     }
+
+    /// Register a callback to run when the host asks the client to reconnect,
+    /// as it limits how long connections may live.
+    ///
+    /// The callback will receive three arguments:
+    /// - The `DbConnection` which the host will close.
+    /// - When the host will close the connection.
+    /// - A reconnect token which can be passed to [`Self::with_reconnect_token`]
+    ///   when building the new connection, if the host issued one.
+    pub fn on_reconnect_requested(
+        mut self,
+        callback: impl FnOnce(&M::DbConnection, Timestamp, Option<&str>) + Send + 'static,
+    ) -> Self {
+        if self.on_reconnect_requested.is_some() {
+            panic!(
+                "DbConnectionBuilder can only register a single `on_reconnect_requested` callback.
+
+Instead of registering multiple `on_reconnect_requested` callbacks, register a single callback which does multiple operations."
+            );
+        }
+        self.on_reconnect_requested = Some(Box::new(callback));
+        self
+    }
 }
 
 // When called from within an async context, return a handle to it (and no
@@ -1018,12 +1138,40 @@ fn enter_or_create_runtime() -> crate::Result<(Option<Runtime>, runtime::Handle)
 }
 
 enum ParsedMessage<M: SpacetimeModule> {
-    InitialSubscription { db_update: M::DbUpdate, sub_id: u32 },
+    InitialSubscription {
+        db_update: M::DbUpdate,
+        sub_id: u32,
+    },
     TransactionUpdate(Event<M::Reducer>, Option<M::DbUpdate>),
+    /// A `TransactionUpdate` to acknowledge once it's applied.
+    AcknowledgedUpdate {
+        delivery_id: u64,
+        event: Event<M::Reducer>,
+        db_update: Option<M::DbUpdate>,
+    },
     IdentityToken(Identity, Box<str>, ConnectionId),
-    SubscribeApplied { query_id: u32, initial_update: M::DbUpdate },
-    UnsubscribeApplied { query_id: u32, initial_update: M::DbUpdate },
-    SubscriptionError { query_id: Option<u32>, error: String },
+    ReconnectRequested {
+        deadline: Timestamp,
+        reconnect_token: Option<Box<str>>,
+    },
+    SubscribeApplied {
+        query_id: u32,
+        initial_update: M::DbUpdate,
+    },
+    UnsubscribeApplied {
+        query_id: u32,
+        initial_update: M::DbUpdate,
+    },
+    SubscriptionError {
+        query_id: Option<u32>,
+        error: String,
+    },
+    /// The host warned that the client used a deprecated feature.
+    DeprecationNotice(ws::DeprecationNotice),
+    /// The host warned that the client's messages stopped reaching it.
+    ConnectionDegraded(ws::ConnectionDegraded),
+    /// The host skipped updates to some of the client's subscriptions while it was behind.
+    ResyncRequired(ws::ResyncRequired),
     Error(crate::Error),
 }
 
@@ -1056,35 +1204,9 @@ async fn parse_loop<M: SpacetimeModule>(
                             .into(),
                     )
                 }),
-            ws::ServerMessage::TransactionUpdate(ws::TransactionUpdate {
-                status,
-                timestamp,
-                caller_identity,
-                caller_connection_id,
-                reducer_call,
-                energy_quanta_used,
-                ..
-            }) => match Status::parse_status_and_update::<M>(status) {
-                Err(e) => ParsedMessage::Error(
-                    InternalError::failed_parse("Status", "TransactionUpdate")
-                        .with_cause(e)
-                        .into(),
-                ),
-                Ok((status, db_update)) => {
-                    let event = M::Reducer::try_from(reducer_call)
-                        .map(|reducer| {
-                            Event::Reducer(ReducerEvent {
-                                caller_connection_id: caller_connection_id.none_if_zero(),
-                                caller_identity,
-                                energy_consumed: Some(energy_quanta_used.quanta),
-                                timestamp,
-                                reducer,
-                                status,
-                            })
-                        })
-                        .unwrap_or(Event::UnknownTransaction);
-                    ParsedMessage::TransactionUpdate(event, db_update)
-                }
+            ws::ServerMessage::TransactionUpdate(update) => match parse_transaction_update::<M>(update) {
+                Err(e) => ParsedMessage::Error(e),
+                Ok((event, db_update)) => ParsedMessage::TransactionUpdate(event, db_update),
             },
             ws::ServerMessage::TransactionUpdateLight(ws::TransactionUpdateLight { update, request_id: _ }) => {
                 match M::DbUpdate::parse_update(update) {
@@ -1139,12 +1261,84 @@ async fn parse_loop<M: SpacetimeModule>(
                 error: e.error.to_string(),
             },
             ws::ServerMessage::SubscribeApplied(_) => unreachable!("Rust client SDK never sends `SubscribeSingle`, but received a `SubscribeApplied` from the host... huh?"),
-            ws::ServerMessage::UnsubscribeApplied(_) => unreachable!("Rust client SDK never sends `UnsubscribeSingle`, but received a `UnsubscribeApplied` from the host... huh?")
+            ws::ServerMessage::SubscribeWindowApplied(_) => unreachable!("Rust client SDK never sends `SubscribeWindow`, but received a `SubscribeWindowApplied` from the host... huh?"),
+            ws::ServerMessage::UnsubscribeApplied(_) => unreachable!("Rust client SDK never sends `UnsubscribeSingle`, but received a `UnsubscribeApplied` from the host... huh?"),
+            ws::ServerMessage::SubscribeMultiExplained(_) => unreachable!("Rust client SDK never sends `ExplainSubscribeMulti`, but received a `SubscribeMultiExplained` from the host... huh?"),
+            ws::ServerMessage::DatabaseStats(_) => unreachable!("Rust client SDK never asks for database stats, but received a `DatabaseStats` from the host... huh?"),
+            ws::ServerMessage::ReducerTimings(_) => unreachable!("Rust client SDK never asks for reducer timings, but received a `ReducerTimings` from the host... huh?"),
+            ws::ServerMessage::ConnectionStatus(_) => unreachable!("Rust client SDK never sends `GetConnectionStatus`, but received a `ConnectionStatus` from the host... huh?"),
+            ws::ServerMessage::RequestError(_) => unreachable!("Rust client SDK never asks for request errors, but received a `RequestError` from the host... huh?"),
+            ws::ServerMessage::SettingsUpdated(_) => unreachable!("Rust client SDK never sends `UpdateSettings`, but received a `SettingsUpdated` from the host... huh?"),
+            ws::ServerMessage::TokenRefreshed(_) => unreachable!("Rust client SDK never sends `RefreshToken`, but received a `TokenRefreshed` from the host... huh?"),
+            ws::ServerMessage::QueryRows(_) => unreachable!("Rust client SDK never sends `Query`, but received a `QueryRows` from the host... huh?"),
+            ws::ServerMessage::ReconnectRequested(ws::ReconnectRequested {
+                deadline,
+                reconnect_token,
+            }) => ParsedMessage::ReconnectRequested {
+                deadline,
+                reconnect_token,
+            },
+            ws::ServerMessage::AcknowledgedUpdate(ws::AcknowledgedUpdate { delivery_id, update }) => {
+                match parse_transaction_update::<M>(update) {
+                    Err(e) => ParsedMessage::Error(e),
+                    Ok((event, db_update)) => ParsedMessage::AcknowledgedUpdate {
+                        delivery_id,
+                        event,
+                        db_update,
+                    },
+                }
+            }
+            ws::ServerMessage::MessageDropped(ws::MessageDropped { reason, message_kind }) => {
+                ParsedMessage::Error(
+                    InternalError::new(format!(
+                        "Host dropped a `{message_kind}` message ({reason:?}); the client cache may be out of date"
+                    ))
+                    .into(),
+                )
+            }
+            ws::ServerMessage::DeprecationNotice(notice) => ParsedMessage::DeprecationNotice(notice),
+            ws::ServerMessage::ConnectionDegraded(notice) => ParsedMessage::ConnectionDegraded(notice),
+            ws::ServerMessage::ResyncRequired(notice) => ParsedMessage::ResyncRequired(notice),
+            // Progress is advisory, always followed by the call's reply,
+            // and the SDK doesn't yet expose it, so it's skipped.
+            ws::ServerMessage::CallProgress(_) => continue,
+            // The SDK goes by what it negotiated when connecting, so it has no use for the server's capabilities yet.
+            ws::ServerMessage::ServerCapabilities(_) => continue,
         })
         .expect("Failed to send ParsedMessage to main thread");
     }
 }
 
+/// Parse a `TransactionUpdate` into its event and, if it committed, its update.
+#[allow(clippy::type_complexity)]
+fn parse_transaction_update<M: SpacetimeModule>(
+    ws::TransactionUpdate {
+        status,
+        timestamp,
+        caller_identity,
+        caller_connection_id,
+        reducer_call,
+        energy_quanta_used,
+        ..
+    }: ws::TransactionUpdate<BsatnFormat>,
+) -> crate::Result<(Event<M::Reducer>, Option<M::DbUpdate>)> {
+    let (status, db_update) = Status::parse_status_and_update::<M>(status)
+        .map_err(|e| crate::Error::from(InternalError::failed_parse("Status", "TransactionUpdate").with_cause(e)))?;
+    let event = M::Reducer::try_from(reducer_call)
+        .map(|reducer| {
+            Event::Reducer(ReducerEvent {
+                caller_connection_id: caller_connection_id.none_if_zero(),
+                caller_identity,
+                energy_consumed: Some(energy_quanta_used.quanta),
+                timestamp,
+                reducer,
+                status,
+            })
+        })
+        .unwrap_or(Event::UnknownTransaction);
+    Ok((event, db_update))
+}
+
 /// Operations a user can make to a `DbContext` which must be postponed
 pub(crate) enum PendingMutation<M: SpacetimeModule> {
     // TODO: Rename to `SubscribeLegacy`, or replace with `SubscribeToAllTables`.
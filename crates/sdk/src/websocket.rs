@@ -11,8 +11,7 @@ use futures::{SinkExt, StreamExt as _, TryStreamExt};
 use futures_channel::mpsc;
 use http::uri::{InvalidUri, Scheme, Uri};
 use spacetimedb_client_api_messages::websocket::{
-    brotli_decompress, gzip_decompress, BsatnFormat, Compression, BIN_PROTOCOL, SERVER_MSG_COMPRESSION_TAG_BROTLI,
-    SERVER_MSG_COMPRESSION_TAG_GZIP, SERVER_MSG_COMPRESSION_TAG_NONE,
+    brotli_decompress, gzip_decompress, zstd_decompress, BsatnFormat, Compression, BIN_PROTOCOL_V2,
 };
 use spacetimedb_client_api_messages::websocket::{ClientMessage, ServerMessage};
 use spacetimedb_lib::{bsatn, ConnectionId};
@@ -106,10 +105,14 @@ fn parse_scheme(scheme: Option<Scheme>) -> Result<Scheme, UriError> {
     })
 }
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Default)]
 pub(crate) struct WsParams {
     pub compression: Compression,
     pub light: bool,
+    /// A reconnect token standing in for the credentials of the connection.
+    pub reconnect_token: Option<String>,
+    /// Whether to acknowledge the updates of acknowledged reducers.
+    pub acknowledged_delivery: bool,
 }
 
 fn make_uri(host: Uri, db_name: &str, connection_id: ConnectionId, params: WsParams) -> Result<Uri, UriError> {
@@ -145,6 +148,7 @@ fn make_uri(host: Uri, db_name: &str, connection_id: ConnectionId, params: WsPar
         // The host uses the same default as the sdk,
         // but in case this changes, we prefer to be explicit now.
         Compression::Brotli => path.push_str("&compression=Brotli"),
+        Compression::Zstd => path.push_str("&compression=Zstd"),
     };
 
     // Specify the `light` mode if requested.
@@ -152,6 +156,17 @@ fn make_uri(host: Uri, db_name: &str, connection_id: ConnectionId, params: WsPar
         path.push_str("&light=true");
     }
 
+    // Opt into acknowledged delivery if requested.
+    if params.acknowledged_delivery {
+        path.push_str("&acknowledged_delivery=true");
+    }
+
+    // Reconnect with the token the host issued, if any.
+    if let Some(reconnect_token) = &params.reconnect_token {
+        path.push_str("&reconnect_token=");
+        path.push_str(reconnect_token);
+    }
+
     parts.path_and_query = Some(path.parse().map_err(|source: InvalidUri| UriError::InvalidUri {
         source: Arc::new(source),
     })?);
@@ -189,7 +204,7 @@ fn make_request(
 fn request_insert_protocol_header(req: &mut http::Request<()>) {
     req.headers_mut().insert(
         http::header::SEC_WEBSOCKET_PROTOCOL,
-        const { http::HeaderValue::from_static(BIN_PROTOCOL) },
+        const { http::HeaderValue::from_static(BIN_PROTOCOL_V2) },
     );
 }
 
@@ -234,30 +249,32 @@ impl WsConnection {
     }
 
     pub(crate) fn parse_response(bytes: &[u8]) -> Result<ServerMessage<BsatnFormat>, WsError> {
-        let (compression, bytes) = bytes.split_first().ok_or(WsError::EmptyMessage)?;
-
-        Ok(match *compression {
-            SERVER_MSG_COMPRESSION_TAG_NONE => {
-                bsatn::from_slice(bytes).map_err(|source| WsError::DeserializeMessage { source })?
-            }
-            SERVER_MSG_COMPRESSION_TAG_BROTLI => {
-                bsatn::from_slice(&brotli_decompress(bytes).map_err(|source| WsError::Decompress {
-                    scheme: "brotli",
-                    source: Arc::new(source),
-                })?)
-                .map_err(|source| WsError::DeserializeMessage { source })?
+        let (&tag, bytes) = bytes.split_first().ok_or(WsError::EmptyMessage)?;
+        let compression = Compression::from_tag(tag).ok_or(WsError::UnknownCompressionScheme { scheme: tag })?;
+
+        let decompress = |scheme: &'static str, decompress: fn(&[u8]) -> std::io::Result<Vec<u8>>| {
+            decompress(bytes).map_err(|source| WsError::Decompress {
+                scheme,
+                source: Arc::new(source),
+            })
+        };
+        let decompressed;
+        let bytes = match compression {
+            Compression::None => bytes,
+            Compression::Brotli => {
+                decompressed = decompress("brotli", brotli_decompress)?;
+                &decompressed
             }
-            SERVER_MSG_COMPRESSION_TAG_GZIP => {
-                bsatn::from_slice(&gzip_decompress(bytes).map_err(|source| WsError::Decompress {
-                    scheme: "gzip",
-                    source: Arc::new(source),
-                })?)
-                .map_err(|source| WsError::DeserializeMessage { source })?
+            Compression::Gzip => {
+                decompressed = decompress("gzip", gzip_decompress)?;
+                &decompressed
             }
-            c => {
-                return Err(WsError::UnknownCompressionScheme { scheme: c });
+            Compression::Zstd => {
+                decompressed = decompress("zstd", zstd_decompress)?;
+                &decompressed
             }
-        })
+        };
+        bsatn::from_slice(bytes).map_err(|source| WsError::DeserializeMessage { source })
     }
 
     pub(crate) fn encode_message(msg: ClientMessage<Bytes>) -> WebSocketMessage {
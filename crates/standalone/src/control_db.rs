@@ -3,15 +3,22 @@ use sled::transaction::{
     self, ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionResult,
     Transactional, TransactionalTree,
 };
+use spacetimedb::client::uniques::{self, Day, IdentitySketch};
 use spacetimedb::energy;
 use spacetimedb::identity::Identity;
-use spacetimedb::messages::control_db::{Database, EnergyBalance, Node, Replica};
+use spacetimedb::messages::control_db::{
+    AuthExpiryPolicy, ClientConfigDefaults, ConnectionTimeouts, Database, EnergyBalance, FirehoseConfig,
+    MessageSizeLimits, NetworkAcl, Node, Replica, SendBacklogPolicy,
+};
 
 use spacetimedb_client_api_messages::name::{
     DomainName, DomainParsingError, InsertDomainResult, RegisterTldResult, SetDomainsResult, Tld, TldRef,
 };
-use spacetimedb_lib::bsatn;
+use spacetimedb_lib::hash::Hash;
+use spacetimedb_lib::{bsatn, DatabaseFeatures};
 use spacetimedb_paths::standalone::ControlDbDir;
+use spacetimedb_schema::schema_diff::SchemaDiff;
+use std::ops::RangeInclusive;
 
 #[cfg(test)]
 mod tests;
@@ -95,6 +102,25 @@ fn identity_from_le_ivec(ivec: &sled::IVec) -> Result<Identity> {
     Ok(Identity::from_byte_array(identity_bytes))
 }
 
+/// The key of the diff of the schema of `database_identity` from the schema with digest `from` to that with `to`,
+/// under which the diffs of a database are grouped.
+fn schema_diff_key(database_identity: &Identity, from: &Hash, to: &Hash) -> [u8; 96] {
+    let mut key = [0; 96];
+    key[..32].copy_from_slice(&database_identity.to_be_byte_array());
+    key[32..64].copy_from_slice(&from.data);
+    key[64..].copy_from_slice(&to.data);
+    key
+}
+
+/// The key of the sketch of the identities which connected to `database_identity` on `day`,
+/// under which the sketches of a database are ordered by day.
+fn unique_identities_key(database_identity: &Identity, day: Day) -> [u8; 36] {
+    let mut key = [0; 36];
+    key[..32].copy_from_slice(&database_identity.to_be_byte_array());
+    key[32..].copy_from_slice(&day.to_be_bytes());
+    key
+}
+
 impl ControlDb {
     pub fn spacetime_dns(&self, domain: &str) -> Result<Option<Identity>> {
         let tree = self.db.open_tree("dns")?;
@@ -387,12 +413,295 @@ impl ControlDb {
 
             tree_by_identity.remove(&key[..])?;
             tree.remove(id.to_be_bytes())?;
+            self.db.open_tree("network_acl")?.remove(&key[..])?;
+            self.db.open_tree("client_config_defaults")?.remove(&key[..])?;
+            self.db.open_tree("connection_timeouts")?.remove(&key[..])?;
+            self.db.open_tree("message_size_limits")?.remove(&key[..])?;
+            self.db.open_tree("send_backlog_policy")?.remove(&key[..])?;
+            self.db.open_tree("auth_expiry_policy")?.remove(&key[..])?;
+            self.db.open_tree("database_features")?.remove(&key[..])?;
+            self.db.open_tree("firehose")?.remove(&key[..])?;
+            let schema_diffs = self.db.open_tree("schema_diff")?;
+            for diff in schema_diffs.scan_prefix(&key[..]).keys() {
+                schema_diffs.remove(diff?)?;
+            }
+            let unique_identities = self.db.open_tree("unique_identities")?;
+            for sketch in unique_identities.scan_prefix(&key[..]).keys() {
+                unique_identities.remove(sketch?)?;
+            }
             return Ok(Some(id));
         }
 
         Ok(None)
     }
 
+    pub fn get_network_acl(&self, database_identity: &Identity) -> Result<Option<NetworkAcl>> {
+        let tree = self.db.open_tree("network_acl")?;
+        let key = database_identity.to_be_byte_array();
+        match tree.get(&key[..])? {
+            Some(value) => Ok(Some(bsatn::from_slice(&value[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the network ACL of `database_identity`, removing it if `acl` is empty.
+    pub fn set_network_acl(&self, database_identity: &Identity, acl: &NetworkAcl) -> Result<()> {
+        let tree = self.db.open_tree("network_acl")?;
+        let key = database_identity.to_be_byte_array();
+        if acl.is_empty() {
+            tree.remove(&key[..])?;
+        } else {
+            tree.insert(&key[..], bsatn::to_vec(acl)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_client_config_defaults(&self, database_identity: &Identity) -> Result<Option<ClientConfigDefaults>> {
+        let tree = self.db.open_tree("client_config_defaults")?;
+        let key = database_identity.to_be_byte_array();
+        match tree.get(&key[..])? {
+            Some(value) => Ok(Some(bsatn::from_slice(&value[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the client config defaults of `database_identity`, removing them if `defaults` is empty.
+    pub fn set_client_config_defaults(
+        &self,
+        database_identity: &Identity,
+        defaults: &ClientConfigDefaults,
+    ) -> Result<()> {
+        let tree = self.db.open_tree("client_config_defaults")?;
+        let key = database_identity.to_be_byte_array();
+        if defaults.is_empty() {
+            tree.remove(&key[..])?;
+        } else {
+            tree.insert(&key[..], bsatn::to_vec(defaults)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_connection_timeouts(&self, database_identity: &Identity) -> Result<Option<ConnectionTimeouts>> {
+        let tree = self.db.open_tree("connection_timeouts")?;
+        let key = database_identity.to_be_byte_array();
+        match tree.get(&key[..])? {
+            Some(value) => Ok(Some(bsatn::from_slice(&value[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the connection timeouts of `database_identity`, removing them if `timeouts` is empty.
+    pub fn set_connection_timeouts(&self, database_identity: &Identity, timeouts: &ConnectionTimeouts) -> Result<()> {
+        let tree = self.db.open_tree("connection_timeouts")?;
+        let key = database_identity.to_be_byte_array();
+        if timeouts.is_empty() {
+            tree.remove(&key[..])?;
+        } else {
+            tree.insert(&key[..], bsatn::to_vec(timeouts)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_message_size_limits(&self, database_identity: &Identity) -> Result<Option<MessageSizeLimits>> {
+        let tree = self.db.open_tree("message_size_limits")?;
+        let key = database_identity.to_be_byte_array();
+        match tree.get(&key[..])? {
+            Some(value) => Ok(Some(bsatn::from_slice(&value[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the message size limits of `database_identity`, removing them if `limits` is empty.
+    pub fn set_message_size_limits(&self, database_identity: &Identity, limits: &MessageSizeLimits) -> Result<()> {
+        let tree = self.db.open_tree("message_size_limits")?;
+        let key = database_identity.to_be_byte_array();
+        if limits.is_empty() {
+            tree.remove(&key[..])?;
+        } else {
+            tree.insert(&key[..], bsatn::to_vec(limits)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_send_backlog_policy(&self, database_identity: &Identity) -> Result<Option<SendBacklogPolicy>> {
+        let tree = self.db.open_tree("send_backlog_policy")?;
+        let key = database_identity.to_be_byte_array();
+        match tree.get(&key[..])? {
+            Some(value) => Ok(Some(bsatn::from_slice(&value[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the send backlog policy of `database_identity`, removing it if `policy` is empty.
+    pub fn set_send_backlog_policy(&self, database_identity: &Identity, policy: &SendBacklogPolicy) -> Result<()> {
+        let tree = self.db.open_tree("send_backlog_policy")?;
+        let key = database_identity.to_be_byte_array();
+        if policy.is_empty() {
+            tree.remove(&key[..])?;
+        } else {
+            tree.insert(&key[..], bsatn::to_vec(policy)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_auth_expiry_policy(&self, database_identity: &Identity) -> Result<Option<AuthExpiryPolicy>> {
+        let tree = self.db.open_tree("auth_expiry_policy")?;
+        let key = database_identity.to_be_byte_array();
+        match tree.get(&key[..])? {
+            Some(value) => Ok(Some(bsatn::from_slice(&value[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the auth expiry policy of `database_identity`, removing it if `policy` is empty.
+    pub fn set_auth_expiry_policy(&self, database_identity: &Identity, policy: &AuthExpiryPolicy) -> Result<()> {
+        let tree = self.db.open_tree("auth_expiry_policy")?;
+        let key = database_identity.to_be_byte_array();
+        if policy.is_empty() {
+            tree.remove(&key[..])?;
+        } else {
+            tree.insert(&key[..], bsatn::to_vec(policy)?)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the features turned on for `database_identity`, if any are.
+    pub fn get_database_features(&self, database_identity: &Identity) -> Result<Option<DatabaseFeatures>> {
+        let tree = self.db.open_tree("database_features")?;
+        let key = database_identity.to_be_byte_array();
+        match tree.get(&key[..])? {
+            Some(value) => Ok(Some(DatabaseFeatures::from_bits(bsatn::from_slice(&value[..])?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the features of all databases which have any turned on, keyed by database identity.
+    pub fn get_all_database_features(&self) -> Result<Vec<(Identity, DatabaseFeatures)>> {
+        let tree = self.db.open_tree("database_features")?;
+        tree.iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let key: [u8; 32] = key[..].try_into().context("malformed database features key")?;
+                let database_identity = Identity::from_be_byte_array(key);
+                Ok((
+                    database_identity,
+                    DatabaseFeatures::from_bits(bsatn::from_slice(&value[..])?),
+                ))
+            })
+            .collect()
+    }
+
+    /// Replace the features turned on for `database_identity`, removing them if `features` is empty.
+    pub fn set_database_features(&self, database_identity: &Identity, features: DatabaseFeatures) -> Result<()> {
+        let tree = self.db.open_tree("database_features")?;
+        let key = database_identity.to_be_byte_array();
+        if features.is_empty() {
+            tree.remove(&key[..])?;
+        } else {
+            tree.insert(&key[..], bsatn::to_vec(&features.bits())?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_firehose(&self, database_identity: &Identity) -> Result<Option<FirehoseConfig>> {
+        let tree = self.db.open_tree("firehose")?;
+        let key = database_identity.to_be_byte_array();
+        match tree.get(&key[..])? {
+            Some(value) => Ok(Some(bsatn::from_slice(&value[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the firehoses of all databases which have one, keyed by database identity.
+    pub fn get_firehoses(&self) -> Result<Vec<(Identity, FirehoseConfig)>> {
+        let tree = self.db.open_tree("firehose")?;
+        tree.iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let key: [u8; 32] = key[..].try_into().context("malformed firehose key")?;
+                let database_identity = Identity::from_be_byte_array(key);
+                Ok((database_identity, bsatn::from_slice(&value[..])?))
+            })
+            .collect()
+    }
+
+    /// Replace the firehose of `database_identity`, removing it if `firehose` is `None`.
+    pub fn set_firehose(&self, database_identity: &Identity, firehose: Option<&FirehoseConfig>) -> Result<()> {
+        let tree = self.db.open_tree("firehose")?;
+        let key = database_identity.to_be_byte_array();
+        match firehose {
+            Some(firehose) => tree.insert(&key[..], bsatn::to_vec(firehose)?)?,
+            None => tree.remove(&key[..])?,
+        };
+        Ok(())
+    }
+
+    /// Returns the diff of the schema of `database_identity` from the schema with digest `from` to that with `to`,
+    /// if a publish made that change.
+    pub fn get_schema_diff(&self, database_identity: &Identity, from: &Hash, to: &Hash) -> Result<Option<SchemaDiff>> {
+        let tree = self.db.open_tree("schema_diff")?;
+        match tree.get(schema_diff_key(database_identity, from, to))? {
+            Some(value) => Ok(Some(bsatn::from_slice(&value[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Record that a publish changed the schema of `database_identity`
+    /// from the schema with digest `from` to that with `to` by `diff`.
+    pub fn insert_schema_diff(
+        &self,
+        database_identity: &Identity,
+        from: &Hash,
+        to: &Hash,
+        diff: &SchemaDiff,
+    ) -> Result<()> {
+        let tree = self.db.open_tree("schema_diff")?;
+        tree.insert(schema_diff_key(database_identity, from, to), bsatn::to_vec(diff)?)?;
+        Ok(())
+    }
+
+    /// Returns the sketches of the identities which connected to `database_identity` on each of `days`,
+    /// for those days on which any did.
+    pub fn get_unique_identities(
+        &self,
+        database_identity: &Identity,
+        days: RangeInclusive<Day>,
+    ) -> Result<Vec<(Day, IdentitySketch)>> {
+        let tree = self.db.open_tree("unique_identities")?;
+        let (first, last) = days.into_inner();
+        let range = unique_identities_key(database_identity, first)..=unique_identities_key(database_identity, last);
+        tree.range(range)
+            .map(|entry| {
+                let (key, value) = entry?;
+                let day = Day::from_be_bytes(key[32..].try_into().context("malformed unique identities key")?);
+                let sketch = IdentitySketch::from_bytes(&value).context("malformed unique identities sketch")?;
+                Ok((day, sketch))
+            })
+            .collect()
+    }
+
+    /// Merge `sketch` into the sketch of the identities which connected to `database_identity` on `day`,
+    /// dropping the database's sketches which have fallen out of the [retention](uniques::RETENTION_DAYS).
+    pub fn merge_unique_identities(
+        &self,
+        database_identity: &Identity,
+        day: Day,
+        sketch: &IdentitySketch,
+    ) -> Result<()> {
+        let tree = self.db.open_tree("unique_identities")?;
+        tree.update_and_fetch(unique_identities_key(database_identity, day), |persisted| {
+            let mut merged = persisted.and_then(IdentitySketch::from_bytes).unwrap_or_default();
+            merged.merge(sketch);
+            Some(merged.as_bytes().to_vec())
+        })?;
+        let cutoff = (day + 1).saturating_sub(uniques::RETENTION_DAYS);
+        let expired = unique_identities_key(database_identity, 0)..unique_identities_key(database_identity, cutoff);
+        for key in tree.range(expired).keys() {
+            tree.remove(key?)?;
+        }
+        Ok(())
+    }
+
     pub fn get_replicas(&self) -> Result<Vec<Replica>> {
         let tree = self.db.open_tree("replica")?;
         let mut replicas = Vec::new();
@@ -1,10 +1,12 @@
 use std::str::FromStr;
 
 use once_cell::sync::Lazy;
-use spacetimedb::messages::control_db::HostType;
+use spacetimedb::messages::control_db::{AuthExpiryAction, ClientDefault, HostType, SendBacklogAction};
+use spacetimedb::messages::websocket::Compression;
 use spacetimedb_client_api::auth::LOCALHOST;
 use spacetimedb_lib::error::ResultTest;
-use spacetimedb_lib::Hash;
+use spacetimedb_lib::hash::hash_bytes;
+use spacetimedb_lib::{DatabaseFeature, Hash};
 use tempfile::TempDir;
 
 use super::*;
@@ -152,3 +154,368 @@ fn test_decode() -> ResultTest<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_network_acl() -> ResultTest<()> {
+    let path = TempDir::with_prefix("network-acl")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    assert_eq!(cdb.get_network_acl(&BOB)?, None);
+
+    let acl = NetworkAcl {
+        allow: vec!["10.0.0.0/8".into(), "2001:db8::/32".into()],
+        deny: vec!["10.0.0.1/32".into()],
+        deny_as_not_found: true,
+    };
+    cdb.set_network_acl(&BOB, &acl)?;
+    assert_eq!(cdb.get_network_acl(&BOB)?, Some(acl.clone()));
+
+    // Setting an empty ACL removes it.
+    cdb.set_network_acl(&BOB, &NetworkAcl::default())?;
+    assert_eq!(cdb.get_network_acl(&BOB)?, None);
+
+    // Deleting the database removes its ACL.
+    cdb.set_network_acl(&BOB, &acl)?;
+    cdb.delete_database(id)?;
+    assert_eq!(cdb.get_network_acl(&BOB)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_client_config_defaults() -> ResultTest<()> {
+    let path = TempDir::with_prefix("client-config-defaults")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    assert_eq!(cdb.get_client_config_defaults(&BOB)?, None);
+
+    let defaults = ClientConfigDefaults {
+        compression: Some(ClientDefault {
+            value: Compression::Brotli,
+            forced: true,
+        }),
+        light: Some(ClientDefault {
+            value: true,
+            forced: false,
+        }),
+    };
+    cdb.set_client_config_defaults(&BOB, &defaults)?;
+    assert_eq!(cdb.get_client_config_defaults(&BOB)?, Some(defaults.clone()));
+
+    // Setting empty defaults removes them.
+    cdb.set_client_config_defaults(&BOB, &ClientConfigDefaults::default())?;
+    assert_eq!(cdb.get_client_config_defaults(&BOB)?, None);
+
+    // Deleting the database removes its defaults.
+    cdb.set_client_config_defaults(&BOB, &defaults)?;
+    cdb.delete_database(id)?;
+    assert_eq!(cdb.get_client_config_defaults(&BOB)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_connection_timeouts() -> ResultTest<()> {
+    let path = TempDir::with_prefix("connection-timeouts")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    assert_eq!(cdb.get_connection_timeouts(&BOB)?, None);
+
+    let timeouts = ConnectionTimeouts {
+        liveness_timeout_secs: Some(120),
+        send_timeout_secs: None,
+    };
+    cdb.set_connection_timeouts(&BOB, &timeouts)?;
+    assert_eq!(cdb.get_connection_timeouts(&BOB)?, Some(timeouts));
+
+    // Setting empty timeouts removes them.
+    cdb.set_connection_timeouts(&BOB, &ConnectionTimeouts::default())?;
+    assert_eq!(cdb.get_connection_timeouts(&BOB)?, None);
+
+    // Deleting the database removes its timeouts.
+    cdb.set_connection_timeouts(&BOB, &timeouts)?;
+    cdb.delete_database(id)?;
+    assert_eq!(cdb.get_connection_timeouts(&BOB)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_message_size_limits() -> ResultTest<()> {
+    let path = TempDir::with_prefix("message-size-limits")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    assert_eq!(cdb.get_message_size_limits(&BOB)?, None);
+
+    let limits = MessageSizeLimits {
+        max_message_size: Some(64 * 1024),
+        max_frame_size: None,
+    };
+    cdb.set_message_size_limits(&BOB, &limits)?;
+    assert_eq!(cdb.get_message_size_limits(&BOB)?, Some(limits));
+
+    // Setting empty limits removes them.
+    cdb.set_message_size_limits(&BOB, &MessageSizeLimits::default())?;
+    assert_eq!(cdb.get_message_size_limits(&BOB)?, None);
+
+    // Deleting the database removes its limits.
+    cdb.set_message_size_limits(&BOB, &limits)?;
+    cdb.delete_database(id)?;
+    assert_eq!(cdb.get_message_size_limits(&BOB)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_database_features() -> ResultTest<()> {
+    let path = TempDir::with_prefix("database-features")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    assert_eq!(cdb.get_database_features(&BOB)?, None);
+
+    let features = DatabaseFeatures::from_iter([DatabaseFeature::DeltaUpdates]);
+    cdb.set_database_features(&BOB, features)?;
+    assert_eq!(cdb.get_database_features(&BOB)?, Some(features));
+    assert_eq!(cdb.get_all_database_features()?, [(*BOB, features)]);
+
+    // Turning every feature off removes them.
+    cdb.set_database_features(&BOB, DatabaseFeatures::default())?;
+    assert_eq!(cdb.get_database_features(&BOB)?, None);
+
+    // Deleting the database removes its features.
+    cdb.set_database_features(&BOB, features)?;
+    cdb.delete_database(id)?;
+    assert_eq!(cdb.get_database_features(&BOB)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_send_backlog_policy() -> ResultTest<()> {
+    let path = TempDir::with_prefix("send-backlog-policy")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    assert_eq!(cdb.get_send_backlog_policy(&BOB)?, None);
+
+    let policy = SendBacklogPolicy {
+        action: SendBacklogAction::Coalesce,
+        max_messages: Some(256),
+        max_bytes: None,
+    };
+    cdb.set_send_backlog_policy(&BOB, &policy)?;
+    assert_eq!(cdb.get_send_backlog_policy(&BOB)?, Some(policy));
+
+    // A policy without thresholds removes it.
+    cdb.set_send_backlog_policy(&BOB, &SendBacklogPolicy::default())?;
+    assert_eq!(cdb.get_send_backlog_policy(&BOB)?, None);
+
+    // Deleting the database removes its policy.
+    cdb.set_send_backlog_policy(&BOB, &policy)?;
+    cdb.delete_database(id)?;
+    assert_eq!(cdb.get_send_backlog_policy(&BOB)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_auth_expiry_policy() -> ResultTest<()> {
+    let path = TempDir::with_prefix("auth-expiry-policy")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    assert_eq!(cdb.get_auth_expiry_policy(&BOB)?, None);
+
+    let policy = AuthExpiryPolicy {
+        action: AuthExpiryAction::Close,
+        grace_secs: 30,
+    };
+    cdb.set_auth_expiry_policy(&BOB, &policy)?;
+    assert_eq!(cdb.get_auth_expiry_policy(&BOB)?, Some(policy));
+
+    // The default policy removes it.
+    cdb.set_auth_expiry_policy(&BOB, &AuthExpiryPolicy::default())?;
+    assert_eq!(cdb.get_auth_expiry_policy(&BOB)?, None);
+
+    // Deleting the database removes its policy.
+    cdb.set_auth_expiry_policy(&BOB, &policy)?;
+    cdb.delete_database(id)?;
+    assert_eq!(cdb.get_auth_expiry_policy(&BOB)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_firehose() -> ResultTest<()> {
+    let path = TempDir::with_prefix("firehose")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    assert_eq!(cdb.get_firehose(&BOB)?, None);
+    assert!(cdb.get_firehoses()?.is_empty());
+
+    let firehose = FirehoseConfig {
+        target: "file:events.jsonl".into(),
+    };
+    cdb.set_firehose(&BOB, Some(&firehose))?;
+    assert_eq!(cdb.get_firehose(&BOB)?, Some(firehose.clone()));
+    assert_eq!(cdb.get_firehoses()?, [(*BOB, firehose.clone())]);
+
+    cdb.set_firehose(&BOB, None)?;
+    assert_eq!(cdb.get_firehose(&BOB)?, None);
+
+    // Deleting the database removes its firehose.
+    cdb.set_firehose(&BOB, Some(&firehose))?;
+    cdb.delete_database(id)?;
+    assert_eq!(cdb.get_firehose(&BOB)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_schema_diffs() -> ResultTest<()> {
+    use spacetimedb_schema::schema_diff::{ChangeKind, SchemaChange, SchemaItem};
+
+    let path = TempDir::with_prefix("schema-diffs")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    let (v1, v2) = (hash_bytes("v1"), hash_bytes("v2"));
+    assert_eq!(cdb.get_schema_diff(&BOB, &v1, &v2)?, None);
+
+    let diff = SchemaDiff {
+        changes: vec![SchemaChange {
+            item: SchemaItem::Table,
+            table: None,
+            name: "person".into(),
+            kind: ChangeKind::Removed,
+            detail: None,
+            breaking: true,
+        }],
+    };
+    cdb.insert_schema_diff(&BOB, &v1, &v2, &diff)?;
+    assert_eq!(cdb.get_schema_diff(&BOB, &v1, &v2)?, Some(diff.clone()));
+    // Diffs are directed, and kept per database.
+    assert_eq!(cdb.get_schema_diff(&BOB, &v2, &v1)?, None);
+    assert_eq!(cdb.get_schema_diff(&ALICE, &v1, &v2)?, None);
+
+    // Deleting the database removes its diffs.
+    cdb.delete_database(id)?;
+    assert_eq!(cdb.get_schema_diff(&BOB, &v1, &v2)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_unique_identities() -> ResultTest<()> {
+    let path = TempDir::with_prefix("unique-identities")?;
+    let cdb = ControlDb::at(path)?;
+
+    let db = Database {
+        id: 0,
+        database_identity: *BOB,
+        owner_identity: *ALICE,
+        host_type: HostType::Wasm,
+        initial_program: Hash::ZERO,
+    };
+    let id = cdb.insert_database(db)?;
+    assert!(cdb.get_unique_identities(&BOB, 0..=Day::MAX)?.is_empty());
+
+    let sketch = |identity: &Identity| {
+        let mut sketch = IdentitySketch::default();
+        sketch.insert(identity);
+        sketch
+    };
+    // Sketches of the same day are merged.
+    cdb.merge_unique_identities(&BOB, 10, &sketch(&ALICE))?;
+    cdb.merge_unique_identities(&BOB, 10, &sketch(&BOB))?;
+    cdb.merge_unique_identities(&BOB, 12, &sketch(&ALICE))?;
+    let sketches = cdb.get_unique_identities(&BOB, 10..=11)?;
+    assert_eq!(sketches.len(), 1);
+    assert_eq!(sketches[0].0, 10);
+    assert_eq!(sketches[0].1.estimate(), 2);
+    assert!(cdb.get_unique_identities(&ALICE, 0..=Day::MAX)?.is_empty());
+
+    // Days beyond the retention are dropped.
+    cdb.merge_unique_identities(&BOB, 10 + uniques::RETENTION_DAYS, &sketch(&ALICE))?;
+    let days: Vec<_> = cdb
+        .get_unique_identities(&BOB, 0..=Day::MAX)?
+        .into_iter()
+        .map(|(day, _)| day)
+        .collect();
+    assert_eq!(days, [12, 10 + uniques::RETENTION_DAYS]);
+
+    // Deleting the database removes its sketches.
+    cdb.delete_database(id)?;
+    assert!(cdb.get_unique_identities(&BOB, 0..=Day::MAX)?.is_empty());
+
+    Ok(())
+}
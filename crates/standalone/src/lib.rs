@@ -8,26 +8,42 @@ use crate::subcommands::{extract_schema, start};
 use anyhow::{ensure, Context, Ok};
 use async_trait::async_trait;
 use clap::{ArgMatches, Command};
-use spacetimedb::client::ClientActorIndex;
+use spacetimedb::client::uniques::{Day, IdentitySketch};
+use spacetimedb::client::{
+    ClientActorIndex, ClientRuntime, ConnectionLimits, LoadAdmission, LoadAdmissionConfig, NetworkOptions,
+    WebSocketOptions,
+};
 use spacetimedb::config::{CertificateAuthority, MetadataFile};
 use spacetimedb::db::datastore::traits::Program;
 use spacetimedb::db::db_metrics::data_size::DATA_SIZE_METRICS;
 use spacetimedb::db::relational_db;
 use spacetimedb::db::{db_metrics::DB_METRICS, Config};
 use spacetimedb::energy::{EnergyBalance, EnergyQuanta, NullEnergyMonitor};
+use spacetimedb::hash::Hash;
 use spacetimedb::host::{
-    DiskStorage, DurabilityProvider, ExternalDurability, HostController, StartSnapshotWatcher, UpdateDatabaseResult,
+    DiskStorage, DurabilityProvider, ExternalDurability, HostController, ModuleExitCause, ModuleHost, PublishOptions,
+    StartSnapshotWatcher, UpdateDatabaseResult,
 };
 use spacetimedb::identity::Identity;
-use spacetimedb::messages::control_db::{Database, Node, Replica};
+use spacetimedb::messages::control_db::{
+    AuthExpiryPolicy, ClientConfigDefaults, ConnectionTimeouts, Database, FirehoseConfig, MessageSizeLimits,
+    NetworkAcl, Node, Replica, SendBacklogPolicy,
+};
+use spacetimedb::subscription::fanout::FanoutOptions;
+use spacetimedb::subscription::firehose::FirehoseSink;
 use spacetimedb::util::jobs::JobCores;
 use spacetimedb::worker_metrics::WORKER_METRICS;
 use spacetimedb_client_api::auth::{self, LOCALHOST};
+use spacetimedb_client_api::hooks;
+use spacetimedb_client_api::timeouts::{ReloadableTimeouts, TimeoutsConfig};
 use spacetimedb_client_api::{Host, NodeDelegate};
 use spacetimedb_client_api_messages::name::{DomainName, InsertDomainResult, RegisterTldResult, SetDomainsResult, Tld};
+use spacetimedb_lib::DatabaseFeatures;
 use spacetimedb_paths::server::{ModuleLogsDir, PidFile, ServerDataDir};
 use spacetimedb_paths::standalone::StandaloneDataDirExt;
+use spacetimedb_schema::schema_diff::SchemaDiff;
 use spacetimedb_table::page_pool::PagePool;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 
 pub use spacetimedb_client_api::routes::subscribe::{BIN_PROTOCOL, TEXT_PROTOCOL};
@@ -37,19 +53,46 @@ pub struct StandaloneEnv {
     program_store: Arc<DiskStorage>,
     host_controller: HostController,
     client_actor_index: ClientActorIndex,
+    timeouts: Arc<ReloadableTimeouts>,
     metrics_registry: prometheus::Registry,
     _pid_file: PidFile,
     auth_provider: auth::DefaultJwtAuthProvider,
 }
 
+/// The options of a [`StandaloneEnv`] which apply to the whole node,
+/// each read from its section of `config.toml`.
+#[derive(Clone, Default)]
+pub struct NodeOptions {
+    pub connection_limits: ConnectionLimits,
+    pub websocket: WebSocketOptions,
+    pub timeouts: TimeoutsConfig,
+    pub load_admission: LoadAdmissionConfig,
+    pub network: NetworkOptions,
+    pub publish: PublishOptions,
+    pub fanout: FanoutOptions,
+}
+
 impl StandaloneEnv {
     pub async fn init(
         config: Config,
         certs: &CertificateAuthority,
         data_dir: Arc<ServerDataDir>,
         db_cores: JobCores,
+        options: NodeOptions,
     ) -> anyhow::Result<Arc<Self>> {
+        let NodeOptions {
+            connection_limits,
+            websocket: websocket_options,
+            timeouts,
+            load_admission,
+            network: network_options,
+            publish: publish_options,
+            fanout: fanout_options,
+        } = options;
         let _pid_file = data_dir.pid_file()?;
+        let timeouts = ReloadableTimeouts::new(timeouts).context("invalid websocket timeouts in config.toml")?;
+        let load_admission =
+            LoadAdmission::new(load_admission).context("invalid load admission thresholds in config.toml")?;
         let meta_path = data_dir.metadata_toml();
         let mut meta = MetadataFile::new("standalone");
         if let Some(existing_meta) = MetadataFile::read(&meta_path).context("failed reading metadata.toml")? {
@@ -71,8 +114,26 @@ impl StandaloneEnv {
             energy_monitor,
             durability_provider,
             db_cores,
+            publish_options,
+            fanout_options,
         );
-        let client_actor_index = ClientActorIndex::new();
+        let client_runtime = match websocket_options.client_runtime_threads {
+            Some(threads) => {
+                ClientRuntime::with_worker_threads(threads).context("failed to start the client runtime")?
+            }
+            None => ClientRuntime::current(),
+        };
+        let client_actor_index = ClientActorIndex::with_limits(connection_limits)
+            .with_client_runtime(client_runtime)
+            .with_websocket_options(websocket_options)
+            .with_network_options(network_options)
+            .with_load_admission(load_admission);
+        // The sweeper lives as long as the node, as does the flusher of unique identities.
+        client_actor_index.spawn_stale_sweeper();
+        let uniques_db = control_db.clone();
+        client_actor_index.spawn_uniques_flusher(move |database_identity, day, sketch| {
+            Ok(uniques_db.merge_unique_identities(&database_identity, day, sketch)?)
+        });
         let jwt_keys = certs.get_or_create_keys()?;
 
         let auth_env = auth::default_auth_environment(jwt_keys, LOCALHOST.to_owned());
@@ -82,15 +143,21 @@ impl StandaloneEnv {
         metrics_registry.register(Box::new(&*DB_METRICS)).unwrap();
         metrics_registry.register(Box::new(&*DATA_SIZE_METRICS)).unwrap();
 
-        Ok(Arc::new(Self {
+        let env = Arc::new(Self {
             control_db,
             program_store,
             host_controller,
             client_actor_index,
+            timeouts: Arc::new(timeouts),
             metrics_registry,
             _pid_file,
             auth_provider: auth_env,
-        }))
+        });
+        env.attach_firehoses().await.context("failed to read firehoses")?;
+        for (database_identity, features) in env.control_db.get_all_database_features()? {
+            env.host_controller.features(database_identity).set(features);
+        }
+        Ok(env)
     }
 
     pub fn data_dir(&self) -> &Arc<ServerDataDir> {
@@ -100,6 +167,10 @@ impl StandaloneEnv {
     pub fn page_pool(&self) -> &PagePool {
         &self.host_controller.page_pool
     }
+
+    pub fn timeouts(&self) -> &Arc<ReloadableTimeouts> {
+        &self.timeouts
+    }
 }
 
 struct StandaloneDurabilityProvider {
@@ -161,6 +232,10 @@ impl NodeDelegate for StandaloneEnv {
     fn module_logs_dir(&self, replica_id: u64) -> ModuleLogsDir {
         self.data_dir().replica(replica_id).module_logs()
     }
+
+    fn timeouts_config(&self) -> Arc<TimeoutsConfig> {
+        self.timeouts.current()
+    }
 }
 
 impl spacetimedb_client_api::ControlStateReadAccess for StandaloneEnv {
@@ -222,6 +297,60 @@ impl spacetimedb_client_api::ControlStateReadAccess for StandaloneEnv {
     fn reverse_lookup(&self, database_identity: &Identity) -> anyhow::Result<Vec<DomainName>> {
         Ok(self.control_db.spacetime_reverse_dns(database_identity)?)
     }
+
+    // Network access
+    fn get_network_acl(&self, database_identity: &Identity) -> anyhow::Result<Option<NetworkAcl>> {
+        Ok(self.control_db.get_network_acl(database_identity)?)
+    }
+
+    // Client config
+    fn get_client_config_defaults(&self, database_identity: &Identity) -> anyhow::Result<Option<ClientConfigDefaults>> {
+        Ok(self.control_db.get_client_config_defaults(database_identity)?)
+    }
+
+    fn get_connection_timeouts(&self, database_identity: &Identity) -> anyhow::Result<Option<ConnectionTimeouts>> {
+        Ok(self.control_db.get_connection_timeouts(database_identity)?)
+    }
+
+    fn get_message_size_limits(&self, database_identity: &Identity) -> anyhow::Result<Option<MessageSizeLimits>> {
+        Ok(self.control_db.get_message_size_limits(database_identity)?)
+    }
+
+    fn get_send_backlog_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<SendBacklogPolicy>> {
+        Ok(self.control_db.get_send_backlog_policy(database_identity)?)
+    }
+
+    fn get_auth_expiry_policy(&self, database_identity: &Identity) -> anyhow::Result<Option<AuthExpiryPolicy>> {
+        Ok(self.control_db.get_auth_expiry_policy(database_identity)?)
+    }
+
+    fn get_database_features(&self, database_identity: &Identity) -> anyhow::Result<Option<DatabaseFeatures>> {
+        Ok(self.control_db.get_database_features(database_identity)?)
+    }
+
+    // Firehose
+    fn get_firehose(&self, database_identity: &Identity) -> anyhow::Result<Option<FirehoseConfig>> {
+        Ok(self.control_db.get_firehose(database_identity)?)
+    }
+
+    // Schema diffs
+    fn get_schema_diff(
+        &self,
+        database_identity: &Identity,
+        from: &Hash,
+        to: &Hash,
+    ) -> anyhow::Result<Option<SchemaDiff>> {
+        Ok(self.control_db.get_schema_diff(database_identity, from, to)?)
+    }
+
+    // Unique identities
+    fn get_unique_identities(
+        &self,
+        database_identity: &Identity,
+        days: RangeInclusive<Day>,
+    ) -> anyhow::Result<Vec<(Day, IdentitySketch)>> {
+        Ok(self.control_db.get_unique_identities(database_identity, days)?)
+    }
 }
 
 #[async_trait]
@@ -285,10 +414,17 @@ impl spacetimedb_client_api::ControlStateWriteAccess for StandaloneEnv {
                     .leader(database_id)
                     .await?
                     .ok_or_else(|| anyhow::anyhow!("No leader for database"))?;
+                // The module being replaced, to record how the update changes its schema.
+                let old_module = leader.module().await.ok();
                 let update_result = leader
                     .update(database, spec.host_type, spec.program_bytes.into())
                     .await?;
                 if update_result.was_successful() {
+                    if let Some(old_module) = old_module {
+                        if let Err(e) = self.record_schema_diff(database_identity, &old_module, &leader).await {
+                            log::error!("failed to record the schema diff of {database_identity}: {e:#}");
+                        }
+                    }
                     let replicas = self.control_db.get_replicas_by_database(database_id)?;
                     let desired_replicas = num_replicas as usize;
                     if desired_replicas == 0 {
@@ -352,6 +488,8 @@ impl spacetimedb_client_api::ControlStateWriteAccess for StandaloneEnv {
         );
 
         self.control_db.delete_database(database.id)?;
+        self.host_controller.remove_firehose(database_identity);
+        self.host_controller.remove_features(database_identity);
 
         for instance in self.control_db.get_replicas_by_database(database.id)? {
             self.delete_replica(instance.id).await?;
@@ -401,9 +539,128 @@ impl spacetimedb_client_api::ControlStateWriteAccess for StandaloneEnv {
             .control_db
             .spacetime_replace_domains(database_identity, owner_identity, domain_names)?)
     }
+
+    async fn set_network_acl(&self, database_identity: &Identity, acl: NetworkAcl) -> anyhow::Result<()> {
+        Ok(self.control_db.set_network_acl(database_identity, &acl)?)
+    }
+
+    async fn set_client_config_defaults(
+        &self,
+        database_identity: &Identity,
+        defaults: ClientConfigDefaults,
+    ) -> anyhow::Result<()> {
+        Ok(self
+            .control_db
+            .set_client_config_defaults(database_identity, &defaults)?)
+    }
+
+    async fn set_connection_timeouts(
+        &self,
+        database_identity: &Identity,
+        timeouts: ConnectionTimeouts,
+    ) -> anyhow::Result<()> {
+        Ok(self.control_db.set_connection_timeouts(database_identity, &timeouts)?)
+    }
+
+    async fn set_message_size_limits(
+        &self,
+        database_identity: &Identity,
+        limits: MessageSizeLimits,
+    ) -> anyhow::Result<()> {
+        Ok(self.control_db.set_message_size_limits(database_identity, &limits)?)
+    }
+
+    async fn set_send_backlog_policy(
+        &self,
+        database_identity: &Identity,
+        policy: SendBacklogPolicy,
+    ) -> anyhow::Result<()> {
+        Ok(self.control_db.set_send_backlog_policy(database_identity, &policy)?)
+    }
+
+    async fn set_auth_expiry_policy(
+        &self,
+        database_identity: &Identity,
+        policy: AuthExpiryPolicy,
+    ) -> anyhow::Result<()> {
+        Ok(self.control_db.set_auth_expiry_policy(database_identity, &policy)?)
+    }
+
+    async fn set_database_features(
+        &self,
+        database_identity: &Identity,
+        features: DatabaseFeatures,
+    ) -> anyhow::Result<()> {
+        self.control_db.set_database_features(database_identity, features)?;
+        self.host_controller.features(*database_identity).set(features);
+        Ok(())
+    }
+
+    async fn set_firehose(&self, database_identity: &Identity, firehose: Option<FirehoseConfig>) -> anyhow::Result<()> {
+        // Open the new sink before storing its target, so that a sink which can't be opened isn't stored.
+        let sink = match &firehose {
+            Some(firehose) => Some(self.open_firehose_sink(database_identity, firehose).await?),
+            None => None,
+        };
+        self.control_db.set_firehose(database_identity, firehose.as_ref())?;
+        self.host_controller.firehose(*database_identity).set(sink);
+        Ok(())
+    }
 }
 
 impl StandaloneEnv {
+    /// Record how a publish changed the schema of `database_identity`,
+    /// from that of `old_module` to that of the module `leader` now runs.
+    async fn record_schema_diff(
+        &self,
+        database_identity: Identity,
+        old_module: &ModuleHost,
+        leader: &Host,
+    ) -> anyhow::Result<()> {
+        let new_module = leader.module().await?;
+        let (old, new) = (&old_module.info, &new_module.info);
+        if old.schema.digest == new.schema.digest {
+            return Ok(());
+        }
+        let diff = SchemaDiff::between(&old.module_def, &new.module_def);
+        log::info!(
+            "schema of {database_identity} changed from {} to {}: {:?}",
+            old.schema.digest.to_hex(),
+            new.schema.digest.to_hex(),
+            diff.summary()
+        );
+        self.control_db
+            .insert_schema_diff(&database_identity, &old.schema.digest, &new.schema.digest, &diff)?;
+        Ok(())
+    }
+
+    async fn open_firehose_sink(
+        &self,
+        database_identity: &Identity,
+        firehose: &FirehoseConfig,
+    ) -> anyhow::Result<Arc<dyn FirehoseSink>> {
+        let target = firehose.target.parse()?;
+        let connection_hooks = self.connection_hooks();
+        let dir = self.data_dir().firehose();
+        Ok(hooks::open_firehose_sink(&*connection_hooks, &dir, *database_identity, &target).await?)
+    }
+
+    /// Attach the stored firehoses of databases to them, as the node starts.
+    ///
+    /// A firehose whose sink can't be opened is logged and left detached until its owner sets it again.
+    async fn attach_firehoses(&self) -> anyhow::Result<()> {
+        for (database_identity, firehose) in self.control_db.get_firehoses()? {
+            match self.open_firehose_sink(&database_identity, &firehose).await {
+                Result::Ok(sink) => self.host_controller.firehose(database_identity).set(Some(sink)),
+                Err(e) => log::warn!(
+                    "failed to attach firehose `{}` of database {database_identity}: {e:#}",
+                    firehose.target
+                ),
+            }
+        }
+        Ok(())
+    }
+
     async fn insert_replica(&self, replica: Replica) -> Result<(), anyhow::Error> {
         let mut new_replica = replica.clone();
         let id = self.control_db.insert_replica(replica)?;
@@ -458,7 +715,9 @@ impl StandaloneEnv {
         // replicas which have been deleted. This will just drop
         // them from memory, but will not remove them from disk.  We need
         // some kind of database lifecycle manager long term.
-        self.host_controller.exit_module_host(replica_id).await?;
+        self.host_controller
+            .exit_module_host(replica_id, ModuleExitCause::Deleted)
+            .await?;
 
         Ok(())
     }
@@ -519,11 +778,13 @@ mod tests {
             page_pool_max_size: None,
         };
 
-        let _env = StandaloneEnv::init(config, &ca, data_dir.clone(), Default::default()).await?;
+        let _env = StandaloneEnv::init(config, &ca, data_dir.clone(), Default::default(), Default::default()).await?;
         // Ensure that we have a lock.
-        assert!(StandaloneEnv::init(config, &ca, data_dir.clone(), Default::default())
-            .await
-            .is_err());
+        assert!(
+            StandaloneEnv::init(config, &ca, data_dir.clone(), Default::default(), Default::default(),)
+                .await
+                .is_err()
+        );
 
         Ok(())
     }
@@ -1,10 +1,12 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use crate::StandaloneEnv;
+use crate::{NodeOptions, StandaloneEnv};
 use anyhow::Context;
 use axum::extract::DefaultBodyLimit;
 use clap::ArgAction::SetTrue;
 use clap::{Arg, ArgMatches};
+use spacetimedb::client::LoadAdmissionConfig;
 use spacetimedb::config::{CertificateAuthority, ConfigFile};
 use spacetimedb::db::{Config, Storage};
 use spacetimedb::startup::{self, TracingOptions};
@@ -12,6 +14,8 @@ use spacetimedb::util::jobs::JobCores;
 use spacetimedb::worker_metrics;
 use spacetimedb_client_api::routes::database::DatabaseRoutes;
 use spacetimedb_client_api::routes::router;
+use spacetimedb_client_api::timeouts::TimeoutsConfig;
+use spacetimedb_client_api::NodeDelegate;
 use spacetimedb_paths::cli::{PrivKeyPath, PubKeyPath};
 use spacetimedb_paths::server::ServerDataDir;
 use tokio::net::TcpListener;
@@ -123,9 +127,12 @@ pub async fn exec(args: &ArgMatches, db_cores: JobCores) -> anyhow::Result<()> {
         }
     };
 
+    let timeouts = TimeoutsConfig::read(config_path.as_ref())?.unwrap_or_default();
+    let load_admission = LoadAdmissionConfig::read(config_path.as_ref())?.unwrap_or_default();
+
     startup::configure_tracing(TracingOptions {
         config: config.logs,
-        reload_config: cfg!(debug_assertions).then_some(config_path),
+        reload_config: cfg!(debug_assertions).then(|| config_path.clone()),
         disk_logging: std::env::var_os("SPACETIMEDB_DISABLE_DISK_LOGGING")
             .is_none()
             .then(|| data_dir.logs()),
@@ -144,7 +151,24 @@ pub async fn exec(args: &ArgMatches, db_cores: JobCores) -> anyhow::Result<()> {
         .context("cannot omit --jwt-{pub,priv}-key-path when those options are not specified in config.toml")?;
 
     let data_dir = Arc::new(data_dir.clone());
-    let ctx = StandaloneEnv::init(db_config, &certs, data_dir, db_cores).await?;
+    let ctx = StandaloneEnv::init(
+        db_config,
+        &certs,
+        data_dir,
+        db_cores,
+        NodeOptions {
+            connection_limits: config.connection_limits,
+            websocket: config.websocket,
+            timeouts,
+            load_admission,
+            network: config.network,
+            publish: config.publish,
+            fanout: config.fanout,
+        },
+    )
+    .await?;
+    ctx.timeouts().watch(config_path.clone());
+    ctx.client_actor_index().load_admission().watch(config_path);
     worker_metrics::spawn_jemalloc_stats(listen_addr.clone());
     worker_metrics::spawn_tokio_stats(listen_addr.clone());
     worker_metrics::spawn_page_pool_stats(listen_addr.clone(), ctx.page_pool().clone());
@@ -157,7 +181,7 @@ pub async fn exec(args: &ArgMatches, db_cores: JobCores) -> anyhow::Result<()> {
     let tcp = TcpListener::bind(listen_addr).await?;
     socket2::SockRef::from(&tcp).set_nodelay(true)?;
     log::debug!("Starting SpacetimeDB listening on {}", tcp.local_addr().unwrap());
-    axum::serve(tcp, service).await?;
+    axum::serve(tcp, service.into_make_service_with_connect_info::<SocketAddr>()).await?;
     Ok(())
 }
 
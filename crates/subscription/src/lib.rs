@@ -7,10 +7,13 @@ use spacetimedb_execution::{
     Datastore, DeltaStore, Row,
 };
 use spacetimedb_expr::check::SchemaView;
-use spacetimedb_lib::{identity::AuthCtx, metrics::ExecutionMetrics, query::Delta, AlgebraicValue};
+use spacetimedb_lib::sats::{i256, u256, ArrayValue};
+use spacetimedb_lib::{
+    identity::AuthCtx, metrics::ExecutionMetrics, query::Delta, AlgebraicType, AlgebraicValue, ProductValue,
+};
 use spacetimedb_physical_plan::plan::{IxJoin, IxScan, Label, PhysicalPlan, ProjectPlan, Sarg, TableScan, TupleField};
-use spacetimedb_primitives::{ColId, ColList, IndexId, TableId};
-use spacetimedb_query::compile_subscription;
+use spacetimedb_primitives::{ColId, ColList, ColSet, IndexId, TableId};
+use spacetimedb_query::compile_subscription_with_columns;
 use std::sync::Arc;
 use std::{collections::HashSet, ops::RangeBounds};
 
@@ -353,6 +356,8 @@ pub struct SubscriptionPlan {
     fragments: Fragments,
     /// The optimized plan without any delta scans
     plan_opt: ProjectPlan,
+    /// The columns selected, if the subscription doesn't select whole rows
+    projection: Option<ColumnProjection>,
 }
 
 impl SubscriptionPlan {
@@ -381,6 +386,11 @@ impl SubscriptionPlan {
         &self.plan_opt
     }
 
+    /// Which columns does this plan select, if not whole rows?
+    pub fn projection(&self) -> Option<&ColumnProjection> {
+        self.projection.as_ref()
+    }
+
     /// From which indexes does this plan read?
     pub fn index_ids(&self) -> impl Iterator<Item = (TableId, IndexId)> {
         self.fragments.index_ids()
@@ -472,7 +482,20 @@ impl SubscriptionPlan {
 
     /// Generate a plan for incrementally maintaining a subscription
     pub fn compile(sql: &str, tx: &impl SchemaView, auth: &AuthCtx) -> Result<(Vec<Self>, bool)> {
-        let (plans, return_id, return_name, has_param) = compile_subscription(sql, tx, auth)?;
+        let (plans, return_id, return_name, columns, has_param) = compile_subscription_with_columns(sql, tx, auth)?;
+
+        let projection = match columns {
+            None => None,
+            Some(columns) => {
+                let Some(schema) = tx.schema_for_table(return_id) else {
+                    bail!("TableId `{return_id}` does not exist")
+                };
+                Some(ColumnProjection::new(
+                    columns,
+                    schema.columns().iter().map(|col| &col.col_type),
+                ))
+            }
+        };
 
         /// Does this plan have any non-index joins?
         fn has_non_index_join(plan: &PhysicalPlan) -> bool {
@@ -529,9 +552,119 @@ impl SubscriptionPlan {
                 table_ids,
                 plan_opt,
                 fragments,
+                projection: projection.clone(),
             });
         }
 
         Ok((subscriptions, has_param))
     }
 }
+
+/// The columns of its table a subscription selects, e.g. `SELECT id, name FROM player`,
+/// always including the table's primary key, so that clients can key the rows they're sent.
+///
+/// Rows are still sent in the shape of the table, so that clients decode them as whole rows,
+/// but with every other column replaced by a placeholder:
+/// zero, `false`, an empty string or array, or the first unit variant of a sum type, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnProjection {
+    columns: ColSet,
+    /// For each column of the table, its placeholder, or `None` if it's selected.
+    placeholders: Box<[Option<AlgebraicValue>]>,
+}
+
+impl ColumnProjection {
+    /// Select `columns` of a table with columns of the types `col_types`.
+    pub fn new<'a>(columns: ColSet, col_types: impl Iterator<Item = &'a AlgebraicType>) -> Self {
+        let placeholders = col_types
+            .enumerate()
+            .map(|(pos, ty)| (!columns.contains(ColId::from(pos))).then(|| placeholder(ty)))
+            .collect();
+        Self { columns, placeholders }
+    }
+
+    /// The columns selected, in ascending order.
+    pub fn columns(&self) -> &ColSet {
+        &self.columns
+    }
+
+    /// Replace the columns of `row` which aren't selected with their placeholders.
+    pub fn project(&self, mut row: ProductValue) -> ProductValue {
+        for (value, placeholder) in row.elements.iter_mut().zip(self.placeholders.iter()) {
+            if let Some(placeholder) = placeholder {
+                *value = placeholder.clone();
+            }
+        }
+        row
+    }
+}
+
+/// The value of type `ty` sent in place of a column which isn't selected.
+///
+/// Column types never refer to a typespace, as they're resolved in table schemas.
+fn placeholder(ty: &AlgebraicType) -> AlgebraicValue {
+    match ty {
+        AlgebraicType::Sum(sum) => {
+            let (tag, variant) = sum
+                .variants
+                .iter()
+                .enumerate()
+                .find(|(_, variant)| variant.is_unit())
+                .or_else(|| sum.variants.iter().enumerate().next())
+                .expect("sum types have at least one variant");
+            AlgebraicValue::sum(tag as u8, placeholder(&variant.algebraic_type))
+        }
+        AlgebraicType::Product(product) => AlgebraicValue::product(
+            product
+                .elements
+                .iter()
+                .map(|elem| placeholder(&elem.algebraic_type))
+                .collect::<ProductValue>(),
+        ),
+        AlgebraicType::Array(array) => AlgebraicValue::Array(empty_array(&array.elem_ty)),
+        AlgebraicType::String => AlgebraicValue::String("".into()),
+        AlgebraicType::Bool => AlgebraicValue::Bool(false),
+        AlgebraicType::I8 => 0i8.into(),
+        AlgebraicType::U8 => 0u8.into(),
+        AlgebraicType::I16 => 0i16.into(),
+        AlgebraicType::U16 => 0u16.into(),
+        AlgebraicType::I32 => 0i32.into(),
+        AlgebraicType::U32 => 0u32.into(),
+        AlgebraicType::I64 => 0i64.into(),
+        AlgebraicType::U64 => 0u64.into(),
+        AlgebraicType::I128 => 0i128.into(),
+        AlgebraicType::U128 => 0u128.into(),
+        AlgebraicType::I256 => i256::ZERO.into(),
+        AlgebraicType::U256 => u256::ZERO.into(),
+        AlgebraicType::F32 => 0f32.into(),
+        AlgebraicType::F64 => 0f64.into(),
+        AlgebraicType::Ref(_) => unreachable!("column types are resolved"),
+    }
+}
+
+/// An empty array of `elem_ty`,
+/// in the same representation as the arrays of that type read from a table.
+fn empty_array(elem_ty: &AlgebraicType) -> ArrayValue {
+    match elem_ty {
+        AlgebraicType::Sum(_) => ArrayValue::Sum([].into()),
+        AlgebraicType::Product(_) => ArrayValue::Product([].into()),
+        AlgebraicType::Array(_) => ArrayValue::Array([].into()),
+        AlgebraicType::String => ArrayValue::String([].into()),
+        AlgebraicType::Bool => ArrayValue::Bool([].into()),
+        AlgebraicType::I8 => ArrayValue::I8([].into()),
+        AlgebraicType::U8 => ArrayValue::U8([].into()),
+        AlgebraicType::I16 => ArrayValue::I16([].into()),
+        AlgebraicType::U16 => ArrayValue::U16([].into()),
+        AlgebraicType::I32 => ArrayValue::I32([].into()),
+        AlgebraicType::U32 => ArrayValue::U32([].into()),
+        AlgebraicType::I64 => ArrayValue::I64([].into()),
+        AlgebraicType::U64 => ArrayValue::U64([].into()),
+        AlgebraicType::I128 => ArrayValue::I128([].into()),
+        AlgebraicType::U128 => ArrayValue::U128([].into()),
+        AlgebraicType::I256 => ArrayValue::I256([].into()),
+        AlgebraicType::U256 => ArrayValue::U256([].into()),
+        AlgebraicType::F32 => ArrayValue::F32([].into()),
+        AlgebraicType::F64 => ArrayValue::F64([].into()),
+        AlgebraicType::Ref(_) => unreachable!("column types are resolved"),
+    }
+}
@@ -0,0 +1,56 @@
+//! Checks that a live node speaks the websocket protocol as SDKs expect, see [`spacetimedb_testing::conformance`].
+
+#![allow(clippy::disallowed_macros)]
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use anyhow::Context;
+use clap::Parser;
+use spacetimedb_testing::conformance::{self, Options, ReportFormat};
+use spacetimedb_testing::modules::{CompilationMode, CompiledModule};
+
+#[derive(Parser, Debug)]
+#[clap(about)]
+struct Args {
+    #[command(flatten)]
+    options: Options,
+
+    /// How to report the outcome of each check.
+    #[clap(long, value_enum, default_value_t)]
+    format: ReportFormat,
+
+    /// Write the report to this file, rather than to stdout.
+    #[clap(long)]
+    output: Option<PathBuf>,
+
+    /// Publish this build of the `protocol-conformance` module, rather than compiling it from this checkout.
+    #[clap(long, value_name = "WASM")]
+    module: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    env_logger::init();
+    let args = Args::parse();
+
+    let program = match &args.module {
+        Some(path) => std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?,
+        None => CompiledModule::compile("protocol-conformance", CompilationMode::Debug)
+            .program_bytes()
+            .to_vec(),
+    };
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let report = runtime.block_on(conformance::run(&args.options, program))?;
+
+    let rendered = report.render(args.format);
+    match &args.output {
+        Some(path) => std::fs::write(path, rendered).with_context(|| format!("failed to write {}", path.display()))?,
+        None => print!("{rendered}"),
+    }
+    Ok(if report.passed() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
@@ -0,0 +1,533 @@
+//! The checks of the suite, each exchanging messages with the scratch database over connections of its own.
+//!
+//! Checks run one after another, so that a connection subscribed to the `message` table
+//! is sent the updates of the reducers its check calls, and of no other check.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context as _};
+use futures::future::BoxFuture;
+use spacetimedb_client_api_messages::websocket::{
+    self as ws, Ack, CallReducer, CallReducerFlags, ClientMessage, Compression, OneOffQuery, QueryId, SubscribeMulti,
+    UnsubscribeMulti,
+};
+use spacetimedb_lib::sats::{product, ArrayValue};
+use spacetimedb_lib::{ConnectionId, DatabaseFeature, Identity, ProductValue};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+use super::client::{Conn, Received, Status, Tx};
+use super::{Encoding, ErrorFormat, Options, ScratchDatabase, Variant};
+
+/// What a check runs with.
+#[derive(Clone)]
+pub(super) struct Ctx {
+    pub options: Arc<Options>,
+    pub database: Arc<ScratchDatabase>,
+    pub variant: Variant,
+}
+
+impl Ctx {
+    /// Open a connection to the scratch database with `query`,
+    /// and `token` as its credentials, if any, without waiting for its first message.
+    async fn open(&self, query: &str, token: Option<&str>) -> Result<Conn, WsError> {
+        let url = self.database.subscribe_url(query);
+        Conn::open(&url, self.variant, token, self.options.timeout()).await
+    }
+
+    /// Open a connection as [`Ctx::open`] does, returning it along with the `IdentityToken` it was sent first.
+    async fn connect(&self, query: &str, token: Option<&str>) -> anyhow::Result<(Conn, ws::IdentityToken)> {
+        let conn = self.open(query, token).await.context("failed to connect")?;
+        recv_identity_token(conn).await
+    }
+
+    /// Connect as [`Ctx::connect`] does, as the connection id in `query` of a connection just closed,
+    /// which the server refuses to reuse until it's done disconnecting the closed connection.
+    async fn reconnect(&self, query: &str, token: &str) -> anyhow::Result<(Conn, ws::IdentityToken)> {
+        let deadline = tokio::time::Instant::now() + self.options.timeout();
+        loop {
+            match self.open(query, Some(token)).await {
+                Err(WsError::Http(response))
+                    if response.status() == StatusCode::CONFLICT && tokio::time::Instant::now() < deadline =>
+                {
+                    tokio::time::sleep(Duration::from_millis(50)).await
+                }
+                opened => return recv_identity_token(opened.context("failed to reconnect")?).await,
+            }
+        }
+    }
+}
+
+/// Receive the `IdentityToken` a connection is sent first, returning it along with the connection.
+async fn recv_identity_token(mut conn: Conn) -> anyhow::Result<(Conn, ws::IdentityToken)> {
+    match conn.recv_message().await? {
+        Received::IdentityToken(token) => Ok((conn, token)),
+        received => Err(unexpected("an IdentityToken as the first message", received)),
+    }
+}
+
+/// A check of the protocol, run once for each [`Variant`].
+pub(super) struct Check {
+    /// The name of the check, prefixed with the part of the protocol it's of, e.g. `handshake.`.
+    pub name: &'static str,
+    /// The feature the check is of, without which it's skipped.
+    pub feature: Option<DatabaseFeature>,
+    pub run: fn(Ctx) -> BoxFuture<'static, anyhow::Result<()>>,
+}
+
+impl Check {
+    /// Returns why the check isn't run with `ctx`, if it isn't.
+    pub fn skip_reason(&self, ctx: &Ctx) -> Option<String> {
+        let feature = self
+            .feature
+            .filter(|&feature| !ctx.options.features().contains(feature))?;
+        Some(format!("feature `{}` is off", feature.name()))
+    }
+}
+
+macro_rules! check {
+    ($name:literal, $run:path) => {
+        check!($name, $run, None)
+    };
+    ($name:literal, $run:path, $feature:expr) => {
+        Check {
+            name: $name,
+            feature: $feature,
+            run: |ctx| Box::pin($run(ctx)),
+        }
+    };
+}
+
+/// Every check, in the order they run.
+pub(super) static CHECKS: &[Check] = &[
+    check!("handshake.subprotocol", handshake_subprotocol),
+    check!("handshake.unknown-subprotocol", handshake_unknown_subprotocol),
+    check!(
+        "handshake.compression-requires-version",
+        handshake_compression_requires_version
+    ),
+    check!("identity.token-first", identity_token_first),
+    check!("identity.token-reconnect", identity_token_reconnect),
+    check!("subscribe.applied-then-updates", subscribe_applied_then_updates),
+    check!("subscribe.unsubscribe", subscribe_unsubscribe),
+    check!("subscribe.error", subscribe_error),
+    check!("call.committed", call_committed),
+    check!("call.failed", call_failed),
+    check!("call.unknown-reducer", call_unknown_reducer),
+    check!("call.no-success-notify", call_no_success_notify),
+    check!("oneoff.query", oneoff_query),
+    check!("close.kicked-by-module", close_kicked_by_module),
+    check!("framing.compression", framing_compression),
+    check!("features.negotiated", features_negotiated),
+    check!("features.delta-updates", features_delta_updates),
+    check!(
+        "features.acknowledged-delivery",
+        features_acknowledged_delivery,
+        Some(DatabaseFeature::AcknowledgedDelivery)
+    ),
+];
+
+const SUBSCRIBE_ALL: &str = "SELECT * FROM message";
+
+fn unexpected(expected: &str, received: impl Debug) -> anyhow::Error {
+    anyhow!("expected {expected}, got {received:?}")
+}
+
+fn call(reducer: &str, args: ProductValue, request_id: u32) -> ClientMessage<ProductValue> {
+    call_with_flags(reducer, args, request_id, CallReducerFlags::FullUpdate)
+}
+
+fn call_with_flags(
+    reducer: &str,
+    args: ProductValue,
+    request_id: u32,
+    flags: CallReducerFlags,
+) -> ClientMessage<ProductValue> {
+    ClientMessage::CallReducer(CallReducer {
+        reducer: reducer.into(),
+        args,
+        request_id,
+        flags,
+        traceparent: None,
+    })
+}
+
+fn subscribe(query: &str, request_id: u32, query_id: u32) -> ClientMessage<ProductValue> {
+    ClientMessage::SubscribeMulti(SubscribeMulti {
+        query_strings: [query.into()].into(),
+        request_id,
+        query_id: QueryId::new(query_id),
+    })
+}
+
+/// Subscribe to every message, waiting for the subscription to be applied.
+async fn subscribe_all(conn: &mut Conn, request_id: u32, query_id: u32) -> anyhow::Result<()> {
+    conn.send(subscribe(SUBSCRIBE_ALL, request_id, query_id)).await?;
+    match conn.recv_message().await? {
+        Received::SubscribeMultiApplied {
+            request_id: applied_request,
+            query_id: applied_query,
+            ..
+        } if (applied_request, applied_query) == (request_id, query_id) => Ok(()),
+        received => Err(unexpected(
+            &format!("SubscribeMultiApplied for request {request_id} and query {query_id}"),
+            received,
+        )),
+    }
+}
+
+/// Receive the `TransactionUpdate` of the reducer call `request_id`.
+async fn recv_tx(conn: &mut Conn, request_id: u32) -> anyhow::Result<Tx> {
+    match conn.recv_message().await? {
+        Received::TransactionUpdate(tx) if tx.request_id == request_id => Ok(tx),
+        received => Err(unexpected(
+            &format!("the TransactionUpdate of request {request_id}"),
+            received,
+        )),
+    }
+}
+
+/// Succeed if the websocket upgrade was refused as a bad request.
+fn expect_bad_request<T>(result: Result<T, WsError>) -> anyhow::Result<()> {
+    match result {
+        Err(WsError::Http(response)) if response.status() == StatusCode::BAD_REQUEST => Ok(()),
+        Err(WsError::Http(response)) => bail!("refused the connection with {}, not 400", response.status()),
+        Err(e) => Err(e).context("failed to connect"),
+        Ok(_) => bail!("accepted the connection"),
+    }
+}
+
+async fn handshake_subprotocol(ctx: Ctx) -> anyhow::Result<()> {
+    let (conn, _) = ctx.connect("", None).await?;
+    let subprotocol = conn.header(header::SEC_WEBSOCKET_PROTOCOL.as_str());
+    ensure!(
+        subprotocol == Some(ctx.variant.subprotocol()),
+        "negotiated the subprotocol {subprotocol:?}"
+    );
+    let error_format = conn.header(ws::ERROR_FORMAT_HEADER);
+    ensure!(
+        error_format == Some(ctx.options.error_format.as_str()),
+        "sent the error format {error_format:?}, not {:?}",
+        ctx.options.error_format.as_str()
+    );
+    conn.close().await
+}
+
+async fn handshake_unknown_subprotocol(ctx: Ctx) -> anyhow::Result<()> {
+    let encoding = match ctx.variant.encoding {
+        Encoding::Bsatn => "bsatn",
+        Encoding::Json => "json",
+    };
+    let mut request = ctx.database.subscribe_url("").into_client_request()?;
+    let subprotocol = HeaderValue::try_from(format!("v0.{encoding}.spacetimedb"))?;
+    request
+        .headers_mut()
+        .insert(header::SEC_WEBSOCKET_PROTOCOL, subprotocol);
+    expect_bad_request(tokio_tungstenite::connect_async(request).await)
+}
+
+async fn handshake_compression_requires_version(ctx: Ctx) -> anyhow::Result<()> {
+    if ctx.variant.version.supports(Compression::Zstd) {
+        let (conn, _) = ctx.connect("compression=Zstd", None).await?;
+        conn.close().await
+    } else {
+        expect_bad_request(ctx.open("compression=Zstd", None).await)
+    }
+}
+
+async fn identity_token_first(ctx: Ctx) -> anyhow::Result<()> {
+    let (conn, token) = ctx.connect("", None).await?;
+    ensure!(token.identity != Identity::ZERO, "sent the zero identity");
+    ensure!(token.connection_id != ConnectionId::ZERO, "sent the zero connection id");
+    ensure!(!token.token.is_empty(), "sent an empty token");
+    conn.close().await
+}
+
+async fn identity_token_reconnect(ctx: Ctx) -> anyhow::Result<()> {
+    let (conn, first) = ctx.connect("", None).await?;
+    conn.close().await?;
+    let (conn, second) = ctx.connect("", Some(&first.token)).await?;
+    ensure!(
+        second.identity == first.identity,
+        "reconnecting with the token of {} connected as {}",
+        first.identity,
+        second.identity
+    );
+    ensure!(
+        second.connection_id != first.connection_id,
+        "reconnecting without a connection id kept the connection id {}",
+        first.connection_id
+    );
+    conn.close().await
+}
+
+async fn subscribe_applied_then_updates(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, _) = ctx.connect("", None).await?;
+    subscribe_all(&mut conn, 1, 1).await?;
+    conn.send(call("send", product!["applied then updated"], 2)).await?;
+    let tx = recv_tx(&mut conn, 2).await?;
+    ensure!(
+        tx.status == Status::Committed && tx.rows == 1,
+        "expected the committed insert of a row, got {tx:?}"
+    );
+    conn.close().await
+}
+
+async fn subscribe_unsubscribe(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, _) = ctx.connect("", None).await?;
+    subscribe_all(&mut conn, 1, 1).await?;
+    conn.send(ClientMessage::UnsubscribeMulti(UnsubscribeMulti {
+        request_id: 2,
+        query_id: QueryId::new(1),
+    }))
+    .await?;
+    match conn.recv_message().await? {
+        Received::UnsubscribeMultiApplied {
+            request_id: 2,
+            query_id: 1,
+        } => {}
+        received => {
+            return Err(unexpected(
+                "UnsubscribeMultiApplied for request 2 and query 1",
+                received,
+            ))
+        }
+    }
+    // The caller is sent the update of its call, but none of its rows, as it's no longer subscribed to them.
+    conn.send(call("send", product!["after unsubscribing"], 3)).await?;
+    let tx = recv_tx(&mut conn, 3).await?;
+    ensure!(
+        tx.status == Status::Committed && tx.rows == 0,
+        "expected a committed update without rows, got {tx:?}"
+    );
+    conn.close().await
+}
+
+async fn subscribe_error(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, _) = ctx.connect("", None).await?;
+    conn.send(subscribe("SELECT * FROM no_such_table", 1, 7)).await?;
+    match conn.recv_message().await? {
+        Received::SubscriptionError {
+            request_id: Some(1),
+            query_id: Some(7),
+            error,
+        } => ensure!(!error.is_empty(), "sent a SubscriptionError without its error"),
+        received => return Err(unexpected("a SubscriptionError for request 1 and query 7", received)),
+    }
+    // The connection outlives the error.
+    subscribe_all(&mut conn, 2, 8).await?;
+    conn.close().await
+}
+
+async fn call_committed(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, me) = ctx.connect("", None).await?;
+    conn.send(call("send", product!["committed"], 42)).await?;
+    let tx = recv_tx(&mut conn, 42).await?;
+    ensure!(
+        tx.status == Status::Committed,
+        "expected a committed update, got {tx:?}"
+    );
+    ensure!(tx.reducer == "send", "named the reducer `{}`", tx.reducer);
+    ensure!(tx.caller == me.identity, "named the caller {}", tx.caller);
+    conn.close().await
+}
+
+async fn call_failed(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, _) = ctx.connect("", None).await?;
+    let args = product!["rolled back", "refused by the module"];
+    conn.send(call("send_then_fail", args, 3)).await?;
+    let tx = recv_tx(&mut conn, 3).await?;
+    match &tx.status {
+        Status::Failed(error) if error.contains("refused by the module") => {}
+        _ => bail!("expected a failed update carrying the reducer's error, got {tx:?}"),
+    }
+    conn.close().await
+}
+
+async fn call_unknown_reducer(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, _) = ctx.connect("", None).await?;
+    conn.send(call("no_such_reducer", product![], 4)).await?;
+    let tx = recv_tx(&mut conn, 4).await?;
+    ensure!(
+        matches!(tx.status, Status::Failed(_)),
+        "expected a failed update, got {tx:?}"
+    );
+    conn.close().await
+}
+
+async fn call_no_success_notify(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, _) = ctx.connect("", None).await?;
+    let quiet = call_with_flags("send", product!["quiet"], 5, CallReducerFlags::NoSuccessNotify);
+    conn.send(quiet).await?;
+    // A failing call is always reported, so its update is the next message, unless the quiet call's preceded it.
+    conn.send(call("send", product![""], 6)).await?;
+    let tx = recv_tx(&mut conn, 6).await?;
+    ensure!(
+        matches!(tx.status, Status::Failed(_)),
+        "expected a failed update, got {tx:?}"
+    );
+    conn.close().await
+}
+
+async fn oneoff_query(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, _) = ctx.connect("", None).await?;
+    // A query which succeeds is answered with the rows of the table it selects from, and one which fails with its error.
+    for (message_id, query, fails) in [
+        (&b"conformance"[..], SUBSCRIBE_ALL, false),
+        (&b"bad query"[..], "SELECT * FROM no_such_table", true),
+    ] {
+        conn.send(ClientMessage::OneOffQuery(OneOffQuery {
+            message_id: message_id.into(),
+            query_string: query.into(),
+        }))
+        .await?;
+        match conn.recv_message().await? {
+            Received::OneOffQueryResponse {
+                message_id: id,
+                error,
+                tables,
+            } if id == message_id && error.is_some() == fails && (fails || tables == ["message"]) => {}
+            received => {
+                let outcome = if fails { "an error" } else { "no error" };
+                return Err(unexpected(
+                    &format!("a OneOffQueryResponse to `{query}` with {outcome}"),
+                    received,
+                ));
+            }
+        }
+    }
+    conn.close().await
+}
+
+async fn close_kicked_by_module(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, _) = ctx.connect("", None).await?;
+    conn.send(call("kick_me", product!["conformance"], 7)).await?;
+    let frame = conn.recv_close().await?;
+    ensure!(frame.code == CloseCode::Policy, "closed with {}, not 1008", frame.code);
+    let reason = &*frame.reason;
+    match ctx.options.error_format {
+        ErrorFormat::Legacy => ensure!(reason == "kicked by module", "closed for {reason:?}"),
+        ErrorFormat::Structured => {
+            let payload: serde_json::Value =
+                serde_json::from_str(reason).with_context(|| format!("closed for {reason:?}, which isn't JSON"))?;
+            ensure!(
+                payload["reason"] == "kicked by module" && payload["message"] == "conformance",
+                "closed for {payload}"
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn framing_compression(ctx: Ctx) -> anyhow::Result<()> {
+    for (i, compression) in (1..).zip(ctx.variant.compressions()) {
+        let (mut conn, _) = ctx.connect(&format!("compression={compression:?}"), None).await?;
+        subscribe_all(&mut conn, 1, 1).await?;
+        // Long enough to be compressed, however the node's threshold is set.
+        conn.send(call("send_padded", product![1u32 << 16], i)).await?;
+        let frame = conn.recv_frame().await?;
+        // Text messages are never compressed.
+        let expected = match ctx.variant.encoding {
+            Encoding::Bsatn => compression,
+            Encoding::Json => Compression::None,
+        };
+        match frame.message {
+            Received::TransactionUpdate(tx) if tx.request_id == i => ensure!(
+                frame.compression == expected,
+                "asked for {compression:?}, sent an update compressed with {:?}",
+                frame.compression
+            ),
+            received => return Err(unexpected(&format!("the TransactionUpdate of request {i}"), received)),
+        }
+        conn.close().await?;
+    }
+    Ok(())
+}
+
+async fn features_negotiated(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, _) = ctx.connect("", None).await?;
+    let header = conn
+        .header(ws::FEATURES_HEADER)
+        .with_context(|| format!("didn't send the `{}` header", ws::FEATURES_HEADER))?;
+    let expected = ctx.options.features();
+    ensure!(
+        ws::parse_features(header) == expected,
+        "negotiated the features `{header}`, not `{}`",
+        ws::encode_features(expected)
+    );
+    let names = expected
+        .iter()
+        .map(|feature| feature.name().into())
+        .collect::<Box<[Box<str>]>>();
+    let args = product![ArrayValue::from(names)];
+    conn.send(call("expect_features", args, 8)).await?;
+    let tx = recv_tx(&mut conn, 8).await?;
+    ensure!(tx.status == Status::Committed, "the module saw other features: {tx:?}");
+    conn.close().await
+}
+
+async fn features_delta_updates(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut subscriber, _) = ctx.connect("", None).await?;
+    subscribe_all(&mut subscriber, 1, 1).await?;
+    let (mut caller, _) = ctx.connect("", None).await?;
+    caller.send(call("send", product!["delta"], 9)).await?;
+    recv_tx(&mut caller, 9).await?;
+    let light = ctx.options.features().contains(DatabaseFeature::DeltaUpdates);
+    match subscriber.recv_message().await? {
+        Received::TransactionUpdateLight { rows: 1, .. } if light => {}
+        Received::TransactionUpdate(Tx { rows: 1, .. }) if !light => {}
+        received => {
+            let expected = if light {
+                "a TransactionUpdateLight"
+            } else {
+                "a TransactionUpdate"
+            };
+            return Err(unexpected(&format!("{expected} of the other client's row"), received));
+        }
+    }
+    caller.close().await?;
+    subscriber.close().await
+}
+
+async fn features_acknowledged_delivery(ctx: Ctx) -> anyhow::Result<()> {
+    let (mut conn, me) = ctx.connect("", None).await?;
+    conn.send(call("send_acknowledged", product!["acknowledged"], 10))
+        .await?;
+    let delivery_id = match conn.recv_message().await? {
+        Received::AcknowledgedUpdate { delivery_id, update }
+            if update.request_id == 10 && update.status == Status::Committed =>
+        {
+            delivery_id
+        }
+        received => return Err(unexpected("the AcknowledgedUpdate of request 10", received)),
+    };
+    conn.close().await?;
+
+    // Reconnecting as the same connection redelivers the update, as it wasn't acknowledged.
+    let query = format!("connection_id={}", me.connection_id.to_hex());
+    let (mut conn, _) = ctx.reconnect(&query, &me.token).await?;
+    match conn.recv_message().await? {
+        Received::AcknowledgedUpdate { delivery_id: id, .. } if id == delivery_id => {}
+        received => {
+            return Err(unexpected(
+                &format!("the AcknowledgedUpdate {delivery_id}, redelivered"),
+                received,
+            ))
+        }
+    }
+    conn.send(ClientMessage::Ack(Ack { delivery_id })).await?;
+    // Messages are handled in order, so the acknowledgment is by the time this call is.
+    conn.send(call("send", product![""], 11)).await?;
+    recv_tx(&mut conn, 11).await?;
+    conn.close().await?;
+
+    // Once acknowledged, the update isn't redelivered, so the update of the next call is the first message.
+    let (mut conn, _) = ctx.reconnect(&query, &me.token).await?;
+    conn.send(call("send", product![""], 12)).await?;
+    recv_tx(&mut conn, 12).await?;
+    conn.close().await
+}
@@ -0,0 +1,379 @@
+//! A bare websocket client of the protocol, which checks the framing of each message it's sent as it decodes it.
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context as _};
+use futures::{SinkExt, StreamExt};
+use spacetimedb_client_api_messages::websocket::{
+    self as ws, brotli_decompress, gzip_decompress, zstd_decompress, BsatnFormat, ClientMessage, Compression,
+    JsonFormat, ProtocolVersion, ServerMessage, TextEnvelope, UpdateStatus, WebsocketFormat,
+};
+use spacetimedb_lib::de::serde::DeserializeWrapper;
+use spacetimedb_lib::ser::serde::SerializeWrapper;
+use spacetimedb_lib::{bsatn, Identity, ProductValue};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header, HeaderMap, HeaderValue};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// How the messages of the protocol are encoded.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Binary messages, BSATN-encoded, e.g. `v1.bsatn.spacetimedb`.
+    Bsatn,
+    /// Text messages, JSON-encoded, e.g. `v1.json.spacetimedb`.
+    Json,
+}
+
+/// A version and encoding of the protocol, negotiated as the subprotocol of the websocket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Variant {
+    pub version: ProtocolVersion,
+    pub encoding: Encoding,
+}
+
+impl Variant {
+    /// The websocket subprotocol which negotiates this variant, e.g. `v2.bsatn.spacetimedb`.
+    pub fn subprotocol(self) -> &'static str {
+        match (self.version, self.encoding) {
+            (ProtocolVersion::V1, Encoding::Bsatn) => ws::BIN_PROTOCOL,
+            (ProtocolVersion::V1, Encoding::Json) => ws::TEXT_PROTOCOL,
+            (ProtocolVersion::V2, Encoding::Bsatn) => ws::BIN_PROTOCOL_V2,
+            (ProtocolVersion::V2, Encoding::Json) => ws::TEXT_PROTOCOL_V2,
+        }
+    }
+
+    /// The compressions a client of this variant may ask for.
+    pub fn compressions(self) -> Vec<Compression> {
+        [
+            Compression::None,
+            Compression::Brotli,
+            Compression::Gzip,
+            Compression::Zstd,
+        ]
+        .into_iter()
+        .filter(|&compression| self.version.supports(compression))
+        .collect()
+    }
+}
+
+/// What a [`Conn`] was sent, once decoded.
+#[derive(Debug)]
+pub(super) enum Event {
+    Message(Frame),
+    /// The connection was closed, with the close frame, if the server sent one.
+    Closed(Option<CloseFrame>),
+}
+
+/// A message the server sent, along with the compression it was framed with.
+#[derive(Debug)]
+pub(super) struct Frame {
+    pub compression: Compression,
+    pub message: Received,
+}
+
+/// The parts of a [`ServerMessage`] the checks look at, whatever its encoding.
+#[derive(Debug)]
+pub(super) enum Received {
+    IdentityToken(ws::IdentityToken),
+    SubscribeMultiApplied {
+        request_id: u32,
+        query_id: u32,
+    },
+    UnsubscribeMultiApplied {
+        request_id: u32,
+        query_id: u32,
+    },
+    SubscriptionError {
+        request_id: Option<u32>,
+        query_id: Option<u32>,
+        error: String,
+    },
+    TransactionUpdate(Tx),
+    TransactionUpdateLight {
+        rows: usize,
+    },
+    AcknowledgedUpdate {
+        delivery_id: u64,
+        update: Tx,
+    },
+    OneOffQueryResponse {
+        message_id: Vec<u8>,
+        error: Option<String>,
+        tables: Vec<String>,
+    },
+    /// A message the server may send at any time, e.g. a `DeprecationNotice`, which [`Conn::recv`] skips.
+    Advisory(&'static str),
+    /// Any other message, by the name of its type.
+    #[allow(dead_code)] // The name is only read by `Debug`, in the reasons checks fail for.
+    Other(&'static str),
+}
+
+/// The parts of a `TransactionUpdate` the checks look at.
+#[derive(Debug)]
+pub(super) struct Tx {
+    pub request_id: u32,
+    pub reducer: String,
+    pub caller: Identity,
+    pub status: Status,
+    /// The number of rows inserted and deleted, if the transaction committed.
+    pub rows: usize,
+}
+
+/// The status of a [`Tx`].
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Status {
+    Committed,
+    Failed(String),
+    OutOfEnergy,
+}
+
+impl<F: WebsocketFormat> From<ws::TransactionUpdate<F>> for Tx {
+    fn from(tx: ws::TransactionUpdate<F>) -> Self {
+        let (status, rows) = match tx.status {
+            UpdateStatus::Committed(update) => (Status::Committed, update.num_rows()),
+            UpdateStatus::Failed(error) => (Status::Failed(error.into()), 0),
+            UpdateStatus::OutOfEnergy => (Status::OutOfEnergy, 0),
+        };
+        Self {
+            request_id: tx.reducer_call.request_id,
+            reducer: tx.reducer_call.reducer_name.into(),
+            caller: tx.caller_identity,
+            status,
+            rows,
+        }
+    }
+}
+
+impl<F: WebsocketFormat> From<ServerMessage<F>> for Received {
+    fn from(message: ServerMessage<F>) -> Self {
+        match message {
+            ServerMessage::IdentityToken(token) => Self::IdentityToken(token),
+            ServerMessage::SubscribeMultiApplied(applied) => Self::SubscribeMultiApplied {
+                request_id: applied.request_id,
+                query_id: applied.query_id.id,
+            },
+            ServerMessage::UnsubscribeMultiApplied(applied) => Self::UnsubscribeMultiApplied {
+                request_id: applied.request_id,
+                query_id: applied.query_id.id,
+            },
+            ServerMessage::SubscriptionError(error) => Self::SubscriptionError {
+                request_id: error.request_id,
+                query_id: error.query_id,
+                error: error.error.into(),
+            },
+            ServerMessage::TransactionUpdate(tx) => Self::TransactionUpdate(tx.into()),
+            ServerMessage::TransactionUpdateLight(tx) => Self::TransactionUpdateLight {
+                rows: tx.update.num_rows(),
+            },
+            ServerMessage::AcknowledgedUpdate(update) => Self::AcknowledgedUpdate {
+                delivery_id: update.delivery_id,
+                update: update.update.into(),
+            },
+            ServerMessage::OneOffQueryResponse(response) => Self::OneOffQueryResponse {
+                message_id: response.message_id.into(),
+                error: response.error.map(Into::into),
+                tables: response
+                    .tables
+                    .iter()
+                    .map(|table| table.table_name.to_string())
+                    .collect(),
+            },
+            ServerMessage::DeprecationNotice(_) => Self::Advisory("DeprecationNotice"),
+            ServerMessage::ReconnectRequested(_) => Self::Advisory("ReconnectRequested"),
+            ServerMessage::ConnectionDegraded(_) => Self::Advisory("ConnectionDegraded"),
+            ServerMessage::CallProgress(_) => Self::Advisory("CallProgress"),
+            ServerMessage::ServerCapabilities(_) => Self::Advisory("ServerCapabilities"),
+            ServerMessage::InitialSubscription(_) => Self::Other("InitialSubscription"),
+            ServerMessage::SubscribeApplied(_) => Self::Other("SubscribeApplied"),
+            ServerMessage::UnsubscribeApplied(_) => Self::Other("UnsubscribeApplied"),
+            ServerMessage::SubscribeWindowApplied(_) => Self::Other("SubscribeWindowApplied"),
+            ServerMessage::SubscribeMultiExplained(_) => Self::Other("SubscribeMultiExplained"),
+            ServerMessage::DatabaseStats(_) => Self::Other("DatabaseStats"),
+            ServerMessage::ReducerTimings(_) => Self::Other("ReducerTimings"),
+            ServerMessage::ConnectionStatus(_) => Self::Other("ConnectionStatus"),
+            ServerMessage::MessageDropped(_) => Self::Other("MessageDropped"),
+            ServerMessage::ResyncRequired(_) => Self::Other("ResyncRequired"),
+            ServerMessage::RequestError(_) => Self::Other("RequestError"),
+            ServerMessage::SettingsUpdated(_) => Self::Other("SettingsUpdated"),
+            ServerMessage::TokenRefreshed(_) => Self::Other("TokenRefreshed"),
+            ServerMessage::QueryRows(_) => Self::Other("QueryRows"),
+        }
+    }
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A websocket connection of a [`Variant`] of the protocol.
+pub(super) struct Conn {
+    socket: Socket,
+    variant: Variant,
+    /// How long to wait for each message.
+    timeout: Duration,
+    /// The headers of the response to the websocket upgrade.
+    pub headers: HeaderMap,
+}
+
+impl Conn {
+    /// Open a websocket at `url`, offering the subprotocol of `variant`,
+    /// with `token` as its credentials, if any.
+    pub async fn open(url: &str, variant: Variant, token: Option<&str>, timeout: Duration) -> Result<Self, WsError> {
+        let mut request = url.into_client_request()?;
+        let headers = request.headers_mut();
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(variant.subprotocol()),
+        );
+        if let Some(token) = token {
+            let auth = HeaderValue::try_from(format!("Bearer {token}"))
+                .map_err(tokio_tungstenite::tungstenite::http::Error::from)?;
+            headers.insert(header::AUTHORIZATION, auth);
+        }
+        let (socket, response) = tokio_tungstenite::connect_async(request).await?;
+        Ok(Self {
+            socket,
+            variant,
+            timeout,
+            headers: response.headers().clone(),
+        })
+    }
+
+    /// The value of the header `name` of the response to the websocket upgrade, if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    /// Send `message`, encoding its reducer arguments, if any, as the arguments of the connection's encoding.
+    pub async fn send(&mut self, message: ClientMessage<ProductValue>) -> anyhow::Result<()> {
+        let message = match self.variant.encoding {
+            Encoding::Bsatn => {
+                let message = message.map_args(|args| bsatn::to_vec(&args).unwrap());
+                WsMessage::Binary(bsatn::to_vec(&message)?.into())
+            }
+            Encoding::Json => {
+                let message =
+                    message.map_args(|args| serde_json::to_string(SerializeWrapper::from_ref(&args)).unwrap());
+                WsMessage::Text(serde_json::to_string(SerializeWrapper::from_ref(&message))?.into())
+            }
+        };
+        self.socket.send(message).await.context("failed to send a message")
+    }
+
+    /// Close the connection, as a client going away would, waiting for the server to close its end.
+    pub async fn close(mut self) -> anyhow::Result<()> {
+        self.socket
+            .close(None)
+            .await
+            .context("failed to close the connection")?;
+        tokio::time::timeout(self.timeout, async {
+            while let Some(Ok(_)) = self.socket.next().await {}
+        })
+        .await
+        .context("timed out waiting for the server to close the connection")
+    }
+
+    /// Receive the next message, or the end of the connection,
+    /// skipping the [advisory](Received::Advisory) messages the server may send at any time.
+    pub async fn recv(&mut self) -> anyhow::Result<Event> {
+        loop {
+            let message = tokio::time::timeout(self.timeout, self.socket.next())
+                .await
+                .context("timed out waiting for a message")?;
+            let frame = match message {
+                None | Some(Err(WsError::ConnectionClosed | WsError::AlreadyClosed)) => return Ok(Event::Closed(None)),
+                Some(Err(e)) => return Err(e).context("failed to receive a message"),
+                Some(Ok(WsMessage::Close(frame))) => return Ok(Event::Closed(frame)),
+                Some(Ok(WsMessage::Binary(bytes))) => self.decode_binary(&bytes)?,
+                Some(Ok(WsMessage::Text(text))) => self.decode_text(&text)?,
+                Some(Ok(WsMessage::Ping(_) | WsMessage::Pong(_) | WsMessage::Frame(_))) => continue,
+            };
+            match frame.message {
+                Received::Advisory(name) => log::debug!("skipping a {name} message"),
+                _ => return Ok(Event::Message(frame)),
+            }
+        }
+    }
+
+    /// Receive the next message, failing if the connection ends instead.
+    pub async fn recv_frame(&mut self) -> anyhow::Result<Frame> {
+        match self.recv().await? {
+            Event::Message(frame) => Ok(frame),
+            Event::Closed(frame) => bail!("the connection was closed: {frame:?}"),
+        }
+    }
+
+    /// Receive the next message, failing if the connection ends instead.
+    pub async fn recv_message(&mut self) -> anyhow::Result<Received> {
+        Ok(self.recv_frame().await?.message)
+    }
+
+    /// Receive until the connection is closed, returning its close frame.
+    pub async fn recv_close(&mut self) -> anyhow::Result<CloseFrame> {
+        loop {
+            if let Event::Closed(frame) = self.recv().await? {
+                return frame.context("the connection ended without a close frame");
+            }
+        }
+    }
+
+    /// Decode a binary message, prefixed with its compression tag.
+    fn decode_binary(&self, bytes: &[u8]) -> anyhow::Result<Frame> {
+        ensure!(
+            self.variant.encoding == Encoding::Bsatn,
+            "sent a binary message over `{}`",
+            self.variant.subprotocol()
+        );
+        let (&tag, body) = bytes
+            .split_first()
+            .context("sent an empty binary message, which should start with a compression tag")?;
+        let compression = Compression::from_tag(tag)
+            .with_context(|| format!("sent a message with unknown compression tag {tag:#x}"))?;
+        ensure!(
+            self.variant.version.supports(compression),
+            "sent a message compressed with {compression:?}, which isn't part of `{}`",
+            self.variant.subprotocol()
+        );
+        let body = match compression {
+            Compression::None => Cow::Borrowed(body),
+            Compression::Brotli => brotli_decompress(body)?.into(),
+            Compression::Gzip => gzip_decompress(body)?.into(),
+            Compression::Zstd => zstd_decompress(body)?.into(),
+        };
+        let message = bsatn::from_slice::<ServerMessage<BsatnFormat>>(&body)
+            .with_context(|| format!("sent a malformed message, compressed with {compression:?}"))?;
+        Ok(Frame {
+            compression,
+            message: message.into(),
+        })
+    }
+
+    /// Decode a text message, which is bare JSON in version 1, and wrapped in a [`TextEnvelope`] in version 2.
+    fn decode_text(&self, text: &str) -> anyhow::Result<Frame> {
+        ensure!(
+            self.variant.encoding == Encoding::Json,
+            "sent a text message over `{}`",
+            self.variant.subprotocol()
+        );
+        type Message = DeserializeWrapper<ServerMessage<JsonFormat>>;
+        let DeserializeWrapper(message) = match self.variant.version {
+            ProtocolVersion::V1 => serde_json::from_str::<Message>(text).context("sent a malformed message")?,
+            ProtocolVersion::V2 => {
+                let envelope = serde_json::from_str::<TextEnvelope<Message>>(text)
+                    .context("sent a malformed message, or one without its envelope")?;
+                ensure!(
+                    envelope.compression == Compression::None,
+                    "sent a text message enveloped as compressed with {:?}",
+                    envelope.compression
+                );
+                envelope.message
+            }
+        };
+        Ok(Frame {
+            compression: Compression::None,
+            message: message.into(),
+        })
+    }
+}
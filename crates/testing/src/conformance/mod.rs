@@ -0,0 +1,281 @@
+//! A suite checking the websocket protocol of a live node over the network, run as `spacetime-protocol-conformance`.
+//!
+//! SDKs rely on the contract of the protocol: how the handshake is negotiated, that the identity token comes first,
+//! that subscriptions are applied before any update to them, the shapes of replies and errors,
+//! the codes of close frames, how messages are framed and compressed, and the features negotiated when connecting.
+//! The suite publishes the `protocol-conformance` module to a scratch database,
+//! then runs each check once for each [`Variant`] of the protocol, reporting the outcome of each, see [`Report`].
+//!
+//! So that an SDK can pin the subset of the protocol it implements,
+//! the versions, encodings and features checked are chosen by the [`Options`].
+
+mod checks;
+mod client;
+mod report;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context as _};
+use serde::Deserialize;
+use spacetimedb_client_api_messages::name::PublishResult;
+use spacetimedb_client_api_messages::websocket::ProtocolVersion;
+use spacetimedb_lib::{DatabaseFeature, DatabaseFeatures, Identity};
+
+pub use client::{Encoding, Variant};
+pub use report::{Entry, Outcome, Report, ReportFormat};
+
+/// What the suite checks, and against which node.
+#[derive(clap::Args, Clone, Debug)]
+pub struct Options {
+    /// The URL of the node to check.
+    #[arg(long, default_value = "http://127.0.0.1:3000")]
+    pub server: String,
+    /// A version of the protocol to check, `v1` or `v2`. May be repeated. Defaults to every version.
+    #[arg(long = "protocol", value_parser = parse_version)]
+    pub versions: Vec<ProtocolVersion>,
+    /// An encoding of the protocol to check. May be repeated. Defaults to every encoding.
+    #[arg(long = "encoding", value_enum)]
+    pub encodings: Vec<Encoding>,
+    /// A feature to turn on for the scratch database, e.g. `acknowledged-delivery`. May be repeated.
+    ///
+    /// The checks of features which aren't turned on are skipped.
+    #[arg(long = "feature", value_parser = parse_feature)]
+    pub features: Vec<DatabaseFeature>,
+    /// How the scratch database formats the errors and close frames it sends.
+    #[arg(long, value_enum, default_value_t)]
+    pub error_format: ErrorFormat,
+    /// Only run the checks whose names start with this, e.g. `handshake.`. May be repeated.
+    #[arg(long)]
+    pub only: Vec<String>,
+    /// Skip the checks whose names start with this. May be repeated.
+    #[arg(long)]
+    pub skip: Vec<String>,
+    /// How long to wait for each message the server should send, in seconds.
+    #[arg(long, default_value_t = 10)]
+    pub timeout_secs: u64,
+    /// Keep the scratch database once the checks have run, rather than deleting it.
+    #[arg(long)]
+    pub keep_database: bool,
+}
+
+/// How a database formats the errors and close frames it sends, see `SET legacy_errors`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// The plain text of older versions, the default of new databases.
+    #[default]
+    Legacy,
+    /// Machine-readable errors, e.g. JSON close payloads.
+    Structured,
+}
+
+impl ErrorFormat {
+    /// The name of the format, as sent in the `spacetime-error-format` header.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Legacy => "legacy",
+            Self::Structured => "structured",
+        }
+    }
+}
+
+fn parse_version(version: &str) -> Result<ProtocolVersion, String> {
+    match version {
+        "v1" => Ok(ProtocolVersion::V1),
+        "v2" => Ok(ProtocolVersion::V2),
+        _ => Err(format!("unknown protocol version `{version}`, expected `v1` or `v2`")),
+    }
+}
+
+fn parse_feature(name: &str) -> Result<DatabaseFeature, String> {
+    DatabaseFeature::from_name(name).ok_or_else(|| {
+        let known = DatabaseFeature::ALL.map(DatabaseFeature::name).join(", ");
+        format!("unknown feature `{name}`, expected one of: {known}")
+    })
+}
+
+impl Options {
+    /// The variants of the protocol to check, every version in every encoding chosen.
+    pub fn variants(&self) -> Vec<Variant> {
+        let versions = match &*self.versions {
+            [] => &[ProtocolVersion::V1, ProtocolVersion::V2][..],
+            versions => versions,
+        };
+        let encodings = match &*self.encodings {
+            [] => &[Encoding::Bsatn, Encoding::Json][..],
+            encodings => encodings,
+        };
+        versions
+            .iter()
+            .flat_map(|&version| encodings.iter().map(move |&encoding| Variant { version, encoding }))
+            .collect()
+    }
+
+    pub fn features(&self) -> DatabaseFeatures {
+        self.features.iter().copied().collect()
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    /// Returns whether the check `name` was chosen to run.
+    fn selected(&self, name: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|prefix| name.starts_with(&**prefix)))
+            && !self.skip.iter().any(|prefix| name.starts_with(&**prefix))
+    }
+}
+
+/// The database the checks are run against, published for the run by an identity of its own.
+pub struct ScratchDatabase {
+    http: reqwest::Client,
+    /// The URL of the node, without a trailing slash.
+    server: String,
+    pub identity: Identity,
+    /// The token of the database's owner.
+    owner_token: String,
+}
+
+#[derive(Deserialize)]
+struct NewIdentity {
+    token: String,
+}
+
+impl ScratchDatabase {
+    /// Publish `program` to a new database on `server`, owned by a new identity.
+    pub async fn publish(server: &str, program: Vec<u8>) -> anyhow::Result<Self> {
+        let http = reqwest::Client::new();
+        let server = server.trim_end_matches('/').to_owned();
+        let owner = http
+            .post(format!("{server}/v1/identity"))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .with_context(|| format!("failed to create an identity on {server}"))?
+            .json::<NewIdentity>()
+            .await?;
+        let res = http
+            .post(format!("{server}/v1/database"))
+            .bearer_auth(&owner.token)
+            .body(program)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            bail!("failed to publish the module: {}: {}", res.status(), res.text().await?);
+        }
+        let identity = match res.json::<PublishResult>().await? {
+            PublishResult::Success { database_identity, .. } => database_identity,
+            PublishResult::PermissionDenied { name } => bail!("not permitted to publish to {name}"),
+        };
+        Ok(Self {
+            http,
+            server,
+            identity,
+            owner_token: owner.token,
+        })
+    }
+
+    /// Execute `sql` against the database as its owner, e.g. to `SET` its system variables.
+    pub async fn sql(&self, sql: &str) -> anyhow::Result<()> {
+        self.http
+            .post(format!("{}/v1/database/{}/sql", self.server, self.identity))
+            .bearer_auth(&self.owner_token)
+            .body(sql.to_owned())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .with_context(|| format!("failed to execute `{sql}`"))?;
+        Ok(())
+    }
+
+    /// Turn on exactly `features` for the database.
+    pub async fn set_features(&self, features: DatabaseFeatures) -> anyhow::Result<()> {
+        let features = features.iter().map(DatabaseFeature::name).collect::<Vec<_>>();
+        self.http
+            .put(format!("{}/v1/database/{}/features", self.server, self.identity))
+            .bearer_auth(&self.owner_token)
+            .json(&serde_json::json!({ "features": features }))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .context("failed to set the features of the database")?;
+        Ok(())
+    }
+
+    pub async fn delete(&self) -> anyhow::Result<()> {
+        self.http
+            .delete(format!("{}/v1/database/{}", self.server, self.identity))
+            .bearer_auth(&self.owner_token)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .context("failed to delete the scratch database")?;
+        Ok(())
+    }
+
+    /// The URL of the websocket endpoint of the database, with `query`.
+    fn subscribe_url(&self, query: &str) -> String {
+        let server = match self.server.split_once("://") {
+            Some(("https", rest)) => format!("wss://{rest}"),
+            Some((_, rest)) => format!("ws://{rest}"),
+            None => format!("ws://{}", self.server),
+        };
+        format!("{server}/v1/database/{}/subscribe?{query}", self.identity)
+    }
+}
+
+/// Publish `program`, the `protocol-conformance` module, to a scratch database on the node of `options`,
+/// run the chosen checks against it, then delete it, unless asked to keep it.
+pub async fn run(options: &Options, program: Vec<u8>) -> anyhow::Result<Report> {
+    let database = Arc::new(ScratchDatabase::publish(&options.server, program).await?);
+    log::info!("published the conformance module to {}", database.identity);
+    let report = configure(options, &database).await;
+    let report = match report {
+        Ok(()) => Ok(run_checks(options, &database).await),
+        Err(e) => Err(e),
+    };
+    if options.keep_database {
+        log::info!("kept the scratch database {}", database.identity);
+    } else if let Err(e) = database.delete().await {
+        log::warn!("{e:#}");
+    }
+    report
+}
+
+async fn configure(options: &Options, database: &ScratchDatabase) -> anyhow::Result<()> {
+    if options.error_format == ErrorFormat::Structured {
+        database.sql("SET legacy_errors = false").await?;
+    }
+    database.set_features(options.features()).await
+}
+
+async fn run_checks(options: &Options, database: &Arc<ScratchDatabase>) -> Report {
+    let options = Arc::new(options.clone());
+    let mut report = Report::default();
+    for variant in options.variants() {
+        for check in checks::CHECKS.iter().filter(|check| options.selected(check.name)) {
+            let ctx = checks::Ctx {
+                options: options.clone(),
+                database: database.clone(),
+                variant,
+            };
+            let start = Instant::now();
+            let outcome = match check.skip_reason(&ctx) {
+                Some(reason) => Outcome::Skip(reason),
+                // Every message a check waits for is bounded by the timeout, but connecting isn't.
+                None => match tokio::time::timeout(options.timeout() * 3, (check.run)(ctx)).await {
+                    Ok(Ok(())) => Outcome::Pass,
+                    Ok(Err(e)) => Outcome::Fail(format!("{e:#}")),
+                    Err(_) => Outcome::Fail("timed out".into()),
+                },
+            };
+            report.entries.push(Entry {
+                check: check.name,
+                variant,
+                outcome,
+                duration: start.elapsed(),
+            });
+        }
+    }
+    report
+}
@@ -0,0 +1,197 @@
+//! The outcome of each check of a run of the suite, rendered as TAP or JSON.
+
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::Variant;
+
+/// How a [`Report`] is rendered.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// The Test Anything Protocol, version 13, with the reason of each failure as a YAML diagnostic.
+    #[default]
+    Tap,
+    /// A JSON object summarizing the run, with an entry for each check.
+    Json,
+}
+
+/// What became of a check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// The check failed, for the reason given.
+    Fail(String),
+    /// The check wasn't run, for the reason given, e.g. because it's of a feature that isn't turned on.
+    Skip(String),
+}
+
+/// The outcome of a check of a [`Variant`].
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub check: &'static str,
+    pub variant: Variant,
+    pub outcome: Outcome,
+    pub duration: Duration,
+}
+
+/// The outcomes of the checks of a run of the suite, in the order they ran.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+    checks: Vec<JsonEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    check: &'a str,
+    protocol: &'a str,
+    status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+    duration_ms: u128,
+}
+
+impl Report {
+    /// How many checks had an outcome matching `f`.
+    fn count(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+        self.entries.iter().filter(|entry| f(&entry.outcome)).count()
+    }
+
+    /// Returns whether no check failed.
+    pub fn passed(&self) -> bool {
+        self.count(|outcome| matches!(outcome, Outcome::Fail(_))) == 0
+    }
+
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Tap => self.to_tap(),
+            ReportFormat::Json => self.to_json(),
+        }
+    }
+
+    /// Render the report as TAP, naming each check after the subprotocol it was run over.
+    pub fn to_tap(&self) -> String {
+        let mut out = format!("TAP version 13\n1..{}\n", self.entries.len());
+        for (i, entry) in self.entries.iter().enumerate() {
+            let name = format!("{} {}", entry.variant.subprotocol(), entry.check);
+            let n = i + 1;
+            match &entry.outcome {
+                Outcome::Pass => writeln!(out, "ok {n} - {name}"),
+                Outcome::Skip(reason) => writeln!(out, "ok {n} - {name} # SKIP {reason}"),
+                Outcome::Fail(reason) => {
+                    // A JSON string is a YAML scalar, whatever it contains.
+                    let reason = serde_json::to_string(reason).unwrap();
+                    writeln!(out, "not ok {n} - {name}\n  ---\n  message: {reason}\n  ...")
+                }
+            }
+            .unwrap();
+        }
+        out
+    }
+
+    /// Render the report as a JSON object.
+    pub fn to_json(&self) -> String {
+        let checks = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let (status, reason) = match &entry.outcome {
+                    Outcome::Pass => ("pass", None),
+                    Outcome::Fail(reason) => ("fail", Some(&**reason)),
+                    Outcome::Skip(reason) => ("skip", Some(&**reason)),
+                };
+                JsonEntry {
+                    check: entry.check,
+                    protocol: entry.variant.subprotocol(),
+                    status,
+                    reason,
+                    duration_ms: entry.duration.as_millis(),
+                }
+            })
+            .collect();
+        let report = JsonReport {
+            passed: self.count(|outcome| *outcome == Outcome::Pass),
+            failed: self.count(|outcome| matches!(outcome, Outcome::Fail(_))),
+            skipped: self.count(|outcome| matches!(outcome, Outcome::Skip(_))),
+            checks,
+        };
+        serde_json::to_string_pretty(&report).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::Encoding;
+    use spacetimedb_client_api_messages::websocket::ProtocolVersion;
+
+    fn report() -> Report {
+        let entry = |check, version, outcome| Entry {
+            check,
+            variant: Variant {
+                version,
+                encoding: Encoding::Bsatn,
+            },
+            outcome,
+            duration: Duration::from_millis(3),
+        };
+        Report {
+            entries: vec![
+                entry("identity.token-first", ProtocolVersion::V1, Outcome::Pass),
+                entry(
+                    "call.failed",
+                    ProtocolVersion::V2,
+                    Outcome::Fail("expected \"boom\",\ngot nothing".into()),
+                ),
+                entry(
+                    "features.acknowledged-delivery",
+                    ProtocolVersion::V2,
+                    Outcome::Skip("feature `acknowledged-delivery` is off".into()),
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn tap_reports_failures_as_yaml_diagnostics() {
+        let report = report();
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_tap(),
+            "TAP version 13\n\
+             1..3\n\
+             ok 1 - v1.bsatn.spacetimedb identity.token-first\n\
+             not ok 2 - v2.bsatn.spacetimedb call.failed\n  \
+             ---\n  \
+             message: \"expected \\\"boom\\\",\\ngot nothing\"\n  \
+             ...\n\
+             ok 3 - v2.bsatn.spacetimedb features.acknowledged-delivery # SKIP feature `acknowledged-delivery` is off\n"
+        );
+    }
+
+    #[test]
+    fn json_summarizes_the_outcomes() {
+        let json: serde_json::Value = serde_json::from_str(&report().to_json()).unwrap();
+        assert_eq!(
+            (
+                json["passed"].as_u64(),
+                json["failed"].as_u64(),
+                json["skipped"].as_u64()
+            ),
+            (Some(1), Some(1), Some(1))
+        );
+        assert_eq!(json["checks"][1]["protocol"], "v2.bsatn.spacetimedb");
+        assert_eq!(json["checks"][1]["status"], "fail");
+        assert!(json["checks"][0].get("reason").is_none());
+        assert_eq!(json["checks"][2]["reason"], "feature `acknowledged-delivery` is off");
+    }
+}
@@ -3,6 +3,7 @@ use spacetimedb_cli::Config;
 use spacetimedb_paths::SpacetimePaths;
 use spacetimedb_schema::def::ModuleDef;
 
+pub mod conformance;
 pub mod modules;
 pub mod sdk;
 
@@ -1,5 +1,6 @@
 use std::env;
 use std::future::Future;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::OnceLock;
@@ -10,21 +11,25 @@ use spacetimedb::messages::control_db::HostType;
 use spacetimedb::Identity;
 use spacetimedb_client_api::auth::SpacetimeAuth;
 use spacetimedb_client_api::routes::subscribe::generate_random_connection_id;
+use spacetimedb_client_api::routes::{self, database::DatabaseRoutes};
 use spacetimedb_paths::{RootDir, SpacetimePaths};
 use spacetimedb_schema::def::ModuleDef;
 use tokio::runtime::{Builder, Runtime};
 
-use spacetimedb::client::{ClientActorId, ClientConfig, ClientConnection, DataMessage};
+use spacetimedb::client::{
+    ClientActorId, ClientConfig, ClientConnection, ConnectionLimits, DataMessage, WebSocketOptions,
+};
 use spacetimedb::database_logger::DatabaseLogger;
 use spacetimedb::db::{Config, Storage};
 use spacetimedb::host::ReducerArgs;
 use spacetimedb::messages::websocket::CallReducerFlags;
 use spacetimedb_client_api::{ControlStateReadAccess, ControlStateWriteAccess, DatabaseDef, NodeDelegate};
+use spacetimedb_lib::identity::AuthCtx;
 use spacetimedb_lib::{bsatn, sats};
 
 pub use spacetimedb::database_logger::LogLevel;
 
-use spacetimedb_standalone::StandaloneEnv;
+use spacetimedb_standalone::{NodeOptions, StandaloneEnv};
 
 pub fn start_runtime() -> Runtime {
     Builder::new_multi_thread().enable_all().build().unwrap()
@@ -56,7 +61,7 @@ impl ModuleHandle {
     async fn call_reducer(&self, reducer: &str, args: ReducerArgs) -> anyhow::Result<()> {
         let result = self
             .client
-            .call_reducer(reducer, args, 0, Instant::now(), CallReducerFlags::FullUpdate)
+            .call_reducer(reducer, args, 0, Instant::now(), CallReducerFlags::FullUpdate, None)
             .await;
         let result = match result {
             Ok(result) => result.into(),
@@ -80,7 +85,64 @@ impl ModuleHandle {
 
     pub async fn send(&self, message: impl Into<DataMessage>) -> anyhow::Result<()> {
         let timer = Instant::now();
-        self.client.handle_message(message, timer).await.map_err(Into::into)
+        self.client
+            .handle_message(message, timer)
+            .await
+            .map(drop)
+            .map_err(Into::into)
+    }
+
+    /// The node hosting the database.
+    pub fn node(&self) -> &StandaloneEnv {
+        &self._env
+    }
+
+    /// Serve the node's HTTP API on a free local port, for as long as the runtime lives,
+    /// returning the address it's served at.
+    pub async fn serve(&self) -> anyhow::Result<SocketAddr> {
+        let ctx = self._env.clone();
+        let service = routes::router(&ctx, DatabaseRoutes::default(), axum::Router::new()).with_state(ctx);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            axum::serve(listener, service.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+        Ok(addr)
+    }
+
+    /// Serve the routes of [`Self::serve`] over the single connection `io`,
+    /// e.g. one half of a [`tokio::io::duplex`] the test controls the other half of.
+    pub fn serve_connection<IO>(&self, io: IO)
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        let ctx = self._env.clone();
+        let service = routes::router(&ctx, DatabaseRoutes::default(), axum::Router::new()).with_state(ctx);
+        let conn = hyper::server::conn::http1::Builder::new()
+            .serve_connection(
+                hyper_util::rt::TokioIo::new(io),
+                hyper_util::service::TowerToHyperService::new(service),
+            )
+            .with_upgrades();
+        tokio::spawn(conn);
+    }
+
+    /// Execute `sql` against the database as its owner, e.g. to `SET` its system variables.
+    pub async fn sql(&self, sql: &str) -> anyhow::Result<()> {
+        let database = self
+            ._env
+            .get_database_by_identity(&self.db_identity)?
+            .ok_or_else(|| anyhow::anyhow!("no such database: {}", self.db_identity))?;
+        let host = self
+            ._env
+            .leader(database.id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("database {} has no leader", self.db_identity))?;
+        let auth = AuthCtx::for_current(database.owner_identity);
+        host.exec_sql(auth, database, sql.to_owned())
+            .await
+            .map(drop)
+            .map_err(|_| anyhow::anyhow!("failed to execute `{sql}`"))
     }
 
     pub async fn read_log(&self, size: Option<u32>) -> String {
@@ -93,6 +155,9 @@ pub struct CompiledModule {
     name: String,
     path: PathBuf,
     program_bytes: OnceLock<Vec<u8>>,
+    /// The websocket options of the nodes the module is loaded into.
+    websocket_options: WebSocketOptions,
+    connection_limits: ConnectionLimits,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -113,6 +178,24 @@ impl CompiledModule {
             name: name.to_owned(),
             path,
             program_bytes: OnceLock::new(),
+            websocket_options: WebSocketOptions::default(),
+            connection_limits: ConnectionLimits::default(),
+        }
+    }
+
+    /// Load the module into nodes whose websocket connections follow `options`.
+    pub fn with_websocket_options(self, websocket_options: WebSocketOptions) -> Self {
+        Self {
+            websocket_options,
+            ..self
+        }
+    }
+
+    /// Load the module into nodes which admit websocket connections within `limits`.
+    pub fn with_connection_limits(self, connection_limits: ConnectionLimits) -> Self {
+        Self {
+            connection_limits,
+            ..self
         }
     }
 
@@ -178,10 +261,19 @@ impl CompiledModule {
         };
 
         let certs = CertificateAuthority::in_cli_config_dir(&paths.cli_config_dir);
-        let env =
-            spacetimedb_standalone::StandaloneEnv::init(config, &certs, paths.data_dir.into(), Default::default())
-                .await
-                .unwrap();
+        let env = spacetimedb_standalone::StandaloneEnv::init(
+            config,
+            &certs,
+            paths.data_dir.into(),
+            Default::default(),
+            NodeOptions {
+                connection_limits: self.connection_limits.clone(),
+                websocket: self.websocket_options.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
         // TODO: Fix this when we update identity generation.
         let identity = Identity::ZERO;
         let db_identity = SpacetimeAuth::alloc(&env).await.unwrap().identity;
@@ -1,13 +1,52 @@
+use futures::{FutureExt, SinkExt, StreamExt};
 use serial_test::serial;
+use spacetimedb::auth::identity::SpacetimeIdentityClaims;
+use spacetimedb::auth::token_validation::TokenSigner;
+use spacetimedb::client::messages::SerializableMessage;
+use spacetimedb::client::{
+    ClientActorId, ClientConfig, ClientConnection, ClientConnectionSender, CloseReason, ConnectionIdReuse,
+    ConnectionLimits, ConnectionPolicy, MessageThrottle, WebSocketOptions,
+};
+use spacetimedb::host::progress::PROGRESS_BURST;
+use spacetimedb::host::ReducerArgs;
+use spacetimedb::messages::control_db::MessageSizeLimits;
+use spacetimedb::messages::websocket::CallReducerFlags;
+use spacetimedb_client_api::auth::{JwtAuthProvider, SpacetimeAuth};
+use spacetimedb_client_api::routes::subscribe::{
+    generate_random_connection_id, purge_replay_buffers, PurgedReplayBuffers,
+};
+use spacetimedb_client_api::timeouts::TimeoutsConfig;
+use spacetimedb_client_api::{ControlStateReadAccess, ControlStateWriteAccess, NodeDelegate};
+use spacetimedb_client_api_messages::e2e;
+use spacetimedb_client_api_messages::websocket::{
+    decode_channel_frame, encode_channel_frame, Attach, Authenticate, CallReducer, ClientMessage, Detach,
+    DisconnectReason, MultiplexClientMessage, MultiplexServerMessage, MULTIPLEX_CONTROL_CHANNEL, RESUME_TOKEN_HEADER,
+    SESSION_RESUMED_HEADER,
+};
 use spacetimedb_lib::sats::{product, AlgebraicValue};
+use spacetimedb_lib::{bsatn, DatabaseFeature, Identity, ReducerTransport};
+use spacetimedb_testing::conformance::{self, ErrorFormat, Options};
 use spacetimedb_testing::modules::{
     CompilationMode, CompiledModule, Csharp, LogLevel, LoggerRecord, ModuleHandle, ModuleLanguage, Rust,
     DEFAULT_CONFIG, IN_MEMORY_CONFIG,
 };
 use std::{
     future::Future,
-    time::{Duration, Instant},
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{CloseCode, Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+use tokio_tungstenite::WebSocketStream;
 
 fn init() {
     let _ = env_logger::builder()
@@ -213,6 +252,1545 @@ fn test_call_query_macro() {
     });
 }
 
+#[test]
+#[serial]
+/// Ban the test client in the `kick-test` module,
+/// which kicks the client only once the ban is committed.
+fn test_kicking_a_client() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            // The test client connects as `Identity::ZERO`.
+            let args = product![Identity::ZERO, "spam"];
+
+            // A kick is dropped along with the rest of a failed reducer's transaction.
+            assert!(module.call_reducer_binary("ban_then_fail", &args).await.is_err());
+            assert_eq!(module.client.close_reason(), None);
+
+            module.call_reducer_binary("ban", &args).await.unwrap();
+            assert_eq!(
+                module.client.close_reason(),
+                Some(CloseReason::KickedByModule("spam".into()))
+            );
+            assert!(module
+                .read_log(None)
+                .await
+                .contains(&format!("Banned {}, kicking 1 connection(s)", Identity::ZERO)));
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Call the reducers of the `transport-test` module over each transport,
+/// each of which fails unless it was called over the transport it expects.
+fn test_reducer_transport() {
+    init();
+
+    CompiledModule::compile("transport-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            module
+                .call_reducer_binary("check_websocket", &product![])
+                .await
+                .unwrap();
+            assert!(module.call_reducer_binary("check_http", &product![]).await.is_err());
+
+            let res: anyhow::Result<()> = module
+                .client
+                .module
+                .call_reducer(
+                    module.client.id.identity,
+                    Some(module.client.id.connection_id),
+                    None,
+                    ReducerTransport::Http,
+                    None,
+                    None,
+                    None,
+                    "check_http",
+                    ReducerArgs::Nullary,
+                )
+                .await
+                .unwrap()
+                .into();
+            res.unwrap();
+
+            // `init` schedules `check_scheduled` to run right away.
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !module.read_log(None).await.contains("check_scheduled was called over") {
+                assert!(Instant::now() < deadline, "`check_scheduled` never ran");
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+
+            let logs = module.read_log(None).await;
+            for expected in [
+                "init was called over internal",
+                "check_scheduled was called over scheduled",
+                "check_websocket was called over websocket",
+                "check_http was called over http",
+            ] {
+                assert!(logs.contains(expected), "missing `{expected}` in logs:\n{logs}");
+            }
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Call the reducers of the `progress-test` module, which count, reporting each step as progress,
+/// which is sent to the caller ahead of the call's reply, up to the rate limit,
+/// whether or not the call fails.
+fn test_reducer_progress() {
+    init();
+
+    CompiledModule::compile("progress-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            for reducer in ["count_to", "count_to_then_fail"] {
+                let (caller, mut rx) = ClientConnectionSender::dummy_with_capacity(
+                    module.client.id,
+                    ClientConfig::for_test(),
+                    <_>::default(),
+                );
+                let args = bsatn::to_vec(&product![100u32]).unwrap();
+                module
+                    .client
+                    .module
+                    .call_reducer(
+                        module.client.id.identity,
+                        Some(module.client.id.connection_id),
+                        Some(Arc::new(caller)),
+                        ReducerTransport::Websocket,
+                        Some(7),
+                        None,
+                        None,
+                        reducer,
+                        ReducerArgs::Bsatn(args.into()),
+                    )
+                    .await
+                    .unwrap();
+
+                // Progress precedes the call's reply.
+                let mut steps = vec![];
+                let reply = loop {
+                    let message = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                        .await
+                        .expect("the call was never replied to")
+                        .unwrap();
+                    match message {
+                        SerializableMessage::CallProgress(progress) => {
+                            assert_eq!(progress.request_id, 7);
+                            steps.push(bsatn::from_slice::<u32>(&progress.payload).unwrap());
+                        }
+                        reply => break reply,
+                    }
+                };
+                assert!(matches!(reply, SerializableMessage::TxUpdate(_)), "{reply:?}");
+
+                // A burst of steps is sent at once, beyond which most are dropped.
+                assert!(steps.len() >= PROGRESS_BURST as usize && steps.len() < 100, "{steps:?}");
+                assert_eq!(
+                    steps[..PROGRESS_BURST as usize],
+                    (0..PROGRESS_BURST).collect::<Vec<_>>()
+                );
+                assert!(steps.is_sorted(), "{steps:?}");
+            }
+        },
+    );
+}
+
+type TestWebSocket = WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Connect a websocket client to the database of `module`, whose node is served at `addr`,
+/// returning once the client has been sent its identity token, which establishes the connection,
+/// and the server's capabilities.
+async fn connect_ws(addr: SocketAddr, module: &ModuleHandle) -> Result<TestWebSocket, WsError> {
+    connect_ws_with(addr, module, "").await
+}
+
+/// Like [`connect_ws`], with the query parameters `query`, e.g. `token=...&connection_id=...`.
+async fn connect_ws_with(addr: SocketAddr, module: &ModuleHandle, query: &str) -> Result<TestWebSocket, WsError> {
+    let (mut ws, _) = open_ws(addr, module, query).await?;
+    recv_handshake(&mut ws).await;
+    Ok(ws)
+}
+
+/// Receive the messages a new connection is sent first, its identity token and then the server's capabilities,
+/// skipping any pings sent in between.
+async fn recv_handshake<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut WebSocketStream<S>) -> [WsMessage; 2] {
+    let mut handshake = vec![];
+    for what in ["identity token", "capabilities"] {
+        let message = loop {
+            match tokio::time::timeout(Duration::from_secs(5), ws.next()).await {
+                Ok(Some(Ok(WsMessage::Ping(_)))) => {}
+                message => break message,
+            }
+        };
+        let Ok(Some(Ok(message @ (WsMessage::Binary(_) | WsMessage::Text(_))))) = message else {
+            panic!("expected the {what}, got {message:?}");
+        };
+        handshake.push(message);
+    }
+    handshake.try_into().unwrap()
+}
+
+/// Open a websocket to the database of `module` with the query parameters `query`,
+/// returning it along with the upgrade response, without waiting for anything to be sent over it.
+async fn open_ws(addr: SocketAddr, module: &ModuleHandle, query: &str) -> Result<(TestWebSocket, Response), WsError> {
+    tokio_tungstenite::connect_async(subscribe_request(&addr.to_string(), module, query)?).await
+}
+
+/// Like [`open_ws`], but over an in-process stream to the node of `module`
+/// which buffers at most `capacity` bytes in each direction,
+/// so that the node's writes block once the client stops reading.
+async fn open_ws_over_duplex(
+    module: &ModuleHandle,
+    query: &str,
+    capacity: usize,
+) -> Result<(WebSocketStream<tokio::io::DuplexStream>, Response), WsError> {
+    let (client, server) = tokio::io::duplex(capacity);
+    module.serve_connection(server);
+    tokio_tungstenite::client_async(subscribe_request("localhost", module, query)?, client).await
+}
+
+fn subscribe_request(
+    host: &str,
+    module: &ModuleHandle,
+    query: &str,
+) -> Result<tokio_tungstenite::tungstenite::handshake::client::Request, WsError> {
+    let url = format!("ws://{host}/v1/database/{}/subscribe?{query}", module.db_identity);
+    let mut request = url.into_client_request()?;
+    request
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.bsatn.spacetimedb"));
+    Ok(request)
+}
+
+/// Receive from `ws` until it's closed within `timeout`, returning the close frame it was sent.
+async fn recv_close_frame<S: AsyncRead + AsyncWrite + Unpin>(
+    ws: &mut WebSocketStream<S>,
+    timeout: Duration,
+) -> CloseFrame {
+    loop {
+        let message = tokio::time::timeout(timeout, ws.next())
+            .await
+            .expect("the client was never closed");
+        match message {
+            Some(Ok(WsMessage::Close(frame))) => return frame.expect("the close frame should carry a reason"),
+            Some(Ok(_)) => {}
+            ended => panic!("the socket ended without a close frame: {ended:?}"),
+        }
+    }
+}
+
+#[test]
+#[serial]
+/// Connect a websocket client which asks for end-to-end encryption,
+/// whose server key is bound to its own by a signature of the node's.
+fn test_e2e_keys_are_bound_by_the_node() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let addr = module.serve().await.unwrap();
+            let client = e2e::KeyPair::generate();
+            let query = format!("{}={}", e2e::PUBLIC_KEY_PARAM, client.public_key());
+            let (_ws, response) = open_ws(addr, &module, &query).await.unwrap();
+            let server_public_key = response.headers()[e2e::PUBLIC_KEY_HEADER].to_str().unwrap();
+            let binding = response.headers()[e2e::KEY_BINDING_HEADER].to_str().unwrap();
+
+            let node_key = module.node().jwt_auth_provider().public_key_bytes();
+            let node_key = jsonwebtoken::DecodingKey::from_ec_pem(node_key).unwrap();
+            let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256);
+            validation.required_spec_claims.clear();
+            validation.validate_exp = false;
+            let binding = jsonwebtoken::decode::<e2e::KeyBinding>(binding, &node_key, &validation).unwrap();
+            assert_eq!(
+                binding.claims,
+                e2e::KeyBinding {
+                    client_public_key: client.public_key(),
+                    server_public_key: server_public_key.to_owned(),
+                }
+            );
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Connect a websocket client to a node, then drain the node,
+/// which tells the client why before closing the socket, and refuses new clients.
+fn test_draining_closes_websockets_with_a_reason() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let addr = module.serve().await.unwrap();
+            let mut ws = connect_ws(addr, &module).await.unwrap();
+
+            let drained = module.node().client_actor_index().drain(Some(Duration::from_secs(30)));
+            assert!(drained >= 1, "the client should be asked to close");
+
+            let frame = recv_close_frame(&mut ws, Duration::from_secs(5)).await;
+            assert_eq!(frame.code, CloseCode::Away);
+            assert!(frame.reason.contains("server draining"), "{frame:?}");
+            // The close handshake completes, ending the socket.
+            assert!(matches!(ws.next().await, None | Some(Err(_))));
+
+            let Err(WsError::Http(refused)) = connect_ws(addr, &module).await else {
+                panic!("a draining node should refuse new clients");
+            };
+            assert_eq!(refused.status(), 503);
+            assert_eq!(refused.headers()["retry-after"], "30");
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Connect a burst of websocket clients at once to a node with a hard connection limit below its soft one,
+/// which admits only as many as the hard limit, refusing the rest.
+fn test_connection_limits_hold_under_a_burst() {
+    init();
+
+    let limits = ConnectionLimits {
+        soft_limit: Some(3),
+        hard_limit: Some(2),
+        ..<_>::default()
+    };
+    CompiledModule::compile("kick-test", CompilationMode::Debug)
+        .with_connection_limits(limits)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            let addr = module.serve().await.unwrap();
+            let burst = (0..8).map(|_| connect_ws(addr, &module));
+            let (connected, refused): (Vec<_>, Vec<_>) = futures::future::join_all(burst)
+                .await
+                .into_iter()
+                .partition(Result::is_ok);
+
+            assert_eq!(connected.len(), 2);
+            for refused in refused {
+                let Err(WsError::Http(refused)) = refused else {
+                    panic!("expected the client to be refused, got {refused:?}");
+                };
+                assert_eq!(refused.status(), 503);
+            }
+            assert_eq!(module.node().client_actor_index().num_connections(), 2);
+        });
+}
+
+#[test]
+#[serial]
+/// Run the protocol conformance suite against a node over the network,
+/// both with a database's defaults and with every feature and structured errors turned on.
+fn test_protocol_conformance_suite() {
+    init();
+
+    let program = CompiledModule::compile("protocol-conformance", CompilationMode::Debug);
+    let bytes = program.program_bytes().to_vec();
+    program.with_module_async(DEFAULT_CONFIG, |module| async move {
+        let addr = module.serve().await.unwrap();
+        for (features, error_format) in [
+            (vec![], ErrorFormat::Legacy),
+            (DatabaseFeature::ALL.to_vec(), ErrorFormat::Structured),
+        ] {
+            let options = Options {
+                server: format!("http://{addr}"),
+                versions: vec![],
+                encodings: vec![],
+                features,
+                error_format,
+                only: vec![],
+                skip: vec![],
+                timeout_secs: 10,
+                keep_database: false,
+            };
+            let report = conformance::run(&options, bytes.clone()).await.unwrap();
+            assert!(report.passed(), "{}", report.to_tap());
+        }
+    });
+}
+
+/// Spawn a client of a fresh identity on the database of `module`, with `actor` as its actor,
+/// returning its identity and what became of spawning it.
+async fn spawn_client<Fut>(
+    module: &ModuleHandle,
+    actor: impl FnOnce(ClientConnection) -> Fut,
+) -> (Identity, std::thread::Result<ClientConnection>)
+where
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let identity = SpacetimeAuth::alloc(module.node()).await.unwrap().identity;
+    let id = ClientActorId {
+        identity,
+        connection_id: generate_random_connection_id(),
+        name: module.node().client_actor_index().next_client_name(),
+    };
+    let spawned = ClientConnection::spawn(
+        id,
+        ClientConfig::for_test(),
+        module.client.replica_id,
+        module.client.module_watcher(),
+        ConnectionPolicy::default(),
+        None,
+        |client, _sendrx| actor(client),
+    );
+    let spawned = AssertUnwindSafe(spawned).catch_unwind().await;
+    (identity, spawned.map(Result::unwrap))
+}
+
+/// Wait until the module of `module` has logged that `identity` disconnected,
+/// then return how many times it logged that `identity` connected and disconnected.
+async fn connects_and_disconnects(module: &ModuleHandle, identity: Identity) -> (usize, usize) {
+    let connected = format!("Connected {identity}");
+    let disconnected = format!("Disconnected {identity}");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let logs = module.read_log(None).await;
+        let count = |message: &str| logs.lines().filter(|line| line.contains(message)).count();
+        if count(&disconnected) > 0 {
+            return (count(&connected), count(&disconnected));
+        }
+        assert!(Instant::now() < deadline, "{identity} was never disconnected");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[test]
+#[serial]
+/// Clients which connect to their module, but whose connections go no further,
+/// are disconnected from their module again, rather than left connected with nothing to disconnect them.
+fn test_clients_failing_before_their_actor_starts_are_disconnected() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let index = module.node().client_actor_index();
+
+            // As in `handle_websocket`, the websocket is handed to the actor once it's spawned,
+            // but here it never is, as though upgrading the connection failed.
+            let (ws_tx, ws_rx) = tokio::sync::oneshot::channel::<()>();
+            let (identity, spawned) = spawn_client(&module, move |client| {
+                let registration = index.register_client(&client, None);
+                async move {
+                    let Ok(()) = ws_rx.await else { return };
+                    drop(registration);
+                }
+            })
+            .await;
+            let client = spawned.unwrap();
+            assert_eq!(index.clients(&module.db_identity).len(), 1);
+            drop(ws_tx);
+
+            assert_eq!(connects_and_disconnects(&module, identity).await, (1, 1));
+            assert!(client.is_disconnected());
+            assert!(index.clients(&module.db_identity).is_empty());
+
+            // An actor which fails to even be made leaves nothing else to disconnect the client.
+            let (identity, spawned) = spawn_client(&module, |_client| -> std::future::Ready<()> {
+                panic!("the actor failed to start")
+            })
+            .await;
+            assert!(spawned.is_err());
+            assert_eq!(connects_and_disconnects(&module, identity).await, (1, 1));
+        },
+    );
+}
+
+/// Connect two websocket clients of the same identity with the same connection id to the database of `module`,
+/// returning the first client and what became of the second.
+async fn connect_twice(module: &ModuleHandle) -> (TestWebSocket, Result<TestWebSocket, WsError>) {
+    let addr = module.serve().await.unwrap();
+    let auth = SpacetimeAuth::alloc(module.node()).await.unwrap();
+    let connection_id = generate_random_connection_id();
+    let query = format!("token={}&connection_id={}", auth.creds.token(), connection_id.to_hex());
+    let first = connect_ws_with(addr, module, &query).await.unwrap();
+    let second = connect_ws_with(addr, module, &query).await;
+    (first, second)
+}
+
+#[test]
+#[serial]
+/// Connect a second websocket client with the connection id of a connected one,
+/// which is refused by default, leaving the first connected.
+fn test_connection_id_reuse_is_rejected() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let (mut first, second) = connect_twice(&module).await;
+            let Err(WsError::Http(refused)) = second else {
+                panic!("a reused connection id should be refused");
+            };
+            assert_eq!(refused.status(), 409);
+
+            // The first client is still connected.
+            assert!(tokio::time::timeout(Duration::from_millis(500), first.next())
+                .await
+                .is_err());
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Connect a second websocket client with the connection id of a connected one to a node which replaces them,
+/// which closes the first client and connects the second.
+fn test_connection_id_reuse_replaces_the_old_connection() {
+    init();
+
+    let options = WebSocketOptions {
+        connection_id_reuse: ConnectionIdReuse::Replace,
+        ..<_>::default()
+    };
+    CompiledModule::compile("kick-test", CompilationMode::Debug)
+        .with_websocket_options(options)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            module.sql("SET legacy_errors = false").await.unwrap();
+            let (mut first, second) = connect_twice(&module).await;
+            let mut second = second.expect("the new connection should replace the old one");
+
+            let frame = recv_close_frame(&mut first, Duration::from_secs(5)).await;
+            assert_eq!(frame.code, CloseCode::Policy);
+            let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+            assert_eq!(payload["reason"], "connection replaced");
+            assert_eq!(payload["retry"], "never");
+
+            // The second client stays connected.
+            assert!(tokio::time::timeout(Duration::from_millis(500), second.next())
+                .await
+                .is_err());
+        });
+}
+
+#[test]
+#[serial]
+/// Connect a websocket client which asks for a resumable session, and drop its connection,
+/// which it resumes with its token, without being sent its identity token again,
+/// until its connection drops for longer than the session is kept.
+fn test_resuming_a_lost_session() {
+    init();
+
+    let options = WebSocketOptions {
+        session_resume_grace: Some(Duration::from_secs(1)),
+        ..<_>::default()
+    };
+    CompiledModule::compile("kick-test", CompilationMode::Debug)
+        .with_websocket_options(options)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            let addr = module.serve().await.unwrap();
+            let auth = SpacetimeAuth::alloc(module.node()).await.unwrap();
+            let query = format!("token={}&resumable=true", auth.creds.token());
+            let (mut ws, response) = open_ws(addr, &module, &query).await.unwrap();
+            let resume_token = response.headers()[RESUME_TOKEN_HEADER].to_str().unwrap().to_owned();
+            recv_handshake(&mut ws).await;
+            // Dropping the socket loses the connection, without closing it.
+            drop(ws);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            let resume = format!("token={}&resume_token={resume_token}", auth.creds.token());
+            let (mut ws, response) = open_ws(addr, &module, &resume).await.unwrap();
+            assert_eq!(response.headers()[SESSION_RESUMED_HEADER], "true");
+            assert!(tokio::time::timeout(Duration::from_millis(500), ws.next())
+                .await
+                .is_err());
+
+            drop(ws);
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            // The session has expired, so the client connects anew.
+            let (mut ws, response) = open_ws(addr, &module, &resume).await.unwrap();
+            assert_eq!(response.headers()[SESSION_RESUMED_HEADER], "false");
+            recv_handshake(&mut ws).await;
+        });
+}
+
+#[test]
+#[serial]
+/// Connect a websocket client which asks for a resumable session, and drop its connection,
+/// then purge what's held for its identity, after which it can't resume its session,
+/// but connects anew, rather than being replayed part of the session.
+fn test_purged_sessions_are_not_resumed() {
+    init();
+
+    let options = WebSocketOptions {
+        session_resume_grace: Some(Duration::from_secs(30)),
+        ..<_>::default()
+    };
+    CompiledModule::compile("kick-test", CompilationMode::Debug)
+        .with_websocket_options(options)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            let addr = module.serve().await.unwrap();
+            let auth = SpacetimeAuth::alloc(module.node()).await.unwrap();
+            let query = format!("token={}&resumable=true", auth.creds.token());
+            let (mut ws, response) = open_ws(addr, &module, &query).await.unwrap();
+            let resume_token = response.headers()[RESUME_TOKEN_HEADER].to_str().unwrap().to_owned();
+            recv_handshake(&mut ws).await;
+            drop(ws);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            let purged = purge_replay_buffers(&module.client.module, &auth.identity);
+            assert_eq!(
+                purged,
+                PurgedReplayBuffers {
+                    parked_sessions: 1,
+                    pending_deliveries: 0,
+                }
+            );
+
+            // The client is sent its identity token, as a new connection is.
+            let resume = format!("token={}&resume_token={resume_token}", auth.creds.token());
+            let (mut ws, response) = open_ws(addr, &module, &resume).await.unwrap();
+            assert_eq!(response.headers()[SESSION_RESUMED_HEADER], "false");
+            recv_handshake(&mut ws).await;
+            assert_eq!(
+                purge_replay_buffers(&module.client.module, &auth.identity).parked_sessions,
+                0
+            );
+        });
+}
+
+/// Receive the next binary frame of `ws`, split into its channel and the frame of that channel.
+async fn recv_channel_frame(ws: &mut TestWebSocket) -> (u32, Vec<u8>) {
+    let message = tokio::time::timeout(Duration::from_secs(5), ws.next())
+        .await
+        .expect("no frame was received");
+    let Some(Ok(WsMessage::Binary(frame))) = message else {
+        panic!("expected a binary frame, got {message:?}");
+    };
+    let (channel, frame) = decode_channel_frame(&frame).expect("the frame should have a channel");
+    (channel, frame.to_vec())
+}
+
+async fn send_control(ws: &mut TestWebSocket, message: MultiplexClientMessage) {
+    let message = bsatn::to_vec(&message).unwrap();
+    let frame = encode_channel_frame(MULTIPLEX_CONTROL_CHANNEL, &message);
+    ws.send(WsMessage::Binary(frame.into())).await.unwrap();
+}
+
+#[test]
+#[serial]
+/// Open a multiplexed connection and attach a database to a channel,
+/// which then carries the database's messages, until it's detached,
+/// while attaching a database which doesn't exist fails without affecting the connection.
+fn test_multiplexed_connection() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let addr = module.serve().await.unwrap();
+            let mut request = format!("ws://{addr}/v1/subscribe").into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.bsatn.spacetimedb"));
+            let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+            let attach = |channel, database: String| {
+                MultiplexClientMessage::Attach(Attach {
+                    channel,
+                    database: database.into(),
+                    compression: None,
+                    light: None,
+                })
+            };
+            send_control(&mut ws, attach(1, module.db_identity.to_hex().to_string())).await;
+            let (channel, message) = recv_channel_frame(&mut ws).await;
+            assert_eq!(channel, MULTIPLEX_CONTROL_CHANNEL);
+            let MultiplexServerMessage::Attached(attached) = bsatn::from_slice(&message).unwrap() else {
+                panic!("the database should be attached");
+            };
+            assert_eq!((attached.channel, attached.database_identity), (1, module.db_identity));
+            // The database's connection is established with its identity token, as over a websocket of its own.
+            let (channel, _) = recv_channel_frame(&mut ws).await;
+            assert_eq!(channel, 1);
+
+            send_control(&mut ws, attach(2, "no-such-database".into())).await;
+            let (_, message) = recv_channel_frame(&mut ws).await;
+            let MultiplexServerMessage::AttachFailed(failed) = bsatn::from_slice(&message).unwrap() else {
+                panic!("a database which doesn't exist can't be attached");
+            };
+            assert_eq!((failed.channel, failed.status), (2, 404));
+
+            send_control(&mut ws, MultiplexClientMessage::Detach(Detach { channel: 1 })).await;
+            let (_, message) = recv_channel_frame(&mut ws).await;
+            let MultiplexServerMessage::Detached(detached) = bsatn::from_slice(&message).unwrap() else {
+                panic!("the database should be detached");
+            };
+            assert_eq!((detached.channel, detached.code), (1, u16::from(CloseCode::Normal)));
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Connect a websocket client which stops reading, so that a reply to it blocks,
+/// whose actor is torn down, close frame and `disconnect()` included, within one teardown deadline.
+fn test_teardown_of_blackholed_socket_is_bounded_by_one_deadline() {
+    init();
+
+    const TEARDOWN: Duration = Duration::from_secs(1);
+    const EPSILON: Duration = Duration::from_millis(300);
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let timeouts = TimeoutsConfig {
+                send_timeout: TEARDOWN,
+                min_send_timeout: TEARDOWN,
+                teardown_timeout: TEARDOWN,
+                ..<_>::default()
+            };
+            module.node().timeouts().reload(timeouts).unwrap();
+            let index = module.node().client_actor_index();
+            let connections = index.num_connections();
+            let (mut ws, _) = open_ws_over_duplex(&module, "compression=None", 1024).await.unwrap();
+            recv_handshake(&mut ws).await;
+            assert_eq!(index.num_connections(), connections + 1);
+
+            // The uncompressed reply carries the reducer's arguments back,
+            // far more than fits in the stream, and the client never reads it.
+            let call = ClientMessage::CallReducer(CallReducer {
+                reducer: "ban".into(),
+                args: bsatn::to_vec(&product![Identity::ONE, "spam".repeat(16 * 1024)]).unwrap(),
+                request_id: 0,
+                flags: CallReducerFlags::FullUpdate,
+                traceparent: None,
+            });
+            ws.send(WsMessage::Binary(bsatn::to_vec(&call).unwrap().into()))
+                .await
+                .unwrap();
+            let start = Instant::now();
+
+            while index.num_connections() > connections {
+                let elapsed = start.elapsed();
+                assert!(
+                    elapsed < TEARDOWN + EPSILON,
+                    "the actor outlived its teardown: {elapsed:?}"
+                );
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let elapsed = start.elapsed();
+            assert!(elapsed >= TEARDOWN, "the send timed out early: {elapsed:?}");
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Connect a websocket client which stops answering pings,
+/// which is told it was dropped for a liveness timeout.
+fn test_liveness_timeout_close_code() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            module.sql("SET legacy_errors = false").await.unwrap();
+            let timeouts = TimeoutsConfig {
+                liveness_timeout: Duration::from_secs(2),
+                min_liveness_timeout: Duration::from_secs(2),
+                send_timeout: Duration::from_secs(1),
+                ..<_>::default()
+            };
+            module.node().timeouts().reload(timeouts).unwrap();
+            let addr = module.serve().await.unwrap();
+            let mut ws = connect_ws(addr, &module).await.unwrap();
+
+            // Pings are only answered as the client reads, which it doesn't until it's timed out.
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            let frame = recv_close_frame(&mut ws, Duration::from_secs(5)).await;
+            assert_eq!(frame.code, CloseCode::from(DisconnectReason::LivenessTimeout.code()));
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Connect a websocket client with a token expiring in 2 seconds,
+/// which is told its auth expired as its token does, unless it refreshed its token before then.
+fn test_expired_auth_closes_the_connection() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            module.sql("SET legacy_errors = false").await.unwrap();
+            let addr = module.serve().await.unwrap();
+            let auth = SpacetimeAuth::alloc(module.node()).await.unwrap();
+            let token = |expiry| {
+                auth.re_sign_with_expiry(module.node().jwt_auth_provider(), expiry)
+                    .unwrap()
+            };
+
+            let query = format!("token={}", token(Duration::from_secs(2)));
+            let mut ws = connect_ws_with(addr, &module, &query).await.unwrap();
+            let frame = recv_close_frame(&mut ws, Duration::from_secs(5)).await;
+            assert_eq!(frame.code, CloseCode::from(DisconnectReason::AuthExpired.code()));
+
+            let mut ws = connect_ws_with(addr, &module, &query).await.unwrap();
+            let refresh = ClientMessage::<&[u8]>::Authenticate(Authenticate {
+                token: token(Duration::from_secs(60)).into(),
+            });
+            ws.send(WsMessage::Binary(bsatn::to_vec(&refresh).unwrap().into()))
+                .await
+                .unwrap();
+            // Refreshing its token for the same identity keeps the client connected, without telling it anything.
+            let message = tokio::time::timeout(Duration::from_secs(4), ws.next()).await;
+            assert!(
+                message.is_err(),
+                "the client should still be connected, got {message:?}"
+            );
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Send a binary frame larger than the node's limit, and one within a larger limit,
+/// and one within the node's limit but beyond the lower limit of its database.
+/// Only frames beyond the limit close the connection, and with a code saying so.
+fn test_oversized_frames_close_with_message_too_large() {
+    init();
+
+    const FRAME: usize = 64 * 1024;
+    for (node_limit, database_limit, too_large) in [
+        (FRAME / 2, None, true),
+        (FRAME * 2, None, false),
+        (FRAME * 2, Some(FRAME / 2), true),
+    ] {
+        // An undecodable frame within the limit is then rejected, rather than closing the connection.
+        let options = WebSocketOptions {
+            max_message_size: node_limit,
+            protocol_violation_budget: Some(1),
+            ..<_>::default()
+        };
+        CompiledModule::compile("kick-test", CompilationMode::Debug)
+            .with_websocket_options(options)
+            .with_module_async(DEFAULT_CONFIG, |module| async move {
+                module.sql("SET legacy_errors = false").await.unwrap();
+                if let Some(limit) = database_limit {
+                    let limits = MessageSizeLimits {
+                        max_message_size: Some(limit as u64),
+                        max_frame_size: None,
+                    };
+                    module
+                        .node()
+                        .set_message_size_limits(&module.db_identity, limits)
+                        .await
+                        .unwrap();
+                }
+                let addr = module.serve().await.unwrap();
+                let mut ws = connect_ws(addr, &module).await.unwrap();
+
+                ws.send(WsMessage::Binary(vec![0xff; FRAME].into())).await.unwrap();
+                if too_large {
+                    let frame = recv_close_frame(&mut ws, Duration::from_secs(5)).await;
+                    assert_eq!(frame.code, CloseCode::from(DisconnectReason::MessageTooLarge.code()));
+                    let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+                    assert_eq!(payload["limit"], database_limit.unwrap_or(node_limit));
+                } else {
+                    let reply = tokio::time::timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+                    assert!(matches!(reply, Some(Ok(WsMessage::Binary(_)))), "{reply:?}");
+                }
+            });
+    }
+}
+
+#[test]
+#[serial]
+/// Connect a websocket client which sends a text frame that isn't UTF-8,
+/// which is closed with a protocol error.
+fn test_invalid_utf8_text_frame_close_code() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let addr = module.serve().await.unwrap();
+            let mut ws = connect_ws(addr, &module).await.unwrap();
+
+            let frame = Frame::message(vec![b'{', 0xff, 0xfe, b'}'], OpCode::Data(Data::Text), true);
+            ws.send(WsMessage::Frame(frame)).await.unwrap();
+
+            let frame = recv_close_frame(&mut ws, Duration::from_secs(5)).await;
+            assert_eq!(frame.code, CloseCode::Protocol);
+            assert_eq!(frame.reason, "protocol error");
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Connect a websocket client to a database, then delete the database,
+/// which exits its module, and the client is told so.
+fn test_module_exit_close_code() {
+    init();
+
+    CompiledModule::compile("kick-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            module.sql("SET legacy_errors = false").await.unwrap();
+            let addr = module.serve().await.unwrap();
+            let mut ws = connect_ws(addr, &module).await.unwrap();
+
+            let node = module.node();
+            let database = node.get_database_by_identity(&module.db_identity).unwrap().unwrap();
+            node.delete_database(&database.owner_identity, &module.db_identity)
+                .await
+                .unwrap();
+
+            let frame = recv_close_frame(&mut ws, Duration::from_secs(5)).await;
+            assert_eq!(frame.code, CloseCode::from(DisconnectReason::ModuleExited.code()));
+            let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+            assert_eq!(payload["cause"], "delete");
+        },
+    );
+}
+
+#[test]
+#[serial]
+fn test_reducer_interruption_latency() {
+    init();
+
+    CompiledModule::compile("spin-test", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            module.sql("SET max_reducer_duration_ms = 100").await.unwrap();
+
+            let mut interrupted_after = vec![];
+            for interval_ms in [10, 500] {
+                module
+                    .sql(&format!("SET epoch_interval_ms = {interval_ms}"))
+                    .await
+                    .unwrap();
+                // The settings apply to the next instance, which a trap brings about.
+                assert!(module.call_reducer_json("reinstantiate", &product![]).await.is_err());
+
+                let start = Instant::now();
+                let spun = module.call_reducer_json("spin", &product![]).await;
+                assert!(spun.is_err(), "the spinning reducer was never interrupted");
+                interrupted_after.push(start.elapsed());
+            }
+
+            // The spinning reducer is interrupted at the first check past the limit,
+            // which a coarse interval puts off.
+            let [fine, coarse] = interrupted_after[..] else {
+                unreachable!()
+            };
+            assert!(fine >= Duration::from_millis(100), "{fine:?}");
+            assert!(fine < Duration::from_millis(300), "{fine:?}");
+            assert!(coarse >= Duration::from_millis(500), "{coarse:?}");
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// Keep a connection's module busy with a slow reducer while the client sends more calls than may wait,
+/// the last of which is rejected with a "server busy" error, rather than queued.
+fn test_busy_connection_rejects_messages_beyond_its_queue() {
+    init();
+
+    const MAX_LEN: usize = 3;
+    let options = WebSocketOptions {
+        max_incoming_queue_len: MAX_LEN,
+        ..<_>::default()
+    };
+    CompiledModule::compile("spin-test", CompilationMode::Debug)
+        .with_websocket_options(options)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            module.sql("SET legacy_errors = false").await.unwrap();
+            module.sql("SET max_reducer_duration_ms = 1000").await.unwrap();
+            assert!(module.call_reducer_json("reinstantiate", &product![]).await.is_err());
+
+            let addr = module.serve().await.unwrap();
+            let url = format!("ws://{addr}/v1/database/{}/subscribe", module.db_identity);
+            let mut request = url.into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.json.spacetimedb"));
+            let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            recv_handshake(&mut ws).await;
+
+            let spin = |request_id: usize| {
+                let call = format!(
+                    r#"{{"CallReducer":{{"reducer":"spin","args":"[]","request_id":{request_id},"flags":0}}}}"#
+                );
+                WsMessage::Text(call.into())
+            };
+            // The first call is being handled while the others wait, up to the limit.
+            ws.send(spin(0)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            for request_id in 1..=MAX_LEN + 1 {
+                ws.send(spin(request_id)).await.unwrap();
+            }
+
+            let reply = tokio::time::timeout(Duration::from_millis(500), ws.next())
+                .await
+                .expect("the call beyond the queue should be rejected right away");
+            let Some(Ok(WsMessage::Text(reply))) = reply else {
+                panic!("expected a reply, got {reply:?}");
+            };
+            let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+            let error = reply["TransactionUpdate"]["status"]["Failed"]
+                .as_str()
+                .unwrap_or_else(|| panic!("expected a failed update, got {reply}"));
+            let error: serde_json::Value = serde_json::from_str(error).unwrap();
+            assert_eq!(error["reason"], "server busy");
+            assert_eq!(error["queued"], MAX_LEN);
+            assert_eq!(error["max_len"], MAX_LEN);
+            assert_eq!(error["retry"], "backoff");
+        });
+}
+
+#[test]
+#[serial]
+fn test_database_rate_limit_rejects_messages_beyond_its_burst() {
+    init();
+
+    const BURST: usize = 2;
+    // The node's limit is generous, but the database sets a strict one of its own.
+    let options = WebSocketOptions {
+        client_message_rate: Some(1000.0),
+        client_message_throttle: MessageThrottle::Reject,
+        ..<_>::default()
+    };
+    CompiledModule::compile("spin-test", CompilationMode::Debug)
+        .with_websocket_options(options)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            module.sql("SET legacy_errors = false").await.unwrap();
+            module.sql("SET client_message_rate = 1").await.unwrap();
+            module
+                .sql(&format!("SET client_message_burst = {BURST}"))
+                .await
+                .unwrap();
+
+            let addr = module.serve().await.unwrap();
+            let url = format!("ws://{addr}/v1/database/{}/subscribe", module.db_identity);
+            let mut request = url.into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.json.spacetimedb"));
+            let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            recv_handshake(&mut ws).await;
+
+            // Every call fails, but only the one beyond the burst is rate limited.
+            for request_id in 0..=BURST {
+                let call = format!(
+                    r#"{{"CallReducer":{{"reducer":"reinstantiate","args":"[]","request_id":{request_id},"flags":0}}}}"#
+                );
+                ws.send(WsMessage::Text(call.into())).await.unwrap();
+            }
+
+            let mut rate_limited = Vec::new();
+            for _ in 0..=BURST {
+                let reply = tokio::time::timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+                let Some(Ok(WsMessage::Text(reply))) = reply else {
+                    panic!("expected a reply, got {reply:?}");
+                };
+                let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+                let error = reply["TransactionUpdate"]["status"]["Failed"]
+                    .as_str()
+                    .unwrap_or_else(|| panic!("expected a failed update, got {reply}"));
+                if let Ok(error) = serde_json::from_str::<serde_json::Value>(error) {
+                    if error["reason"] == "rate limited" {
+                        rate_limited.push(error);
+                    }
+                }
+            }
+            let [error] = &rate_limited[..] else {
+                panic!("expected one call to be rate limited, got {rate_limited:?}");
+            };
+            assert_eq!(error["rate"], 1.0);
+            assert_eq!(error["burst"], BURST);
+            assert_eq!(error["retry"], "backoff");
+            assert!(error["retry_after_ms"].as_u64().is_some_and(|ms| ms > 0 && ms <= 1000));
+        });
+}
+
+#[test]
+#[serial]
+/// A client which asked for request errors is told of messages it can recover from
+/// with a `RequestError` apiece, rather than having its connection closed.
+fn test_request_errors_keep_the_connection_open() {
+    init();
+
+    CompiledModule::compile("protocol-conformance", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let addr = module.serve().await.unwrap();
+            let url = format!(
+                "ws://{addr}/v1/database/{}/subscribe?request_errors=true",
+                module.db_identity
+            );
+            let mut request = url.into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.json.spacetimedb"));
+            let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            recv_handshake(&mut ws).await;
+
+            let call = |reducer: &str, args: &str, request_id: u32| {
+                let args = serde_json::to_string(args).unwrap();
+                let call = format!(
+                    r#"{{"CallReducer":{{"reducer":"{reducer}","args":{args},"request_id":{request_id},"flags":0}}}}"#
+                );
+                WsMessage::Text(call.into())
+            };
+
+            for (message, code, request_id) in [
+                (WsMessage::Text("not a message".into()), "DecodeFailure", None),
+                (call("no_such_reducer", "[]", 1), "NoSuchReducer", Some(1)),
+                (call("send", "[1, 2]", 2), "ArgsMismatch", Some(2)),
+            ] {
+                ws.send(message).await.unwrap();
+                let reply = recv_json(&mut ws).await;
+                let error = &reply["RequestError"];
+                assert!(
+                    error["code"].get(code).is_some(),
+                    "expected a {code} error, got {reply}"
+                );
+                match request_id {
+                    Some(request_id) => assert_eq!(error["request_id"]["some"], request_id),
+                    None => assert!(error["request_id"].get("none").is_some(), "{reply}"),
+                }
+                assert!(error["message"].as_str().is_some_and(|message| !message.is_empty()));
+            }
+
+            // The connection is still open, and handles well-formed messages as usual.
+            ws.send(call("send", r#"["hello"]"#, 3)).await.unwrap();
+            let reply = recv_json(&mut ws).await;
+            assert!(
+                reply["TransactionUpdate"]["status"].get("Committed").is_some(),
+                "expected the call to commit, got {reply}"
+            );
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// A new connection is sent the server's capabilities right after its identity token,
+/// as configured for the node.
+fn test_capabilities_follow_the_identity_token() {
+    init();
+
+    let options = WebSocketOptions {
+        session_resume_grace: Some(Duration::from_secs(5)),
+        ..<_>::default()
+    };
+    let max_message_size = options.max_message_size;
+    CompiledModule::compile("kick-test", CompilationMode::Debug)
+        .with_websocket_options(options)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            let addr = module.serve().await.unwrap();
+            let url = format!("ws://{addr}/v1/database/{}/subscribe", module.db_identity);
+            let mut request = url.into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v2.json.spacetimedb"));
+            let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+            let [token, capabilities] = recv_handshake(&mut ws).await.map(|message| {
+                let envelope: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+                envelope["message"].clone()
+            });
+            assert!(token.get("IdentityToken").is_some(), "{token}");
+            let capabilities = &capabilities["ServerCapabilities"];
+            assert_eq!(capabilities["protocol"], "v2.json.spacetimedb", "{capabilities}");
+            assert!(
+                capabilities["compression"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|compression| compression.get("Zstd").is_some()),
+                "{capabilities}"
+            );
+            assert_eq!(capabilities["max_message_size"], max_message_size, "{capabilities}");
+            let features = capabilities["features"].as_array().unwrap();
+            assert!(
+                features.iter().any(|feature| feature == "session-resume"),
+                "{capabilities}"
+            );
+        });
+}
+
+#[test]
+#[serial]
+/// A client can change its compression and light mode without reconnecting,
+/// and is told of settings it can't have with a `RequestError`, rather than disconnected.
+fn test_settings_change_mid_session() {
+    init();
+
+    CompiledModule::compile("protocol-conformance", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let addr = module.serve().await.unwrap();
+            let url = format!(
+                "ws://{addr}/v1/database/{}/subscribe?request_errors=true",
+                module.db_identity
+            );
+            let mut request = url.into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.json.spacetimedb"));
+            let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            recv_handshake(&mut ws).await;
+
+            let update = |request_id: u32, compression: &str| {
+                let update = format!(
+                    r#"{{"UpdateSettings":{{"request_id":{request_id},"compression":{{"some":{{"{compression}":[]}}}},"tx_update_full":{{"some":false}},"compression_threshold":{{"none":[]}}}}}}"#
+                );
+                WsMessage::Text(update.into())
+            };
+
+            ws.send(update(1, "Gzip")).await.unwrap();
+            let reply = recv_json(&mut ws).await;
+            let updated = &reply["SettingsUpdated"];
+            assert_eq!(updated["request_id"], 1, "{reply}");
+            assert!(updated["compression"].get("Gzip").is_some(), "{reply}");
+            assert_eq!(updated["tx_update_full"], false, "{reply}");
+
+            // Zstd isn't supported by the v1 protocol.
+            ws.send(update(2, "Zstd")).await.unwrap();
+            let reply = recv_json(&mut ws).await;
+            let error = &reply["RequestError"];
+            assert!(error["code"].get("InvalidSettings").is_some(), "{reply}");
+            assert_eq!(error["request_id"]["some"], 2, "{reply}");
+
+            // The connection is still open, and its caller is still replied to in full.
+            let call = r#"{"CallReducer":{"reducer":"send","args":"[\"hello\"]","request_id":3,"flags":0}}"#;
+            ws.send(WsMessage::Text(call.into())).await.unwrap();
+            let reply = recv_json(&mut ws).await;
+            assert!(
+                reply["TransactionUpdate"]["status"].get("Committed").is_some(),
+                "expected the call to commit, got {reply}"
+            );
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// A client can replace its token with a newer one for the same identity, staying connected past the old one's expiry,
+/// and is told of tokens which are invalid or for another identity with a `RequestError`, rather than disconnected.
+fn test_token_refresh() {
+    init();
+
+    CompiledModule::compile("protocol-conformance", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let addr = module.serve().await.unwrap();
+            let signer = module.node().jwt_auth_provider();
+            let auth = SpacetimeAuth::alloc(module.node()).await.unwrap();
+            let token = |expiry| auth.re_sign_with_expiry(signer, expiry).unwrap();
+
+            let url = format!(
+                "ws://{addr}/v1/database/{}/subscribe?request_errors=true&token={}",
+                module.db_identity,
+                token(Duration::from_secs(2))
+            );
+            let mut request = url.into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.json.spacetimedb"));
+            let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            recv_handshake(&mut ws).await;
+
+            let refresh = |request_id: u32, token: &str| {
+                WsMessage::Text(format!(r#"{{"RefreshToken":{{"request_id":{request_id},"token":"{token}"}}}}"#).into())
+            };
+
+            // An expired token is refused, however long it's past the validator's leeway.
+            let now = SystemTime::now();
+            let expired = SpacetimeIdentityClaims {
+                identity: auth.identity,
+                subject: auth.subject.clone(),
+                issuer: auth.issuer.clone(),
+                audience: vec![],
+                iat: now - Duration::from_secs(2 * 3600),
+                exp: Some(now - Duration::from_secs(3600)),
+            };
+            ws.send(refresh(1, &signer.sign(&expired).unwrap())).await.unwrap();
+            let reply = recv_json(&mut ws).await;
+            let error = &reply["RequestError"];
+            assert!(error["code"].get("InvalidToken").is_some(), "{reply}");
+            assert_eq!(error["request_id"]["some"], 1, "{reply}");
+
+            // A token for another identity doesn't switch the connection over to it.
+            let other = SpacetimeAuth::alloc(module.node()).await.unwrap();
+            ws.send(refresh(2, other.creds.token())).await.unwrap();
+            let reply = recv_json(&mut ws).await;
+            let error = &reply["RequestError"];
+            assert!(error["code"].get("IdentityMismatch").is_some(), "{reply}");
+            assert_eq!(error["request_id"]["some"], 2, "{reply}");
+
+            ws.send(refresh(3, &token(Duration::from_secs(60)))).await.unwrap();
+            let reply = recv_json(&mut ws).await;
+            let refreshed = &reply["TokenRefreshed"];
+            assert_eq!(refreshed["request_id"], 3, "{reply}");
+            assert!(refreshed["expires_at"].get("some").is_some(), "{reply}");
+
+            // Past the expiry of the token it connected with, the client is still connected.
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            let call = r#"{"CallReducer":{"reducer":"send","args":"[\"hello\"]","request_id":4,"flags":0}}"#;
+            ws.send(WsMessage::Text(call.into())).await.unwrap();
+            let reply = recv_json(&mut ws).await;
+            assert!(
+                reply["TransactionUpdate"]["status"].get("Committed").is_some(),
+                "expected the call to commit, got {reply}"
+            );
+        },
+    );
+}
+
+/// Open a websocket speaking JSON to the database of `module`, asking for request errors, and wait out its handshake.
+async fn connect_json_with_request_errors(module: &ModuleHandle) -> TestWebSocket {
+    let addr = module.serve().await.unwrap();
+    let url = format!(
+        "ws://{addr}/v1/database/{}/subscribe?request_errors=true",
+        module.db_identity
+    );
+    let mut request = url.into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.json.spacetimedb"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    recv_handshake(&mut ws).await;
+    ws
+}
+
+/// Send `query` as a `Query` tagged with `request_id` over `ws`, and receive the first reply to it.
+async fn send_query(ws: &mut TestWebSocket, request_id: u32, query: &str) -> serde_json::Value {
+    let message = format!(r#"{{"Query":{{"request_id":{request_id},"query_string":"{query}"}}}}"#);
+    ws.send(WsMessage::Text(message.into())).await.unwrap();
+    recv_json(ws).await
+}
+
+/// Receive the next text message from `ws` as JSON.
+async fn recv_json(ws: &mut TestWebSocket) -> serde_json::Value {
+    let reply = tokio::time::timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+    let Some(Ok(WsMessage::Text(reply))) = reply else {
+        panic!("expected a reply, got {reply:?}");
+    };
+    serde_json::from_str(&reply).unwrap()
+}
+
+#[test]
+#[serial]
+/// A query sent with `Query` over the websocket is answered with its rows, in chunks tagged with its request id.
+fn test_query_rows_are_chunked() {
+    init();
+
+    let options = WebSocketOptions {
+        one_off_query_chunk_rows: Some(2),
+        ..<_>::default()
+    };
+    CompiledModule::compile("protocol-conformance", CompilationMode::Debug)
+        .with_websocket_options(options)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            for text in ["a", "b", "c"] {
+                module.call_reducer_json("send", &product![text]).await.unwrap();
+            }
+            let mut ws = connect_json_with_request_errors(&module).await;
+
+            let first = send_query(&mut ws, 1, "SELECT * FROM message").await;
+            let second = recv_json(&mut ws).await;
+            let chunks = [&first["QueryRows"], &second["QueryRows"]];
+            for chunk in chunks {
+                assert_eq!(chunk["request_id"], 1, "{chunk}");
+                assert_eq!(chunk["table_name"], "message", "{chunk}");
+            }
+            let rows = chunks.map(|chunk| chunk["rows"].as_array().unwrap().len());
+            assert_eq!(rows, [2, 1]);
+            assert_eq!(chunks.map(|chunk| chunk["last"].as_bool().unwrap()), [false, true]);
+
+            // A query which matches no rows is answered with a single, empty, chunk.
+            let reply = send_query(&mut ws, 2, "SELECT * FROM message WHERE text = 'z'").await;
+            let chunk = &reply["QueryRows"];
+            assert_eq!(chunk["rows"].as_array().unwrap().len(), 0, "{reply}");
+            assert_eq!(chunk["last"], true, "{reply}");
+        });
+}
+
+#[test]
+#[serial]
+/// A query sent with `Query` over the websocket which reads a private table, or runs for too long,
+/// is answered with a `RequestError` telling the client why, and the connection stays open.
+fn test_query_errors() {
+    init();
+
+    // With no time to run, every query which gets as far as running times out.
+    let options = WebSocketOptions {
+        one_off_query_timeout: Some(Duration::ZERO),
+        ..<_>::default()
+    };
+    CompiledModule::compile("module-test", CompilationMode::Debug)
+        .with_websocket_options(options)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            module
+                .call_reducer_json("add_private", &product!["secret"])
+                .await
+                .unwrap();
+            let mut ws = connect_json_with_request_errors(&module).await;
+
+            let reply = send_query(&mut ws, 1, "SELECT * FROM private_table").await;
+            let error = &reply["RequestError"];
+            assert!(error["code"].get("PermissionDenied").is_some(), "{reply}");
+            assert_eq!(error["request_id"]["some"], 1, "{reply}");
+
+            let reply = send_query(&mut ws, 2, "SELECT * FROM person").await;
+            let error = &reply["RequestError"];
+            assert!(error["code"].get("QueryTimeout").is_some(), "{reply}");
+            assert_eq!(error["request_id"]["some"], 2, "{reply}");
+
+            let reply = send_query(&mut ws, 3, "SELECT * FROM no_such_table").await;
+            assert!(reply["RequestError"]["code"].get("QueryFailed").is_some(), "{reply}");
+        });
+}
+
+#[test]
+#[serial]
+/// A client which connected with no flow control credits receives no updates until it grants some,
+/// and then receives the updates held back for it, with those of others' calls coalesced into one,
+/// and the reply to its own call intact.
+fn test_flow_control_credits() {
+    init();
+
+    CompiledModule::compile("protocol-conformance", CompilationMode::Debug).with_module_async(
+        DEFAULT_CONFIG,
+        |module| async move {
+            let addr = module.serve().await.unwrap();
+            let url = format!(
+                "ws://{addr}/v1/database/{}/subscribe?flow_control_credits=0&light=true",
+                module.db_identity
+            );
+            let mut request = url.into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.json.spacetimedb"));
+            let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            recv_handshake(&mut ws).await;
+
+            // Replies other than updates spend no credits.
+            let subscribe =
+                r#"{"SubscribeSingle":{"query":"SELECT * FROM message","request_id":1,"query_id":{"id":1}}}"#;
+            ws.send(WsMessage::Text(subscribe.into())).await.unwrap();
+            let reply = recv_json(&mut ws).await;
+            assert!(reply.get("SubscribeApplied").is_some(), "{reply}");
+
+            for text in ["a", "b", "c"] {
+                module.call_reducer_json("send", &product![text]).await.unwrap();
+            }
+            let call = r#"{"CallReducer":{"reducer":"send","args":"[\"d\"]","request_id":2,"flags":0}}"#;
+            ws.send(WsMessage::Text(call.into())).await.unwrap();
+            let stalled = tokio::time::timeout(Duration::from_millis(500), ws.next()).await;
+            assert!(stalled.is_err(), "expected no updates without credits, got {stalled:?}");
+
+            ws.send(WsMessage::Text(r#"{"GrantCredits":{"credits":1000000}}"#.into()))
+                .await
+                .unwrap();
+            let reply = recv_json(&mut ws).await;
+            let tables = reply["TransactionUpdateLight"]["update"]["tables"].as_array().unwrap();
+            assert_eq!(tables.len(), 1, "{reply}");
+            assert_eq!(tables[0]["num_rows"], 3, "{reply}");
+            let reply = recv_json(&mut ws).await;
+            let update = &reply["TransactionUpdate"];
+            assert!(update["status"].get("Committed").is_some(), "{reply}");
+            assert_eq!(update["reducer_call"]["request_id"], 2, "{reply}");
+        },
+    );
+}
+
+#[test]
+#[serial]
+/// A client which asked for request errors is told of messages refused for exceeding its queue or rate limit
+/// with a `RequestError` of the matching code.
+fn test_rejected_messages_are_request_errors() {
+    init();
+
+    const MAX_LEN: usize = 2;
+    let options = WebSocketOptions {
+        max_incoming_queue_len: MAX_LEN,
+        client_message_rate: Some(1000.0),
+        client_message_throttle: MessageThrottle::Reject,
+        ..<_>::default()
+    };
+    CompiledModule::compile("spin-test", CompilationMode::Debug)
+        .with_websocket_options(options)
+        .with_module_async(DEFAULT_CONFIG, |module| async move {
+            module.sql("SET max_reducer_duration_ms = 1000").await.unwrap();
+            assert!(module.call_reducer_json("reinstantiate", &product![]).await.is_err());
+
+            let addr = module.serve().await.unwrap();
+            let url = format!(
+                "ws://{addr}/v1/database/{}/subscribe?request_errors=true",
+                module.db_identity
+            );
+            let connect = || {
+                let mut request = url.as_str().into_client_request().unwrap();
+                request
+                    .headers_mut()
+                    .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1.json.spacetimedb"));
+                async move {
+                    let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+                    recv_handshake(&mut ws).await;
+                    ws
+                }
+            };
+            let call = |reducer: &str, request_id: usize| {
+                let call = format!(
+                    r#"{{"CallReducer":{{"reducer":"{reducer}","args":"[]","request_id":{request_id},"flags":0}}}}"#
+                );
+                WsMessage::Text(call.into())
+            };
+            let request_error = |reply: Option<Result<WsMessage, WsError>>| {
+                let Some(Ok(WsMessage::Text(reply))) = reply else {
+                    panic!("expected a reply, got {reply:?}");
+                };
+                let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+                reply.get("RequestError").cloned()
+            };
+
+            // The first call is being handled while the others wait, up to the limit.
+            let mut ws = connect().await;
+            ws.send(call("spin", 0)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            for request_id in 1..=MAX_LEN + 1 {
+                ws.send(call("spin", request_id)).await.unwrap();
+            }
+            let reply = tokio::time::timeout(Duration::from_millis(500), ws.next())
+                .await
+                .expect("the call beyond the queue should be rejected right away");
+            let error = request_error(reply).expect("expected a request error");
+            assert!(error["code"].get("QueueFull").is_some(), "{error}");
+            drop(ws);
+
+            // Every call fails, but only the one beyond the burst is rate limited.
+            module.sql("SET client_message_rate = 1").await.unwrap();
+            module.sql("SET client_message_burst = 1").await.unwrap();
+            let mut ws = connect().await;
+            for request_id in 0..2 {
+                ws.send(call("reinstantiate", request_id)).await.unwrap();
+            }
+            let mut errors = Vec::new();
+            for _ in 0..2 {
+                let reply = tokio::time::timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+                errors.extend(request_error(reply));
+            }
+            let [error] = &errors[..] else {
+                panic!("expected one call to be rate limited, got {errors:?}");
+            };
+            assert!(error["code"].get("RateLimited").is_some(), "{error}");
+        });
+}
+
 #[test]
 #[serial]
 /// This test runs the index scan workloads in the `perf-test` module.
@@ -0,0 +1,17 @@
+//! The diagnostic module run by a node's self-test.
+//!
+//! The self-test publishes this module to a throwaway database,
+//! subscribes to `probes`, calls `probe` with a fresh nonce,
+//! and expects the inserted row to be delivered to it.
+use spacetimedb::{ReducerContext, Table};
+
+#[spacetimedb::table(name = probes, public)]
+pub struct Probe {
+    #[primary_key]
+    nonce: u64,
+}
+
+#[spacetimedb::reducer]
+pub fn probe(ctx: &ReducerContext, nonce: u64) {
+    ctx.db.probes().insert(Probe { nonce });
+}
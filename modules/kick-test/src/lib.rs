@@ -0,0 +1,34 @@
+use spacetimedb::{log, Identity, ReducerContext, Table};
+
+#[spacetimedb::table(name = banned)]
+pub struct Banned {
+    #[primary_key]
+    identity: Identity,
+    reason: String,
+}
+
+#[spacetimedb::reducer(client_connected)]
+pub fn client_connected(ctx: &ReducerContext) {
+    log::info!("Connected {}", ctx.sender);
+}
+
+#[spacetimedb::reducer(client_disconnected)]
+pub fn client_disconnected(ctx: &ReducerContext) {
+    log::info!("Disconnected {}", ctx.sender);
+}
+
+/// Ban `identity` and kick all of its connections.
+#[spacetimedb::reducer]
+pub fn ban(ctx: &ReducerContext, identity: Identity, reason: String) {
+    let kicked = ctx.disconnect_client(identity, &reason);
+    ctx.db.banned().insert(Banned { identity, reason });
+    log::info!("Banned {identity}, kicking {kicked} connection(s)");
+}
+
+/// Try to ban `identity`, but fail after kicking it,
+/// so that neither the ban nor the kick take effect.
+#[spacetimedb::reducer]
+pub fn ban_then_fail(ctx: &ReducerContext, identity: Identity, reason: String) -> Result<(), String> {
+    ban(ctx, identity, reason);
+    Err("changed my mind".into())
+}
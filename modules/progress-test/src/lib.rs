@@ -0,0 +1,26 @@
+use spacetimedb::{log, ReducerContext, Table};
+
+#[spacetimedb::table(name = counted)]
+pub struct Counted {
+    n: u32,
+}
+
+/// Count up to `n`, reporting each step as progress.
+#[spacetimedb::reducer]
+pub fn count_to(ctx: &ReducerContext, n: u32) {
+    let reported = (0..n)
+        .filter(|&i| {
+            ctx.db.counted().insert(Counted { n: i });
+            ctx.report_progress(i)
+        })
+        .count();
+    log::info!("Counted to {n}, reporting {reported} step(s)");
+}
+
+/// Count up to `n`, then fail,
+/// so that nothing is counted, though the progress was reported all the same.
+#[spacetimedb::reducer]
+pub fn count_to_then_fail(ctx: &ReducerContext, n: u32) -> Result<(), String> {
+    count_to(ctx, n);
+    Err("lost count".into())
+}
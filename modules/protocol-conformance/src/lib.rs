@@ -0,0 +1,72 @@
+use spacetimedb::{log, Identity, ReducerContext, Table};
+
+/// The rows the conformance checks subscribe to and query.
+#[spacetimedb::table(name = message, public)]
+pub struct Message {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    sender: Identity,
+    text: String,
+}
+
+#[spacetimedb::reducer(client_connected)]
+pub fn client_connected(ctx: &ReducerContext) {
+    log::info!("Connected {}", ctx.sender);
+}
+
+/// Insert a message, failing if `text` is empty.
+#[spacetimedb::reducer]
+pub fn send(ctx: &ReducerContext, text: String) -> Result<(), String> {
+    if text.is_empty() {
+        return Err("empty message".into());
+    }
+    ctx.db.message().insert(Message {
+        id: 0,
+        sender: ctx.sender,
+        text,
+    });
+    Ok(())
+}
+
+/// Insert a message, then fail with `error`, so that the message is rolled back.
+#[spacetimedb::reducer]
+pub fn send_then_fail(ctx: &ReducerContext, text: String, error: String) -> Result<(), String> {
+    send(ctx, text)?;
+    Err(error)
+}
+
+/// Insert a message of `len` bytes, so that the updates carrying it are large enough to be compressed.
+#[spacetimedb::reducer]
+pub fn send_padded(ctx: &ReducerContext, len: u32) -> Result<(), String> {
+    send(ctx, "x".repeat(len as usize))
+}
+
+/// Insert a message, as an acknowledged reducer.
+#[spacetimedb::reducer(acknowledged)]
+pub fn send_acknowledged(ctx: &ReducerContext, text: String) -> Result<(), String> {
+    send(ctx, text)
+}
+
+/// Disconnect the connection which called this reducer, telling it it was kicked for `reason`.
+#[spacetimedb::reducer]
+pub fn kick_me(ctx: &ReducerContext, reason: String) -> Result<(), String> {
+    let connection_id = ctx.connection_id.ok_or("kick_me must be called by a client")?;
+    ctx.disconnect_client(connection_id, &reason);
+    Ok(())
+}
+
+/// Fail unless the features turned on for the database are exactly `expected`, by name.
+#[spacetimedb::reducer]
+pub fn expect_features(ctx: &ReducerContext, expected: Vec<String>) -> Result<(), String> {
+    let mut features = ctx.features().iter().map(|feature| feature.name()).collect::<Vec<_>>();
+    let mut expected = expected.iter().map(String::as_str).collect::<Vec<_>>();
+    features.sort_unstable();
+    expected.sort_unstable();
+    if features != expected {
+        return Err(format!(
+            "expected features {expected:?}, but the database has {features:?}"
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use spacetimedb::{log, ReducerContext};
+
+/// Spin forever, or rather until the host interrupts the call.
+#[spacetimedb::reducer]
+pub fn spin(_ctx: &ReducerContext) {
+    log::info!("Spinning");
+    let mut n = 0u64;
+    loop {
+        n = std::hint::black_box(n.wrapping_add(1));
+    }
+}
+
+/// Trap, so that the next call instantiates the module afresh,
+/// applying the database's current metering settings.
+#[spacetimedb::reducer]
+pub fn reinstantiate(_ctx: &ReducerContext) {
+    panic!("reinstantiating");
+}
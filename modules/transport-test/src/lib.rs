@@ -0,0 +1,50 @@
+use spacetimedb::{log, ReducerContext, ReducerTransport, ScheduleAt, Table};
+
+#[spacetimedb::table(name = scheduled_check, scheduled(check_scheduled))]
+pub struct ScheduledCheck {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+/// Fail unless `reducer` was called over the `expected` transport.
+fn check(ctx: &ReducerContext, reducer: &str, expected: ReducerTransport) -> Result<(), String> {
+    let transport = ctx.transport();
+    if transport != expected {
+        let msg = format!(
+            "{reducer} expected {}, but was called over {}",
+            expected.as_str(),
+            transport.as_str()
+        );
+        log::error!("{msg}");
+        return Err(msg);
+    }
+    log::info!("{reducer} was called over {}", transport.as_str());
+    Ok(())
+}
+
+#[spacetimedb::reducer(init)]
+pub fn init(ctx: &ReducerContext) -> Result<(), String> {
+    check(ctx, "init", ReducerTransport::Internal)?;
+    ctx.db.scheduled_check().insert(ScheduledCheck {
+        scheduled_id: 0,
+        scheduled_at: ScheduleAt::Time(ctx.timestamp),
+    });
+    Ok(())
+}
+
+#[spacetimedb::reducer]
+pub fn check_scheduled(ctx: &ReducerContext, _arg: ScheduledCheck) -> Result<(), String> {
+    check(ctx, "check_scheduled", ReducerTransport::Scheduled)
+}
+
+#[spacetimedb::reducer]
+pub fn check_websocket(ctx: &ReducerContext) -> Result<(), String> {
+    check(ctx, "check_websocket", ReducerTransport::Websocket)
+}
+
+#[spacetimedb::reducer]
+pub fn check_http(ctx: &ReducerContext) -> Result<(), String> {
+    check(ctx, "check_http", ReducerTransport::Http)
+}